@@ -1 +1,6 @@
-fn main() {}
+fn main() {
+    // The messages and service of the gRPC transport, generated with `protoc`
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/candle_vllm.proto")
+        .expect("Cannot compile proto/candle_vllm.proto, the grpc feature requires protoc");
+}
@@ -3,6 +3,59 @@ use std::fs::read_to_string;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::path::PathBuf;
+use std::process::Command;
+
+/// Lines appended to the generated `src/lib.rs`
+const LIB_EXTRA_LINES: [&str; 2] = [
+    "pub mod ffi;",
+    "include!(concat!(env!(\"OUT_DIR\"), \"/arch_kernels.rs\"));",
+];
+
+/// Compile the `copy_blocks` PTX for each compute capability listed in `CUDA_COMPUTE_CAPS`
+/// (e.g. `61,75,80,90`) so that the runtime can pick the variant matching the GPU, and write the
+/// `COPY_BLOCKS_KERNELS` table. The table is empty when the variable is unset.
+fn build_arch_kernels() -> Result<()> {
+    println!("cargo:rerun-if-env-changed=CUDA_COMPUTE_CAPS");
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    let nvcc = std::env::var("NVCC").unwrap_or_else(|_| "nvcc".to_string());
+    let mut caps = std::env::var("CUDA_COMPUTE_CAPS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|cap| !cap.trim().is_empty())
+        .map(|cap| cap.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()?;
+    caps.sort();
+    caps.dedup();
+
+    let mut entries = Vec::new();
+    for cap in caps {
+        let ptx = out_dir.join(format!("copy_blocks_kernel_sm{cap}.ptx"));
+        let status = Command::new(&nvcc)
+            .arg("--ptx")
+            .arg(format!("--gpu-architecture=compute_{cap}"))
+            .arg("-O3")
+            .arg("src/copy_blocks_kernel.cu")
+            .arg("-o")
+            .arg(&ptx)
+            .status()?;
+        if !status.success() {
+            anyhow::bail!("nvcc failed to build copy_blocks_kernel.cu for compute_{cap}");
+        }
+        entries.push(format!(
+            "    ({cap}, include_str!({:?})),",
+            ptx.display().to_string()
+        ));
+    }
+    std::fs::write(
+        out_dir.join("arch_kernels.rs"),
+        format!(
+            "/// `copy_blocks` PTX per compute capability (major * 10 + minor), ascending.\n\
+            pub const COPY_BLOCKS_KERNELS: &[(usize, &str)] = &[\n{}\n];\n",
+            entries.join("\n")
+        ),
+    )?;
+    Ok(())
+}
 
 fn read_lines(filename: &str) -> Vec<String> {
     let mut result = Vec::new();
@@ -19,12 +72,16 @@ fn main() -> Result<()> {
     println!("cargo:rerun-if-changed=src/pagedattention.cu");
     println!("cargo:rerun-if-changed=src/copy_blocks_kernel.cu");
     println!("cargo:rerun-if-changed=src/reshape_and_cache_kernel.cu");
+    println!("cargo:rerun-if-changed=src/fp8_dequantize_kernel.cu");
+    println!("cargo:rerun-if-changed=src/dequantize_4bit_kernel.cu");
+    println!("cargo:rerun-if-changed=src/penalty_kernel.cu");
     let builder = bindgen_cuda::Builder::default();
     println!("cargo:info={builder:?}");
     builder.build_lib("libpagedattention.a");
 
     let bindings = builder.build_ptx().unwrap();
     bindings.write("src/lib.rs").unwrap();
+    build_arch_kernels()?;
 
     let kernel_dir = PathBuf::from("../kernels/");
     let absolute_kernel_dir = std::fs::canonicalize(&kernel_dir).unwrap();
@@ -37,20 +94,19 @@ fn main() -> Result<()> {
     println!("cargo:rustc-link-lib=dylib=cudart");
 
     let contents = read_lines("src/lib.rs");
-    for line in contents {
-        if line == "pub mod ffi;" {
-            return Ok(());
-        }
-    }
     let mut file = OpenOptions::new()
         .write(true)
         .append(true)
         .open("src/lib.rs")
         .unwrap();
-    //Expose paged attention interface to Rust
-    if let Err(e) = writeln!(file, "pub mod ffi;") {
-        anyhow::bail!("error while building dependencies: {:?}\n", e,)
-    } else {
-        Ok(())
+    //Expose paged attention interface and the per-arch kernels to Rust
+    for line in LIB_EXTRA_LINES {
+        if contents.iter().any(|existing| existing == line) {
+            continue;
+        }
+        if let Err(e) = writeln!(file, "{line}") {
+            anyhow::bail!("error while building dependencies: {:?}\n", e,)
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,97 @@
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+#include <stdint.h>
+
+#include <algorithm>
+
+namespace vllm {
+
+// The 16 values of the 4-bit codes, NF4 (the quantiles of a normal distribution, as in
+// bitsandbytes) and symmetric INT4 (k / 7)
+__constant__ float NF4_CODEBOOK[16] = {
+  -1.0f, -0.6961928009986877f, -0.5250730514526367f, -0.39491748809814453f,
+  -0.28444138169288635f, -0.18477343022823334f, -0.09105003625154495f, 0.0f,
+  0.07958029955625534f, 0.16093020141124725f, 0.24611230194568634f, 0.33791524171829224f,
+  0.44070982933044434f, 0.5626170039176941f, 0.7229568362236023f, 1.0f,
+};
+
+__constant__ float INT4_CODEBOOK[16] = {
+  -8.0f / 7.0f, -1.0f, -6.0f / 7.0f, -5.0f / 7.0f, -4.0f / 7.0f, -3.0f / 7.0f, -2.0f / 7.0f,
+  -1.0f / 7.0f, 0.0f, 1.0f / 7.0f, 2.0f / 7.0f, 3.0f / 7.0f, 4.0f / 7.0f, 5.0f / 7.0f,
+  6.0f / 7.0f, 1.0f,
+};
+
+template<typename scalar_t>
+__device__ __forceinline__ scalar_t to_scalar(float value);
+
+template<>
+__device__ __forceinline__ float to_scalar<float>(float value) {
+  return value;
+}
+
+template<>
+__device__ __forceinline__ __half to_scalar<__half>(float value) {
+  return __float2half(value);
+}
+
+template<>
+__device__ __forceinline__ __nv_bfloat16 to_scalar<__nv_bfloat16>(float value) {
+  return __float2bfloat16(value);
+}
+
+// Each thread decodes one byte, the codes of two consecutive weights (the first in the high bits)
+template<typename scalar_t>
+__global__ void dequantize_4bit_kernel(
+  const uint8_t* __restrict__ codes,   // [numel / 2]
+  const float* __restrict__ absmax,    // [numel / block_size]
+  scalar_t* __restrict__ out,          // [numel]
+  const int64_t numel,
+  const int64_t block_size,
+  const uint32_t codebook) {
+  const float* values = codebook == 0 ? NF4_CODEBOOK : INT4_CODEBOOK;
+  for (int64_t idx = blockIdx.x * (int64_t)blockDim.x + threadIdx.x; idx < numel / 2;
+       idx += (int64_t)gridDim.x * blockDim.x) {
+    const uint8_t byte = codes[idx];
+    // Blocks have an even size, both weights share their scale
+    const float scale = absmax[2 * idx / block_size];
+    out[2 * idx] = to_scalar<scalar_t>(values[byte >> 4] * scale);
+    out[2 * idx + 1] = to_scalar<scalar_t>(values[byte & 0xF] * scale);
+  }
+}
+
+} // namespace vllm
+
+#define CALL_DEQUANTIZE_4BIT(T)                                       \
+  vllm::dequantize_4bit_kernel<T><<<grid, block, 0, stream>>>(        \
+    reinterpret_cast<const uint8_t*>(codes),                          \
+    absmax,                                                           \
+    reinterpret_cast<T*>(out),                                        \
+    numel,                                                            \
+    block_size,                                                       \
+    codebook);
+
+extern "C" void dequantize_4bit(
+  const void *codes,    // [numel / 2]
+  const float *absmax,  // [numel / block_size]
+  void *out,            // [numel]
+
+  int64_t numel,
+  int64_t block_size,
+  uint32_t codebook,    // 0 => nf4; 1 => int4
+
+  uint32_t dtype        // 0 => f16; 1 => bf16; 2 => f32
+  )
+{
+  const int64_t bytes = numel / 2;
+  dim3 block(256);
+  dim3 grid((unsigned int)std::max<int64_t>(std::min<int64_t>((bytes + 255) / 256, 65535), 1));
+  const cudaStream_t stream = 0;
+
+  if (dtype == 0) {
+    CALL_DEQUANTIZE_4BIT(__half);
+  } else if (dtype == 1) {
+    CALL_DEQUANTIZE_4BIT(__nv_bfloat16);
+  } else if (dtype == 2) {
+    CALL_DEQUANTIZE_4BIT(float);
+  }
+}
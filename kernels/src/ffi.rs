@@ -17,6 +17,7 @@ extern "C" {
         value_stride: c_int,
 
         dtype: u32,
+        int8_kv_cache: bool,
     );
 
     pub fn paged_attention_v1(
@@ -41,6 +42,8 @@ extern "C" {
 
         dtype: u32,
         softscapping: f32,
+        sliding_window: c_int,
+        int8_kv_cache: bool,
     );
 
     pub fn paged_attention_v2(
@@ -68,5 +71,39 @@ extern "C" {
 
         dtype: u32,
         softscapping: f32,
+        sliding_window: c_int,
+        int8_kv_cache: bool,
+    );
+
+    pub fn fp8_dequantize(
+        weight: *const c_void,
+        scale: *const f32,
+        out: *const c_void,
+        rows: i64,
+        cols: i64,
+        scale_rows: i64,
+
+        dtype: u32,
+    );
+
+    pub fn dequantize_4bit(
+        codes: *const c_void,
+        absmax: *const f32,
+        out: *const c_void,
+        numel: i64,
+        block_size: i64,
+        codebook: u32,
+
+        dtype: u32,
+    );
+
+    pub fn apply_penalties(
+        logits: *const c_void,
+        tokens: *const u32,
+        counts: *const u32,
+        penalties: *const f32,
+        num_seqs: i64,
+        vocab_size: i64,
+        num_tokens: i64,
     );
 }
@@ -0,0 +1,94 @@
+#include <cuda_fp16.h>
+#include <cuda_bf16.h>
+#include <stdint.h>
+
+#include <algorithm>
+
+namespace vllm {
+
+// FP8 E4M3 (the `fn` variant without infinities): 1 sign, 4 exponent (bias 7) and 3 mantissa bits
+__device__ __forceinline__ float fp8_e4m3_to_float(uint8_t bits) {
+  const uint32_t sign = (uint32_t)(bits & 0x80) << 24;
+  const uint32_t exponent = (bits >> 3) & 0xF;
+  const uint32_t mantissa = bits & 0x7;
+  if (exponent == 0) {
+    // Subnormal, mantissa / 8 * 2^-6
+    const float value = (float)mantissa * 0.001953125f;
+    return sign ? -value : value;
+  }
+  if (exponent == 0xF && mantissa == 0x7) {
+    return __int_as_float(0x7FC00000 | sign);
+  }
+  return __uint_as_float(sign | ((exponent + 120) << 23) | (mantissa << 20));
+}
+
+template<typename scalar_t>
+__device__ __forceinline__ scalar_t from_float(float value);
+
+template<>
+__device__ __forceinline__ float from_float<float>(float value) {
+  return value;
+}
+
+template<>
+__device__ __forceinline__ __half from_float<__half>(float value) {
+  return __float2half(value);
+}
+
+template<>
+__device__ __forceinline__ __nv_bfloat16 from_float<__nv_bfloat16>(float value) {
+  return __float2bfloat16(value);
+}
+
+template<typename scalar_t>
+__global__ void fp8_dequantize_kernel(
+  const uint8_t* __restrict__ weight,  // [rows, cols]
+  const float* __restrict__ scale,     // [scale_rows], 1 (per tensor) or rows (per channel)
+  scalar_t* __restrict__ out,          // [rows, cols]
+  const int64_t rows,
+  const int64_t cols,
+  const int64_t scale_rows) {
+  const int64_t numel = rows * cols;
+  for (int64_t idx = blockIdx.x * (int64_t)blockDim.x + threadIdx.x; idx < numel;
+       idx += (int64_t)gridDim.x * blockDim.x) {
+    const float s = scale_rows == 1 ? scale[0] : scale[idx / cols];
+    out[idx] = from_float<scalar_t>(fp8_e4m3_to_float(weight[idx]) * s);
+  }
+}
+
+} // namespace vllm
+
+#define CALL_FP8_DEQUANTIZE(T)                                        \
+  vllm::fp8_dequantize_kernel<T><<<grid, block, 0, stream>>>(         \
+    reinterpret_cast<const uint8_t*>(weight),                         \
+    reinterpret_cast<const float*>(scale),                            \
+    reinterpret_cast<T*>(out),                                        \
+    rows,                                                             \
+    cols,                                                             \
+    scale_rows);
+
+extern "C" void fp8_dequantize(
+  const void *weight,  // [rows, cols] FP8 E4M3
+  const float *scale,  // [scale_rows]
+  void *out,           // [rows, cols]
+
+  int64_t rows,
+  int64_t cols,
+  int64_t scale_rows,
+
+  uint32_t dtype       // 0 => f16; 1 => bf16; 2 => f32
+  )
+{
+  const int64_t numel = rows * cols;
+  dim3 block(256);
+  dim3 grid((unsigned int)std::min<int64_t>((numel + 255) / 256, 65535));
+  const cudaStream_t stream = 0;
+
+  if (dtype == 0) {
+    CALL_FP8_DEQUANTIZE(__half);
+  } else if (dtype == 1) {
+    CALL_FP8_DEQUANTIZE(__nv_bfloat16);
+  } else if (dtype == 2) {
+    CALL_FP8_DEQUANTIZE(float);
+  }
+}
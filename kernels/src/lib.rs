@@ -4,3 +4,4 @@ pub const PAGEDATTENTION: &str = include_str!(concat!(env!("OUT_DIR"), "/pagedat
 pub const RESHAPE_AND_CACHE_KERNEL: &str =
     include_str!(concat!(env!("OUT_DIR"), "/reshape_and_cache_kernel.ptx"));
 pub mod ffi;
+include!(concat!(env!("OUT_DIR"), "/arch_kernels.rs"));
@@ -25,6 +25,7 @@
 #include "attention/attention_utils.cuh"
 
 #include <algorithm>
+#include <type_traits>
 
 #ifndef USE_ROCM
 #define WARP_SIZE 32
@@ -89,8 +90,11 @@ inline __device__ float fast_tanh(float x) {
 
 // TODO(woosuk): Merge the last two dimensions of the grid.
 // Grid: (num_heads, num_seqs, max_num_partitions).
+// INT8 caches (`cache_t` = int8_t) store, per block and kv head, the head_size * block_size
+// quantized values followed by one float scale per slot (padded to 16 * block_size bytes).
 template<
   typename scalar_t,
+  typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS,
@@ -100,8 +104,8 @@ __device__ void paged_attention_kernel(
   float* __restrict__ max_logits,         // [num_seqs, num_heads, max_num_partitions]
   scalar_t* __restrict__ out,             // [num_seqs, num_heads, max_num_partitions, head_size]
   const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const scalar_t* __restrict__ k_cache,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const scalar_t* __restrict__ v_cache,   // [num_blocks, num_kv_heads, head_size, block_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const uint32_t* __restrict__ block_tables,   // [num_seqs, max_num_blocks_per_seq]
@@ -111,7 +115,8 @@ __device__ void paged_attention_kernel(
   const int q_stride,
   const int kv_block_stride,
   const int kv_head_stride,
-  const float softscapping) {
+  const float softscapping,
+  const int sliding_window) {
   const int seq_idx = blockIdx.y;
   const int partition_idx = blockIdx.z;
   const int max_num_partitions = gridDim.z;
@@ -134,6 +139,8 @@ __device__ void paged_attention_kernel(
   const int start_token_idx = start_block_idx * BLOCK_SIZE;
   const int end_token_idx = MIN(start_token_idx + num_blocks * BLOCK_SIZE, context_len);
   const int num_tokens = end_token_idx - start_token_idx;
+  // Tokens before the sliding window are masked out, a window of 0 attends to the whole context.
+  const int window_start = sliding_window > 0 ? MAX((int)context_len - sliding_window, 0) : 0;
 
   constexpr int THREAD_GROUP_SIZE = MAX(WARP_SIZE / BLOCK_SIZE, 1);
   constexpr int NUM_THREAD_GROUPS = NUM_THREADS / THREAD_GROUP_SIZE; // Note: This assumes THREAD_GROUP_SIZE divides NUM_THREADS
@@ -189,7 +196,8 @@ __device__ void paged_attention_kernel(
 
   // x == THREAD_GROUP_SIZE * VEC_SIZE
   // Each thread group fetches x elements from the key at a time.
-  constexpr int x = 16 / sizeof(scalar_t);
+  constexpr int x = 16 / sizeof(cache_t);
+  constexpr bool IS_INT8_KV_CACHE = std::is_same<cache_t, int8_t>::value;
   float qk_max = -FLT_MAX;
 
   // Iterate over the key blocks.
@@ -212,16 +220,29 @@ __device__ void paged_attention_kernel(
       const int physical_block_offset = (thread_group_idx + i * WARP_SIZE) % BLOCK_SIZE;
       const int token_idx = block_idx * BLOCK_SIZE + physical_block_offset;
       K_vec k_vecs[NUM_VECS_PER_THREAD];
+      const cache_t* k_head_ptr = k_cache + physical_block_number * kv_block_stride
+                                          + kv_head_idx * kv_head_stride;
+      float k_scale = 1.f;
+      if (IS_INT8_KV_CACHE) {
+        k_scale = reinterpret_cast<const float*>(k_head_ptr + HEAD_SIZE * BLOCK_SIZE)[physical_block_offset];
+      }
 
 #pragma unroll
       for (int j = 0; j < NUM_VECS_PER_THREAD; j++) {
-        const scalar_t* k_ptr = k_cache + physical_block_number * kv_block_stride
-                                        + kv_head_idx * kv_head_stride
-                                        + physical_block_offset * x;
+        const cache_t* k_ptr = k_head_ptr + physical_block_offset * x;
         const int vec_idx = thread_group_offset + j * THREAD_GROUP_SIZE;
         const int offset1 = (vec_idx * VEC_SIZE) / x;
         const int offset2 = (vec_idx * VEC_SIZE) % x;
-        k_vecs[j] = *reinterpret_cast<const K_vec*>(k_ptr + offset1 * BLOCK_SIZE * x + offset2);
+        if (IS_INT8_KV_CACHE) {
+          const int8_t* k_quant = reinterpret_cast<const int8_t*>(k_ptr + offset1 * BLOCK_SIZE * x + offset2);
+          scalar_t* k_vec_ptr = reinterpret_cast<scalar_t*>(&k_vecs[j]);
+#pragma unroll
+          for (int e = 0; e < VEC_SIZE; e++) {
+            from_float(k_vec_ptr[e], k_quant[e] * k_scale);
+          }
+        } else {
+          k_vecs[j] = *reinterpret_cast<const K_vec*>(k_ptr + offset1 * BLOCK_SIZE * x + offset2);
+        }
       }
 
       // Compute dot product.
@@ -237,7 +258,7 @@ __device__ void paged_attention_kernel(
       if (thread_group_offset == 0) {
         // Store the partial reductions to shared memory.
         // NOTE(woosuk): It is required to zero out the masked logits.
-        const bool mask = token_idx >= context_len;
+        const bool mask = token_idx >= context_len || token_idx < window_start;
         logits[token_idx - start_token_idx] = mask ? 0.f : qk;
         // Update the max value.
         qk_max = mask ? qk_max : fmaxf(qk_max, qk);
@@ -270,7 +291,7 @@ __device__ void paged_attention_kernel(
   // Get the sum of the exp values.
   float exp_sum = 0.f;
   for (int i = thread_idx; i < num_tokens; i += NUM_THREADS) {
-    float val = __expf(logits[i] - qk_max);
+    float val = start_token_idx + i < window_start ? 0.f : __expf(logits[i] - qk_max);
     logits[i] = val;
     exp_sum += val;
   }
@@ -324,14 +345,32 @@ __device__ void paged_attention_kernel(
     L_vec logits_vec;
     from_float(logits_vec, *reinterpret_cast<Float_L_vec*>(logits + token_idx - start_token_idx));
 
-    const scalar_t* v_ptr = v_cache + physical_block_number * kv_block_stride
-                                    + kv_head_idx * kv_head_stride;
+    const cache_t* v_ptr = v_cache + physical_block_number * kv_block_stride
+                                   + kv_head_idx * kv_head_stride;
+    float v_scales[V_VEC_SIZE];
+    if (IS_INT8_KV_CACHE) {
+      const float* v_scales_ptr = reinterpret_cast<const float*>(v_ptr + HEAD_SIZE * BLOCK_SIZE);
+#pragma unroll
+      for (int j = 0; j < V_VEC_SIZE; j++) {
+        v_scales[j] = v_scales_ptr[physical_block_offset + j];
+      }
+    }
 #pragma unroll
     for (int i = 0; i < NUM_ROWS_PER_THREAD; i++) {
       const int row_idx = lane / NUM_V_VECS_PER_ROW + i * NUM_ROWS_PER_ITER;
       if (row_idx < HEAD_SIZE) {
         const int offset = row_idx * BLOCK_SIZE + physical_block_offset;
-        V_vec v_vec = *reinterpret_cast<const V_vec*>(v_ptr + offset);
+        V_vec v_vec;
+        if (IS_INT8_KV_CACHE) {
+          const int8_t* v_quant = reinterpret_cast<const int8_t*>(v_ptr + offset);
+          scalar_t* v_vec_ptr = reinterpret_cast<scalar_t*>(&v_vec);
+#pragma unroll
+          for (int j = 0; j < V_VEC_SIZE; j++) {
+            from_float(v_vec_ptr[j], v_quant[j] * v_scales[j]);
+          }
+        } else {
+          v_vec = *reinterpret_cast<const V_vec*>(v_ptr + offset);
+        }
         if (block_idx == num_context_blocks - 1) {
           // NOTE(woosuk): When v_vec contains the tokens that are out of the context,
           // we should explicitly zero out the values since they may contain NaNs.
@@ -412,14 +451,15 @@ __device__ void paged_attention_kernel(
 // Grid: (num_heads, num_seqs, 1).
 template<
   typename scalar_t,
+  typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS>
 __global__ void paged_attention_v1_kernel(
   scalar_t* __restrict__ out,             // [num_seqs, num_heads, head_size]
   const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const scalar_t* __restrict__ k_cache,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const scalar_t* __restrict__ v_cache,   // [num_blocks, num_kv_heads, head_size, block_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const uint32_t* __restrict__ block_tables,   // [num_seqs, max_num_blocks_per_seq]
@@ -429,16 +469,18 @@ __global__ void paged_attention_v1_kernel(
   const int q_stride,
   const int kv_block_stride,
   const int kv_head_stride,
-  const float softscapping) {
-  paged_attention_kernel<scalar_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>(
+  const float softscapping,
+  const int sliding_window) {
+  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>(
     /* exp_sums */ nullptr, /* max_logits */ nullptr,
     out, q, k_cache, v_cache, num_kv_heads, scale, block_tables, context_lens,
-    max_num_blocks_per_seq, alibi_slopes, q_stride, kv_block_stride, kv_head_stride, softscapping);
+    max_num_blocks_per_seq, alibi_slopes, q_stride, kv_block_stride, kv_head_stride, softscapping, sliding_window);
 }
 
 // Grid: (num_heads, num_seqs, max_num_partitions).
 template<
   typename scalar_t,
+  typename cache_t,
   int HEAD_SIZE,
   int BLOCK_SIZE,
   int NUM_THREADS,
@@ -448,8 +490,8 @@ __global__ void paged_attention_v2_kernel(
   float* __restrict__ max_logits,         // [num_seqs, num_heads, max_num_partitions]
   scalar_t* __restrict__ tmp_out,         // [num_seqs, num_heads, max_num_partitions, head_size]
   const scalar_t* __restrict__ q,         // [num_seqs, num_heads, head_size]
-  const scalar_t* __restrict__ k_cache,   // [num_blocks, num_kv_heads, head_size/x, block_size, x]
-  const scalar_t* __restrict__ v_cache,   // [num_blocks, num_kv_heads, head_size, block_size]
+  const cache_t* __restrict__ k_cache,    // [num_blocks, num_kv_heads, head_size/x, block_size, x]
+  const cache_t* __restrict__ v_cache,    // [num_blocks, num_kv_heads, head_size, block_size]
   const int num_kv_heads,                 // [num_heads]
   const float scale,
   const uint32_t* __restrict__ block_tables,   // [num_seqs, max_num_blocks_per_seq]
@@ -459,11 +501,12 @@ __global__ void paged_attention_v2_kernel(
   const int q_stride,
   const int kv_block_stride,
   const int kv_head_stride,
-  const float softscapping) {
-  paged_attention_kernel<scalar_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE>(
+  const float softscapping,
+  const int sliding_window) {
+  paged_attention_kernel<scalar_t, cache_t, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE>(
     exp_sums, max_logits, tmp_out, q, k_cache, v_cache, num_kv_heads, scale,
     block_tables, context_lens, max_num_blocks_per_seq, alibi_slopes,
-    q_stride, kv_block_stride, kv_head_stride, softscapping);
+    q_stride, kv_block_stride, kv_head_stride, softscapping, sliding_window);
 }
 
 // Grid: (num_heads, num_seqs).
@@ -569,14 +612,14 @@ __global__ void paged_attention_v2_reduce_kernel(
 
 #define LAUNCH_PAGED_ATTENTION_V1(HEAD_SIZE)                                                  \
   VLLM_DevFuncAttribute_SET_MaxDynamicSharedMemorySize(                                       \
-    ((void*)vllm::paged_attention_v1_kernel<T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>),          \
+    ((void*)vllm::paged_attention_v1_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>), \
     shared_mem_size);                                                                         \
-  vllm::paged_attention_v1_kernel<T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>                      \
+  vllm::paged_attention_v1_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS>             \
   <<<grid, block, shared_mem_size, stream>>>(                                                 \
     reinterpret_cast<T*>(out),                                                                \
     reinterpret_cast<T*>(query),                                                              \
-    reinterpret_cast<T*>(key_cache),                                                          \
-    reinterpret_cast<T*>(value_cache),                                                        \
+    reinterpret_cast<CACHE_T*>(key_cache),                                                    \
+    reinterpret_cast<CACHE_T*>(value_cache),                                                  \
     num_kv_heads,                                                                             \
     scale,                                                                                    \
     block_tables,                                                                             \
@@ -586,11 +629,13 @@ __global__ void paged_attention_v2_reduce_kernel(
     q_stride,                                                                                 \
     kv_block_stride,                                                                          \
     kv_head_stride,\
-    softscapping);
+    softscapping,                                                                             \
+    sliding_window);
 
 // TODO(woosuk): Tune NUM_THREADS.
 template<
   typename T,
+  typename CACHE_T,
   int BLOCK_SIZE,
   int NUM_THREADS = 128>
 void paged_attention_v1_launcher(
@@ -611,7 +656,8 @@ void paged_attention_v1_launcher(
   int q_stride,
   int kv_block_stride,
   int kv_head_stride,
-  float softscapping
+  float softscapping,
+  int sliding_window
   ) {
 
   // int thread_group_size = MAX(WARP_SIZE / BLOCK_SIZE, 1);
@@ -658,8 +704,8 @@ void paged_attention_v1_launcher(
   }
 }
 
-#define CALL_V1_LAUNCHER(T, BLOCK_SIZE)                             \
-  paged_attention_v1_launcher<T, BLOCK_SIZE>(                       \
+#define CALL_V1_LAUNCHER(T, CACHE_T, BLOCK_SIZE)                    \
+  paged_attention_v1_launcher<T, CACHE_T, BLOCK_SIZE>(              \
     out,                                                            \
     query,                                                          \
     key_cache,                                                      \
@@ -676,25 +722,33 @@ void paged_attention_v1_launcher(
     q_stride,                                                       \
     kv_block_stride,                                                \
     kv_head_stride, \
-    softscapping);
+    softscapping,                                                                             \
+    sliding_window);
 
 // NOTE(woosuk): To reduce the compilation time, we omitted block sizes
 // 1, 2, 4, 64, 128, 256.
-#define CALL_V1_LAUNCHER_BLOCK_SIZE(T)                              \
+#define CALL_V1_LAUNCHER_BLOCK_SIZE(T, CACHE_T)                     \
   switch (block_size) {                                             \
     case 8:                                                         \
-      CALL_V1_LAUNCHER(T, 8);                                       \
+      CALL_V1_LAUNCHER(T, CACHE_T, 8);                              \
       break;                                                        \
     case 16:                                                        \
-      CALL_V1_LAUNCHER(T, 16);                                      \
+      CALL_V1_LAUNCHER(T, CACHE_T, 16);                             \
       break;                                                        \
     case 32:                                                        \
-      CALL_V1_LAUNCHER(T, 32);                                      \
+      CALL_V1_LAUNCHER(T, CACHE_T, 32);                             \
       break;                                                        \
     default:                                                        \
       break;                                                        \
   }
 
+#define CALL_V1_LAUNCHER_CACHE_T(T)                                 \
+  if (int8_kv_cache) {                                              \
+    CALL_V1_LAUNCHER_BLOCK_SIZE(T, int8_t);                         \
+  } else {                                                          \
+    CALL_V1_LAUNCHER_BLOCK_SIZE(T, T);                              \
+  }
+
 extern "C" void paged_attention_v1(
   void *out,             // [num_seqs, num_heads, head_size]
   void *query,           // [num_seqs, num_heads, head_size]
@@ -716,26 +770,28 @@ extern "C" void paged_attention_v1(
   int32_t kv_head_stride,
 
   uint32_t dtype,      // 0 => f16; 1 => bf16; 2 => f32
-  float softscapping
+  float softscapping,
+  int32_t sliding_window, // 0 => attend to the whole context
+  bool int8_kv_cache   // int8 caches with per-slot scales
   ) {
   if (dtype == 2) {
-    CALL_V1_LAUNCHER_BLOCK_SIZE(float);
+    CALL_V1_LAUNCHER_CACHE_T(float);
   } else if (dtype == 0) {
-    CALL_V1_LAUNCHER_BLOCK_SIZE(uint16_t);
+    CALL_V1_LAUNCHER_CACHE_T(uint16_t);
   } else if (dtype == 1) {
-    CALL_V1_LAUNCHER_BLOCK_SIZE(__nv_bfloat16);
+    CALL_V1_LAUNCHER_CACHE_T(__nv_bfloat16);
   }
 }
 
 #define LAUNCH_PAGED_ATTENTION_V2(HEAD_SIZE)                                                  \
-  vllm::paged_attention_v2_kernel<T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE>      \
+  vllm::paged_attention_v2_kernel<T, CACHE_T, HEAD_SIZE, BLOCK_SIZE, NUM_THREADS, PARTITION_SIZE> \
   <<<grid, block, shared_mem_size, stream>>>(                                                 \
     exp_sums,                                                                                 \
     max_logits,                                                                               \
     tmp_out_ptr,                                                                              \
     reinterpret_cast<T*>(query),                                                              \
-    reinterpret_cast<T*>(key_cache),                                                          \
-    reinterpret_cast<T*>(value_cache),                                                        \
+    reinterpret_cast<CACHE_T*>(key_cache),                                                    \
+    reinterpret_cast<CACHE_T*>(value_cache),                                                  \
     num_kv_heads,                                                                             \
     scale,                                                                                    \
     block_tables,                                                                             \
@@ -745,7 +801,8 @@ extern "C" void paged_attention_v1(
     q_stride,                                                                                 \
     kv_block_stride,                                                                          \
     kv_head_stride,\
-    softscapping);                                                                          \
+    softscapping,                                                                             \
+    sliding_window);                                                                          \
   vllm::paged_attention_v2_reduce_kernel<T, HEAD_SIZE, NUM_THREADS, PARTITION_SIZE>           \
   <<<reduce_grid, block, reduce_shared_mem_size, stream>>>(                                   \
     reinterpret_cast<T*>(out),                                                                \
@@ -757,6 +814,7 @@ extern "C" void paged_attention_v1(
 
 template<
   typename T,
+  typename CACHE_T,
   int BLOCK_SIZE,
   int NUM_THREADS = 128,
   int PARTITION_SIZE = 512>
@@ -781,7 +839,8 @@ void paged_attention_v2_launcher(
   int q_stride,
   int kv_block_stride,
   int kv_head_stride,
-  float softscapping
+  float softscapping,
+  int sliding_window
   ) {
   // int thread_group_size = MAX(WARP_SIZE / BLOCK_SIZE, 1);
 
@@ -831,8 +890,8 @@ void paged_attention_v2_launcher(
   }
 }
 
-#define CALL_V2_LAUNCHER(T, BLOCK_SIZE)                             \
-  paged_attention_v2_launcher<T, BLOCK_SIZE>(                       \
+#define CALL_V2_LAUNCHER(T, CACHE_T, BLOCK_SIZE)                    \
+  paged_attention_v2_launcher<T, CACHE_T, BLOCK_SIZE>(              \
     out,                                                            \
     exp_sums,                                                       \
     max_logits,                                                     \
@@ -852,25 +911,33 @@ void paged_attention_v2_launcher(
     q_stride,                                                       \
     kv_block_stride,                                                \
     kv_head_stride,\
-    softscapping);
+    softscapping,                                                                             \
+    sliding_window);
 
 // NOTE(woosuk): To reduce the compilation time, we omitted block sizes
 // 1, 2, 4, 64, 128, 256.
-#define CALL_V2_LAUNCHER_BLOCK_SIZE(T)                              \
+#define CALL_V2_LAUNCHER_BLOCK_SIZE(T, CACHE_T)                     \
   switch (block_size) {                                             \
     case 8:                                                         \
-      CALL_V2_LAUNCHER(T, 8);                                       \
+      CALL_V2_LAUNCHER(T, CACHE_T, 8);                              \
       break;                                                        \
     case 16:                                                        \
-      CALL_V2_LAUNCHER(T, 16);                                      \
+      CALL_V2_LAUNCHER(T, CACHE_T, 16);                             \
       break;                                                        \
     case 32:                                                        \
-      CALL_V2_LAUNCHER(T, 32);                                      \
+      CALL_V2_LAUNCHER(T, CACHE_T, 32);                             \
       break;                                                        \
     default:                                                        \
       break;                                                        \
   }
 
+#define CALL_V2_LAUNCHER_CACHE_T(T)                                 \
+  if (int8_kv_cache) {                                              \
+    CALL_V2_LAUNCHER_BLOCK_SIZE(T, int8_t);                         \
+  } else {                                                          \
+    CALL_V2_LAUNCHER_BLOCK_SIZE(T, T);                              \
+  }
+
 extern "C" void paged_attention_v2(
   void *out,             // [num_seqs, num_heads, head_size]
   float *exp_sums,        // [num_seqs, num_heads, max_num_partitions]
@@ -895,14 +962,16 @@ extern "C" void paged_attention_v2(
   int32_t kv_head_stride,
 
   uint32_t dtype,      // 0 => f16; 1 => bf16; 2 => f32
-  float softscapping
+  float softscapping,
+  int32_t sliding_window, // 0 => attend to the whole context
+  bool int8_kv_cache   // int8 caches with per-slot scales
   ) {
   if (dtype == 2) {
-    CALL_V2_LAUNCHER_BLOCK_SIZE(float);
+    CALL_V2_LAUNCHER_CACHE_T(float);
   } else if (dtype == 0) {
-    CALL_V2_LAUNCHER_BLOCK_SIZE(uint16_t);
+    CALL_V2_LAUNCHER_CACHE_T(uint16_t);
   } else if (dtype == 1) {
-    CALL_V2_LAUNCHER_BLOCK_SIZE(__nv_bfloat16);
+    CALL_V2_LAUNCHER_CACHE_T(__nv_bfloat16);
   }
 }
 
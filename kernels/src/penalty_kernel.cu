@@ -0,0 +1,70 @@
+#include <stdint.h>
+
+#include <algorithm>
+
+namespace vllm {
+
+// One row of blocks per sequence, the threads of a row go over the distinct tokens of the
+// sequence. The tokens of a row are distinct, so no two threads write the same logit.
+__global__ void apply_penalties_kernel(
+  float* __restrict__ logits,            // [num_seqs, vocab_size]
+  const uint32_t* __restrict__ tokens,   // [num_seqs, num_tokens]
+  const uint32_t* __restrict__ counts,   // [num_seqs, num_tokens, 2]
+  const float* __restrict__ penalties,   // [num_seqs, 3]
+  const int64_t vocab_size,
+  const int64_t num_tokens) {
+  const int64_t seq = blockIdx.y;
+  const float repetition = penalties[seq * 3];
+  const float presence = penalties[seq * 3 + 1];
+  const float frequency = penalties[seq * 3 + 2];
+  for (int64_t idx = blockIdx.x * (int64_t)blockDim.x + threadIdx.x; idx < num_tokens;
+       idx += (int64_t)gridDim.x * blockDim.x) {
+    const int64_t entry = seq * num_tokens + idx;
+    const uint32_t token = tokens[entry];
+    // Occurrences in the repetition window and among the generated tokens
+    const uint32_t window = counts[entry * 2];
+    const uint32_t output = counts[entry * 2 + 1];
+    if ((int64_t)token >= vocab_size || (window == 0 && output == 0)) {
+      continue;
+    }
+    float* logit = logits + seq * vocab_size + token;
+    float value = *logit;
+    if (window > 0) {
+      value = value >= 0.f ? value / repetition : value * repetition;
+    }
+    if (output > 0) {
+      value -= presence + frequency * (float)output;
+    }
+    *logit = value;
+  }
+}
+
+} // namespace vllm
+
+extern "C" void apply_penalties(
+  void *logits,               // [num_seqs, vocab_size] f32
+  const uint32_t *tokens,     // [num_seqs, num_tokens]
+  const uint32_t *counts,     // [num_seqs, num_tokens, 2]
+  const float *penalties,     // [num_seqs, 3] repetition, presence, frequency
+
+  int64_t num_seqs,
+  int64_t vocab_size,
+  int64_t num_tokens
+  )
+{
+  if (num_seqs == 0 || num_tokens == 0) {
+    return;
+  }
+  dim3 block(256);
+  dim3 grid((unsigned int)std::min<int64_t>((num_tokens + 255) / 256, 65535),
+            (unsigned int)num_seqs);
+  const cudaStream_t stream = 0;
+
+  vllm::apply_penalties_kernel<<<grid, block, 0, stream>>>(
+    reinterpret_cast<float*>(logits),
+    tokens,
+    counts,
+    penalties,
+    vocab_size,
+    num_tokens);
+}
@@ -58,6 +58,96 @@ __global__ void reshape_and_cache_kernel(
   }
 }
 
+inline __device__ float to_float(float u) { return u; }
+inline __device__ float to_float(__half u) { return __half2float(u); }
+inline __device__ float to_float(__nv_bfloat16 u) { return __bfloat162float(u); }
+
+// Grid: (num_tokens, num_heads), block: INT8_THREADS.
+// INT8 caches hold, per block and head, the head_size * block_size quantized values followed by
+// one float scale per slot (absmax / 127 of the token's head vector), padded to 16 * block_size
+// bytes: key_cache is [num_blocks, num_heads, head_size/x + 1, block_size, x] and value_cache
+// [num_blocks, num_heads, head_size + 16, block_size].
+#define INT8_THREADS 128
+template<typename scalar_t>
+__global__ void reshape_and_cache_int8_kernel(
+  const scalar_t* __restrict__ key,           // [num_tokens, num_heads, head_size]
+  const scalar_t* __restrict__ value,         // [num_tokens, num_heads, head_size]
+  int8_t* __restrict__ key_cache,             // [num_blocks, num_heads, head_size/x + 1, block_size, x]
+  int8_t* __restrict__ value_cache,           // [num_blocks, num_heads, head_size + 16, block_size]
+  const int64_t* __restrict__ slot_mapping,   // [num_tokens]
+  const int key_stride,
+  const int value_stride,
+  const int num_heads,
+  const int head_size,
+  const int block_size,
+  const int x) {
+  const int64_t token_idx = blockIdx.x;
+  const int head_idx = blockIdx.y;
+  const int64_t slot_idx = slot_mapping[token_idx];
+  if (slot_idx < 0) {
+    // Padding token that should be ignored.
+    return;
+  }
+
+  const int64_t block_idx = slot_idx / block_size;
+  const int64_t block_offset = slot_idx % block_size;
+  const scalar_t* src_key = key + token_idx * key_stride + head_idx * head_size;
+  const scalar_t* src_value = value + token_idx * value_stride + head_idx * head_size;
+
+  // Absmax of the key and value vectors of the head.
+  __shared__ float key_max[INT8_THREADS];
+  __shared__ float value_max[INT8_THREADS];
+  float k_max = 0.f;
+  float v_max = 0.f;
+  for (int i = threadIdx.x; i < head_size; i += blockDim.x) {
+    k_max = fmaxf(k_max, fabsf(to_float(src_key[i])));
+    v_max = fmaxf(v_max, fabsf(to_float(src_value[i])));
+  }
+  key_max[threadIdx.x] = k_max;
+  value_max[threadIdx.x] = v_max;
+  __syncthreads();
+  for (int s = blockDim.x / 2; s > 0; s /= 2) {
+    if (threadIdx.x < s) {
+      key_max[threadIdx.x] = fmaxf(key_max[threadIdx.x], key_max[threadIdx.x + s]);
+      value_max[threadIdx.x] = fmaxf(value_max[threadIdx.x], value_max[threadIdx.x + s]);
+    }
+    __syncthreads();
+  }
+  const float k_scale = fmaxf(key_max[0], 1e-8f) / 127.f;
+  const float v_scale = fmaxf(value_max[0], 1e-8f) / 127.f;
+
+  // Both caches have head_size * block_size values and 16 * block_size scale bytes per head.
+  const int64_t head_bytes = (int64_t)(head_size + 16) * block_size;
+  int8_t* key_head = key_cache + (block_idx * num_heads + head_idx) * head_bytes;
+  int8_t* value_head = value_cache + (block_idx * num_heads + head_idx) * head_bytes;
+  for (int i = threadIdx.x; i < head_size; i += blockDim.x) {
+    const int x_idx = i / x;
+    const int x_offset = i % x;
+    const float k = fminf(fmaxf(rintf(to_float(src_key[i]) / k_scale), -127.f), 127.f);
+    const float v = fminf(fmaxf(rintf(to_float(src_value[i]) / v_scale), -127.f), 127.f);
+    key_head[x_idx * block_size * x + block_offset * x + x_offset] = static_cast<int8_t>(k);
+    value_head[i * block_size + block_offset] = static_cast<int8_t>(v);
+  }
+  if (threadIdx.x == 0) {
+    reinterpret_cast<float*>(key_head + head_size * block_size)[block_offset] = k_scale;
+    reinterpret_cast<float*>(value_head + head_size * block_size)[block_offset] = v_scale;
+  }
+}
+
+#define CALL_RESHAPE_AND_CACHE_INT8(T)                                \
+  vllm::reshape_and_cache_int8_kernel<T><<<grid, block, 0, stream>>>( \
+    reinterpret_cast<T*>(key),                                        \
+    reinterpret_cast<T*>(value),                                      \
+    reinterpret_cast<int8_t*>(key_cache),                             \
+    reinterpret_cast<int8_t*>(value_cache),                           \
+    slot_mapping,                                                     \
+    key_stride,                                                       \
+    value_stride,                                                     \
+    num_heads,                                                        \
+    head_size,                                                        \
+    block_size,                                                       \
+    x);
+
 #define CALL_RESHAPE_AND_CACHE(T)                                     \
   vllm::reshape_and_cache_kernel<T><<<grid, block, 0, stream>>>(      \
     reinterpret_cast<T*>(key),                                        \
@@ -90,9 +180,23 @@ extern "C" void reshape_and_cache(
   int32_t key_stride,
   int32_t value_stride,
 
-  uint32_t dtype      // 0 => f16; 1 => bf16; 2 => f32
+  uint32_t dtype,     // 0 => f16; 1 => bf16; 2 => f32
+  bool int8_kv_cache  // int8 caches with per-slot scales
   )
 {
+  if (int8_kv_cache) {
+    dim3 grid(num_tokens, num_heads);
+    dim3 block(INT8_THREADS);
+    const cudaStream_t stream = 0;
+    if (dtype == 0) {
+      CALL_RESHAPE_AND_CACHE_INT8(__half);
+    } else if (dtype == 1) {
+      CALL_RESHAPE_AND_CACHE_INT8(__nv_bfloat16);
+    } else if (dtype == 2) {
+      CALL_RESHAPE_AND_CACHE_INT8(float);
+    }
+    return;
+  }
   dim3 grid(num_tokens);
   dim3 block(std::min(num_heads * head_size, 512));
   const cudaStream_t stream = 0;
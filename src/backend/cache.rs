@@ -1,18 +1,21 @@
-use std::{collections::HashMap, iter::zip, ptr::NonNull};
+use std::collections::HashMap;
+#[cfg(feature = "cuda")]
+use std::{iter::zip, ptr::NonNull};
 
-use crate::{
-    backend::{get_or_load_func, Conjoined},
-    openai::responses::APIError,
-    try_api,
-};
-use candle_core::cuda_backend::CudaStorageSlice;
+#[cfg(feature = "cuda")]
+use crate::backend::{get_or_load_func, Conjoined};
+use crate::{openai::responses::APIError, try_api};
+#[cfg(feature = "cuda")]
 use candle_core::{
     cuda_backend::cudarc::driver::{CudaSlice, DevicePtr, LaunchAsync, LaunchConfig},
-    Device, IndexOp, Storage, Tensor,
+    cuda_backend::CudaStorageSlice,
+    IndexOp, Storage,
 };
+use candle_core::{Device, Tensor};
 
-use super::COPY_BLOCKS_KERNEL_NAME;
-use kernels::COPY_BLOCKS_KERNEL;
+use super::fallback::{naive_copy_blocks, naive_kernels_enabled};
+#[cfg(feature = "cuda")]
+use super::{copy_blocks_ptx, COPY_BLOCKS_KERNEL_NAME};
 
 /// # Safety
 /// Unsafe due to passing pointers
@@ -20,6 +23,30 @@ pub unsafe fn copy_blocks(
     key_caches: Vec<&mut Tensor>,
     value_caches: Vec<&mut Tensor>,
     block_mapping: HashMap<usize, Vec<usize>>,
+) -> Result<(), APIError> {
+    if naive_kernels_enabled() {
+        return naive_copy_blocks(
+            key_caches.into_iter().chain(value_caches).collect(),
+            block_mapping,
+        );
+    }
+    match key_caches.first().map(|cache| cache.device().clone()) {
+        Some(Device::Cpu) => super::cpu::copy_blocks(key_caches, value_caches, block_mapping),
+        #[cfg(feature = "metal")]
+        Some(Device::Metal(_)) => {
+            super::metal::copy_blocks(key_caches, value_caches, block_mapping)
+        }
+        #[cfg(feature = "cuda")]
+        Some(Device::Cuda(_)) => cuda_copy_blocks(key_caches, value_caches, block_mapping),
+        device => panic!("Expected the key caches on a supported device, got {device:?}."),
+    }
+}
+
+#[cfg(feature = "cuda")]
+unsafe fn cuda_copy_blocks(
+    key_caches: Vec<&mut Tensor>,
+    value_caches: Vec<&mut Tensor>,
+    block_mapping: HashMap<usize, Vec<usize>>,
 ) -> Result<(), APIError> {
     let cache_dev = key_caches.first().unwrap().device();
     let Device::Cuda(dev) = cache_dev else {
@@ -91,9 +118,15 @@ pub unsafe fn copy_blocks(
                 let ptr_value = *slice_value.slice(0..).device_ptr();
                 (ptr_key, ptr_value)
             }
+            // INT8 caches
+            (CudaStorageSlice::U8(slice_key), CudaStorageSlice::U8(slice_value)) => {
+                let ptr_key = *slice_key.slice(0..).device_ptr();
+                let ptr_value = *slice_value.slice(0..).device_ptr();
+                (ptr_key, ptr_value)
+            }
             _ => {
                 return Err(APIError::from(
-                    "only f32, f16 and bf16 input data type supported!",
+                    "only f32, f16, bf16 and u8 (int8) cache data types supported!",
                 ));
             }
         };
@@ -138,7 +171,7 @@ pub unsafe fn copy_blocks(
     let stream = try_api!(dev.fork_default_stream());
 
     let kernel = try_api!(get_or_load_func(
-        COPY_BLOCKS_KERNEL,
+        copy_blocks_ptx(dev),
         COPY_BLOCKS_KERNEL_NAME,
         key_caches.first().unwrap().dtype(),
         None,
@@ -161,14 +194,24 @@ pub unsafe fn copy_blocks(
     Ok(())
 }
 
+/// `copy_blocks` for latent caches (see `KvCacheLayout::Latent`), which have no native kernels.
+pub fn copy_latent_blocks(
+    latent_caches: Vec<&mut Tensor>,
+    block_mapping: HashMap<usize, Vec<usize>>,
+) -> Result<(), APIError> {
+    naive_copy_blocks(latent_caches, block_mapping)
+}
+
 pub fn swap_blocks(
     src: Tensor,
     dst: &mut Tensor,
     block_mapping: HashMap<usize, usize>,
 ) -> Result<(), APIError> {
-    let block_size_in_bytes = src.dtype().size_in_bytes() * src.dims()[0];
     match (src.device(), dst.device()) {
+        #[cfg(feature = "cuda")]
         (Device::Cuda(src_dev), Device::Cuda(dst_dev)) => {
+            // Caches are laid out as (num_blocks, ...), a block is one slice along the first dim.
+            let block_size_in_bytes = src.dtype().size_in_bytes() * src.elem_count() / src.dims()[0];
             if src_dev.ordinal() != dst_dev.ordinal() {
                 return Err(APIError::new(format!("Tensors must be on the same device to copy, got ordinals {} (src) and {} (dst).", src_dev.ordinal(), dst_dev.ordinal())))
             }
@@ -194,8 +237,13 @@ pub fn swap_blocks(
                     let ptr_dst = *slice_dst.slice(dst_layout.start_offset()..).device_ptr();
                     (ptr_src, ptr_dst)
                 }
+                (CudaStorageSlice::U8(slice_src), CudaStorageSlice::U8(slice_dst)) => {
+                    let ptr_src = *slice_src.slice(src_layout.start_offset()..).device_ptr();
+                    let ptr_dst = *slice_dst.slice(dst_layout.start_offset()..).device_ptr();
+                    (ptr_src, ptr_dst)
+                }
                 _ => {
-                    return Err(APIError::from("only f32, f16 and bf16 input data type supported!"));
+                    return Err(APIError::from("only f32, f16, bf16 and u8 (int8) cache data types supported!"));
                 }
             };
             // let src_ptr = src_storage.as_cuda_slice::<u8>().map_err(APIError::from)?.device_ptr() + TryInto::<u64>::try_into(src_layout.start_offset()).unwrap();
@@ -211,23 +259,13 @@ pub fn swap_blocks(
                 try_api!(src_dev.dtod_copy(&src_slice, &mut dst_slice));
             }
         }
-        (Device::Cpu, Device::Cuda(dst_dev)) => {
-            let (src_storage, _src_layout) = src.storage_and_layout();
-            let (dst_storage, dst_layout) = dst.storage_and_layout();
-            assert!(matches!(&*src_storage, Storage::Cpu(_)));
-            assert!(matches!(&*dst_storage, Storage::Cuda(_)));
-            let Storage::Cpu(src_storage) = &*src_storage else { unreachable!() };
-            let Storage::Cuda(dst_storage) = &*dst_storage else { unreachable!() };
-            let dst_ptr = dst_storage.as_cuda_slice::<u8>().map_err(APIError::from)?.device_ptr() + TryInto::<u64>::try_into(dst_layout.start_offset()).unwrap();
-            let src_slice = try_api!(src_storage.as_slice());
-
+        // Swaps through host memory copy one block at a time, `to_device` does the htod/dtoh transfer.
+        // Metal shares memory with the host, its copies within the device take the same path.
+        (Device::Cpu, Device::Cuda(_)) | (Device::Cuda(_), Device::Cpu) | (Device::Cpu, Device::Cpu)
+        | (Device::Cpu, Device::Metal(_)) | (Device::Metal(_), Device::Cpu) | (Device::Metal(_), Device::Metal(_)) => {
             for (src_block_number, dst_block_number) in block_mapping {
-                let src_offset = src_block_number * block_size_in_bytes;
-                let dst_offset: u64 = (dst_block_number * block_size_in_bytes).try_into().unwrap();
-                // u8s because we copy by bytes
-                let mut dst_slice: CudaSlice<u8> = unsafe { dst_dev.upgrade_device_ptr(dst_ptr+dst_offset, block_size_in_bytes) };
-
-                try_api!(dst_dev.htod_sync_copy_into(&src_slice[src_offset..src_offset+block_size_in_bytes], &mut dst_slice));
+                let block = try_api!(try_api!(src.narrow(0, src_block_number, 1)).to_device(dst.device()));
+                try_api!(dst.slice_set(&block, 0, dst_block_number));
             }
         }
         (src, dst) => {
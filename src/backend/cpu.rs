@@ -0,0 +1,403 @@
+//! Paged attention, `reshape_and_cache` and `copy_blocks` on the CPU, for `--cpu`. The caches
+//! have the same layouts as for the CUDA kernels:
+//!   key_cache:   [num_blocks, num_kv_heads, head_size / x, block_size, x]
+//!   value_cache: [num_blocks, num_kv_heads, head_size, block_size]
+//! The work is split over the rayon thread pool, one (sequence, head) per task for attention
+//! and one block per task for the cache writes. Dot products run over eight accumulators so
+//! that they are vectorized on stable Rust.
+
+use std::{collections::HashMap, iter::zip};
+
+use candle_core::{
+    bail, CpuStorage, DType, InplaceOp1, InplaceOp2, Layout, Result, Shape, Storage, Tensor,
+    WithDType,
+};
+use half::{bf16, f16};
+use rayon::prelude::*;
+
+use crate::{openai::responses::APIError, try_api};
+
+/// Element types of the caches, attention is computed in f32.
+trait CacheElem: WithDType + Send + Sync {
+    fn to_f32(self) -> f32;
+    fn from_f32(v: f32) -> Self;
+}
+
+impl CacheElem for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+}
+
+impl CacheElem for f16 {
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+    fn from_f32(v: f32) -> Self {
+        f16::from_f32(v)
+    }
+}
+
+impl CacheElem for bf16 {
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+    fn from_f32(v: f32) -> Self {
+        bf16::from_f32(v)
+    }
+}
+
+/// Dot product with eight independent accumulators, which the compiler turns into SIMD.
+fn dot<T: CacheElem>(a: &[f32], b: &[T]) -> f32 {
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let tail = zip(a_chunks.remainder(), b_chunks.remainder())
+        .map(|(a, b)| a * b.to_f32())
+        .sum::<f32>();
+    let mut acc = [0f32; 8];
+    for (a, b) in zip(a_chunks, b_chunks) {
+        for ((acc, a), b) in acc.iter_mut().zip(a).zip(b) {
+            *acc += a * b.to_f32();
+        }
+    }
+    acc.iter().sum::<f32>() + tail
+}
+
+/// The elements of a contiguous tensor on the CPU, from its start offset.
+fn cpu_slice<'a, T: WithDType>(
+    storage: &'a Storage,
+    layout: &Layout,
+    name: &str,
+) -> Result<&'a [T]> {
+    let Storage::Cpu(storage) = storage else {
+        bail!("{name} is not on the CPU")
+    };
+    let Some((start, end)) = layout.contiguous_offsets() else {
+        bail!("{name} must be contiguous")
+    };
+    Ok(&storage.as_slice::<T>()?[start..end])
+}
+
+/// Decoding attention of one query token per sequence, see [`super::paged_attention`].
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn paged_attention(
+    q: &CpuStorage,
+    q_l: &Layout,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    softmax_scale: f32,
+    softcapping: f32,
+    sliding_window: usize,
+) -> Result<(CpuStorage, Shape)> {
+    let attention = CpuPagedAttention {
+        key_cache,
+        value_cache,
+        block_tables: block_tables.to_dtype(DType::I64)?.to_vec2::<i64>()?,
+        context_lens: context_lens.to_dtype(DType::I64)?.to_vec1::<i64>()?,
+        softmax_scale,
+        softcapping,
+        sliding_window,
+    };
+    match q {
+        CpuStorage::F32(q) => attention.fwd(q, q_l),
+        CpuStorage::F16(q) => attention.fwd(q, q_l),
+        CpuStorage::BF16(q) => attention.fwd(q, q_l),
+        q => bail!(
+            "paged-attention is only supported for f32/f16/bf16 ({:?})",
+            q.dtype()
+        ),
+    }
+}
+
+struct CpuPagedAttention<'a> {
+    key_cache: &'a Tensor,
+    value_cache: &'a Tensor,
+    block_tables: Vec<Vec<i64>>,
+    context_lens: Vec<i64>,
+    softmax_scale: f32,
+    softcapping: f32,
+    sliding_window: usize,
+}
+
+impl CpuPagedAttention<'_> {
+    fn fwd<T: CacheElem>(&self, q: &[T], q_l: &Layout) -> Result<(CpuStorage, Shape)> {
+        if self.key_cache.dtype() == DType::U8 {
+            bail!("the int8 KV cache requires the CUDA kernels");
+        }
+        let (num_seqs, num_heads, head_size) = q_l.shape().dims3()?;
+        let (q_offset, q_stride) = (q_l.start_offset(), q_l.stride());
+        let (_, num_kv_heads, _, block_size, x) = self.key_cache.dims5()?;
+        let num_queries_per_kv = num_heads / num_kv_heads;
+        let head_stride = head_size * block_size;
+        let block_stride = num_kv_heads * head_stride;
+
+        let (key_storage, key_l) = self.key_cache.storage_and_layout();
+        let key_cache = cpu_slice::<T>(&key_storage, key_l, "key_cache")?;
+        let (value_storage, value_l) = self.value_cache.storage_and_layout();
+        let value_cache = cpu_slice::<T>(&value_storage, value_l, "value_cache")?;
+
+        let mut out = vec![T::from_f32(0.); num_seqs * num_heads * head_size];
+        out.par_chunks_mut(head_size)
+            .enumerate()
+            .for_each(|(i, out)| {
+                let (seq, head) = (i / num_heads, i % num_heads);
+                let kv_head_offset = head / num_queries_per_kv * head_stride;
+                let table = &self.block_tables[seq];
+                let context_len = self.context_lens[seq] as usize;
+                let q = (0..head_size)
+                    .map(|d| {
+                        let idx =
+                            q_offset + seq * q_stride[0] + head * q_stride[1] + d * q_stride[2];
+                        q[idx].to_f32() * self.softmax_scale
+                    })
+                    .collect::<Vec<_>>();
+
+                // Logits, block by block: each row of `x` elements of the query is multiplied
+                // with the rows of all the slots of the block, which are contiguous
+                let mut logits = vec![0f32; context_len];
+                for (block, logits) in zip(table, logits.chunks_mut(block_size)) {
+                    let k = &key_cache[*block as usize * block_stride + kv_head_offset..];
+                    for (row, q) in q.chunks(x).enumerate() {
+                        let k = &k[row * block_size * x..];
+                        for (slot, logit) in logits.iter_mut().enumerate() {
+                            *logit += dot(q, &k[slot * x..(slot + 1) * x]);
+                        }
+                    }
+                }
+                if self.softcapping != 1f32 {
+                    for logit in logits.iter_mut() {
+                        *logit = (*logit / self.softcapping).tanh() * self.softcapping;
+                    }
+                }
+                // Tokens before the window get a zero probability
+                let window_start = match self.sliding_window {
+                    0 => 0,
+                    window => context_len.saturating_sub(window),
+                };
+                let max_logit = logits[window_start..]
+                    .iter()
+                    .copied()
+                    .fold(f32::NEG_INFINITY, f32::max);
+                let mut exp_sum = 0f32;
+                for (t, logit) in logits.iter_mut().enumerate() {
+                    *logit = if t < window_start {
+                        0f32
+                    } else {
+                        (*logit - max_logit).exp()
+                    };
+                    exp_sum += *logit;
+                }
+
+                // Weighted sum of the values, a value row holds one element of every slot
+                let mut acc = vec![0f32; head_size];
+                for (block, probs) in zip(table, logits.chunks(block_size)) {
+                    let v = &value_cache[*block as usize * block_stride + kv_head_offset..];
+                    for (d, acc) in acc.iter_mut().enumerate() {
+                        *acc += dot(probs, &v[d * block_size..d * block_size + probs.len()]);
+                    }
+                }
+                let inv_sum = 1f32 / (exp_sum + 1e-6);
+                for (out, acc) in zip(out, acc) {
+                    *out = T::from_f32(acc * inv_sum);
+                }
+            });
+        Ok((
+            T::to_cpu_storage_owned(out),
+            Shape::from((num_seqs, num_heads, head_size)),
+        ))
+    }
+}
+
+/// Write the rows of a key or value `(num_tokens, num_heads, head_size)` to their slots of a
+/// cache, applied in place to the cache.
+struct WriteSlots<'a> {
+    /// Tokens of each block, with their offset in the block
+    tokens_by_block: &'a HashMap<usize, Vec<(usize, usize)>>,
+    key_layout: bool,
+}
+
+impl WriteSlots<'_> {
+    fn write<T: Copy + Send + Sync>(
+        &self,
+        cache: &mut [T],
+        cache_l: &Layout,
+        src: &[T],
+        src_l: &Layout,
+    ) -> Result<()> {
+        let (_, num_heads, head_size) = src_l.shape().dims3()?;
+        let dims = cache_l.dims();
+        let (block_size, x) = if self.key_layout {
+            (dims[3], dims[4])
+        } else {
+            (dims[3], 1)
+        };
+        let (src_offset, src_stride) = (src_l.start_offset(), src_l.stride());
+        let block_numel = num_heads * head_size * block_size;
+        let cache = &mut cache[cache_l.start_offset()..];
+        cache
+            .par_chunks_mut(block_numel)
+            .enumerate()
+            .for_each(|(block, cache)| {
+                let Some(tokens) = self.tokens_by_block.get(&block) else {
+                    return;
+                };
+                for &(token, block_offset) in tokens {
+                    for head in 0..num_heads {
+                        let cache = &mut cache[head * head_size * block_size..];
+                        for d in 0..head_size {
+                            let idx = if self.key_layout {
+                                (d / x) * block_size * x + block_offset * x + d % x
+                            } else {
+                                d * block_size + block_offset
+                            };
+                            cache[idx] = src[src_offset
+                                + token * src_stride[0]
+                                + head * src_stride[1]
+                                + d * src_stride[2]];
+                        }
+                    }
+                }
+            });
+        Ok(())
+    }
+}
+
+impl InplaceOp2 for WriteSlots<'_> {
+    fn name(&self) -> &'static str {
+        "reshape-and-cache"
+    }
+
+    fn cpu_fwd(
+        &self,
+        cache: &mut CpuStorage,
+        cache_l: &Layout,
+        src: &CpuStorage,
+        src_l: &Layout,
+    ) -> Result<()> {
+        if !cache_l.is_contiguous() {
+            bail!("reshape_and_cache needs contiguous caches")
+        }
+        match (cache, src) {
+            (CpuStorage::F32(cache), CpuStorage::F32(src)) => {
+                self.write(cache, cache_l, src, src_l)
+            }
+            (CpuStorage::F16(cache), CpuStorage::F16(src)) => {
+                self.write(cache, cache_l, src, src_l)
+            }
+            (CpuStorage::BF16(cache), CpuStorage::BF16(src)) => {
+                self.write(cache, cache_l, src, src_l)
+            }
+            (cache, src) => bail!(
+                "reshape_and_cache on the CPU needs f32, f16 or bf16 caches of the key dtype, got \
+                {:?} for {:?}",
+                cache.dtype(),
+                src.dtype()
+            ),
+        }
+    }
+}
+
+/// Write the keys and values of the tokens to the slots of `slot_mapping`, see
+/// [`super::reshape_and_cache`].
+pub(crate) fn reshape_and_cache(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    let block_size = value_cache.dim(3)?;
+    let slots = slot_mapping
+        .flatten_all()?
+        .to_dtype(DType::I64)?
+        .to_vec1::<i64>()?;
+    let mut tokens_by_block = HashMap::<usize, Vec<(usize, usize)>>::new();
+    // Negative slots are padding
+    for (token, slot) in slots.into_iter().enumerate().filter(|(_, slot)| *slot >= 0) {
+        let slot = slot as usize;
+        tokens_by_block
+            .entry(slot / block_size)
+            .or_default()
+            .push((token, slot % block_size));
+    }
+    key_cache.inplace_op2(
+        key,
+        &WriteSlots {
+            tokens_by_block: &tokens_by_block,
+            key_layout: true,
+        },
+    )?;
+    value_cache.inplace_op2(
+        value,
+        &WriteSlots {
+            tokens_by_block: &tokens_by_block,
+            key_layout: false,
+        },
+    )
+}
+
+/// Copy whole blocks of a cache in place, `(src, dst)` pairs.
+struct CopyBlocks<'a> {
+    pairs: &'a [(usize, usize)],
+}
+
+impl CopyBlocks<'_> {
+    fn copy<T: Copy>(&self, cache: &mut [T], layout: &Layout) {
+        let numel_per_block = layout.shape().elem_count() / layout.dims()[0];
+        let cache = &mut cache[layout.start_offset()..];
+        for &(src, dst) in self.pairs {
+            cache.copy_within(
+                src * numel_per_block..(src + 1) * numel_per_block,
+                dst * numel_per_block,
+            );
+        }
+    }
+}
+
+impl InplaceOp1 for CopyBlocks<'_> {
+    fn name(&self) -> &'static str {
+        "copy-blocks"
+    }
+
+    fn cpu_fwd(&self, cache: &mut CpuStorage, layout: &Layout) -> Result<()> {
+        if !layout.is_contiguous() {
+            bail!("copy_blocks needs contiguous caches")
+        }
+        match cache {
+            CpuStorage::U8(cache) => self.copy(cache, layout),
+            CpuStorage::F32(cache) => self.copy(cache, layout),
+            CpuStorage::F16(cache) => self.copy(cache, layout),
+            CpuStorage::BF16(cache) => self.copy(cache, layout),
+            cache => bail!(
+                "copy_blocks is only supported for u8, f32, f16 and bf16 caches ({:?})",
+                cache.dtype()
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Copy the blocks of `block_mapping` in every key and value cache, one layer per task.
+pub(crate) fn copy_blocks(
+    key_caches: Vec<&mut Tensor>,
+    value_caches: Vec<&mut Tensor>,
+    block_mapping: HashMap<usize, Vec<usize>>,
+) -> std::result::Result<(), APIError> {
+    let pairs = block_mapping
+        .iter()
+        .flat_map(|(src, dsts)| dsts.iter().map(|dst| (*src, *dst)))
+        .collect::<Vec<_>>();
+    let op = CopyBlocks { pairs: &pairs };
+    let caches = key_caches
+        .into_iter()
+        .chain(value_caches)
+        .collect::<Vec<_>>();
+    try_api!(caches
+        .par_iter()
+        .try_for_each(|cache| cache.inplace_op1(&op)));
+    Ok(())
+}
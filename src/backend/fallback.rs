@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    iter::zip,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{openai::responses::APIError, try_api};
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+
+#[cfg(feature = "cuda")]
+use super::{copy_blocks_ptx, get_or_load_func, COPY_BLOCKS_KERNEL_NAME};
+use super::{paged_attention, reshape_and_cache};
+
+/// Set once at startup when the native paged attention kernels are unusable on the GPU.
+static NAIVE_KERNELS: AtomicBool = AtomicBool::new(false);
+
+/// Whether the cache and attention ops run on the naive candle implementation instead of
+/// the native kernels.
+pub fn naive_kernels_enabled() -> bool {
+    NAIVE_KERNELS.load(Ordering::Relaxed)
+}
+
+/// Run the native paged attention kernels (CUDA, Metal or CPU) once on a small batch and compare
+/// them against the naive implementation. Kernels built for another architecture (e.g. sm_61)
+/// fail to launch or produce garbage; in that case fall back to the naive implementation with a
+/// warning, or return an error when `require_native` is set.
+pub fn probe_native_kernels(
+    device: &Device,
+    dtype: DType,
+    require_native: bool,
+) -> std::result::Result<(), APIError> {
+    let Err(e) = check_native_kernels(device, dtype) else {
+        return Ok(());
+    };
+    if require_native {
+        return Err(APIError::new(format!(
+            "Native paged attention kernels are unusable on this device: {e}"
+        )));
+    }
+    tracing::warn!(
+        error = %e,
+        "Native paged attention kernels are unusable on this device, falling back to a naive \
+         attention implementation with much lower throughput. Pass --require-native-kernels to \
+         abort instead."
+    );
+    NAIVE_KERNELS.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+fn check_native_kernels(device: &Device, dtype: DType) -> std::result::Result<(), APIError> {
+    let (num_blocks, block_size, num_kv_heads, num_heads, head_size) = (4, 16, 2, 4, 64);
+    let x = 16 / dtype.size_in_bytes();
+    let num_tokens = 20;
+    // A single sequence spanning blocks 2 and 0
+    let block_table = [2u32, 0];
+    let slots = (0..num_tokens)
+        .map(|t| (block_table[t / block_size] as usize * block_size + t % block_size) as i64)
+        .collect::<Vec<_>>();
+
+    let randn = |shape: (usize, usize, usize)| -> Result<Tensor> {
+        Tensor::randn(0f32, 1f32, shape, device)?.to_dtype(dtype)
+    };
+    let key = try_api!(randn((num_tokens, num_kv_heads, head_size)));
+    let value = try_api!(randn((num_tokens, num_kv_heads, head_size)));
+    let query = try_api!(randn((1, num_heads, head_size)));
+    let slot_mapping = try_api!(Tensor::from_vec(slots, num_tokens, device));
+    let block_tables = try_api!(Tensor::from_vec(block_table.to_vec(), (1, 2), device));
+    let context_lens = try_api!(Tensor::from_vec(vec![num_tokens as u32], 1, device));
+    let scale = 1f32 / (head_size as f32).sqrt();
+
+    let run = |naive: bool| -> Result<Tensor> {
+        let key_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size / x, block_size, x),
+            dtype,
+            device,
+        )?;
+        let value_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size, block_size),
+            dtype,
+            device,
+        )?;
+        let out = if naive {
+            naive_reshape_and_cache(&key, &value, &key_cache, &value_cache, &slot_mapping)?;
+            naive_paged_attention(
+                &query,
+                &key_cache,
+                &value_cache,
+                &block_tables,
+                &context_lens,
+                scale,
+                1f32,
+                0,
+            )?
+        } else {
+            reshape_and_cache(&key, &value, &key_cache, &value_cache, &slot_mapping)?;
+            paged_attention(
+                &query,
+                &key_cache,
+                &value_cache,
+                &block_tables,
+                &context_lens,
+                num_tokens,
+                scale,
+                1f32,
+                0,
+            )?
+        };
+        device.synchronize()?;
+        out.to_dtype(DType::F32)
+    };
+    let native = try_api!(run(false));
+    let reference = try_api!(run(true));
+    let diff = try_api!(try_api!(try_api!((native - reference)).abs()).max_all());
+    let diff = try_api!(diff.to_scalar::<f32>());
+    // NaN also ends up here
+    if !(diff < 1e-2) {
+        return Err(APIError::new(format!(
+            "paged attention output differs from the reference by {diff}"
+        )));
+    }
+
+    // copy_blocks is JIT compiled from PTX, which fails for architectures the PTX does not support
+    #[cfg(feature = "cuda")]
+    if let Device::Cuda(dev) = device {
+        get_or_load_func(
+            copy_blocks_ptx(dev),
+            COPY_BLOCKS_KERNEL_NAME,
+            dtype,
+            None,
+            dev,
+        )?;
+    }
+    Ok(())
+}
+
+/// The naive path stores the paged caches slot-major, both viewed as
+/// `(num_blocks * block_size, num_kv_heads, head_size)`. The element count per block matches the
+/// native layouts, so block allocation, copy and swap are unaffected.
+fn slot_view(cache: &Tensor, num_kv_heads: usize, head_size: usize) -> Result<Tensor> {
+    cache.reshape(((), num_kv_heads, head_size))
+}
+
+/// Write the rows of `src` (one per token) to the slots of `slot_mapping` in `slots_view`: runs
+/// of consecutive slots are written with a single copy.
+fn write_slots(src: &Tensor, slots_view: &Tensor, slot_mapping: &Tensor) -> Result<()> {
+    let slots = slot_mapping
+        .flatten_all()?
+        .to_dtype(DType::I64)?
+        .to_vec1::<i64>()?;
+    let mut start = 0;
+    while start < slots.len() {
+        let mut end = start + 1;
+        while slots[start] >= 0 && end < slots.len() && slots[end] == slots[end - 1] + 1 {
+            end += 1;
+        }
+        // Negative slots are padding
+        if slots[start] >= 0 {
+            let rows = src.narrow(0, start, end - start)?.contiguous()?;
+            slots_view.slice_set(&rows, 0, slots[start] as usize)?;
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Naive `reshape_and_cache`.
+pub(crate) fn naive_reshape_and_cache(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    let (_, num_kv_heads, head_size) = key.dims3()?;
+    write_slots(
+        key,
+        &slot_view(key_cache, num_kv_heads, head_size)?,
+        slot_mapping,
+    )?;
+    write_slots(
+        value,
+        &slot_view(value_cache, num_kv_heads, head_size)?,
+        slot_mapping,
+    )
+}
+
+/// Write the latents `(num_tokens, latent_dim)` of multi-head latent attention to a latent cache
+/// `(num_blocks, 1, latent_dim / x, block_size, x)`, viewed slot-major like the naive caches.
+pub(crate) fn naive_reshape_and_cache_latent(
+    latent: &Tensor,
+    latent_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    let (_, latent_dim) = latent.dims2()?;
+    write_slots(
+        latent,
+        &latent_cache.reshape(((), latent_dim))?,
+        slot_mapping,
+    )
+}
+
+/// Slots of the first `context_len` tokens of a sequence with the block `table`.
+fn context_slots(
+    table: &[i64],
+    context_len: usize,
+    block_size: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let slots = (0..context_len)
+        .map(|t| (table[t / block_size] as usize * block_size + t % block_size) as u32)
+        .collect::<Vec<_>>();
+    Tensor::from_vec(slots, context_len, device)
+}
+
+/// Naive decoding attention over the paged caches, one sequence at a time, over the last
+/// `sliding_window` tokens of each context if it is not 0.
+///
+/// Returns a tensor of shape `(num_sequences, num_heads_q, head_size)`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn naive_paged_attention(
+    q: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    softmax_scale: f32,
+    softcapping: f32,
+    sliding_window: usize,
+) -> Result<Tensor> {
+    let (_, num_heads, head_size) = q.dims3()?;
+    let (_, num_kv_heads, _, block_size) = value_cache.dims4()?;
+    let num_queries_per_kv = num_heads / num_kv_heads;
+    let key_slots = slot_view(key_cache, num_kv_heads, head_size)?;
+    let value_slots = slot_view(value_cache, num_kv_heads, head_size)?;
+    let block_tables = block_tables.to_dtype(DType::I64)?.to_vec2::<i64>()?;
+    let context_lens = context_lens.to_dtype(DType::I64)?.to_vec1::<i64>()?;
+
+    let mut outputs = Vec::with_capacity(context_lens.len());
+    for (i, (table, context_len)) in zip(block_tables, context_lens).enumerate() {
+        let context_len = context_len as usize;
+        let window_start = match sliding_window {
+            0 => 0,
+            window => context_len.saturating_sub(window),
+        };
+        let slots = context_slots(&table, context_len, block_size, q.device())?.narrow(
+            0,
+            window_start,
+            context_len - window_start,
+        )?;
+        let context_len = context_len - window_start;
+        // (num_heads, context_len, head_size), query head h attends to kv head h / num_queries_per_kv
+        let gather = |slots_view: &Tensor| -> Result<Tensor> {
+            slots_view
+                .index_select(&slots, 0)?
+                .transpose(0, 1)?
+                .unsqueeze(1)?
+                .broadcast_as((num_kv_heads, num_queries_per_kv, context_len, head_size))?
+                .contiguous()?
+                .reshape((num_heads, context_len, head_size))
+        };
+        let k = gather(&key_slots)?;
+        let v = gather(&value_slots)?;
+
+        // (num_heads, 1, head_size)
+        let q = q.i(i)?.unsqueeze(1)?.contiguous()?;
+        let att = (q.matmul(&k.t()?)? * f64::from(softmax_scale))?;
+        let att = if softcapping != 1f32 {
+            let softcapping = f64::from(softcapping);
+            ((att / softcapping)?.tanh()? * softcapping)?
+        } else {
+            att
+        };
+        let probs = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?;
+        let out = probs.to_dtype(q.dtype())?.matmul(&v)?;
+        outputs.push(out.squeeze(1)?);
+    }
+    Tensor::stack(&outputs, 0)
+}
+
+/// Decoding attention of multi-head latent attention over a latent cache, one sequence at a
+/// time. Every query head attends to the same latents, which are the keys and, truncated to
+/// their first `value_dim` elements, the values.
+///
+/// Returns a tensor of shape `(num_sequences, num_heads_q, value_dim)`.
+pub(crate) fn naive_latent_attention(
+    q: &Tensor,
+    latent_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    softmax_scale: f32,
+    value_dim: usize,
+) -> Result<Tensor> {
+    let (_, _, latent_dim) = q.dims3()?;
+    let block_size = latent_cache.dim(3)?;
+    let latent_slots = latent_cache.reshape(((), latent_dim))?;
+    let block_tables = block_tables.to_dtype(DType::I64)?.to_vec2::<i64>()?;
+    let context_lens = context_lens.to_dtype(DType::I64)?.to_vec1::<i64>()?;
+
+    let mut outputs = Vec::with_capacity(context_lens.len());
+    for (i, (table, context_len)) in zip(block_tables, context_lens).enumerate() {
+        let slots = context_slots(&table, context_len as usize, block_size, q.device())?;
+        // (context_len, latent_dim)
+        let latents = latent_slots.index_select(&slots, 0)?;
+        // (num_heads, latent_dim)
+        let q = q.i(i)?.contiguous()?;
+        let att = (q.matmul(&latents.t()?)? * f64::from(softmax_scale))?;
+        let probs = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?;
+        let values = latents.narrow(1, 0, value_dim)?;
+        outputs.push(probs.to_dtype(q.dtype())?.matmul(&values)?);
+    }
+    Tensor::stack(&outputs, 0)
+}
+
+/// Naive `copy_blocks`, one block copy per mapping entry and cache.
+pub(crate) fn naive_copy_blocks(
+    caches: Vec<&mut Tensor>,
+    block_mapping: HashMap<usize, Vec<usize>>,
+) -> std::result::Result<(), APIError> {
+    for cache in caches {
+        for (src, dsts) in &block_mapping {
+            // Copied out first, the source and destination share the same storage
+            let block = try_api!(try_api!(cache.narrow(0, *src, 1)).copy());
+            for dst in dsts {
+                try_api!(cache.slice_set(&block, 0, *dst));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Dequantization of FP8 (E4M3) weights with per-tensor or per-channel scales, for the W8A16
+//! linear layers of FP8 checkpoints. Candle has no FP8 dtype, the weights are kept as their
+//! bytes in U8 tensors.
+
+#[cfg(feature = "cuda")]
+use candle_core::{
+    backend::BackendStorage,
+    cuda_backend::{
+        cudarc::driver::{DevicePtr, DeviceRepr},
+        CudaDType, WrapErr,
+    },
+    CudaStorage,
+};
+use candle_core::{bail, CpuStorage, DType, Device, Layout, Result, Shape, Tensor};
+use half::{bf16, f16};
+#[cfg(feature = "cuda")]
+use kernels::ffi;
+use rayon::prelude::*;
+
+use super::fallback::naive_kernels_enabled;
+
+/// The value of FP8 E4M3 bits, the `fn` variant of the checkpoints (no infinities, a single NaN
+/// of each sign).
+pub fn fp8_e4m3_to_f32(bits: u8) -> f32 {
+    let sign = if bits & 0x80 != 0 { -1. } else { 1. };
+    let exponent = (bits >> 3) & 0xF;
+    let mantissa = (bits & 0x7) as f32;
+    match exponent {
+        0 => sign * mantissa / 8. * 2f32.powi(-6),
+        0xF if mantissa == 7. => f32::NAN,
+        _ => sign * (1. + mantissa / 8.) * 2f32.powi(exponent as i32 - 7),
+    }
+}
+
+struct Fp8Dequantize {
+    dtype: DType,
+}
+
+impl Fp8Dequantize {
+    fn cpu_fwd_t<T: candle_core::WithDType>(
+        weight: &[u8],
+        scale: &[f32],
+        cols: usize,
+        from_f32: impl Fn(f32) -> T + Sync,
+    ) -> Vec<T> {
+        let table = (0..=255u8).map(fp8_e4m3_to_f32).collect::<Vec<_>>();
+        let mut out = vec![T::from_f64(0.); weight.len()];
+        out.par_chunks_mut(cols)
+            .zip(weight.par_chunks(cols))
+            .enumerate()
+            .for_each(|(row, (out, weight))| {
+                let scale = scale[if scale.len() == 1 { 0 } else { row }];
+                for (out, &bits) in out.iter_mut().zip(weight) {
+                    *out = from_f32(table[bits as usize] * scale);
+                }
+            });
+        out
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd_t<T: CudaDType + DeviceRepr>(
+        &self,
+        weight: &CudaStorage,
+        weight_l: &Layout,
+        scale: &CudaStorage,
+        scale_l: &Layout,
+        internal_type: u32,
+    ) -> Result<(CudaStorage, Shape)> {
+        let dev = weight.device();
+        let (rows, cols) = weight_l.shape().dims2()?;
+        let weight = weight.as_cuda_slice::<u8>()?;
+        let weight = weight.slice(weight_l.start_offset()..);
+        let scale = scale.as_cuda_slice::<f32>()?;
+        let scale = scale.slice(scale_l.start_offset()..);
+        let out = unsafe { dev.alloc::<T>(rows * cols) }.w()?;
+
+        unsafe {
+            ffi::fp8_dequantize(
+                *weight.device_ptr() as *const core::ffi::c_void,
+                *scale.device_ptr() as *const f32,
+                *out.device_ptr() as *const core::ffi::c_void,
+                rows as i64,
+                cols as i64,
+                scale_l.shape().elem_count() as i64,
+                internal_type,
+            )
+        }
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, weight_l.shape().clone()))
+    }
+}
+
+impl candle_core::CustomOp2 for Fp8Dequantize {
+    fn name(&self) -> &'static str {
+        "fp8-dequantize"
+    }
+
+    fn cpu_fwd(
+        &self,
+        weight: &CpuStorage,
+        weight_l: &Layout,
+        scale: &CpuStorage,
+        scale_l: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let (_, cols) = weight_l.shape().dims2()?;
+        let (Some((w_start, w_end)), Some((s_start, s_end))) =
+            (weight_l.contiguous_offsets(), scale_l.contiguous_offsets())
+        else {
+            bail!("fp8-dequantize expects contiguous tensors")
+        };
+        let weight = &weight.as_slice::<u8>()?[w_start..w_end];
+        let scale = &scale.as_slice::<f32>()?[s_start..s_end];
+        let out = match self.dtype {
+            DType::F32 => CpuStorage::F32(Self::cpu_fwd_t(weight, scale, cols, |v| v)),
+            DType::F16 => CpuStorage::F16(Self::cpu_fwd_t(weight, scale, cols, f16::from_f32)),
+            DType::BF16 => CpuStorage::BF16(Self::cpu_fwd_t(weight, scale, cols, bf16::from_f32)),
+            dtype => bail!("fp8-dequantize does not support {dtype:?}"),
+        };
+        Ok((out, weight_l.shape().clone()))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        weight: &CudaStorage,
+        weight_l: &Layout,
+        scale: &CudaStorage,
+        scale_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        match self.dtype {
+            DType::F16 => self.cuda_fwd_t::<f16>(weight, weight_l, scale, scale_l, 0),
+            DType::BF16 => self.cuda_fwd_t::<bf16>(weight, weight_l, scale, scale_l, 1),
+            DType::F32 => self.cuda_fwd_t::<f32>(weight, weight_l, scale, scale_l, 2),
+            dtype => bail!("fp8-dequantize does not support {dtype:?}"),
+        }
+    }
+}
+
+/// Dequantize FP8 weights through a table of the 256 values, with candle ops on any device.
+fn dequantize_with_table(weight: &Tensor, scale: &Tensor, dtype: DType) -> Result<Tensor> {
+    let table = (0..=255u8).map(fp8_e4m3_to_f32).collect::<Vec<_>>();
+    let table = Tensor::from_vec(table, 256, weight.device())?;
+    table
+        .index_select(&weight.flatten_all()?, 0)?
+        .reshape(weight.shape())?
+        .broadcast_mul(&scale.reshape(((), 1))?)?
+        .to_dtype(dtype)
+}
+
+/// Dequantize the FP8 E4M3 `weight` of shape `(rows, cols)`, stored as U8, into `dtype`.
+///
+/// # Arguments
+///
+/// * `weight` - FP8 bytes of shape `(rows, cols)`.
+/// * `scale` - The scale of the whole tensor (one element) or of each row (`rows` elements).
+/// * `dtype` - f32, f16 or bf16.
+pub fn fp8_dequantize(weight: &Tensor, scale: &Tensor, dtype: DType) -> Result<Tensor> {
+    let (rows, _) = weight.dims2()?;
+    if weight.dtype() != DType::U8 {
+        bail!("FP8 weights are stored as u8, got {:?}", weight.dtype())
+    }
+    let scale = scale.flatten_all()?.to_dtype(DType::F32)?;
+    if scale.elem_count() != 1 && scale.elem_count() != rows {
+        bail!(
+            "FP8 scales are per tensor or per row, got {} scales for {rows} rows",
+            scale.elem_count()
+        )
+    }
+    match weight.device() {
+        Device::Cpu | Device::Cuda(_) if !naive_kernels_enabled() => {
+            let op = Fp8Dequantize { dtype };
+            weight
+                .contiguous()?
+                .apply_op2_no_bwd(&scale.contiguous()?, &op)
+        }
+        _ => dequantize_with_table(weight, &scale, dtype),
+    }
+}
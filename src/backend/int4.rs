@@ -0,0 +1,308 @@
+//! Blockwise 4-bit quantization of weights at load time, NF4 or INT4 as in bitsandbytes: each
+//! block of [`BLOCK_SIZE_4BIT`] weights is scaled by its absolute maximum and each weight is
+//! replaced by the index of the nearest of 16 values, two indices per byte. With double
+//! quantization, the scales are quantized to 8 bits in groups of [`ABSMAX_GROUP_SIZE`].
+
+#[cfg(feature = "cuda")]
+use candle_core::{
+    backend::BackendStorage,
+    cuda_backend::{
+        cudarc::driver::{DevicePtr, DeviceRepr},
+        CudaDType, WrapErr,
+    },
+    CudaStorage,
+};
+use candle_core::{bail, CpuStorage, DType, Device, Layout, Result, Shape, Tensor};
+use half::{bf16, f16};
+#[cfg(feature = "cuda")]
+use kernels::ffi;
+use rayon::prelude::*;
+
+use super::fallback::naive_kernels_enabled;
+
+/// Weights per scale.
+pub const BLOCK_SIZE_4BIT: usize = 64;
+/// Scales per second-level scale with double quantization.
+pub const ABSMAX_GROUP_SIZE: usize = 256;
+
+/// The 16 values of the 4-bit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codebook4Bit {
+    /// The quantiles of a normal distribution, suited to the weights of trained models
+    Nf4,
+    /// Evenly spaced values, `k / 7`
+    Int4,
+}
+
+impl Codebook4Bit {
+    /// The values in ascending order, in `[-1, 1]` except for the lowest INT4 value.
+    pub fn values(self) -> [f32; 16] {
+        match self {
+            Self::Nf4 => [
+                -1.0,
+                -0.696_192_8,
+                -0.525_073_05,
+                -0.394_917_5,
+                -0.284_441_38,
+                -0.184_773_43,
+                -0.091_050_036,
+                0.0,
+                0.079_580_3,
+                0.160_930_2,
+                0.246_112_3,
+                0.337_915_24,
+                0.440_709_83,
+                0.562_617,
+                0.722_956_84,
+                1.0,
+            ],
+            Self::Int4 => std::array::from_fn(|k| (k as f32 - 8.) / 7.),
+        }
+    }
+
+    /// The codebook argument of the kernel.
+    #[cfg(feature = "cuda")]
+    fn kernel_code(self) -> u32 {
+        match self {
+            Self::Nf4 => 0,
+            Self::Int4 => 1,
+        }
+    }
+}
+
+struct Dequantize4Bit {
+    codebook: Codebook4Bit,
+    dtype: DType,
+}
+
+impl Dequantize4Bit {
+    fn cpu_fwd_t<T: candle_core::WithDType>(
+        &self,
+        codes: &[u8],
+        absmax: &[f32],
+        from_f32: impl Fn(f32) -> T + Sync,
+    ) -> Vec<T> {
+        let values = self.codebook.values();
+        let mut out = vec![T::from_f64(0.); codes.len() * 2];
+        out.par_chunks_mut(BLOCK_SIZE_4BIT)
+            .zip(codes.par_chunks(BLOCK_SIZE_4BIT / 2))
+            .zip(absmax.par_iter())
+            .for_each(|((out, codes), &scale)| {
+                for (out, &byte) in out.chunks_mut(2).zip(codes) {
+                    out[0] = from_f32(values[(byte >> 4) as usize] * scale);
+                    out[1] = from_f32(values[(byte & 0xF) as usize] * scale);
+                }
+            });
+        out
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd_t<T: CudaDType + DeviceRepr>(
+        &self,
+        codes: &CudaStorage,
+        codes_l: &Layout,
+        absmax: &CudaStorage,
+        absmax_l: &Layout,
+        internal_type: u32,
+    ) -> Result<(CudaStorage, Shape)> {
+        let dev = codes.device();
+        let numel = codes_l.shape().elem_count() * 2;
+        let codes = codes.as_cuda_slice::<u8>()?;
+        let codes = codes.slice(codes_l.start_offset()..);
+        let absmax = absmax.as_cuda_slice::<f32>()?;
+        let absmax = absmax.slice(absmax_l.start_offset()..);
+        let out = unsafe { dev.alloc::<T>(numel) }.w()?;
+
+        unsafe {
+            ffi::dequantize_4bit(
+                *codes.device_ptr() as *const core::ffi::c_void,
+                *absmax.device_ptr() as *const f32,
+                *out.device_ptr() as *const core::ffi::c_void,
+                numel as i64,
+                BLOCK_SIZE_4BIT as i64,
+                self.codebook.kernel_code(),
+                internal_type,
+            )
+        }
+        let out = CudaStorage::wrap_cuda_slice(out, dev.clone());
+        Ok((out, Shape::from(numel)))
+    }
+}
+
+impl candle_core::CustomOp2 for Dequantize4Bit {
+    fn name(&self) -> &'static str {
+        "dequantize-4bit"
+    }
+
+    fn cpu_fwd(
+        &self,
+        codes: &CpuStorage,
+        codes_l: &Layout,
+        absmax: &CpuStorage,
+        absmax_l: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let (Some((c_start, c_end)), Some((a_start, a_end))) =
+            (codes_l.contiguous_offsets(), absmax_l.contiguous_offsets())
+        else {
+            bail!("dequantize-4bit expects contiguous tensors")
+        };
+        let codes = &codes.as_slice::<u8>()?[c_start..c_end];
+        let absmax = &absmax.as_slice::<f32>()?[a_start..a_end];
+        let out = match self.dtype {
+            DType::F32 => CpuStorage::F32(self.cpu_fwd_t(codes, absmax, |v| v)),
+            DType::F16 => CpuStorage::F16(self.cpu_fwd_t(codes, absmax, f16::from_f32)),
+            DType::BF16 => CpuStorage::BF16(self.cpu_fwd_t(codes, absmax, bf16::from_f32)),
+            dtype => bail!("dequantize-4bit does not support {dtype:?}"),
+        };
+        Ok((out, Shape::from(codes.len() * 2)))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        codes: &CudaStorage,
+        codes_l: &Layout,
+        absmax: &CudaStorage,
+        absmax_l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        match self.dtype {
+            DType::F16 => self.cuda_fwd_t::<f16>(codes, codes_l, absmax, absmax_l, 0),
+            DType::BF16 => self.cuda_fwd_t::<bf16>(codes, codes_l, absmax, absmax_l, 1),
+            DType::F32 => self.cuda_fwd_t::<f32>(codes, codes_l, absmax, absmax_l, 2),
+            dtype => bail!("dequantize-4bit does not support {dtype:?}"),
+        }
+    }
+}
+
+/// Quantize `weight` blockwise to 4 bits, with candle ops on the device of the weight.
+///
+/// Returns the codes (U8, two per byte, the first weight in the high bits) and the absolute
+/// maximum of each block (F32). The number of weights must be a multiple of
+/// [`BLOCK_SIZE_4BIT`].
+pub fn quantize_4bit(weight: &Tensor, codebook: Codebook4Bit) -> Result<(Tensor, Tensor)> {
+    let numel = weight.elem_count();
+    if numel % BLOCK_SIZE_4BIT != 0 {
+        bail!("4-bit quantization needs a multiple of {BLOCK_SIZE_4BIT} weights, got {numel}")
+    }
+    let blocks = weight
+        .to_dtype(DType::F32)?
+        .reshape((numel / BLOCK_SIZE_4BIT, BLOCK_SIZE_4BIT))?;
+    let absmax = blocks.abs()?.max_keepdim(1)?;
+    // Blocks of zeros stay zeros
+    let normalized = blocks.broadcast_div(&absmax.maximum(f32::MIN_POSITIVE)?)?;
+    // The index of the nearest value is the number of midpoints below the weight
+    let mut codes = normalized.zeros_like()?;
+    for pair in codebook.values().windows(2) {
+        let midpoint = (pair[0] + pair[1]) / 2.;
+        codes = (codes + normalized.gt(midpoint as f64)?.to_dtype(DType::F32)?)?;
+    }
+    let codes = codes.reshape((numel / 2, 2))?;
+    let codes = ((codes.narrow(1, 0, 1)? * 16.)? + codes.narrow(1, 1, 1)?)?
+        .flatten_all()?
+        .to_dtype(DType::U8)?;
+    Ok((codes, absmax.flatten_all()?))
+}
+
+/// Quantize the scales of the blocks to 8 bits (double quantization): each group of
+/// [`ABSMAX_GROUP_SIZE`] scales is divided by its maximum. Returns the quantized scales (U8) and
+/// the maximum of each group (F32).
+pub fn quantize_absmax(absmax: &Tensor) -> Result<(Tensor, Tensor)> {
+    let len = absmax.elem_count();
+    let groups = len.div_ceil(ABSMAX_GROUP_SIZE);
+    let padding = Tensor::zeros(
+        groups * ABSMAX_GROUP_SIZE - len,
+        DType::F32,
+        absmax.device(),
+    )?;
+    let grouped = Tensor::cat(&[&absmax.flatten_all()?, &padding], 0)?
+        .reshape((groups, ABSMAX_GROUP_SIZE))?;
+    let scale = grouped.max_keepdim(1)?;
+    let quantized = grouped
+        .broadcast_div(&scale.maximum(f32::MIN_POSITIVE)?)?
+        .affine(255., 0.)?
+        .round()?
+        .flatten_all()?
+        .narrow(0, 0, len)?
+        .to_dtype(DType::U8)?;
+    Ok((quantized, scale.flatten_all()?))
+}
+
+/// The scales of the blocks from [`quantize_absmax`].
+pub fn dequantize_absmax(quantized: &Tensor, scale: &Tensor) -> Result<Tensor> {
+    let len = quantized.elem_count();
+    let groups = scale.elem_count();
+    let scale = scale
+        .reshape((groups, 1))?
+        .broadcast_as((groups, ABSMAX_GROUP_SIZE))?
+        .flatten_all()?
+        .narrow(0, 0, len)?;
+    (quantized.to_dtype(DType::F32)? / 255.)?.mul(&scale)
+}
+
+/// Dequantize through a table of the 16 values, with candle ops on any device.
+fn dequantize_with_table(
+    codes: &Tensor,
+    absmax: &Tensor,
+    codebook: Codebook4Bit,
+    dtype: DType,
+) -> Result<Tensor> {
+    let codes = codes.to_dtype(DType::F32)?;
+    let high = (&codes / 16.)?.floor()?;
+    let low = (&codes - (&high * 16.)?)?;
+    let indices = Tensor::stack(&[&high, &low], 1)?
+        .flatten_all()?
+        .to_dtype(DType::U32)?;
+    let table = Tensor::new(&codebook.values(), codes.device())?;
+    table
+        .index_select(&indices, 0)?
+        .reshape(((), BLOCK_SIZE_4BIT))?
+        .broadcast_mul(&absmax.reshape(((), 1))?)?
+        .flatten_all()?
+        .to_dtype(dtype)
+}
+
+/// Dequantize the 4-bit `codes` from [`quantize_4bit`] into a `dtype` tensor of `shape`.
+///
+/// # Arguments
+///
+/// * `codes` - Two codes per byte, `shape.elem_count() / 2` bytes.
+/// * `absmax` - The scale of each block of [`BLOCK_SIZE_4BIT`] weights, F32.
+/// * `codebook` - The values of the codes.
+/// * `shape` - The shape of the weight.
+/// * `dtype` - f32, f16 or bf16.
+pub fn dequantize_4bit(
+    codes: &Tensor,
+    absmax: &Tensor,
+    codebook: Codebook4Bit,
+    shape: impl Into<Shape>,
+    dtype: DType,
+) -> Result<Tensor> {
+    let shape = shape.into();
+    if codes.dtype() != DType::U8 || codes.elem_count() * 2 != shape.elem_count() {
+        bail!(
+            "{shape:?} needs {} 4-bit codes in u8, got {} in {:?}",
+            shape.elem_count() / 2,
+            codes.elem_count(),
+            codes.dtype()
+        )
+    }
+    if absmax.elem_count() * BLOCK_SIZE_4BIT != shape.elem_count() {
+        bail!(
+            "{shape:?} needs {} scales, got {}",
+            shape.elem_count() / BLOCK_SIZE_4BIT,
+            absmax.elem_count()
+        )
+    }
+    let absmax = absmax.flatten_all()?.to_dtype(DType::F32)?;
+    let weight = match codes.device() {
+        Device::Cpu | Device::Cuda(_) if !naive_kernels_enabled() => {
+            let op = Dequantize4Bit { codebook, dtype };
+            codes
+                .flatten_all()?
+                .contiguous()?
+                .apply_op2_no_bwd(&absmax.contiguous()?, &op)?
+        }
+        _ => dequantize_with_table(codes, &absmax, codebook, dtype)?,
+    };
+    weight.reshape(shape)
+}
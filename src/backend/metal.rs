@@ -0,0 +1,323 @@
+//! Metal ports of the paged attention, `reshape_and_cache` and `copy_blocks` kernels for Apple
+//! Silicon, compiled at runtime from `pagedattention.metal`. INT8 caches are CUDA only.
+
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    sync::{Mutex, OnceLock},
+};
+
+use candle::backend::BackendStorage;
+use candle::{DType, Device, Layout, MetalDevice, MetalStorage, Result, Shape, Storage, Tensor};
+use candle_core as candle;
+use metal::{Buffer, ComputeCommandEncoderRef, ComputePipelineState, MTLSize};
+
+use crate::{openai::responses::APIError, try_api};
+
+const SOURCE: &str = include_str!("pagedattention.metal");
+/// Threads per threadgroup, lowered to what the pipeline supports.
+const THREADS: u64 = 256;
+
+#[repr(C)]
+struct ReshapeAndCacheParams {
+    num_heads: i32,
+    head_size: i32,
+    block_size: i32,
+    x: i32,
+    key_stride: i32,
+    value_stride: i32,
+}
+
+#[repr(C)]
+struct PagedAttentionParams {
+    num_kv_heads: i32,
+    num_heads: i32,
+    head_size: i32,
+    block_size: i32,
+    x: i32,
+    max_num_blocks_per_seq: i32,
+    max_context_len: i32,
+    q_stride: i32,
+    kv_block_stride: i32,
+    kv_head_stride: i32,
+    scale: f32,
+    softcapping: f32,
+    sliding_window: i32,
+}
+
+/// Pipeline of kernel `<name>_<dtype>`, the library is compiled once per device.
+fn pipeline(device: &MetalDevice, name: &str, dtype: DType) -> Result<ComputePipelineState> {
+    type Pipelines = HashMap<(u64, String), ComputePipelineState>;
+    static LIBRARIES: OnceLock<Mutex<HashMap<u64, metal::Library>>> = OnceLock::new();
+    static PIPELINES: OnceLock<Mutex<Pipelines>> = OnceLock::new();
+
+    let suffix = match dtype {
+        DType::F32 => "f32",
+        DType::F16 => "f16",
+        DType::BF16 => "bf16",
+        dtype => candle::bail!("the Metal paged attention kernels do not support {dtype:?}"),
+    };
+    let name = format!("{name}_{suffix}");
+    let id = device.device().registry_id();
+    let mut pipelines = PIPELINES.get_or_init(Default::default).lock().unwrap();
+    if let Some(pipeline) = pipelines.get(&(id, name.clone())) {
+        return Ok(pipeline.clone());
+    }
+    let mut libraries = LIBRARIES.get_or_init(Default::default).lock().unwrap();
+    let library = match libraries.get(&id) {
+        Some(library) => library.clone(),
+        None => {
+            let library = device
+                .device()
+                .new_library_with_source(SOURCE, &metal::CompileOptions::new())
+                .map_err(candle::Error::msg)?;
+            libraries.insert(id, library.clone());
+            library
+        }
+    };
+    let function = library
+        .get_function(&name, None)
+        .map_err(candle::Error::msg)?;
+    let pipeline = device
+        .device()
+        .new_compute_pipeline_state_with_function(&function)
+        .map_err(candle::Error::msg)?;
+    pipelines.insert((id, name), pipeline.clone());
+    Ok(pipeline)
+}
+
+fn threadgroup_size(pipeline: &ComputePipelineState) -> MTLSize {
+    // A multiple of the SIMD width, the reductions assume full SIMD groups
+    let width = THREADS.min(pipeline.max_total_threads_per_threadgroup()) / 32 * 32;
+    MTLSize {
+        width: width.max(32),
+        height: 1,
+        depth: 1,
+    }
+}
+
+fn set_params<P>(encoder: &ComputeCommandEncoderRef, index: u64, params: &P) {
+    encoder.set_bytes(
+        index,
+        std::mem::size_of::<P>() as u64,
+        params as *const P as *const c_void,
+    );
+}
+
+/// Metal storage of a tensor and the byte offset of its first element.
+fn metal_buffer<'a>(
+    storage: &'a Storage,
+    layout: &Layout,
+    name: &str,
+) -> Result<(&'a Buffer, u64)> {
+    match storage {
+        Storage::Metal(s) => Ok((
+            s.buffer(),
+            (layout.start_offset() * s.dtype().size_in_bytes()) as u64,
+        )),
+        _ => candle::bail!("{name} must be a metal tensor"),
+    }
+}
+
+/// See [`super::reshape_and_cache`].
+pub(crate) fn reshape_and_cache(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    if key_cache.dtype() == DType::U8 {
+        candle::bail!("the int8 KV cache requires the CUDA kernels");
+    }
+    let (num_tokens, num_heads, head_size) = key.dims3()?;
+    let (_, _, _, block_size, x) = key_cache.dims5()?;
+    let key = key.contiguous()?;
+    let value = value.contiguous()?;
+    let slot_mapping = slot_mapping.flatten_all()?.to_dtype(DType::I64)?;
+
+    let (k, k_l) = key.storage_and_layout();
+    let (v, v_l) = value.storage_and_layout();
+    let (kc, kc_l) = key_cache.storage_and_layout();
+    let (vc, vc_l) = value_cache.storage_and_layout();
+    let (s, s_l) = slot_mapping.storage_and_layout();
+    let (k, k_offset) = metal_buffer(&k, k_l, "key")?;
+    let (v, v_offset) = metal_buffer(&v, v_l, "value")?;
+    let (kc, kc_offset) = metal_buffer(&kc, kc_l, "key_cache")?;
+    let (vc, vc_offset) = metal_buffer(&vc, vc_l, "value_cache")?;
+    let (s, s_offset) = metal_buffer(&s, s_l, "slot_mapping")?;
+    let Device::Metal(device) = key.device() else {
+        candle::bail!("key must be a metal tensor")
+    };
+
+    let params = ReshapeAndCacheParams {
+        num_heads: num_heads as i32,
+        head_size: head_size as i32,
+        block_size: block_size as i32,
+        x: x as i32,
+        key_stride: (num_heads * head_size) as i32,
+        value_stride: (num_heads * head_size) as i32,
+    };
+    let pipeline = pipeline(device, "reshape_and_cache", key.dtype())?;
+    let command_buffer = device.command_buffer()?;
+    let encoder = command_buffer.new_compute_command_encoder();
+    encoder.set_compute_pipeline_state(&pipeline);
+    encoder.set_buffer(0, Some(k), k_offset);
+    encoder.set_buffer(1, Some(v), v_offset);
+    encoder.set_buffer(2, Some(kc), kc_offset);
+    encoder.set_buffer(3, Some(vc), vc_offset);
+    encoder.set_buffer(4, Some(s), s_offset);
+    set_params(encoder, 5, &params);
+    encoder.dispatch_thread_groups(
+        MTLSize {
+            width: num_tokens as u64,
+            height: 1,
+            depth: 1,
+        },
+        threadgroup_size(&pipeline),
+    );
+    encoder.end_encoding();
+    Ok(())
+}
+
+/// Metal forward of the paged attention op, see [`super::paged_attention`].
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn paged_attention(
+    q: &MetalStorage,
+    q_l: &Layout,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    max_context_len: usize,
+    softmax_scale: f32,
+    softcapping: f32,
+    sliding_window: usize,
+) -> Result<(MetalStorage, Shape)> {
+    if key_cache.dtype() == DType::U8 {
+        candle::bail!("the int8 KV cache requires the CUDA kernels");
+    }
+    let (num_seqs, num_heads, head_size) = q_l.shape().dims3()?;
+    if head_size > 256 {
+        candle::bail!("paged-attention supports a `head_size` of at most 256 on Metal");
+    }
+    if q_l.stride()[1..] != [head_size, 1] {
+        candle::bail!("paged-attention expects the heads of `q` to be contiguous ({q_l:?})");
+    }
+    let (_, num_kv_heads, _, block_size, x) = key_cache.dims5()?;
+    let (_, max_num_blocks_per_seq) = block_tables.dims2()?;
+    let block_tables = block_tables.to_dtype(DType::U32)?.contiguous()?;
+    let context_lens = context_lens.to_dtype(DType::U32)?.contiguous()?;
+
+    let (kc, kc_l) = key_cache.storage_and_layout();
+    let (vc, vc_l) = value_cache.storage_and_layout();
+    let (bt, bt_l) = block_tables.storage_and_layout();
+    let (cl, cl_l) = context_lens.storage_and_layout();
+    let (kc_buffer, kc_offset) = metal_buffer(&kc, kc_l, "key_cache")?;
+    let (vc_buffer, vc_offset) = metal_buffer(&vc, vc_l, "value_cache")?;
+    let (bt_buffer, bt_offset) = metal_buffer(&bt, bt_l, "block_tables")?;
+    let (cl_buffer, cl_offset) = metal_buffer(&cl, cl_l, "context_lens")?;
+
+    let device = q.device();
+    let out_shape = q_l.shape().clone();
+    let out = device.new_buffer(out_shape.elem_count(), q.dtype(), "paged-attention")?;
+    let logits = device.new_buffer(
+        (num_seqs * num_heads * max_context_len).max(1),
+        DType::F32,
+        "paged-attention-logits",
+    )?;
+
+    let params = PagedAttentionParams {
+        num_kv_heads: num_kv_heads as i32,
+        num_heads: num_heads as i32,
+        head_size: head_size as i32,
+        block_size: block_size as i32,
+        x: x as i32,
+        max_num_blocks_per_seq: max_num_blocks_per_seq as i32,
+        max_context_len: max_context_len as i32,
+        q_stride: q_l.stride()[0] as i32,
+        kv_block_stride: kc_l.stride()[0] as i32,
+        kv_head_stride: kc_l.stride()[1] as i32,
+        scale: softmax_scale,
+        softcapping,
+        sliding_window: sliding_window as i32,
+    };
+    let pipeline = pipeline(device, "paged_attention", q.dtype())?;
+    let command_buffer = device.command_buffer()?;
+    let encoder = command_buffer.new_compute_command_encoder();
+    encoder.set_compute_pipeline_state(&pipeline);
+    encoder.set_buffer(0, Some(&out), 0);
+    encoder.set_buffer(
+        1,
+        Some(q.buffer()),
+        (q_l.start_offset() * q.dtype().size_in_bytes()) as u64,
+    );
+    encoder.set_buffer(2, Some(kc_buffer), kc_offset);
+    encoder.set_buffer(3, Some(vc_buffer), vc_offset);
+    encoder.set_buffer(4, Some(bt_buffer), bt_offset);
+    encoder.set_buffer(5, Some(cl_buffer), cl_offset);
+    encoder.set_buffer(6, Some(&logits), 0);
+    set_params(encoder, 7, &params);
+    encoder.dispatch_thread_groups(
+        MTLSize {
+            width: num_heads as u64,
+            height: num_seqs as u64,
+            depth: 1,
+        },
+        threadgroup_size(&pipeline),
+    );
+    encoder.end_encoding();
+
+    let out = MetalStorage::new(out, device.clone(), out_shape.elem_count(), q.dtype());
+    Ok((out, out_shape))
+}
+
+/// See [`super::copy_blocks`], one dispatch per layer.
+pub(crate) fn copy_blocks(
+    key_caches: Vec<&mut Tensor>,
+    value_caches: Vec<&mut Tensor>,
+    block_mapping: HashMap<usize, Vec<usize>>,
+) -> std::result::Result<(), APIError> {
+    let Some(first) = key_caches.first() else {
+        return Ok(());
+    };
+    let pairs = block_mapping
+        .iter()
+        .flat_map(|(src, dsts)| dsts.iter().flat_map(move |dst| [*src as i64, *dst as i64]))
+        .collect::<Vec<_>>();
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    let numel_per_block = (first.elem_count() / first.dim(0).map_err(APIError::from)?) as i32;
+    let Device::Metal(device) = first.device() else {
+        return Err(APIError::new_str(
+            "Expected the key caches to be on a Metal device.",
+        ));
+    };
+
+    let mapping = try_api!(device.new_buffer_with_data(&pairs));
+    let pipeline = try_api!(pipeline(device, "copy_blocks", first.dtype()));
+    let command_buffer = try_api!(device.command_buffer());
+    for (key_cache, value_cache) in key_caches.iter().zip(&value_caches) {
+        let (kc, kc_l) = key_cache.storage_and_layout();
+        let (vc, vc_l) = value_cache.storage_and_layout();
+        let (kc, kc_offset) = try_api!(metal_buffer(&kc, kc_l, "key_cache"));
+        let (vc, vc_offset) = try_api!(metal_buffer(&vc, vc_l, "value_cache"));
+        let encoder = command_buffer.new_compute_command_encoder();
+        encoder.set_compute_pipeline_state(&pipeline);
+        encoder.set_buffer(0, Some(kc), kc_offset);
+        encoder.set_buffer(1, Some(vc), vc_offset);
+        encoder.set_buffer(2, Some(&mapping), 0);
+        set_params(encoder, 3, &numel_per_block);
+        encoder.dispatch_thread_groups(
+            MTLSize {
+                width: (pairs.len() / 2) as u64,
+                height: 1,
+                depth: 1,
+            },
+            threadgroup_size(&pipeline),
+        );
+        encoder.end_encoding();
+    }
+    Ok(())
+}
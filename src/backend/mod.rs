@@ -1,8 +1,84 @@
 mod cache;
+mod cpu;
+mod fallback;
+mod fp8;
+mod int4;
+#[cfg(feature = "metal")]
+mod metal;
 mod paged_attention;
+mod penalties;
 
+#[cfg(feature = "cuda")]
 const COPY_BLOCKS_KERNEL_NAME: &str = "copy_blocks_kernel";
 
+/// Compute capability `(major, minor)` of a CUDA device, `None` for other devices.
+pub fn compute_capability(device: &Device) -> Result<Option<(usize, usize)>, APIError> {
+    match device {
+        #[cfg(feature = "cuda")]
+        Device::Cuda(device) => cuda_compute_capability(device).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Free and total memory of a CUDA device in bytes, `None` for other devices.
+pub fn memory_info(device: &Device) -> Result<Option<(usize, usize)>, APIError> {
+    match device {
+        #[cfg(feature = "cuda")]
+        Device::Cuda(device) => {
+            device
+                .cuda_device()
+                .bind_to_thread()
+                .map_err(APIError::from)?;
+            mem_get_info().map(Some).map_err(APIError::from)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// PCI bus id (`domain:bus:device.function`) of a CUDA device, `None` for other devices.
+pub fn pci_bus_id(device: &Device) -> Result<Option<String>, APIError> {
+    match device {
+        #[cfg(feature = "cuda")]
+        Device::Cuda(device) => {
+            let device = device.cuda_device();
+            let attribute = |attribute| device.attribute(attribute).map_err(APIError::from);
+            let domain = attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID)?;
+            let bus = attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_PCI_BUS_ID)?;
+            let dev = attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID)?;
+            Ok(Some(format!("{domain:08x}:{bus:02x}:{dev:02x}.0")))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(feature = "cuda")]
+fn cuda_compute_capability(device: &CudaDevice) -> Result<(usize, usize), APIError> {
+    let device = device.cuda_device();
+    let major = device
+        .attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR)
+        .map_err(APIError::from)?;
+    let minor = device
+        .attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR)
+        .map_err(APIError::from)?;
+    Ok((major as usize, minor as usize))
+}
+
+/// The `copy_blocks` PTX built for the highest compute capability the device supports (see
+/// `CUDA_COMPUTE_CAPS` in the kernels build), or the default build if there is none.
+#[cfg(feature = "cuda")]
+fn copy_blocks_ptx(device: &CudaDevice) -> &'static str {
+    let Ok((major, minor)) = cuda_compute_capability(device) else {
+        return COPY_BLOCKS_KERNEL;
+    };
+    COPY_BLOCKS_KERNELS
+        .iter()
+        .rev()
+        .find(|(cap, _)| *cap <= major * 10 + minor)
+        .map(|(_, ptx)| *ptx)
+        .unwrap_or(COPY_BLOCKS_KERNEL)
+}
+
+#[cfg(feature = "cuda")]
 pub fn get_or_load_func(
     ptx_file: &'static str,
     kernel_base: &str,
@@ -30,12 +106,14 @@ pub fn get_or_load_func(
         .map_err(APIError::from)
 }
 
+#[cfg(feature = "cuda")]
 #[repr(transparent)]
 struct Conjoined<'a, T, R> {
     raw: *mut T,
     _ref: PhantomData<&'a mut R>,
 }
 
+#[cfg(feature = "cuda")]
 impl<'a, T, R> Conjoined<'a, T, R> {
     fn new(raw: NonNull<T>, _ref: &'a mut R) -> Self {
         Self {
@@ -51,6 +129,7 @@ impl<'a, T, R> Conjoined<'a, T, R> {
 ///
 /// ## Safety
 /// - The returned pointer **must not** outlive the &self reference. Otherwise, a dangling pointer is created.
+#[cfg(feature = "cuda")]
 unsafe impl<'a, T, R> DeviceRepr for Conjoined<'a, T, R> {
     fn as_kernel_param(&self) -> *mut std::ffi::c_void {
         addr_of!(self.raw) as *mut _
@@ -58,12 +137,23 @@ unsafe impl<'a, T, R> DeviceRepr for Conjoined<'a, T, R> {
 }
 
 pub use cache::*;
+use candle_core::Device;
+#[cfg(feature = "cuda")]
 use candle_core::{
-    cuda_backend::cudarc::driver::{CudaFunction, DeviceRepr},
+    cuda_backend::cudarc::driver::{
+        result::mem_get_info, sys::CUdevice_attribute, CudaFunction, DeviceRepr,
+    },
     CudaDevice, DType,
 };
+pub use fallback::{naive_kernels_enabled, probe_native_kernels};
+pub use fp8::*;
+pub use int4::*;
+#[cfg(feature = "cuda")]
+use kernels::{COPY_BLOCKS_KERNEL, COPY_BLOCKS_KERNELS};
 pub use paged_attention::*;
+pub use penalties::*;
 pub use std::ops::Deref;
+#[cfg(feature = "cuda")]
 use std::{
     marker::PhantomData,
     ptr::{addr_of, NonNull},
@@ -1,17 +1,66 @@
 // use candle_core::{cuda_backend::cudarc::driver::CudaFunction, DType, Tensor};
+#[cfg(feature = "cuda")]
 use candle::backend::BackendStorage;
+#[cfg(feature = "cuda")]
 use candle::cuda_backend::cudarc::driver::DevicePtr;
+#[cfg(feature = "cuda")]
 use candle::cuda_backend::WrapErr;
-use candle::{CpuStorage, CudaStorage, DType, Layout, Result, Shape, Storage, Tensor};
+use candle::{CpuStorage, DType, Device, IndexOp, Layout, Result, Shape, Tensor};
+#[cfg(feature = "cuda")]
+use candle::{CudaStorage, Storage};
 use candle_core as candle;
+#[cfg(feature = "cuda")]
 use half::{bf16, f16};
+#[cfg(feature = "cuda")]
 use kernels::ffi;
+#[cfg(feature = "cuda")]
 use kernels::ffi::{paged_attention_v1, paged_attention_v2};
+#[cfg(feature = "cuda")]
 use std::ffi::c_int;
+use std::iter::zip;
+
+use super::fallback::{
+    naive_kernels_enabled, naive_latent_attention, naive_paged_attention, naive_reshape_and_cache,
+    naive_reshape_and_cache_latent,
+};
+
+/// Rows per head of the key (rows of `x` elements) and value caches. U8 caches hold INT8
+/// values, each head of a block is followed by extra rows with one f32 scale per slot (the key
+/// and value blocks keep the same size, so copy and swap move the scales with the blocks).
+pub fn kv_cache_head_rows(head_size: usize, x: usize, dtype: DType) -> (usize, usize) {
+    if dtype == DType::U8 {
+        (head_size / x + 1, head_size + 16)
+    } else {
+        (head_size / x, head_size)
+    }
+}
+
+/// Device pointer to the start of a key or value cache of element type `T` or U8 (INT8).
+#[cfg(feature = "cuda")]
+fn cache_ptr<
+    T: candle::cuda_backend::CudaDType + candle::cuda_backend::cudarc::driver::DeviceRepr,
+>(
+    cache: &CudaStorage,
+    layout: &Layout,
+) -> Result<*const core::ffi::c_void> {
+    let ptr = if cache.dtype() == DType::U8 {
+        *cache
+            .as_cuda_slice::<u8>()?
+            .slice(layout.start_offset()..)
+            .device_ptr()
+    } else {
+        *cache
+            .as_cuda_slice::<T>()?
+            .slice(layout.start_offset()..)
+            .device_ptr()
+    };
+    Ok(ptr as *const core::ffi::c_void)
+}
 
 struct PagedAttention {
     softmax_scale: f32,
     softcapping: f32,
+    sliding_window: usize,
     key_cache: Tensor,
     value_cache: Tensor,
     block_tables: Tensor,
@@ -19,6 +68,7 @@ struct PagedAttention {
     max_context_len: usize,
 }
 
+#[cfg(feature = "cuda")]
 impl PagedAttention {
     fn cuda_fwd_t<
         T: candle::cuda_backend::CudaDType + candle::cuda_backend::cudarc::driver::DeviceRepr,
@@ -87,17 +137,17 @@ impl PagedAttention {
             )
         }
 
+        let int8_kv_cache = kc.dtype() == DType::U8;
+        let kc_ptr = cache_ptr::<T>(kc, kc_l)?;
+        let vc_ptr = cache_ptr::<T>(vc, vc_l)?;
+
         // Get cuda slices for all tensors
         let q = q.as_cuda_slice::<T>()?;
-        let kc = kc.as_cuda_slice::<T>()?;
-        let vc = vc.as_cuda_slice::<T>()?;
         let cl = cl.as_cuda_slice::<u32>()?; // Should be i32!
         let bt = bt.as_cuda_slice::<u32>()?; // Should be i32!
 
         // Get cuda views for all tensors
         let q = q.slice(q_l.start_offset()..);
-        let kc = kc.slice(kc_l.start_offset()..);
-        let vc = vc.slice(vc_l.start_offset()..);
         let cl = cl.slice(cl_l.start_offset()..);
         let bt = bt.slice(bt_l.start_offset()..);
 
@@ -123,15 +173,16 @@ impl PagedAttention {
         }
 
         let (num_blocks, num_kv_heads, head_size_kc, block_size, x) = kc_l.shape().dims5()?;
-        if head_size_kc != head_size / x {
+        let (key_rows, value_rows) = kv_cache_head_rows(head_size, x, kc.dtype());
+        if head_size_kc != key_rows {
             candle::bail!(
                 "shape mismatch value_cache {:?}, expected {:?}",
                 vc_l.shape(),
-                (num_blocks, num_heads, head_size / x, block_size, x)
+                (num_blocks, num_heads, key_rows, block_size, x)
             )
         }
 
-        if (num_blocks, num_kv_heads, head_size, block_size) != vc_l.shape().dims4()? {
+        if (num_blocks, num_kv_heads, value_rows, block_size) != vc_l.shape().dims4()? {
             candle::bail!(
                 "shape mismatch key_cache {:?} and value_cache {:?}",
                 kc_l.shape(),
@@ -161,8 +212,6 @@ impl PagedAttention {
 
         let out_ptr = *out.device_ptr() as *const core::ffi::c_void;
         let q_ptr = *q.device_ptr() as *const core::ffi::c_void;
-        let kc_ptr = *kc.device_ptr() as *const core::ffi::c_void;
-        let vc_ptr = *vc.device_ptr() as *const core::ffi::c_void;
         let bt_ptr = *bt.device_ptr() as *const core::ffi::c_int;
         let cl_ptr = *cl.device_ptr() as *const core::ffi::c_int;
 
@@ -188,6 +237,8 @@ impl PagedAttention {
                     kv_head_stride as c_int,
                     internal_type,
                     self.softcapping,
+                    self.sliding_window as c_int,
+                    int8_kv_cache,
                 )
             }
         } else {
@@ -225,6 +276,8 @@ impl PagedAttention {
                     kv_head_stride as c_int,
                     internal_type,
                     self.softcapping,
+                    self.sliding_window as c_int,
+                    int8_kv_cache,
                 )
             }
         }
@@ -239,10 +292,21 @@ impl candle::CustomOp1 for PagedAttention {
         "paged-attention"
     }
 
-    fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
-        candle::bail!("no cpu support for paged-attention")
+    fn cpu_fwd(&self, q: &CpuStorage, q_l: &Layout) -> Result<(CpuStorage, Shape)> {
+        super::cpu::paged_attention(
+            q,
+            q_l,
+            &self.key_cache,
+            &self.value_cache,
+            &self.block_tables,
+            &self.context_lens,
+            self.softmax_scale,
+            self.softcapping,
+            self.sliding_window,
+        )
     }
 
+    #[cfg(feature = "cuda")]
     fn cuda_fwd(&self, q: &CudaStorage, q_l: &Layout) -> Result<(CudaStorage, Shape)> {
         match q.dtype() {
             DType::F32 => self.cuda_fwd_t::<f32>(q, q_l),
@@ -251,6 +315,135 @@ impl candle::CustomOp1 for PagedAttention {
             dt => candle::bail!("paged-attention is only supported for f32/f16/bf16 ({dt:?})"),
         }
     }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        q: &candle::MetalStorage,
+        q_l: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        super::metal::paged_attention(
+            q,
+            q_l,
+            &self.key_cache,
+            &self.value_cache,
+            &self.block_tables,
+            &self.context_lens,
+            self.max_context_len,
+            self.softmax_scale,
+            self.softcapping,
+            self.sliding_window,
+        )
+    }
+}
+
+/// The attention each cached token receives from the decoding query of its sequence, summed over
+/// the heads, for heavy-hitter eviction: the paged attention kernels do not return their
+/// probabilities.
+///
+/// # Arguments
+///
+/// * `q` - Query tensor with shape `(num_sequences, num_heads_q, head_size)`.
+/// * `key_cache` - Key cache of shape `(num_blocks, num_heads_kv, head_size / x, block_size, x)`,
+///   or a latent cache with a single head. INT8 caches are not supported.
+/// * `block_tables`, `context_lens`, `softmax_scale` and `softcapping` - As in
+///   [`paged_attention`].
+///
+/// The resulting f32 tensor has dimensions `(num_sequences, max_context_len)`, zero past the
+/// context of a sequence.
+pub fn paged_attention_scores(
+    q: &Tensor,
+    key_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    softmax_scale: f32,
+    softcapping: f32,
+) -> Result<Tensor> {
+    let (_, num_heads, head_size) = q.dims3()?;
+    let num_kv_heads = key_cache.dim(1)?;
+    let num_queries_per_kv = num_heads / num_kv_heads;
+    let block_tables = block_tables.to_dtype(DType::U32)?.to_vec2::<u32>()?;
+    let context_lens = context_lens.to_dtype(DType::I64)?.to_vec1::<i64>()?;
+    let max_context_len = context_lens.iter().copied().max().unwrap_or(0) as usize;
+
+    let mut scores = Vec::with_capacity(context_lens.len());
+    for (i, (table, context_len)) in zip(block_tables, context_lens).enumerate() {
+        let context_len = context_len as usize;
+        // (num_heads, context_len, head_size), query head h attends to kv head h / num_queries_per_kv
+        let keys = gather_keys(key_cache, &table, context_len)?
+            .transpose(0, 1)?
+            .unsqueeze(1)?
+            .broadcast_as((num_kv_heads, num_queries_per_kv, context_len, head_size))?
+            .contiguous()?
+            .reshape((num_heads, context_len, head_size))?;
+        // (num_heads, 1, head_size)
+        let q = q.i(i)?.unsqueeze(1)?.contiguous()?;
+        let att = (q.matmul(&keys.t()?)? * f64::from(softmax_scale))?;
+        let att = if softcapping != 1f32 {
+            let softcapping = f64::from(softcapping);
+            ((att / softcapping)?.tanh()? * softcapping)?
+        } else {
+            att
+        };
+        let probs = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?;
+        scores.push(
+            probs
+                .sum((0, 1))?
+                .pad_with_zeros(0, 0, max_context_len - context_len)?,
+        );
+    }
+    Tensor::stack(&scores, 0)
+}
+
+/// The keys `(context_len, num_heads_kv, head_size)` cached for a sequence with the block table
+/// `table`, from a key cache of shape `(num_blocks, num_heads_kv, head_size / x, block_size, x)`
+/// in the layout of the native kernels, or slot-major on the naive path. INT8 caches are not
+/// supported.
+pub fn gather_keys(key_cache: &Tensor, table: &[u32], context_len: usize) -> Result<Tensor> {
+    let (total_blocks, num_kv_heads, rows, block_size, x) = key_cache.dims5()?;
+    if key_cache.dtype() == DType::U8 {
+        candle::bail!("Keys cannot be gathered from an INT8 KV cache")
+    }
+    let head_size = rows * x;
+    let num_blocks = context_len.div_ceil(block_size);
+    let blocks = Tensor::new(&table[..num_blocks], key_cache.device())?;
+    let keys = if naive_kernels_enabled() {
+        key_cache
+            .reshape((total_blocks, block_size, num_kv_heads, head_size))?
+            .index_select(&blocks, 0)?
+    } else {
+        key_cache
+            .index_select(&blocks, 0)?
+            .permute((0, 3, 1, 2, 4))?
+            .contiguous()?
+    };
+    keys.reshape((num_blocks * block_size, num_kv_heads, head_size))?
+        .narrow(0, 0, context_len)
+}
+
+/// The values `(context_len, num_heads_kv, head_size)` cached for a sequence with the block table
+/// `table`, from a value cache of shape `(num_blocks, num_heads_kv, head_size, block_size)`, see
+/// [`gather_keys`].
+pub fn gather_values(value_cache: &Tensor, table: &[u32], context_len: usize) -> Result<Tensor> {
+    let (total_blocks, num_kv_heads, head_size, block_size) = value_cache.dims4()?;
+    if value_cache.dtype() == DType::U8 {
+        candle::bail!("Values cannot be gathered from an INT8 KV cache")
+    }
+    let num_blocks = context_len.div_ceil(block_size);
+    let blocks = Tensor::new(&table[..num_blocks], value_cache.device())?;
+    let values = if naive_kernels_enabled() {
+        value_cache
+            .reshape((total_blocks, block_size, num_kv_heads, head_size))?
+            .index_select(&blocks, 0)?
+    } else {
+        value_cache
+            .index_select(&blocks, 0)?
+            .permute((0, 3, 1, 2))?
+            .contiguous()?
+    };
+    values
+        .reshape((num_blocks * block_size, num_kv_heads, head_size))?
+        .narrow(0, 0, context_len)
 }
 
 /// Paged Attention layer.
@@ -263,14 +456,19 @@ impl candle::CustomOp1 for PagedAttention {
 ///
 /// * `q` - Query tensor with shape `(num_sequences, num_heads_q, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads_kv, head_size / x, block_size, x)`
-///   with `x` being the size of an element in bytes.
+///   with `x` being the number of elements in 16 bytes.
 /// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads_kv, head_size, block_size)`.
+///   U8 caches hold INT8 values with per-slot scales, see [`kv_cache_head_rows`].
 /// * `block_tables` - Padded table associating blocks to each sequence of shape `(num_sequences, max_context_len // block_size)`
 /// * `context_lens` - Tensor associating lengths to each sequence of shape `(num_sequences)`
 /// * `max_context_len` - Max of `context_len`
 /// * `softmax_scale` - scaling factor
+/// * `softcapping` - tanh softcapping of the logits, 1 for none
+/// * `sliding_window` - attend to the last `sliding_window` tokens of the context only, 0 for
+///   the whole context
 ///
 /// The resulting tensor has dimensions `(num_sequences, num_heads_q, head_size)`.
+#[allow(clippy::too_many_arguments)]
 pub fn paged_attention(
     q: &Tensor,
     key_cache: &Tensor,
@@ -280,7 +478,20 @@ pub fn paged_attention(
     max_context_len: usize,
     softmax_scale: f32,
     softcapping: f32,
+    sliding_window: usize,
 ) -> Result<Tensor> {
+    if naive_kernels_enabled() {
+        return naive_paged_attention(
+            q,
+            key_cache,
+            value_cache,
+            block_tables,
+            context_lens,
+            softmax_scale,
+            softcapping,
+            sliding_window,
+        );
+    }
     let op = PagedAttention {
         softmax_scale,
         key_cache: key_cache.clone(),
@@ -289,10 +500,12 @@ pub fn paged_attention(
         context_lens: context_lens.clone(),
         max_context_len,
         softcapping,
+        sliding_window,
     };
     q.apply_op1(op)
 }
 
+#[cfg(feature = "cuda")]
 fn update_cache<
     T: candle::cuda_backend::CudaDType + candle::cuda_backend::cudarc::driver::DeviceRepr,
 >(
@@ -364,18 +577,18 @@ fn update_cache<
         )
     }
 
+    let int8_kv_cache = kc.dtype() == DType::U8;
+    let kc_ptr = cache_ptr::<T>(kc, kc_l)?;
+    let vc_ptr = cache_ptr::<T>(vc, vc_l)?;
+
     // Get cuda slices for all tensors
     let k = k.as_cuda_slice::<T>()?;
     let v = v.as_cuda_slice::<T>()?;
-    let kc = kc.as_cuda_slice::<T>()?;
-    let vc = vc.as_cuda_slice::<T>()?;
     let s = s.as_cuda_slice::<i64>()?;
 
     // Get cuda views for all tensors
     let k = k.slice(k_l.start_offset()..);
     let v = v.slice(v_l.start_offset()..);
-    let kc = kc.slice(kc_l.start_offset()..);
-    let vc = vc.slice(vc_l.start_offset()..);
     let s = s.slice(s_l.start_offset()..);
 
     let (num_tokens, num_heads, head_size) = k_l.shape().dims3()?;
@@ -384,15 +597,16 @@ fn update_cache<
     }
 
     let (num_blocks, num_heads_kc, head_size_kc, block_size, x) = kc_l.shape().dims5()?;
-    if num_heads_kc != num_heads || head_size_kc != head_size / x {
+    let (key_rows, value_rows) = kv_cache_head_rows(head_size, x, kc.dtype());
+    if num_heads_kc != num_heads || head_size_kc != key_rows {
         candle::bail!(
             "shape mismatch value_cache {:?}, expected {:?}",
             vc_l.shape(),
-            (num_blocks, num_heads, head_size / x, block_size, x)
+            (num_blocks, num_heads, key_rows, block_size, x)
         )
     }
 
-    if (num_blocks, num_heads, head_size, block_size) != vc_l.shape().dims4()? {
+    if (num_blocks, num_heads, value_rows, block_size) != vc_l.shape().dims4()? {
         candle::bail!(
             "shape mismatch key_cache {:?} and value_cache {:?}",
             kc_l.shape(),
@@ -413,8 +627,6 @@ fn update_cache<
 
     let k_ptr = *k.device_ptr() as *const core::ffi::c_void;
     let v_ptr = *v.device_ptr() as *const core::ffi::c_void;
-    let kc_ptr = *kc.device_ptr() as *const core::ffi::c_void;
-    let vc_ptr = *vc.device_ptr() as *const core::ffi::c_void;
     let s_ptr = *s.device_ptr() as *const core::ffi::c_long;
 
     unsafe {
@@ -432,6 +644,7 @@ fn update_cache<
             key_stride,
             value_stride,
             internal_type,
+            int8_kv_cache,
         )
     }
     Ok(())
@@ -444,8 +657,9 @@ fn update_cache<
 /// * `key` - Key tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `value` - Value tensor of shape `(num_tokens, num_heads, head_size)`.
 /// * `key_cache` - Key cache paged tensor of shape `(num_blocks, num_heads, head_size / x, block_size, x)`
-///   with `x` being the size of an element in bytes.
+///   with `x` being the number of elements in 16 bytes.
 /// * `value_cache` - Value cache paged tensor of shape `(num_blocks, num_heads, head_size, block_size)`.
+///   U8 caches are quantized to INT8 with per-slot scales, see [`kv_cache_head_rows`].
 /// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
 pub fn reshape_and_cache(
     key: &Tensor,
@@ -454,12 +668,73 @@ pub fn reshape_and_cache(
     value_cache: &Tensor,
     slot_mapping: &Tensor,
 ) -> Result<()> {
-    match key.dtype() {
-        DType::F16 => update_cache::<f16>(key, value, key_cache, value_cache, slot_mapping),
-        DType::BF16 => update_cache::<bf16>(key, value, key_cache, value_cache, slot_mapping),
-        DType::F32 => update_cache::<f32>(key, value, key_cache, value_cache, slot_mapping),
-        dt => {
-            candle::bail!("reshape_and_cache is only supported for f32, f16 and bf16 ({dt:?})")
+    if naive_kernels_enabled() {
+        return naive_reshape_and_cache(key, value, key_cache, value_cache, slot_mapping);
+    }
+    match key.device() {
+        Device::Cpu => {
+            super::cpu::reshape_and_cache(key, value, key_cache, value_cache, slot_mapping)
+        }
+        #[cfg(feature = "metal")]
+        Device::Metal(_) => {
+            super::metal::reshape_and_cache(key, value, key_cache, value_cache, slot_mapping)
         }
+        #[cfg(feature = "cuda")]
+        Device::Cuda(_) => match key.dtype() {
+            DType::F16 => update_cache::<f16>(key, value, key_cache, value_cache, slot_mapping),
+            DType::BF16 => update_cache::<bf16>(key, value, key_cache, value_cache, slot_mapping),
+            DType::F32 => update_cache::<f32>(key, value, key_cache, value_cache, slot_mapping),
+            dt => {
+                candle::bail!("reshape_and_cache is only supported for f32, f16 and bf16 ({dt:?})")
+            }
+        },
+        #[allow(unreachable_patterns)]
+        device => candle::bail!("reshape_and_cache has no kernels for {device:?}"),
     }
 }
+
+/// Insert the latents of multi-head latent attention into a latent cache, there are no native
+/// kernels for that layout.
+///
+/// # Arguments
+///
+/// * `latent` - New latents of shape `(num_tokens, latent_dim)`.
+/// * `latent_cache` - Latent cache paged tensor of shape `(num_blocks, 1, latent_dim / x, block_size, x)`.
+/// * `slot_mapping` - Mapping associating a slot to each token of shape `(num_tokens)`.
+pub fn reshape_and_cache_latent(
+    latent: &Tensor,
+    latent_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    naive_reshape_and_cache_latent(latent, latent_cache, slot_mapping)
+}
+
+/// Decoding attention of multi-head latent attention over a latent cache.
+///
+/// # Arguments
+///
+/// * `q` - Queries projected to the latent space, of shape `(num_sequences, num_heads_q, latent_dim)`.
+/// * `latent_cache` - Latent cache paged tensor of shape `(num_blocks, 1, latent_dim / x, block_size, x)`.
+/// * `block_tables` - Padded table associating blocks to each sequence of shape `(num_sequences, max_context_len // block_size)`
+/// * `context_lens` - Tensor associating lengths to each sequence of shape `(num_sequences)`
+/// * `softmax_scale` - scaling factor
+/// * `value_dim` - Leading elements of the latents that are attended as values.
+///
+/// The resulting tensor has dimensions `(num_sequences, num_heads_q, value_dim)`.
+pub fn paged_latent_attention(
+    q: &Tensor,
+    latent_cache: &Tensor,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    softmax_scale: f32,
+    value_dim: usize,
+) -> Result<Tensor> {
+    naive_latent_attention(
+        q,
+        latent_cache,
+        block_tables,
+        context_lens,
+        softmax_scale,
+        value_dim,
+    )
+}
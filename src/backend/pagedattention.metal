@@ -0,0 +1,269 @@
+// Metal ports of the paged attention kernels (see kernels/src/*.cu), for Apple Silicon.
+//
+// Cache layouts are the same as the CUDA kernels:
+//   key_cache:   [num_blocks, num_kv_heads, head_size / x, block_size, x]
+//   value_cache: [num_blocks, num_kv_heads, head_size, block_size]
+
+#include <metal_stdlib>
+using namespace metal;
+
+#define MAX_HEAD_SIZE 256
+
+// ---------------------------------------------------------------------------------------------
+// reshape_and_cache: write the key and value of each token to its slot of the paged caches.
+// One threadgroup per token.
+
+struct ReshapeAndCacheParams {
+    int num_heads;
+    int head_size;
+    int block_size;
+    int x;
+    int key_stride;
+    int value_stride;
+};
+
+template <typename T>
+void reshape_and_cache_impl(
+    const device T* key,
+    const device T* value,
+    device T* key_cache,
+    device T* value_cache,
+    const device long* slot_mapping,
+    constant ReshapeAndCacheParams& p,
+    uint token_idx,
+    uint tid,
+    uint threads)
+{
+    const long slot_idx = slot_mapping[token_idx];
+    // Negative slots are padding
+    if (slot_idx < 0) {
+        return;
+    }
+    const long block_idx = slot_idx / p.block_size;
+    const long block_offset = slot_idx % p.block_size;
+    const int n = p.num_heads * p.head_size;
+    for (int i = tid; i < n; i += threads) {
+        const int head_idx = i / p.head_size;
+        const int head_offset = i % p.head_size;
+        const int x_idx = head_offset / p.x;
+        const int x_offset = head_offset % p.x;
+        const long tgt_key_idx = block_idx * p.num_heads * p.head_size * p.block_size
+            + head_idx * p.head_size * p.block_size
+            + x_idx * p.block_size * p.x
+            + block_offset * p.x
+            + x_offset;
+        const long tgt_value_idx = block_idx * p.num_heads * p.head_size * p.block_size
+            + head_idx * p.head_size * p.block_size
+            + head_offset * p.block_size
+            + block_offset;
+        key_cache[tgt_key_idx] = key[token_idx * p.key_stride + i];
+        value_cache[tgt_value_idx] = value[token_idx * p.value_stride + i];
+    }
+}
+
+// ---------------------------------------------------------------------------------------------
+// copy_blocks: copy whole blocks of one layer's caches, one threadgroup per (src, dst) pair.
+
+template <typename T>
+void copy_blocks_impl(
+    device T* key_cache,
+    device T* value_cache,
+    const device long* block_mapping,
+    constant int& numel_per_block,
+    uint pair_idx,
+    uint tid,
+    uint threads)
+{
+    const long src = block_mapping[2 * pair_idx] * numel_per_block;
+    const long dst = block_mapping[2 * pair_idx + 1] * numel_per_block;
+    for (int i = tid; i < numel_per_block; i += threads) {
+        key_cache[dst + i] = key_cache[src + i];
+        value_cache[dst + i] = value_cache[src + i];
+    }
+}
+
+// ---------------------------------------------------------------------------------------------
+// paged_attention: decoding attention of one query token per sequence over its paged context.
+// One threadgroup per (head, sequence). The logits of the context are kept in the `logits`
+// scratch buffer, [num_seqs, num_heads, max_context_len].
+
+struct PagedAttentionParams {
+    int num_kv_heads;
+    int num_heads;
+    int head_size;
+    int block_size;
+    int x;
+    int max_num_blocks_per_seq;
+    int max_context_len;
+    int q_stride;
+    int kv_block_stride;
+    int kv_head_stride;
+    float scale;
+    float softcapping;
+    // Attend to the last `sliding_window` tokens only, 0 for the whole context
+    int sliding_window;
+};
+
+inline float threadgroup_max(float v, threadgroup float* red, uint simd_lane, uint simd_group,
+                             uint num_simd_groups)
+{
+    v = simd_max(v);
+    if (simd_lane == 0) {
+        red[simd_group] = v;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    v = simd_max(simd_lane < num_simd_groups ? red[simd_lane] : -INFINITY);
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    return v;
+}
+
+inline float threadgroup_sum(float v, threadgroup float* red, uint simd_lane, uint simd_group,
+                             uint num_simd_groups)
+{
+    v = simd_sum(v);
+    if (simd_lane == 0) {
+        red[simd_group] = v;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    v = simd_sum(simd_lane < num_simd_groups ? red[simd_lane] : 0.0f);
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    return v;
+}
+
+template <typename T>
+void paged_attention_impl(
+    device T* out,
+    const device T* q,
+    const device T* key_cache,
+    const device T* value_cache,
+    const device uint* block_tables,
+    const device uint* context_lens,
+    device float* logits,
+    constant PagedAttentionParams& p,
+    threadgroup float* q_shared,
+    threadgroup float* red,
+    uint head_idx,
+    uint seq_idx,
+    uint tid,
+    uint threads,
+    uint simd_lane,
+    uint simd_group,
+    uint num_simd_groups)
+{
+    const int context_len = context_lens[seq_idx];
+    const int window_start = p.sliding_window > 0 ? max(context_len - p.sliding_window, 0) : 0;
+    const int kv_head_idx = head_idx / (p.num_heads / p.num_kv_heads);
+    const device uint* block_table = block_tables + seq_idx * p.max_num_blocks_per_seq;
+    device float* seq_logits = logits + (seq_idx * p.num_heads + head_idx) * p.max_context_len;
+    const long kv_head_offset = kv_head_idx * p.kv_head_stride;
+
+    for (int d = tid; d < p.head_size; d += threads) {
+        q_shared[d] = float(q[seq_idx * p.q_stride + head_idx * p.head_size + d]);
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    // Logits, q.k * scale with optional softcapping
+    float max_logit = -INFINITY;
+    for (int t = window_start + tid; t < context_len; t += threads) {
+        const long block = block_table[t / p.block_size];
+        const int block_offset = t % p.block_size;
+        const device T* k = key_cache + block * p.kv_block_stride + kv_head_offset;
+        float dot = 0.0f;
+        for (int d = 0; d < p.head_size; d++) {
+            const int k_idx = (d / p.x) * p.block_size * p.x + block_offset * p.x + d % p.x;
+            dot += q_shared[d] * float(k[k_idx]);
+        }
+        float logit = dot * p.scale;
+        if (p.softcapping != 1.0f) {
+            logit = precise::tanh(logit / p.softcapping) * p.softcapping;
+        }
+        seq_logits[t] = logit;
+        max_logit = max(max_logit, logit);
+    }
+    max_logit = threadgroup_max(max_logit, red, simd_lane, simd_group, num_simd_groups);
+
+    // Softmax numerators, each thread rewrites the logits it wrote
+    float exp_sum = 0.0f;
+    for (int t = window_start + tid; t < context_len; t += threads) {
+        const float e = exp(seq_logits[t] - max_logit);
+        seq_logits[t] = e;
+        exp_sum += e;
+    }
+    exp_sum = threadgroup_sum(exp_sum, red, simd_lane, simd_group, num_simd_groups);
+    threadgroup_barrier(mem_flags::mem_device);
+    const float inv_sum = 1.0f / (exp_sum + 1e-6f);
+
+    // Weighted sum of the values, one output element per thread
+    for (int d = tid; d < p.head_size; d += threads) {
+        float acc = 0.0f;
+        for (int t = window_start; t < context_len; t++) {
+            const long block = block_table[t / p.block_size];
+            const int block_offset = t % p.block_size;
+            const long v_idx =
+                block * p.kv_block_stride + kv_head_offset + d * p.block_size + block_offset;
+            acc += seq_logits[t] * float(value_cache[v_idx]);
+        }
+        out[(seq_idx * p.num_heads + head_idx) * p.head_size + d] = T(acc * inv_sum);
+    }
+}
+
+// ---------------------------------------------------------------------------------------------
+// Kernels per element type, named like the CUDA kernels: `<name>_<dtype>`.
+
+#define INSTANTIATE(T, SUFFIX)                                                                  \
+    kernel void reshape_and_cache_##SUFFIX(                                                     \
+        const device T* key [[buffer(0)]],                                                      \
+        const device T* value [[buffer(1)]],                                                    \
+        device T* key_cache [[buffer(2)]],                                                      \
+        device T* value_cache [[buffer(3)]],                                                    \
+        const device long* slot_mapping [[buffer(4)]],                                          \
+        constant ReshapeAndCacheParams& p [[buffer(5)]],                                        \
+        uint token_idx [[threadgroup_position_in_grid]],                                        \
+        uint tid [[thread_position_in_threadgroup]],                                            \
+        uint threads [[threads_per_threadgroup]])                                               \
+    {                                                                                           \
+        reshape_and_cache_impl<T>(key, value, key_cache, value_cache, slot_mapping, p,          \
+                                  token_idx, tid, threads);                                     \
+    }                                                                                           \
+                                                                                                \
+    kernel void copy_blocks_##SUFFIX(                                                           \
+        device T* key_cache [[buffer(0)]],                                                      \
+        device T* value_cache [[buffer(1)]],                                                    \
+        const device long* block_mapping [[buffer(2)]],                                         \
+        constant int& numel_per_block [[buffer(3)]],                                            \
+        uint pair_idx [[threadgroup_position_in_grid]],                                         \
+        uint tid [[thread_position_in_threadgroup]],                                            \
+        uint threads [[threads_per_threadgroup]])                                               \
+    {                                                                                           \
+        copy_blocks_impl<T>(key_cache, value_cache, block_mapping, numel_per_block, pair_idx,   \
+                            tid, threads);                                                      \
+    }                                                                                           \
+                                                                                                \
+    kernel void paged_attention_##SUFFIX(                                                       \
+        device T* out [[buffer(0)]],                                                            \
+        const device T* q [[buffer(1)]],                                                        \
+        const device T* key_cache [[buffer(2)]],                                                \
+        const device T* value_cache [[buffer(3)]],                                              \
+        const device uint* block_tables [[buffer(4)]],                                          \
+        const device uint* context_lens [[buffer(5)]],                                          \
+        device float* logits [[buffer(6)]],                                                     \
+        constant PagedAttentionParams& p [[buffer(7)]],                                         \
+        uint2 group [[threadgroup_position_in_grid]],                                           \
+        uint tid [[thread_position_in_threadgroup]],                                            \
+        uint threads [[threads_per_threadgroup]],                                               \
+        uint simd_lane [[thread_index_in_simdgroup]],                                           \
+        uint simd_group [[simdgroup_index_in_threadgroup]],                                     \
+        uint num_simd_groups [[simdgroups_per_threadgroup]])                                    \
+    {                                                                                           \
+        threadgroup float q_shared[MAX_HEAD_SIZE];                                              \
+        threadgroup float red[32];                                                              \
+        paged_attention_impl<T>(out, q, key_cache, value_cache, block_tables, context_lens,     \
+                                logits, p, q_shared, red, group.x, group.y, tid, threads,       \
+                                simd_lane, simd_group, num_simd_groups);                        \
+    }
+
+INSTANTIATE(float, f32)
+INSTANTIATE(half, f16)
+#if defined(__HAVE_BFLOAT__)
+INSTANTIATE(bfloat, bf16)
+#endif
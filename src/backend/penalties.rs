@@ -0,0 +1,251 @@
+//! Repetition, presence and frequency penalties of a batch in a single pass over its logits,
+//! instead of a round trip of every row through the host. Each sequence gives the distinct tokens
+//! it has seen with their counts, and only those logits are read and written.
+
+use std::collections::HashMap;
+
+#[cfg(feature = "cuda")]
+use candle_core::{
+    backend::BackendStorage,
+    cuda_backend::{cudarc::driver::DevicePtr, WrapErr},
+    CudaStorage,
+};
+use candle_core::{bail, CpuStorage, DType, Device, InplaceOp3, Layout, Result, Tensor};
+#[cfg(feature = "cuda")]
+use kernels::ffi;
+use rayon::prelude::*;
+
+use super::fallback::naive_kernels_enabled;
+
+/// The penalties of a sequence, neutral by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Penalties {
+    /// Divides the positive logits (and multiplies the negative ones) of the tokens in the
+    /// repetition window
+    pub repetition: f32,
+    /// Subtracted once from the logits of the generated tokens
+    pub presence: f32,
+    /// Subtracted from the logits of the generated tokens for each of their occurrences
+    pub frequency: f32,
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Self {
+            repetition: 1.,
+            presence: 0.,
+            frequency: 0.,
+        }
+    }
+}
+
+impl Penalties {
+    pub fn is_neutral(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The distinct tokens of each sequence and their counts, the inputs of [`apply_penalties`]:
+/// `tokens` of shape `(num_seqs, num_tokens)` and `counts` of shape `(num_seqs, num_tokens, 2)`,
+/// the occurrences of each token in the repetition window and among the generated tokens. Rows
+/// are padded with `u32::MAX` tokens, which are skipped.
+///
+/// # Arguments
+///
+/// * `sequences` - The tokens of the repetition window and the generated tokens of each
+///   sequence.
+/// * `device` - Where the logits are.
+pub fn penalty_counts(sequences: &[(&[u32], &[u32])], device: &Device) -> Result<(Tensor, Tensor)> {
+    let rows = sequences
+        .iter()
+        .map(|(window, output)| {
+            let mut counts = HashMap::<u32, [u32; 2]>::new();
+            for &token in *window {
+                counts.entry(token).or_default()[0] += 1;
+            }
+            for &token in *output {
+                counts.entry(token).or_default()[1] += 1;
+            }
+            counts
+        })
+        .collect::<Vec<_>>();
+    let num_tokens = rows.iter().map(HashMap::len).max().unwrap_or(0);
+    let mut tokens = vec![u32::MAX; sequences.len() * num_tokens];
+    let mut counts = vec![0u32; sequences.len() * num_tokens * 2];
+    for (seq, row) in rows.into_iter().enumerate() {
+        for (i, (token, [window, output])) in row.into_iter().enumerate() {
+            let entry = seq * num_tokens + i;
+            tokens[entry] = token;
+            counts[entry * 2] = window;
+            counts[entry * 2 + 1] = output;
+        }
+    }
+    Ok((
+        Tensor::from_vec(tokens, (sequences.len(), num_tokens), device)?,
+        Tensor::from_vec(counts, (sequences.len(), num_tokens, 2), device)?,
+    ))
+}
+
+struct ApplyPenalties<'a> {
+    penalties: &'a [Penalties],
+}
+
+impl ApplyPenalties<'_> {
+    fn penalize(penalties: Penalties, logit: &mut f32, window: u32, output: u32) {
+        if window > 0 {
+            *logit = if *logit >= 0. {
+                *logit / penalties.repetition
+            } else {
+                *logit * penalties.repetition
+            };
+        }
+        if output > 0 {
+            *logit -= penalties.presence + penalties.frequency * output as f32;
+        }
+    }
+}
+
+impl InplaceOp3 for ApplyPenalties<'_> {
+    fn name(&self) -> &'static str {
+        "apply-penalties"
+    }
+
+    fn cpu_fwd(
+        &self,
+        logits: &mut CpuStorage,
+        logits_l: &Layout,
+        tokens: &CpuStorage,
+        tokens_l: &Layout,
+        counts: &CpuStorage,
+        counts_l: &Layout,
+    ) -> Result<()> {
+        let (_, vocab_size) = logits_l.shape().dims2()?;
+        let (_, num_tokens) = tokens_l.shape().dims2()?;
+        let (Some((l_start, l_end)), Some((t_start, t_end)), Some((c_start, c_end))) = (
+            logits_l.contiguous_offsets(),
+            tokens_l.contiguous_offsets(),
+            counts_l.contiguous_offsets(),
+        ) else {
+            bail!("apply-penalties expects contiguous tensors")
+        };
+        let CpuStorage::F32(logits) = logits else {
+            bail!(
+                "apply-penalties expects f32 logits, got {:?}",
+                logits.dtype()
+            )
+        };
+        if num_tokens == 0 {
+            return Ok(());
+        }
+        let tokens = &tokens.as_slice::<u32>()?[t_start..t_end];
+        let counts = &counts.as_slice::<u32>()?[c_start..c_end];
+        // A row of the batch per task, the tokens of a row are distinct
+        logits[l_start..l_end]
+            .par_chunks_mut(vocab_size)
+            .zip(tokens.par_chunks(num_tokens))
+            .zip(counts.par_chunks(num_tokens * 2))
+            .zip(self.penalties.par_iter())
+            .for_each(|(((logits, tokens), counts), &penalties)| {
+                for (&token, counts) in tokens.iter().zip(counts.chunks_exact(2)) {
+                    if let Some(logit) = logits.get_mut(token as usize) {
+                        Self::penalize(penalties, logit, counts[0], counts[1]);
+                    }
+                }
+            });
+        Ok(())
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        logits: &mut CudaStorage,
+        logits_l: &Layout,
+        tokens: &CudaStorage,
+        tokens_l: &Layout,
+        counts: &CudaStorage,
+        counts_l: &Layout,
+    ) -> Result<()> {
+        let (num_seqs, vocab_size) = logits_l.shape().dims2()?;
+        let (_, num_tokens) = tokens_l.shape().dims2()?;
+        let dev = logits.device().clone();
+        let penalties = self
+            .penalties
+            .iter()
+            .flat_map(|p| [p.repetition, p.presence, p.frequency])
+            .collect::<Vec<_>>();
+        let penalties = dev.htod_sync_copy(&penalties).w()?;
+        let logits = logits.as_cuda_slice::<f32>()?;
+        let logits = logits.slice(logits_l.start_offset()..);
+        let tokens = tokens.as_cuda_slice::<u32>()?;
+        let tokens = tokens.slice(tokens_l.start_offset()..);
+        let counts = counts.as_cuda_slice::<u32>()?;
+        let counts = counts.slice(counts_l.start_offset()..);
+
+        unsafe {
+            ffi::apply_penalties(
+                *logits.device_ptr() as *const core::ffi::c_void,
+                *tokens.device_ptr() as *const u32,
+                *counts.device_ptr() as *const u32,
+                *penalties.device_ptr() as *const f32,
+                num_seqs as i64,
+                vocab_size as i64,
+                num_tokens as i64,
+            )
+        }
+        Ok(())
+    }
+}
+
+/// Apply the penalties of each sequence to its row of `logits` in place. Other tensors sharing
+/// the storage of `logits` see the change.
+///
+/// # Arguments
+///
+/// * `logits` - Contiguous f32 logits of shape `(num_seqs, vocab_size)`.
+/// * `tokens` - The distinct tokens of each sequence, see [`penalty_counts`].
+/// * `counts` - Their occurrences in the repetition window and among the generated tokens.
+/// * `penalties` - The penalties of each sequence.
+pub fn apply_penalties(
+    logits: &Tensor,
+    tokens: &Tensor,
+    counts: &Tensor,
+    penalties: &[Penalties],
+) -> Result<()> {
+    let (num_seqs, _) = logits.dims2()?;
+    let (token_rows, num_tokens) = tokens.dims2()?;
+    if logits.dtype() != DType::F32 || !logits.is_contiguous() {
+        bail!("Penalties apply to contiguous f32 logits")
+    }
+    if token_rows != num_seqs
+        || penalties.len() != num_seqs
+        || counts.dims() != [num_seqs, num_tokens, 2]
+    {
+        bail!(
+            "Penalties need the tokens, counts and penalties of the {num_seqs} sequences, got \
+            {:?}, {:?} and {}",
+            tokens.dims(),
+            counts.dims(),
+            penalties.len()
+        )
+    }
+    if penalties.iter().all(Penalties::is_neutral) {
+        return Ok(());
+    }
+    let tokens = tokens.to_dtype(DType::U32)?.contiguous()?;
+    let counts = counts.to_dtype(DType::U32)?.contiguous()?;
+    let op = ApplyPenalties { penalties };
+    match logits.device() {
+        Device::Cpu => logits.inplace_op3(&tokens, &counts, &op),
+        Device::Cuda(_) if !naive_kernels_enabled() => logits.inplace_op3(&tokens, &counts, &op),
+        // Penalized on the host and copied back
+        device => {
+            let host = logits.to_device(&Device::Cpu)?;
+            host.inplace_op3(
+                &tokens.to_device(&Device::Cpu)?,
+                &counts.to_device(&Device::Cpu)?,
+                &op,
+            )?;
+            logits.slice_set(&host.to_device(device)?, 0, 0)
+        }
+    }
+}
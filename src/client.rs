@@ -0,0 +1,484 @@
+//! A typed client for a candle-vllm server (feature `client`).
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), candle_vllm::client::ClientError> {
+//! use candle_vllm::client::{ChatCompletionRequestBuilder, Client};
+//! use futures::StreamExt;
+//!
+//! let client = Client::new("http://127.0.0.1:2000");
+//! let request = ChatCompletionRequestBuilder::new("llama")
+//!     .system("You are a helpful assistant.")
+//!     .user("Explain how to best learn Rust.")
+//!     .max_tokens(256)
+//!     .build();
+//! let mut stream = client.chat_completion_stream(&request).await?;
+//! while let Some(chunk) = stream.next().await {
+//!     for choice in chunk?.choices {
+//!         print!("{}", choice.delta.content.unwrap_or_default());
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, pin::Pin, time::Duration};
+
+use derive_more::{Display, Error};
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::openai::{
+    requests::{
+        AttentionSinks, CachePriority, ChatCompletionRequest, EmbeddingRequest, ForkRequest,
+        Messages, Quality, ReloadRequest, ResponseFormat, ScoreRequest, StopTokens, StreamOptions,
+        TokenClassificationRequest, Tool, ToolChoice,
+    },
+    responses::{
+        AbortResponse, ChatCompletionChunk, ChatCompletionResponse, EmbeddingResponse, ModelList,
+        ReloadResponse, ScoreResponse, TokenClassificationResponse,
+    },
+};
+
+#[derive(Debug, Display, Error)]
+#[non_exhaustive]
+pub enum ClientError {
+    /// The request could not be sent or its response not received.
+    #[display(fmt = "Request failed: {}", _0)]
+    Http(reqwest::Error),
+    /// The server rejected the request.
+    #[display(fmt = "Server returned status {}: {}", status, message)]
+    Api { status: u16, message: String },
+    /// The response body is not the expected JSON.
+    #[display(fmt = "Invalid response: {}", _0)]
+    Decode(serde_json::Error),
+    /// An error event in a chat completion stream.
+    #[display(fmt = "Stream error: {}", _0)]
+    Stream(#[error(not(source))] String),
+}
+
+/// Chunks of a streamed chat completion, ending after the `[DONE]` event.
+pub type ChatCompletionStream =
+    Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk, ClientError>> + Send>>;
+
+/// Builds a [`ChatCompletionRequest`], unset parameters take the server defaults.
+pub struct ChatCompletionRequestBuilder {
+    request: ChatCompletionRequest,
+    messages: Vec<HashMap<String, Value>>,
+}
+
+impl ChatCompletionRequestBuilder {
+    pub fn new(model: impl Into<String>) -> Self {
+        Self {
+            request: ChatCompletionRequest {
+                model: model.into(),
+                ..Default::default()
+            },
+            messages: vec![],
+        }
+    }
+
+    /// Append a message with the given role.
+    pub fn message(mut self, role: impl Into<String>, content: impl Into<String>) -> Self {
+        self.messages.push(HashMap::from([
+            ("role".to_string(), Value::String(role.into())),
+            ("content".to_string(), Value::String(content.into())),
+        ]));
+        self
+    }
+
+    pub fn system(self, content: impl Into<String>) -> Self {
+        self.message("system", content)
+    }
+
+    pub fn user(self, content: impl Into<String>) -> Self {
+        self.message("user", content)
+    }
+
+    pub fn assistant(self, content: impl Into<String>) -> Self {
+        self.message("assistant", content)
+    }
+
+    /// Send a raw prompt instead of messages, the chat template is not applied.
+    pub fn prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.request.messages = Messages::Literal(prompt.into());
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.request.temperature = Some(temperature);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.request.top_p = Some(top_p);
+        self
+    }
+
+    pub fn top_k(mut self, top_k: isize) -> Self {
+        self.request.top_k = Some(top_k);
+        self
+    }
+
+    pub fn min_p(mut self, min_p: f32) -> Self {
+        self.request.min_p = Some(min_p);
+        self
+    }
+
+    pub fn typical_p(mut self, typical_p: f32) -> Self {
+        self.request.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn n(mut self, n: usize) -> Self {
+        self.request.n = Some(n);
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.request.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn stop(mut self, stop: Vec<String>) -> Self {
+        self.request.stop = Some(StopTokens::Multi(stop));
+        self
+    }
+
+    pub fn stop_token_ids(mut self, stop_token_ids: Vec<usize>) -> Self {
+        self.request.stop_token_ids = Some(stop_token_ids);
+        self
+    }
+
+    pub fn repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.request.repetition_penalty = Some(repetition_penalty);
+        self
+    }
+
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.request.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.request.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    /// Add `bias` (in [-100, 100], -100 bans the token) to the logit of `token_id`.
+    pub fn logit_bias(mut self, token_id: u32, bias: f32) -> Self {
+        self.request
+            .logit_bias
+            .get_or_insert_with(Default::default)
+            .insert(token_id.to_string(), bias);
+        self
+    }
+
+    pub fn ignore_eos(mut self, ignore_eos: bool) -> Self {
+        self.request.ignore_eos = Some(ignore_eos);
+        self
+    }
+
+    pub fn min_tokens(mut self, min_tokens: usize) -> Self {
+        self.request.min_tokens = Some(min_tokens);
+        self
+    }
+
+    pub fn logprobs(mut self, logprobs: bool) -> Self {
+        self.request.logprobs = Some(logprobs);
+        self
+    }
+
+    /// Start the completion with the prompt.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.request.echo = Some(echo);
+        self
+    }
+
+    /// Score every prompt token, with the `top_n` most likely tokens at each position.
+    pub fn prompt_logprobs(mut self, top_n: usize) -> Self {
+        self.request.prompt_logprobs = Some(top_n);
+        self
+    }
+
+    pub fn tools(mut self, tools: Vec<Tool>, tool_choice: Option<ToolChoice>) -> Self {
+        self.request.tools = Some(tools);
+        self.request.tool_choice = tool_choice;
+        self
+    }
+
+    pub fn response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.request.response_format = Some(response_format);
+        self
+    }
+
+    pub fn guided_regex(mut self, regex: impl Into<String>) -> Self {
+        self.request.guided_regex = Some(regex.into());
+        self
+    }
+
+    /// Usage reporting of streamed completions, see [`StreamOptions`].
+    pub fn stream_options(mut self, stream_options: StreamOptions) -> Self {
+        self.request.stream_options = Some(stream_options);
+        self
+    }
+
+    /// Keep the KV cache of the request once it finishes, to be forked with [`Client::fork`].
+    pub fn forkable(mut self, forkable: bool) -> Self {
+        self.request.forkable = Some(forkable);
+        self
+    }
+
+    /// Bound the KV cache of the request to sink tokens and a recent window, see
+    /// [`AttentionSinks`].
+    pub fn attention_sinks(mut self, attention_sinks: AttentionSinks) -> Self {
+        self.request.attention_sinks = Some(attention_sinks);
+        self
+    }
+
+    /// Keep the last `tokens` tokens of a prompt that is longer, -1 for as many as fit in the
+    /// context with `max_tokens`.
+    pub fn truncate_prompt_tokens(mut self, tokens: isize) -> Self {
+        self.request.truncate_prompt_tokens = Some(tokens);
+        self
+    }
+
+    /// Scheduling priority of the request, lower values are served first when the server runs
+    /// with `--scheduling-policy priority`.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.request.priority = Some(priority);
+        self
+    }
+
+    /// Eviction hint for the cached system prompt of the request, e.g. `Pinned` to keep a long
+    /// system prompt cached between the turns of a session.
+    pub fn cache_priority(mut self, cache_priority: CachePriority) -> Self {
+        self.request.cache_priority = Some(cache_priority);
+        self
+    }
+
+    /// Session of the request. The KV cache of the conversation is kept once the request
+    /// finishes, and the next request of the session only prefills what follows it.
+    pub fn session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.request.session_id = Some(session_id.into());
+        self
+    }
+
+    /// Abort the request on the server once it has run for `timeout`, whatever the
+    /// `--request-timeout` of the server.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeout = Some(timeout.as_secs_f64());
+        self
+    }
+
+    /// Run the request on the quantized (`fast`) or the full precision (`best`) variant of a model
+    /// served with `--fast-quant`.
+    pub fn quality(mut self, quality: Quality) -> Self {
+        self.request.quality = Some(quality);
+        self
+    }
+
+    pub fn build(mut self) -> ChatCompletionRequest {
+        if let Messages::Map(messages) = &mut self.request.messages {
+            messages.append(&mut self.messages);
+        }
+        self.request
+    }
+}
+
+/// Client of the OpenAI compatible API of a candle-vllm server.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl Client {
+    /// `base_url` is the address of the server, e.g. `http://127.0.0.1:2000`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// Use a preconfigured HTTP client, e.g. with timeouts or a proxy.
+    pub fn with_http_client(base_url: impl Into<String>, http: reqwest::Client) -> Self {
+        Self {
+            http,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub async fn models(&self) -> Result<ModelList, ClientError> {
+        let response = self
+            .http
+            .get(format!("{}/v1/models", self.base_url))
+            .send()
+            .await
+            .map_err(ClientError::Http)?;
+        decode(check_status(response).await?).await
+    }
+
+    /// Chat completion, `stream` is ignored.
+    pub async fn chat_completion(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, ClientError> {
+        let request = ChatCompletionRequest {
+            stream: Some(false),
+            ..request.clone()
+        };
+        let response = self.post("/v1/chat/completions", &request).await?;
+        decode(response).await
+    }
+
+    /// Streamed chat completion, `stream` is ignored.
+    pub async fn chat_completion_stream(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionStream, ClientError> {
+        let request = ChatCompletionRequest {
+            stream: Some(true),
+            ..request.clone()
+        };
+        let response = self.post("/v1/chat/completions", &request).await?;
+        Ok(sse_chunks(response))
+    }
+
+    /// Branch the finished generation of a forkable request, one choice per branch.
+    pub async fn fork(&self, request: &ForkRequest) -> Result<ChatCompletionResponse, ClientError> {
+        let response = self.post("/v1/chat/completions/fork", request).await?;
+        decode(response).await
+    }
+
+    /// Embed the inputs of the request, one embedding per input.
+    pub async fn embeddings(
+        &self,
+        request: &EmbeddingRequest,
+    ) -> Result<EmbeddingResponse, ClientError> {
+        let response = self.post("/v1/embeddings", request).await?;
+        decode(response).await
+    }
+
+    /// Tag the tokens of the inputs of the request, one result per input.
+    pub async fn token_classify(
+        &self,
+        request: &TokenClassificationRequest,
+    ) -> Result<TokenClassificationResponse, ClientError> {
+        let response = self.post("/v1/token_classify", request).await?;
+        decode(response).await
+    }
+
+    /// Score the inputs of the request with a reward model, one result per input.
+    pub async fn score(&self, request: &ScoreRequest) -> Result<ScoreResponse, ClientError> {
+        let response = self.post("/v1/score", request).await?;
+        decode(response).await
+    }
+
+    /// Abort an in-flight chat completion by its id.
+    pub async fn abort(&self, request_id: &str) -> Result<AbortResponse, ClientError> {
+        let response = self.post(&format!("/v1/abort/{request_id}"), &()).await?;
+        decode(response).await
+    }
+
+    /// Swap a served model for the model of the request, once the requests in flight are done.
+    /// The client must be created with the address of `--admin-port`.
+    pub async fn reload(
+        &self,
+        request: &ReloadRequest,
+        admin_token: &str,
+    ) -> Result<ReloadResponse, ClientError> {
+        let response = self
+            .http
+            .post(format!("{}/admin/reload", self.base_url))
+            .bearer_auth(admin_token)
+            .json(request)
+            .send()
+            .await
+            .map_err(ClientError::Http)?;
+        decode(check_status(response).await?).await
+    }
+
+    async fn post<T: Serialize>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> Result<reqwest::Response, ClientError> {
+        let response = self
+            .http
+            .post(format!("{}{path}", self.base_url))
+            .json(body)
+            .send()
+            .await
+            .map_err(ClientError::Http)?;
+        check_status(response).await
+    }
+}
+
+/// Turn an error status into [`ClientError::Api`], with the message of the JSON error body.
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.map_err(ClientError::Http)?;
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|error| {
+            // OpenAI formatted errors nest the message in `error`
+            let error = error.get("error").unwrap_or(&error);
+            error.get("message")?.as_str().map(str::to_string)
+        })
+        .unwrap_or(body);
+    Err(ClientError::Api {
+        status: status.as_u16(),
+        message,
+    })
+}
+
+async fn decode<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<T, ClientError> {
+    let body = response.bytes().await.map_err(ClientError::Http)?;
+    serde_json::from_slice(&body).map_err(ClientError::Decode)
+}
+
+/// Parse the server-sent events of a streamed chat completion. Keep-alive comments are skipped,
+/// events whose data is not a chunk are errors reported by the server.
+fn sse_chunks(response: reqwest::Response) -> ChatCompletionStream {
+    let state = (response.bytes_stream().boxed(), Vec::<u8>::new(), false);
+    Box::pin(stream::unfold(
+        state,
+        |(mut bytes, mut buffer, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                    let event = String::from_utf8_lossy(&buffer[..end]).to_string();
+                    buffer.drain(..end + 2);
+                    let data = event
+                        .lines()
+                        .filter_map(|line| line.strip_prefix("data:"))
+                        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    if data.is_empty() {
+                        continue;
+                    }
+                    if data == "[DONE]" {
+                        return None;
+                    }
+                    let chunk = serde_json::from_str::<ChatCompletionChunk>(&data)
+                        .map_err(|_| ClientError::Stream(data));
+                    return Some((chunk, (bytes, buffer, false)));
+                }
+                match bytes.next().await {
+                    Some(Ok(data)) => buffer.extend_from_slice(&data),
+                    Some(Err(e)) => {
+                        return Some((Err(ClientError::Http(e)), (bytes, buffer, true)))
+                    }
+                    None => return None,
+                }
+            }
+        },
+    ))
+}
@@ -0,0 +1,136 @@
+//! Fault injection for resilience tests. With the `fault-injection` feature, faults are
+//! enabled through environment variables read once at startup:
+//!
+//! - `CANDLE_VLLM_FAULT_KERNEL_RATE`: probability (0 to 1) that a model forward pass fails.
+//! - `CANDLE_VLLM_FAULT_SWAP_DELAY_MS`: delay before each swap of KV cache blocks.
+//! - `CANDLE_VLLM_FAULT_DROP_STREAM_AFTER`: number of chunks after which a streamed response is
+//!   dropped, as if the client disconnected.
+//! - `CANDLE_VLLM_FAULT_SEED`: seed of the failure draws (0 by default), so that a test run
+//!   fails the same forward passes every time.
+//!
+//! [`init`] parses them at startup, an invalid value fails it. Without the feature the hooks do
+//! nothing and compile away.
+
+use crate::openai::responses::APIError;
+
+#[cfg(feature = "fault-injection")]
+mod injector {
+    use crate::openai::responses::APIError;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::{
+        str::FromStr,
+        sync::{Mutex, OnceLock},
+        time::Duration,
+    };
+
+    pub(super) struct Faults {
+        pub kernel_rate: f64,
+        pub swap_delay: Option<Duration>,
+        pub drop_stream_after: Option<usize>,
+        rng: Mutex<StdRng>,
+    }
+
+    static FAULTS: OnceLock<Faults> = OnceLock::new();
+
+    fn var<T: FromStr>(name: &str) -> Result<Option<T>, APIError> {
+        let Ok(value) = std::env::var(name) else {
+            return Ok(None);
+        };
+        value
+            .parse()
+            .map(Some)
+            .map_err(|_| APIError::new(format!("Invalid value {value:?} for {name}.")))
+    }
+
+    pub(super) fn init() -> Result<(), APIError> {
+        let faults = Faults::from_env()?;
+        let _ = FAULTS.set(faults);
+        Ok(())
+    }
+
+    /// The faults parsed by [`init`], or read on first use when it was not called (tests).
+    pub(super) fn faults() -> &'static Faults {
+        FAULTS.get_or_init(|| {
+            Faults::from_env().unwrap_or_else(|e| {
+                tracing::error!("{e} No fault is injected.");
+                Faults::none()
+            })
+        })
+    }
+
+    impl Faults {
+        fn from_env() -> Result<Self, APIError> {
+            Ok(Self {
+                kernel_rate: var("CANDLE_VLLM_FAULT_KERNEL_RATE")?.unwrap_or(0.),
+                swap_delay: var("CANDLE_VLLM_FAULT_SWAP_DELAY_MS")?.map(Duration::from_millis),
+                drop_stream_after: var("CANDLE_VLLM_FAULT_DROP_STREAM_AFTER")?,
+                rng: Mutex::new(StdRng::seed_from_u64(
+                    var("CANDLE_VLLM_FAULT_SEED")?.unwrap_or(0),
+                )),
+            })
+        }
+
+        fn none() -> Self {
+            Self {
+                kernel_rate: 0.,
+                swap_delay: None,
+                drop_stream_after: None,
+                rng: Mutex::new(StdRng::seed_from_u64(0)),
+            }
+        }
+
+        pub fn draw(&self, rate: f64) -> bool {
+            rate > 0. && self.rng.lock().unwrap().gen_bool(rate.min(1.))
+        }
+    }
+}
+
+/// Parse the faults of the environment. Called at startup, so that an invalid value fails it
+/// rather than the first forward pass.
+pub fn init() -> Result<(), APIError> {
+    #[cfg(feature = "fault-injection")]
+    injector::init()?;
+    Ok(())
+}
+
+/// Fail a model forward pass at the configured rate.
+#[inline]
+pub fn kernel_failure() -> Result<(), APIError> {
+    #[cfg(feature = "fault-injection")]
+    {
+        let faults = injector::faults();
+        if faults.draw(faults.kernel_rate) {
+            tracing::warn!("Injecting a kernel failure.");
+            return Err(APIError::new_str("Injected kernel failure."));
+        }
+    }
+    Ok(())
+}
+
+/// Delay a swap of KV cache blocks by the configured duration.
+#[inline]
+pub fn delay_swap() {
+    #[cfg(feature = "fault-injection")]
+    if let Some(delay) = injector::faults().swap_delay {
+        tracing::warn!(
+            delay_ms = delay.as_millis() as u64,
+            "Injecting a swap delay."
+        );
+        std::thread::sleep(delay);
+    }
+}
+
+/// Whether a streamed response that sent `chunks` chunks should be dropped.
+#[inline]
+pub fn drop_stream(chunks: usize) -> bool {
+    #[cfg(feature = "fault-injection")]
+    if injector::faults()
+        .drop_stream_after
+        .is_some_and(|after| chunks >= after)
+    {
+        tracing::warn!(chunks, "Injecting a dropped client connection.");
+        return true;
+    }
+    let _ = chunks;
+    false
+}
@@ -1,12 +1,17 @@
 #![warn(clippy::cast_lossless)]
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::path::{Path, PathBuf};
 
-use candle::Result;
 use candle_core as candle;
 use clap::Subcommand;
-use openai::pipelines::{pipeline::DefaultLoader, ModelLoader};
+use openai::models::SelfExtend;
+use openai::pipelines::{mock::MockLoader, pipeline::DefaultLoader, ModelLoader};
+use openai::requests::Pooling;
 
-#[derive(Debug, Subcommand)]
+/// The model to serve, new models are added in minor releases.
+#[derive(Debug, Clone, Subcommand)]
+#[non_exhaustive]
 pub enum ModelSelected {
     /// Select the llama model (default llama2-7b).
     Llama {
@@ -115,6 +120,31 @@ pub enum ModelSelected {
         quant: Option<String>,
     },
 
+    /// Select the qwen2-moe model (default A2.7B).
+    Qwen2Moe {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        top_p: Option<f64>,
+
+        #[arg(long)]
+        top_k: Option<usize>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        #[arg(long)]
+        quant: Option<String>,
+    },
+
     /// Select the gemma model (default 2b).
     Gemma {
         /// Control the application of repeat penalty for the last n tokens
@@ -134,6 +164,25 @@ pub enum ModelSelected {
         quant: Option<String>,
     },
 
+    /// Select the gemma2 model (default 9b).
+    Gemma2 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        #[arg(long)]
+        quant: Option<String>,
+    },
+
     /// Select the mistral model (default 7b).
     Mistral {
         /// Control the application of repeat penalty for the last n tokens
@@ -153,6 +202,25 @@ pub enum ModelSelected {
         quant: Option<String>,
     },
 
+    /// Select the mixtral model (default 8x7b).
+    Mixtral {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        #[arg(long)]
+        quant: Option<String>,
+    },
+
     /// Select the Yi model (default 6b).
     Yi {
         /// Control the application of repeat penalty for the last n tokens
@@ -172,6 +240,45 @@ pub enum ModelSelected {
         quant: Option<String>,
     },
 
+    /// Select the DeepSeek-V2 model (default V2-Lite-Chat).
+    #[command(name = "deepseek-v2")]
+    DeepSeekV2 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        #[arg(long)]
+        quant: Option<String>,
+    },
+
+    /// Select the GLM-4 model (default glm-4-9b-chat).
+    Glm4 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        #[arg(long)]
+        quant: Option<String>,
+    },
+
     /// Select the stable-lm model (default zephyr-3b).
     StableLM {
         /// Control the application of repeat penalty for the last n tokens
@@ -190,6 +297,108 @@ pub enum ModelSelected {
         #[arg(long)]
         quant: Option<String>,
     },
+
+    /// Select a Mamba2 state space model (default Mamba-Codestral-7B). Each sequence keeps a
+    /// fixed-size state instead of a KV cache.
+    Mamba2 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        #[arg(long)]
+        quant: Option<String>,
+    },
+
+    /// Select a T5 encoder-decoder model (default flan-t5-base). The prompt is encoded once per
+    /// request and the decoder attends to its output.
+    T5 {
+        /// Control the application of repeat penalty for the last n tokens
+        #[arg(long)]
+        repeat_last_n: Option<usize>,
+
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        penalty: Option<f32>,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        #[arg(long)]
+        quant: Option<String>,
+    },
+
+    /// Select a BERT encoder (default bge-base-en-v1.5), serves /v1/embeddings, and
+    /// /v1/token_classify for token classification checkpoints such as dslim/bert-base-NER.
+    Bert {
+        /// Default pooling of the embeddings (cls for BGE, mean for GTE and sentence-transformers)
+        #[arg(long, value_enum, default_value_t = Pooling::Cls)]
+        pooling: Pooling,
+    },
+
+    /// Serve a mock model that loads no weights and streams deterministic replies, for testing
+    /// clients against the API without a GPU.
+    Mock {
+        /// Reply with this text instead of echoing the last user message
+        #[arg(long)]
+        response: Option<String>,
+
+        /// Delay of the prompt forward pass, in milliseconds
+        #[arg(long, default_value_t = 0)]
+        prefill_latency_ms: u64,
+
+        /// Delay of every generated token, in milliseconds
+        #[arg(long, default_value_t = 20)]
+        latency_ms: u64,
+
+        #[arg(long, default_value_t = 4096)]
+        max_model_len: usize,
+
+        #[arg(long)]
+        max_gen_tokens: Option<usize>,
+
+        /// Run as a recurrent model, with a state per sequence instead of KV cache blocks
+        #[arg(long)]
+        recurrent: bool,
+    },
+}
+
+impl ModelSelected {
+    /// The same model with its weights quantized to `quant` at load time, `None` for the models
+    /// that cannot be quantized.
+    pub fn with_quant(&self, quant: String) -> Option<Self> {
+        let mut selected = self.clone();
+        match &mut selected {
+            ModelSelected::Llama { quant: q, .. }
+            | ModelSelected::Llama3 { quant: q, .. }
+            | ModelSelected::Phi2 { quant: q, .. }
+            | ModelSelected::Phi3 { quant: q, .. }
+            | ModelSelected::Qwen2 { quant: q, .. }
+            | ModelSelected::Qwen2Moe { quant: q, .. }
+            | ModelSelected::Gemma { quant: q, .. }
+            | ModelSelected::Gemma2 { quant: q, .. }
+            | ModelSelected::Mistral { quant: q, .. }
+            | ModelSelected::Mixtral { quant: q, .. }
+            | ModelSelected::Yi { quant: q, .. }
+            | ModelSelected::DeepSeekV2 { quant: q, .. }
+            | ModelSelected::Glm4 { quant: q, .. }
+            | ModelSelected::StableLM { quant: q, .. }
+            | ModelSelected::Mamba2 { quant: q, .. }
+            | ModelSelected::T5 { quant: q, .. } => *q = Some(quant),
+            ModelSelected::Bert { .. } | ModelSelected::Mock { .. } => return None,
+        }
+        Some(selected)
+    }
 }
 
 impl Display for ModelSelected {
@@ -216,10 +425,19 @@ impl Display for ModelSelected {
                 max_gen_tokens: _,
                 quant: _,
             } => write!(f, "qwen2"),
+            ModelSelected::Qwen2Moe { .. } => write!(f, "qwen2moe"),
             ModelSelected::Gemma { .. } => write!(f, "gemma"),
+            ModelSelected::Gemma2 { .. } => write!(f, "gemma2"),
             ModelSelected::Mistral { .. } => write!(f, "mistral"),
+            ModelSelected::Mixtral { .. } => write!(f, "mixtral"),
             ModelSelected::Yi { .. } => write!(f, "yi"),
+            ModelSelected::DeepSeekV2 { .. } => write!(f, "deepseekv2"),
+            ModelSelected::Glm4 { .. } => write!(f, "glm4"),
             ModelSelected::StableLM { .. } => write!(f, "stablelm"),
+            ModelSelected::Mamba2 { .. } => write!(f, "mamba2"),
+            ModelSelected::T5 { .. } => write!(f, "t5"),
+            ModelSelected::Bert { .. } => write!(f, "bert"),
+            ModelSelected::Mock { .. } => write!(f, "mock"),
         }
     }
 }
@@ -233,6 +451,10 @@ pub struct SpecificConfig {
     penalty: Option<f32>,
     max_gen_tokens: Option<usize>,
     quant: Option<String>,
+    self_extend: Option<SelfExtend>,
+    stream_weights: bool,
+    num_gpu_layers: Option<usize>,
+    pooling: Option<Pooling>,
 }
 
 impl SpecificConfig {
@@ -253,6 +475,10 @@ impl SpecificConfig {
             penalty,
             max_gen_tokens,
             quant,
+            self_extend: None,
+            stream_weights: false,
+            num_gpu_layers: None,
+            pooling: None,
         }
     }
 }
@@ -334,7 +560,7 @@ pub fn get_model_loader(
             if let Some(model_id) = model_id {
                 model_id
             } else {
-                "microsoft/microsoft/phi-2".to_string()
+                "microsoft/phi-2".to_string()
             },
         ),
         ModelSelected::Phi3 {
@@ -391,6 +617,33 @@ pub fn get_model_loader(
                 "Qwen/Qwen1.5-1.8B-Chat".to_string()
             },
         ),
+        ModelSelected::Qwen2Moe {
+            repeat_last_n,
+            temperature,
+            top_k,
+            top_p,
+            penalty,
+            max_gen_tokens,
+            quant,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    top_k,
+                    top_p,
+                    penalty,
+                    max_gen_tokens,
+                    quant,
+                ),
+                "qwen2moe".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "Qwen/Qwen1.5-MoE-A2.7B-Chat".to_string()
+            },
+        ),
         ModelSelected::Gemma {
             repeat_last_n,
             temperature,
@@ -416,6 +669,31 @@ pub fn get_model_loader(
                 "google/gemma-2b-it".to_string()
             },
         ),
+        ModelSelected::Gemma2 {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+            quant,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                    quant,
+                ),
+                "gemma2".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "google/gemma-2-9b-it".to_string()
+            },
+        ),
         ModelSelected::Mistral {
             repeat_last_n,
             temperature,
@@ -441,6 +719,31 @@ pub fn get_model_loader(
                 "mistralai/Mistral-7B-Instruct-v0.3".to_string()
             },
         ),
+        ModelSelected::Mixtral {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+            quant,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                    quant,
+                ),
+                "mixtral".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "mistralai/Mixtral-8x7B-Instruct-v0.1".to_string()
+            },
+        ),
 
         ModelSelected::Yi {
             repeat_last_n,
@@ -468,6 +771,58 @@ pub fn get_model_loader(
             },
         ),
 
+        ModelSelected::DeepSeekV2 {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+            quant,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                    quant,
+                ),
+                "deepseekv2".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "deepseek-ai/DeepSeek-V2-Lite-Chat".to_string()
+            },
+        ),
+
+        ModelSelected::Glm4 {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+            quant,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                    quant,
+                ),
+                "glm4".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "THUDM/glm-4-9b-chat".to_string()
+            },
+        ),
+
         ModelSelected::StableLM {
             repeat_last_n,
             temperature,
@@ -493,31 +848,250 @@ pub fn get_model_loader(
                 "stabilityai/stablelm-zephyr-3b".to_string()
             },
         ),
+
+        ModelSelected::Mamba2 {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+            quant,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                    quant,
+                ),
+                "mamba2".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "mistralai/Mamba-Codestral-7B-v0.1".to_string()
+            },
+        ),
+
+        ModelSelected::T5 {
+            repeat_last_n,
+            temperature,
+            penalty,
+            max_gen_tokens,
+            quant,
+        } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig::new(
+                    repeat_last_n,
+                    temperature,
+                    None,
+                    None,
+                    penalty,
+                    max_gen_tokens,
+                    quant,
+                ),
+                "t5".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "google/flan-t5-base".to_string()
+            },
+        ),
+
+        ModelSelected::Bert { pooling } => (
+            Box::new(DefaultLoader::new(
+                SpecificConfig {
+                    pooling: Some(pooling),
+                    ..SpecificConfig::new(None, None, None, None, None, None, None)
+                },
+                "bert".to_string(),
+            )),
+            if let Some(model_id) = model_id {
+                model_id
+            } else {
+                "BAAI/bge-base-en-v1.5".to_string()
+            },
+        ),
+
+        ModelSelected::Mock {
+            response,
+            prefill_latency_ms,
+            latency_ms,
+            max_model_len,
+            max_gen_tokens,
+            recurrent,
+        } => (
+            Box::new(MockLoader::new(
+                response,
+                prefill_latency_ms,
+                latency_ms,
+                max_model_len,
+                max_gen_tokens,
+                recurrent,
+            )),
+            model_id.unwrap_or_else(|| "mock".to_string()),
+        ),
+    }
+}
+
+/// Errors locating the weight files of a local model directory.
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+pub enum WeightFilesError {
+    #[display(fmt = "Cannot read {}: {}", path, source)]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[display(fmt = "Invalid weight index {}: {}", path, reason)]
+    InvalidIndex { path: String, reason: String },
+    #[display(fmt = "Weight file {} listed in {} does not exist", shard, index)]
+    MissingShard { index: String, shard: String },
+    #[display(
+        fmt = "No weights in {}, expected model.safetensors(.index.json), consolidated.safetensors or pytorch_model.bin(.index.json)",
+        _0
+    )]
+    NoWeights(#[error(not(source))] String),
+    #[display(fmt = "Cannot convert {} to safetensors: {}", path, source)]
+    Conversion { path: String, source: candle::Error },
+}
+
+/// The weight files of a `weight_map`, once each and in their order of first appearance.
+struct WeightMapFiles(Vec<String>);
+
+impl<'de> serde::Deserialize<'de> for WeightMapFiles {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FilesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FilesVisitor {
+            type Value = WeightMapFiles;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a map of tensor names to weight files")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut files = Vec::<String>::new();
+                while let Some((_, file)) = map.next_entry::<serde::de::IgnoredAny, String>()? {
+                    if !files.contains(&file) {
+                        files.push(file);
+                    }
+                }
+                Ok(WeightMapFiles(files))
+            }
+        }
+
+        deserializer.deserialize_map(FilesVisitor)
     }
 }
 
+#[derive(serde::Deserialize)]
+struct WeightIndex {
+    weight_map: WeightMapFiles,
+}
+
+/// The files listed in the `weight_map` of the index `json_file` of the directory `path`,
+/// without duplicates and in the order of the index. Every file must exist.
 pub fn hub_load_local_safetensors(
-    path: &String,
+    path: &str,
     json_file: &str,
-) -> Result<Vec<std::path::PathBuf>> {
-    println!("{:}", path.to_owned() + json_file);
-    let jsfile = std::fs::File::open(path.to_owned() + json_file)?;
-    let json: serde_json::Value = serde_json::from_reader(&jsfile).map_err(candle::Error::wrap)?;
-    let weight_map = match json.get("weight_map") {
-        None => panic!("no weight map in {json_file:?}"),
-        Some(serde_json::Value::Object(map)) => map,
-        Some(_) => panic!("weight map in {json_file:?} is not a map"),
-    };
-    let mut safetensors_files = Vec::<std::path::PathBuf>::new();
-    for value in weight_map.values() {
-        if let Some(file) = value.as_str() {
-            safetensors_files.insert(0, (path.to_owned() + file).into());
+) -> Result<Vec<PathBuf>, WeightFilesError> {
+    let dir = Path::new(path);
+    let index_path = dir.join(json_file);
+    let index = index_path.display().to_string();
+    let json = std::fs::read(&index_path).map_err(|source| WeightFilesError::Io {
+        path: index.clone(),
+        source,
+    })?;
+    let weight_index: WeightIndex =
+        serde_json::from_slice(&json).map_err(|e| WeightFilesError::InvalidIndex {
+            path: index.clone(),
+            reason: e.to_string(),
+        })?;
+    weight_index
+        .weight_map
+        .0
+        .into_iter()
+        .map(|file| {
+            let file = dir.join(file);
+            if file.is_file() {
+                Ok(file)
+            } else {
+                Err(WeightFilesError::MissingShard {
+                    index: index.clone(),
+                    shard: file.display().to_string(),
+                })
+            }
+        })
+        .collect()
+}
+
+/// The weight files of the local model directory `path`: the shards of
+/// `model.safetensors.index.json`, `model.safetensors`, `consolidated.safetensors`, or else the
+/// PyTorch checkpoint (`pytorch_model.bin.index.json` or `pytorch_model.bin`) converted to
+/// safetensors next to it on first use.
+pub fn local_weight_files(path: &str) -> Result<Vec<PathBuf>, WeightFilesError> {
+    let dir = Path::new(path);
+    if dir.join("model.safetensors.index.json").is_file() {
+        return hub_load_local_safetensors(path, "model.safetensors.index.json");
+    }
+    for file in ["model.safetensors", "consolidated.safetensors"] {
+        let file = dir.join(file);
+        if file.is_file() {
+            return Ok(vec![file]);
         }
     }
-    Ok(safetensors_files)
+    let checkpoints = if dir.join("pytorch_model.bin.index.json").is_file() {
+        hub_load_local_safetensors(path, "pytorch_model.bin.index.json")?
+    } else if dir.join("pytorch_model.bin").is_file() {
+        vec![dir.join("pytorch_model.bin")]
+    } else {
+        return Err(WeightFilesError::NoWeights(dir.display().to_string()));
+    };
+    checkpoints
+        .iter()
+        .map(|checkpoint| convert_pytorch_checkpoint(checkpoint))
+        .collect()
+}
+
+/// Convert a PyTorch checkpoint to a safetensors file next to it, reused once written.
+fn convert_pytorch_checkpoint(checkpoint: &Path) -> Result<PathBuf, WeightFilesError> {
+    let converted = checkpoint.with_extension("safetensors");
+    if converted.is_file() {
+        return Ok(converted);
+    }
+    println!("Converting {} to safetensors", checkpoint.display());
+    let conversion_error = |source| WeightFilesError::Conversion {
+        path: checkpoint.display().to_string(),
+        source,
+    };
+    let tensors: HashMap<String, candle::Tensor> = candle::pickle::read_all(checkpoint)
+        .map_err(conversion_error)?
+        .into_iter()
+        .collect();
+    // Written under another name first, an interrupted conversion is not mistaken for a done one
+    let partial = checkpoint.with_extension("safetensors.partial");
+    candle::safetensors::save(&tensors, &partial).map_err(conversion_error)?;
+    std::fs::rename(&partial, &converted).map_err(|source| WeightFilesError::Io {
+        path: partial.display().to_string(),
+        source,
+    })?;
+    Ok(converted)
 }
 
+#[doc(hidden)]
 pub mod backend;
+#[cfg(feature = "client")]
+pub mod client;
+#[doc(hidden)]
+pub mod fault;
 pub mod openai;
+#[doc(hidden)]
 pub mod paged_attention;
+pub mod prelude;
 pub mod scheduler;
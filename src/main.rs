@@ -1,25 +1,74 @@
 use axum::{
     http::{self, Method},
-    routing::post,
+    middleware,
+    routing::{get, post},
     Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use candle_core::{DType, Device};
-use candle_vllm::openai::openai_server::chat_completions;
+use candle_vllm::backend::{
+    compute_capability, memory_info, naive_kernels_enabled, probe_native_kernels,
+};
+use candle_vllm::openai::batch;
+use candle_vllm::openai::bench::{self, BenchConfig, LengthRange};
+use candle_vllm::openai::canary::{self, CanaryConfig};
+use candle_vllm::openai::compression::{compression_layer, DEFAULT_MIN_COMPRESSED_BYTES};
+use candle_vllm::openai::grpc;
+use candle_vllm::openai::metrics::{CanaryMetrics, UserMetrics};
+use candle_vllm::openai::openai_server::{
+    abort_request, admission, capabilities, chat_completions, delete_session, embeddings,
+    fork_chat_completion, get_session, metrics, models, queue_depth_header, rate_limit,
+    reload_model, score, stats, token_classify,
+};
+use candle_vllm::openai::otel::{self, OtlpGuard, TracingObserver};
 use candle_vllm::openai::pipelines::llm_engine::LLMEngine;
 use candle_vllm::openai::pipelines::pipeline::DefaultModelPaths;
-use candle_vllm::openai::responses::APIError;
-use candle_vllm::openai::OpenAIServerData;
-use candle_vllm::scheduler::cache_engine::CacheConfig;
-use candle_vllm::scheduler::SchedulerConfig;
-use candle_vllm::{get_model_loader, hub_load_local_safetensors, ModelSelected};
+use candle_vllm::openai::pipelines::weights::{self, DtypeOverride};
+use candle_vllm::openai::pipelines::LoadOptions;
+use candle_vllm::openai::plugins::{PluginHost, PluginLimits};
+use candle_vllm::openai::quality::QualityRouter;
+use candle_vllm::openai::rate_limit::{RateLimiter, RateLimits};
+use candle_vllm::openai::reload::Reloader;
+use candle_vllm::openai::requests::ReloadRequest;
+use candle_vllm::openai::responses::{APIError, REQUEST_ID_HEADER};
+use candle_vllm::openai::sessions::{SessionLimits, SessionStore};
+use candle_vllm::openai::warmup::{self, WarmupConfig};
+use candle_vllm::openai::{OpenAIServerData, PromptLogging, ServedModel};
+use candle_vllm::scheduler::cache_engine::{CacheConfig, CacheEngine, KvCacheLayout};
+use candle_vllm::scheduler::{
+    batch_limits::auto_batch_limits, HeavyHitterConfig, SchedulerConfig, SchedulingPolicy,
+};
+use candle_vllm::{get_model_loader, local_weight_files, ModelSelected};
 use clap::Parser;
-use std::sync::Arc;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 const SIZE_IN_MB: usize = 1024 * 1024;
-use candle_vllm::openai::models::Config;
+use candle_vllm::openai::models::{Config, SelfExtend};
 use std::path::Path;
-use tokio::sync::Notify;
 use tower_http::cors::{AllowOrigin, CorsLayer};
-#[derive(Parser, Debug)]
+use tracing_subscriber::{
+    fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+};
+
+#[cfg(feature = "pprof")]
+#[global_allocator]
+static ALLOCATOR: candle_vllm::openai::profiling::CountingAllocator =
+    candle_vllm::openai::profiling::CountingAllocator;
+
+/// Output format of the server logs.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    /// One JSON object per line, with the request id, phase and duration as fields.
+    Json,
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Huggingface token environment variable (optional). If not specified, load using hf_token_path.
@@ -31,10 +80,45 @@ struct Args {
     #[arg(long)]
     hf_token_path: Option<String>,
 
-    /// Port to serve on (localhost:port)
+    /// Port to serve on (host:port)
     #[arg(long)]
     port: u16,
 
+    /// Address to listen on, e.g. 0.0.0.0 (all IPv4 interfaces) or :: (all IPv6 interfaces)
+    #[arg(long, default_value = "127.0.0.1")]
+    host: IpAddr,
+
+    /// PEM certificate chain, serve HTTPS instead of HTTP (requires tls_key)
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of tls_cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Also serve the API on this Unix domain socket, e.g. /run/candle-vllm.sock, for clients on
+    /// the same host (Unix only)
+    #[arg(long)]
+    uds: Option<PathBuf>,
+
+    /// Also serve the gRPC generation service of proto/candle_vllm.proto on this port (requires
+    /// the `grpc` feature)
+    #[arg(long)]
+    grpc_port: Option<u16>,
+
+    /// Serve `POST /admin/reload` on this port, to the callers holding admin_token. Models cannot
+    /// be reloaded without it
+    #[arg(long, requires = "admin_token")]
+    admin_port: Option<u16>,
+
+    /// Address of the admin_port listener (plain HTTP), the local host by default
+    #[arg(long, default_value = "127.0.0.1")]
+    admin_host: IpAddr,
+
+    /// Bearer token of the callers of /admin/reload (requires admin_port)
+    #[arg(long, requires = "admin_port")]
+    admin_token: Option<String>,
+
     /// Set verbose mode (print all requests)
     #[arg(long)]
     verbose: bool,
@@ -42,9 +126,15 @@ struct Args {
     #[clap(subcommand)]
     command: ModelSelected,
 
-    /// Maximum number of sequences to allow
-    #[arg(long, default_value_t = 256)]
-    max_num_seqs: usize,
+    /// Maximum number of sequences to allow (default: derived from the model size, dtype,
+    /// KV cache and free GPU memory)
+    #[arg(long)]
+    max_num_seqs: Option<usize>,
+
+    /// Maximum number of prompt tokens admitted in one scheduling step (default: derived like
+    /// max_num_seqs)
+    #[arg(long)]
+    max_num_batched_tokens: Option<usize>,
 
     /// Size of a block
     #[arg(long, default_value_t = 32)]
@@ -54,18 +144,81 @@ struct Args {
     #[arg(long)]
     model_id: Option<String>,
 
+    /// Name the model is served under, matched against the `model` field of requests when
+    /// several models are served (default: the model type)
+    #[arg(long)]
+    served_model_name: Option<String>,
+
+    /// Serve another model in the same process, with its own scheduler and KV cache. The value
+    /// holds the arguments of that model: `--name`, `--model-id` (with `--revision` and
+    /// `--subfolder`) or `--weight-path`, `--kvcache-mem-gpu` and `--kvcache-mem-cpu` (1024 MB by default), then its model type and
+    /// options, e.g. "--name small --model-id Qwen/Qwen2-0.5B-Instruct qwen2". Repeatable
+    #[arg(long)]
+    extra_model: Vec<String>,
+
+    /// Also serve the model with its weights quantized to this format (e.g. q4k or nf4) as
+    /// `<name>-fast`, with its own scheduler and KV cache. Requests pick a variant with
+    /// `"quality": "fast"` or `"best"`, the others are spread over both by weighted round-robin
+    #[arg(long)]
+    fast_quant: Option<String>,
+
+    /// Available GPU memory for the kvcache of the fast variant (MB)
+    #[arg(long, default_value_t = 1024)]
+    fast_kvcache_mem_gpu: usize,
+
+    /// Available CPU memory for the kvcache of the fast variant (MB)
+    #[arg(long, default_value_t = 1024)]
+    fast_kvcache_mem_cpu: usize,
+
+    /// Share of the requests without a `quality` sent to the fast variant, against --best-weight
+    #[arg(long, default_value_t = 1)]
+    fast_weight: u32,
+
+    /// Share of the requests without a `quality` sent to the model in its dtype, against
+    /// --fast-weight
+    #[arg(long, default_value_t = 1)]
+    best_weight: u32,
+
+    /// Branch, tag or commit of the model_id repository to download (default: main)
+    #[arg(long)]
+    revision: Option<String>,
+
+    /// Folder of the model_id repository holding the config and weights, when the model is not
+    /// at its root. The tokenizer is taken from the root when the folder has none
+    #[arg(long)]
+    subfolder: Option<String>,
+
+    /// Hugging Face endpoint to download from, e.g. a mirror like https://hf-mirror.com
+    /// (default: the HF_ENDPOINT environment variable or https://huggingface.co)
+    #[arg(long)]
+    hf_endpoint: Option<String>,
+
     /// The folder name that contains safetensor weights and json files
     /// (same structure as huggingface online), path must include last "/"
     #[arg(long)]
     weight_path: Option<String>,
 
+    /// Data type of the model: `f16`, `bf16` (default), `f32` or `auto`, which picks bf16 on GPUs
+    /// of compute capability 8.0 and above, f16 on older GPUs and Metal, and f32 on CPU. bf16 falls
+    /// back to f16 on GPUs without bf16 GEMMs
     #[arg(long)]
     dtype: Option<String>,
 
+    /// Load the modules whose path contains <module> in another dtype, `<module>=<dtype>`, e.g.
+    /// `lm_head=f32` or `norm=f32` (repeatable, the first match wins)
+    #[arg(long = "dtype-override")]
+    dtype_override: Vec<DtypeOverride>,
+
+    /// KV cache data type: `auto` (the model dtype) or `int8`, which quantizes the cache with
+    /// a scale per token and head to hold nearly twice the tokens (CUDA only)
+    #[arg(long)]
+    kv_cache_dtype: Option<String>,
+
     #[arg(long, default_value_t = false)]
     cpu: bool,
 
-    /// Available GPU memory for kvcache (MB)
+    /// Available GPU memory for kvcache (MB), which holds the per-sequence states of recurrent
+    /// models (Mamba2) and the per-sequence keys and values of encoder-decoder models (T5)
     #[arg(long, default_value_t = 4096)]
     kvcache_mem_gpu: usize,
 
@@ -73,34 +226,368 @@ struct Args {
     #[arg(long, default_value_t = 4096)]
     kvcache_mem_cpu: usize,
 
+    /// Hard cap on the GPU memory of the process (MB): weights, KV caches and workspace of all
+    /// the served models. Startup fails if the weights and KV caches do not fit, the batch limits
+    /// are derived from the workspace left. Under CUDA MPS, the driver also enforces it on
+    /// every allocation
+    #[arg(long)]
+    gpu_memory_limit: Option<usize>,
+
+    /// Attribute the GPU energy of every batch to its requests by their share of its tokens,
+    /// reported in `/metrics` and in the `usage` of the responses (requires the `nvml` feature)
+    #[arg(long, default_value_t = false)]
+    energy_telemetry: bool,
+
+    /// Start serving without running warmup requests through the models first. The first
+    /// requests then pay for the first kernel launches and allocations
+    #[arg(long)]
+    skip_warmup: bool,
+
+    /// JSON array of probe prompts generated from greedily at an interval, a warning is logged
+    /// when an output differs from its baseline
+    #[arg(long)]
+    canary_prompts: Option<PathBuf>,
+
+    /// Seconds between two runs of the canary probes
+    #[arg(long, default_value_t = 600)]
+    canary_interval: u64,
+
+    /// Tokens generated per canary probe
+    #[arg(long, default_value_t = 32)]
+    canary_max_tokens: usize,
+
+    /// Directory of the canary baselines, one `<model>.json` per served model, recorded by the
+    /// first run when missing. Without it the baseline is the first run of this server
+    #[arg(long)]
+    canary_baseline_dir: Option<PathBuf>,
+
+    /// Run offline instead of serving: generate the completions of the prompts of this JSONL
+    /// file (`{"id", "prompt", "max_tokens", "temperature", "top_p", "top_k", "stop"}` per
+    /// line) with the first model, all of them batched together
+    #[arg(long, requires = "batch_output")]
+    batch_input: Option<PathBuf>,
+
+    /// JSONL file the batch completions and their usage are written to, in input order
+    #[arg(long, requires = "batch_input")]
+    batch_output: Option<PathBuf>,
+
+    /// Run a benchmark instead of serving: send this many synthetic requests to the first model
+    /// and report the throughput, TTFT and inter-token latency percentiles and KV cache usage
+    #[arg(long)]
+    bench_requests: Option<usize>,
+
+    /// Prompt length of the benchmark requests in tokens, `N` or a uniform range `MIN-MAX`
+    #[arg(long, default_value = "128")]
+    bench_prompt_len: LengthRange,
+
+    /// Generated tokens per benchmark request, `N` or a uniform range `MIN-MAX`
+    #[arg(long, default_value = "128")]
+    bench_output_len: LengthRange,
+
+    /// Benchmark requests sent per second, as a Poisson process (default: all at once)
+    #[arg(long)]
+    bench_request_rate: Option<f64>,
+
+    /// Seed of the synthetic benchmark requests
+    #[arg(long, default_value_t = 0)]
+    bench_seed: u64,
+
+    /// Also write the benchmark report to this JSON file, to compare runs
+    #[arg(long)]
+    bench_report: Option<PathBuf>,
+
+    /// Directory of WebAssembly plugins (`*.wasm`) that rewrite chat completion requests and
+    /// responses, run in file name order (requires the `wasm-plugins` feature)
+    #[arg(long)]
+    plugins_dir: Option<PathBuf>,
+
     /// Record conversation (default false, the client need to record chat history)
     #[arg(long)]
     record_conversation: bool,
+
+    /// System message of the chat requests that have none, a system message in the request
+    /// replaces it
+    #[arg(long)]
+    default_system_prompt: Option<String>,
+
+    /// Enable Self-Extend: attend to the tokens beyond the neighbor window with their positions
+    /// grouped by this size, to serve contexts longer than the model was trained on (e.g. 4
+    /// turns 8k into ~32k)
+    #[arg(long)]
+    self_extend_group_size: Option<usize>,
+
+    /// Neighbor window (in tokens) that keeps exact positions when Self-Extend is enabled
+    #[arg(long, default_value_t = 1024)]
+    self_extend_window: usize,
+
+    /// Experimental KV cache compression (H2O-style): once a sequence caches more than this many
+    /// tokens, the blocks that received the least attention, from the prompt and the generated
+    /// tokens, are evicted. Not available with the int8 KV cache
+    #[arg(long)]
+    kv_budget: Option<usize>,
+
+    /// Most recent tokens that are never evicted when kv_budget is set
+    #[arg(long, default_value_t = 256)]
+    kv_recent_window: usize,
+
+    /// Order in which requests are served: `fcfs`, or `priority` to serve lower `priority`
+    /// values first and let them preempt running requests of a lower priority
+    #[arg(long, value_enum, default_value_t = SchedulingPolicy::Fcfs)]
+    scheduling_policy: SchedulingPolicy,
+
+    /// Reject chat completions with a 429 (and a `Retry-After`) while this many requests wait to
+    /// be scheduled by the model (default: unbounded)
+    #[arg(long)]
+    max_waiting_requests: Option<usize>,
+
+    /// Abort chat completions that have not finished this many seconds after they arrived,
+    /// freeing their KV cache. Requests can set their own `timeout` (default: none)
+    #[arg(long)]
+    request_timeout: Option<f64>,
+
+    /// Requests per minute allowed to each client address, over it they get a 429
+    /// (default: unlimited)
+    #[arg(long)]
+    rate_limit_rpm: Option<u64>,
+
+    /// Tokens per minute allowed to each client address, counting the prompt and `max_tokens` of chat
+    /// completions and the inputs of embeddings (default: unlimited)
+    #[arg(long)]
+    rate_limit_tpm: Option<u64>,
+
+    /// Keep the conversation of the requests with a `session_id` on the server: a request only
+    /// sends its new messages, the prompt starts with the history of its session
+    #[arg(long)]
+    session_history: bool,
+
+    /// Sessions kept with --session-history, the least recently used one is dropped for a new one
+    #[arg(long, default_value_t = 1024)]
+    max_sessions: usize,
+
+    /// Messages kept per session with --session-history, the oldest turns are dropped first
+    #[arg(long, default_value_t = 64)]
+    session_max_messages: usize,
+
+    /// Seconds after which an idle session is dropped with --session-history
+    #[arg(long, default_value_t = 3600)]
+    session_ttl: u64,
+
+    /// Stream decoder layer weights from disk for every forward pass (with prefetch of the next layer)
+    /// instead of keeping them resident, for models that do not fit into memory (slow, llama only)
+    #[arg(long)]
+    stream_weights: bool,
+
+    /// Keep the first N decoder layers (and their KV cache) on the GPU and run the others on the
+    /// CPU, for models that do not fit into GPU memory (default: all layers on the GPU, llama only)
+    #[arg(long)]
+    num_gpu_layers: Option<usize>,
+
+    /// Log the rendered prompt (special tokens visible) and sampling parameters of each request,
+    /// `redacted` only keeps the special tokens of the prompt
+    #[arg(long, value_enum, default_value_t = PromptLogging::Off)]
+    log_prompts: PromptLogging,
+
+    /// Abort startup if the native paged attention kernels do not work on the GPU instead of
+    /// falling back to the (much slower) naive attention implementation
+    #[arg(long)]
+    require_native_kernels: bool,
+
+    /// Log format, `json` emits structured records (with the close events of the queue and
+    /// generation phase spans of each request) for log pipelines such as Loki or ELK.
+    /// The log level is set with `RUST_LOG` (default `info`)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Export the spans of the requests (queue, prefill and decoding steps) to this OTLP gRPC
+    /// collector, e.g. http://localhost:4317 (requires the `otlp` feature)
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Compress non-streaming responses with gzip or zstd when the client accepts it
+    /// (`Accept-Encoding`). Streamed responses are never compressed
+    #[arg(long)]
+    compress_responses: bool,
+
+    /// Smallest response (in bytes) compressed when compress_responses is set
+    #[arg(long, default_value_t = DEFAULT_MIN_COMPRESSED_BYTES)]
+    compression_min_bytes: u16,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), APIError> {
-    let args = Args::parse();
-    let (loader, model_id) = get_model_loader(args.command, args.model_id.clone());
-    if args.model_id.is_none() {
+/// The arguments of a model passed with `--extra-model`.
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct ExtraModelArgs {
+    /// Name the model is served under (default: the model type)
+    #[arg(long)]
+    name: Option<String>,
+
+    #[arg(long)]
+    model_id: Option<String>,
+
+    #[arg(long)]
+    revision: Option<String>,
+
+    #[arg(long)]
+    subfolder: Option<String>,
+
+    #[arg(long)]
+    weight_path: Option<String>,
+
+    /// Available GPU memory for the kvcache of this model (MB)
+    #[arg(long, default_value_t = 1024)]
+    kvcache_mem_gpu: usize,
+
+    /// Available CPU memory for the kvcache of this model (MB)
+    #[arg(long, default_value_t = 1024)]
+    kvcache_mem_cpu: usize,
+
+    #[clap(subcommand)]
+    command: ModelSelected,
+}
+
+/// What differs between the served models, the other arguments are shared.
+#[derive(Clone)]
+struct ModelSpec {
+    name: Option<String>,
+    command: ModelSelected,
+    model_id: Option<String>,
+    revision: Option<String>,
+    subfolder: Option<String>,
+    weight_path: Option<String>,
+    kvcache_mem_gpu: usize,
+    kvcache_mem_cpu: usize,
+}
+
+/// GPU memory left under --gpu-memory-limit for the models still to be loaded.
+struct GpuMemoryBudget {
+    limit: usize,
+    used: usize,
+    models_left: usize,
+}
+
+impl GpuMemoryBudget {
+    /// Check that the weights and KV cache of a model fit and reserve them, with an even share of
+    /// the remaining memory as the workspace of the model, which is returned.
+    fn reserve(
+        &mut self,
+        model_name: &str,
+        weights: usize,
+        kv_cache: usize,
+    ) -> Result<usize, APIError> {
+        let required = self.used + weights + kv_cache;
+        if required >= self.limit {
+            return Err(APIError::new(format!(
+                "{model_name} does not fit in --gpu-memory-limit {} MB: weights {} MB, KV cache \
+                 {} MB, other models {} MB. Lower --kvcache-mem-gpu or quantize the model.",
+                self.limit / SIZE_IN_MB,
+                weights / SIZE_IN_MB,
+                kv_cache / SIZE_IN_MB,
+                self.used / SIZE_IN_MB
+            )));
+        }
+        let workspace = (self.limit - required) / self.models_left.max(1);
+        self.used = required + workspace;
+        self.models_left = self.models_left.saturating_sub(1);
+        println!(
+            "GPU memory of {model_name}: weights {} MB, KV cache {} MB, workspace {} MB ({} of the \
+             {} MB limit used)",
+            weights / SIZE_IN_MB,
+            kv_cache / SIZE_IN_MB,
+            workspace / SIZE_IN_MB,
+            self.used / SIZE_IN_MB,
+            self.limit / SIZE_IN_MB
+        );
+        Ok(workspace)
+    }
+}
+
+/// Install the logger, and the exporter of the request spans with `--otlp-endpoint`, which is
+/// flushed when the returned guard is dropped.
+fn init_logging(
+    format: LogFormat,
+    otlp_endpoint: Option<&str>,
+) -> Result<Option<OtlpGuard>, APIError> {
+    let (otlp, guard) = match otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, guard) = otel::otlp_layer(endpoint)?;
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::registry().with(otlp).with(filter);
+    let logs = tracing_subscriber::fmt::layer().with_target(false);
+    match format {
+        LogFormat::Text => subscriber.with(logs).init(),
+        LogFormat::Json => subscriber
+            .with(
+                logs.json()
+                    .flatten_event(true)
+                    .with_current_span(true)
+                    .with_span_list(false)
+                    .with_span_events(FmtSpan::CLOSE),
+            )
+            .init(),
+    }
+    Ok(guard)
+}
+
+/// Load a model and create its engine.
+/// Dtype of the model on `device`, see `--dtype`.
+fn model_dtype(name: Option<&str>, device: &Device) -> Result<DType, APIError> {
+    let compute_capability = compute_capability(device)?;
+    let dtype = match name {
+        Some("auto") => match (device, compute_capability) {
+            (Device::Cpu, _) => DType::F32,
+            (_, Some((major, _))) if major >= 8 => DType::BF16,
+            _ => DType::F16,
+        },
+        Some(name) => weights::parse_dtype(name)
+            .ok_or_else(|| APIError::new(format!("Unsupported dtype {name}")))?,
+        None => DType::BF16,
+    };
+    match compute_capability {
+        Some((major, minor)) if dtype == DType::BF16 && major < 8 => {
+            tracing::warn!(
+                "GPU compute capability {major}.{minor} has no bf16 GEMMs, running in f16 instead."
+            );
+            Ok(DType::F16)
+        }
+        _ => {
+            tracing::info!("Running the model in {dtype:?}.");
+            Ok(dtype)
+        }
+    }
+}
+
+async fn load_served_model(
+    args: &Args,
+    spec: ModelSpec,
+    budget: &mut Option<GpuMemoryBudget>,
+) -> Result<ServedModel, APIError> {
+    // The mock model has nothing to download
+    let mock = matches!(spec.command, ModelSelected::Mock { .. });
+    let (loader, model_id) = get_model_loader(spec.command, spec.model_id.clone());
+    if spec.model_id.is_none() {
         println!("No model id specified, using the default model or specified in the weight_path!");
     }
 
-    let paths = match &args.weight_path {
+    let paths = match &spec.weight_path {
         Some(path) => Box::new(DefaultModelPaths {
             tokenizer_filename: (path.to_owned() + "tokenizer.json").into(),
+            tokenizer_config_filename: Some(PathBuf::from(
+                path.to_owned() + "tokenizer_config.json",
+            ))
+            .filter(|path| path.exists()),
+            generation_config_filename: Some(PathBuf::from(
+                path.to_owned() + "generation_config.json",
+            ))
+            .filter(|path| path.exists()),
             config_filename: (path.to_owned() + "config.json").into(),
-            filenames: if Path::new(&(path.to_owned() + "model.safetensors.index.json")).exists() {
-                hub_load_local_safetensors(path, "model.safetensors.index.json").unwrap()
-            } else {
-                //a single weight file case
-                let mut safetensors_files = Vec::<std::path::PathBuf>::new();
-                safetensors_files.insert(0, (path.to_owned() + "model.safetensors").into());
-                safetensors_files
-            },
+            filenames: local_weight_files(path).map_err(|e| APIError::new(e.to_string()))?,
         }),
         _ => {
-            if args.hf_token.is_none() && args.hf_token_path.is_none() {
+            if args.hf_token.is_none() && args.hf_token_path.is_none() && !mock {
                 //no token provided
                 let token_path = format!(
                     "{}/.cache/huggingface/token",
@@ -121,82 +608,711 @@ async fn main() -> Result<(), APIError> {
                     write!(output, "{}", input_token.trim()).expect("Failed to save token!");
                 }
             }
-            loader.download_model(model_id, None, args.hf_token, args.hf_token_path)?
+            loader.download_model(
+                model_id,
+                spec.revision.clone(),
+                args.hf_token.clone(),
+                args.hf_token_path.clone(),
+                args.hf_endpoint.clone(),
+                spec.subfolder.clone(),
+            )?
         }
     };
 
-    let dtype = match args.dtype.as_deref() {
-        Some("f16") => DType::F16,
-        Some("bf16") => DType::BF16,
-        Some("f32") => DType::F32,
-        Some(dtype) => panic!("Unsupported dtype {dtype}"),
-        None => DType::BF16,
-    };
+    let self_extend = args
+        .self_extend_group_size
+        .map(|group_size| SelfExtend::new(group_size, args.self_extend_window));
 
     let device = candle_examples::device(args.cpu).unwrap();
-    let model = loader.load_model(paths, dtype, device)?;
+    let dtype = model_dtype(args.dtype.as_deref(), &device)?;
+    let free_before_load = memory_info(&device)?.map(|(free, _)| free);
+    let model = loader.load_model(
+        paths,
+        LoadOptions {
+            dtype_overrides: args.dtype_override.clone(),
+            self_extend,
+            stream_weights: args.stream_weights,
+            num_gpu_layers: args.num_gpu_layers,
+            ..LoadOptions::new(dtype, device)
+        },
+    )?;
     let config: Config = model.0.get_model_config();
-    let dsize = config.kv_cache_dtype.size_in_bytes();
-    let num_gpu_blocks = args.kvcache_mem_gpu * SIZE_IN_MB
-        / dsize
-        / args.block_size
-        / config.num_key_value_heads
-        / config.get_head_size()
-        / config.num_hidden_layers
-        / 2;
-    let num_cpu_blocks = args.kvcache_mem_cpu * SIZE_IN_MB
-        / dsize
-        / args.block_size
-        / config.num_key_value_heads
-        / config.get_head_size()
-        / config.num_hidden_layers
-        / 2;
+    probe_native_kernels(
+        model.0.device(),
+        config.kv_cache_dtype,
+        args.require_native_kernels,
+    )?;
+    let compute_capability = compute_capability(model.0.device())?;
+    if let Some((major, minor)) = compute_capability {
+        println!("GPU compute capability {major}.{minor}");
+    }
+    let model_name = spec.name.unwrap_or_else(|| model.0.name().to_string());
+    let kv_cache_dtype = match args.kv_cache_dtype.as_deref() {
+        Some("auto") | None => config.kv_cache_dtype,
+        Some("int8") => {
+            if !model.0.device().is_cuda() || naive_kernels_enabled() {
+                return Err(APIError::new_str(
+                    "The int8 KV cache requires the native CUDA paged attention kernels.",
+                ));
+            }
+            if KvCacheLayout::of(&config) == KvCacheLayout::Latent {
+                return Err(APIError::new_str(
+                    "The int8 KV cache is not supported for latent attention models.",
+                ));
+            }
+            if config.num_gpu_layers() < config.num_hidden_layers {
+                return Err(APIError::new_str(
+                    "The int8 KV cache is not supported for layers offloaded to the CPU.",
+                ));
+            }
+            DType::U8
+        }
+        Some(dtype) => {
+            return Err(APIError::new(format!(
+                "Unsupported KV cache dtype {dtype}, use auto or int8."
+            )))
+        }
+    };
+    // INT8 blocks also hold their scales, latent caches have no value blocks
+    let block_bytes = CacheEngine::block_bytes(&config, kv_cache_dtype, args.block_size, 1);
+    // Recurrent models have no KV blocks, the KV cache memory holds a fixed-size state per
+    // sequence instead. Encoder-decoder models keep the self- and cross-attention keys and values
+    // of each sequence apart, each at most a full context.
+    let state_bytes = match model.0.recurrent_state()? {
+        Some(state) => Some(
+            state
+                .iter()
+                .map(|tensor| tensor.elem_count() * tensor.dtype().size_in_bytes())
+                .sum::<usize>(),
+        ),
+        None if model.0.decoder_start_token_id().is_some() => Some(
+            2 * CacheEngine::block_bytes(&config, config.kv_cache_dtype, 1, 1)
+                * config.get_max_model_len(),
+        ),
+        None => None,
+    };
+    let state_slots = state_bytes.map(|bytes| spec.kvcache_mem_gpu * SIZE_IN_MB / bytes.max(1));
+    if let (Some(bytes), Some(0)) = (state_bytes, state_slots) {
+        return Err(APIError::new(format!(
+            "--kvcache-mem-gpu is too small for the state of one sequence ({bytes} bytes)."
+        )));
+    }
+    let (num_gpu_blocks, num_cpu_blocks) = match state_slots {
+        Some(_) => (0, 0),
+        None => (
+            spec.kvcache_mem_gpu * SIZE_IN_MB / block_bytes,
+            spec.kvcache_mem_cpu * SIZE_IN_MB / block_bytes,
+        ),
+    };
+    let cache_bytes = match (state_bytes, state_slots) {
+        (Some(bytes), Some(slots)) => {
+            println!("State cache of {slots} sequences ({bytes} bytes each)");
+            bytes * slots
+        }
+        _ => num_gpu_blocks * block_bytes,
+    };
     let cache_config = CacheConfig {
         block_size: args.block_size,
         num_gpu_blocks: Some(num_gpu_blocks),
         num_cpu_blocks: Some(num_cpu_blocks),
         fully_init: true,
-        dtype: config.kv_cache_dtype,
+        dtype: kv_cache_dtype,
+        tensor_parallel_size: 1,
     };
     println!("Cache config {:?}", cache_config);
-    let finish_notify = Arc::new(Notify::new());
+    // The KV cache is allocated with the engine, count it as used
+    let free_after_load = memory_info(model.0.device())?.map(|(free, _)| free);
+    let mut free_memory =
+        free_after_load.map(|free| free.saturating_sub(spec.kvcache_mem_gpu * SIZE_IN_MB));
+    let mut capped = false;
+    match (budget.as_mut(), free_before_load.zip(free_after_load)) {
+        (Some(budget), Some((before, after))) => {
+            let workspace =
+                budget.reserve(&model_name, before.saturating_sub(after), cache_bytes)?;
+            free_memory = free_memory.map(|free| free.min(workspace));
+            capped = true;
+        }
+        (Some(_), None) => {
+            tracing::warn!("--gpu-memory-limit only applies to CUDA devices, it is ignored.")
+        }
+        (None, _) => {}
+    }
+    let auto_limits = auto_batch_limits(
+        &config,
+        dtype,
+        num_gpu_blocks,
+        args.block_size,
+        state_slots,
+        free_memory,
+    );
+    let mut max_num_seqs = args.max_num_seqs.unwrap_or(auto_limits.max_num_seqs);
+    if let Some(state_slots) = state_slots.filter(|slots| max_num_seqs > *slots) {
+        tracing::warn!(
+            state_slots,
+            "max_num_seqs lowered to the sequences whose state fits in --kvcache-mem-gpu."
+        );
+        max_num_seqs = state_slots;
+    }
+    let mut max_num_batched_tokens = args
+        .max_num_batched_tokens
+        .unwrap_or(auto_limits.max_num_batched_tokens);
+    // Larger batches than the workspace holds would allocate beyond the limit
+    if capped
+        && (max_num_seqs > auto_limits.max_num_seqs
+            || max_num_batched_tokens > auto_limits.max_num_batched_tokens)
+    {
+        tracing::warn!(
+            max_num_seqs = auto_limits.max_num_seqs,
+            max_num_batched_tokens = auto_limits.max_num_batched_tokens,
+            "Batch limits lowered to fit the workspace under --gpu-memory-limit."
+        );
+        max_num_seqs = max_num_seqs.min(auto_limits.max_num_seqs);
+        max_num_batched_tokens = max_num_batched_tokens.min(auto_limits.max_num_batched_tokens);
+    }
+    println!(
+        "Batch limits: max_num_seqs {max_num_seqs}, max_num_batched_tokens {max_num_batched_tokens} (~{:.1}B parameters)",
+        config.approx_num_params() as f64 / 1e9
+    );
     let llm_engine = LLMEngine::new(
         model.0,
         SchedulerConfig {
-            max_num_seqs: args.max_num_seqs,
+            max_num_seqs,
+            max_num_batched_tokens,
+            kv_eviction: args.kv_budget.map(|budget| HeavyHitterConfig {
+                budget,
+                recent_window: args.kv_recent_window,
+            }),
+            num_lookahead_slots: 0,
+            policy: args.scheduling_policy,
         },
         cache_config,
-        Arc::new(Notify::new()),
-        finish_notify.clone(),
     )?;
+    if args.energy_telemetry {
+        llm_engine.lock().await.enable_energy_telemetry()?;
+    }
+    if !args.skip_warmup && !mock && !llm_engine.lock().await.get_pipeline().is_encoder_only() {
+        let warmup_config = WarmupConfig::new(max_num_seqs, config.get_max_model_len());
+        let elapsed = warmup::run(&llm_engine, &warmup_config).await?;
+        println!(
+            "Warmup of {model_name} done in {:.2}s (batches of {:?} sequences).",
+            elapsed.as_secs_f64(),
+            warmup_config.batch_sizes
+        );
+    }
+    // After the warmup, whose requests are not traced
+    if args.otlp_endpoint.is_some() {
+        llm_engine
+            .lock()
+            .await
+            .add_observer(Arc::new(TracingObserver::new(model_name.clone())));
+    }
+    Ok(ServedModel::new(llm_engine, model.1, model_name, compute_capability).await)
+}
+
+/// Generate the completions of a batch input file with `served` and exit.
+async fn run_batch(served: &ServedModel, input: &Path, output: &Path) -> Result<(), APIError> {
+    if served.model.lock().await.get_pipeline().is_encoder_only() {
+        return Err(APIError::new_str(
+            "Batch inference requires a model that generates.",
+        ));
+    }
+    let jsonl = std::fs::read_to_string(input)
+        .map_err(|e| APIError::new(format!("Cannot read the batch input: {e}")))?;
+    let requests = batch::parse_requests(&jsonl)?;
+    let file = std::fs::File::create(output)
+        .map_err(|e| APIError::new(format!("Cannot create the batch output: {e}")))?;
+    let mut writer = std::io::BufWriter::new(file);
+    println!(
+        "Running {} batch requests with {}.",
+        requests.len(),
+        served.model_name
+    );
+    let summary = batch::run(served, requests, &mut writer).await?;
+    println!(
+        "Batch done in {:.1}s: {} requests ({} failed), {} prompt tokens, {} completion tokens, \
+         {:.1} tokens/s. Completions written to {}.",
+        summary.elapsed.as_secs_f64(),
+        summary.requests,
+        summary.failed,
+        summary.prompt_tokens,
+        summary.completion_tokens,
+        summary.throughput(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Probe `served` with the canary prompts in the background, unless it does not generate.
+async fn start_canary(served: &mut ServedModel, config: &CanaryConfig) {
+    if served.model.lock().await.get_pipeline().is_encoder_only() {
+        return;
+    }
+    let metrics = Arc::new(CanaryMetrics::default());
+    served.canary_metrics = Some(metrics.clone());
+    tokio::spawn(canary::run(
+        Arc::downgrade(&served.model),
+        served.model_name.clone(),
+        config.clone(),
+        metrics,
+    ));
+}
 
+/// Load the models of `/admin/reload` with the arguments of the server, in place of the models
+/// of `reloadable`. A new model must fit in the share of --gpu-memory-limit of the one it
+/// replaces.
+fn model_reloader(
+    args: Arc<Args>,
+    reloadable: HashMap<String, (ModelSpec, Option<usize>)>,
+    user_metrics: Arc<UserMetrics>,
+    canary_config: Option<CanaryConfig>,
+    token: String,
+) -> Reloader {
+    let reloadable = Arc::new(tokio::sync::Mutex::new(reloadable));
+    Reloader::new(
+        Box::new(move |model_name: String, request: ReloadRequest| {
+            let args = args.clone();
+            let reloadable = reloadable.clone();
+            let user_metrics = user_metrics.clone();
+            let canary_config = canary_config.clone();
+            async move {
+                let mut reloadable = reloadable.lock().await;
+                let Some((spec, share)) = reloadable.get_mut(&model_name) else {
+                    return Err(APIError::new(format!(
+                        "The model `{model_name}` cannot be reloaded."
+                    )));
+                };
+                let mut new_spec = spec.clone();
+                new_spec.name = Some(model_name.clone());
+                if request.model_id.is_some() {
+                    // The revision and local weights of the previous model do not apply
+                    new_spec.model_id = request.model_id;
+                    new_spec.revision = None;
+                    new_spec.weight_path = None;
+                }
+                if request.revision.is_some() {
+                    new_spec.revision = request.revision;
+                }
+                if request.weight_path.is_some() {
+                    new_spec.weight_path = request.weight_path;
+                }
+                let mut budget = share.map(|limit| GpuMemoryBudget {
+                    limit,
+                    used: 0,
+                    models_left: 1,
+                });
+                // Loading blocks, and its loader is not sent between threads
+                let loaded_spec = new_spec.clone();
+                let mut served = tokio::task::spawn_blocking(move || {
+                    tokio::runtime::Handle::current().block_on(load_served_model(
+                        &args,
+                        loaded_spec,
+                        &mut budget,
+                    ))
+                })
+                .await
+                .map_err(APIError::from)??;
+                served.model.lock().await.user_metrics = user_metrics;
+                if let Some(config) = &canary_config {
+                    start_canary(&mut served, config).await;
+                }
+                *spec = new_spec;
+                Ok(served)
+            }
+            .boxed()
+        }),
+        token,
+    )
+}
+
+/// Benchmark `served` with synthetic requests and exit.
+async fn run_bench(
+    served: &ServedModel,
+    config: &BenchConfig,
+    report_path: Option<&Path>,
+) -> Result<(), APIError> {
+    if served.model.lock().await.get_pipeline().is_encoder_only() {
+        return Err(APIError::new_str(
+            "Benchmarks require a model that generates.",
+        ));
+    }
+    println!(
+        "Benchmarking {} with {} requests.",
+        served.model_name, config.num_requests
+    );
+    let report = bench::run(served, config).await?;
+    println!("{report}");
+    if let Some(path) = report_path {
+        let json = serde_json::to_string_pretty(&report).map_err(APIError::from)?;
+        std::fs::write(path, json)
+            .map_err(|e| APIError::new(format!("Cannot write the benchmark report: {e}")))?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), APIError> {
+    let args = Args::parse();
+    let _otlp = init_logging(args.log_format, args.otlp_endpoint.as_deref())?;
+    candle_vllm::fault::init()?;
+    if args.self_extend_group_size == Some(0) {
+        return Err(APIError::new_str(
+            "--self-extend-group-size must be at least 1.",
+        ));
+    }
+
+    let mut specs = vec![ModelSpec {
+        name: args.served_model_name.clone(),
+        command: args.command.clone(),
+        model_id: args.model_id.clone(),
+        revision: args.revision.clone(),
+        subfolder: args.subfolder.clone(),
+        weight_path: args.weight_path.clone(),
+        kvcache_mem_gpu: args.kvcache_mem_gpu,
+        kvcache_mem_cpu: args.kvcache_mem_cpu,
+    }];
+    // The quantized variant of the model, over the same weights
+    let quality_router = match &args.fast_quant {
+        Some(quant) => {
+            let command = args.command.with_quant(quant.clone()).ok_or_else(|| {
+                APIError::new(format!("{} models cannot be quantized.", args.command))
+            })?;
+            let best = args
+                .served_model_name
+                .clone()
+                .unwrap_or_else(|| args.command.to_string());
+            let fast = format!("{best}-fast");
+            specs[0].name = Some(best.clone());
+            let router =
+                QualityRouter::new(best, fast.clone(), args.fast_weight, args.best_weight)?;
+            specs.push(ModelSpec {
+                name: Some(fast),
+                command,
+                model_id: args.model_id.clone(),
+                revision: args.revision.clone(),
+                subfolder: args.subfolder.clone(),
+                weight_path: args.weight_path.clone(),
+                kvcache_mem_gpu: args.fast_kvcache_mem_gpu,
+                kvcache_mem_cpu: args.fast_kvcache_mem_cpu,
+            });
+            Some(router)
+        }
+        None => None,
+    };
+    for extra_model in &args.extra_model {
+        let extra = ExtraModelArgs::try_parse_from(extra_model.split_whitespace())
+            .unwrap_or_else(|e| e.exit());
+        specs.push(ModelSpec {
+            name: extra.name,
+            command: extra.command,
+            model_id: extra.model_id,
+            revision: extra.revision,
+            subfolder: extra.subfolder,
+            weight_path: extra.weight_path,
+            kvcache_mem_gpu: extra.kvcache_mem_gpu,
+            kvcache_mem_cpu: extra.kvcache_mem_cpu,
+        });
+    }
+
+    let mut budget = args.gpu_memory_limit.map(|limit| {
+        // Under CUDA MPS the driver enforces the limit, it must be set before the CUDA context
+        // is created
+        if std::env::var_os("CUDA_MPS_PINNED_DEVICE_MEM_LIMIT").is_none() {
+            std::env::set_var("CUDA_MPS_PINNED_DEVICE_MEM_LIMIT", format!("0={limit}M"));
+        }
+        GpuMemoryBudget {
+            limit: limit * SIZE_IN_MB,
+            used: 0,
+            models_left: specs.len(),
+        }
+    });
+
+    let user_metrics = Arc::new(UserMetrics::default());
+    let mut models: Vec<ServedModel> = Vec::new();
+    // The spec and share of --gpu-memory-limit of every model, to load another in its place
+    let mut reloadable = HashMap::new();
+    for spec in specs {
+        let used_before = budget.as_ref().map(|budget| budget.used);
+        let served = load_served_model(&args, spec.clone(), &mut budget).await?;
+        if models
+            .iter()
+            .any(|model| model.model_name == served.model_name)
+        {
+            return Err(APIError::new(format!(
+                "Two models are served as `{}`, set their names with --served-model-name or \
+                 the --name of --extra-model.",
+                served.model_name
+            )));
+        }
+        served.model.lock().await.user_metrics = user_metrics.clone();
+        let share = budget
+            .as_ref()
+            .zip(used_before)
+            .map(|(budget, used_before)| budget.used - used_before);
+        reloadable.insert(served.model_name.clone(), (spec, share));
+        models.push(served);
+    }
+
+    if let (Some(input), Some(output)) = (&args.batch_input, &args.batch_output) {
+        return run_batch(&models[0], input, output).await;
+    }
+    if let Some(num_requests) = args.bench_requests {
+        let config = BenchConfig {
+            num_requests,
+            prompt_len: args.bench_prompt_len,
+            output_len: args.bench_output_len,
+            request_rate: args.bench_request_rate,
+            seed: args.bench_seed,
+        };
+        return run_bench(&models[0], &config, args.bench_report.as_deref()).await;
+    }
+
+    let canary_config = match &args.canary_prompts {
+        Some(path) => Some(CanaryConfig {
+            prompts: canary::load_prompts(path)?,
+            max_tokens: args.canary_max_tokens,
+            interval: Duration::from_secs(args.canary_interval.max(1)),
+            baseline_dir: args.canary_baseline_dir.clone(),
+        }),
+        None => None,
+    };
+    if let Some(config) = &canary_config {
+        for served in models.iter_mut() {
+            start_canary(served, config).await;
+        }
+    }
+    if args.admin_token.as_deref().is_some_and(str::is_empty) {
+        return Err(APIError::new_str("`--admin-token` must not be empty."));
+    }
+    let reloader = args.admin_token.clone().map(|token| {
+        model_reloader(
+            Arc::new(args.clone()),
+            reloadable,
+            user_metrics.clone(),
+            canary_config,
+            token,
+        )
+    });
+
+    let plugins = match &args.plugins_dir {
+        Some(dir) => PluginHost::load_dir(dir, PluginLimits::default())?,
+        None => PluginHost::default(),
+    };
+    if !plugins.is_empty() {
+        tracing::info!(plugins = ?plugins.names(), "Plugins loaded");
+    }
+
+    let request_timeout = match args.request_timeout.map(Duration::try_from_secs_f64) {
+        None => None,
+        Some(Ok(timeout)) if !timeout.is_zero() => Some(timeout),
+        Some(_) => {
+            return Err(APIError::new_str(
+                "`--request-timeout` must be a positive number of seconds.",
+            ))
+        }
+    };
     let server_data = OpenAIServerData {
-        pipeline_config: model.1,
-        model: llm_engine,
+        models: RwLock::new(models.into_iter().map(Arc::new).collect()),
         record_conversation: args.record_conversation,
+        default_system_prompt: args.default_system_prompt,
         device: Device::Cpu,
-        finish_notify: finish_notify.clone(),
+        log_prompts: args.log_prompts,
+        user_metrics,
+        plugins,
+        max_waiting_requests: args.max_waiting_requests,
+        request_timeout,
+        rate_limiter: (args.rate_limit_rpm.is_some() || args.rate_limit_tpm.is_some()).then(|| {
+            RateLimiter::new(RateLimits {
+                requests_per_minute: args.rate_limit_rpm,
+                tokens_per_minute: args.rate_limit_tpm,
+            })
+        }),
+        sessions: args.session_history.then(|| {
+            Arc::new(SessionStore::new(SessionLimits {
+                max_sessions: args.max_sessions,
+                max_messages: args.session_max_messages,
+                ttl: Duration::from_secs(args.session_ttl),
+            }))
+        }),
+        quality_router,
+        reloader,
     };
 
-    println!("Server started at http://127.0.0.1:{}.", args.port);
-
     let allow_origin = AllowOrigin::any();
     let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE])
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            http::HeaderName::from_static(REQUEST_ID_HEADER),
+        ])
+        .expose_headers([
+            http::HeaderName::from_static(REQUEST_ID_HEADER),
+            http::header::RETRY_AFTER,
+            http::HeaderName::from_static("x-ratelimit-remaining-requests"),
+            http::HeaderName::from_static("x-ratelimit-remaining-tokens"),
+        ])
         .allow_origin(allow_origin);
 
+    let data = Arc::new(server_data);
+    let grpc_data = data.clone();
+    // Served on its own listener, see --admin-port
+    let admin_app = Router::new()
+        .route("/admin/reload", post(reload_model))
+        .with_state(data.clone());
     let app = Router::new()
         .layer(cors_layer)
         .route("/v1/chat/completions", post(chat_completions))
-        .with_state(Arc::new(server_data));
+        .route("/v1/chat/completions/fork", post(fork_chat_completion))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/token_classify", post(token_classify))
+        .route("/v1/score", post(score))
+        // Only the routes above count against the rate limits, and are drained by reloads
+        .route_layer(middleware::from_fn_with_state(data.clone(), rate_limit))
+        .route_layer(middleware::from_fn_with_state(data.clone(), admission))
+        .route(
+            "/v1/abort/:request_id",
+            post(abort_request).delete(abort_request),
+        )
+        .route("/v1/models", get(models))
+        .route("/v1/capabilities", get(capabilities))
+        .route("/metrics", get(metrics))
+        .route("/v1/stats", get(stats))
+        .route(
+            "/v1/sessions/:session_id",
+            get(get_session).delete(delete_session),
+        )
+        .layer(middleware::from_fn_with_state(
+            data.clone(),
+            queue_depth_header,
+        ))
+        .with_state(data);
+    #[cfg(feature = "playground")]
+    let app = app.route("/", get(candle_vllm::openai::playground::playground));
+    #[cfg(feature = "pprof")]
+    let app = app
+        .route(
+            "/debug/pprof/profile",
+            get(candle_vllm::openai::profiling::profile),
+        )
+        .route(
+            "/debug/pprof/allocs",
+            get(candle_vllm::openai::profiling::allocs),
+        );
+    let app = if args.compress_responses {
+        app.layer(compression_layer(args.compression_min_bytes))
+    } else {
+        app
+    };
 
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", args.port))
-        .await
-        .map_err(|e| APIError::new(e.to_string()))?;
-    axum::serve(listener, app)
-        .await
-        .map_err(|e| APIError::new(e.to_string()))?;
+    // The server stops when one of its transports fails
+    let mut servers: Vec<BoxFuture<'static, Result<(), APIError>>> = Vec::new();
+    if let Some(path) = args.uds {
+        servers.push(serve_unix(app.clone(), path).boxed());
+    }
+    if let Some(port) = args.admin_port {
+        let addr = SocketAddr::new(args.admin_host, port);
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|e| APIError::new(e.to_string()))?;
+        println!("Admin server started at http://{addr}.");
+        servers.push(
+            async move {
+                axum::serve(listener, admin_app)
+                    .await
+                    .map_err(|e| APIError::new(e.to_string()))
+            }
+            .boxed(),
+        );
+    }
+    if let Some(port) = args.grpc_port {
+        let addr = SocketAddr::new(args.host, port);
+        println!("gRPC server started at {addr}.");
+        servers.push(grpc::serve(grpc_data, addr).boxed());
+    }
+    let addr = SocketAddr::new(args.host, args.port);
+    match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls_config = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .map_err(|e| APIError::new(format!("Unable to load TLS certificate: {e}")))?;
+            println!("Server started at https://{addr}.");
+            servers.push(
+                async move {
+                    axum_server::bind_rustls(addr, tls_config)
+                        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                        .await
+                        .map_err(|e| APIError::new(e.to_string()))
+                }
+                .boxed(),
+            );
+        }
+        _ => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| APIError::new(e.to_string()))?;
+            println!("Server started at http://{addr}.");
+            servers.push(
+                async move {
+                    axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .await
+                    .map_err(|e| APIError::new(e.to_string()))
+                }
+                .boxed(),
+            );
+        }
+    }
+    futures::future::try_join_all(servers).await?;
 
     Ok(())
 }
+
+/// Serve `app` on the Unix domain socket at `path`. The socket file of an earlier server is
+/// replaced.
+#[cfg(unix)]
+async fn serve_unix(app: Router, path: PathBuf) -> Result<(), APIError> {
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto::Builder;
+    use hyper_util::service::TowerToHyperService;
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(APIError::new(format!(
+                "{} exists and is not a socket.",
+                path.display()
+            )));
+        }
+        std::fs::remove_file(&path).map_err(|e| APIError::new(e.to_string()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(&path)
+        .map_err(|e| APIError::new(format!("Unable to bind {}: {e}", path.display())))?;
+    println!("Server started at unix:{}.", path.display());
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                tracing::warn!("Unable to accept a Unix socket connection: {e}");
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(socket), service)
+                .await
+            {
+                tracing::debug!("Unix socket connection failed: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_app: Router, _path: PathBuf) -> Result<(), APIError> {
+    Err(APIError::new_str(
+        "Unix domain sockets are only supported on Unix.",
+    ))
+}
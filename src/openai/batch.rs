@@ -0,0 +1,186 @@
+//! Offline batch inference for dataset generation and throughput jobs. With `--batch-input
+//! <FILE>` the server does not listen: every line of the JSONL file is a request, all of them are
+//! submitted to the engine at once so that the scheduler batches as many as fit, and their
+//! completions are written to `--batch-output` in the order of the input, one JSON object per
+//! line.
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use super::pipelines::llm_engine::LLMEngine;
+use super::requests::StopTokens;
+use super::responses::APIError;
+use super::sampling_params::{EarlyStoppingCondition, SamplingParams};
+use super::ServedModel;
+
+/// A line of the batch input. Prompts are generated from as is, without the chat template.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchRequest {
+    /// Echoed in the output, `line-<n>` by default
+    #[serde(default)]
+    pub id: Option<String>,
+    pub prompt: String,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<isize>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+/// A line of the batch output. A request that failed has an `error` and no text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchOutput {
+    pub id: String,
+    pub text: String,
+    pub finish_reason: Option<String>,
+    pub usage: BatchUsage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Totals of a batch job.
+#[derive(Debug, Clone, Default)]
+pub struct BatchSummary {
+    pub requests: usize,
+    pub failed: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub elapsed: Duration,
+}
+
+impl BatchSummary {
+    /// Generated tokens per second over the whole job.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.completion_tokens as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Parse the requests of a JSONL batch input, blank lines are skipped.
+pub fn parse_requests(jsonl: &str) -> Result<Vec<BatchRequest>, APIError> {
+    let requests = jsonl
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            let mut request: BatchRequest = serde_json::from_str(line)
+                .map_err(|e| APIError::new(format!("Line {} of the batch input: {e}", idx + 1)))?;
+            request
+                .id
+                .get_or_insert_with(|| format!("line-{}", idx + 1));
+            Ok(request)
+        })
+        .collect::<Result<Vec<_>, APIError>>()?;
+    if requests.is_empty() {
+        return Err(APIError::new_str("The batch input has no requests."));
+    }
+    Ok(requests)
+}
+
+/// Generate the completion of a request, a failed request is reported in its output.
+async fn complete(served: &ServedModel, request: BatchRequest) -> BatchOutput {
+    let id = request.id.clone().unwrap_or_default();
+    let mut output = BatchOutput {
+        id,
+        text: String::new(),
+        finish_reason: None,
+        usage: BatchUsage::default(),
+        error: None,
+    };
+    if let Err(e) = generate(served, request, &mut output).await {
+        output.text.clear();
+        output.finish_reason = None;
+        output.error = Some(e.to_string());
+    }
+    output
+}
+
+async fn generate(
+    served: &ServedModel,
+    request: BatchRequest,
+    output: &mut BatchOutput,
+) -> Result<(), APIError> {
+    let config = &served.pipeline_config;
+    let sampling_params = SamplingParams::new(
+        1,
+        None,
+        0.,
+        0.,
+        config.penalty,
+        request.temperature.unwrap_or(config.temperature),
+        request.top_p.unwrap_or(1.),
+        request.top_k.unwrap_or(-1),
+        false,
+        1.,
+        EarlyStoppingCondition::UnlikelyBetterCandidates,
+        (!request.stop.is_empty()).then_some(StopTokens::Multi(request.stop)),
+        vec![],
+        false,
+        request.max_tokens.unwrap_or(config.default_max_tokens),
+        None,
+        None,
+        true,
+    )?;
+    let mut tokens = LLMEngine::generate(&served.model, &request.prompt, sampling_params).await?;
+    output.usage.prompt_tokens = tokens.prompt_tokens();
+    while let Some(token) = tokens.next().await {
+        let token = token?;
+        output.text.push_str(&token.text);
+        output.usage.completion_tokens += 1;
+        if token.finish_reason.is_some() {
+            output.finish_reason = token.finish_reason;
+        }
+    }
+    output.usage.total_tokens = output.usage.prompt_tokens + output.usage.completion_tokens;
+    Ok(())
+}
+
+/// Run the requests through the engine of `served` and write their outputs to `out`, in the
+/// order of the requests. All the requests are in flight at once: the scheduler runs as many of
+/// them per batch as its limits and the KV cache allow.
+pub async fn run<W: Write>(
+    served: &ServedModel,
+    requests: Vec<BatchRequest>,
+    out: &mut W,
+) -> Result<BatchSummary, APIError> {
+    let start = Instant::now();
+    let mut summary = BatchSummary {
+        requests: requests.len(),
+        ..Default::default()
+    };
+    let in_flight = requests.len().max(1);
+    let mut outputs = futures::stream::iter(requests)
+        .map(|request| complete(served, request))
+        .buffered(in_flight);
+    while let Some(output) = outputs.next().await {
+        if let Some(e) = &output.error {
+            tracing::warn!(id = %output.id, "Batch request failed: {e}");
+            summary.failed += 1;
+        }
+        summary.prompt_tokens += output.usage.prompt_tokens;
+        summary.completion_tokens += output.usage.completion_tokens;
+        serde_json::to_writer(&mut *out, &output).map_err(APIError::from)?;
+        writeln!(out).map_err(APIError::from)?;
+    }
+    out.flush().map_err(APIError::from)?;
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
@@ -0,0 +1,312 @@
+//! Built-in benchmark. With `--bench-requests <N>` the server does not listen: it sends `N`
+//! synthetic requests straight to the engine, with prompt and output lengths drawn from
+//! `--bench-prompt-len` and `--bench-output-len`, all at once or arriving at
+//! `--bench-request-rate` per second (a Poisson process), and reports the throughput, the time to
+//! first token (TTFT), the inter-token latency (ITL) and the KV cache usage.
+
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use super::pipelines::llm_engine::LLMEngine;
+use super::responses::APIError;
+use super::sampling_params::SamplingParams;
+use super::ServedModel;
+
+/// Words of the synthetic prompts, most tokenizers encode each one as a single token.
+const WORDS: [&str; 32] = [
+    "the", "of", "and", "to", "in", "is", "for", "on", "with", "as", "at", "by", "from", "that",
+    "this", "it", "be", "are", "was", "or", "an", "not", "but", "all", "can", "one", "more",
+    "time", "new", "some", "her", "would",
+];
+
+/// KV cache usage is sampled at this interval during the run.
+const KV_CACHE_SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A length in tokens: `N`, or `MIN-MAX` drawn uniformly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthRange {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl LengthRange {
+    pub fn sample(&self, rng: &mut impl Rng) -> usize {
+        rng.gen_range(self.min..=self.max)
+    }
+}
+
+impl FromStr for LengthRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|e| format!("invalid length `{n}`: {e}"))
+        };
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if min == 0 || min > max {
+            return Err(format!(
+                "`{s}` is not a length `N` or a range `MIN-MAX` of lengths above 0"
+            ));
+        }
+        Ok(Self { min, max })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub num_requests: usize,
+    pub prompt_len: LengthRange,
+    pub output_len: LengthRange,
+    /// Requests per second, `None` sends them all at once
+    pub request_rate: Option<f64>,
+    pub seed: u64,
+}
+
+/// A synthetic request: its prompt, the tokens to generate and when it is sent.
+#[derive(Debug, Clone)]
+pub struct SyntheticRequest {
+    pub prompt: String,
+    pub output_len: usize,
+    pub arrival: Duration,
+}
+
+/// The requests of a benchmark, the same for a given seed. Every prompt starts with its index so
+/// that no two prompts share a cached prefix.
+pub fn synthetic_requests(config: &BenchConfig) -> Vec<SyntheticRequest> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut arrival = Duration::ZERO;
+    (0..config.num_requests)
+        .map(|idx| {
+            let len = config.prompt_len.sample(&mut rng);
+            let mut prompt = format!("{idx}");
+            for _ in 1..len {
+                prompt.push(' ');
+                prompt.push_str(WORDS[rng.gen_range(0..WORDS.len())]);
+            }
+            let request = SyntheticRequest {
+                prompt,
+                output_len: config.output_len.sample(&mut rng),
+                arrival,
+            };
+            if let Some(rate) = config.request_rate {
+                // Exponential inter-arrival times
+                let u = rng.gen_range(f64::EPSILON..1.0);
+                arrival += Duration::from_secs_f64(-u.ln() / rate);
+            }
+            request
+        })
+        .collect()
+}
+
+/// Mean and percentiles of a latency, in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Percentiles {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl Percentiles {
+    /// Nearest-rank percentiles of `samples`, zeros without samples.
+    pub fn of(samples: &[Duration]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        let mut ms = samples
+            .iter()
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .collect::<Vec<_>>();
+        ms.sort_by(f64::total_cmp);
+        let rank = |p: f64| ms[((p * ms.len() as f64).ceil() as usize).clamp(1, ms.len()) - 1];
+        Self {
+            mean: ms.iter().sum::<f64>() / ms.len() as f64,
+            p50: rank(0.5),
+            p90: rank(0.9),
+            p99: rank(0.99),
+        }
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mean {:.2} ms, p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms",
+            self.mean, self.p50, self.p90, self.p99
+        )
+    }
+}
+
+/// Timings of a completed request.
+struct RequestTimings {
+    prompt_tokens: usize,
+    output_tokens: usize,
+    ttft: Duration,
+    itls: Vec<Duration>,
+    latency: Duration,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BenchReport {
+    pub requests: usize,
+    pub failed: usize,
+    pub duration_secs: f64,
+    pub prompt_tokens: usize,
+    pub output_tokens: usize,
+    /// Completed requests per second
+    pub request_throughput: f64,
+    /// Generated tokens per second
+    pub output_throughput: f64,
+    /// Prompt and generated tokens per second
+    pub total_throughput: f64,
+    pub ttft: Percentiles,
+    pub itl: Percentiles,
+    /// Time from sending a request to its last token
+    pub latency: Percentiles,
+    /// Mean fraction of the GPU KV cache blocks in use during the run
+    pub kv_cache_usage_mean: f64,
+    pub kv_cache_usage_peak: f64,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Requests:          {} ({} failed) in {:.2}s",
+            self.requests, self.failed, self.duration_secs
+        )?;
+        writeln!(
+            f,
+            "Tokens:            {} prompt, {} generated",
+            self.prompt_tokens, self.output_tokens
+        )?;
+        writeln!(
+            f,
+            "Throughput:        {:.2} requests/s, {:.2} output tokens/s, {:.2} total tokens/s",
+            self.request_throughput, self.output_throughput, self.total_throughput
+        )?;
+        writeln!(f, "Time to 1st token: {}", self.ttft)?;
+        writeln!(f, "Inter-token:       {}", self.itl)?;
+        writeln!(f, "Latency:           {}", self.latency)?;
+        write!(
+            f,
+            "KV cache usage:    mean {:.1}%, peak {:.1}%",
+            self.kv_cache_usage_mean * 100.0,
+            self.kv_cache_usage_peak * 100.0
+        )
+    }
+}
+
+/// Send a request at its arrival time and time its tokens.
+async fn send(
+    served: &ServedModel,
+    request: SyntheticRequest,
+    start: Instant,
+) -> Result<RequestTimings, APIError> {
+    tokio::time::sleep_until((start + request.arrival).into()).await;
+    // Generates exactly `output_len` tokens
+    let sampling_params = SamplingParams::builder()
+        .temperature(served.pipeline_config.temperature)
+        .ignore_eos(true)
+        .max_tokens(request.output_len)
+        .build()?;
+    let sent = Instant::now();
+    let mut tokens = LLMEngine::generate(&served.model, &request.prompt, sampling_params).await?;
+    let prompt_tokens = tokens.prompt_tokens();
+    let mut ttft = None;
+    let mut itls = Vec::new();
+    let mut last = sent;
+    let mut output_tokens = 0;
+    while let Some(token) = tokens.next().await {
+        token?;
+        let now = Instant::now();
+        match ttft {
+            None => ttft = Some(now - sent),
+            Some(_) => itls.push(now - last),
+        }
+        last = now;
+        output_tokens += 1;
+    }
+    Ok(RequestTimings {
+        prompt_tokens,
+        output_tokens,
+        ttft: ttft.unwrap_or_default(),
+        itls,
+        latency: last - sent,
+    })
+}
+
+/// Run the benchmark against the engine of `served`.
+pub async fn run(served: &ServedModel, config: &BenchConfig) -> Result<BenchReport, APIError> {
+    let requests = synthetic_requests(config);
+    let done = AtomicBool::new(false);
+    let start = Instant::now();
+    let mut pending = requests
+        .into_iter()
+        .map(|request| send(served, request, start))
+        .collect::<FuturesUnordered<_>>();
+    let collect = async {
+        let mut timings = Vec::new();
+        let mut failed = 0;
+        while let Some(result) = pending.next().await {
+            match result {
+                Ok(request) => timings.push(request),
+                Err(e) => {
+                    tracing::warn!("Benchmark request failed: {e}");
+                    failed += 1;
+                }
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        (timings, failed)
+    };
+    let sample_kv_cache = async {
+        let mut samples = Vec::new();
+        while !done.load(Ordering::Relaxed) {
+            samples.push(served.kv_cache_metrics.usage_ratio());
+            tokio::time::sleep(KV_CACHE_SAMPLE_INTERVAL).await;
+        }
+        samples
+    };
+    let ((timings, failed), kv_cache_samples) = futures::join!(collect, sample_kv_cache);
+    let duration = start.elapsed().as_secs_f64();
+
+    let prompt_tokens = timings.iter().map(|t| t.prompt_tokens).sum::<usize>();
+    let output_tokens = timings.iter().map(|t| t.output_tokens).sum::<usize>();
+    let per_second = |n: f64| if duration > 0.0 { n / duration } else { 0.0 };
+    let ttfts = timings.iter().map(|t| t.ttft).collect::<Vec<_>>();
+    let itls = timings
+        .iter()
+        .flat_map(|t| t.itls.iter().copied())
+        .collect::<Vec<_>>();
+    let latencies = timings.iter().map(|t| t.latency).collect::<Vec<_>>();
+    Ok(BenchReport {
+        requests: config.num_requests,
+        failed,
+        duration_secs: duration,
+        prompt_tokens,
+        output_tokens,
+        request_throughput: per_second(timings.len() as f64),
+        output_throughput: per_second(output_tokens as f64),
+        total_throughput: per_second((prompt_tokens + output_tokens) as f64),
+        ttft: Percentiles::of(&ttfts),
+        itl: Percentiles::of(&itls),
+        latency: Percentiles::of(&latencies),
+        kv_cache_usage_mean: kv_cache_samples.iter().sum::<f64>()
+            / kv_cache_samples.len().max(1) as f64,
+        kv_cache_usage_peak: kv_cache_samples.iter().copied().fold(0.0, f64::max),
+    })
+}
@@ -0,0 +1,212 @@
+//! Canary probes, a safety net for silent numerical regressions. With `--canary-prompts <FILE>`
+//! (a JSON array of prompts), a background task generates greedily from every probe prompt at
+//! an interval and compares each output with a baseline: the outputs of its first run, or the
+//! ones recorded in `--canary-baseline-dir` by an earlier server, so that a new build, kernel or
+//! model revision is compared with the last known good one. A probe whose output differs from
+//! the baseline has drifted, it is logged as a warning (`event="canary_drift"`) and counted in
+//! `/metrics`.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use super::metrics::CanaryMetrics;
+use super::pipelines::llm_engine::LLMEngine;
+use super::responses::APIError;
+use super::sampling_params::SamplingParams;
+use futures::StreamExt;
+
+#[derive(Debug, Clone)]
+pub struct CanaryConfig {
+    pub prompts: Vec<String>,
+    /// Tokens generated per probe
+    pub max_tokens: usize,
+    pub interval: Duration,
+    /// Directory of the baselines, one `<model>.json` per served model
+    pub baseline_dir: Option<PathBuf>,
+}
+
+/// Read the probe prompts, a JSON array of strings. Prompts are generated from as is, without
+/// the chat template.
+pub fn load_prompts(path: &Path) -> Result<Vec<String>, APIError> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| APIError::new(format!("Cannot read the canary prompts: {e}")))?;
+    let prompts: Vec<String> = serde_json::from_str(&json).map_err(|e| {
+        APIError::new(format!(
+            "The canary prompts must be a JSON array of strings: {e}"
+        ))
+    })?;
+    if prompts.is_empty() {
+        return Err(APIError::new_str("No canary prompts."));
+    }
+    Ok(prompts)
+}
+
+/// The output of a probe prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProbeOutput {
+    pub prompt: String,
+    pub output: String,
+    /// FNV-1a hash of the output, stable across builds
+    pub hash: String,
+}
+
+impl ProbeOutput {
+    pub fn new(prompt: String, output: String) -> Self {
+        let hash = output
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        Self {
+            prompt,
+            output,
+            hash: format!("{hash:016x}"),
+        }
+    }
+}
+
+/// A probe whose output differs from its baseline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub prompt: String,
+    pub baseline_hash: String,
+    pub hash: String,
+    /// Fraction of the baseline output reproduced before the first difference
+    pub similarity: f64,
+}
+
+/// Fraction of the characters of `baseline` that `output` reproduces before it diverges, 1 for
+/// equal outputs. Greedy outputs diverge for good once a token differs, so this tells how early
+/// a regression changes the generation.
+pub fn similarity(baseline: &str, output: &str) -> f64 {
+    if baseline == output {
+        return 1.0;
+    }
+    let common = baseline
+        .chars()
+        .zip(output.chars())
+        .take_while(|(a, b)| a == b)
+        .count();
+    common as f64 / baseline.chars().count().max(output.chars().count()) as f64
+}
+
+/// The probes of `outputs` that differ from `baseline`. Probes without a baseline, e.g. prompts
+/// added since it was recorded, are not compared.
+pub fn compare(baseline: &[ProbeOutput], outputs: &[ProbeOutput]) -> Vec<Drift> {
+    outputs
+        .iter()
+        .filter_map(|output| {
+            let expected = baseline
+                .iter()
+                .find(|probe| probe.prompt == output.prompt)?;
+            (expected.hash != output.hash).then(|| Drift {
+                prompt: output.prompt.clone(),
+                baseline_hash: expected.hash.clone(),
+                hash: output.hash.clone(),
+                similarity: similarity(&expected.output, &output.output),
+            })
+        })
+        .collect()
+}
+
+fn baseline_path(dir: &Path, model_name: &str) -> PathBuf {
+    dir.join(format!("{}.json", model_name.replace(['/', '\\'], "_")))
+}
+
+/// Generate greedily from every probe prompt.
+async fn probe(
+    engine: &Arc<Mutex<LLMEngine>>,
+    config: &CanaryConfig,
+) -> Result<Vec<ProbeOutput>, APIError> {
+    let mut outputs = Vec::with_capacity(config.prompts.len());
+    for prompt in &config.prompts {
+        let sampling_params = SamplingParams::builder()
+            .temperature(0.)
+            .max_tokens(config.max_tokens)
+            .build()?;
+        let mut tokens = LLMEngine::generate(engine, prompt, sampling_params).await?;
+        let mut output = String::new();
+        while let Some(token) = tokens.next().await {
+            output.push_str(&token?.text);
+        }
+        outputs.push(ProbeOutput::new(prompt.clone(), output));
+    }
+    Ok(outputs)
+}
+
+/// Run the probes of `config` against the engine of `model_name` until the engine is unloaded,
+/// which the task does not keep alive.
+pub async fn run(
+    engine: Weak<Mutex<LLMEngine>>,
+    model_name: String,
+    config: CanaryConfig,
+    metrics: Arc<CanaryMetrics>,
+) {
+    let path = config
+        .baseline_dir
+        .as_deref()
+        .map(|dir| baseline_path(dir, &model_name));
+    let mut baseline = path.as_deref().and_then(|path| {
+        let json = std::fs::read_to_string(path).ok()?;
+        match serde_json::from_str::<Vec<ProbeOutput>>(&json) {
+            Ok(baseline) => Some(baseline),
+            Err(e) => {
+                tracing::warn!(model = %model_name, "Ignoring the canary baseline: {e}");
+                None
+            }
+        }
+    });
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        let Some(engine) = engine.upgrade() else {
+            return;
+        };
+        if engine.lock().await.is_stopped() {
+            return;
+        }
+        let outputs = match probe(&engine, &config).await {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                tracing::warn!(model = %model_name, event = "canary_failed", "Canary run failed: {e}");
+                metrics.record_failure();
+                continue;
+            }
+        };
+        let Some(expected) = &baseline else {
+            if let Some(path) = &path {
+                let written = serde_json::to_string_pretty(&outputs)
+                    .map_err(APIError::from)
+                    .and_then(|json| std::fs::write(path, json).map_err(APIError::from));
+                if let Err(e) = written {
+                    tracing::warn!(model = %model_name, "Cannot write the canary baseline: {e}");
+                }
+            }
+            tracing::info!(
+                model = %model_name,
+                probes = outputs.len(),
+                "Canary baseline recorded."
+            );
+            metrics.record_run(0);
+            baseline = Some(outputs);
+            continue;
+        };
+        let drifts = compare(expected, &outputs);
+        for drift in &drifts {
+            tracing::warn!(
+                model = %model_name,
+                event = "canary_drift",
+                prompt = %drift.prompt,
+                baseline_hash = %drift.baseline_hash,
+                hash = %drift.hash,
+                similarity = drift.similarity,
+                "Canary output drifted from the baseline ({:.0}% reproduced).",
+                drift.similarity * 100.0
+            );
+        }
+        metrics.record_run(drifts.len());
+    }
+}
@@ -0,0 +1,21 @@
+use tower_http::compression::{
+    predicate::{And, NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+/// Compress responses of at least this many bytes by default.
+pub const DEFAULT_MIN_COMPRESSED_BYTES: u16 = 1024;
+
+/// Responses that are compressed: bodies above the size threshold, except event streams whose
+/// chunks must reach the client as soon as they are generated.
+pub type CompressionPredicate = And<SizeAbove, NotForContentType>;
+
+/// gzip and zstd compression of large non-streaming responses (e.g. completions with logprobs or
+/// the choices of a fork), negotiated with the `Accept-Encoding` header of the request.
+/// Responses below `min_bytes` are sent as is, the compression would not pay for itself.
+pub fn compression_layer(min_bytes: u16) -> CompressionLayer<CompressionPredicate> {
+    CompressionLayer::new()
+        .gzip(true)
+        .zstd(true)
+        .compress_when(SizeAbove::new(min_bytes).and(NotForContentType::SSE))
+}
@@ -0,0 +1,134 @@
+//! Chat templates of Hugging Face models: the Jinja `chat_template` of `tokenizer_config.json`,
+//! rendered with minijinja. A model whose tokenizer config has no template, or whose template
+//! fails for a request, uses the built-in template of its `SeparatorStyle`.
+
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::openai::responses::APIError;
+
+const TEMPLATE_NAME: &str = "chat_template";
+
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    #[serde(default)]
+    chat_template: Option<TemplateField>,
+    #[serde(default)]
+    bos_token: Option<SpecialToken>,
+    #[serde(default)]
+    eos_token: Option<SpecialToken>,
+}
+
+/// A single template, or named templates (`default`, `tool_use`, ...).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TemplateField {
+    Single(String),
+    Named(Vec<NamedTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedTemplate {
+    name: String,
+    template: String,
+}
+
+/// Special tokens are strings or added token objects.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Text(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn content(self) -> String {
+        match self {
+            SpecialToken::Text(content) | SpecialToken::Added { content } => content,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TemplateMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+/// A compiled Jinja chat template.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    pub fn new(template: String, bos_token: String, eos_token: String) -> Result<Self, APIError> {
+        let mut env = Environment::new();
+        // Templates written for Python Jinja call string and dict methods such as `strip()`
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function(
+            "raise_exception",
+            |message: String| -> Result<String, Error> {
+                Err(Error::new(ErrorKind::InvalidOperation, message))
+            },
+        );
+        env.add_template_owned(TEMPLATE_NAME, template)
+            .map_err(|e| APIError::new(format!("Invalid chat template: {e}")))?;
+        Ok(Self {
+            env,
+            bos_token,
+            eos_token,
+        })
+    }
+
+    /// The template of a `tokenizer_config.json`, the `default` one if it has several. `None`
+    /// if it has no template.
+    pub fn from_tokenizer_config(path: &Path) -> Result<Option<Self>, APIError> {
+        let json = std::fs::read(path).map_err(APIError::from)?;
+        let config: TokenizerConfig = serde_json::from_slice(&json).map_err(APIError::from)?;
+        let template = match config.chat_template {
+            None => return Ok(None),
+            Some(TemplateField::Single(template)) => template,
+            Some(TemplateField::Named(templates)) => {
+                match templates.into_iter().find(|named| named.name == "default") {
+                    Some(named) => named.template,
+                    None => return Ok(None),
+                }
+            }
+        };
+        let bos_token = config.bos_token.map(SpecialToken::content);
+        let eos_token = config.eos_token.map(SpecialToken::content);
+        Self::new(
+            template,
+            bos_token.unwrap_or_default(),
+            eos_token.unwrap_or_default(),
+        )
+        .map(Some)
+    }
+
+    /// Render `(role, content)` messages, ending with the header of the assistant turn when
+    /// `add_generation_prompt` is set.
+    pub fn render(
+        &self,
+        messages: &[(&str, &str)],
+        add_generation_prompt: bool,
+    ) -> Result<String, APIError> {
+        let messages = messages
+            .iter()
+            .map(|&(role, content)| TemplateMessage { role, content })
+            .collect::<Vec<_>>();
+        self.env
+            .get_template(TEMPLATE_NAME)
+            .and_then(|template| {
+                template.render(context! {
+                    messages => messages,
+                    add_generation_prompt => add_generation_prompt,
+                    bos_token => &self.bos_token,
+                    eos_token => &self.eos_token,
+                })
+            })
+            .map_err(|e| APIError::new(format!("Cannot render the chat template: {e}")))
+    }
+}
@@ -1,13 +1,17 @@
 use dyn_fmt::AsStrFormatExt;
+use std::sync::Arc;
 
+use super::chat_template::ChatTemplate;
 use super::Conversation;
+use crate::openai::responses::APIError;
+use crate::openai::tools::ToolFormat;
 
 pub const ROLES: (&str, &str) = ("USER", "ASSISTANT");
 pub const SYSTEM_TEMPLATE: &str = "{}";
 pub const DEFAULT_SEP: &str = "\n";
 
 /// Separator style for default conversation.
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub enum SeparatorStyle {
     #[default]
     AddColonSingle,
@@ -23,8 +27,12 @@ pub enum SeparatorStyle {
     Gemma,
     Mistral,
     Yi,
+    DeepSeek,
     StableLM,
+    /// Plain text, for encoder-decoder models (T5)
+    T5,
     ChatGLM,
+    Glm4,
     ChatML,
     ChatIntern,
     Dolly,
@@ -36,6 +44,7 @@ pub enum SeparatorStyle {
 
 /// A struct for managing prompt templates and conversation history.
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct DefaultConversation {
     name: String,
     system_message: String,
@@ -48,6 +57,10 @@ pub struct DefaultConversation {
     roles: (String, String),
     sep: String,
     sep2: Option<String>,
+    tools_prompt: Option<String>,
+    user: Option<String>,
+    /// Jinja template of the model, rendered instead of `sep_style` when set
+    chat_template: Option<Arc<ChatTemplate>>,
 }
 
 /// Default conversion separators
@@ -57,6 +70,7 @@ pub struct DefaultConversationSeparators {
 }
 
 /// A message in a conversation
+#[derive(Clone)]
 pub struct Message((String, Option<String>));
 
 impl Message {
@@ -90,8 +104,61 @@ impl DefaultConversation {
             roles,
             sep: seps.sep,
             sep2: seps.sep2,
+            tools_prompt: None,
+            user: None,
+            chat_template: None,
         }
     }
+
+    pub fn with_chat_template(mut self, chat_template: Option<ChatTemplate>) -> Self {
+        self.chat_template = chat_template.map(Arc::new);
+        self
+    }
+
+    /// System message carrying the tool definitions, if tools are enabled.
+    fn get_tools_system_message(&self) -> Option<String> {
+        let tools_prompt = self.tools_prompt.as_ref()?;
+        if self.system_message.is_empty() {
+            Some(tools_prompt.clone())
+        } else {
+            Some(format!("{}\n\n{tools_prompt}", self.system_message))
+        }
+    }
+
+    /// Models without a system role (Gemma) get the system message at the start of the first
+    /// user turn.
+    fn merges_system_message(&self) -> bool {
+        matches!(self.sep_style, SeparatorStyle::Gemma)
+    }
+
+    fn render_chat_template(
+        &self,
+        template: &ChatTemplate,
+        add_generation_prompt: bool,
+    ) -> Result<String, APIError> {
+        let mut system = self
+            .get_tools_system_message()
+            .unwrap_or_else(|| self.system_message.clone());
+        let mut messages = Vec::with_capacity(self.messages.len() + 1);
+        if !system.is_empty() && !self.merges_system_message() {
+            messages.push(("system", std::mem::take(&mut system)));
+        }
+        for Message((role, message)) in &self.messages {
+            if let Some(message) = message {
+                let message = if *role == self.roles.0 && !system.is_empty() {
+                    format!("{}\n\n{message}", std::mem::take(&mut system))
+                } else {
+                    message.clone()
+                };
+                messages.push((role.as_str(), message));
+            }
+        }
+        let messages = messages
+            .iter()
+            .map(|(role, message)| (*role, message.as_str()))
+            .collect::<Vec<_>>();
+        template.render(&messages, add_generation_prompt)
+    }
 }
 
 impl Conversation for DefaultConversation {
@@ -122,8 +189,54 @@ impl Conversation for DefaultConversation {
     fn clear_message(&mut self) {
         self.messages.clear()
     }
+
+    fn get_tool_format(&self) -> Option<ToolFormat> {
+        match self.sep_style {
+            SeparatorStyle::Llama3 => Some(ToolFormat::Llama3),
+            SeparatorStyle::Qwen2 => Some(ToolFormat::Qwen2),
+            _ => None,
+        }
+    }
+
+    fn set_tools_prompt(&mut self, tools_prompt: Option<String>) {
+        self.tools_prompt = tools_prompt;
+    }
+
+    fn get_scored_prompt(&mut self) -> String {
+        if let Some(template) = &self.chat_template {
+            match self.render_chat_template(template, false) {
+                Ok(prompt) => return prompt,
+                Err(e) => tracing::warn!(model = %self.name, "{e}, using the built-in template."),
+            }
+        }
+        // The built-in templates only open a turn for a `None` message
+        let chat_template = self.chat_template.take();
+        let prompt = self.get_prompt();
+        self.chat_template = chat_template;
+        prompt
+    }
+
+    fn snapshot(&self) -> Box<dyn Conversation + Send> {
+        Box::new(self.clone())
+    }
+
+    /// Set the end user, the history recorded for another user is dropped.
+    fn set_user(&mut self, user: Option<String>) {
+        if self.user != user {
+            self.messages.clear();
+            self.user = user;
+        }
+    }
+
     /// Convert this conversation to a String prompt
     fn get_prompt(&mut self) -> String {
+        if let Some(template) = &self.chat_template {
+            // Without turns, only the system prefix is rendered
+            match self.render_chat_template(template, !self.messages.is_empty()) {
+                Ok(prompt) => return prompt,
+                Err(e) => tracing::warn!(model = %self.name, "{e}, using the built-in template."),
+            }
+        }
         let system_prompt = self.system_template.format(&[self.system_message.clone()]);
         match self.sep_style {
             SeparatorStyle::AddColonSingle => {
@@ -251,6 +364,11 @@ impl Conversation for DefaultConversation {
 
             SeparatorStyle::Llama3 => {
                 let mut accum = "<|begin_of_text|>".to_string();
+                if let Some(system) = self.get_tools_system_message() {
+                    accum += &format!(
+                        "<|start_header_id|>system<|end_header_id|>\n\n{system}<|eot_id|>"
+                    );
+                }
                 for (i, message) in self.messages.iter().enumerate() {
                     let Message((_role, message)) = message;
                     if _role.clone() == self.roles.0 {
@@ -267,6 +385,13 @@ impl Conversation for DefaultConversation {
                         if let Some(message) = message {
                             accum += &format!("<|start_header_id|>assistant<|end_header_id|>\n\n {message} <|eot_id|>");
                         }
+                    } else if _role == "tool" {
+                        //tool result message
+                        if let Some(message) = message {
+                            accum += &format!(
+                                "<|start_header_id|>ipython<|end_header_id|>\n\n{message}<|eot_id|>"
+                            );
+                        }
                     } else if i == 0 && !system_prompt.is_empty() {
                         accum += &system_prompt;
                     }
@@ -299,6 +424,9 @@ impl Conversation for DefaultConversation {
 
             SeparatorStyle::Qwen2 | SeparatorStyle::Yi => {
                 let mut accum = "".to_string();
+                if let Some(system) = self.get_tools_system_message() {
+                    accum += &format!("<|im_start|>system\n{system}<|im_end|>\n");
+                }
                 for (i, message) in self.messages.iter().enumerate() {
                     let Message((_role, message)) = message;
                     if _role.clone() == self.roles.0 {
@@ -313,6 +441,13 @@ impl Conversation for DefaultConversation {
                         if let Some(message) = message {
                             accum += &format!("<|im_start|>assistant\n {message} <|im_end|>");
                         }
+                    } else if _role == "tool" {
+                        //tool result message
+                        if let Some(message) = message {
+                            accum += &format!(
+                                "<|im_start|>user\n<tool_response>\n{message}\n</tool_response><|im_end|>"
+                            );
+                        }
                     } else if i == 0 && !system_prompt.is_empty() {
                         accum += &system_prompt;
                     }
@@ -322,9 +457,15 @@ impl Conversation for DefaultConversation {
 
             SeparatorStyle::Gemma => {
                 let mut accum = "".to_string();
+                let mut system = self.system_message.as_str();
                 for message in self.messages.iter() {
                     let Message((_role, message)) = message;
                     if let Some(message) = message {
+                        let message = if *_role == self.roles.0 && !system.is_empty() {
+                            format!("{}\n\n{message}", std::mem::take(&mut system))
+                        } else {
+                            message.clone()
+                        };
                         accum +=
                             &format!("<bos><start_of_turn>{_role}\n {message} <end_of_turn>\n");
                     } else {
@@ -335,6 +476,29 @@ impl Conversation for DefaultConversation {
                 accum
             }
 
+            SeparatorStyle::DeepSeek => {
+                let mut accum = "".to_string();
+                for (i, message) in self.messages.iter().enumerate() {
+                    let Message((_role, message)) = message;
+                    if _role.clone() == self.roles.0 {
+                        //user message
+                        if let Some(message) = message {
+                            accum += &format!("User: {message}\n\n");
+                        }
+                    } else if _role.clone() == self.roles.1 {
+                        //assistant message
+                        if let Some(message) = message {
+                            accum += &format!("Assistant: {message}<｜end▁of▁sentence｜>");
+                        } else {
+                            accum += "Assistant:";
+                        }
+                    } else if i == 0 && !system_prompt.is_empty() {
+                        accum += &format!("{system_prompt}\n\n");
+                    }
+                }
+                accum
+            }
+
             SeparatorStyle::StableLM => {
                 let mut accum = "".to_string();
                 for (i, message) in self.messages.iter().enumerate() {
@@ -381,6 +545,29 @@ impl Conversation for DefaultConversation {
                 accum
             }
 
+            SeparatorStyle::Glm4 => {
+                let mut accum = "[gMASK]<sop>".to_string();
+                for (i, message) in self.messages.iter().enumerate() {
+                    let Message((_role, message)) = message;
+                    if _role.clone() == self.roles.0 {
+                        //user message
+                        if let Some(message) = message {
+                            accum += &format!("<|user|>\n{message}");
+                        }
+                    } else if _role.clone() == self.roles.1 {
+                        //assistant message
+                        if let Some(message) = message {
+                            accum += &format!("<|assistant|>\n{message}");
+                        } else {
+                            accum += "<|assistant|>";
+                        }
+                    } else if i == 0 && !system_prompt.is_empty() {
+                        accum += &format!("<|system|>\n{system_prompt}");
+                    }
+                }
+                accum
+            }
+
             SeparatorStyle::ChatML => {
                 let mut accum = if !system_prompt.is_empty() {
                     format!("{}{}\n", system_prompt, self.sep)
@@ -476,6 +663,19 @@ impl Conversation for DefaultConversation {
                 }
                 accum
             }
+
+            // The encoder reads the messages as they are, the decoder starts the reply on its own
+            SeparatorStyle::T5 => {
+                let messages = self
+                    .messages
+                    .iter()
+                    .filter_map(|Message((_, message))| message.as_deref());
+                std::iter::once(self.system_message.as_str())
+                    .filter(|system| !system.is_empty())
+                    .chain(messages)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
         }
     }
 }
@@ -1,5 +1,8 @@
+pub mod chat_template;
 pub mod default_conversation;
 
+use super::tools::ToolFormat;
+
 /// A trait for using conversation managers with a `ModulePipeline`.
 pub trait Conversation {
     fn set_system_message(&mut self, system_message: String);
@@ -14,5 +17,22 @@ pub trait Conversation {
 
     fn get_prompt(&mut self) -> String;
 
+    /// The conversation as it stands, without opening the next assistant turn, for a reward
+    /// model to score.
+    fn get_scored_prompt(&mut self) -> String;
+
     fn clear_message(&mut self);
+
+    /// Tool calling convention of the chat template, `None` if it has none.
+    fn get_tool_format(&self) -> Option<ToolFormat>;
+
+    /// Set the tools system prompt of the next prompt, `None` to disable tools.
+    fn set_tools_prompt(&mut self, tools_prompt: Option<String>);
+
+    /// Set the end user (the OpenAI `user` field) the conversation belongs to. A recorded
+    /// history is never shared between users.
+    fn set_user(&mut self, user: Option<String>);
+
+    /// A copy of the conversation as it stands, to render its prompt without the engine lock.
+    fn snapshot(&self) -> Box<dyn Conversation + Send>;
 }
@@ -0,0 +1,118 @@
+//! GPU energy telemetry. With `--energy-telemetry` (and the `nvml` feature), the engine reads the
+//! energy counter of its GPU through NVML after every batch it runs, and splits the energy used
+//! since the previous reading between the requests of the batch by their share of its tokens.
+//! The totals are served by `/metrics` and every response reports the share of its request in
+//! `usage.energy`. Anything else running on the GPU is attributed to the requests too, so the
+//! figures are estimates for energy budgets, not measurements.
+
+use std::time::Duration;
+
+/// Split `joules` between the requests of a batch in proportion to the tokens each one had
+/// computed in it.
+pub fn attribute(joules: f64, tokens: &[usize]) -> Vec<f64> {
+    let total = tokens.iter().sum::<usize>();
+    if total == 0 {
+        return vec![0.0; tokens.len()];
+    }
+    tokens
+        .iter()
+        .map(|&tokens| joules * tokens as f64 / total as f64)
+        .collect()
+}
+
+/// Average power in watts of `joules` used over `duration`.
+pub fn average_power(joules: f64, duration: Duration) -> f64 {
+    let seconds = duration.as_secs_f64();
+    if seconds > 0.0 {
+        joules / seconds
+    } else {
+        0.0
+    }
+}
+
+#[cfg(feature = "nvml")]
+pub use self::nvml::EnergyMeter;
+
+#[cfg(feature = "nvml")]
+mod nvml {
+    use candle_core::Device;
+    use nvml_wrapper::Nvml;
+    use std::time::{Duration, Instant};
+
+    use crate::openai::responses::APIError;
+
+    /// Reads the energy used by the GPU of a model.
+    pub struct EnergyMeter {
+        nvml: Nvml,
+        /// NVML identifies the GPU by its PCI bus id, CUDA ordinals depend on
+        /// `CUDA_VISIBLE_DEVICES`
+        pci_bus_id: String,
+        /// Energy counter at the previous reading in millijoules, `None` for GPUs without one
+        last_mj: Option<u64>,
+        last_read: Instant,
+    }
+
+    impl EnergyMeter {
+        pub fn new(device: &Device) -> Result<Self, APIError> {
+            let Some(pci_bus_id) = crate::backend::pci_bus_id(device)? else {
+                return Err(APIError::new_str(
+                    "Energy telemetry requires a CUDA device.",
+                ));
+            };
+            let nvml =
+                Nvml::init().map_err(|e| APIError::new(format!("Cannot initialize NVML: {e}")))?;
+            let mut meter = Self {
+                nvml,
+                pci_bus_id,
+                last_mj: None,
+                last_read: Instant::now(),
+            };
+            meter.last_mj = meter.device()?.total_energy_consumption().ok();
+            Ok(meter)
+        }
+
+        fn device(&self) -> Result<nvml_wrapper::Device<'_>, APIError> {
+            self.nvml
+                .device_by_pci_bus_id(self.pci_bus_id.as_str())
+                .map_err(APIError::from)
+        }
+
+        /// Energy in joules used by the GPU since the previous reading, and the time elapsed.
+        pub fn read(&mut self) -> (f64, Duration) {
+            let now = Instant::now();
+            let elapsed = now.duration_since(std::mem::replace(&mut self.last_read, now));
+            // Borrows only `nvml`, `last_mj` is updated meanwhile
+            let Ok(device) = self.nvml.device_by_pci_bus_id(self.pci_bus_id.as_str()) else {
+                return (0.0, elapsed);
+            };
+            let joules = match (self.last_mj, device.total_energy_consumption()) {
+                (Some(last_mj), Ok(mj)) => {
+                    self.last_mj = Some(mj);
+                    mj.saturating_sub(last_mj) as f64 / 1000.0
+                }
+                // GPUs before Volta have no energy counter, the power draw is integrated instead
+                _ => device
+                    .power_usage()
+                    .map_or(0.0, |mw| mw as f64 / 1000.0 * elapsed.as_secs_f64()),
+            };
+            (joules, elapsed)
+        }
+    }
+}
+
+/// Stands in for the NVML meter without the `nvml` feature, it cannot be created.
+#[cfg(not(feature = "nvml"))]
+pub struct EnergyMeter;
+
+#[cfg(not(feature = "nvml"))]
+impl EnergyMeter {
+    pub fn new(_device: &candle_core::Device) -> Result<Self, super::responses::APIError> {
+        Err(super::responses::APIError::new_str(
+            "Energy telemetry requires building with the `nvml` feature.",
+        ))
+    }
+
+    pub fn read(&mut self) -> (f64, Duration) {
+        (0.0, Duration::ZERO)
+    }
+}
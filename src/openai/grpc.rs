@@ -0,0 +1,220 @@
+//! gRPC transport of the engine, with the `grpc` feature and `--grpc-port <PORT>`, for service
+//! meshes that prefer it to HTTP and JSON. It mirrors [`LLMEngine::generate`]: raw prompts,
+//! without the chat template, generated as a whole (`Generate`) or token by token
+//! (`GenerateStream`). The service is defined in `proto/candle_vllm.proto`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::responses::APIError;
+use super::OpenAIServerData;
+
+#[cfg(feature = "grpc")]
+pub mod proto {
+    tonic::include_proto!("candle_vllm");
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+    use axum::http::StatusCode;
+    use futures::stream::BoxStream;
+    use futures::StreamExt;
+    use std::net::SocketAddr;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tonic::{Request, Response, Status};
+
+    use super::proto::generation_server::Generation;
+    use super::proto::{GenerateRequest, GenerateResponse, GeneratedToken};
+    use crate::openai::openai_server::rate_limited;
+    use crate::openai::pipelines::llm_engine::{GenerationStream, LLMEngine};
+    use crate::openai::rate_limit::RateLimitKey;
+    use crate::openai::reload::InFlight;
+    use crate::openai::requests::StopTokens;
+    use crate::openai::responses::{APIError, ChatResponder};
+    use crate::openai::sampling_params::{EarlyStoppingCondition, SamplingParams};
+    use crate::openai::OpenAIServerData;
+
+    /// The status of an error, after its HTTP status.
+    fn status(e: APIError) -> Status {
+        let message = e.message().to_string();
+        match e.status() {
+            StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+            StatusCode::NOT_FOUND => Status::not_found(message),
+            StatusCode::REQUEST_TIMEOUT => Status::deadline_exceeded(message),
+            StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(message),
+            StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+            _ => Status::internal(message),
+        }
+    }
+
+    /// The status of a request turned away by the rate limiter.
+    fn rejected(responder: ChatResponder) -> Status {
+        match responder {
+            ChatResponder::TooManyRequests(e, _) | ChatResponder::ValidationError(e) => status(e),
+            _ => Status::internal("The request was rejected."),
+        }
+    }
+
+    pub struct GenerationService {
+        pub data: Arc<OpenAIServerData>,
+    }
+
+    impl GenerationService {
+        /// Add the request from `addr` to the engine of its model, with the checks of the HTTP
+        /// requests: the rate limits, `--max-waiting-requests` and `--request-timeout`.
+        async fn start(
+            &self,
+            addr: Option<SocketAddr>,
+            request: GenerateRequest,
+        ) -> Result<GenerationStream, Status> {
+            let received = Instant::now();
+            let key = RateLimitKey::of_addr(addr);
+            if let Some(limiter) = &self.data.rate_limiter {
+                limiter
+                    .acquire_request(&key, received)
+                    .map_err(|exceeded| rejected(rate_limited(limiter, exceeded)))?;
+            }
+            let served = self.data.get_model(&request.model).map_err(status)?;
+            if let Some(max_waiting) = self.data.max_waiting_requests {
+                let waiting = served.queue_depth.load(Ordering::Relaxed);
+                if waiting >= max_waiting {
+                    return Err(Status::resource_exhausted(format!(
+                        "{waiting} requests are waiting for the model, retry later."
+                    )));
+                }
+            }
+            let stop = (!request.stop.is_empty()).then_some(StopTokens::Multi(request.stop));
+            let sampling_params = SamplingParams::new(
+                1,
+                None,
+                request.presence_penalty.unwrap_or(0.0),
+                request.frequency_penalty.unwrap_or(0.0),
+                request
+                    .repetition_penalty
+                    .unwrap_or(served.pipeline_config.penalty),
+                request
+                    .temperature
+                    .unwrap_or(served.pipeline_config.temperature),
+                request.top_p.unwrap_or(1.0),
+                request.top_k.map_or(-1, |top_k| top_k as isize),
+                false,
+                1.0,
+                EarlyStoppingCondition::UnlikelyBetterCandidates,
+                stop,
+                vec![],
+                request.ignore_eos,
+                request
+                    .max_tokens
+                    .map_or(served.pipeline_config.default_max_tokens, |max_tokens| {
+                        max_tokens as usize
+                    }),
+                None,
+                None,
+                true,
+            )
+            .map_err(|e| Status::invalid_argument(e.message()))?;
+            let max_tokens = sampling_params.max_tokens;
+            let deadline = self.data.request_timeout.map(|timeout| received + timeout);
+            let tokens = LLMEngine::generate_until(
+                &served.model,
+                &request.prompt,
+                sampling_params,
+                deadline,
+            )
+            .await
+            .map_err(status)?;
+            // Counted once tokenized, a request over the limit is aborted as `tokens` is dropped
+            if let Some(limiter) = &self.data.rate_limiter {
+                limiter
+                    .acquire_tokens(&key, tokens.prompt_tokens() + max_tokens, Instant::now())
+                    .map_err(|exceeded| rejected(rate_limited(limiter, exceeded)))?;
+            }
+            Ok(tokens)
+        }
+
+        /// Count a request in flight until the guard is dropped, so that a reload waits for it.
+        fn admit(&self) -> Result<Option<InFlight<'_>>, Status> {
+            match &self.data.reloader {
+                Some(reloader) => reloader
+                    .admit()
+                    .map(Some)
+                    .ok_or_else(|| Status::unavailable("A model is being reloaded, retry later.")),
+                None => Ok(None),
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Generation for GenerationService {
+        async fn generate(
+            &self,
+            request: Request<GenerateRequest>,
+        ) -> Result<Response<GenerateResponse>, Status> {
+            // In flight until the response, like a non-streamed HTTP request
+            let _in_flight = self.admit()?;
+            let mut tokens = self
+                .start(request.remote_addr(), request.into_inner())
+                .await?;
+            let prompt_tokens = tokens.prompt_tokens() as u32;
+            let mut text = String::new();
+            let mut finish_reason = None;
+            while let Some(token) = tokens.next().await {
+                let token = token.map_err(status)?;
+                text.push_str(&token.text);
+                finish_reason = token.finish_reason.or(finish_reason);
+            }
+            Ok(Response::new(GenerateResponse {
+                text,
+                finish_reason,
+                prompt_tokens,
+            }))
+        }
+
+        type GenerateStreamStream = BoxStream<'static, Result<GeneratedToken, Status>>;
+
+        async fn generate_stream(
+            &self,
+            request: Request<GenerateRequest>,
+        ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+            // In flight until handed to the engine, like a streamed HTTP request
+            let _in_flight = self.admit()?;
+            // Dropped with the call, which aborts the request
+            let tokens = self
+                .start(request.remote_addr(), request.into_inner())
+                .await?;
+            Ok(Response::new(
+                tokens
+                    .map(|token| {
+                        token
+                            .map(|token| GeneratedToken {
+                                text: token.text,
+                                finish_reason: token.finish_reason,
+                            })
+                            .map_err(status)
+                    })
+                    .boxed(),
+            ))
+        }
+    }
+}
+
+/// Serve the gRPC service at `addr` until the server stops.
+#[cfg(feature = "grpc")]
+pub async fn serve(data: Arc<OpenAIServerData>, addr: SocketAddr) -> Result<(), APIError> {
+    tonic::transport::Server::builder()
+        .add_service(proto::generation_server::GenerationServer::new(
+            service::GenerationService { data },
+        ))
+        .serve(addr)
+        .await
+        .map_err(|e| APIError::new(format!("The gRPC server failed: {e}")))
+}
+
+#[cfg(not(feature = "grpc"))]
+pub async fn serve(_data: Arc<OpenAIServerData>, _addr: SocketAddr) -> Result<(), APIError> {
+    Err(APIError::new_str(
+        "Serving gRPC requires building with the `grpc` feature.",
+    ))
+}
@@ -0,0 +1,675 @@
+use super::responses::APIError;
+use crate::try_api;
+use candle_core::Tensor;
+use regex_automata::{
+    dfa::{dense, Automaton, StartKind},
+    util::primitives::StateID,
+    Anchored, Input,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokenizers::Tokenizer;
+
+/// State of a sequence in the automaton of its guide.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GuideState {
+    Regex(StateID),
+    Json(JsonState),
+}
+
+const WS: &str = r"[ ]?";
+const STRING_CHAR: &str = r#"(?:[^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const INTEGER: &str = r"-?(?:0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(?:0|[1-9][0-9]*)(?:\.[0-9]+)?(?:[eE][+-]?[0-9]+)?";
+/// Bytes a guide may take, the DFA of some patterns grows exponentially with their length.
+const DFA_SIZE_LIMIT: usize = 64 << 20;
+
+enum Constraint {
+    Regex {
+        pattern: String,
+        dfa: dense::DFA<Vec<u32>>,
+    },
+    JsonObject,
+}
+
+/// Constrains generation to a regular expression or to a JSON object. A regular expression
+/// is compiled into a DFA over bytes, a JSON object is checked by a byte-level validator;
+/// the tokens allowed in each state are found by walking their bytes through the automaton
+/// (computed on first use of a state and cached).
+pub struct TokenGuide {
+    constraint: Constraint,
+    token_bytes: Arc<Vec<Vec<u8>>>,
+    allowed_tokens: Mutex<HashMap<GuideState, Arc<Vec<u32>>>>,
+}
+
+impl fmt::Debug for TokenGuide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.constraint {
+            Constraint::Regex { pattern, .. } => f
+                .debug_struct("TokenGuide")
+                .field("pattern", pattern)
+                .finish(),
+            Constraint::JsonObject => f
+                .debug_struct("TokenGuide")
+                .field("json_object", &true)
+                .finish(),
+        }
+    }
+}
+
+impl TokenGuide {
+    pub fn new(pattern: &str, token_bytes: Arc<Vec<Vec<u8>>>) -> Result<Self, APIError> {
+        let dfa = try_api!(dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .start_kind(StartKind::Anchored)
+                    .dfa_size_limit(Some(DFA_SIZE_LIMIT))
+                    .determinize_size_limit(Some(DFA_SIZE_LIMIT))
+            )
+            .build(&format!("^(?:{pattern})$")));
+        Ok(Self {
+            constraint: Constraint::Regex {
+                pattern: pattern.to_string(),
+                dfa,
+            },
+            token_bytes,
+            allowed_tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Guide for `response_format` `json_object`: the output must be a single JSON object.
+    pub fn json_object(token_bytes: Arc<Vec<Vec<u8>>>) -> Self {
+        Self {
+            constraint: Constraint::JsonObject,
+            token_bytes,
+            allowed_tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn start_state(&self) -> GuideState {
+        match &self.constraint {
+            Constraint::Regex { dfa, .. } => GuideState::Regex(
+                dfa.start_state_forward(&Input::new("").anchored(Anchored::Yes))
+                    .expect("anchored start state of an anchored DFA"),
+            ),
+            Constraint::JsonObject => GuideState::Json(JsonState::default()),
+        }
+    }
+
+    /// Whether the text generated so far is a complete match (end of sequence is allowed).
+    pub fn is_accepting(&self, state: &GuideState) -> bool {
+        match (&self.constraint, state) {
+            (Constraint::Regex { dfa, .. }, GuideState::Regex(state)) => {
+                dfa.is_match_state(dfa.next_eoi_state(*state))
+            }
+            (Constraint::JsonObject, GuideState::Json(state)) => state.is_complete(),
+            _ => false,
+        }
+    }
+
+    fn walk(&self, state: &GuideState, bytes: &[u8]) -> Option<GuideState> {
+        match (&self.constraint, state) {
+            (Constraint::Regex { dfa, .. }, GuideState::Regex(state)) => {
+                let mut state = *state;
+                for byte in bytes {
+                    state = dfa.next_state(state, *byte);
+                    if dfa.is_dead_state(state) {
+                        return None;
+                    }
+                }
+                Some(GuideState::Regex(state))
+            }
+            (Constraint::JsonObject, GuideState::Json(state)) => {
+                let mut state = state.clone();
+                for byte in bytes {
+                    if !state.feed(*byte) {
+                        return None;
+                    }
+                }
+                Some(GuideState::Json(state))
+            }
+            _ => None,
+        }
+    }
+
+    /// State after `token`, `None` if the token cannot continue a match.
+    pub fn next_state(&self, state: &GuideState, token: u32) -> Option<GuideState> {
+        let bytes = self.token_bytes.get(token as usize)?;
+        if bytes.is_empty() {
+            return None;
+        }
+        self.walk(state, bytes)
+    }
+
+    /// Tokens (excluding end of sequence) that keep the output a prefix of a match.
+    pub fn allowed_tokens(&self, state: &GuideState) -> Arc<Vec<u32>> {
+        if let Some(allowed) = self.allowed_tokens.lock().unwrap().get(state) {
+            return allowed.clone();
+        }
+        let allowed = Arc::new(
+            self.token_bytes
+                .iter()
+                .enumerate()
+                .filter(|(_, bytes)| !bytes.is_empty() && self.walk(state, bytes).is_some())
+                .map(|(token, _)| token as u32)
+                .collect::<Vec<_>>(),
+        );
+        self.allowed_tokens
+            .lock()
+            .unwrap()
+            .insert(state.clone(), allowed.clone());
+        allowed
+    }
+
+    /// Mask out the logits of the tokens not allowed in `state`, `None` if no token is allowed.
+    pub fn mask_logits(
+        &self,
+        state: &GuideState,
+        logits: &Tensor,
+        eos_token_ids: &[u32],
+    ) -> candle_core::Result<Option<Tensor>> {
+        let allowed = self.allowed_tokens(state);
+        let accepting = self.is_accepting(state);
+        if allowed.is_empty() && !accepting {
+            return Ok(None);
+        }
+        let vocab_size = logits.dim(0)?;
+        let mut mask = vec![f32::NEG_INFINITY; vocab_size];
+        let eos_token_ids = if accepting { eos_token_ids } else { &[] };
+        for token in allowed.iter().chain(eos_token_ids) {
+            if let Some(m) = mask.get_mut(*token as usize) {
+                *m = 0.0;
+            }
+        }
+        let mask = Tensor::from_vec(mask, vocab_size, logits.device())?.to_dtype(logits.dtype())?;
+        Ok(Some(logits.broadcast_add(&mask)?))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum JsonContainer {
+    Object,
+    Array,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum JsonNumber {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpDigits,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum JsonMode {
+    /// Before the top-level object.
+    Start,
+    Value,
+    /// After `[`: a value or `]`.
+    ArrayFirst,
+    /// After `{`: a key or `}`.
+    KeyFirst,
+    Key,
+    Colon,
+    AfterValue,
+    /// `escape`: 0 outside of an escape, 1 after `\`, 2..=5 while reading `\uXXXX` digits.
+    String {
+        key: bool,
+        escape: u8,
+    },
+    Literal {
+        literal: &'static [u8],
+        pos: usize,
+    },
+    Number(JsonNumber),
+    /// The top-level object is closed.
+    Done,
+}
+
+/// Byte-level validator of a JSON object under construction (a pushdown automaton over the
+/// JSON syntax, string contents are not checked for valid UTF-8).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct JsonState {
+    stack: Vec<JsonContainer>,
+    mode: JsonMode,
+}
+
+impl Default for JsonState {
+    fn default() -> Self {
+        Self {
+            stack: Vec::new(),
+            mode: JsonMode::Start,
+        }
+    }
+}
+
+impl JsonState {
+    pub fn is_complete(&self) -> bool {
+        self.mode == JsonMode::Done
+    }
+
+    fn close_value(&mut self) {
+        self.mode = if self.stack.is_empty() {
+            JsonMode::Done
+        } else {
+            JsonMode::AfterValue
+        };
+    }
+
+    fn close_container(&mut self, container: JsonContainer) -> bool {
+        if self.stack.last() != Some(&container) {
+            return false;
+        }
+        self.stack.pop();
+        self.close_value();
+        true
+    }
+
+    fn start_value(&mut self, byte: u8) -> bool {
+        self.mode = match byte {
+            b'{' => {
+                self.stack.push(JsonContainer::Object);
+                JsonMode::KeyFirst
+            }
+            b'[' => {
+                self.stack.push(JsonContainer::Array);
+                JsonMode::ArrayFirst
+            }
+            b'"' => JsonMode::String {
+                key: false,
+                escape: 0,
+            },
+            b't' => JsonMode::Literal {
+                literal: b"true",
+                pos: 1,
+            },
+            b'f' => JsonMode::Literal {
+                literal: b"false",
+                pos: 1,
+            },
+            b'n' => JsonMode::Literal {
+                literal: b"null",
+                pos: 1,
+            },
+            b'-' => JsonMode::Number(JsonNumber::Minus),
+            b'0' => JsonMode::Number(JsonNumber::Zero),
+            b'1'..=b'9' => JsonMode::Number(JsonNumber::Int),
+            _ => return false,
+        };
+        true
+    }
+
+    /// Advance by one byte, `false` if the byte makes the document invalid.
+    pub fn feed(&mut self, byte: u8) -> bool {
+        let ws = matches!(byte, b' ' | b'\n' | b'\r' | b'\t');
+        match self.mode {
+            JsonMode::Start => {
+                if byte == b'{' {
+                    self.stack.push(JsonContainer::Object);
+                    self.mode = JsonMode::KeyFirst;
+                    true
+                } else {
+                    ws
+                }
+            }
+            JsonMode::Value => ws || self.start_value(byte),
+            JsonMode::ArrayFirst => {
+                ws || (byte == b']' && self.close_container(JsonContainer::Array))
+                    || self.start_value(byte)
+            }
+            JsonMode::KeyFirst | JsonMode::Key => match byte {
+                b'"' => {
+                    self.mode = JsonMode::String {
+                        key: true,
+                        escape: 0,
+                    };
+                    true
+                }
+                b'}' if self.mode == JsonMode::KeyFirst => {
+                    self.close_container(JsonContainer::Object)
+                }
+                _ => ws,
+            },
+            JsonMode::Colon => {
+                if byte == b':' {
+                    self.mode = JsonMode::Value;
+                    true
+                } else {
+                    ws
+                }
+            }
+            JsonMode::AfterValue => match (byte, self.stack.last()) {
+                (b',', Some(JsonContainer::Object)) => {
+                    self.mode = JsonMode::Key;
+                    true
+                }
+                (b',', Some(JsonContainer::Array)) => {
+                    self.mode = JsonMode::Value;
+                    true
+                }
+                (b'}', _) => self.close_container(JsonContainer::Object),
+                (b']', _) => self.close_container(JsonContainer::Array),
+                _ => ws,
+            },
+            JsonMode::String { key, escape } => {
+                let escape = match (escape, byte) {
+                    (0, b'"') => {
+                        if key {
+                            self.mode = JsonMode::Colon;
+                        } else {
+                            self.close_value();
+                        }
+                        return true;
+                    }
+                    (0, b'\\') => 1,
+                    (0, 0x00..=0x1F) => return false,
+                    (0, _) => 0,
+                    (1, b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => 0,
+                    (1, b'u') => 5,
+                    (1, _) => return false,
+                    (n, _) if byte.is_ascii_hexdigit() => {
+                        if n == 2 {
+                            0
+                        } else {
+                            n - 1
+                        }
+                    }
+                    _ => return false,
+                };
+                self.mode = JsonMode::String { key, escape };
+                true
+            }
+            JsonMode::Literal { literal, pos } => {
+                if literal[pos] != byte {
+                    return false;
+                }
+                if pos + 1 == literal.len() {
+                    self.close_value();
+                } else {
+                    self.mode = JsonMode::Literal {
+                        literal,
+                        pos: pos + 1,
+                    };
+                }
+                true
+            }
+            JsonMode::Number(number) => {
+                let digit = byte.is_ascii_digit();
+                let next = match (number, byte) {
+                    (JsonNumber::Minus, b'0') => JsonNumber::Zero,
+                    (JsonNumber::Minus, b'1'..=b'9') => JsonNumber::Int,
+                    (JsonNumber::Int, _) if digit => JsonNumber::Int,
+                    (JsonNumber::Zero | JsonNumber::Int, b'.') => JsonNumber::Dot,
+                    (JsonNumber::Dot | JsonNumber::Frac, _) if digit => JsonNumber::Frac,
+                    (JsonNumber::Zero | JsonNumber::Int | JsonNumber::Frac, b'e' | b'E') => {
+                        JsonNumber::Exp
+                    }
+                    (JsonNumber::Exp, b'+' | b'-') => JsonNumber::ExpSign,
+                    (JsonNumber::Exp | JsonNumber::ExpSign | JsonNumber::ExpDigits, _) if digit => {
+                        JsonNumber::ExpDigits
+                    }
+                    (
+                        JsonNumber::Zero
+                        | JsonNumber::Int
+                        | JsonNumber::Frac
+                        | JsonNumber::ExpDigits,
+                        _,
+                    ) => {
+                        // The number ends here, the byte belongs to what follows it.
+                        self.close_value();
+                        return self.feed(byte);
+                    }
+                    _ => return false,
+                };
+                self.mode = JsonMode::Number(next);
+                true
+            }
+            JsonMode::Done => false,
+        }
+    }
+}
+
+/// Byte sequence produced by each token of the vocabulary (empty for special tokens).
+pub fn get_token_bytes(tokenizer: &Tokenizer) -> Vec<Vec<u8>> {
+    let vocab = tokenizer.get_vocab(false);
+    let byte_level = vocab.keys().any(|token| token.starts_with('Ġ'));
+    let byte_decoder = if byte_level {
+        bytes_to_unicode()
+            .into_iter()
+            .map(|(b, c)| (c, b))
+            .collect::<HashMap<_, _>>()
+    } else {
+        HashMap::new()
+    };
+    let size = vocab.values().max().map_or(0, |id| *id as usize + 1);
+    let mut token_bytes = vec![Vec::new(); size];
+    for (token, id) in vocab {
+        let bytes = if let Some(byte) = token
+            .strip_prefix("<0x")
+            .and_then(|hex| hex.strip_suffix('>'))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            vec![byte]
+        } else if byte_level {
+            token
+                .chars()
+                .map(|c| byte_decoder.get(&c).copied())
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_else(|| token.as_bytes().to_vec())
+        } else {
+            token.replace('▁', " ").into_bytes()
+        };
+        token_bytes[id as usize] = bytes;
+    }
+    token_bytes
+}
+
+/// The byte to unicode character mapping of byte-level BPE tokenizers (GPT-2).
+fn bytes_to_unicode() -> Vec<(u8, char)> {
+    let mut mapping = Vec::with_capacity(256);
+    let mut n = 0;
+    for b in 0..=255u8 {
+        let printable = (b'!'..=b'~').contains(&b) || (0xA1..=0xAC).contains(&b) || b >= 0xAE;
+        if printable {
+            mapping.push((b, char::from(b)));
+        } else {
+            mapping.push((b, char::from_u32(256 + n).unwrap()));
+            n += 1;
+        }
+    }
+    mapping
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn alternatives(patterns: Vec<String>) -> String {
+    format!("(?:{})", patterns.join("|"))
+}
+
+fn literal_regex(value: &Value) -> String {
+    escape_regex(&value.to_string())
+}
+
+fn get_usize(schema: &serde_json::Map<String, Value>, key: &str) -> Option<usize> {
+    schema.get(key).and_then(Value::as_u64).map(|v| v as usize)
+}
+
+fn repeat(min: usize, max: Option<usize>) -> String {
+    match max {
+        Some(max) => format!("{{{min},{max}}}"),
+        None => format!("{{{min},}}"),
+    }
+}
+
+fn object_regex(schema: &serde_json::Map<String, Value>) -> Result<String, APIError> {
+    let properties = match schema.get("properties") {
+        Some(Value::Object(properties)) => properties,
+        _ => {
+            return Err(APIError::new_str(
+                "JSON schema objects without `properties` are not supported.",
+            ))
+        }
+    };
+    let required = match schema.get("required") {
+        Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let mut props = Vec::new();
+    for (name, value) in properties {
+        let pattern = format!(
+            "{}{WS}:{WS}{}",
+            literal_regex(&Value::String(name.clone())),
+            schema_value_regex(value)?
+        );
+        props.push((pattern, required.contains(&name.as_str())));
+    }
+    // Properties keep their declared order; each alternative starts at a property that
+    // may be the first one present.
+    let mut alts = Vec::new();
+    for first in 0..props.len() {
+        let mut pattern = props[first].0.clone();
+        for (prop, required) in &props[first + 1..] {
+            if *required {
+                pattern += &format!("{WS},{WS}{prop}");
+            } else {
+                pattern += &format!("(?:{WS},{WS}{prop})?");
+            }
+        }
+        alts.push(pattern);
+        if props[first].1 {
+            break;
+        }
+    }
+    let mut body = if alts.is_empty() {
+        String::new()
+    } else {
+        alternatives(alts)
+    };
+    if !props.iter().any(|(_, required)| *required) && !body.is_empty() {
+        body = format!("{body}?");
+    }
+    Ok(format!(r"\{{{WS}{body}{WS}\}}"))
+}
+
+fn type_regex(
+    schema_type: &str,
+    schema: &serde_json::Map<String, Value>,
+) -> Result<String, APIError> {
+    match schema_type {
+        "string" => {
+            if let Some(Value::String(pattern)) = schema.get("pattern") {
+                let pattern = pattern.trim_start_matches('^').trim_end_matches('$');
+                return Ok(format!("\"(?:{pattern})\""));
+            }
+            let min = get_usize(schema, "minLength").unwrap_or(0);
+            let max = get_usize(schema, "maxLength");
+            if min == 0 && max.is_none() {
+                Ok(format!("\"{STRING_CHAR}*\""))
+            } else {
+                Ok(format!("\"{STRING_CHAR}{}\"", repeat(min, max)))
+            }
+        }
+        "integer" => Ok(INTEGER.to_string()),
+        "number" => Ok(NUMBER.to_string()),
+        "boolean" => Ok("(?:true|false)".to_string()),
+        "null" => Ok("null".to_string()),
+        "array" => {
+            let item = match schema.get("items") {
+                Some(items) => schema_value_regex(items)?,
+                None => {
+                    return Err(APIError::new_str(
+                        "JSON schema arrays without `items` are not supported.",
+                    ))
+                }
+            };
+            let min = get_usize(schema, "minItems").unwrap_or(0);
+            let max = get_usize(schema, "maxItems");
+            let body = if max == Some(0) {
+                String::new()
+            } else {
+                let items = format!(
+                    "{item}(?:{WS},{WS}{item}){}",
+                    repeat(min.saturating_sub(1), max.map(|max| max - 1))
+                );
+                if min == 0 {
+                    format!("(?:{items})?")
+                } else {
+                    items
+                }
+            };
+            Ok(format!(r"\[{WS}{body}{WS}\]"))
+        }
+        "object" => object_regex(schema),
+        _ => Err(APIError::new(format!(
+            "JSON schema type `{schema_type}` is not supported."
+        ))),
+    }
+}
+
+fn schema_value_regex(schema: &Value) -> Result<String, APIError> {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        _ => return Err(APIError::new_str("JSON schema must be an object.")),
+    };
+    if let Some(value) = schema.get("const") {
+        return Ok(literal_regex(value));
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        return Ok(alternatives(values.iter().map(literal_regex).collect()));
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(schemas)) = schema.get(key) {
+            let patterns = schemas
+                .iter()
+                .map(schema_value_regex)
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(alternatives(patterns));
+        }
+    }
+    match schema.get("allOf") {
+        Some(Value::Array(schemas)) if schemas.len() == 1 => {
+            return schema_value_regex(&schemas[0]);
+        }
+        Some(_) => {
+            return Err(APIError::new_str("JSON schema `allOf` is not supported."));
+        }
+        None => {}
+    }
+    if schema.contains_key("$ref") {
+        return Err(APIError::new_str("JSON schema `$ref` is not supported."));
+    }
+    match schema.get("type") {
+        Some(Value::String(schema_type)) => type_regex(schema_type, schema),
+        Some(Value::Array(types)) => {
+            let patterns = types
+                .iter()
+                .map(|t| type_regex(t.as_str().unwrap_or_default(), schema))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(alternatives(patterns))
+        }
+        _ => Err(APIError::new_str(
+            "JSON schema without `type`, `enum` or `const` is not supported.",
+        )),
+    }
+}
+
+/// Regular expression matching the JSON documents valid under `schema` (a subset of
+/// JSON schema: properties are generated in their declared order).
+pub fn json_schema_to_regex(schema: &Value) -> Result<String, APIError> {
+    Ok(format!("[ \n]{{0,2}}{}", schema_value_regex(schema)?))
+}
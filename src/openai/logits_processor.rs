@@ -1,6 +1,7 @@
 use crate::candle::D;
 use crate::candle::{DType, Error, Result, Tensor};
-use rand::{distributions::Distribution, SeedableRng};
+use rand::{distributions::Distribution, rngs::StdRng, SeedableRng};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 #[derive(Clone, PartialEq, Debug)]
@@ -13,13 +14,13 @@ pub enum Sampling {
 }
 
 pub struct LogitsProcessor {
-    rng: Arc<Mutex<rand::rngs::StdRng>>,
+    rng: Arc<Mutex<StdRng>>,
     sampling: Sampling,
 }
 
 impl LogitsProcessor {
     pub fn from_sampling(seed: u64, sampling: Sampling) -> Self {
-        let rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let rng = StdRng::seed_from_u64(seed);
         Self {
             rng: Arc::new(Mutex::new(rng)),
             sampling,
@@ -38,6 +39,11 @@ impl LogitsProcessor {
         Self::from_sampling(seed, sampling)
     }
 
+    /// Whether the next token is always the most likely one.
+    pub fn is_greedy(&self) -> bool {
+        self.sampling == Sampling::ArgMax
+    }
+
     fn sample_argmax(&self, logits: Tensor) -> Result<u32> {
         // let logits_v: Vec<f32> = logits.to_vec1()?;
         // Use gpu kernel
@@ -51,9 +57,9 @@ impl LogitsProcessor {
         Ok(next_token)
     }
 
-    fn sample_multinomial(&self, prs: &Vec<f32>) -> Result<u32> {
+    fn sample_multinomial(&self, prs: &Vec<f32>, rng: &Mutex<StdRng>) -> Result<u32> {
         let distr = rand::distributions::WeightedIndex::new(prs).map_err(Error::wrap)?;
-        let mut rng = self.rng.lock().unwrap();
+        let mut rng = rng.lock().unwrap();
         let next_token = distr.sample(&mut *rng) as u32;
         Ok(next_token)
     }
@@ -61,7 +67,7 @@ impl LogitsProcessor {
     /// top-p sampling (or "nucleus sampling") samples from the smallest set of tokens that exceed
     /// probability top_p. This way we never sample tokens that have very low probabilities and are
     /// less likely to go "off the rails".
-    fn sample_topp(&self, prs: &mut Vec<f32>, top_p: f32) -> Result<u32> {
+    fn sample_topp(&self, prs: &mut Vec<f32>, top_p: f32, rng: &Mutex<StdRng>) -> Result<u32> {
         let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
 
         // Sort by descending probability.
@@ -77,28 +83,34 @@ impl LogitsProcessor {
             }
         }
         // Sample with clamped probabilities.
-        self.sample_multinomial(prs)
+        self.sample_multinomial(prs, rng)
     }
 
     // top-k sampling samples from the k tokens with the largest probabilities.
-    fn sample_topk(&self, prs: &mut Vec<f32>, top_k: usize) -> Result<u32> {
+    fn sample_topk(&self, prs: &mut Vec<f32>, top_k: usize, rng: &Mutex<StdRng>) -> Result<u32> {
         if top_k >= prs.len() {
-            self.sample_multinomial(prs)
+            self.sample_multinomial(prs, rng)
         } else {
             let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
             let (indices, _, _) =
                 argsort_indices.select_nth_unstable_by(top_k, |&i, &j| prs[j].total_cmp(&prs[i]));
             let prs = indices.iter().map(|&i| prs[i]).collect::<Vec<_>>();
-            let index = self.sample_multinomial(&prs)?;
+            let index = self.sample_multinomial(&prs, rng)?;
             Ok(indices[index as usize] as u32)
         }
     }
 
     // top-k sampling samples from the k tokens with the largest probabilities.
     // then top-p sampling.
-    fn sample_topk_topp(&self, prs: &mut Vec<f32>, top_k: usize, top_p: f32) -> Result<u32> {
+    fn sample_topk_topp(
+        &self,
+        prs: &mut Vec<f32>,
+        top_k: usize,
+        top_p: f32,
+        rng: &Mutex<StdRng>,
+    ) -> Result<u32> {
         if top_k >= prs.len() {
-            self.sample_topp(prs, top_p)
+            self.sample_topp(prs, top_p, rng)
         } else {
             let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
             let (indices, _, _) =
@@ -106,9 +118,9 @@ impl LogitsProcessor {
             let mut prs = indices.iter().map(|&i| prs[i]).collect::<Vec<_>>();
             let sum_p = prs.iter().sum::<f32>();
             let index = if top_p <= 0.0 || top_p >= sum_p {
-                self.sample_multinomial(&prs)?
+                self.sample_multinomial(&prs, rng)?
             } else {
-                self.sample_topp(&mut prs, top_p)?
+                self.sample_topp(&mut prs, top_p, rng)?
             };
             Ok(indices[index as usize] as u32)
         }
@@ -118,7 +130,23 @@ impl LogitsProcessor {
         self.sample_f(logits, |_| {})
     }
 
+    /// Sample with `rng` instead of the generator shared by all requests, e.g. the generator of a
+    /// request with a `seed`.
+    pub fn sample_with_rng(&self, logits: &Tensor, rng: &Mutex<StdRng>) -> Result<u32> {
+        self.sample_f_with_rng(logits, |_| {}, rng)
+    }
+
     pub fn sample_f(&self, logits: &Tensor, f: impl FnOnce(&mut [f32])) -> Result<u32> {
+        self.sample_f_with_rng(logits, f, &self.rng)
+    }
+
+    /// Sample with `rng` after `f` has been applied to the probabilities.
+    pub fn sample_f_with_rng(
+        &self,
+        logits: &Tensor,
+        f: impl FnOnce(&mut [f32]),
+        rng: &Mutex<StdRng>,
+    ) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
         let prs = |temperature: f64| -> Result<Vec<f32>> {
             let logits = (&logits / temperature)?;
@@ -132,27 +160,148 @@ impl LogitsProcessor {
             Sampling::ArgMax => self.sample_argmax(logits)?,
             Sampling::All { temperature } => {
                 let prs = prs(*temperature)?;
-                self.sample_multinomial(&prs)?
+                self.sample_multinomial(&prs, rng)?
             }
             Sampling::TopP { p, temperature } => {
                 let mut prs = prs(*temperature)?;
                 if *p <= 0.0 || *p >= 1.0 {
                     // simply sample from the predicted probability distribution
-                    self.sample_multinomial(&prs)?
+                    self.sample_multinomial(&prs, rng)?
                 } else {
                     // top-p (nucleus) sampling, clamping the least likely tokens to zero
-                    self.sample_topp(&mut prs, *p as f32)?
+                    self.sample_topp(&mut prs, *p as f32, rng)?
                 }
             }
             Sampling::TopK { k, temperature } => {
                 let mut prs = prs(*temperature)?;
-                self.sample_topk(&mut prs, *k)?
+                self.sample_topk(&mut prs, *k, rng)?
             }
             Sampling::TopKThenTopP { k, p, temperature } => {
                 let mut prs = prs(*temperature)?;
-                self.sample_topk_topp(&mut prs, *k, *p as f32)?
+                self.sample_topk_topp(&mut prs, *k, *p as f32, rng)?
             }
         };
         Ok(next_token)
     }
 }
+
+/// Apply the OpenAI presence and frequency penalties to the 1D `logits`: the logit of every
+/// token in `generated` is lowered by `presence_penalty` once and by `frequency_penalty` for
+/// each of its occurrences.
+pub fn apply_presence_frequency_penalty(
+    logits: &Tensor,
+    presence_penalty: f32,
+    frequency_penalty: f32,
+    generated: &[u32],
+) -> Result<Tensor> {
+    if generated.is_empty() || (presence_penalty == 0. && frequency_penalty == 0.) {
+        return Ok(logits.clone());
+    }
+    let mut counts = HashMap::<u32, usize>::new();
+    for token in generated {
+        *counts.entry(*token).or_default() += 1;
+    }
+    let mut logits_vec = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    for (token, count) in counts {
+        if let Some(logit) = logits_vec.get_mut(token as usize) {
+            *logit -= presence_penalty + frequency_penalty * count as f32;
+        }
+    }
+    let len = logits_vec.len();
+    Tensor::from_vec(logits_vec, len, logits.device())
+}
+
+/// Add the OpenAI `logit_bias` of a request to the 1D `logits`, a bias of -100 bans the token.
+pub fn apply_logit_bias(logits: &Tensor, logit_bias: &HashMap<u32, f32>) -> Result<Tensor> {
+    if logit_bias.is_empty() {
+        return Ok(logits.clone());
+    }
+    let mut logits_vec = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    for (token, bias) in logit_bias {
+        if let Some(logit) = logits_vec.get_mut(*token as usize) {
+            if *bias <= -100. {
+                *logit = f32::NEG_INFINITY;
+            } else {
+                *logit += bias;
+            }
+        }
+    }
+    let len = logits_vec.len();
+    Tensor::from_vec(logits_vec, len, logits.device())
+}
+
+/// Ban `tokens` from the 1D `logits`, they are never sampled.
+pub fn ban_tokens(logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+    if tokens.is_empty() {
+        return Ok(logits.clone());
+    }
+    let mut logits_vec = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    for token in tokens {
+        if let Some(logit) = logits_vec.get_mut(*token as usize) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+    let len = logits_vec.len();
+    Tensor::from_vec(logits_vec, len, logits.device())
+}
+
+/// The log probability of `token` under the 1D `logits`, and the `top_n` most likely tokens with
+/// theirs (highest first).
+pub fn token_logprobs(logits: &Tensor, token: u32, top_n: usize) -> Result<(f32, Vec<(u32, f32)>)> {
+    let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = max
+        + logits
+            .iter()
+            .map(|logit| (logit - max).exp())
+            .sum::<f32>()
+            .ln();
+    let logprob = logits
+        .get(token as usize)
+        .map(|logit| logit - log_sum)
+        .ok_or_else(|| Error::Msg(format!("token {token} is out of the vocabulary")))?;
+    let mut top = logits
+        .iter()
+        .enumerate()
+        .map(|(token, logit)| (token as u32, logit - log_sum))
+        .collect::<Vec<_>>();
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    top.truncate(top_n);
+    Ok((logprob, top))
+}
+
+/// Zero the probabilities `prs` dropped by min-p filtering (below `min_p` times the top
+/// probability), then by typical filtering (outside the smallest set of tokens closest to the
+/// expected information content holding `typical_p` of the mass). `min_p = 0` and
+/// `typical_p = 1` disable them.
+pub fn apply_min_p_typical_p(prs: &mut [f32], min_p: f32, typical_p: f32) {
+    if min_p > 0. {
+        let threshold = min_p * prs.iter().copied().fold(0., f32::max);
+        for p in prs.iter_mut().filter(|p| **p < threshold) {
+            *p = 0.;
+        }
+    }
+    if typical_p < 1. {
+        let total = prs.iter().sum::<f32>();
+        if total <= 0. {
+            return;
+        }
+        let mut tokens = prs
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| **p > 0.)
+            .map(|(i, p)| (i, p / total))
+            .collect::<Vec<_>>();
+        let entropy = -tokens.iter().map(|(_, p)| p * p.ln()).sum::<f32>();
+        let surprise = |p: f32| (-p.ln() - entropy).abs();
+        tokens.sort_by(|(_, p), (_, q)| surprise(*p).total_cmp(&surprise(*q)));
+        let mut cumsum = 0.;
+        for (i, p) in tokens {
+            if cumsum >= typical_p {
+                prs[i] = 0.;
+            } else {
+                cumsum += p;
+            }
+        }
+    }
+}
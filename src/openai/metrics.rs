@@ -0,0 +1,332 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{
+    energy::average_power,
+    responses::{BlockStats, ModelStats},
+    utils::hash_user,
+};
+use crate::scheduler::Preemption;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct UserCounters {
+    requests: usize,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+/// Request and token counters per end user, labeled by the hash of the OpenAI `user` field
+/// (`anonymous` for requests without one). Shared with the server like `PrefixCacheMetrics`.
+#[derive(Debug, Default)]
+pub struct UserMetrics {
+    users: Mutex<HashMap<String, UserCounters>>,
+}
+
+impl UserMetrics {
+    fn update(&self, user: Option<&str>, update: impl FnOnce(&mut UserCounters)) {
+        let label = user.map_or_else(|| "anonymous".to_string(), hash_user);
+        update(self.users.lock().unwrap().entry(label).or_default());
+    }
+
+    /// Count a request of `user` and its prompt tokens.
+    pub fn record_request(&self, user: Option<&str>, prompt_tokens: usize) {
+        self.update(user, |counters| {
+            counters.requests += 1;
+            counters.prompt_tokens += prompt_tokens;
+        });
+    }
+
+    /// Count tokens generated for `user`.
+    pub fn record_completion(&self, user: Option<&str>, completion_tokens: usize) {
+        self.update(user, |counters| {
+            counters.completion_tokens += completion_tokens
+        });
+    }
+
+    /// The counters in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let users = self.users.lock().unwrap();
+        let mut labels = users.keys().collect::<Vec<_>>();
+        labels.sort_unstable();
+        let mut out = String::new();
+        let series: [(&str, &str, fn(&UserCounters) -> usize); 3] = [
+            ("requests", "Requests received", |counters| {
+                counters.requests
+            }),
+            ("prompt_tokens", "Prompt tokens received", |counters| {
+                counters.prompt_tokens
+            }),
+            ("completion_tokens", "Tokens generated", |counters| {
+                counters.completion_tokens
+            }),
+        ];
+        for (name, help, get) in series {
+            let _ = writeln!(
+                out,
+                "# HELP candle_vllm_user_{name}_total {help}, by hashed end user.\n\
+                 # TYPE candle_vllm_user_{name}_total counter"
+            );
+            for label in &labels {
+                let _ = writeln!(
+                    out,
+                    "candle_vllm_user_{name}_total{{user=\"{label}\"}} {}",
+                    get(&users[*label])
+                );
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct EnergyCounters {
+    prefill_joules: f64,
+    decode_joules: f64,
+    /// Average power over the last batch
+    power_watts: f64,
+}
+
+/// GPU energy of the batches of a model with `--energy-telemetry`, shared with the server like
+/// `KvCacheMetrics`.
+#[derive(Debug, Default)]
+pub struct EnergyMetrics {
+    counters: Mutex<EnergyCounters>,
+}
+
+impl EnergyMetrics {
+    /// Count the energy used by a prefill (`is_prompt`) or decoding batch over `duration`.
+    pub fn record(&self, is_prompt: bool, joules: f64, duration: Duration) {
+        let mut counters = self.counters.lock().unwrap();
+        if is_prompt {
+            counters.prefill_joules += joules;
+        } else {
+            counters.decode_joules += joules;
+        }
+        counters.power_watts = average_power(joules, duration);
+    }
+
+    /// The counters of the served models in the Prometheus text format, one series per model.
+    pub fn render(models: &[(&str, &Self)]) -> String {
+        if models.is_empty() {
+            return String::new();
+        }
+        let mut out = String::from(
+            "# HELP candle_vllm_gpu_energy_joules_total GPU energy used by the batches, by phase.\n\
+             # TYPE candle_vllm_gpu_energy_joules_total counter\n",
+        );
+        let counters = models
+            .iter()
+            .map(|(model, metrics)| (*model, *metrics.counters.lock().unwrap()))
+            .collect::<Vec<_>>();
+        for (model, counters) in &counters {
+            let _ = writeln!(
+                out,
+                "candle_vllm_gpu_energy_joules_total{{model=\"{model}\",phase=\"prefill\"}} {}\n\
+                 candle_vllm_gpu_energy_joules_total{{model=\"{model}\",phase=\"decode\"}} {}",
+                counters.prefill_joules, counters.decode_joules
+            );
+        }
+        out.push_str(
+            "# HELP candle_vllm_gpu_power_watts Average GPU power over the last batch.\n\
+             # TYPE candle_vllm_gpu_power_watts gauge\n",
+        );
+        for (model, counters) in &counters {
+            let _ = writeln!(
+                out,
+                "candle_vllm_gpu_power_watts{{model=\"{model}\"}} {}",
+                counters.power_watts
+            );
+        }
+        out
+    }
+}
+
+/// Runs of the canary probes of a model (see `canary`), shared with the server like
+/// `KvCacheMetrics`.
+#[derive(Debug, Default)]
+pub struct CanaryMetrics {
+    runs: AtomicUsize,
+    failures: AtomicUsize,
+    drifts: AtomicUsize,
+    /// Probes that drifted in the last run
+    drifted_probes: AtomicUsize,
+}
+
+impl CanaryMetrics {
+    /// Count a run in which `drifted` probes differed from their baseline.
+    pub fn record_run(&self, drifted: usize) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.drifts.fetch_add(drifted, Ordering::Relaxed);
+        self.drifted_probes.store(drifted, Ordering::Relaxed);
+    }
+
+    /// Count a run that could not generate.
+    pub fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters of the served models in the Prometheus text format, one series per model.
+    pub fn render(models: &[(&str, &Self)]) -> String {
+        let mut out = String::new();
+        if models.is_empty() {
+            return out;
+        }
+        let series: [(&str, &str, &str, fn(&Self) -> &AtomicUsize); 4] = [
+            ("canary_runs_total", "counter", "Canary probe runs.", |m| {
+                &m.runs
+            }),
+            (
+                "canary_failures_total",
+                "counter",
+                "Canary probe runs that failed to generate.",
+                |m| &m.failures,
+            ),
+            (
+                "canary_drifts_total",
+                "counter",
+                "Canary probe outputs that differed from their baseline.",
+                |m| &m.drifts,
+            ),
+            (
+                "canary_drifted_probes",
+                "gauge",
+                "Canary probes that differed from their baseline in the last run.",
+                |m| &m.drifted_probes,
+            ),
+        ];
+        for (name, kind, help, get) in series {
+            let _ = writeln!(
+                out,
+                "# HELP candle_vllm_{name} {help}\n# TYPE candle_vllm_{name} {kind}"
+            );
+            for (model, metrics) in models {
+                let _ = writeln!(
+                    out,
+                    "candle_vllm_{name}{{model=\"{model}\"}} {}",
+                    get(metrics).load(Ordering::Relaxed)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Window of the throughput and batch size of [`EngineStats`].
+pub const STATS_WINDOW: Duration = Duration::from_secs(60);
+
+/// A forward pass, kept for [`STATS_WINDOW`].
+#[derive(Debug, Clone, Copy)]
+struct StepSample {
+    time: Instant,
+    is_prompt: bool,
+    sequences: usize,
+    tokens: usize,
+}
+
+/// Scheduler and KV cache statistics of an engine, served by `/v1/stats`. Shared with the server
+/// like `KvCacheMetrics`, the snapshot does not wait for the engine lock.
+#[derive(Debug)]
+pub struct EngineStats {
+    started: Instant,
+    stats: Mutex<ModelStats>,
+    steps: Mutex<VecDeque<StepSample>>,
+}
+
+impl EngineStats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            stats: Mutex::default(),
+            steps: Mutex::default(),
+        }
+    }
+
+    /// The sizes of the scheduler queues after a scheduling step, and the swaps it issued.
+    pub fn record_schedule(
+        &self,
+        (running, waiting, swapped): (usize, usize, usize),
+        blocks_swapped_in: usize,
+        blocks_swapped_out: usize,
+    ) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.running = running;
+        stats.waiting = waiting;
+        stats.swapped = swapped;
+        stats.blocks_swapped_in += blocks_swapped_in;
+        stats.blocks_swapped_out += blocks_swapped_out;
+    }
+
+    pub fn record_blocks(&self, (gpu_blocks, cpu_blocks): (BlockStats, BlockStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.gpu_blocks = gpu_blocks;
+        stats.cpu_blocks = cpu_blocks;
+    }
+
+    pub fn record_preemption(&self, preemption: Preemption) {
+        let mut stats = self.stats.lock().unwrap();
+        match preemption {
+            Preemption::Swap => stats.preemptions_swap += 1,
+            Preemption::Recompute => stats.preemptions_recompute += 1,
+        }
+    }
+
+    /// Count a forward pass computing `tokens`, prompt tokens for a prefill, after which each of
+    /// the `sequences` sampled a token.
+    pub fn record_step(&self, is_prompt: bool, sequences: usize, tokens: usize, now: Instant) {
+        let mut steps = self.steps.lock().unwrap();
+        steps.push_back(StepSample {
+            time: now,
+            is_prompt,
+            sequences,
+            tokens,
+        });
+        while steps
+            .front()
+            .is_some_and(|step| now.duration_since(step.time) > STATS_WINDOW)
+        {
+            steps.pop_front();
+        }
+    }
+
+    /// The statistics of the model `id`, with the throughput and batch size over the last
+    /// [`STATS_WINDOW`] (or since the start).
+    pub fn snapshot(&self, id: &str, now: Instant) -> ModelStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.id = id.to_string();
+        let steps = self.steps.lock().unwrap();
+        let recent = steps
+            .iter()
+            .filter(|step| now.duration_since(step.time) <= STATS_WINDOW);
+        let (mut prompt_tokens, mut generated_tokens) = (0, 0);
+        let (mut decode_steps, mut decode_sequences) = (0, 0);
+        for step in recent {
+            generated_tokens += step.sequences;
+            if step.is_prompt {
+                prompt_tokens += step.tokens;
+            } else {
+                decode_steps += 1;
+                decode_sequences += step.sequences;
+            }
+        }
+        let elapsed = now
+            .duration_since(self.started)
+            .min(STATS_WINDOW)
+            .as_secs_f64()
+            .max(1e-3);
+        stats.prompt_tokens_per_second = prompt_tokens as f64 / elapsed;
+        stats.generation_tokens_per_second = generated_tokens as f64 / elapsed;
+        stats.average_batch_size = if decode_steps == 0 {
+            0.
+        } else {
+            decode_sequences as f64 / decode_steps as f64
+        };
+        stats
+    }
+}
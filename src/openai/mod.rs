@@ -1,14 +1,30 @@
 use candle_core::Device;
-use std::sync::Arc;
+use std::sync::{atomic::AtomicUsize, Arc, RwLock};
+use std::time::Duration;
 use tokenizers::{EncodeInput, Encoding, Tokenizer};
 use tokio::sync::{Mutex, Notify};
 
-use self::{pipelines::llm_engine::LLMEngine, responses::APIError};
+use self::guided_decoding::get_token_bytes;
+use self::metrics::{CanaryMetrics, EnergyMetrics, EngineStats, UserMetrics};
+use self::plugins::PluginHost;
+use self::quality::QualityRouter;
+use self::rate_limit::RateLimiter;
+use self::reload::Reloader;
+use self::sessions::SessionStore;
+use self::streaming::CancelFlags;
+use self::{
+    pipelines::llm_engine::LLMEngine,
+    requests::Quality,
+    responses::{APIError, ModelCapabilities},
+};
+use crate::scheduler::{block_engine::PrefixCacheMetrics, cache_engine::KvCacheMetrics};
 
+pub mod guided_decoding;
 pub mod requests;
 pub mod responses;
 pub mod sampling_params;
 pub mod streaming;
+pub mod tools;
 
 pub trait TokenizerWrapper<'s, E>
 where
@@ -38,19 +54,242 @@ pub struct PipelineConfig {
     pub penalty: f32,
     pub repeat_last_n: usize,
     pub temperature: f32,
+    /// In-situ quantization of the weights, e.g. `q4k`
+    pub quantization: Option<String>,
 }
 
-pub struct OpenAIServerData {
+/// Logging of the rendered prompt and sampling parameters of each request.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum PromptLogging {
+    Off,
+    /// The rendered prompt with special tokens visible.
+    Full,
+    /// Only the special tokens of the rendered prompt, the text between them is redacted.
+    Redacted,
+}
+
+/// A model served by the server. Every model has its own engine, and so its own scheduler and
+/// KV cache: the requests of one model never wait for the batches of another.
+pub struct ServedModel {
     pub model: Arc<Mutex<LLMEngine>>,
     pub pipeline_config: PipelineConfig,
+    pub finish_notify: Arc<Notify>,
+    pub model_name: String,
+    /// `(major, minor)` of the GPU the model runs on
+    pub compute_capability: Option<(usize, usize)>,
+    pub prefix_cache_metrics: Arc<PrefixCacheMetrics>,
+    pub kv_cache_metrics: Arc<KvCacheMetrics>,
+    /// Cancel flags of the requests in flight, to abort them without the engine lock
+    pub cancel_flags: CancelFlags,
+    /// GPU energy of the batches, with `--energy-telemetry`
+    pub energy_metrics: Option<Arc<EnergyMetrics>>,
+    /// Runs of the canary probes, with `--canary-prompts`
+    pub canary_metrics: Option<Arc<CanaryMetrics>>,
+    pub engine_stats: Arc<EngineStats>,
+    pub queue_depth: Arc<AtomicUsize>,
+    pub capabilities: ModelCapabilities,
+    pub system_fingerprint: String,
+    /// Tokenizer of the model, prompts are tokenized without the engine lock
+    pub tokenizer: Arc<Tokenizer>,
+    /// Bytes of each token of the vocabulary, shared by the guides of the requests
+    pub token_bytes: Arc<Vec<Vec<u8>>>,
+}
+
+impl ServedModel {
+    /// Serve the engine under `model_name`.
+    pub async fn new(
+        model: Arc<Mutex<LLMEngine>>,
+        pipeline_config: PipelineConfig,
+        model_name: String,
+        compute_capability: Option<(usize, usize)>,
+    ) -> Self {
+        let (
+            finish_notify,
+            prefix_cache_metrics,
+            kv_cache_metrics,
+            cancel_flags,
+            energy_metrics,
+            engine_stats,
+            queue_depth,
+            capabilities,
+            system_fingerprint,
+            tokenizer,
+        ) = {
+            let engine = model.lock().await;
+            let generates = !engine.get_pipeline().is_encoder_only();
+            let capabilities = ModelCapabilities {
+                id: model_name.clone(),
+                max_model_len: pipeline_config.max_model_len,
+                chat_completions: generates,
+                embeddings: true,
+                token_classification: !engine.get_pipeline().token_labels().is_empty(),
+                score: !engine.get_pipeline().score_labels().is_empty(),
+                guided_decoding: generates,
+                tools: generates,
+                logprobs: generates,
+                quantization: pipeline_config.quantization.clone(),
+                kv_cache_dtype: format!("{:?}", engine.kv_cache_dtype()).to_lowercase(),
+                speculative_decoding: engine.speculative_decoding(),
+            };
+            (
+                engine.finish_notify.clone(),
+                engine.prefix_cache_metrics.clone(),
+                engine.kv_cache_metrics.clone(),
+                engine.cancel_flags.clone(),
+                engine.energy_metrics.clone(),
+                engine.engine_stats.clone(),
+                engine.queue_depth.clone(),
+                capabilities,
+                engine.system_fingerprint.clone(),
+                engine.tokenizer.clone(),
+            )
+        };
+        let token_bytes = Arc::new(get_token_bytes(&tokenizer));
+        Self {
+            model,
+            pipeline_config,
+            finish_notify,
+            model_name,
+            compute_capability,
+            prefix_cache_metrics,
+            kv_cache_metrics,
+            cancel_flags,
+            energy_metrics,
+            canary_metrics: None,
+            engine_stats,
+            queue_depth,
+            capabilities,
+            system_fingerprint,
+            tokenizer,
+            token_bytes,
+        }
+    }
+}
+
+pub struct OpenAIServerData {
+    /// The served models, requests are routed by their `model` field. A model is swapped for
+    /// another by `/admin/reload`, requests keep the one they were routed to.
+    pub models: RwLock<Vec<Arc<ServedModel>>>,
     pub record_conversation: bool,
+    /// System message of the requests without one
+    pub default_system_prompt: Option<String>,
     pub device: Device,
-    pub finish_notify: Arc<Notify>,
+    pub log_prompts: PromptLogging,
+    /// Shared by the engines of all the models.
+    pub user_metrics: Arc<UserMetrics>,
+    /// Request and response transformation plugins of the chat completions.
+    pub plugins: PluginHost,
+    /// Chat completions are rejected with a 429 while this many requests wait for a model.
+    pub max_waiting_requests: Option<usize>,
+    /// Chat completions are aborted when they run longer, unless they set their own `timeout`.
+    pub request_timeout: Option<Duration>,
+    /// Requests and tokens per minute of every API key, with `--rate-limit-rpm` or
+    /// `--rate-limit-tpm`.
+    pub rate_limiter: Option<RateLimiter>,
+    /// History of the conversations of the requests with a `session_id`, with
+    /// `--session-history`.
+    pub sessions: Option<Arc<SessionStore>>,
+    /// Routing between the model and its quantized variant, with `--fast-quant`.
+    pub quality_router: Option<QualityRouter>,
+    /// Admission of the requests and loading of the models of `/admin/reload`, with
+    /// `--admin-port`. Models cannot be reloaded without it.
+    pub reloader: Option<Reloader>,
+}
+
+impl OpenAIServerData {
+    /// The served models.
+    pub fn models(&self) -> Vec<Arc<ServedModel>> {
+        self.models.read().unwrap().clone()
+    }
+
+    pub fn add_model(&self, served: ServedModel) {
+        self.models.write().unwrap().push(Arc::new(served));
+    }
+
+    /// Stop serving the model named `name`, which is returned.
+    pub fn remove_model(&self, name: &str) -> Option<Arc<ServedModel>> {
+        let mut models = self.models.write().unwrap();
+        let index = models.iter().position(|model| model.model_name == name)?;
+        Some(models.remove(index))
+    }
+
+    /// The model a request names. With a single model (and its fast variant), every request goes
+    /// to it whatever its `model` field.
+    pub fn get_model(&self, name: &str) -> Result<Arc<ServedModel>, APIError> {
+        let served = self.models();
+        let fast = self
+            .quality_router
+            .as_ref()
+            .map(|router| router.fast.as_str());
+        let mut models = served
+            .iter()
+            .filter(|model| Some(model.model_name.as_str()) != fast);
+        if let (Some(model), None) = (models.next(), models.next()) {
+            if fast != Some(name) {
+                return Ok(model.clone());
+            }
+        }
+        served
+            .iter()
+            .find(|model| model.model_name == name)
+            .cloned()
+            .ok_or_else(|| {
+                let names = served
+                    .iter()
+                    .map(|model| model.model_name.as_str())
+                    .collect::<Vec<_>>();
+                APIError::new(format!(
+                    "The model `{name}` is not served, available models: {}.",
+                    names.join(", ")
+                ))
+            })
+    }
+
+    /// The variant of `served` that runs a request with `quality`, see [`QualityRouter`].
+    pub fn route_quality(
+        &self,
+        served: Arc<ServedModel>,
+        quality: Option<Quality>,
+    ) -> Result<Arc<ServedModel>, APIError> {
+        let route = self.quality_router.as_ref().and_then(|router| {
+            router
+                .route(&served.model_name, quality)
+                .map(|quality| (router, quality))
+        });
+        match route {
+            Some((router, Quality::Fast)) => self.get_model(&router.fast),
+            Some((router, Quality::Best)) => self.get_model(&router.best),
+            None if quality == Some(Quality::Fast) => Err(APIError::invalid_request(format!(
+                "The model `{}` has no fast variant, see --fast-quant.",
+                served.model_name
+            ))),
+            None => Ok(served),
+        }
+    }
 }
 
+pub mod batch;
+pub mod bench;
+pub mod canary;
+pub mod compression;
 pub mod conversation;
+pub mod energy;
+pub mod grpc;
 pub mod logits_processor;
+pub mod metrics;
 pub mod models;
+pub mod observer;
 pub mod openai_server;
+pub mod otel;
 pub mod pipelines;
+#[cfg(feature = "playground")]
+pub mod playground;
+pub mod plugins;
+#[cfg(feature = "pprof")]
+pub mod profiling;
+pub mod quality;
+pub mod rate_limit;
+pub mod reload;
+pub mod sessions;
 pub mod utils;
+pub mod warmup;
@@ -0,0 +1,147 @@
+use super::Config;
+use crate::SpecificConfig;
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Linear, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config as BertModelConfig};
+use either::Either;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// BERT encoders (BGE, GTE, MiniLM) only serve embeddings and token classification, the engine
+/// config is derived from these fields to size the (unused) KV cache.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BertConfig {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub max_position_embeddings: usize,
+    pub layer_norm_eps: f64,
+    /// Labels of the token classification head, by class index
+    #[serde(default)]
+    pub id2label: HashMap<String, String>,
+}
+
+impl BertConfig {
+    /// Labels of the classification head in class order, `LABEL_<i>` when unnamed.
+    pub fn labels(&self) -> Vec<String> {
+        (0..self.id2label.len())
+            .map(|i| {
+                self.id2label
+                    .get(&i.to_string())
+                    .cloned()
+                    .unwrap_or_else(|| format!("LABEL_{i}"))
+            })
+            .collect()
+    }
+
+    pub fn into_config(
+        self,
+        use_flash_attn: bool,
+        kv_cache_dtype: DType,
+        scfg: &SpecificConfig,
+    ) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            head_dim: Some(self.hidden_size / self.num_attention_heads),
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_attention_heads,
+            rms_norm_eps: self.layer_norm_eps,
+            rope_theta: 0.,
+            bos_token_id: super::TokenID(Either::Left(None)),
+            eos_token_id: super::TokenID(Either::Left(None)),
+            max_seq_len: self.max_position_embeddings,
+            sliding_window: None,
+            hidden_act: None,
+            tie_word_embeddings: false,
+            rope_scaling: None,
+            use_flash_attn,
+            original_max_position_embeddings: None,
+            attention_bias: true,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: None,
+            custom_stop_tokens: None,
+            specific_config: scfg.clone(),
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
+        }
+    }
+}
+
+pub struct Bert {
+    model: BertModel,
+    /// Token classification head of `BertForTokenClassification` checkpoints (NER taggers)
+    classifier: Option<Linear>,
+    labels: Vec<String>,
+    cfg: Config,
+}
+
+impl Bert {
+    pub fn new(
+        vb: VarBuilder,
+        cfg: &Config,
+        model_cfg: &BertModelConfig,
+        labels: Vec<String>,
+        _device: &Device,
+    ) -> Result<Self> {
+        let classifier = if vb.contains_tensor("classifier.weight") {
+            Some(candle_nn::linear(
+                cfg.hidden_size,
+                labels.len(),
+                vb.pp("classifier"),
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            model: BertModel::load(vb, model_cfg)?,
+            classifier,
+            labels,
+            cfg: cfg.clone(),
+        })
+    }
+
+    /// Final hidden states of every input token, `(b_size, seq_len, hidden_size)`. Inputs are
+    /// never padded, so every token is attended to.
+    pub fn embed(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let token_type_ids = input_ids.zeros_like()?;
+        self.model.forward(input_ids, &token_type_ids, None)
+    }
+
+    /// Labels of the token classification head, empty when the checkpoint has none.
+    pub fn labels(&self) -> &[String] {
+        if self.classifier.is_some() {
+            &self.labels
+        } else {
+            &[]
+        }
+    }
+
+    /// Token classification logits, `(b_size, seq_len, num_labels)`, of a right-padded batch.
+    /// `attention_mask` is 1 for the input tokens and 0 for the padding.
+    pub fn classify(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let Some(classifier) = &self.classifier else {
+            candle_core::bail!("This model has no token classification head.")
+        };
+        let token_type_ids = input_ids.zeros_like()?;
+        let hidden = self
+            .model
+            .forward(input_ids, &token_type_ids, Some(attention_mask))?;
+        classifier.forward(&hidden)
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
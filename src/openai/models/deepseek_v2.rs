@@ -0,0 +1,642 @@
+use super::moe::{SparseMoeBlock, DEEPSEEK_V2_EXPERT_NAMES};
+use super::{
+    last_token_states, yarn_get_mscale, Config, MlaConfig, MoEConfig, RmsNorm, RopeScaling,
+};
+use crate::openai::models::linear::{
+    linear_b_x as linear_b, linear_no_bias_x as linear_no_bias, LinearX as Linear,
+};
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::SpecificConfig;
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+use either::Either;
+use std::collections::HashMap;
+use std::iter::zip;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct DeepSeekV2RopeScaling {
+    #[serde(rename = "type")]
+    pub scaling_type: String,
+    pub factor: f64,
+    pub original_max_position_embeddings: usize,
+    pub beta_fast: f64,
+    pub beta_slow: f64,
+    pub mscale: f64,
+    pub mscale_all_dim: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct DeepSeekV2Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub moe_intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub n_shared_experts: Option<usize>,
+    pub n_routed_experts: Option<usize>,
+    #[serde(default = "default_routed_scaling_factor")]
+    pub routed_scaling_factor: f64,
+    #[serde(default = "default_topk_method")]
+    pub topk_method: String,
+    pub n_group: Option<usize>,
+    pub topk_group: Option<usize>,
+    pub num_experts_per_tok: Option<usize>,
+    #[serde(default = "default_moe_layer_freq")]
+    pub moe_layer_freq: usize,
+    #[serde(default)]
+    pub first_k_dense_replace: usize,
+    #[serde(default)]
+    pub norm_topk_prob: bool,
+    pub q_lora_rank: Option<usize>,
+    pub kv_lora_rank: usize,
+    pub qk_nope_head_dim: usize,
+    pub qk_rope_head_dim: usize,
+    pub v_head_dim: usize,
+    pub hidden_act: Activation,
+    pub max_position_embeddings: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub rope_scaling: Option<DeepSeekV2RopeScaling>,
+    #[serde(default)]
+    pub attention_bias: bool,
+    pub tie_word_embeddings: Option<bool>,
+    pub bos_token_id: usize,
+    pub eos_token_id: usize,
+}
+
+fn default_routed_scaling_factor() -> f64 {
+    1.
+}
+
+fn default_topk_method() -> String {
+    "greedy".to_string()
+}
+
+fn default_moe_layer_freq() -> usize {
+    1
+}
+
+impl DeepSeekV2Config {
+    pub fn into_config(
+        self,
+        use_flash_attn: bool,
+        kv_cache_dtype: DType,
+        scfg: &SpecificConfig,
+    ) -> Config {
+        let mla_config = MlaConfig {
+            q_lora_rank: self.q_lora_rank,
+            kv_lora_rank: self.kv_lora_rank,
+            qk_nope_head_dim: self.qk_nope_head_dim,
+            qk_rope_head_dim: self.qk_rope_head_dim,
+            v_head_dim: self.v_head_dim,
+        };
+        let moe_config = self.n_routed_experts.map(|num_experts| MoEConfig {
+            num_experts,
+            num_experts_per_tok: self.num_experts_per_tok.unwrap_or(1),
+            moe_intermediate_size: self.moe_intermediate_size,
+            shared_expert_intermediate_size: self
+                .n_shared_experts
+                .map(|n| n * self.moe_intermediate_size),
+            shared_expert_gated: false,
+            norm_topk_prob: self.norm_topk_prob,
+            decoder_sparse_step: 1,
+            // Dense layers: the first `first_k_dense_replace` ones and those off the MoE frequency
+            mlp_only_layers: (0..self.num_hidden_layers)
+                .filter(|&idx| {
+                    idx < self.first_k_dense_replace || idx % self.moe_layer_freq.max(1) != 0
+                })
+                .collect(),
+            routed_scaling_factor: self.routed_scaling_factor,
+            topk_groups: match (self.topk_method.as_str(), self.n_group, self.topk_group) {
+                ("group_limited_greedy", Some(n_group), Some(topk_group)) => {
+                    Some((n_group, topk_group))
+                }
+                _ => None,
+            },
+        });
+        // YaRN parameters in the shared rope scaling map, numbers as single element lists
+        let rope_scaling = self.rope_scaling.as_ref().map(|scaling| {
+            let mut map = HashMap::from([(
+                "type".to_string(),
+                RopeScaling(Either::Right(scaling.scaling_type.clone())),
+            )]);
+            for (key, value) in [
+                ("factor", scaling.factor),
+                ("beta_fast", scaling.beta_fast),
+                ("beta_slow", scaling.beta_slow),
+                ("mscale", scaling.mscale),
+                ("mscale_all_dim", scaling.mscale_all_dim),
+            ] {
+                map.insert(key.to_string(), RopeScaling(Either::Left(vec![value])));
+            }
+            map
+        });
+        Config {
+            hidden_size: self.hidden_size,
+            // One latent "head" per token in the KV cache
+            head_dim: Some(mla_config.latent_dim()),
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: 1,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            use_flash_attn,
+            bos_token_id: super::TokenID(Either::Left(Some(self.bos_token_id as u32))),
+            eos_token_id: super::TokenID(Either::Left(Some(self.eos_token_id as u32))),
+            max_seq_len: self.max_position_embeddings,
+            sliding_window: None,
+            hidden_act: Some(self.hidden_act),
+            tie_word_embeddings: self.tie_word_embeddings.unwrap_or(false),
+            rope_scaling,
+            original_max_position_embeddings: self
+                .rope_scaling
+                .as_ref()
+                .map(|scaling| scaling.original_max_position_embeddings),
+            attention_bias: self.attention_bias,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: None,
+            custom_stop_tokens: None,
+            specific_config: scfg.clone(),
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config,
+            mla_config: Some(mla_config),
+            rope_interleaved: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    /// Rotary embedding of the `qk_rope_head_dim` dims, with YaRN frequency interpolation when
+    /// the checkpoint was trained with it.
+    fn new(cfg: &Config, mla: &MlaConfig, dev: &Device) -> Result<Self> {
+        let (sin, cos) = cfg.get_rope_sin_cos(mla.qk_rope_head_dim, dev)?;
+        Ok(Self { sin, cos })
+    }
+
+    /// Rotate `x` (batch, heads, seq_len, dim). DeepSeek-V2 checkpoints store the rotary dims
+    /// interleaved.
+    fn apply(&self, x: &Tensor, input_positions: &[Vec<usize>]) -> Result<Tensor> {
+        let (b_sz, _h, seq_len, _n_embd) = x.dims4()?;
+        let mut embeds = Vec::new();
+        for (b, seqlen_offset) in zip(0..b_sz, input_positions) {
+            let cos = self.cos.narrow(0, seqlen_offset[0], seq_len)?;
+            let sin = self.sin.narrow(0, seqlen_offset[0], seq_len)?;
+            let x_b = x.narrow(0, b, 1)?.contiguous()?;
+            embeds.push(candle_nn::rotary_emb::rope_i(&x_b, &cos, &sin)?);
+        }
+        Tensor::cat(&embeds, 0)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gate_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp("gate_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        let up_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp("up_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        let down_proj = linear_no_bias(
+            intermediate_sz,
+            hidden_sz,
+            vb.pp("down_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act.unwrap_or(Activation::Silu),
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+enum MoeOrMlp {
+    Moe(SparseMoeBlock),
+    Mlp(MLP),
+}
+
+impl Module for MoeOrMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Moe(moe) => moe.forward(xs),
+            Self::Mlp(mlp) => mlp.forward(xs),
+        }
+    }
+}
+
+/// Query projection, low rank for DeepSeek-V2 and direct for V2-Lite.
+enum QueryProj {
+    Direct(Linear),
+    LowRank {
+        q_a_proj: Linear,
+        q_a_layernorm: RmsNorm,
+        q_b_proj: Linear,
+    },
+}
+
+impl Module for QueryProj {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Direct(q_proj) => q_proj.forward(xs),
+            Self::LowRank {
+                q_a_proj,
+                q_a_layernorm,
+                q_b_proj,
+            } => xs.apply(q_a_proj)?.apply(q_a_layernorm)?.apply(q_b_proj),
+        }
+    }
+}
+
+/// Multi-head latent attention. Only the normalized KV latent and the rotary key of each token
+/// are cached. The key up-projection is absorbed into the queries, so that the queries attend
+/// directly to the latents, and the value up-projection is applied to the attention output.
+struct Attention {
+    q_proj: QueryProj,
+    kv_a_proj_with_mqa: Linear,
+    kv_a_layernorm: RmsNorm,
+    /// Key up-projection (heads, qk_nope_head_dim, kv_lora_rank)
+    w_uk: Tensor,
+    /// Transposed value up-projection (heads, kv_lora_rank, v_head_dim)
+    w_uv_t: Tensor,
+    o_proj: Linear,
+    num_heads: usize,
+    mla: MlaConfig,
+    rotary_emb: Arc<RotaryEmbedding>,
+    attn: PagedAttention,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let mla = cfg.mla_config.clone().unwrap();
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let q_head_dim = mla.qk_nope_head_dim + mla.qk_rope_head_dim;
+        let quant = &cfg.specific_config.quant;
+        let q_proj = match mla.q_lora_rank {
+            Some(q_lora_rank) => QueryProj::LowRank {
+                q_a_proj: linear_b(
+                    hidden_sz,
+                    q_lora_rank,
+                    cfg.attention_bias,
+                    vb.pp("q_a_proj"),
+                    quant,
+                )?,
+                q_a_layernorm: RmsNorm::new(q_lora_rank, cfg.rms_norm_eps, vb.pp("q_a_layernorm"))?,
+                q_b_proj: linear_no_bias(
+                    q_lora_rank,
+                    num_heads * q_head_dim,
+                    vb.pp("q_b_proj"),
+                    quant,
+                )?,
+            },
+            None => QueryProj::Direct(linear_no_bias(
+                hidden_sz,
+                num_heads * q_head_dim,
+                vb.pp("q_proj"),
+                quant,
+            )?),
+        };
+        let kv_a_proj_with_mqa = linear_b(
+            hidden_sz,
+            mla.latent_dim(),
+            cfg.attention_bias,
+            vb.pp("kv_a_proj_with_mqa"),
+            quant,
+        )?;
+        let kv_a_layernorm =
+            RmsNorm::new(mla.kv_lora_rank, cfg.rms_norm_eps, vb.pp("kv_a_layernorm"))?;
+        // Kept unquantized, it is split per head and absorbed
+        let kv_b_proj = vb.pp("kv_b_proj").get(
+            (
+                num_heads * (mla.qk_nope_head_dim + mla.v_head_dim),
+                mla.kv_lora_rank,
+            ),
+            "weight",
+        )?;
+        let kv_b_proj = kv_b_proj.reshape((
+            num_heads,
+            mla.qk_nope_head_dim + mla.v_head_dim,
+            mla.kv_lora_rank,
+        ))?;
+        let w_uk = kv_b_proj.narrow(1, 0, mla.qk_nope_head_dim)?.contiguous()?;
+        let w_uv_t = kv_b_proj
+            .narrow(1, mla.qk_nope_head_dim, mla.v_head_dim)?
+            .t()?
+            .contiguous()?;
+        let o_proj = linear_b(
+            num_heads * mla.v_head_dim,
+            hidden_sz,
+            cfg.attention_bias,
+            vb.pp("o_proj"),
+            quant,
+        )?;
+        let mut softmax_scale = 1. / (q_head_dim as f64).sqrt();
+        if let (Some(factor), Some(mscale_all_dim)) = (
+            cfg.rope_scaling_param("factor"),
+            cfg.rope_scaling_param("mscale_all_dim"),
+        ) {
+            let mscale = yarn_get_mscale(factor, mscale_all_dim);
+            softmax_scale *= mscale * mscale;
+        }
+        Ok(Self {
+            q_proj,
+            kv_a_proj_with_mqa,
+            kv_a_layernorm,
+            w_uk,
+            w_uv_t,
+            o_proj,
+            num_heads,
+            attn: PagedAttention::new(
+                num_heads,
+                mla.latent_dim(),
+                softmax_scale as f32,
+                Some(1),
+                None,
+                vb.device().clone(),
+                None,
+            )?,
+            mla,
+            rotary_emb,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        input_positions: &[Vec<usize>],
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+        let MlaConfig {
+            kv_lora_rank,
+            qk_nope_head_dim,
+            qk_rope_head_dim,
+            v_head_dim,
+            ..
+        } = self.mla;
+
+        let q = self
+            .q_proj
+            .forward(xs)?
+            .reshape((
+                b_sz,
+                seq_len,
+                self.num_heads,
+                qk_nope_head_dim + qk_rope_head_dim,
+            ))?
+            .transpose(1, 2)?;
+        let q_nope = q.narrow(3, 0, qk_nope_head_dim)?.contiguous()?;
+        let q_pe = q.narrow(3, qk_nope_head_dim, qk_rope_head_dim)?;
+
+        let compressed_kv = self.kv_a_proj_with_mqa.forward(xs)?;
+        let kv_latent = compressed_kv
+            .narrow(2, 0, kv_lora_rank)?
+            .apply(&self.kv_a_layernorm)?;
+        let k_pe = compressed_kv
+            .narrow(2, kv_lora_rank, qk_rope_head_dim)?
+            .reshape((b_sz, 1, seq_len, qk_rope_head_dim))?;
+
+        let dtype = kv_latent.dtype();
+        let q_pe = self
+            .rotary_emb
+            .apply(&q_pe.to_dtype(DType::F32)?, input_positions)?
+            .to_dtype(dtype)?;
+        let k_pe = self
+            .rotary_emb
+            .apply(&k_pe.to_dtype(DType::F32)?, input_positions)?
+            .to_dtype(dtype)?
+            .squeeze(1)?;
+
+        // (b, heads, seq_len, kv_lora_rank + qk_rope_head_dim) against the cached latents
+        let q_latent = q_nope.broadcast_matmul(&self.w_uk)?;
+        let q = Tensor::cat(&[&q_latent, &q_pe], 3)?;
+        let latent = Tensor::cat(&[&kv_latent, &k_pe], 2)?;
+
+        let y = self.attn.forward_latent(
+            &q,
+            &latent,
+            attention_mask,
+            cache.map(|(latent_cache, _)| latent_cache),
+            input_metadata,
+            kv_lora_rank,
+        )?;
+        let y = y
+            .reshape((b_sz, self.num_heads, seq_len, kv_lora_rank))?
+            .broadcast_matmul(&self.w_uv_t)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.num_heads * v_head_dim))?;
+        self.o_proj.forward(&y)
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MoeOrMlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        layer_idx: usize,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?;
+        let is_sparse = cfg
+            .moe_config
+            .as_ref()
+            .is_some_and(|moe_cfg| moe_cfg.is_sparse_layer(layer_idx));
+        let mlp = if is_sparse {
+            MoeOrMlp::Moe(SparseMoeBlock::new(
+                cfg,
+                DEEPSEEK_V2_EXPERT_NAMES,
+                vb.pp("mlp"),
+            )?)
+        } else {
+            MoeOrMlp::Mlp(MLP::new(cfg, vb.pp("mlp"))?)
+        };
+        let input_layernorm =
+            RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        input_positions: &[Vec<usize>],
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs =
+            self.self_attn
+                .forward(&xs, attention_mask, input_positions, cache, input_metadata)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+}
+
+pub struct DeepSeekV2 {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    device: Device,
+    dtype: DType,
+    cfg: Config,
+}
+
+impl DeepSeekV2 {
+    pub fn new(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let mla = cfg
+            .mla_config
+            .as_ref()
+            .ok_or_else(|| candle_core::Error::Msg("missing mla config".to_string()))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(cfg, mla, vb_m.device())?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx), layer_idx)?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
+        let lm_head = linear_no_bias(
+            cfg.hidden_size,
+            cfg.vocab_size,
+            if cfg.tie_word_embeddings {
+                vb_m.pp("embed_tokens")
+            } else {
+                vb.pp("lm_head")
+            },
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: device.clone(),
+            dtype,
+            cfg: cfg.clone(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(&self, b_size: usize, tgt_len: usize) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        mask.expand((b_size, 1, tgt_len, tgt_len))?
+            .to_dtype(self.dtype)
+    }
+
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        input_positions: &[Vec<usize>],
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len)?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        if let Some(kv_caches) = kv_caches {
+            for ((k_cache, v_cache), layer) in zip(kv_caches.iter(), self.layers.iter_mut()) {
+                xs = layer.forward(
+                    &xs,
+                    attention_mask.as_ref(),
+                    input_positions,
+                    Some((k_cache, v_cache)),
+                    input_metadata,
+                )?
+            }
+        } else {
+            for layer in self.layers.iter_mut() {
+                xs = layer.forward(
+                    &xs,
+                    attention_mask.as_ref(),
+                    input_positions,
+                    None,
+                    input_metadata,
+                )?
+            }
+        }
+
+        last_token_states(&xs, input_metadata)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)?
+            .to_dtype(DType::F32)
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
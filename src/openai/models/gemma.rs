@@ -1,4 +1,4 @@
-use super::Config;
+use super::{last_token_states, Config, RopeScaling};
 use crate::openai::models::linear::{
     linear_b_x as linear_b, linear_no_bias_x as linear, LinearX as Linear,
 };
@@ -6,10 +6,11 @@ use crate::openai::models::TokenID;
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use crate::SpecificConfig;
-use candle::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle::{DType, Device, Module, Result, Tensor};
 use candle_core as candle;
 use candle_nn::Activation;
 use candle_nn::{RmsNorm, VarBuilder};
+use std::collections::HashMap;
 use std::iter::zip;
 use std::sync::Arc;
 
@@ -33,6 +34,7 @@ pub struct GemmaConfig {
     pub max_position_embeddings: Option<usize>,
     pub attn_logit_softcapping: Option<f64>,
     pub final_logit_softcapping: Option<f64>,
+    pub rope_scaling: Option<HashMap<String, RopeScaling>>,
 }
 
 impl GemmaConfig {
@@ -70,7 +72,7 @@ impl GemmaConfig {
             sliding_window: None,
             hidden_act,
             tie_word_embeddings: false,
-            rope_scaling: None,
+            rope_scaling: self.rope_scaling,
             original_max_position_embeddings: None,
             attention_bias: self.attention_bias,
             partial_rotary_factor: None,
@@ -81,6 +83,11 @@ impl GemmaConfig {
             specific_config: scfg.clone(),
             attn_logit_softcapping: self.attn_logit_softcapping,
             final_logit_softcapping: self.final_logit_softcapping,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
         }
     }
 }
@@ -99,21 +106,8 @@ struct RotaryEmbedding {
 impl RotaryEmbedding {
     fn new(_dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
         let dim = cfg.get_head_size();
-        let max_seq_len = cfg.max_seq_len;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
+        let (sin, cos) = cfg.get_rope_sin_cos(dim, dev)?;
+        Ok(Self { sin, cos })
     }
 
     fn apply_rotary_emb_qkv(
@@ -254,7 +248,8 @@ impl Attention {
                 None,
                 vb.device().clone(),
                 None,
-            )?,
+            )?
+            .with_self_extend(cfg),
         })
     }
 
@@ -511,8 +506,7 @@ impl Gemma {
             }
         }
 
-        let logits = xs
-            .i((.., seq_len - 1, ..))?
+        let logits = last_token_states(&xs, input_metadata)?
             .apply(&self.norm)?
             .apply(&self.lm_head)?;
 
@@ -0,0 +1,547 @@
+use super::{last_token_states, Config, RopeScaling};
+use crate::openai::models::linear::{
+    linear_b_x as linear_b, linear_no_bias_x as linear, LinearX as Linear,
+};
+use crate::openai::models::TokenID;
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::SpecificConfig;
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_core as candle;
+use candle_nn::Activation;
+use candle_nn::{RmsNorm, VarBuilder};
+use std::collections::HashMap;
+use std::iter::zip;
+use std::sync::Arc;
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Gemma2Config {
+    pub attention_bias: bool,
+    pub head_dim: usize,
+    pub hidden_act: Option<Activation>,
+    pub hidden_activation: Option<Activation>,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_attention_heads: usize,
+    pub num_hidden_layers: usize,
+    pub num_key_value_heads: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub vocab_size: usize,
+    pub bos_token_id: TokenID,
+    pub eos_token_id: TokenID,
+    pub max_position_embeddings: Option<usize>,
+    pub attn_logit_softcapping: Option<f64>,
+    pub final_logit_softcapping: Option<f64>,
+    pub query_pre_attn_scalar: usize,
+    pub sliding_window: Option<usize>,
+    pub rope_scaling: Option<HashMap<String, RopeScaling>>,
+}
+
+impl Gemma2Config {
+    pub fn into_config(
+        self,
+        use_flash_attn: bool,
+        kv_cache_dtype: DType,
+        scfg: &SpecificConfig,
+    ) -> Config {
+        let hidden_act = match (self.hidden_act, self.hidden_activation) {
+            (None, Some(act)) | (Some(act), None) => Some(act),
+            (Some(act), Some(_)) => {
+                println!("both hidden_act and hidden_activation are set");
+                Some(act)
+            }
+            (None, None) => Some(Activation::GeluPytorchTanh),
+        };
+        Config {
+            hidden_size: self.hidden_size,
+            head_dim: Some(self.head_dim),
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            use_flash_attn,
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id,
+            max_seq_len: self.max_position_embeddings.unwrap_or(8192),
+            // Only every other layer is local, so the engine must keep the
+            // full context; the window is applied inside the model instead.
+            sliding_window: None,
+            hidden_act,
+            tie_word_embeddings: false,
+            rope_scaling: self.rope_scaling,
+            original_max_position_embeddings: None,
+            attention_bias: self.attention_bias,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: None,
+            custom_stop_tokens: None,
+            specific_config: scfg.clone(),
+            attn_logit_softcapping: self.attn_logit_softcapping,
+            final_logit_softcapping: self.final_logit_softcapping,
+            query_pre_attn_scalar: Some(self.query_pre_attn_scalar),
+            local_sliding_window: self.sliding_window,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
+        }
+    }
+}
+
+fn rms_norm(dim: usize, eps: f64, vb: VarBuilder) -> Result<RmsNorm> {
+    let weight = vb.get(dim, "weight")?;
+    Ok(RmsNorm::new((weight + 1.0f64)?, eps))
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(_dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let dim = cfg.get_head_size();
+        let (sin, cos) = cfg.get_rope_sin_cos(dim, dev)?;
+        Ok(Self { sin, cos })
+    }
+
+    fn apply_rotary_emb_qkv(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        input_positions: &[Vec<usize>],
+    ) -> Result<(Tensor, Tensor)> {
+        let (b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let mut q_embeds = Vec::new();
+        let mut k_embeds = Vec::new();
+        for (b, seqlen_offset) in zip(0..b_sz, input_positions) {
+            let cos = self.cos.narrow(0, seqlen_offset[0], seq_len)?;
+            let sin = self.sin.narrow(0, seqlen_offset[0], seq_len)?;
+            let x_q = q.narrow(0, b, 1)?;
+            let x_k = k.narrow(0, b, 1)?;
+            let q_embed = candle_nn::rotary_emb::rope(&x_q, &cos, &sin).unwrap();
+            let k_embed = candle_nn::rotary_emb::rope(&x_k, &cos, &sin).unwrap();
+            q_embeds.push(q_embed);
+            k_embeds.push(k_embed);
+        }
+        Ok((
+            Tensor::cat(&q_embeds, 0).unwrap(),
+            Tensor::cat(&k_embeds, 0).unwrap(),
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: candle_nn::Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gate_proj = linear(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp("gate_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        let up_proj = linear(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp("up_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        let down_proj = linear(
+            intermediate_sz,
+            hidden_sz,
+            vb.pp("down_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act.unwrap(),
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    attn: PagedAttention,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        sliding_window: Option<usize>,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = cfg.head_dim.unwrap();
+        let bias = cfg.attention_bias;
+        let q_proj = linear_b(
+            hidden_sz,
+            num_heads * head_dim,
+            bias,
+            vb.pp("q_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        let k_proj = linear_b(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            bias,
+            vb.pp("k_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        let v_proj = linear_b(
+            hidden_sz,
+            num_kv_heads * head_dim,
+            bias,
+            vb.pp("v_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        let o_proj = linear_b(
+            num_heads * head_dim,
+            hidden_sz,
+            bias,
+            vb.pp("o_proj"),
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            rotary_emb,
+            attn: PagedAttention::new(
+                cfg.num_attention_heads,
+                head_dim,
+                1. / ((cfg.query_pre_attn_scalar.unwrap_or(head_dim) as f32).sqrt()),
+                Some(cfg.num_key_value_heads),
+                sliding_window,
+                vb.device().clone(),
+                None,
+            )?
+            .with_self_extend(cfg),
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        input_positions: &[Vec<usize>],
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+        softcapping: Option<f64>,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let (q, k, v) = if seq_len == 1 {
+            //no need transpose for seq_len == 1, change reshape dim
+            let q = query_states.reshape((b_sz, self.num_heads, seq_len, self.head_dim))?;
+            let k = key_states.reshape((b_sz, self.num_kv_heads, seq_len, self.head_dim))?;
+            let v = value_states.reshape((b_sz, self.num_kv_heads, seq_len, self.head_dim))?;
+            (q, k, v)
+        } else {
+            let q = query_states
+                .reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?;
+            let k = key_states
+                .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?;
+            let v = value_states
+                .reshape((b_sz, seq_len, self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?;
+            (q, k, v.contiguous()?)
+        };
+
+        let (q, k) = self.rotary_emb.apply_rotary_emb_qkv(
+            &q.to_dtype(DType::F32)?,
+            &k.to_dtype(DType::F32)?,
+            input_positions,
+        )?;
+
+        let q = q.to_dtype(v.dtype())?;
+        let k = k.to_dtype(v.dtype())?;
+
+        // No need repeat_kv since we performed broadcasted matmul in the prefiling stage
+        // while, the decoding stage used paged-attention which also does not need kv stacking (to match query dim)
+        // let k = candle_transformers::utils::repeat_kv(k, self.num_kv_groups)?.contiguous()?;
+        // let v =
+        //     candle_transformers::utils::repeat_kv(v, self.num_kv_groups)?.contiguous()?;
+
+        let y = self.attn.forward(
+            &q,
+            &k,
+            &v,
+            attention_mask,
+            cache.map(|(k_, _)| k_.clone()),
+            cache.map(|(_, v_)| v_.clone()),
+            input_metadata,
+            softcapping,
+        )?;
+
+        let y = if attention_mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, ()))?
+        } else {
+            y.reshape((b_sz, seq_len, ()))?
+        };
+        let y = self.o_proj.forward(&y)?;
+        Ok(y)
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    pre_feedforward_layernorm: RmsNorm,
+    post_feedforward_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    sliding_window: Option<usize>,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        layer_idx: usize,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        // Gemma2 interleaves local (sliding window) and global attention, starting with a local layer.
+        let sliding_window = if layer_idx % 2 == 0 {
+            cfg.local_sliding_window
+        } else {
+            None
+        };
+        let self_attn = Attention::new(rotary_emb, cfg, sliding_window, vb.pp("self_attn"))?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"))?;
+        let input_layernorm =
+            rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let pre_feedforward_layernorm = rms_norm(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("pre_feedforward_layernorm"),
+        )?;
+        let post_feedforward_layernorm = rms_norm(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_feedforward_layernorm"),
+        )?;
+        let post_attention_layernorm = rms_norm(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            pre_feedforward_layernorm,
+            post_feedforward_layernorm,
+            post_attention_layernorm,
+            sliding_window,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        input_positions: &[Vec<usize>],
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+        softcapping: Option<f64>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(
+            &xs,
+            attention_mask,
+            input_positions,
+            cache,
+            input_metadata,
+            softcapping,
+        )?;
+        let xs = xs.apply(&self.post_attention_layernorm)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs
+            .apply(&self.pre_feedforward_layernorm)?
+            .apply(&self.mlp)?
+            .apply(&self.post_feedforward_layernorm)?;
+        residual + xs
+    }
+}
+
+pub struct Gemma2 {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    device: Device,
+    dtype: DType,
+    hidden_size: usize,
+    cfg: Config,
+}
+
+impl Gemma2 {
+    pub fn new(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(vb.dtype(), cfg, vb_m.device())?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, layer_idx, vb_l.pp(layer_idx))?;
+            layers.push(layer)
+        }
+        let norm = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
+        let lm_head = Linear::new(
+            embed_tokens.embeddings().clone(),
+            None,
+            &cfg.specific_config.quant,
+        );
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: device.clone(),
+            dtype,
+            hidden_size: cfg.hidden_size,
+            cfg: cfg.clone(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        b_size: usize,
+        tgt_len: usize,
+        sliding_window: Option<usize>,
+    ) -> Result<Tensor> {
+        let sliding_window = sliding_window.unwrap_or(tgt_len + 1);
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| {
+                (0..tgt_len).map(move |j| {
+                    if i < j || j + sliding_window <= i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        mask.expand((b_size, 1, tgt_len, tgt_len))?
+            .to_dtype(self.dtype)
+    }
+
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        input_positions: &[Vec<usize>],
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let (global_mask, local_mask) = if seq_len <= 1 {
+            (None, None)
+        } else {
+            let global_mask = self.prepare_decoder_attention_mask(b_size, seq_len, None)?;
+            let local_mask = self.prepare_decoder_attention_mask(
+                b_size,
+                seq_len,
+                self.cfg.local_sliding_window,
+            )?;
+            (Some(global_mask), Some(local_mask))
+        };
+        let xs = self.embed_tokens.forward(input_ids)?;
+        let mut xs = (xs * (self.hidden_size as f64).sqrt())?;
+        if let Some(kv_caches) = kv_caches {
+            for ((k_cache, v_cache), layer) in zip(kv_caches.iter(), self.layers.iter_mut()) {
+                let attention_mask = if layer.sliding_window.is_some() {
+                    local_mask.as_ref()
+                } else {
+                    global_mask.as_ref()
+                };
+                xs = layer.forward(
+                    &xs,
+                    attention_mask,
+                    input_positions,
+                    Some((k_cache, v_cache)),
+                    input_metadata,
+                    self.cfg.attn_logit_softcapping,
+                )?
+            }
+        } else {
+            for layer in self.layers.iter_mut() {
+                let attention_mask = if layer.sliding_window.is_some() {
+                    local_mask.as_ref()
+                } else {
+                    global_mask.as_ref()
+                };
+                xs = layer.forward(
+                    &xs,
+                    attention_mask,
+                    input_positions,
+                    None,
+                    input_metadata,
+                    self.cfg.attn_logit_softcapping,
+                )?
+            }
+        }
+
+        let logits = last_token_states(&xs, input_metadata)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)?;
+
+        let logits = match self.cfg.final_logit_softcapping {
+            None => logits,
+            Some(sc) => ((logits / sc)?.tanh()? * sc)?,
+        };
+        logits.to_dtype(DType::F32)
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
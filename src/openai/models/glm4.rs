@@ -0,0 +1,485 @@
+use super::{last_token_states, Config, RmsNorm, RopeScaling, TokenID};
+use crate::openai::models::linear::{
+    linear_b_x as linear_b, linear_no_bias_x as linear_no_bias, LinearX as Linear,
+};
+use crate::paged_attention::input_metadata::InputMetadata;
+use crate::paged_attention::PagedAttention;
+use crate::SpecificConfig;
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+use either::Either;
+use std::collections::HashMap;
+use std::iter::zip;
+use std::sync::Arc;
+
+/// Config of the ChatGLM architecture (ChatGLM3, GLM-4).
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Glm4Config {
+    pub num_layers: usize,
+    pub padded_vocab_size: usize,
+    pub hidden_size: usize,
+    pub ffn_hidden_size: usize,
+    pub kv_channels: usize,
+    pub num_attention_heads: usize,
+    pub seq_length: usize,
+    pub layernorm_epsilon: f64,
+    #[serde(default = "default_true")]
+    pub rmsnorm: bool,
+    #[serde(default)]
+    pub apply_residual_connection_post_layernorm: bool,
+    #[serde(default = "default_true")]
+    pub post_layer_norm: bool,
+    #[serde(default)]
+    pub add_bias_linear: bool,
+    #[serde(default)]
+    pub add_qkv_bias: bool,
+    #[serde(default)]
+    pub multi_query_attention: bool,
+    #[serde(default = "default_multi_query_group_num")]
+    pub multi_query_group_num: usize,
+    pub rope_ratio: Option<f64>,
+    pub tie_word_embeddings: Option<bool>,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: TokenID,
+    pub rope_scaling: Option<HashMap<String, RopeScaling>>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_multi_query_group_num() -> usize {
+    1
+}
+
+impl Glm4Config {
+    pub fn into_config(
+        self,
+        use_flash_attn: bool,
+        kv_cache_dtype: DType,
+        scfg: &SpecificConfig,
+    ) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            head_dim: Some(self.kv_channels),
+            intermediate_size: self.ffn_hidden_size,
+            vocab_size: self.padded_vocab_size,
+            num_hidden_layers: self.num_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: if self.multi_query_attention {
+                self.multi_query_group_num
+            } else {
+                self.num_attention_heads
+            },
+            rms_norm_eps: self.layernorm_epsilon,
+            rope_theta: 10000. * self.rope_ratio.unwrap_or(1.),
+            use_flash_attn,
+            bos_token_id: TokenID(Either::Left(self.bos_token_id)),
+            eos_token_id: self.eos_token_id,
+            max_seq_len: self.seq_length,
+            sliding_window: None,
+            hidden_act: Some(Activation::Silu),
+            tie_word_embeddings: self.tie_word_embeddings.unwrap_or(false),
+            rope_scaling: self.rope_scaling,
+            original_max_position_embeddings: None,
+            attention_bias: self.add_bias_linear,
+            // Half of each head is rotary embedded
+            partial_rotary_factor: Some(0.5),
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: Some(self.add_qkv_bias),
+            custom_stop_tokens: Some(vec!["<|user|>".to_string(), "<|observation|>".to_string()]),
+            specific_config: scfg.clone(),
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: true,
+        }
+    }
+}
+
+/// `Glm4Config` fields that do not map to `Config`.
+#[derive(Debug, Clone, Copy)]
+struct Glm4Layout {
+    rmsnorm: bool,
+    post_layer_norm: bool,
+    apply_residual_connection_post_layernorm: bool,
+}
+
+impl From<&Glm4Config> for Glm4Layout {
+    fn from(cfg: &Glm4Config) -> Self {
+        Self {
+            rmsnorm: cfg.rmsnorm,
+            post_layer_norm: cfg.post_layer_norm,
+            apply_residual_connection_post_layernorm: cfg.apply_residual_connection_post_layernorm,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &Config, dev: &Device) -> Result<Self> {
+        let dim = cfg.get_rotary_dim();
+        let (sin, cos) = cfg.get_rope_sin_cos(dim, dev)?;
+        Ok(Self { sin, cos })
+    }
+
+    /// Rotate the leading rotary dims of `x` (batch, heads, seq_len, head_dim), as interleaved
+    /// pairs; the remaining dims pass through.
+    fn apply(&self, x: &Tensor, input_positions: &[Vec<usize>]) -> Result<Tensor> {
+        let (b_sz, _h, seq_len, head_dim) = x.dims4()?;
+        let rotary_dim = self.cos.dim(1)? * 2;
+        let mut embeds = Vec::new();
+        for (b, seqlen_offset) in zip(0..b_sz, input_positions) {
+            let cos = self.cos.narrow(0, seqlen_offset[0], seq_len)?;
+            let sin = self.sin.narrow(0, seqlen_offset[0], seq_len)?;
+            let x_b = x.narrow(0, b, 1)?;
+            let rot = x_b.narrow(3, 0, rotary_dim)?.contiguous()?;
+            let pass = x_b.narrow(3, rotary_dim, head_dim - rotary_dim)?;
+            let rot = candle_nn::rotary_emb::rope_i(&rot, &cos, &sin)?;
+            embeds.push(Tensor::cat(&[&rot, &pass], 3)?);
+        }
+        Tensor::cat(&embeds, 0)
+    }
+}
+
+/// SwiGLU MLP with fused gate and up projections.
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    dense_h_to_4h: Linear,
+    dense_4h_to_h: Linear,
+    intermediate_size: usize,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dense_h_to_4h = linear_b(
+            cfg.hidden_size,
+            cfg.intermediate_size * 2,
+            cfg.attention_bias,
+            vb.pp("dense_h_to_4h"),
+            &cfg.specific_config.quant,
+        )?;
+        let dense_4h_to_h = linear_b(
+            cfg.intermediate_size,
+            cfg.hidden_size,
+            cfg.attention_bias,
+            vb.pp("dense_4h_to_h"),
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            dense_h_to_4h,
+            dense_4h_to_h,
+            intermediate_size: cfg.intermediate_size,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs.apply(&self.dense_h_to_4h)?;
+        let gate = xs.narrow(2, 0, self.intermediate_size)?;
+        let up = xs.narrow(2, self.intermediate_size, self.intermediate_size)?;
+        (gate.apply(&Activation::Silu)? * up)?.apply(&self.dense_4h_to_h)
+    }
+}
+
+/// Grouped (multi-query) attention with a fused query/key/value projection.
+struct Attention {
+    query_key_value: Linear,
+    dense: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    attn: PagedAttention,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = cfg.get_head_size();
+        let query_key_value = linear_b(
+            cfg.hidden_size,
+            (num_heads + 2 * num_kv_heads) * head_dim,
+            cfg.use_qkv_bias.unwrap_or(false) || cfg.attention_bias,
+            vb.pp("query_key_value"),
+            &cfg.specific_config.quant,
+        )?;
+        let dense = linear_b(
+            num_heads * head_dim,
+            cfg.hidden_size,
+            cfg.attention_bias,
+            vb.pp("dense"),
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            query_key_value,
+            dense,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            rotary_emb,
+            attn: PagedAttention::new(
+                num_heads,
+                head_dim,
+                1. / ((head_dim as f32).sqrt()),
+                Some(num_kv_heads),
+                None,
+                vb.device().clone(),
+                None,
+            )?
+            .with_self_extend(cfg),
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        input_positions: &[Vec<usize>],
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, _) = xs.dims3()?;
+
+        let qkv = self.query_key_value.forward(xs)?;
+        let q_size = self.num_heads * self.head_dim;
+        let kv_size = self.num_kv_heads * self.head_dim;
+        let split = |start: usize, size: usize, heads: usize| -> Result<Tensor> {
+            qkv.narrow(2, start, size)?
+                .reshape((b_sz, seq_len, heads, self.head_dim))?
+                .transpose(1, 2)
+        };
+        let q = split(0, q_size, self.num_heads)?;
+        let k = split(q_size, kv_size, self.num_kv_heads)?;
+        let v = split(q_size + kv_size, kv_size, self.num_kv_heads)?.contiguous()?;
+
+        let q = self
+            .rotary_emb
+            .apply(&q.to_dtype(DType::F32)?, input_positions)?
+            .to_dtype(v.dtype())?;
+        let k = self
+            .rotary_emb
+            .apply(&k.to_dtype(DType::F32)?, input_positions)?
+            .to_dtype(v.dtype())?;
+
+        let y = self.attn.forward(
+            &q,
+            &k,
+            &v,
+            attention_mask,
+            cache.map(|(k_, _)| k_.clone()),
+            cache.map(|(_, v_)| v_.clone()),
+            input_metadata,
+            None,
+        )?;
+
+        let y = if attention_mask.is_some() {
+            y.transpose(1, 2)?.reshape((b_sz, seq_len, q_size))?
+        } else {
+            y.reshape((b_sz, seq_len, q_size))?
+        };
+        self.dense.forward(&y)
+    }
+}
+
+struct DecoderLayer {
+    self_attention: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    residual_post_layernorm: bool,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        layout: Glm4Layout,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let self_attention = Attention::new(rotary_emb, cfg, vb.pp("self_attention"))?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"))?;
+        let input_layernorm =
+            RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            self_attention,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+            residual_post_layernorm: layout.apply_residual_connection_post_layernorm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        input_positions: &[Vec<usize>],
+        cache: Option<(&Tensor, &Tensor)>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let normed = self.input_layernorm.forward(xs)?;
+        let attn_out = self.self_attention.forward(
+            &normed,
+            attention_mask,
+            input_positions,
+            cache,
+            input_metadata,
+        )?;
+        let residual = if self.residual_post_layernorm {
+            &normed
+        } else {
+            xs
+        };
+        let xs = (attn_out + residual)?;
+        let normed = self.post_attention_layernorm.forward(&xs)?;
+        let mlp_out = self.mlp.forward(&normed)?;
+        let residual = if self.residual_post_layernorm {
+            &normed
+        } else {
+            &xs
+        };
+        mlp_out + residual
+    }
+}
+
+pub struct Glm4 {
+    embedding: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    final_layernorm: Option<RmsNorm>,
+    output_layer: Linear,
+    device: Device,
+    dtype: DType,
+    cfg: Config,
+}
+
+impl Glm4 {
+    pub fn new(
+        vb: VarBuilder,
+        cfg: &Config,
+        glm_cfg: &Glm4Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let layout = Glm4Layout::from(glm_cfg);
+        if !layout.rmsnorm {
+            candle_core::bail!("ChatGLM checkpoints with LayerNorm are not supported");
+        }
+        let vb_t = vb.pp("transformer");
+        let embedding = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            vb_t.pp("embedding.word_embeddings"),
+        )?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(cfg, vb.device())?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_t.pp("encoder.layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, layout, vb_l.pp(layer_idx))?;
+            layers.push(layer)
+        }
+        let final_layernorm = if layout.post_layer_norm {
+            Some(RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb_t.pp("encoder.final_layernorm"),
+            )?)
+        } else {
+            None
+        };
+        let output_layer = linear_no_bias(
+            cfg.hidden_size,
+            cfg.vocab_size,
+            if cfg.tie_word_embeddings {
+                vb_t.pp("embedding.word_embeddings")
+            } else {
+                vb_t.pp("output_layer")
+            },
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            embedding,
+            layers,
+            final_layernorm,
+            output_layer,
+            device: device.clone(),
+            dtype,
+            cfg: cfg.clone(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(&self, b_size: usize, tgt_len: usize) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        mask.expand((b_size, 1, tgt_len, tgt_len))?
+            .to_dtype(self.dtype)
+    }
+
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        input_positions: &[Vec<usize>],
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(b_size, seq_len)?;
+            Some(mask)
+        };
+        let mut xs = self.embedding.forward(input_ids)?;
+        if let Some(kv_caches) = kv_caches {
+            for ((k_cache, v_cache), layer) in zip(kv_caches.iter(), self.layers.iter_mut()) {
+                xs = layer.forward(
+                    &xs,
+                    attention_mask.as_ref(),
+                    input_positions,
+                    Some((k_cache, v_cache)),
+                    input_metadata,
+                )?
+            }
+        } else {
+            for layer in self.layers.iter_mut() {
+                xs = layer.forward(
+                    &xs,
+                    attention_mask.as_ref(),
+                    input_positions,
+                    None,
+                    input_metadata,
+                )?
+            }
+        }
+
+        let xs = last_token_states(&xs, input_metadata)?;
+        let xs = match &self.final_layernorm {
+            Some(norm) => xs.apply(norm)?,
+            None => xs,
+        };
+        xs.apply(&self.output_layer)?.to_dtype(DType::F32)
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
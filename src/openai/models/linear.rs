@@ -17,14 +17,17 @@
 //! assert_eq!(ys.to_vec2::<f32>()?, &[[210.0, 430.0, 650.0]]);
 //! # Ok(()) }
 //! ```
+use crate::backend::{
+    dequantize_4bit, dequantize_absmax, fp8_dequantize, quantize_4bit, quantize_absmax,
+    Codebook4Bit,
+};
 use crate::candle::Module;
 use crate::candle::{
     quantized::{gguf_file, QMatMul, QTensor},
-    DType, Device, Result, Tensor,
+    DType, Device, Result, Shape, Tensor,
 };
 use candle_core::quantized;
 use candle_nn::init;
-use either::Either;
 use std::sync::Arc;
 
 #[derive(Clone, Debug)]
@@ -51,6 +54,12 @@ impl Linear {
 //Remember use this linear layer throughout all of the models
 impl Module for Linear {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        // The weight is in another dtype than the activations under a dtype override
+        if x.dtype() != self.weight.dtype() {
+            return self
+                .forward(&x.to_dtype(self.weight.dtype())?)?
+                .to_dtype(x.dtype());
+        }
         let w = match *x.dims() {
             [b1, seq_len, _, _] => {
                 if seq_len > 1 {
@@ -134,6 +143,13 @@ pub fn linear_b(
     }
 }
 
+/// In-situ quantizations of the `quant` model option, see [`QLinear::from_linear_x`] and
+/// [`Linear4Bit::parse`].
+pub const QUANTIZATIONS: [&str; 14] = [
+    "q4_0", "q4_1", "q5_0", "q5_1", "q8_0", "q2k", "q3k", "q4k", "q5k", "q6k", "nf4", "nf4_dq",
+    "int4", "int4_dq",
+];
+
 #[derive(Debug, Clone)]
 pub struct QLinear {
     inner: QMatMul,
@@ -367,28 +383,201 @@ impl Module for QLinear {
     }
 }
 
+/// A linear layer with FP8 (E4M3) weights and per-tensor or per-channel scales, from an FP8
+/// checkpoint (`fp8` or `compressed-tensors` quantization). The weights are dequantized to the
+/// dtype of the activations for every forward pass (W8A16), they stay in FP8 in memory.
+#[derive(Debug, Clone)]
+pub struct Fp8Linear {
+    /// FP8 bytes of shape `(out_dim, in_dim)`
+    weight: Tensor,
+    /// One scale, or one per output channel
+    scale: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl Fp8Linear {
+    pub fn new(weight: Tensor, scale: Tensor, bias: Option<Tensor>) -> Self {
+        Self {
+            weight,
+            scale,
+            bias,
+        }
+    }
+
+    /// Whether the module at `vb` holds FP8 weights, which come with their `weight_scale`.
+    pub fn is_fp8(vb: &candle_nn::VarBuilder) -> bool {
+        vb.contains_tensor("weight_scale")
+    }
+
+    /// Load the FP8 weight, its scales and the bias of the module at `vb`. The scales are stored
+    /// as a scalar, a single element or one per output channel, depending on the checkpoint.
+    pub fn load(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        vb: candle_nn::VarBuilder,
+    ) -> Result<Self> {
+        let weight = vb.get_with_hints_dtype((out_dim, in_dim), "weight", init::ZERO, DType::U8)?;
+        let scale = [
+            Shape::from((out_dim, 1)),
+            Shape::from(out_dim),
+            Shape::from(()),
+            Shape::from(1),
+        ]
+        .into_iter()
+        .find_map(|shape| {
+            vb.get_with_hints_dtype(shape, "weight_scale", init::ZERO, DType::F32)
+                .ok()
+        })
+        .ok_or_else(|| {
+            candle::Error::Msg(format!(
+                "{}.weight_scale is neither a per-tensor nor a per-channel scale",
+                vb.prefix()
+            ))
+        })?;
+        let bias = if bias {
+            Some(vb.get(out_dim, "bias")?)
+        } else {
+            None
+        };
+        Ok(Self::new(weight, scale, bias))
+    }
+}
+
+impl Module for Fp8Linear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let weight = fp8_dequantize(&self.weight, &self.scale, x.dtype())?;
+        Linear::new(weight, self.bias.clone()).forward(x)
+    }
+}
+
+/// The scales of the blocks of a [`Linear4Bit`].
+#[derive(Debug, Clone)]
+enum Absmax {
+    Full(Tensor),
+    /// Quantized to 8 bits, see [`quantize_absmax`]
+    Double {
+        quantized: Tensor,
+        scale: Tensor,
+    },
+}
+
+/// A linear layer quantized blockwise to 4 bits (NF4 or INT4) at load time, like the 4-bit
+/// layers of bitsandbytes. The weights are dequantized for every forward pass, this trades speed
+/// for memory: about 4.5 bits per weight, 4.1 with double quantization.
+#[derive(Debug, Clone)]
+pub struct Linear4Bit {
+    codes: Tensor,
+    absmax: Absmax,
+    codebook: Codebook4Bit,
+    shape: Shape,
+    /// The dtype of the weights before quantization
+    dtype: DType,
+    bias: Option<Tensor>,
+}
+
+impl Linear4Bit {
+    /// The codebook and whether the scales are double quantized, for the 4-bit `quant` options
+    /// (`nf4`, `nf4_dq`, `int4` and `int4_dq`).
+    pub fn parse(quant: &str) -> Option<(Codebook4Bit, bool)> {
+        match quant {
+            "nf4" => Some((Codebook4Bit::Nf4, false)),
+            "nf4_dq" => Some((Codebook4Bit::Nf4, true)),
+            "int4" => Some((Codebook4Bit::Int4, false)),
+            "int4_dq" => Some((Codebook4Bit::Int4, true)),
+            _ => None,
+        }
+    }
+
+    pub fn quantize(linear: Linear, codebook: Codebook4Bit, double_quant: bool) -> Result<Self> {
+        let weight = linear.weight();
+        let (codes, absmax) = quantize_4bit(weight, codebook)?;
+        let absmax = if double_quant {
+            let (quantized, scale) = quantize_absmax(&absmax)?;
+            Absmax::Double { quantized, scale }
+        } else {
+            Absmax::Full(absmax)
+        };
+        Ok(Self {
+            codes,
+            absmax,
+            codebook,
+            shape: weight.shape().clone(),
+            dtype: weight.dtype(),
+            bias: linear.bias().cloned(),
+        })
+    }
+
+    /// The weights in their original dtype.
+    pub fn dequantize(&self) -> Result<Tensor> {
+        let absmax = match &self.absmax {
+            Absmax::Full(absmax) => absmax.clone(),
+            Absmax::Double { quantized, scale } => dequantize_absmax(quantized, scale)?,
+        };
+        dequantize_4bit(
+            &self.codes,
+            &absmax,
+            self.codebook,
+            self.shape.clone(),
+            self.dtype,
+        )
+    }
+}
+
+impl Module for Linear4Bit {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        Linear::new(self.dequantize()?, self.bias.clone()).forward(x)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum LinearKind {
+    Plain(Linear),
+    Quantized(QLinear),
+    Fp8(Fp8Linear),
+    FourBit(Linear4Bit),
+}
+
 #[derive(Debug, Clone)]
-pub struct LinearX(Either<Linear, QLinear>);
+pub struct LinearX(LinearKind);
 
 impl Module for LinearX {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
         match &self.0 {
-            Either::Left(ln) => ln.forward(x),
-            Either::Right(ln) => ln.forward(x),
+            LinearKind::Plain(ln) => ln.forward(x),
+            LinearKind::Quantized(ln) => ln.forward(x),
+            LinearKind::Fp8(ln) => ln.forward(x),
+            LinearKind::FourBit(ln) => ln.forward(x),
         }
     }
 }
 impl LinearX {
     pub fn new(weight: Tensor, bias: Option<Tensor>, quant: &Option<String>) -> Self {
-        let ln = Linear::new(weight, bias);
-        if let Some(quatized_type) = quant {
-            LinearX(Either::Right(QLinear::from_linear_x(
-                ln,
-                quatized_type.clone(),
-            )))
-        } else {
-            LinearX(Either::Left(ln))
-        }
+        quantize_linear(Linear::new(weight, bias), quant).unwrap()
+    }
+}
+
+/// Apply the in-situ quantization `quant`, if any, to a loaded layer.
+fn quantize_linear(ln: Linear, quant: &Option<String>) -> Result<LinearX> {
+    let Some(quant) = quant else {
+        return Ok(LinearX(LinearKind::Plain(ln)));
+    };
+    Ok(match Linear4Bit::parse(quant) {
+        Some((codebook, double_quant)) => LinearX(LinearKind::FourBit(Linear4Bit::quantize(
+            ln,
+            codebook,
+            double_quant,
+        )?)),
+        None => LinearX(LinearKind::Quantized(QLinear::from_linear_x(
+            ln,
+            quant.clone(),
+        ))),
+    })
+}
+
+impl From<Fp8Linear> for LinearX {
+    fn from(ln: Fp8Linear) -> Self {
+        LinearX(LinearKind::Fp8(ln))
     }
 }
 
@@ -398,15 +587,12 @@ pub fn linear_x(
     vb: candle_nn::VarBuilder,
     quant: &Option<String>,
 ) -> Result<LinearX> {
-    let ln = linear(in_dim, out_dim, vb).unwrap();
-    if let Some(quatized_type) = quant {
-        Ok(LinearX(Either::Right(QLinear::from_linear_x(
-            ln,
-            quatized_type.clone(),
-        ))))
-    } else {
-        Ok(LinearX(Either::Left(ln)))
+    // The weights of FP8 checkpoints are already quantized
+    if Fp8Linear::is_fp8(&vb) {
+        return Ok(Fp8Linear::load(in_dim, out_dim, true, vb)?.into());
     }
+    let ln = linear(in_dim, out_dim, vb).unwrap();
+    quantize_linear(ln, quant)
 }
 
 pub fn linear_no_bias_x(
@@ -415,17 +601,13 @@ pub fn linear_no_bias_x(
     vb: candle_nn::VarBuilder,
     quant: &Option<String>,
 ) -> Result<LinearX> {
+    if Fp8Linear::is_fp8(&vb) {
+        return Ok(Fp8Linear::load(in_dim, out_dim, false, vb)?.into());
+    }
     let init_ws = init::DEFAULT_KAIMING_NORMAL;
     let ws = vb.get_with_hints((out_dim, in_dim), "weight", init_ws)?;
     let ln = Linear::new(ws, None);
-    if let Some(quatized_type) = quant {
-        Ok(LinearX(Either::Right(QLinear::from_linear_x(
-            ln,
-            quatized_type.clone(),
-        ))))
-    } else {
-        Ok(LinearX(Either::Left(ln)))
-    }
+    quantize_linear(ln, quant)
 }
 
 pub fn linear_b_x(
@@ -441,3 +623,66 @@ pub fn linear_b_x(
         linear_no_bias_x(in_dim, out_dim, vb, quant)
     }
 }
+
+/// The `quantization_config` of a `config.json`, for the checkpoints published with quantized
+/// weights.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct QuantizationConfig {
+    pub quant_method: String,
+    /// Block-wise scales (`fp8`), e.g. `[128, 128]`
+    #[serde(default)]
+    pub weight_block_size: Option<Vec<usize>>,
+    /// The schemes of `compressed-tensors`
+    #[serde(default)]
+    pub config_groups: std::collections::HashMap<String, QuantizationGroup>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct QuantizationGroup {
+    #[serde(default)]
+    pub weights: Option<QuantizationArgs>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct QuantizationArgs {
+    pub num_bits: usize,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub strategy: String,
+}
+
+impl QuantizationConfig {
+    /// Check that the weights are FP8 with per-tensor or per-channel scales, the only quantized
+    /// checkpoints that are loaded (by [`Fp8Linear`]).
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match self.quant_method.as_str() {
+            "fp8" => match &self.weight_block_size {
+                Some(block) => Err(format!(
+                    "FP8 weights with block-wise scales ({block:?}) are not supported"
+                )),
+                None => Ok(()),
+            },
+            "compressed-tensors" => {
+                for (name, group) in &self.config_groups {
+                    let Some(weights) = &group.weights else {
+                        continue;
+                    };
+                    if weights.kind != "float" || weights.num_bits != 8 {
+                        return Err(format!(
+                            "Quantization group {name} has {}-bit {} weights, only FP8 weights are supported",
+                            weights.num_bits, weights.kind
+                        ));
+                    }
+                    if !matches!(weights.strategy.as_str(), "tensor" | "channel") {
+                        return Err(format!(
+                            "Quantization group {name} has {} scales, only per-tensor and per-channel scales are supported",
+                            weights.strategy
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            method => Err(format!("Quantization method {method} is not supported")),
+        }
+    }
+}
@@ -1,15 +1,16 @@
-use super::Config;
+use super::{last_token_states, Config, RmsNorm, RopeScaling};
 use crate::openai::models::linear::{linear_no_bias_x as linear, LinearX as Linear};
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use crate::SpecificConfig;
-use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle::{DType, Device, Result, Tensor};
 use candle_core as candle;
 use candle_nn::{embedding, Embedding, Module, VarBuilder};
-use candle_transformers::models::with_tracing::RmsNorm;
 pub const MAX_SEQ_LEN: usize = 4096;
 use crate::openai::models::TokenID;
+use std::collections::HashMap;
 use std::iter::zip;
+use std::thread::JoinHandle;
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct LlamaConfig {
@@ -25,6 +26,7 @@ pub struct LlamaConfig {
     pub bos_token_id: TokenID,
     pub eos_token_id: TokenID,
     pub max_position_embeddings: Option<usize>,
+    pub rope_scaling: Option<HashMap<String, RopeScaling>>,
 }
 
 fn default_rope() -> f32 {
@@ -55,7 +57,7 @@ impl LlamaConfig {
             sliding_window: None,
             hidden_act: None,
             tie_word_embeddings: false,
-            rope_scaling: None,
+            rope_scaling: self.rope_scaling,
             original_max_position_embeddings: None,
             attention_bias: false,
             partial_rotary_factor: None,
@@ -66,6 +68,11 @@ impl LlamaConfig {
             specific_config: scfg.clone(),
             attn_logit_softcapping: None,
             final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
         }
     }
 }
@@ -80,18 +87,11 @@ impl Cache {
     pub fn new(dtype: DType, config: &Config, device: &Device) -> Result<Self> {
         // precompute freqs_cis
         let n_elem = config.hidden_size / config.num_attention_heads;
-        let theta: Vec<_> = (0..n_elem)
-            .step_by(2)
-            .map(|i| 1f32 / config.rope_theta.powf(i as f64 / n_elem as f64) as f32)
-            .collect();
-        let theta = Tensor::new(theta.as_slice(), device)?;
-        let idx_theta = Tensor::arange(0, config.max_seq_len as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((config.max_seq_len, 1))?
-            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-        let cos = idx_theta.cos()?.to_dtype(dtype)?;
-        let sin = idx_theta.sin()?.to_dtype(dtype)?;
-        Ok(Self { cos, sin })
+        let (sin, cos) = config.get_rope_sin_cos(n_elem, device)?;
+        Ok(Self {
+            cos: cos.to_dtype(dtype)?,
+            sin: sin.to_dtype(dtype)?,
+        })
     }
 }
 
@@ -228,7 +228,8 @@ impl CausalSelfAttention {
                 None,
                 vb.device().clone(),
                 None,
-            )?,
+            )?
+            .with_self_extend(cfg),
             cos_sin_cache: Cache::new(dtype, cfg, device)?,
         })
     }
@@ -324,11 +325,42 @@ impl Block {
     }
 }
 
+/// Decoder layers that are read from the memory-mapped checkpoint on every forward pass instead
+/// of staying resident. The next layer is loaded on a background thread while the current one
+/// runs, so at most two layers occupy device memory at a time.
+struct StreamedBlocks {
+    vb: VarBuilder<'static>,
+    prefetched: Option<JoinHandle<Result<Block>>>,
+}
+
+impl StreamedBlocks {
+    fn spawn_load(
+        &self,
+        idx: usize,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> JoinHandle<Result<Block>> {
+        let vb = self.vb.pp(format!("model.layers.{idx}"));
+        let cfg = cfg.clone();
+        let device = device.clone();
+        std::thread::spawn(move || Block::load(vb, &cfg, dtype, &device))
+    }
+}
+
+fn join_block(handle: JoinHandle<Result<Block>>) -> Result<Block> {
+    handle
+        .join()
+        .map_err(|_| candle::Error::Msg("Layer loading thread panicked".to_string()))?
+}
+
 pub struct Llama {
     wte: Embedding,
     blocks: Vec<Block>,
+    streamed: Option<StreamedBlocks>,
     ln_f: RmsNorm,
-    lm_head: Linear,
+    /// `None` for reward models, which have a `ScoreHead` instead
+    lm_head: Option<Linear>,
     cfg: Config,
     dtype: DType,
     device: Device,
@@ -360,49 +392,160 @@ impl Llama {
             Some(mask)
         };
         let mut x = self.wte.forward(x)?;
-        if let Some(kv_caches) = kv_caches {
-            for ((k_cache, v_cache), block) in zip(kv_caches.iter(), &mut self.blocks) {
+        if let Some(streamed) = &mut self.streamed {
+            let num_layers = self.cfg.num_hidden_layers;
+            let mut next = Some(match streamed.prefetched.take() {
+                Some(handle) => handle,
+                None => streamed.spawn_load(0, &self.cfg, self.dtype, &self.device),
+            });
+            for idx in 0..num_layers {
+                let mut block = join_block(next.take().unwrap())?;
+                if idx + 1 < num_layers {
+                    next = Some(streamed.spawn_load(idx + 1, &self.cfg, self.dtype, &self.device));
+                }
+                let cache = kv_caches.map(|kv_caches| (&kv_caches[idx].0, &kv_caches[idx].1));
                 x = block.forward(
                     &x,
                     attention_mask.as_ref(),
                     input_positions,
-                    Some((k_cache, v_cache)),
+                    cache,
                     input_metadata,
                 )?;
             }
+            // Start reading the first layer for the next step right away.
+            streamed.prefetched = Some(streamed.spawn_load(0, &self.cfg, self.dtype, &self.device));
         } else {
-            for block in &mut self.blocks {
-                x = block.forward(
-                    &x,
-                    attention_mask.as_ref(),
-                    input_positions,
-                    None,
-                    input_metadata,
-                )?;
-            }
+            x = self.forward_blocks(
+                x,
+                attention_mask.as_ref(),
+                input_positions,
+                kv_caches,
+                input_metadata,
+            )?;
         }
         let x = self.ln_f.forward(&x)?;
-        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
-        let logits = self.lm_head.forward(&x)?;
+        let x = last_token_states(&x, input_metadata)?.contiguous()?;
+        let logits = self.lm_head()?.forward(&x)?;
         logits.to_dtype(DType::F32)
     }
 
-    pub fn load(vb: VarBuilder, cfg: &Config, dtype: DType, device: &Device) -> Result<Self> {
-        let wte = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
-        let lm_head = linear(
-            cfg.hidden_size,
-            cfg.vocab_size,
-            vb.pp("lm_head"),
-            &cfg.specific_config.quant,
+    /// Normalized final hidden states of every input token, `(b_size, seq_len, hidden_size)`,
+    /// pooled into embeddings. Runs without the KV cache.
+    pub fn embed(&mut self, x: &Tensor, input_metadata: &mut InputMetadata) -> Result<Tensor> {
+        if self.streamed.is_some() {
+            candle::bail!("Embeddings are not supported with weight streaming");
+        }
+        let (b_sz, seq_len) = x.dims2()?;
+        // Always masked, which also keeps single token inputs on the prefill path
+        let attention_mask = self.prepare_decoder_attention_mask(b_sz, seq_len)?;
+        let input_positions = vec![vec![0]; b_sz];
+        let x = self.wte.forward(x)?;
+        let x = self.forward_blocks(
+            x,
+            Some(&attention_mask),
+            &input_positions,
+            None,
+            input_metadata,
         )?;
+        self.ln_f.forward(&x)
+    }
+
+    /// Logits of every input token, `(b_size, seq_len, vocab_size)`, to score a prompt. Runs
+    /// without the KV cache.
+    pub fn prompt_logits(
+        &mut self,
+        x: &Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let x = self.embed(x, input_metadata)?;
+        self.lm_head()?.forward(&x)?.to_dtype(DType::F32)
+    }
+
+    fn lm_head(&self) -> Result<&Linear> {
+        self.lm_head
+            .as_ref()
+            .ok_or_else(|| candle::Error::Msg("Reward models do not generate.".to_string()))
+    }
+
+    /// Run the resident decoder layers. The activations move to the CPU for the layers offloaded
+    /// to it (see `Config::num_gpu_layers`), and back for the final norm and the head.
+    fn forward_blocks(
+        &mut self,
+        mut x: Tensor,
+        attention_mask: Option<&Tensor>,
+        input_positions: &[Vec<usize>],
+        kv_caches: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let num_gpu_layers = self.cfg.num_gpu_layers();
+        let (gpu_blocks, cpu_blocks) = self.blocks.split_at_mut(num_gpu_layers);
+        for (idx, block) in gpu_blocks.iter_mut().enumerate() {
+            let cache = kv_caches.map(|kv_caches| (&kv_caches[idx].0, &kv_caches[idx].1));
+            x = block.forward(&x, attention_mask, input_positions, cache, input_metadata)?;
+        }
+        if cpu_blocks.is_empty() {
+            return Ok(x);
+        }
+        let cpu = Device::Cpu;
+        let mut x = x.to_device(&cpu)?;
+        let attention_mask = attention_mask
+            .map(|mask| mask.to_device(&cpu))
+            .transpose()?;
+        let mut cpu_metadata = input_metadata.to_device(&cpu)?;
+        for (idx, block) in cpu_blocks.iter_mut().enumerate() {
+            let idx = num_gpu_layers + idx;
+            let cache = kv_caches.map(|kv_caches| (&kv_caches[idx].0, &kv_caches[idx].1));
+            x = block.forward(
+                &x,
+                attention_mask.as_ref(),
+                input_positions,
+                cache,
+                &mut cpu_metadata,
+            )?;
+        }
+        input_metadata.merge_attn_scores(cpu_metadata)?;
+        x.to_device(&self.device)
+    }
+
+    pub fn load(
+        vb: VarBuilder<'static>,
+        cfg: &Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let wte = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
+        let lm_head = if vb.contains_tensor("score.weight") {
+            None
+        } else {
+            Some(linear(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                vb.pp("lm_head"),
+                &cfg.specific_config.quant,
+            )?)
+        };
         let ln_f = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("model.norm"))?;
-        let blocks: Vec<_> = (0..cfg.num_hidden_layers)
-            .map(|i| Block::load(vb.pp(&format!("model.layers.{i}")), cfg, dtype, device).unwrap())
-            .collect();
+        let (blocks, streamed) = if cfg.specific_config.stream_weights {
+            let streamed = StreamedBlocks {
+                vb,
+                prefetched: None,
+            };
+            (Vec::new(), Some(streamed))
+        } else {
+            let blocks: Vec<_> = (0..cfg.num_hidden_layers)
+                .map(|i| {
+                    let device = cfg.layer_device(i, device);
+                    let vb = vb.clone().set_device(device.clone());
+                    Block::load(vb.pp(&format!("model.layers.{i}")), cfg, dtype, &device).unwrap()
+                })
+                .collect();
+            (blocks, None)
+        };
 
         Ok(Self {
             wte,
             blocks,
+            streamed,
             ln_f,
             lm_head,
             cfg: cfg.clone(),
@@ -0,0 +1,461 @@
+use super::{Config, RmsNorm, TokenID};
+use crate::openai::models::linear::{linear_b_x as linear_b, LinearX as Linear};
+use crate::SpecificConfig;
+use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_core as candle;
+use candle_nn::{embedding, Embedding, VarBuilder};
+use either::Either;
+
+/// Recurrent models have no context limit, this bounds the length of the requests.
+pub const MAX_SEQ_LEN: usize = 8192;
+
+/// The `config.json` of transformers' `Mamba2ForCausalLM` (Mamba-Codestral, mamba2-*-hf).
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Mamba2Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    #[serde(default = "default_state_size")]
+    pub state_size: usize,
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_epsilon: f64,
+    #[serde(default = "default_expand")]
+    pub expand: usize,
+    #[serde(default = "default_conv_kernel")]
+    pub conv_kernel: usize,
+    #[serde(default = "default_n_groups")]
+    pub n_groups: usize,
+    #[serde(default = "default_num_heads")]
+    pub num_heads: usize,
+    #[serde(default = "default_head_dim")]
+    pub head_dim: usize,
+    #[serde(default)]
+    pub use_bias: bool,
+    #[serde(default = "default_use_conv_bias")]
+    pub use_conv_bias: bool,
+    /// Bounds of the discretization step, `(0, Infinity)` by default. transformers writes the
+    /// upper bound as `Infinity`, read as none.
+    #[serde(default)]
+    pub time_step_limit: (Option<f64>, Option<f64>),
+    pub bos_token_id: Option<TokenID>,
+    pub eos_token_id: Option<TokenID>,
+}
+
+fn default_state_size() -> usize {
+    128
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-5
+}
+
+fn default_expand() -> usize {
+    2
+}
+
+fn default_conv_kernel() -> usize {
+    4
+}
+
+fn default_n_groups() -> usize {
+    8
+}
+
+fn default_num_heads() -> usize {
+    128
+}
+
+fn default_head_dim() -> usize {
+    64
+}
+
+fn default_use_conv_bias() -> bool {
+    true
+}
+
+impl Mamba2Config {
+    /// Width of the SSM, `num_heads * head_dim`.
+    pub fn intermediate_size(&self) -> usize {
+        self.expand * self.hidden_size
+    }
+
+    /// Channels of the convolution: the SSM input and its `B` and `C` projections.
+    pub fn conv_dim(&self) -> usize {
+        self.intermediate_size() + 2 * self.n_groups * self.state_size
+    }
+
+    pub fn into_config(
+        self,
+        use_flash_attn: bool,
+        kv_cache_dtype: DType,
+        scfg: &SpecificConfig,
+    ) -> Config {
+        Config {
+            hidden_size: self.hidden_size,
+            head_dim: Some(self.head_dim),
+            intermediate_size: self.intermediate_size(),
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_heads,
+            num_key_value_heads: self.num_heads,
+            rms_norm_eps: self.layer_norm_epsilon,
+            rope_theta: 0.,
+            use_flash_attn,
+            bos_token_id: self.bos_token_id.unwrap_or(TokenID(Either::Left(None))),
+            eos_token_id: self.eos_token_id.unwrap_or(TokenID(Either::Left(None))),
+            max_seq_len: MAX_SEQ_LEN,
+            sliding_window: None,
+            hidden_act: None,
+            tie_word_embeddings: false,
+            rope_scaling: None,
+            original_max_position_embeddings: None,
+            attention_bias: false,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: None,
+            custom_stop_tokens: None,
+            specific_config: scfg.clone(),
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
+        }
+    }
+}
+
+/// `log(1 + exp(x))` without overflowing for large `x`.
+fn softplus(xs: &Tensor) -> Result<Tensor> {
+    xs.relu()? + (xs.abs()?.neg()?.exp()? + 1.)?.log()?
+}
+
+/// The Mamba-2 mixer: a depthwise causal convolution followed by the selective state space
+/// model, scanned one token at a time.
+struct Mixer {
+    in_proj: Linear,
+    /// `(conv_dim, conv_kernel)`
+    conv_weight: Tensor,
+    conv_bias: Option<Tensor>,
+    dt_bias: Tensor,
+    /// `-exp(A_log)`, in F32
+    a: Tensor,
+    d: Tensor,
+    norm_weight: Tensor,
+    out_proj: Linear,
+    intermediate_size: usize,
+    conv_dim: usize,
+    conv_kernel: usize,
+    num_heads: usize,
+    head_dim: usize,
+    n_groups: usize,
+    state_size: usize,
+    time_step_limit: (f64, f64),
+    eps: f64,
+}
+
+impl Mixer {
+    fn new(cfg: &Mamba2Config, quant: &Option<String>, vb: VarBuilder) -> Result<Self> {
+        let intermediate_size = cfg.intermediate_size();
+        let conv_dim = cfg.conv_dim();
+        let in_proj = linear_b(
+            cfg.hidden_size,
+            intermediate_size + conv_dim + cfg.num_heads,
+            cfg.use_bias,
+            vb.pp("in_proj"),
+            quant,
+        )?;
+        let conv_weight = vb
+            .get((conv_dim, 1, cfg.conv_kernel), "conv1d.weight")?
+            .reshape((conv_dim, cfg.conv_kernel))?;
+        let conv_bias = if cfg.use_conv_bias {
+            Some(vb.get(conv_dim, "conv1d.bias")?)
+        } else {
+            None
+        };
+        let a = vb
+            .get(cfg.num_heads, "A_log")?
+            .to_dtype(DType::F32)?
+            .exp()?
+            .neg()?;
+        let out_proj = linear_b(
+            intermediate_size,
+            cfg.hidden_size,
+            cfg.use_bias,
+            vb.pp("out_proj"),
+            quant,
+        )?;
+        Ok(Self {
+            in_proj,
+            conv_weight,
+            conv_bias,
+            dt_bias: vb.get(cfg.num_heads, "dt_bias")?.to_dtype(DType::F32)?,
+            a,
+            d: vb.get(cfg.num_heads, "D")?.to_dtype(DType::F32)?,
+            norm_weight: vb
+                .get(intermediate_size, "norm.weight")?
+                .to_dtype(DType::F32)?,
+            out_proj,
+            intermediate_size,
+            conv_dim,
+            conv_kernel: cfg.conv_kernel,
+            num_heads: cfg.num_heads,
+            head_dim: cfg.head_dim,
+            n_groups: cfg.n_groups,
+            state_size: cfg.state_size,
+            time_step_limit: (
+                cfg.time_step_limit.0.unwrap_or(0.),
+                cfg.time_step_limit.1.unwrap_or(f64::INFINITY),
+            ),
+            eps: cfg.layer_norm_epsilon,
+        })
+    }
+
+    /// Run `xs` `(b_size, seq_len, hidden_size)` from the states `conv_state`
+    /// `(b_size, conv_dim, conv_kernel - 1)` and `ssm_state`
+    /// `(b_size, num_heads, head_dim, state_size)`, which are advanced past its tokens.
+    fn forward(
+        &self,
+        xs: &Tensor,
+        conv_state: &mut Tensor,
+        ssm_state: &mut Tensor,
+    ) -> Result<Tensor> {
+        let (b_size, seq_len, _) = xs.dims3()?;
+        let dtype = xs.dtype();
+        let projected = self.in_proj.forward(xs)?;
+        let gate = projected.narrow(D::Minus1, 0, self.intermediate_size)?;
+        let xbc = projected.narrow(D::Minus1, self.intermediate_size, self.conv_dim)?;
+        let dt = projected.narrow(
+            D::Minus1,
+            self.intermediate_size + self.conv_dim,
+            self.num_heads,
+        )?;
+
+        // Depthwise causal convolution, continuing from the last inputs of the previous call
+        let padded = Tensor::cat(&[&*conv_state, &xbc.transpose(1, 2)?], 2)?;
+        *conv_state = padded
+            .narrow(2, seq_len, self.conv_kernel - 1)?
+            .contiguous()?;
+        let mut conv = match &self.conv_bias {
+            Some(bias) => bias
+                .reshape((1, self.conv_dim, 1))?
+                .broadcast_as((b_size, self.conv_dim, seq_len))?
+                .contiguous()?,
+            None => Tensor::zeros((b_size, self.conv_dim, seq_len), dtype, xs.device())?,
+        };
+        for k in 0..self.conv_kernel {
+            let weight = self
+                .conv_weight
+                .narrow(1, k, 1)?
+                .reshape((1, self.conv_dim, 1))?;
+            conv = (conv + padded.narrow(2, k, seq_len)?.broadcast_mul(&weight)?)?;
+        }
+        let xbc = candle_nn::ops::silu(&conv)?
+            .transpose(1, 2)?
+            .to_dtype(DType::F32)?;
+
+        let groups_size = self.n_groups * self.state_size;
+        let heads_per_group = self.num_heads / self.n_groups;
+        let x = xbc.narrow(D::Minus1, 0, self.intermediate_size)?.reshape((
+            b_size,
+            seq_len,
+            self.num_heads,
+            self.head_dim,
+        ))?;
+        // The B and C projections of a group are shared by its heads
+        let per_head = |xs: Tensor| -> Result<Tensor> {
+            xs.reshape((b_size, seq_len, self.n_groups, 1, self.state_size))?
+                .broadcast_as((
+                    b_size,
+                    seq_len,
+                    self.n_groups,
+                    heads_per_group,
+                    self.state_size,
+                ))?
+                .reshape((b_size, seq_len, self.num_heads, self.state_size))
+        };
+        let b = per_head(xbc.narrow(D::Minus1, self.intermediate_size, groups_size)?)?;
+        let c =
+            per_head(xbc.narrow(D::Minus1, self.intermediate_size + groups_size, groups_size)?)?;
+        let dt = softplus(&dt.to_dtype(DType::F32)?.broadcast_add(&self.dt_bias)?)?;
+        let dt = if self.time_step_limit != (0., f64::INFINITY) {
+            dt.clamp(self.time_step_limit.0, self.time_step_limit.1)?
+        } else {
+            dt
+        };
+
+        let d = self.d.reshape((1, self.num_heads, 1))?;
+        let mut ys = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            let dt_t = dt.i((.., t))?;
+            let x_t = x.i((.., t))?;
+            let decay =
+                dt_t.broadcast_mul(&self.a)?
+                    .exp()?
+                    .reshape((b_size, self.num_heads, 1, 1))?;
+            let dx = x_t.broadcast_mul(&dt_t.reshape((b_size, self.num_heads, 1))?)?;
+            let dbx = dx
+                .unsqueeze(3)?
+                .broadcast_mul(&b.i((.., t))?.unsqueeze(2)?)?;
+            *ssm_state = (ssm_state.broadcast_mul(&decay)? + dbx)?;
+            let y = ssm_state
+                .matmul(&c.i((.., t))?.unsqueeze(3)?.contiguous()?)?
+                .squeeze(3)?;
+            ys.push((y + x_t.broadcast_mul(&d)?)?);
+        }
+        let y = Tensor::stack(&ys, 1)?.reshape((b_size, seq_len, self.intermediate_size))?;
+
+        // Gated RMS norm, over the channels of each group
+        let y = (y * candle_nn::ops::silu(&gate.to_dtype(DType::F32)?)?)?;
+        let group_len = self.intermediate_size / self.n_groups;
+        let y = y.reshape((b_size, seq_len, self.n_groups, group_len))?;
+        let variance = y.sqr()?.mean_keepdim(D::Minus1)?;
+        let y = y
+            .broadcast_div(&(variance + self.eps)?.sqrt()?)?
+            .reshape((b_size, seq_len, self.intermediate_size))?
+            .broadcast_mul(&self.norm_weight)?;
+        self.out_proj.forward(&y.to_dtype(dtype)?)
+    }
+}
+
+struct Block {
+    norm: RmsNorm,
+    mixer: Mixer,
+}
+
+impl Block {
+    fn forward(
+        &self,
+        xs: &Tensor,
+        conv_state: &mut Tensor,
+        ssm_state: &mut Tensor,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self
+            .mixer
+            .forward(&self.norm.forward(xs)?, conv_state, ssm_state)?;
+        xs + residual
+    }
+}
+
+/// Mamba-2, a state space model: each sequence keeps a fixed-size state, a convolution state
+/// and an SSM state per layer, in place of a KV cache.
+pub struct Mamba2 {
+    embeddings: Embedding,
+    layers: Vec<Block>,
+    norm_f: RmsNorm,
+    lm_head: Linear,
+    conv_dim: usize,
+    conv_kernel: usize,
+    num_heads: usize,
+    head_dim: usize,
+    state_size: usize,
+    dtype: DType,
+    device: Device,
+    cfg: Config,
+}
+
+impl Mamba2 {
+    pub fn new(
+        vb: VarBuilder,
+        cfg: &Config,
+        mamba_cfg: &Mamba2Config,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let quant = &cfg.specific_config.quant;
+        let vb_b = vb.pp("backbone");
+        let embeddings = embedding(
+            mamba_cfg.vocab_size,
+            mamba_cfg.hidden_size,
+            vb_b.pp("embeddings"),
+        )?;
+        let mut layers = Vec::with_capacity(mamba_cfg.num_hidden_layers);
+        let vb_l = vb_b.pp("layers");
+        for layer_idx in 0..mamba_cfg.num_hidden_layers {
+            let vb_l = vb_l.pp(layer_idx);
+            layers.push(Block {
+                norm: RmsNorm::new(
+                    mamba_cfg.hidden_size,
+                    mamba_cfg.layer_norm_epsilon,
+                    vb_l.pp("norm"),
+                )?,
+                mixer: Mixer::new(mamba_cfg, quant, vb_l.pp("mixer"))?,
+            });
+        }
+        let norm_f = RmsNorm::new(
+            mamba_cfg.hidden_size,
+            mamba_cfg.layer_norm_epsilon,
+            vb_b.pp("norm_f"),
+        )?;
+        // Checkpoints without an output layer tie it to the embeddings
+        let lm_head = if vb.contains_tensor("lm_head.weight") {
+            linear_b(
+                mamba_cfg.hidden_size,
+                mamba_cfg.vocab_size,
+                false,
+                vb.pp("lm_head"),
+                quant,
+            )?
+        } else {
+            Linear::new(embeddings.embeddings().clone(), None, quant)
+        };
+        Ok(Self {
+            embeddings,
+            layers,
+            norm_f,
+            lm_head,
+            conv_dim: mamba_cfg.conv_dim(),
+            conv_kernel: mamba_cfg.conv_kernel,
+            num_heads: mamba_cfg.num_heads,
+            head_dim: mamba_cfg.head_dim,
+            state_size: mamba_cfg.state_size,
+            dtype,
+            device: device.clone(),
+            cfg: cfg.clone(),
+        })
+    }
+
+    /// The state of a new sequence: the convolution state `(1, conv_dim, conv_kernel - 1)` and
+    /// the F32 SSM state `(1, num_heads, head_dim, state_size)` of each layer, zeroed.
+    pub fn initial_state(&self) -> Result<Vec<Tensor>> {
+        let mut state = Vec::with_capacity(2 * self.layers.len());
+        for _ in &self.layers {
+            state.push(Tensor::zeros(
+                (1, self.conv_dim, self.conv_kernel - 1),
+                self.dtype,
+                &self.device,
+            )?);
+            state.push(Tensor::zeros(
+                (1, self.num_heads, self.head_dim, self.state_size),
+                DType::F32,
+                &self.device,
+            )?);
+        }
+        Ok(state)
+    }
+
+    /// Logits `(b_size, vocab_size)` of the last of the tokens `input_ids`
+    /// `(b_size, seq_len)`, advancing `state` (see `initial_state`, batched along the first
+    /// dimension) past them.
+    pub fn forward(&self, input_ids: &Tensor, state: &mut [Tensor]) -> Result<Tensor> {
+        let (_, seq_len) = input_ids.dims2()?;
+        let mut xs = self.embeddings.forward(input_ids)?;
+        for (layer, layer_state) in self.layers.iter().zip(state.chunks_mut(2)) {
+            let [conv_state, ssm_state] = layer_state else {
+                candle::bail!("Expected a convolution and an SSM state per layer")
+            };
+            xs = layer.forward(&xs, conv_state, ssm_state)?;
+        }
+        let xs = xs.i((.., seq_len - 1, ..))?.contiguous()?;
+        self.lm_head
+            .forward(&self.norm_f.forward(&xs)?)?
+            .to_dtype(DType::F32)
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
@@ -1,12 +1,12 @@
-use super::Config;
+use super::{last_token_states, Config, RmsNorm, RopeScaling};
 use crate::openai::models::linear::{linear_no_bias_x as linear_no_bias, LinearX as Linear};
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use crate::SpecificConfig;
-use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_core::{DType, Device, Module, Result, Tensor};
 use candle_nn::{Activation, VarBuilder};
-use candle_transformers::models::with_tracing::RmsNorm;
 use either::Either;
+use std::collections::HashMap;
 use std::iter::zip;
 use std::sync::Arc;
 
@@ -26,6 +26,7 @@ pub struct MistralConfig {
     pub bos_token_id: usize,
     pub eos_token_id: usize,
     pub tie_word_embeddings: Option<bool>,
+    pub rope_scaling: Option<HashMap<String, RopeScaling>>,
 }
 
 impl MistralConfig {
@@ -52,7 +53,7 @@ impl MistralConfig {
             sliding_window: self.sliding_window,
             hidden_act: Some(self.hidden_act),
             tie_word_embeddings: self.tie_word_embeddings.unwrap_or(false),
-            rope_scaling: None,
+            rope_scaling: self.rope_scaling,
             original_max_position_embeddings: None,
             attention_bias: false,
             partial_rotary_factor: None,
@@ -63,6 +64,11 @@ impl MistralConfig {
             specific_config: scfg.clone(),
             attn_logit_softcapping: None,
             final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
         }
     }
 }
@@ -75,24 +81,9 @@ struct RotaryEmbedding {
 
 impl RotaryEmbedding {
     fn new(_dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
-        let rope_theta = cfg.rope_theta as f32;
         let dim = cfg.hidden_size / cfg.num_attention_heads;
-        let max_seq_len = cfg.max_seq_len;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / rope_theta.powf(i as f32 / dim as f32))
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
+        let (sin, cos) = cfg.get_rope_sin_cos(dim, dev)?;
+        Ok(Self { sin, cos })
     }
 
     fn apply_rotary_emb_qkv(
@@ -227,10 +218,11 @@ impl Attention {
                 head_dim,
                 1. / ((head_dim as f32).sqrt()),
                 Some(cfg.num_key_value_heads),
-                None,
+                cfg.sliding_window,
                 vb.device().clone(),
                 None,
-            )?,
+            )?
+            .with_self_extend(cfg),
         })
     }
 
@@ -348,7 +340,8 @@ pub struct Mistral {
     embed_tokens: candle_nn::Embedding,
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
-    lm_head: Linear,
+    /// `None` for reward models, which have a `ScoreHead` instead
+    lm_head: Option<Linear>,
     sliding_window: Option<usize>,
     device: Device,
     dtype: DType,
@@ -368,12 +361,16 @@ impl Mistral {
             layers.push(layer)
         }
         let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
-        let lm_head = linear_no_bias(
-            cfg.hidden_size,
-            cfg.vocab_size,
-            vb.pp("lm_head"),
-            &cfg.specific_config.quant,
-        )?;
+        let lm_head = if vb.contains_tensor("score.weight") {
+            None
+        } else {
+            Some(linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                vb.pp("lm_head"),
+                &cfg.specific_config.quant,
+            )?)
+        };
         Ok(Self {
             embed_tokens,
             layers,
@@ -391,7 +388,7 @@ impl Mistral {
         let mask: Vec<_> = (0..tgt_len)
             .flat_map(|i| {
                 (0..tgt_len).map(move |j| {
-                    if i < j || j + sliding_window < i {
+                    if i < j || j + sliding_window <= i {
                         f32::NEG_INFINITY
                     } else {
                         0.
@@ -440,14 +437,55 @@ impl Mistral {
                 )?
             }
         }
-        let logits = xs
-            .i((.., seq_len - 1, ..))?
+        let logits = last_token_states(&xs, input_metadata)?
             .apply(&self.norm)?
-            .apply(&self.lm_head)?;
+            .apply(self.lm_head()?)?;
 
         logits.to_dtype(DType::F32)
     }
 
+    /// Normalized final hidden states of every input token, `(b_size, seq_len, hidden_size)`,
+    /// pooled into embeddings. Runs without the KV cache.
+    pub fn embed(
+        &mut self,
+        input_ids: &Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        // Always masked, which also keeps single token inputs on the prefill path
+        let attention_mask = self.prepare_decoder_attention_mask(b_size, seq_len)?;
+        let input_positions = vec![vec![0]; b_size];
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(
+                &xs,
+                Some(&attention_mask),
+                &input_positions,
+                None,
+                input_metadata,
+            )?
+        }
+        xs.apply(&self.norm)
+    }
+
+    /// Logits of every input token, `(b_size, seq_len, vocab_size)`, to score a prompt. Runs
+    /// without the KV cache.
+    pub fn prompt_logits(
+        &mut self,
+        input_ids: &Tensor,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor> {
+        self.embed(input_ids, input_metadata)?
+            .apply(self.lm_head()?)?
+            .to_dtype(DType::F32)
+    }
+
+    fn lm_head(&self) -> Result<&Linear> {
+        self.lm_head
+            .as_ref()
+            .ok_or_else(|| candle_core::Error::Msg("Reward models do not generate.".to_string()))
+    }
+
     pub fn get_config(&self) -> &Config {
         &self.cfg
     }
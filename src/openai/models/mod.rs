@@ -1,26 +1,156 @@
+pub mod bert;
+pub mod deepseek_v2;
 pub mod gemma;
+pub mod gemma2;
+pub mod glm4;
 pub mod linear;
 pub mod llama;
+pub mod mamba2;
 pub mod mistral;
+pub mod mixtral;
+pub mod moe;
 pub mod phi2;
 pub mod phi3;
 pub mod qwen2;
+pub mod qwen2_moe;
 pub mod stable_lm;
+pub mod t5;
 pub mod yi;
+use crate::paged_attention::input_metadata::InputMetadata;
 use crate::SpecificConfig;
-use candle_core::DType;
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::Module;
 use either::Either;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct RopeScaling(#[serde(with = "either::serde_untagged")] pub Either<Vec<f64>, String>);
+/// A value of the `rope_scaling` map of config.json: numbers as single element lists, strings
+/// (`type`, `rope_type`) as they are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RopeScaling(pub Either<Vec<f64>, String>);
+
+impl<'de> Deserialize<'de> for RopeScaling {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let values = match value {
+            serde_json::Value::String(value) => return Ok(Self(Either::Right(value))),
+            serde_json::Value::Number(value) => value.as_f64().into_iter().collect(),
+            serde_json::Value::Bool(value) => vec![f64::from(u8::from(value))],
+            serde_json::Value::Array(values) => values
+                .iter()
+                .map(|value| {
+                    value.as_f64().ok_or_else(|| {
+                        serde::de::Error::custom("rope scaling lists must hold numbers")
+                    })
+                })
+                .collect::<std::result::Result<_, _>>()?,
+            // Keys set to null, e.g. `"original_max_position_embeddings": null`
+            _ => vec![],
+        };
+        Ok(Self(Either::Left(values)))
+    }
+}
+
+/// How the rotary embedding of a checkpoint extends its context, from the `type` (or
+/// `rope_type`) of `rope_scaling`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RopeScalingKind {
+    /// Positions divided by the factor
+    Linear,
+    /// NTK-aware: the base is scaled for the extended context (static, as in vLLM)
+    DynamicNtk,
+    /// YaRN: low frequencies interpolated, high frequencies kept, sin/cos scaled by mscale
+    Yarn,
+    /// Llama 3.1: low frequencies divided by the factor, smoothed towards the high ones
+    Llama3,
+}
+
+impl RopeScalingKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "linear" => Some(Self::Linear),
+            "dynamic" => Some(Self::DynamicNtk),
+            "yarn" => Some(Self::Yarn),
+            "llama3" => Some(Self::Llama3),
+            _ => None,
+        }
+    }
+}
+
+/// YaRN attention scaling for a context extended by `scale`.
+pub fn yarn_get_mscale(scale: f64, mscale: f64) -> f64 {
+    if scale <= 1. {
+        1.
+    } else {
+        0.1 * mscale * scale.ln() + 1.
+    }
+}
+
+/// Hidden state `(b_size, hidden_size)` of the last token of each sequence of `xs`
+/// `(b_size, seq_len, hidden_size)`. The prompts of a prefill are padded to the longest one,
+/// the shorter ones end before `seq_len`.
+pub fn last_token_states(xs: &Tensor, input_metadata: &InputMetadata) -> Result<Tensor> {
+    let (b_size, seq_len, _) = xs.dims3()?;
+    let prompt_lens = &input_metadata.prompt_lens;
+    if prompt_lens.len() != b_size || prompt_lens.iter().all(|len| *len == seq_len) {
+        return xs.i((.., seq_len - 1, ..));
+    }
+    let states = prompt_lens
+        .iter()
+        .enumerate()
+        .map(|(i, len)| xs.i((i, len - 1, ..)))
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::stack(&states, 0)
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct TokenID(
     #[serde(with = "either::serde_untagged")] pub Either<Option<u32>, Option<Vec<u32>>>,
 );
 
+impl TokenID {
+    /// The ids, a single one or a list, none when unset.
+    pub fn ids(&self) -> Vec<u32> {
+        match &self.0 {
+            Either::Left(id) => id.iter().copied().collect(),
+            Either::Right(ids) => ids.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Special tokens of the `generation_config.json` of a model. Chat models list there every
+/// token that ends a turn, which `config.json` may lack: Llama 3.1 ends its turns with
+/// `<|eot_id|>` while its config only names `<|end_of_text|>`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct GenerationConfig {
+    pub bos_token_id: Option<TokenID>,
+    pub eos_token_id: Option<TokenID>,
+    pub pad_token_id: Option<TokenID>,
+}
+
+impl Config {
+    /// Take the BOS and EOS tokens of the generation config: its EOS tokens are added to those
+    /// of the model config, its BOS token is used when the model config has none.
+    pub fn merge_generation_config(&mut self, generation: &GenerationConfig) {
+        if let Some(eos) = &generation.eos_token_id {
+            let mut ids = self.eos_token_id.ids();
+            for id in eos.ids() {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+            if !ids.is_empty() {
+                self.eos_token_id = TokenID(Either::Right(Some(ids)));
+            }
+        }
+        if let Some(bos) = &generation.bos_token_id {
+            if self.bos_token_id.ids().is_empty() {
+                self.bos_token_id = bos.clone();
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub hidden_size: usize,
@@ -50,11 +180,446 @@ pub struct Config {
     pub specific_config: SpecificConfig,
     pub attn_logit_softcapping: Option<f64>,
     pub final_logit_softcapping: Option<f64>,
+    pub query_pre_attn_scalar: Option<usize>,
+    pub local_sliding_window: Option<usize>,
+    pub moe_config: Option<MoEConfig>,
+    pub mla_config: Option<MlaConfig>,
+    /// Rotary embedding applied to interleaved pairs of dims (GPT-J style) rather than to the
+    /// two halves of the rotary dims.
+    pub rope_interleaved: bool,
+}
+
+/// Mixture-of-experts settings shared by the sparse models (Mixtral, Qwen2-MoE, DeepSeek-V2).
+#[derive(Debug, Clone)]
+pub struct MoEConfig {
+    pub num_experts: usize,
+    pub num_experts_per_tok: usize,
+    pub moe_intermediate_size: usize,
+    pub shared_expert_intermediate_size: Option<usize>,
+    /// Qwen2-MoE gates its shared expert with a sigmoid, DeepSeek-V2 adds its shared experts
+    /// as they are.
+    pub shared_expert_gated: bool,
+    pub norm_topk_prob: bool,
+    pub decoder_sparse_step: usize,
+    pub mlp_only_layers: Vec<usize>,
+    /// Scale of the routing weights when they are not normalized.
+    pub routed_scaling_factor: f64,
+    /// Group limited routing as (number of expert groups, groups kept per token): the top-k
+    /// experts are only picked within the groups holding the best scoring experts.
+    pub topk_groups: Option<(usize, usize)>,
+}
+
+/// Multi-head latent attention (DeepSeek-V2). Keys and values are compressed into a
+/// `kv_lora_rank` latent shared by all heads plus a `qk_rope_head_dim` rotary key, and only
+/// those are cached, see `KvCacheLayout::Latent`.
+#[derive(Debug, Clone)]
+pub struct MlaConfig {
+    pub q_lora_rank: Option<usize>,
+    pub kv_lora_rank: usize,
+    pub qk_nope_head_dim: usize,
+    pub qk_rope_head_dim: usize,
+    pub v_head_dim: usize,
+}
+
+impl MlaConfig {
+    /// Elements cached per token and layer: the latent and the rotary key.
+    pub fn latent_dim(&self) -> usize {
+        self.kv_lora_rank + self.qk_rope_head_dim
+    }
+}
+
+impl MoEConfig {
+    /// Whether the decoder layer `layer_idx` uses a sparse MoE block instead of a dense MLP.
+    pub fn is_sparse_layer(&self, layer_idx: usize) -> bool {
+        !self.mlp_only_layers.contains(&layer_idx)
+            && self.decoder_sparse_step > 0
+            && (layer_idx + 1) % self.decoder_sparse_step == 0
+    }
+}
+
+/// Self-Extend (LongLM) attention for contexts longer than the model was trained on. A query
+/// attends to the keys of its neighbor `window` at their exact relative positions, and to the
+/// keys beyond it with grouped positions: the query at `q` is placed at
+/// `q / group_size + window - window / group_size` and the key at `k` at `k / group_size`, so
+/// the two branches meet at the edge of the window. A model trained on `n` positions then
+/// addresses about `window + (n - window) * group_size` positions without finetuning.
+/// The rotary tables keep the exact positions, the grouped branch is computed in attention, see
+/// `crate::paged_attention::self_extend`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfExtend {
+    /// At least 1, validated with the command line
+    pub group_size: usize,
+    pub window: usize,
+}
+
+impl SelfExtend {
+    pub fn new(group_size: usize, window: usize) -> Self {
+        Self { group_size, window }
+    }
+
+    /// Whether the key at `key` is in the neighbor window of the query at `query`.
+    pub fn is_neighbor(&self, query: usize, key: usize) -> bool {
+        query < key + self.window
+    }
+
+    /// Position of the query at `pos` in the grouped attention.
+    pub fn grouped_query_position(&self, pos: usize) -> usize {
+        pos / self.group_size + self.window - self.window / self.group_size
+    }
+
+    /// Position of the key at `pos` in the grouped attention.
+    pub fn grouped_key_position(&self, pos: usize) -> usize {
+        pos / self.group_size
+    }
+
+    /// Relative position of the key at `key` seen from the query at `query`, `key <= query`.
+    pub fn relative_position(&self, query: usize, key: usize) -> usize {
+        if self.is_neighbor(query, key) {
+            query - key
+        } else {
+            self.grouped_query_position(query) - self.grouped_key_position(key)
+        }
+    }
+
+    /// Number of positions that can be addressed when the model was trained on `trained_len`.
+    pub fn extended_len(&self, trained_len: usize) -> usize {
+        if trained_len <= self.window {
+            trained_len
+        } else {
+            self.window + (trained_len - self.window) * self.group_size
+        }
+    }
 }
 
 impl Config {
+    /// Decoder layers on the device of the model, the others run on the CPU (see
+    /// `--num-gpu-layers`).
+    pub fn num_gpu_layers(&self) -> usize {
+        self.specific_config
+            .num_gpu_layers
+            .unwrap_or(self.num_hidden_layers)
+            .min(self.num_hidden_layers)
+    }
+
+    /// Device of the decoder layer `layer` and of its KV cache, `device` unless it is offloaded.
+    pub fn layer_device(&self, layer: usize, device: &Device) -> Device {
+        if layer < self.num_gpu_layers() {
+            device.clone()
+        } else {
+            Device::Cpu
+        }
+    }
+
     pub fn get_head_size(&self) -> usize {
         self.head_dim
             .unwrap_or(self.hidden_size / self.num_attention_heads)
     }
+
+    /// Approximate number of parameters, from the shapes of the layers.
+    pub fn approx_num_params(&self) -> usize {
+        let head_size = self.get_head_size();
+        let attention = match &self.mla_config {
+            Some(mla) => {
+                let qk_head_dim = mla.qk_nope_head_dim + mla.qk_rope_head_dim;
+                let q = match mla.q_lora_rank {
+                    Some(rank) => {
+                        rank * (self.hidden_size + self.num_attention_heads * qk_head_dim)
+                    }
+                    None => self.hidden_size * self.num_attention_heads * qk_head_dim,
+                };
+                q + self.hidden_size * mla.latent_dim()
+                    + mla.kv_lora_rank
+                        * self.num_attention_heads
+                        * (mla.qk_nope_head_dim + mla.v_head_dim)
+                    + self.num_attention_heads * mla.v_head_dim * self.hidden_size
+            }
+            None => {
+                self.hidden_size
+                    * head_size
+                    * (2 * self.num_attention_heads + 2 * self.num_key_value_heads)
+            }
+        };
+        let mlp_intermediate = match &self.moe_config {
+            Some(moe) => {
+                moe.moe_intermediate_size * moe.num_experts
+                    + moe.shared_expert_intermediate_size.unwrap_or(0)
+            }
+            None => self.intermediate_size,
+        };
+        let mlp = 3 * self.hidden_size * mlp_intermediate;
+        let embeddings = self.vocab_size * self.hidden_size;
+        let lm_head = if self.tie_word_embeddings {
+            0
+        } else {
+            embeddings
+        };
+        self.num_hidden_layers * (attention + mlp) + embeddings + lm_head
+    }
+
+    /// Number of leading dimensions of each head that are rotary embedded.
+    pub fn get_rotary_dim(&self) -> usize {
+        (self.get_head_size() as f32 * self.partial_rotary_factor.unwrap_or(1.)) as usize
+    }
+
+    /// Cosine and sine (`(1, rotary_dim / 2)`, F32) of the rotation that moves rotary embedded
+    /// keys `delta` positions back, to rebase the positions of cached keys.
+    pub fn get_rope_rebase(&self, delta: usize, dev: &Device) -> Result<(Tensor, Tensor)> {
+        let dim = self.get_rotary_dim();
+        let angles: Vec<f32> = (0..dim)
+            .step_by(2)
+            .map(|i| (-(delta as f64) / self.rope_theta.powf(i as f64 / dim as f64)) as f32)
+            .collect();
+        let angles = Tensor::from_vec(angles, (1, dim / 2), dev)?;
+        Ok((angles.cos()?, angles.sin()?))
+    }
+
+    /// A number of the `rope_scaling` map.
+    pub fn rope_scaling_param(&self, key: &str) -> Option<f64> {
+        match &self.rope_scaling.as_ref()?.get(key)?.0 {
+            Either::Left(values) => values.first().copied(),
+            Either::Right(_) => None,
+        }
+    }
+
+    /// The `type` (or `rope_type`) of the `rope_scaling` map.
+    pub fn rope_scaling_type(&self) -> Option<&str> {
+        let scaling = self.rope_scaling.as_ref()?;
+        ["rope_type", "type"]
+            .iter()
+            .find_map(|key| match &scaling.get(*key)?.0 {
+                Either::Right(name) => Some(name.as_str()),
+                Either::Left(_) => None,
+            })
+    }
+
+    /// The rope scaling applied by `get_rope_inv_freq`, with its factor. `None` without scaling,
+    /// for a factor of 1 or less, and for scalings the model implements itself (Phi-3 LongRoPE).
+    pub fn get_rope_scaling(&self) -> Option<(RopeScalingKind, f64)> {
+        let kind = RopeScalingKind::from_name(self.rope_scaling_type()?)?;
+        let factor = self.rope_scaling_param("factor")?;
+        (factor > 1.).then_some((kind, factor))
+    }
+
+    /// Context length the checkpoint was pretrained with, before its rope scaling.
+    pub fn get_original_max_len(&self) -> usize {
+        self.rope_scaling_param("original_max_position_embeddings")
+            .map(|len| len as usize)
+            .or(self.original_max_position_embeddings)
+            .unwrap_or(self.max_seq_len)
+    }
+
+    /// Number of positions of the rotary tables: `max_position_embeddings`, or the context
+    /// extended by the rope scaling when it is longer (Llama 3.1 configs give the extended
+    /// length, Qwen2.5 YaRN configs the original one).
+    pub fn get_rope_max_len(&self) -> usize {
+        match self.get_rope_scaling() {
+            Some((RopeScalingKind::Llama3, _)) | None => self.max_seq_len,
+            Some((RopeScalingKind::Yarn, factor)) => self
+                .max_seq_len
+                .max((self.get_original_max_len() as f64 * factor) as usize),
+            Some((_, factor)) => (self.max_seq_len as f64 * factor) as usize,
+        }
+    }
+
+    /// Inverse frequencies of `dim` rotary dims with the rope scaling of the checkpoint applied,
+    /// and the factor of the sin/cos tables (YaRN attention scaling, 1 otherwise).
+    pub fn get_rope_inv_freq(&self, dim: usize) -> (Vec<f64>, f64) {
+        let base = self.rope_theta;
+        let inv_freq = (0..dim)
+            .step_by(2)
+            .map(|i| 1. / base.powf(i as f64 / dim as f64));
+        let Some((kind, factor)) = self.get_rope_scaling() else {
+            return (inv_freq.collect(), 1.);
+        };
+        let original_len = self.get_original_max_len() as f64;
+        match kind {
+            RopeScalingKind::Linear => (inv_freq.map(|freq| freq / factor).collect(), 1.),
+            RopeScalingKind::DynamicNtk => {
+                let max_len = self.get_rope_max_len() as f64;
+                let base = base
+                    * (factor * max_len / self.max_seq_len as f64 - (factor - 1.))
+                        .powf(dim as f64 / (dim as f64 - 2.));
+                let inv_freq = (0..dim)
+                    .step_by(2)
+                    .map(|i| 1. / base.powf(i as f64 / dim as f64))
+                    .collect();
+                (inv_freq, 1.)
+            }
+            RopeScalingKind::Yarn => {
+                let correction_dim = |rotations: f64| {
+                    dim as f64 * (original_len / (rotations * 2. * std::f64::consts::PI)).ln()
+                        / (2. * base.ln())
+                };
+                let beta_fast = self.rope_scaling_param("beta_fast").unwrap_or(32.);
+                let beta_slow = self.rope_scaling_param("beta_slow").unwrap_or(1.);
+                let low = correction_dim(beta_fast).floor().max(0.);
+                let high = correction_dim(beta_slow).ceil().min(dim as f64 - 1.);
+                let high = if low == high { high + 0.001 } else { high };
+                // High frequencies are kept, low frequencies interpolated by the factor
+                let inv_freq = inv_freq
+                    .enumerate()
+                    .map(|(i, freq)| {
+                        let extra = 1. - ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                        freq / factor * (1. - extra) + freq * extra
+                    })
+                    .collect();
+                let mscale = self
+                    .rope_scaling_param("attention_factor")
+                    .unwrap_or_else(|| {
+                        let mscale = self.rope_scaling_param("mscale").unwrap_or(1.);
+                        let mscale_all_dim =
+                            self.rope_scaling_param("mscale_all_dim").unwrap_or(0.);
+                        yarn_get_mscale(factor, mscale) / yarn_get_mscale(factor, mscale_all_dim)
+                    });
+                (inv_freq, mscale)
+            }
+            RopeScalingKind::Llama3 => {
+                let low_freq_factor = self.rope_scaling_param("low_freq_factor").unwrap_or(1.);
+                let high_freq_factor = self.rope_scaling_param("high_freq_factor").unwrap_or(4.);
+                let low_freq_wavelen = original_len / low_freq_factor;
+                let high_freq_wavelen = original_len / high_freq_factor;
+                let inv_freq = inv_freq
+                    .map(|freq| {
+                        let wavelen = 2. * std::f64::consts::PI / freq;
+                        if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / factor
+                        } else {
+                            let smooth = (original_len / wavelen - low_freq_factor)
+                                / (high_freq_factor - low_freq_factor);
+                            (1. - smooth) * freq / factor + smooth * freq
+                        }
+                    })
+                    .collect();
+                (inv_freq, 1.)
+            }
+        }
+    }
+
+    /// Sine and cosine tables (`(max_len, dim / 2)`, F32) of `dim` rotary dims at every position,
+    /// with the rope scaling of the checkpoint applied.
+    pub fn get_rope_sin_cos(&self, dim: usize, dev: &Device) -> Result<(Tensor, Tensor)> {
+        let (inv_freq, mscale) = self.get_rope_inv_freq(dim);
+        let inv_freq = inv_freq.iter().map(|&freq| freq as f32).collect::<Vec<_>>();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, dim / 2), dev)?;
+        let freqs = self.get_rope_positions(dev)?.matmul(&inv_freq)?;
+        if mscale == 1. {
+            Ok((freqs.sin()?, freqs.cos()?))
+        } else {
+            Ok(((freqs.sin()? * mscale)?, (freqs.cos()? * mscale)?))
+        }
+    }
+
+    /// Maximum number of token positions served, including the rope scaling and self-extend
+    /// ranges.
+    pub fn get_max_model_len(&self) -> usize {
+        let max_len = self.get_rope_max_len();
+        match &self.specific_config.self_extend {
+            Some(self_extend) => self_extend.extended_len(max_len),
+            None => max_len,
+        }
+    }
+
+    /// Positions (as a `(len, 1)` F32 column) used to build the rotary sin/cos tables.
+    pub fn get_rope_positions(&self, dev: &Device) -> Result<Tensor> {
+        let max_len = self.get_max_model_len();
+        Tensor::arange(0u32, max_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_len, 1))
+    }
+}
+
+/// RMS norm computed in the dtype of its weight, which a dtype override may keep in f32 while the
+/// model runs in bf16 or f16. Returns the dtype of its input.
+#[derive(Debug, Clone)]
+pub struct RmsNorm {
+    inner: candle_nn::RmsNorm,
+    dtype: DType,
+}
+
+impl RmsNorm {
+    pub fn new(size: usize, eps: f64, vb: candle_nn::VarBuilder) -> Result<Self> {
+        let weight = vb.get(size, "weight")?;
+        let dtype = weight.dtype();
+        Ok(Self {
+            inner: candle_nn::RmsNorm::new(weight, eps),
+            dtype,
+        })
+    }
+}
+
+impl Module for RmsNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        if xs.dtype() == self.dtype {
+            self.inner.forward(xs)
+        } else {
+            self.inner
+                .forward(&xs.to_dtype(self.dtype)?)?
+                .to_dtype(xs.dtype())
+        }
+    }
+}
+
+/// The labels of `*ForSequenceClassification` checkpoints, read from their `config.json`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SequenceClassificationConfig {
+    #[serde(default)]
+    pub id2label: HashMap<String, String>,
+    pub num_labels: Option<usize>,
+}
+
+impl SequenceClassificationConfig {
+    /// Labels of the score head in class order, `LABEL_<i>` when unnamed. A single score
+    /// (reward models) without either field.
+    pub fn labels(&self) -> Vec<String> {
+        let num_labels = match self.id2label.len() {
+            0 => self.num_labels.unwrap_or(1),
+            len => len,
+        };
+        (0..num_labels)
+            .map(|i| {
+                self.id2label
+                    .get(&i.to_string())
+                    .cloned()
+                    .unwrap_or_else(|| format!("LABEL_{i}"))
+            })
+            .collect()
+    }
+}
+
+/// The `score` head of `*ForSequenceClassification` checkpoints (reward models such as
+/// Skywork-Reward), which rates a whole input from the final hidden state of its last token.
+/// These checkpoints have no language model head, they do not generate.
+#[derive(Debug, Clone)]
+pub struct ScoreHead {
+    score: candle_nn::Linear,
+    labels: Vec<String>,
+}
+
+impl ScoreHead {
+    /// The head of the checkpoint, `None` for language models.
+    pub fn load(
+        vb: &candle_nn::VarBuilder,
+        hidden_size: usize,
+        labels: Vec<String>,
+    ) -> Result<Option<Self>> {
+        if !vb.contains_tensor("score.weight") {
+            return Ok(None);
+        }
+        let score = candle_nn::linear_no_bias(hidden_size, labels.len(), vb.pp("score"))?;
+        Ok(Some(Self { score, labels }))
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Scores `(b_size, num_labels)` in f32 of the final hidden states
+    /// `(b_size, seq_len, hidden_size)` of unpadded inputs.
+    pub fn forward(&self, hidden: &Tensor) -> Result<Tensor> {
+        let (_, seq_len, _) = hidden.dims3()?;
+        let last = hidden.narrow(1, seq_len - 1, 1)?.squeeze(1)?;
+        self.score.forward(&last)?.to_dtype(DType::F32)
+    }
 }
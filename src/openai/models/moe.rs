@@ -0,0 +1,199 @@
+use super::{Config, MoEConfig};
+use crate::openai::models::linear::{linear_no_bias_x as linear_no_bias, LinearX as Linear};
+use candle_core::{DType, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+
+/// Weight names of the projections of an expert: (gate, up, down).
+pub type ExpertNames = (&'static str, &'static str, &'static str);
+
+pub const MIXTRAL_EXPERT_NAMES: ExpertNames = ("w1", "w3", "w2");
+pub const QWEN2_MOE_EXPERT_NAMES: ExpertNames = ("gate_proj", "up_proj", "down_proj");
+pub const DEEPSEEK_V2_EXPERT_NAMES: ExpertNames = ("gate_proj", "up_proj", "down_proj");
+
+#[derive(Debug, Clone)]
+struct Expert {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl Expert {
+    fn new(
+        cfg: &Config,
+        intermediate_sz: usize,
+        names: ExpertNames,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let (gate_name, up_name, down_name) = names;
+        let gate_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp(gate_name),
+            &cfg.specific_config.quant,
+        )?;
+        let up_proj = linear_no_bias(
+            hidden_sz,
+            intermediate_sz,
+            vb.pp(up_name),
+            &cfg.specific_config.quant,
+        )?;
+        let down_proj = linear_no_bias(
+            intermediate_sz,
+            hidden_sz,
+            vb.pp(down_name),
+            &cfg.specific_config.quant,
+        )?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act.unwrap_or(Activation::Silu),
+        })
+    }
+}
+
+impl Module for Expert {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+/// Sparse mixture-of-experts block: a router selects the top-k experts for each token and
+/// the expert outputs are combined with the routing weights. Qwen2-MoE additionally adds a
+/// sigmoid-gated shared expert that every token goes through, DeepSeek-V2 ungated ones.
+pub struct SparseMoeBlock {
+    gate: Linear,
+    experts: Vec<Expert>,
+    shared_expert: Option<(Expert, Option<Linear>)>,
+    num_experts_per_tok: usize,
+    norm_topk_prob: bool,
+    routed_scaling_factor: f32,
+    topk_groups: Option<(usize, usize)>,
+}
+
+impl SparseMoeBlock {
+    pub fn new(cfg: &Config, names: ExpertNames, vb: VarBuilder) -> Result<Self> {
+        let moe_cfg: &MoEConfig = cfg
+            .moe_config
+            .as_ref()
+            .ok_or_else(|| candle_core::Error::Msg("missing moe config".to_string()))?;
+        let gate = linear_no_bias(
+            cfg.hidden_size,
+            moe_cfg.num_experts,
+            vb.pp("gate"),
+            &cfg.specific_config.quant,
+        )?;
+        let vb_e = vb.pp("experts");
+        let mut experts = Vec::with_capacity(moe_cfg.num_experts);
+        for idx in 0..moe_cfg.num_experts {
+            experts.push(Expert::new(
+                cfg,
+                moe_cfg.moe_intermediate_size,
+                names,
+                vb_e.pp(idx),
+            )?);
+        }
+        let shared_expert = match moe_cfg.shared_expert_intermediate_size {
+            Some(intermediate_sz) if moe_cfg.shared_expert_gated => Some((
+                Expert::new(cfg, intermediate_sz, names, vb.pp("shared_expert"))?,
+                Some(linear_no_bias(
+                    cfg.hidden_size,
+                    1,
+                    vb.pp("shared_expert_gate"),
+                    &cfg.specific_config.quant,
+                )?),
+            )),
+            Some(intermediate_sz) => Some((
+                Expert::new(cfg, intermediate_sz, names, vb.pp("shared_experts"))?,
+                None,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            gate,
+            experts,
+            shared_expert,
+            num_experts_per_tok: moe_cfg.num_experts_per_tok,
+            norm_topk_prob: moe_cfg.norm_topk_prob,
+            routed_scaling_factor: moe_cfg.routed_scaling_factor as f32,
+            topk_groups: moe_cfg.topk_groups,
+        })
+    }
+}
+
+impl Module for SparseMoeBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+        let xs = xs.reshape(((), hidden_dim))?;
+        let router_logits = xs.apply(&self.gate)?;
+        let routing_weights =
+            candle_nn::ops::softmax_last_dim(&router_logits.to_dtype(DType::F32)?)?;
+
+        // Routing is decided on the host, the number of tokens per step is small.
+        let routing_weights = routing_weights.to_vec2::<f32>()?;
+        let mut top_x = vec![vec![]; self.experts.len()];
+        let mut selected_rws = vec![vec![]; self.experts.len()];
+        for (row_idx, rw) in routing_weights.iter().enumerate() {
+            let mut dst = (0..rw.len()).collect::<Vec<_>>();
+            if let Some((num_groups, topk_group)) = self.topk_groups {
+                // Keep the experts of the groups with the best scoring experts
+                let group_size = rw.len() / num_groups;
+                let group_score = |g: usize| {
+                    rw[g * group_size..(g + 1) * group_size]
+                        .iter()
+                        .copied()
+                        .fold(f32::NEG_INFINITY, f32::max)
+                };
+                let mut groups = (0..num_groups).collect::<Vec<_>>();
+                groups.sort_by(|&i, &j| group_score(j).total_cmp(&group_score(i)));
+                groups.truncate(topk_group);
+                dst.retain(|idx| groups.contains(&(idx / group_size)));
+            }
+            dst.sort_by(|&i, &j| rw[j].total_cmp(&rw[i]));
+            let top_k = &dst[..self.num_experts_per_tok];
+            let norm = if self.norm_topk_prob {
+                top_k.iter().map(|&idx| rw[idx]).sum::<f32>()
+            } else {
+                1.0 / self.routed_scaling_factor
+            };
+            for &expert_idx in top_k {
+                top_x[expert_idx].push(row_idx as u32);
+                selected_rws[expert_idx].push(rw[expert_idx] / norm);
+            }
+        }
+
+        let mut ys = xs.zeros_like()?;
+        for (expert_idx, expert) in self.experts.iter().enumerate() {
+            let top_x = &top_x[expert_idx];
+            if top_x.is_empty() {
+                continue;
+            }
+            let top_x = Tensor::new(top_x.as_slice(), xs.device())?;
+            let selected_rws = Tensor::new(selected_rws[expert_idx].as_slice(), xs.device())?
+                .reshape(((), 1))?
+                .to_dtype(xs.dtype())?;
+            let current_state = xs.index_select(&top_x, 0)?;
+            let current_hidden_states = expert
+                .forward(&current_state)?
+                .broadcast_mul(&selected_rws)?;
+            ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
+        }
+
+        if let Some((shared_expert, shared_expert_gate)) = &self.shared_expert {
+            let shared_out = shared_expert.forward(&xs)?;
+            let shared_out = match shared_expert_gate {
+                Some(gate) => {
+                    let shared_gate = candle_nn::ops::sigmoid(&xs.apply(gate)?)?;
+                    shared_out.broadcast_mul(&shared_gate)?
+                }
+                None => shared_out,
+            };
+            ys = (ys + shared_out)?;
+        }
+        ys.reshape((b_size, seq_len, hidden_dim))
+    }
+}
@@ -1,5 +1,5 @@
-use super::Config;
-use crate::openai::models::linear::{linear_no_bias_x as linear, LinearX as Linear};
+use super::{last_token_states, Config, RopeScaling};
+use crate::openai::models::linear::{linear_x as linear, LinearX as Linear};
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use crate::SpecificConfig;
@@ -9,6 +9,7 @@ use candle_transformers::models::with_tracing::{layer_norm, Embedding, LayerNorm
 
 use either::Either;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::iter::zip;
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -30,6 +31,7 @@ pub struct Phi2Config {
     pub eos_token_id: Option<u32>,
     pub sliding_window: Option<usize>,
     pub original_max_position_embeddings: Option<usize>,
+    pub rope_scaling: Option<HashMap<String, RopeScaling>>,
 }
 
 impl Phi2Config {
@@ -55,7 +57,7 @@ impl Phi2Config {
             sliding_window: self.sliding_window,
             hidden_act: Some(self.hidden_act),
             tie_word_embeddings: false,
-            rope_scaling: None,
+            rope_scaling: self.rope_scaling,
             use_flash_attn,
             original_max_position_embeddings: self.original_max_position_embeddings,
             attention_bias: false,
@@ -67,6 +69,11 @@ impl Phi2Config {
             specific_config: scfg.clone(),
             attn_logit_softcapping: None,
             final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
         }
     }
 }
@@ -82,21 +89,8 @@ impl RotaryEmbedding {
     fn new(cfg: &Config, _dtype: DType, dev: &Device) -> Result<Self> {
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
         let dim = (cfg.partial_rotary_factor.unwrap() * head_dim as f32) as usize;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| (1f64 / cfg.rope_theta.powf(i as f64 / dim as f64)) as f32)
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let t = Tensor::arange(0u32, cfg.max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((cfg.max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        Ok(Self {
-            dim,
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
+        let (sin, cos) = cfg.get_rope_sin_cos(dim, dev)?;
+        Ok(Self { dim, sin, cos })
     }
 
     fn apply_rotary_emb(&self, xs: &Tensor, input_positions: &[Vec<usize>]) -> Result<Tensor> {
@@ -226,7 +220,8 @@ impl Attention {
                 None,
                 vb.device().clone(),
                 None,
-            )?,
+            )?
+            .with_self_extend(cfg),
         })
     }
 
@@ -426,9 +421,7 @@ impl Phi2 {
                 )?
             }
         }
-        xs.apply(&self.final_layernorm)?
-            .i((.., seq_len - 1, ..))?
-            .apply(&self.lm_head)
+        last_token_states(&xs.apply(&self.final_layernorm)?, input_metadata)?.apply(&self.lm_head)
     }
 
     pub fn get_config(&self) -> &Config {
@@ -1,14 +1,13 @@
 // This implementation is based on:
 // https://huggingface.co/microsoft/Phi-3-mini-4k-instruct/blob/main/modeling_phi3.py
-use super::{Config, RopeScaling};
+use super::{last_token_states, Config, RmsNorm, RopeScaling};
 use crate::openai::models::linear::{linear_no_bias_x as linear, LinearX as Linear};
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use crate::SpecificConfig;
-use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle::{DType, Device, Module, Result, Tensor, D};
 use candle_core as candle;
 use candle_nn::VarBuilder;
-use candle_transformers::models::with_tracing::RmsNorm;
 use either::Either;
 use std::collections::HashMap;
 use std::iter::zip;
@@ -68,6 +67,11 @@ impl PhiConfig {
             specific_config: scfg.clone(),
             attn_logit_softcapping: None,
             final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
         }
     }
 }
@@ -84,19 +88,14 @@ struct RotaryEmbedding {
 impl RotaryEmbedding {
     fn new(_dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
         let dim = cfg.hidden_size / cfg.num_attention_heads;
-        let max_seq_len = cfg.max_seq_len;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-
-        if let Some(rope_scaling) = &cfg.rope_scaling {
+        let t = cfg.get_rope_positions(dev)?;
+
+        // LongRoPE (`su`) factors per dim, other scalings are applied by the config
+        let long_rope = cfg
+            .rope_scaling
+            .as_ref()
+            .filter(|rope_scaling| rope_scaling.contains_key("long_factor"));
+        if let Some(rope_scaling) = long_rope {
             match (
                 &rope_scaling["short_factor"],
                 &rope_scaling["long_factor"],
@@ -140,10 +139,6 @@ impl RotaryEmbedding {
                         .collect::<Vec<_>>();
                     let inv_freq_len = inv_freq_long.len();
 
-                    let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-                        .to_dtype(DType::F32)?
-                        .reshape((max_seq_len, 1))?;
-
                     // Calculate sin,cos for long
                     let inv_freq_long = Tensor::from_vec(inv_freq_long, (1, inv_freq_len), dev)?
                         .to_dtype(DType::F32)?;
@@ -172,9 +167,10 @@ impl RotaryEmbedding {
             }
         }
 
+        let (sin, cos) = cfg.get_rope_sin_cos(dim, dev)?;
         Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
+            sin,
+            cos,
             sin_long: None,
             cos_long: None,
             original_max_position_embeddings: None,
@@ -270,10 +266,11 @@ impl Attention {
                 head_dim,
                 1. / ((head_dim as f32).sqrt()),
                 Some(cfg.num_key_value_heads),
-                None,
+                cfg.sliding_window,
                 vb.device().clone(),
                 None,
-            )?,
+            )?
+            .with_self_extend(cfg),
         })
     }
 
@@ -479,8 +476,17 @@ impl Phi {
     }
 
     fn prepare_decoder_attention_mask(&self, b_size: usize, tgt_len: usize) -> Result<Tensor> {
+        let sliding_window = self.cfg.sliding_window.unwrap_or(tgt_len + 1);
         let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .flat_map(|i| {
+                (0..tgt_len).map(move |j| {
+                    if i < j || j + sliding_window <= i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
             .collect();
         let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
         mask.expand((b_size, 1, tgt_len, tgt_len))?
@@ -524,7 +530,7 @@ impl Phi {
                 )?
             }
         }
-        xs.i((.., seq_len - 1, ..))?
+        last_token_states(&xs, input_metadata)?
             .apply(&self.norm)?
             .apply(&self.lm_head)?
             .to_dtype(DType::F32)
@@ -1,13 +1,14 @@
-use super::Config;
+use super::{last_token_states, Config, RopeScaling};
 use crate::openai::models::linear::{
     linear_no_bias_x as linear_no_bias, linear_x as linear, LinearX as Linear,
 };
 use crate::paged_attention::input_metadata::InputMetadata;
 use crate::paged_attention::PagedAttention;
 use crate::SpecificConfig;
-use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_core::{DType, Device, Module, Result, Tensor, D};
 use candle_nn::{Activation, LayerNorm, VarBuilder};
 use either::Either;
+use std::collections::HashMap;
 use std::iter::zip;
 use std::sync::Arc;
 
@@ -31,6 +32,7 @@ pub struct StableLMConfig {
     pub bos_token_id: usize,
     pub eos_token_id: usize,
     pub sliding_window: Option<usize>,
+    pub rope_scaling: Option<HashMap<String, RopeScaling>>,
 }
 
 impl StableLMConfig {
@@ -57,7 +59,7 @@ impl StableLMConfig {
             sliding_window: self.sliding_window,
             hidden_act: Some(self.hidden_act),
             tie_word_embeddings: self.tie_word_embeddings.unwrap_or(false),
-            rope_scaling: None,
+            rope_scaling: self.rope_scaling,
             original_max_position_embeddings: None,
             attention_bias: false,
             partial_rotary_factor: Some(
@@ -71,6 +73,11 @@ impl StableLMConfig {
             specific_config: scfg.clone(),
             attn_logit_softcapping: None,
             final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
         }
     }
 }
@@ -86,22 +93,8 @@ impl RotaryEmbedding {
     pub(crate) fn new(_dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
         let head_dim = cfg.hidden_size / cfg.num_attention_heads;
         let dim = (cfg.partial_rotary_factor.unwrap() * head_dim as f32) as usize;
-        let max_seq_len = cfg.max_seq_len;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(DType::F32)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-            dim,
-        })
+        let (sin, cos) = cfg.get_rope_sin_cos(dim, dev)?;
+        Ok(Self { sin, cos, dim })
     }
 
     fn apply_rotary_emb(&self, xs: &Tensor, input_positions: &[Vec<usize>]) -> Result<Tensor> {
@@ -242,7 +235,8 @@ impl Attention {
                 None,
                 vb.device().clone(),
                 None,
-            )?,
+            )?
+            .with_self_extend(cfg),
         })
     }
 
@@ -444,7 +438,7 @@ impl StableLM {
                 )?
             }
         }
-        xs.i((.., seq_len - 1, ..))?
+        last_token_states(&xs, input_metadata)?
             .apply(&self.norm)?
             .apply(&self.lm_head)?
             .to_dtype(DType::F32)
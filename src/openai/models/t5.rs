@@ -0,0 +1,583 @@
+use super::{Config, RmsNorm, TokenID};
+use crate::openai::models::linear::{linear_no_bias_x as linear, LinearX as Linear};
+use crate::scheduler::cache_engine::{EncoderDecoderKv, KVCache};
+use crate::SpecificConfig;
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_core as candle;
+use candle_nn::{embedding, Activation, Embedding, VarBuilder};
+use either::Either;
+
+/// The `config.json` of transformers' `T5ForConditionalGeneration` (T5, FLAN-T5, MADLAD-400).
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct T5Config {
+    pub vocab_size: usize,
+    pub d_model: usize,
+    pub d_kv: usize,
+    pub d_ff: usize,
+    pub num_layers: usize,
+    /// The decoder has as many layers as the encoder by default
+    pub num_decoder_layers: Option<usize>,
+    pub num_heads: usize,
+    #[serde(default = "default_relative_attention_num_buckets")]
+    pub relative_attention_num_buckets: usize,
+    #[serde(default = "default_relative_attention_max_distance")]
+    pub relative_attention_max_distance: usize,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_epsilon: f64,
+    /// `relu`, or `gated-gelu` (T5 v1.1, FLAN-T5, MADLAD-400)
+    #[serde(default = "default_feed_forward_proj")]
+    pub feed_forward_proj: String,
+    #[serde(default = "default_tie_word_embeddings")]
+    pub tie_word_embeddings: bool,
+    /// The padding token by default
+    pub decoder_start_token_id: Option<u32>,
+    pub pad_token_id: Option<u32>,
+    pub eos_token_id: Option<TokenID>,
+    /// T5 has relative positions, this bounds the length of the requests.
+    pub n_positions: Option<usize>,
+}
+
+fn default_relative_attention_num_buckets() -> usize {
+    32
+}
+
+fn default_relative_attention_max_distance() -> usize {
+    128
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-6
+}
+
+fn default_feed_forward_proj() -> String {
+    "relu".to_string()
+}
+
+fn default_tie_word_embeddings() -> bool {
+    true
+}
+
+impl T5Config {
+    pub fn num_decoder_layers(&self) -> usize {
+        self.num_decoder_layers.unwrap_or(self.num_layers)
+    }
+
+    pub fn decoder_start_token_id(&self) -> u32 {
+        self.decoder_start_token_id
+            .or(self.pad_token_id)
+            .unwrap_or(0)
+    }
+
+    /// The activation of the feed-forward layers, and whether they are gated.
+    fn activation(&self) -> Result<(Activation, bool)> {
+        let (gated, act) = match self.feed_forward_proj.split_once('-') {
+            Some(("gated", act)) => (true, act),
+            _ => (false, self.feed_forward_proj.as_str()),
+        };
+        let act = match (act, gated) {
+            ("relu", _) => Activation::Relu,
+            // transformers maps `gated-gelu` to the tanh approximation
+            ("gelu", true) | ("gelu_new", _) => Activation::NewGelu,
+            ("gelu", false) => Activation::Gelu,
+            ("silu", _) => Activation::Silu,
+            _ => candle::bail!("Unsupported feed_forward_proj {}", self.feed_forward_proj),
+        };
+        Ok((act, gated))
+    }
+
+    pub fn into_config(
+        self,
+        use_flash_attn: bool,
+        kv_cache_dtype: DType,
+        scfg: &SpecificConfig,
+    ) -> Config {
+        Config {
+            hidden_size: self.d_model,
+            head_dim: Some(self.d_kv),
+            intermediate_size: self.d_ff,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_decoder_layers(),
+            num_attention_heads: self.num_heads,
+            num_key_value_heads: self.num_heads,
+            rms_norm_eps: self.layer_norm_epsilon,
+            rope_theta: 0.,
+            use_flash_attn,
+            bos_token_id: TokenID(Either::Left(None)),
+            eos_token_id: self.eos_token_id.unwrap_or(TokenID(Either::Left(Some(1)))),
+            max_seq_len: self.n_positions.unwrap_or(512),
+            sliding_window: None,
+            hidden_act: None,
+            tie_word_embeddings: self.tie_word_embeddings,
+            rope_scaling: None,
+            original_max_position_embeddings: None,
+            attention_bias: false,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype,
+            use_qkv_bias: None,
+            custom_stop_tokens: None,
+            specific_config: scfg.clone(),
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
+        }
+    }
+}
+
+/// The bucket of a key `relative_position` tokens after its query (`_relative_position_bucket`
+/// of transformers): one bucket per distance up to half the buckets, then logarithmically
+/// larger ones up to `max_distance`. Bidirectional attention splits the buckets between the
+/// keys before and after the query, the decoder only sees the keys before.
+fn relative_position_bucket(
+    relative_position: i64,
+    bidirectional: bool,
+    num_buckets: usize,
+    max_distance: usize,
+) -> u32 {
+    let mut num_buckets = num_buckets as i64;
+    let mut bucket = 0;
+    let distance = if bidirectional {
+        num_buckets /= 2;
+        if relative_position > 0 {
+            bucket += num_buckets;
+        }
+        relative_position.abs()
+    } else {
+        -relative_position.min(0)
+    };
+    let max_exact = num_buckets / 2;
+    bucket += if distance < max_exact {
+        distance
+    } else {
+        let log_ratio = (distance as f64 / max_exact as f64).ln()
+            / (max_distance as f64 / max_exact as f64).ln();
+        (max_exact + (log_ratio * (num_buckets - max_exact) as f64) as i64).min(num_buckets - 1)
+    };
+    bucket as u32
+}
+
+/// A mask of `(b_size, 1, 1, max_len)` hiding the keys past the length of each sequence.
+fn length_mask(lens: &[usize], max_len: usize, device: &Device) -> Result<Tensor> {
+    let mask = lens
+        .iter()
+        .flat_map(|&len| (0..max_len).map(move |j| if j < len { 0. } else { f32::NEG_INFINITY }))
+        .collect::<Vec<_>>();
+    Tensor::from_vec(mask, (lens.len(), 1, 1, max_len), device)
+}
+
+/// The keys or values of each sequence `(1, num_heads, len, head_dim)`, zero-padded to
+/// `max_len` and batched.
+fn pad_batch(kv: &[&Tensor], max_len: usize) -> Result<Tensor> {
+    let padded = kv
+        .iter()
+        .map(|kv| {
+            let len = kv.dim(2)?;
+            kv.pad_with_zeros(2, 0, max_len - len)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::cat(&padded, 0)
+}
+
+/// T5 attention: unscaled dot products, and in the first layer of each stack a learned bias
+/// by relative position, shared with the other layers.
+struct Attention {
+    q: Linear,
+    k: Linear,
+    v: Linear,
+    o: Linear,
+    /// `(num_buckets, num_heads)`
+    relative_attention_bias: Option<Embedding>,
+    num_heads: usize,
+    d_kv: usize,
+}
+
+impl Attention {
+    fn new(cfg: &T5Config, has_bias: bool, quant: &Option<String>, vb: VarBuilder) -> Result<Self> {
+        let inner_dim = cfg.num_heads * cfg.d_kv;
+        let relative_attention_bias = if has_bias {
+            Some(embedding(
+                cfg.relative_attention_num_buckets,
+                cfg.num_heads,
+                vb.pp("relative_attention_bias"),
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            q: linear(cfg.d_model, inner_dim, vb.pp("q"), quant)?,
+            k: linear(cfg.d_model, inner_dim, vb.pp("k"), quant)?,
+            v: linear(cfg.d_model, inner_dim, vb.pp("v"), quant)?,
+            o: linear(inner_dim, cfg.d_model, vb.pp("o"), quant)?,
+            relative_attention_bias,
+            num_heads: cfg.num_heads,
+            d_kv: cfg.d_kv,
+        })
+    }
+
+    /// The F32 position bias `(b_size, num_heads, q_len, k_len)` of the relative position
+    /// `buckets` `(b_size, q_len, k_len)`.
+    fn position_bias(&self, buckets: &Tensor) -> Result<Tensor> {
+        let Some(bias) = &self.relative_attention_bias else {
+            candle::bail!("Only the first layer has a relative attention bias")
+        };
+        bias.forward(buckets)?
+            .permute((0, 3, 1, 2))?
+            .to_dtype(DType::F32)
+    }
+
+    /// `(b_size, len, d_model)` to `(b_size, num_heads, len, d_kv)`
+    fn split_heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, len, _) = xs.dims3()?;
+        xs.reshape((b_size, len, self.num_heads, self.d_kv))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    /// Keys and values of `xs` `(b_size, len, d_model)`.
+    fn key_value(&self, xs: &Tensor) -> Result<KVCache> {
+        Ok((
+            self.split_heads(&self.k.forward(xs)?)?,
+            self.split_heads(&self.v.forward(xs)?)?,
+        ))
+    }
+
+    /// Attend from `xs` `(b_size, q_len, d_model)` to `key` and `value`
+    /// `(b_size, num_heads, k_len, d_kv)`, with the additive F32 `bias` broadcast to
+    /// `(b_size, num_heads, q_len, k_len)`.
+    fn attend(&self, xs: &Tensor, key: &Tensor, value: &Tensor, bias: &Tensor) -> Result<Tensor> {
+        let (b_size, q_len, _) = xs.dims3()?;
+        let query = self.split_heads(&self.q.forward(xs)?)?;
+        let scores = query
+            .matmul(&key.t()?.contiguous()?)?
+            .to_dtype(DType::F32)?
+            .broadcast_add(bias)?;
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?.to_dtype(value.dtype())?;
+        let ys = probs.matmul(value)?.transpose(1, 2)?.reshape((
+            b_size,
+            q_len,
+            self.num_heads * self.d_kv,
+        ))?;
+        self.o.forward(&ys)
+    }
+}
+
+struct FeedForward {
+    /// `wi`, or `wi_0` when gated
+    wi: Linear,
+    /// `wi_1`, multiplying the activation when gated
+    gate: Option<Linear>,
+    wo: Linear,
+    act: Activation,
+}
+
+impl FeedForward {
+    fn new(cfg: &T5Config, quant: &Option<String>, vb: VarBuilder) -> Result<Self> {
+        let (act, gated) = cfg.activation()?;
+        let (wi, gate) = if gated {
+            (
+                linear(cfg.d_model, cfg.d_ff, vb.pp("wi_0"), quant)?,
+                Some(linear(cfg.d_model, cfg.d_ff, vb.pp("wi_1"), quant)?),
+            )
+        } else {
+            (linear(cfg.d_model, cfg.d_ff, vb.pp("wi"), quant)?, None)
+        };
+        Ok(Self {
+            wi,
+            gate,
+            wo: linear(cfg.d_ff, cfg.d_model, vb.pp("wo"), quant)?,
+            act,
+        })
+    }
+}
+
+impl Module for FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let hidden = self.act.forward(&self.wi.forward(xs)?)?;
+        let hidden = match &self.gate {
+            Some(gate) => (hidden * gate.forward(xs)?)?,
+            None => hidden,
+        };
+        self.wo.forward(&hidden)
+    }
+}
+
+/// Half precision activations of T5 overflow, transformers clamps them below the largest f16.
+fn clamp_f16(xs: Tensor) -> Result<Tensor> {
+    if xs.dtype() == DType::F16 {
+        let max = f64::from(half::f16::MAX) - 1000.;
+        xs.clamp(-max, max)
+    } else {
+        Ok(xs)
+    }
+}
+
+struct EncoderBlock {
+    self_attn: Attention,
+    self_attn_norm: RmsNorm,
+    ff: FeedForward,
+    ff_norm: RmsNorm,
+}
+
+impl EncoderBlock {
+    fn new(
+        cfg: &T5Config,
+        layer_idx: usize,
+        quant: &Option<String>,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let vb = vb.pp("layer");
+        let eps = cfg.layer_norm_epsilon;
+        Ok(Self {
+            self_attn: Attention::new(cfg, layer_idx == 0, quant, vb.pp(0).pp("SelfAttention"))?,
+            self_attn_norm: RmsNorm::new(cfg.d_model, eps, vb.pp(0).pp("layer_norm"))?,
+            ff: FeedForward::new(cfg, quant, vb.pp(1).pp("DenseReluDense"))?,
+            ff_norm: RmsNorm::new(cfg.d_model, eps, vb.pp(1).pp("layer_norm"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, position_bias: &Tensor) -> Result<Tensor> {
+        let normed = self.self_attn_norm.forward(xs)?;
+        let (key, value) = self.self_attn.key_value(&normed)?;
+        let xs = clamp_f16(
+            (xs + self
+                .self_attn
+                .attend(&normed, &key, &value, position_bias)?)?,
+        )?;
+        clamp_f16((&xs + self.ff.forward(&self.ff_norm.forward(&xs)?)?)?)
+    }
+}
+
+struct DecoderBlock {
+    self_attn: Attention,
+    self_attn_norm: RmsNorm,
+    cross_attn: Attention,
+    cross_attn_norm: RmsNorm,
+    ff: FeedForward,
+    ff_norm: RmsNorm,
+}
+
+impl DecoderBlock {
+    fn new(
+        cfg: &T5Config,
+        layer_idx: usize,
+        quant: &Option<String>,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let vb = vb.pp("layer");
+        let eps = cfg.layer_norm_epsilon;
+        Ok(Self {
+            self_attn: Attention::new(cfg, layer_idx == 0, quant, vb.pp(0).pp("SelfAttention"))?,
+            self_attn_norm: RmsNorm::new(cfg.d_model, eps, vb.pp(0).pp("layer_norm"))?,
+            cross_attn: Attention::new(cfg, false, quant, vb.pp(1).pp("EncDecAttention"))?,
+            cross_attn_norm: RmsNorm::new(cfg.d_model, eps, vb.pp(1).pp("layer_norm"))?,
+            ff: FeedForward::new(cfg, quant, vb.pp(2).pp("DenseReluDense"))?,
+            ff_norm: RmsNorm::new(cfg.d_model, eps, vb.pp(2).pp("layer_norm"))?,
+        })
+    }
+}
+
+/// T5, an encoder-decoder model. The encoder runs once over the prompt of a request, and its
+/// output is only read through the cross-attention keys and values of the decoder layers,
+/// which `encode` returns to be cached. The decoder then generates one token per step.
+pub struct T5 {
+    shared: Embedding,
+    encoder: Vec<EncoderBlock>,
+    encoder_norm: RmsNorm,
+    decoder: Vec<DecoderBlock>,
+    decoder_norm: RmsNorm,
+    lm_head: Linear,
+    /// Tied output layers scale the decoder output by `d_model^-0.5`
+    output_scale: Option<f64>,
+    num_buckets: usize,
+    max_distance: usize,
+    decoder_start_token_id: u32,
+    device: Device,
+    cfg: Config,
+}
+
+impl T5 {
+    pub fn new(vb: VarBuilder, cfg: &Config, t5_cfg: &T5Config, device: &Device) -> Result<Self> {
+        let quant = &cfg.specific_config.quant;
+        let shared = embedding(t5_cfg.vocab_size, t5_cfg.d_model, vb.pp("shared"))?;
+        let eps = t5_cfg.layer_norm_epsilon;
+        let vb_e = vb.pp("encoder");
+        let encoder = (0..t5_cfg.num_layers)
+            .map(|layer_idx| {
+                EncoderBlock::new(t5_cfg, layer_idx, quant, vb_e.pp("block").pp(layer_idx))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let encoder_norm = RmsNorm::new(t5_cfg.d_model, eps, vb_e.pp("final_layer_norm"))?;
+        let vb_d = vb.pp("decoder");
+        let decoder = (0..t5_cfg.num_decoder_layers())
+            .map(|layer_idx| {
+                DecoderBlock::new(t5_cfg, layer_idx, quant, vb_d.pp("block").pp(layer_idx))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let decoder_norm = RmsNorm::new(t5_cfg.d_model, eps, vb_d.pp("final_layer_norm"))?;
+        let (lm_head, output_scale) = if t5_cfg.tie_word_embeddings {
+            (
+                Linear::new(shared.embeddings().clone(), None, quant),
+                Some((t5_cfg.d_model as f64).powf(-0.5)),
+            )
+        } else {
+            (
+                linear(t5_cfg.d_model, t5_cfg.vocab_size, vb.pp("lm_head"), quant)?,
+                None,
+            )
+        };
+        Ok(Self {
+            shared,
+            encoder,
+            encoder_norm,
+            decoder,
+            decoder_norm,
+            lm_head,
+            output_scale,
+            num_buckets: t5_cfg.relative_attention_num_buckets,
+            max_distance: t5_cfg.relative_attention_max_distance,
+            decoder_start_token_id: t5_cfg.decoder_start_token_id(),
+            device: device.clone(),
+            cfg: cfg.clone(),
+        })
+    }
+
+    pub fn decoder_start_token_id(&self) -> u32 {
+        self.decoder_start_token_id
+    }
+
+    /// Run the encoder over the prompt `input_ids` `(1, seq_len)`: the cross-attention keys and
+    /// values of its output for each decoder layer.
+    pub fn encode(&self, input_ids: &Tensor) -> Result<Vec<KVCache>> {
+        let (_, seq_len) = input_ids.dims2()?;
+        let buckets = (0..seq_len)
+            .flat_map(|i| {
+                (0..seq_len).map(move |j| {
+                    relative_position_bucket(
+                        j as i64 - i as i64,
+                        true,
+                        self.num_buckets,
+                        self.max_distance,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        let buckets = Tensor::from_vec(buckets, (1, seq_len, seq_len), &self.device)?;
+        let position_bias = self.encoder[0].self_attn.position_bias(&buckets)?;
+        let mut xs = self.shared.forward(input_ids)?;
+        for block in &self.encoder {
+            xs = block.forward(&xs, &position_bias)?;
+        }
+        let xs = self.encoder_norm.forward(&xs)?;
+        self.decoder
+            .iter()
+            .map(|block| block.cross_attn.key_value(&xs))
+            .collect()
+    }
+
+    /// Logits `(b_size, vocab_size)` of the next token of each sequence after `input_ids`
+    /// `(b_size, 1)`, its last token. Each sequence attends to the tokens it decoded before and
+    /// to its encoder output through `kv`, whose decoder keys and values are extended with
+    /// those of `input_ids`. Sequences of different lengths are padded and masked.
+    pub fn decode(&self, input_ids: &Tensor, kv: &mut [EncoderDecoderKv]) -> Result<Tensor> {
+        let decoder_lens = kv.iter().map(|kv| kv.decoder_len() + 1).collect::<Vec<_>>();
+        let encoder_lens = kv
+            .iter()
+            .map(EncoderDecoderKv::encoder_len)
+            .collect::<Vec<_>>();
+        let max_decoder_len = decoder_lens.iter().copied().max().unwrap_or(1);
+        let max_encoder_len = encoder_lens.iter().copied().max().unwrap_or(0);
+
+        // The query is the last token of each sequence, the keys are its tokens so far
+        let buckets = decoder_lens
+            .iter()
+            .flat_map(|&len| {
+                (0..max_decoder_len).map(move |j| {
+                    relative_position_bucket(
+                        j as i64 - (len - 1) as i64,
+                        false,
+                        self.num_buckets,
+                        self.max_distance,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        let buckets = Tensor::from_vec(buckets, (kv.len(), 1, max_decoder_len), &self.device)?;
+        let self_bias = self.decoder[0]
+            .self_attn
+            .position_bias(&buckets)?
+            .broadcast_add(&length_mask(&decoder_lens, max_decoder_len, &self.device)?)?;
+        let cross_mask = length_mask(&encoder_lens, max_encoder_len, &self.device)?;
+
+        let mut xs = self.shared.forward(input_ids)?;
+        for (layer_idx, block) in self.decoder.iter().enumerate() {
+            let normed = block.self_attn_norm.forward(&xs)?;
+            let (key, value) = block.self_attn.key_value(&normed)?;
+            let mut keys = Vec::with_capacity(kv.len());
+            let mut values = Vec::with_capacity(kv.len());
+            for (i, seq_kv) in kv.iter_mut().enumerate() {
+                let (key, value) = (key.narrow(0, i, 1)?, value.narrow(0, i, 1)?);
+                let cached = match seq_kv.decoder.get(layer_idx) {
+                    Some((cached_key, cached_value)) => (
+                        Tensor::cat(&[cached_key, &key], 2)?,
+                        Tensor::cat(&[cached_value, &value], 2)?,
+                    ),
+                    None => (key, value),
+                };
+                if layer_idx < seq_kv.decoder.len() {
+                    seq_kv.decoder[layer_idx] = cached;
+                } else {
+                    seq_kv.decoder.push(cached);
+                }
+                let (key, value) = &seq_kv.decoder[layer_idx];
+                keys.push(key.clone());
+                values.push(value.clone());
+            }
+            let keys = pad_batch(&keys.iter().collect::<Vec<_>>(), max_decoder_len)?;
+            let values = pad_batch(&values.iter().collect::<Vec<_>>(), max_decoder_len)?;
+            xs = clamp_f16(
+                (&xs + block
+                    .self_attn
+                    .attend(&normed, &keys, &values, &self_bias)?)?,
+            )?;
+
+            let normed = block.cross_attn_norm.forward(&xs)?;
+            let cross = kv
+                .iter()
+                .map(|seq_kv| {
+                    seq_kv.cross.get(layer_idx).ok_or_else(|| {
+                        candle::Error::Msg(format!("No cross-attention keys for layer {layer_idx}"))
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let keys = pad_batch(
+                &cross.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+                max_encoder_len,
+            )?;
+            let values = pad_batch(
+                &cross.iter().map(|(_, v)| v).collect::<Vec<_>>(),
+                max_encoder_len,
+            )?;
+            xs = clamp_f16(
+                (&xs + block
+                    .cross_attn
+                    .attend(&normed, &keys, &values, &cross_mask)?)?,
+            )?;
+
+            xs = clamp_f16((&xs + block.ff.forward(&block.ff_norm.forward(&xs)?)?)?)?;
+        }
+        let xs = self.decoder_norm.forward(&xs)?.squeeze(1)?;
+        let xs = match self.output_scale {
+            Some(scale) => (xs * scale)?,
+            None => xs,
+        };
+        self.lm_head.forward(&xs)?.to_dtype(DType::F32)
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.cfg
+    }
+}
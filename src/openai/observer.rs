@@ -0,0 +1,97 @@
+//! Observers of the engine events, for library users to plug in their own telemetry, billing or
+//! tracing exporters. An [`EngineObserver`] is registered with [`LLMEngine::add_observer`] and
+//! called from the generation loop with the engine locked, so it should return quickly, e.g. by
+//! sending the events to a channel processed elsewhere.
+//!
+//! [`LLMEngine::add_observer`]: super::pipelines::llm_engine::LLMEngine::add_observer
+
+use std::time::Duration;
+
+pub use crate::scheduler::Preemption;
+
+/// A request was queued.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RequestStart {
+    pub request_id: String,
+    /// End user of the request (the OpenAI `user` field).
+    pub user: Option<String>,
+    pub prompt_tokens: usize,
+    pub max_tokens: usize,
+}
+
+/// The scheduler picked the requests of the next step.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ScheduleEvent {
+    pub request_ids: Vec<String>,
+    /// Whether the step prefills prompts, otherwise it decodes one token per sequence.
+    pub is_prompt: bool,
+    /// Requests left waiting to be scheduled.
+    pub queue_depth: usize,
+    /// KV cache blocks moved between the GPU and the CPU, and copied on write, before the step.
+    pub blocks_to_swap_in: usize,
+    pub blocks_to_swap_out: usize,
+    pub blocks_to_copy: usize,
+}
+
+/// A forward pass and the sampling of its tokens. The prompts of a step are prefilled one at a
+/// time, each in its own forward pass.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct StepEvent {
+    pub request_ids: Vec<String>,
+    pub is_prompt: bool,
+    /// Tokens of the forward pass.
+    pub num_tokens: usize,
+    pub duration: Duration,
+}
+
+/// A token was generated.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TokenEvent {
+    pub request_id: String,
+    /// Index of the sequence when the request generates `n > 1` of them.
+    pub index: usize,
+    pub token: usize,
+    pub text: String,
+}
+
+/// A request finished, failed or was aborted.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct FinishEvent {
+    pub request_id: String,
+    pub user: Option<String>,
+    /// `stop`, `length` and the other finish reasons of the API, `error` if the forward pass of
+    /// the request failed, `abort` if it was aborted and `timeout` if it exceeded its timeout.
+    pub finish_reason: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// From the arrival of the request.
+    pub duration: Duration,
+}
+
+/// A running request was preempted to free KV cache blocks for others.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PreemptEvent {
+    pub request_id: String,
+    pub preemption: Preemption,
+}
+
+/// Callbacks of the engine events, all of them do nothing by default.
+pub trait EngineObserver: Send + Sync {
+    fn on_request_start(&self, _event: &RequestStart) {}
+
+    fn on_schedule(&self, _event: &ScheduleEvent) {}
+
+    fn on_step(&self, _event: &StepEvent) {}
+
+    fn on_token(&self, _event: &TokenEvent) {}
+
+    fn on_finish(&self, _event: &FinishEvent) {}
+
+    fn on_preempt(&self, _event: &PreemptEvent) {}
+}
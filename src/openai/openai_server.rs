@@ -1,106 +1,377 @@
-use super::requests::ChatCompletionRequest;
+use super::guided_decoding::{json_schema_to_regex, TokenGuide};
+use super::metrics::{CanaryMetrics, EnergyMetrics};
+use super::models::linear::QUANTIZATIONS;
+use super::pipelines::llm_engine::{LLMEngine, NewRequest};
+use super::plugins::{Hook, PluginError};
+use super::rate_limit::{RateLimitExceeded, RateLimitKey, RateLimiter};
+use super::reload::{DRAIN_POLL_INTERVAL, RELOAD_RETRY_AFTER};
 use super::requests::Messages;
-use super::responses::{APIError, ChatCompletionResponse, ChatResponder};
+use super::requests::{
+    ChatCompletionRequest, EmbeddingInput, EmbeddingRequest, ForkRequest, ReloadRequest,
+    ScoreInput, ScoreRequest, TokenClassificationRequest,
+};
+use super::responses::{
+    APIError, AbortResponse, Capabilities, ChatChoice, ChatCompletionResponse,
+    ChatCompletionUsageResponse, ChatResponder, ClassifiedToken, EmbeddingData, EmbeddingResponse,
+    EmbeddingUsage, EmbeddingVector, EnergyUsage, ModelCard, ModelList, ReloadResponse,
+    RouterHints, ScoreData, ScoreResponse, SessionDeleted, SessionResponse, Stats,
+    TokenClassificationData, TokenClassificationResponse, TokenEntity, ToolCall,
+    ENGINE_QUEUE_DEPTH_HEADER, REQUEST_ID_HEADER,
+};
 use super::sampling_params::{EarlyStoppingCondition, SamplingParams};
-use super::streaming::{Streamer, StreamingStatus};
-use super::OpenAIServerData;
+use super::sessions::{relay_session_stream, reply_message};
+use super::streaming::{CancelFlag, CancelOnDrop, Streamer};
+use super::tools::{relay_tool_call_stream, select_tools, ToolFormat};
+use super::utils::{base64_encode, get_created_time_secs};
+use super::{OpenAIServerData, PromptLogging, ServedModel};
+use crate::scheduler::{block_engine::PrefixCacheMetrics, cache_engine::KvCacheMetrics};
+use axum::extract::rejection::JsonRejection;
+use axum::http::{header, HeaderMap};
 use axum::response::sse::KeepAlive;
 use axum::{
-    extract::{Json, State},
-    response::Sse,
+    extract::{Extension, Json, Path, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response, Sse},
 };
 use flume;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::env;
-use std::sync::Arc;
-use std::time::SystemTime;
-use tokenizers::Encoding;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Instant, SystemTime};
+use tokenizers::{Encoding, Tokenizer, TruncationDirection};
 use tokio::time::Duration;
 use uuid::Uuid;
-// fn verify_model(data: &OpenAIServerData<'_>, model_name: &String) -> Result<(), APIError> {
-//     let current_name = {
-//         let model = data.model.lock().unwrap();
-//         model.get_pipeline().name().to_string()
-//     };
-//     if &current_name != model_name {
-//         Err(APIError::new(format!(
-//             "Model name `{model_name}` is invalid."
-//         )))
-//     } else {
-//         Ok(())
-//     }
-// }
-
-// Get prompt, roles
+
+/// Retry-After of the requests rejected because the waiting queue is full
+const QUEUE_FULL_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Longest wait of a reload for the references of an unloaded model to be dropped
+const UNLOAD_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most top logprobs a request can ask for at each position, as in the OpenAI API.
+const MAX_TOP_LOGPROBS: usize = 20;
+
+/// Run prompt work (chat template rendering, tokenization) on the blocking pool, so that long
+/// prompts neither hold the engine lock nor stall the runtime threads.
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, APIError> + Send + 'static,
+) -> Result<T, APIError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(APIError::from)?
+}
+
+/// Tokenize `text` with the tokenizer of the model, without the engine lock.
+async fn tokenize(served: &ServedModel, text: String) -> Result<Encoding, APIError> {
+    let tokenizer = served.tokenizer.clone();
+    run_blocking(move || tokenizer.encode(text, false).map_err(APIError::from)).await
+}
+
+// Get prompt, the rendered system and tools prefix of the prompt, and the tool calling format if
+// tools are enabled
 async fn get_gen_prompt(
     data: &OpenAIServerData,
+    served: &ServedModel,
     request: &ChatCompletionRequest,
-) -> Result<String, APIError> {
-    let mut model = data.model.lock().await;
-    let conversation = model
-        .get_mut_pipeline()
-        .get_conversation(data.record_conversation);
+) -> Result<(String, String, Option<ToolFormat>), APIError> {
+    let (mut conversation, mut prefix, tool_format) = {
+        let mut model = served.model.lock().await;
+        let conversation = model
+            .get_mut_pipeline()
+            .get_conversation(data.record_conversation);
+        conversation.set_user(request.user.clone());
+        let tool_format = conversation.get_tool_format();
 
-    match &request.messages {
-        Messages::Literal(msg) => {
-            return Ok(msg.clone());
-        }
-        Messages::Map(messages) => {
-            for message in messages {
-                let role = message
-                    .get("role")
-                    .ok_or(APIError::new("Message key `role` not found.".to_string()))?;
-                let content = message
-                    .get("content")
-                    .ok_or(APIError::new(
-                        "Message key `content` not found.".to_string(),
-                    ))?
-                    .clone();
+        let tools = select_tools(&request.tools, &request.tool_choice)?;
+        let tools_prompt = match (&tools, tool_format) {
+            (None, _) => None,
+            (Some((tools, required)), Some(format)) => Some(format.system_prompt(tools, *required)),
+            (Some(_), None) => {
+                return Err(APIError::new_str(
+                    "Tool calling is not supported by the chat template of this model.",
+                ));
+            }
+        };
+        conversation.set_tools_prompt(tools_prompt);
+        // Reset for every request, the system message of the previous one must not leak into it
+        conversation.set_system_message(data.default_system_prompt.clone().unwrap_or_default());
 
-                if role == "system" {
-                    conversation.set_system_message(content);
-                } else {
-                    conversation.append_message(role.to_string(), content)
+        match &request.messages {
+            Messages::Literal(msg) => return Ok((msg.clone(), String::new(), None)),
+            Messages::Map(messages) => {
+                let mut turns = Vec::new();
+                for message in messages {
+                    let role = message
+                        .get("role")
+                        .and_then(Value::as_str)
+                        .ok_or(APIError::new("Message key `role` not found.".to_string()))?;
+                    let tool_calls = match (message.get("tool_calls"), tool_format) {
+                        (None | Some(Value::Null), _) => None,
+                        (Some(tool_calls), Some(format)) => {
+                            let tool_calls: Vec<ToolCall> =
+                                serde_json::from_value(tool_calls.clone())
+                                    .map_err(APIError::from)?;
+                            Some(format.format_tool_calls(&tool_calls))
+                        }
+                        (Some(_), None) => {
+                            return Err(APIError::new_str(
+                                "Tool calling is not supported by the chat template of this model.",
+                            ));
+                        }
+                    };
+                    let content = match (message.get("content"), tool_calls) {
+                        (Some(Value::String(content)), None) => content.clone(),
+                        (Some(Value::String(content)), Some(tool_calls)) => {
+                            format!("{content}{tool_calls}")
+                        }
+                        (None | Some(Value::Null), Some(tool_calls)) => tool_calls,
+                        _ => {
+                            return Err(APIError::new(
+                                "Message key `content` not found.".to_string(),
+                            ))
+                        }
+                    };
+
+                    if role == "system" {
+                        conversation.set_system_message(content);
+                    } else {
+                        turns.push((role.to_string(), content));
+                    }
+                }
+                // Rendered before the user turns so that it only depends on the system message and
+                // the tools, which agent workloads repeat across requests.
+                let prefix = conversation.snapshot();
+                for (role, content) in turns {
+                    conversation.append_message(role, content);
                 }
+                (conversation.snapshot(), prefix, tools.and(tool_format))
+            }
+        }
+    };
+    // Rendered without the engine lock, the templates of long conversations are slow to render
+    let (prompt, prefix) =
+        run_blocking(move || Ok((conversation.get_prompt(), prefix.get_prompt()))).await?;
+    Ok((prompt, prefix, tool_format))
+}
+
+/// Number of leading prompt tokens shared with the rendered system and tools prefix. The prefix
+/// is tokenized on its own and compared token by token, templates that render the system
+/// message differently without a user turn simply share fewer (or no) tokens.
+async fn get_prefix_len(
+    served: &ServedModel,
+    prefix: &str,
+    token_ids: &Encoding,
+) -> Result<usize, APIError> {
+    if prefix.is_empty() {
+        return Ok(0);
+    }
+    let prefix_ids = tokenize(served, prefix.to_string()).await?;
+    Ok(prefix_ids
+        .get_ids()
+        .iter()
+        .zip(token_ids.get_ids())
+        .take_while(|(a, b)| a == b)
+        .count())
+}
+
+async fn check_stop_token_ids(
+    request: &ChatCompletionRequest,
+    served: &ServedModel,
+) -> Result<Vec<usize>, APIError> {
+    let stop_token_ids = request.stop_token_ids.clone().unwrap_or_default();
+    let vocab_size = served.tokenizer.get_vocab_size(true);
+    match stop_token_ids.iter().find(|id| **id >= vocab_size) {
+        Some(token_id) => Err(APIError::new(format!(
+            "Stop token id {} is out of the vocabulary (size {}).",
+            token_id, vocab_size
+        ))),
+        None => Ok(stop_token_ids),
+    }
+}
+
+/// Keep only the special tokens of a rendered prompt, the text between them is replaced by
+/// its length.
+fn redact_prompt(prompt: &str, special_tokens: &[String]) -> String {
+    let mut redacted = String::new();
+    let mut rest = prompt;
+    while !rest.is_empty() {
+        let next = special_tokens
+            .iter()
+            .filter_map(|token| rest.find(token.as_str()).map(|pos| (pos, token)))
+            .min_by_key(|(pos, token)| (*pos, usize::MAX - token.len()));
+        let (text, token) = match next {
+            Some((pos, token)) => (&rest[..pos], Some(token)),
+            None => (rest, None),
+        };
+        if text.trim().is_empty() {
+            redacted += text;
+        } else {
+            redacted += &format!("<redacted {} chars>", text.chars().count());
+        }
+        match token {
+            Some(token) => {
+                redacted += token;
+                rest = &rest[text.len() + token.len()..];
             }
+            None => break,
         }
     }
+    redacted
+}
 
-    Ok(conversation.get_prompt())
+async fn log_prompt(
+    data: &OpenAIServerData,
+    served: &ServedModel,
+    request_id: &str,
+    prompt: &str,
+    sampling_params: &SamplingParams,
+) {
+    let prompt = match data.log_prompts {
+        PromptLogging::Off => return,
+        PromptLogging::Full => prompt.to_string(),
+        PromptLogging::Redacted => {
+            let special_tokens = served
+                .tokenizer
+                .get_added_tokens_decoder()
+                .into_values()
+                .filter(|token| token.special && !token.content.is_empty())
+                .map(|token| token.content)
+                .collect::<Vec<_>>();
+            redact_prompt(prompt, &special_tokens)
+        }
+    };
+    tracing::info!(%request_id, "Prompt: {prompt:?}");
+    tracing::info!(%request_id, "Sampling params: {sampling_params:?}");
 }
 
+async fn get_guide(
+    request: &ChatCompletionRequest,
+    served: &ServedModel,
+) -> Result<Option<Arc<TokenGuide>>, APIError> {
+    // Some(pattern) for a regular expression, None for a JSON object
+    let constraint = match (&request.response_format, &request.guided_regex) {
+        (Some(_), Some(_)) => {
+            return Err(APIError::new_str(
+                "`response_format` and `guided_regex` cannot be used together.",
+            ));
+        }
+        (Some(format), None) => match format.format_type.as_str() {
+            "text" => return Ok(None),
+            "json_object" => None,
+            "json_schema" => {
+                let json_schema = format.json_schema.as_ref().ok_or(APIError::new_str(
+                    "`json_schema` is required for `response_format` of type `json_schema`.",
+                ))?;
+                Some(json_schema_to_regex(&json_schema.schema)?)
+            }
+            format_type => {
+                return Err(APIError::new(format!(
+                    "`response_format` of type `{format_type}` is not supported."
+                )));
+            }
+        },
+        (None, Some(regex)) => Some(regex.clone()),
+        (None, None) => return Ok(None),
+    };
+    // The automaton is compiled off the engine lock
+    let token_bytes = served.token_bytes.clone();
+    let guide = run_blocking(move || match constraint {
+        Some(pattern) => TokenGuide::new(&pattern, token_bytes),
+        None => Ok(TokenGuide::json_object(token_bytes)),
+    })
+    .await?;
+    Ok(Some(Arc::new(guide)))
+}
+
+/// Tokenize the prompt, left-truncated to `truncate_prompt_tokens`, and check that it fits in the
+/// context with the tokens to generate.
 async fn check_length(
     request: &ChatCompletionRequest,
     prompt: String,
-    data: &OpenAIServerData,
-) -> Result<Encoding, APIError> {
-    let token_ids = {
-        let model = data.model.lock().await;
-        model
-            .get_pipeline()
-            .tokenizer()
-            .tokenizer()
-            .encode(prompt, false)
-            .map_err(APIError::from)?
-    };
+    served: &ServedModel,
+) -> Result<Encoding, ChatResponder> {
+    let mut token_ids = tokenize(served, prompt)
+        .await
+        .map_err(ChatResponder::ValidationError)?;
 
     let max_gen_tokens = request
         .max_tokens
-        .unwrap_or(data.pipeline_config.default_max_tokens);
-
-    if token_ids.len() + max_gen_tokens > data.pipeline_config.max_model_len {
-        Err(APIError::new(format!(
-            "This model's maximum context length is {} tokens. \
-            However, you requested {} tokens ({} in the messages, \
-            {} in the completion). \nPlease clear the chat history or reduce the length of the \
-            messages.",
-            data.pipeline_config.max_model_len,
-            max_gen_tokens + token_ids.len(),
-            token_ids.len(),
-            max_gen_tokens
-        )))
+        .unwrap_or(served.pipeline_config.default_max_tokens);
+
+    // Attention sinks bound the KV cache, only the prompt has to fit in the context
+    let max_gen_tokens = if request.attention_sinks.is_some() {
+        0
     } else {
-        Ok(token_ids)
+        max_gen_tokens
+    };
+    let max_prompt_tokens = match request.truncate_prompt_tokens {
+        None => None,
+        Some(-1) => Some(
+            served
+                .pipeline_config
+                .max_model_len
+                .saturating_sub(max_gen_tokens),
+        ),
+        Some(tokens) if tokens > 0 => Some(tokens as usize),
+        Some(tokens) => {
+            return Err(ChatResponder::ValidationError(APIError::new(format!(
+                "`truncate_prompt_tokens` must be positive or -1, got {tokens}."
+            ))))
+        }
+    };
+    if let Some(max_prompt_tokens) = max_prompt_tokens.filter(|&max| max < token_ids.len()) {
+        tracing::info!(
+            prompt_tokens = token_ids.len(),
+            kept = max_prompt_tokens,
+            "Prompt truncated."
+        );
+        token_ids.truncate(max_prompt_tokens, 0, TruncationDirection::Left);
     }
+    served
+        .model
+        .lock()
+        .await
+        .check_context_length(token_ids.len(), max_gen_tokens)
+        .map_err(ChatResponder::ContextLengthExceeded)?;
+    Ok(token_ids)
+}
+
+pub async fn models(State(data): State<Arc<OpenAIServerData>>) -> Json<ModelList> {
+    Json(ModelList {
+        object: "list".to_string(),
+        data: data
+            .models()
+            .iter()
+            .map(|served| ModelCard {
+                id: served.model_name.clone(),
+                object: "model".to_string(),
+                created: get_created_time_secs(),
+                owned_by: "candle-vllm".to_string(),
+                compute_capability: served
+                    .compute_capability
+                    .map(|(major, minor)| format!("{major}.{minor}")),
+                max_model_len: Some(served.pipeline_config.max_model_len),
+            })
+            .collect(),
+    })
+}
+
+/// Version and features of the server and its models, for gateways in front of replicas that may
+/// run different versions during a rolling upgrade.
+pub async fn capabilities(State(data): State<Arc<OpenAIServerData>>) -> Json<Capabilities> {
+    Json(Capabilities {
+        object: "capabilities".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        quantizations: QUANTIZATIONS
+            .iter()
+            .map(|quant| quant.to_string())
+            .collect(),
+        models: data
+            .models()
+            .iter()
+            .map(|served| served.capabilities.clone())
+            .collect(),
+    })
 }
 
 #[utoipa::path(
@@ -112,37 +383,326 @@ async fn check_length(
 )]
 pub async fn chat_completions(
     State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+    rate_limit_key: Option<Extension<RateLimitKey>>,
+    request: Result<Json<ChatCompletionRequest>, JsonRejection>,
+) -> Response {
+    let request = match request_body(request) {
+        Ok(request) => request,
+        Err(responder) => return responder.into_response(),
+    };
+    let request_id = match headers.get(REQUEST_ID_HEADER) {
+        Some(value) => match value.to_str().ok().filter(|id| is_valid_request_id(id)) {
+            Some(id) => id.to_string(),
+            None => {
+                return ChatResponder::ValidationError(APIError::new(format!(
+                    "`{REQUEST_ID_HEADER}` must be 1 to 128 ASCII letters, digits, `-`, `_`, `.` \
+                     or `:`."
+                )))
+                .into_response()
+            }
+        },
+        None => format!("cmpl-{}", Uuid::new_v4()),
+    };
+    let request = match run_plugins(&data, Hook::Request, request.0).await {
+        Ok(request) => Json(request),
+        Err(responder) => return responder.into_response(),
+    };
+    let mut hints = None;
+    let rate_limit_key = rate_limit_key.map(|Extension(key)| key);
+    let responder = match chat_completion(
+        data.clone(),
+        request,
+        request_id.clone(),
+        rate_limit_key.as_ref(),
+        &mut hints,
+    )
+    .await
+    {
+        // Streamed responses are not transformed
+        ChatResponder::Completion(response) => {
+            match run_plugins(&data, Hook::Response, response).await {
+                Ok(response) => ChatResponder::Completion(response),
+                Err(responder) => responder,
+            }
+        }
+        responder => responder,
+    };
+    (hints, [(REQUEST_ID_HEADER, request_id)], responder).into_response()
+}
+
+/// A body that is not the JSON of the request is an invalid request, answered in the OpenAI error
+/// format instead of the plain text rejection of axum.
+fn request_body<T>(body: Result<Json<T>, JsonRejection>) -> Result<Json<T>, ChatResponder> {
+    body.map_err(|e| ChatResponder::ValidationError(APIError::invalid_request(e.body_text())))
+}
+
+/// Request ids supplied by callers end up in logs and response headers.
+fn is_valid_request_id(id: &str) -> bool {
+    (1..=128).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Abort an in-flight chat completion by its id (the `id` of its response and chunks, also in the
+/// `x-request-id` response header), freeing its KV cache. Its caller gets an error instead of
+/// the rest of the response.
+pub async fn abort_request(
+    State(data): State<Arc<OpenAIServerData>>,
+    Path(request_id): Path<String>,
+) -> Response {
+    if !data
+        .models()
+        .iter()
+        .any(|served| served.cancel_flags.abort(&request_id))
+    {
+        return ChatResponder::RequestNotFound(APIError::new(format!(
+            "No request {request_id} is in flight."
+        )))
+        .into_response();
+    }
+    Json(AbortResponse {
+        id: request_id,
+        object: "abort".to_string(),
+        aborted: true,
+    })
+    .into_response()
+}
+
+/// The history of a session with `--session-history`.
+pub async fn get_session(
+    State(data): State<Arc<OpenAIServerData>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let history = data
+        .sessions
+        .as_ref()
+        .and_then(|sessions| sessions.history(&session_id, Instant::now()));
+    match history {
+        Some(messages) => Json(SessionResponse {
+            id: session_id,
+            object: "session".to_string(),
+            messages,
+        })
+        .into_response(),
+        None => ChatResponder::RequestNotFound(APIError::new(format!(
+            "No session {session_id} has a history."
+        )))
+        .into_response(),
+    }
+}
+
+/// Forget the history of a session with `--session-history`.
+pub async fn delete_session(
+    State(data): State<Arc<OpenAIServerData>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let deleted = data
+        .sessions
+        .as_ref()
+        .is_some_and(|sessions| sessions.remove(&session_id));
+    if !deleted {
+        return ChatResponder::RequestNotFound(APIError::new(format!(
+            "No session {session_id} has a history."
+        )))
+        .into_response();
+    }
+    Json(SessionDeleted {
+        id: session_id,
+        object: "session.deleted".to_string(),
+        deleted,
+    })
+    .into_response()
+}
+
+/// Run a request or response through the plugins, on a blocking thread since they are CPU bound.
+async fn run_plugins<T: Serialize + DeserializeOwned + Send + 'static>(
+    data: &Arc<OpenAIServerData>,
+    hook: Hook,
+    value: T,
+) -> Result<T, ChatResponder> {
+    if data.plugins.is_empty() {
+        return Ok(value);
+    }
+    let data = data.clone();
+    match tokio::task::spawn_blocking(move || data.plugins.transform(hook, value)).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e @ PluginError::Rejected { .. })) => {
+            tracing::info!(hook = ?hook, "{e}");
+            Err(ChatResponder::ValidationError(APIError::new(e.to_string())))
+        }
+        Ok(Err(e)) => Err(ChatResponder::InternalError(APIError::new(e.to_string()))),
+        Err(e) => Err(ChatResponder::InternalError(APIError::from(e))),
+    }
+}
+
+/// Serve a chat completion, `hints` is set once the prompt is known.
+async fn chat_completion(
+    data: Arc<OpenAIServerData>,
     request: Json<ChatCompletionRequest>,
+    request_id: String,
+    rate_limit_key: Option<&RateLimitKey>,
+    hints: &mut Option<RouterHints>,
 ) -> ChatResponder {
-    // let model_name = &request.model;
-    // let res = verify_model(&data, model_name);
-    // if res.is_err() {
-    //     return Either::Left(Err(res.err().unwrap()));
-    // }
-
-    if request.logit_bias.as_ref().is_some()
-        && request.logit_bias.as_ref().is_some_and(|x| !x.is_empty())
-    {
+    let received = Instant::now();
+    tracing::info!(
+        %request_id,
+        user = request.user.as_deref(),
+        model = %request.model,
+        stream = request.stream.unwrap_or(false),
+        event = "received",
+        "Chat completion request received."
+    );
+    let served = match data.get_model(&request.model) {
+        Ok(served) => served,
+        Err(e) => return ChatResponder::ModelNotFound(e),
+    };
+    let served = match data.route_quality(served, request.quality) {
+        Ok(served) => served,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
+
+    if let Some(max_waiting) = data.max_waiting_requests {
+        let waiting = served.queue_depth.load(Ordering::Relaxed);
+        if waiting >= max_waiting {
+            tracing::info!(%request_id, waiting, "Request rejected, the queue is full.");
+            return ChatResponder::TooManyRequests(
+                APIError::too_many_requests(format!(
+                    "{waiting} requests are waiting for the model, retry later."
+                ))
+                .with_code("queue_full"),
+                QUEUE_FULL_RETRY_AFTER,
+            );
+        }
+    }
+
+    let timeout = match request.timeout.map(Duration::try_from_secs_f64) {
+        None => data.request_timeout,
+        Some(Ok(timeout)) if !timeout.is_zero() => Some(timeout),
+        Some(_) => {
+            return ChatResponder::ValidationError(
+                APIError::invalid_request(format!(
+                    "`timeout` must be a positive number of seconds, got {}.",
+                    request.timeout.unwrap_or_default()
+                ))
+                .with_param("timeout"),
+            )
+        }
+    };
+
+    if served.model.lock().await.get_pipeline().is_encoder_only() {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "This model does not generate, it serves /v1/embeddings, /v1/token_classify or /v1/score.",
+        ));
+    }
+
+    if request.stream_options.is_some() && !request.stream.is_some_and(|x| x) {
         return ChatResponder::ValidationError(APIError::new_str(
-            "`logit_bias` is not currently supported.",
+            "`stream_options` is only allowed when `stream` is true.",
         ));
     }
 
-    let prompt = get_gen_prompt(&data, &request).await;
+    if request.stream.is_some_and(|x| x) {
+        for (param, set) in [
+            ("echo", request.echo.is_some_and(|x| x)),
+            ("prompt_logprobs", request.prompt_logprobs.is_some()),
+        ] {
+            if set {
+                return ChatResponder::ValidationError(
+                    APIError::invalid_request(format!("`{param}` is not supported with `stream`."))
+                        .with_param(param),
+                );
+            }
+        }
+    }
+    if request
+        .prompt_logprobs
+        .is_some_and(|top_n| top_n > MAX_TOP_LOGPROBS)
+    {
+        return ChatResponder::ValidationError(
+            APIError::invalid_request(format!(
+                "`prompt_logprobs` must be at most {MAX_TOP_LOGPROBS}."
+            ))
+            .with_param("prompt_logprobs"),
+        );
+    }
+
+    if let Err(e) = served
+        .model
+        .lock()
+        .await
+        .check_num_seqs(request.n.unwrap_or(1), request.best_of)
+    {
+        return ChatResponder::ValidationError(e);
+    }
+
+    if let Some(sinks) = &request.attention_sinks {
+        if let Err(e) = served.model.lock().await.check_attention_sinks(sinks) {
+            return ChatResponder::ValidationError(e);
+        }
+    }
+
+    // With --session-history, the request only sends its new messages, the prompt starts with the
+    // history of its session
+    let mut request = request;
+    let session_turn = match (&data.sessions, &request.session_id) {
+        (Some(sessions), Some(session_id)) => match &request.messages {
+            Messages::Map(messages) => {
+                let turn = messages.clone();
+                request.messages =
+                    Messages::Map(sessions.prompt_messages(session_id, messages, received));
+                Some((sessions.clone(), session_id.clone(), turn))
+            }
+            Messages::Literal(_) => {
+                return ChatResponder::ValidationError(
+                    APIError::invalid_request(
+                        "`messages` must be a list of messages for a session with history.",
+                    )
+                    .with_param("messages"),
+                )
+            }
+        },
+        _ => None,
+    };
+
+    let prompt = get_gen_prompt(&data, &served, &request).await;
     if prompt.is_err() {
         return ChatResponder::ValidationError(prompt.err().unwrap());
     }
-    let prompt = prompt.unwrap();
+    let (prompt, prefix, tool_format) = prompt.unwrap();
 
-    let token_ids = check_length(&request, prompt.clone(), &data).await;
-    if token_ids.is_err() {
-        return ChatResponder::ValidationError(token_ids.err().unwrap());
+    let token_ids: Encoding = match check_length(&request, prompt.clone(), &served).await {
+        Ok(token_ids) => token_ids,
+        Err(responder) => return responder,
+    };
+    let max_tokens = request
+        .max_tokens
+        .unwrap_or(served.pipeline_config.default_max_tokens);
+    if let Err(responder) = acquire_tokens(&data, rate_limit_key, token_ids.len() + max_tokens) {
+        return responder;
     }
-    let token_ids: Encoding = token_ids.unwrap();
 
-    println!("\n\n\nPrompt {:?}", prompt);
+    let prefix_len = get_prefix_len(&served, &prefix, &token_ids).await;
+    if prefix_len.is_err() {
+        return ChatResponder::ValidationError(prefix_len.err().unwrap());
+    }
+    let prefix_len = prefix_len.unwrap();
 
-    let request_id = format!("cmpl-{}", Uuid::new_v4());
+    *hints = Some(RouterHints {
+        prefix_cache_hit_tokens: served
+            .model
+            .lock()
+            .await
+            .cached_prefix_len(token_ids.get_ids(), prefix_len),
+        queue_depth: served.queue_depth.load(Ordering::Relaxed),
+    });
+
+    let stop_token_ids = check_stop_token_ids(&request, &served).await;
+    if stop_token_ids.is_err() {
+        return ChatResponder::ValidationError(stop_token_ids.err().unwrap());
+    }
+    let stop_token_ids = stop_token_ids.unwrap();
 
     let sampling_params = SamplingParams::new(
         request.n.unwrap_or(1),
@@ -151,21 +711,19 @@ pub async fn chat_completions(
         request.frequency_penalty.unwrap_or(0.0),
         request
             .repetition_penalty
-            .unwrap_or(data.pipeline_config.penalty),
+            .unwrap_or(served.pipeline_config.penalty),
         request
             .temperature
-            .unwrap_or(data.pipeline_config.temperature),
+            .unwrap_or(served.pipeline_config.temperature),
         request.top_p.unwrap_or(1.0),
         request.top_k.unwrap_or(-1),
         request.use_beam_search.unwrap_or(false),
         1.0,
         EarlyStoppingCondition::UnlikelyBetterCandidates,
         request.stop.clone(),
-        request.stop_token_ids.clone().unwrap_or_default(),
+        stop_token_ids,
         request.ignore_eos.unwrap_or(false),
-        request
-            .max_tokens
-            .unwrap_or(data.pipeline_config.default_max_tokens),
+        max_tokens,
         None,
         None,
         request.skip_special_tokens.unwrap_or(true),
@@ -173,13 +731,64 @@ pub async fn chat_completions(
     if sampling_params.is_err() {
         return ChatResponder::ValidationError(sampling_params.err().unwrap());
     }
-    let sampling_params = sampling_params.unwrap();
+    let mut sampling_params = sampling_params.unwrap();
+
+    let guide = get_guide(&request, &served).await;
+    if guide.is_err() {
+        return ChatResponder::ValidationError(guide.err().unwrap());
+    }
+    sampling_params.guide = guide.unwrap();
+    sampling_params.seed = request.seed;
+    if let Err(e) = sampling_params.set_probability_filters(request.min_p, request.typical_p) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Err(e) = sampling_params.set_min_tokens(request.min_tokens) {
+        return ChatResponder::ValidationError(e);
+    }
+    if let Some(logit_bias) = &request.logit_bias {
+        let vocab_size = served
+            .model
+            .lock()
+            .await
+            .get_pipeline()
+            .get_model_config()
+            .vocab_size;
+        if let Err(e) = sampling_params.set_logit_bias(logit_bias, vocab_size) {
+            return ChatResponder::ValidationError(e);
+        }
+    }
+
+    // Scored by a forward pass of its own, before the prompt is queued for generation
+    let prompt_logprobs = match request.prompt_logprobs {
+        Some(top_n) => {
+            let mut model = served.model.lock().await;
+            match model
+                .get_mut_pipeline()
+                .prompt_logprobs(token_ids.get_ids(), top_n)
+            {
+                Ok(logprobs) => Some(logprobs),
+                Err(e) => return ChatResponder::ValidationError(e),
+            }
+        }
+        None => None,
+    };
+    let echo = request.echo.is_some_and(|x| x).then(|| prompt.clone());
+
+    log_prompt(&data, &served, &request_id, &prompt, &sampling_params).await;
 
     let (response_tx, rx) = flume::unbounded();
+    let cancel = CancelFlag::default().with_deadline(timeout.map(|timeout| received + timeout));
+    // Registered now, the engine only registers the request once it takes it
+    if !served.cancel_flags.try_insert(&request_id, cancel.clone()) {
+        return ChatResponder::ValidationError(APIError::new(format!(
+            "A request with id {request_id} is already in flight."
+        )));
+    }
+    let cancel_clone = cancel.clone();
     // println!("{:?}", sampling_params);
 
-    let finish_notify = data.finish_notify.clone();
-    let data_clone = data.clone();
+    let finish_notify = served.finish_notify.clone();
+    let engine = served.model.clone();
     let request_id_clone = request_id.clone();
     let stream_request = request.stream.is_some_and(|x| x);
     let model_name = request.model.clone();
@@ -188,27 +797,55 @@ pub async fn chat_completions(
         tokio::runtime::Handle::current().block_on(async move {
             {
                 //send completion request to inference engine
-                let mut model = data.model.lock().await;
-                model.add_request(
-                    token_ids,
-                    request_id.clone(),
-                    SystemTime::now(),
+                let mut model = engine.lock().await;
+                model.add_request(NewRequest {
+                    prompt: token_ids,
+                    prefix_len,
+                    request_id: request_id.clone(),
+                    created: SystemTime::now(),
                     sampling_params,
-                    request.logprobs.unwrap_or(false),
-                    Some(response_tx),
-                );
+                    use_logprobs: request.logprobs.unwrap_or(false),
+                    sender: Some(response_tx),
+                    stream_options: request.stream_options.clone().unwrap_or_default(),
+                    cancel: cancel_clone,
+                    forkable: request.forkable.unwrap_or(false),
+                    user: request.user.clone(),
+                    attention_sinks: request.attention_sinks,
+                    priority: request.priority.unwrap_or(0),
+                    cache_priority: request.cache_priority.unwrap_or_default(),
+                    session_id: request.session_id.clone(),
+                });
                 model.notify.notify_one();
             }
         });
     });
 
     if stream_request {
+        let rx = match session_turn {
+            Some((sessions, session_id, turn)) => {
+                let (session_tx, session_rx) = flume::unbounded();
+                tokio::spawn(relay_session_stream(
+                    rx,
+                    session_tx,
+                    sessions,
+                    session_id,
+                    turn,
+                    tool_format,
+                ));
+                session_rx
+            }
+            None => rx,
+        };
+        let rx = match tool_format {
+            Some(format) => {
+                let (tool_tx, tool_rx) = flume::unbounded();
+                tokio::spawn(relay_tool_call_stream(rx, tool_tx, format));
+                tool_rx
+            }
+            None => rx,
+        };
         ChatResponder::Streamer(
-            Sse::new(Streamer {
-                rx,
-                status: StreamingStatus::Uninitilized,
-            })
-            .keep_alive(
+            Sse::new(Streamer::new(rx, cancel)).keep_alive(
                 KeepAlive::new()
                     .interval(Duration::from_millis(
                         env::var("KEEP_ALIVE_INTERVAL")
@@ -219,9 +856,14 @@ pub async fn chat_completions(
             ),
         )
     } else {
-        // wait until current response finished
+        // wait until current response finished, abort if the client goes away meanwhile
+        let cancel_guard = CancelOnDrop::new(cancel);
         finish_notify.notified().await;
-        let model = data_clone.model.lock().await;
+        cancel_guard.disarm();
+        let mut model = served.model.lock().await;
+        if let Some(error) = model.failed_requests.remove(&request_id_clone) {
+            return ChatResponder::ModelError(error);
+        }
         if !model.completion_records.contains_key(&request_id_clone) {
             return ChatResponder::ModelError(APIError::from(format!(
                 "Unable to generate response for request {}",
@@ -229,16 +871,880 @@ pub async fn chat_completions(
             )));
         }
 
-        let choices = &model.completion_records[&request_id_clone].0;
+        let mut choices = model.completion_records[&request_id_clone].0.clone();
         let usage = &model.completion_records[&request_id_clone].1;
+        if let Some(format) = tool_format {
+            for choice in choices.iter_mut() {
+                let tool_calls = choice
+                    .message
+                    .content
+                    .as_ref()
+                    .and_then(|content| format.parse_tool_calls(content));
+                if tool_calls.is_some() {
+                    choice.message.content = None;
+                    choice.message.tool_calls = tool_calls;
+                    choice.finish_reason = Some("tool_calls".to_string());
+                }
+            }
+        }
+        if let (Some((sessions, session_id, mut turn)), Some(choice)) =
+            (session_turn, choices.first())
+        {
+            turn.push(reply_message(&choice.message));
+            sessions.append(&session_id, turn, Instant::now());
+        }
+        if let Some(prompt) = echo {
+            for content in choices
+                .iter_mut()
+                .filter_map(|c| c.message.content.as_mut())
+            {
+                content.insert_str(0, &prompt);
+            }
+        }
 
         ChatResponder::Completion(ChatCompletionResponse {
             id: request_id_clone,
-            choices: choices.to_vec(),
+            choices,
             created: usage.created,
             model: model_name,
-            object: "chat.completion",
+            object: "chat.completion".to_string(),
+            system_fingerprint: Some(served.system_fingerprint.clone()),
             usage: usage.clone(),
+            prompt_logprobs,
+        })
+    }
+}
+
+/// Branch the finished generation of a `forkable` request into `n` continuations that share its
+/// KV cache (candle-vllm extension). A request still generating is forked once it finishes.
+pub async fn fork_chat_completion(
+    State(data): State<Arc<OpenAIServerData>>,
+    request: Result<Json<ForkRequest>, JsonRejection>,
+) -> ChatResponder {
+    let request = match request_body(request) {
+        Ok(request) => request,
+        Err(responder) => return responder,
+    };
+    let n = request.n.unwrap_or(1);
+    if n == 0 {
+        return ChatResponder::ValidationError(APIError::new_str("`n` must be at least 1."));
+    }
+    let served = match data.get_model(request.model.as_deref().unwrap_or_default()) {
+        Ok(served) => served,
+        Err(e) => return ChatResponder::ModelNotFound(e),
+    };
+    let request_id = format!("cmpl-{}", Uuid::new_v4());
+    let branch_ids = (0..n)
+        .map(|index| format!("{request_id}-{index}"))
+        .collect::<Vec<_>>();
+
+    let finish_notify = served.finish_notify.clone();
+    {
+        let mut model = served.model.lock().await;
+        if let Err(e) = model.fork_request(&request, &branch_ids, SystemTime::now()) {
+            return ChatResponder::ValidationError(e);
+        }
+        model.notify.notify_one();
+    }
+    // All branches are generated by the same run of the engine
+    finish_notify.notified().await;
+
+    let model = served.model.lock().await;
+    let mut choices = Vec::new();
+    let mut usage: Option<ChatCompletionUsageResponse> = None;
+    for (index, branch_id) in branch_ids.iter().enumerate() {
+        let Some((branch_choices, branch_usage)) = model.completion_records.get(branch_id) else {
+            return ChatResponder::ModelError(APIError::new(format!(
+                "Unable to generate response for request {branch_id}"
+            )));
+        };
+        choices.extend(branch_choices.iter().map(|choice| ChatChoice {
+            index,
+            ..choice.clone()
+        }));
+        usage = Some(match usage {
+            None => ChatCompletionUsageResponse {
+                request_id: request_id.clone(),
+                ..branch_usage.clone()
+            },
+            Some(usage) => ChatCompletionUsageResponse {
+                completion_tokens: usage.completion_tokens + branch_usage.completion_tokens,
+                total_tokens: usage.total_tokens + branch_usage.completion_tokens,
+                completion_time_costs: usage
+                    .completion_time_costs
+                    .max(branch_usage.completion_time_costs),
+                // The branches run concurrently, their power adds up
+                energy: usage
+                    .energy
+                    .zip(branch_usage.energy)
+                    .map(|(a, b)| EnergyUsage {
+                        joules: a.joules + b.joules,
+                        avg_power_watts: a.avg_power_watts + b.avg_power_watts,
+                    }),
+                ..usage
+            },
+        });
+    }
+    let usage = usage.unwrap();
+
+    ChatResponder::Completion(ChatCompletionResponse {
+        id: request_id,
+        choices,
+        created: usage.created,
+        model: served.model_name.clone(),
+        object: "chat.completion".to_string(),
+        system_fingerprint: Some(served.system_fingerprint.clone()),
+        usage,
+        prompt_logprobs: None,
+    })
+}
+
+/// Turn the request away with a 503 while a model is reloaded, and count it in flight otherwise
+/// so that a reload waits for it.
+pub async fn admission(
+    State(data): State<Arc<OpenAIServerData>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(reloader) = &data.reloader else {
+        return next.run(request).await;
+    };
+    match reloader.admit() {
+        Some(_in_flight) => next.run(request).await,
+        None => ChatResponder::Unavailable(
+            APIError::new_str("A model is being reloaded, retry later.").with_code("reloading"),
+            RELOAD_RETRY_AFTER,
+        )
+        .into_response(),
+    }
+}
+
+/// Swap a served model for the model of the request without restarting the server, see
+/// [`reload`](super::reload). New requests are turned away until the new model is served, the
+/// ones in flight finish on the old model first (or are aborted after `drain_timeout`).
+pub async fn reload_model(
+    State(data): State<Arc<OpenAIServerData>>,
+    headers: HeaderMap,
+    request: Result<Json<ReloadRequest>, JsonRejection>,
+) -> ChatResponder {
+    let Some(reloader) = &data.reloader else {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "Models cannot be reloaded by this server.",
+        ));
+    };
+    if !reloader.authorize(&headers) {
+        return ChatResponder::ValidationError(APIError::unauthorized(
+            "Reloading a model requires the admin token, as `Authorization: Bearer <token>`.",
+        ));
+    }
+    let request = match request_body(request) {
+        Ok(request) => request.0,
+        Err(responder) => return responder,
+    };
+    let drain_timeout = match request.drain_timeout.map(Duration::try_from_secs_f64) {
+        None => None,
+        Some(Ok(timeout)) => Some(timeout),
+        Some(Err(_)) => {
+            return ChatResponder::ValidationError(
+                APIError::invalid_request(format!(
+                    "`drain_timeout` must be a number of seconds, got {}.",
+                    request.drain_timeout.unwrap_or_default()
+                ))
+                .with_param("drain_timeout"),
+            )
+        }
+    };
+    let served = match data.get_model(request.model.as_deref().unwrap_or_default()) {
+        Ok(served) => served,
+        Err(e) => return ChatResponder::ModelNotFound(e),
+    };
+    if let Some(router) = &data.quality_router {
+        if served.model_name == router.best || served.model_name == router.fast {
+            return ChatResponder::ValidationError(APIError::new(format!(
+                "`{}` is served with its fast variant (--fast-quant), it cannot be reloaded.",
+                served.model_name
+            )));
+        }
+    }
+    let Some(_reloading) = reloader.begin() else {
+        return ChatResponder::Unavailable(
+            APIError::new_str("Another model is being reloaded, retry later.")
+                .with_code("reloading"),
+            RELOAD_RETRY_AFTER,
+        );
+    };
+    let started = Instant::now();
+    let model_name = served.model_name.clone();
+    tracing::info!(
+        model = %model_name,
+        event = "reload_started",
+        "Draining the model to reload it."
+    );
+
+    let mut aborted_requests = None;
+    loop {
+        // The engine is locked while it generates, it is idle once it can be locked without work
+        let idle = served
+            .model
+            .try_lock()
+            .is_ok_and(|engine| !engine.has_unfinished_requests());
+        if idle && reloader.in_flight() == 0 {
+            break;
+        }
+        if aborted_requests.is_none()
+            && drain_timeout.is_some_and(|timeout| started.elapsed() >= timeout)
+        {
+            let aborted = served.cancel_flags.abort_all();
+            tracing::warn!(
+                model = %model_name,
+                requests = aborted,
+                "Requests aborted, they did not finish within the drain timeout."
+            );
+            aborted_requests = Some(aborted);
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    // Unload the model before loading the new one, whose weights and KV cache may not fit next
+    // to it
+    data.remove_model(&model_name);
+    served.model.lock().await.stop();
+    let unloaded = Instant::now();
+    // Until the generation loop and the handlers that just looked the model up let go of it
+    while (Arc::strong_count(&served) > 1 || Arc::strong_count(&served.model) > 1)
+        && unloaded.elapsed() < UNLOAD_TIMEOUT
+    {
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    drop(served);
+    let loaded = match reloader.load(model_name.clone(), request).await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            tracing::error!(model = %model_name, event = "reload_failed", "Reload failed: {e}");
+            // Serve the model that was unloaded again rather than none
+            let error = match reloader
+                .load(model_name.clone(), ReloadRequest::default())
+                .await
+            {
+                Ok(previous) => {
+                    data.add_model(previous);
+                    format!(
+                        "The model `{model_name}` could not be loaded, the previous one is served \
+                         again: {e}"
+                    )
+                }
+                Err(_) => format!(
+                    "The model `{model_name}` could not be loaded and is no longer served: {e}"
+                ),
+            };
+            return ChatResponder::InternalError(APIError::new(error));
+        }
+    };
+    let system_fingerprint = loaded.system_fingerprint.clone();
+    data.add_model(loaded);
+    let duration_ms = started.elapsed().as_millis() as u64;
+    tracing::info!(model = %model_name, event = "reload_finished", duration_ms, "Model reloaded.");
+    ChatResponder::Reload(ReloadResponse {
+        object: "reload".to_string(),
+        model: model_name,
+        system_fingerprint,
+        aborted_requests: aborted_requests.unwrap_or_default(),
+        duration_ms,
+    })
+}
+
+/// Count the request against the rate limits of its client address, rejecting it with a 429 over
+/// them, and report the remaining requests and tokens of the client in the `x-ratelimit-*` headers.
+pub async fn rate_limit(
+    State(data): State<Arc<OpenAIServerData>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &data.rate_limiter else {
+        return next.run(request).await;
+    };
+    let key = RateLimitKey::of(&request);
+    let mut response = match limiter.acquire_request(&key, Instant::now()) {
+        Ok(()) => {
+            request.extensions_mut().insert(key.clone());
+            next.run(request).await
+        }
+        Err(exceeded) => rate_limited(limiter, exceeded).into_response(),
+    };
+    limiter
+        .status(&key, Instant::now())
+        .insert_headers(response.headers_mut());
+    response
+}
+
+/// The response of a request over a rate limit.
+pub(crate) fn rate_limited(limiter: &RateLimiter, exceeded: RateLimitExceeded) -> ChatResponder {
+    let limits = limiter.limits();
+    let (limit, retry_after) = match exceeded {
+        RateLimitExceeded::Requests(retry_after) => (
+            format!(
+                "{} requests per minute",
+                limits.requests_per_minute.unwrap_or_default()
+            ),
+            retry_after,
+        ),
+        RateLimitExceeded::Tokens(retry_after) => (
+            format!(
+                "{} tokens per minute",
+                limits.tokens_per_minute.unwrap_or_default()
+            ),
+            retry_after,
+        ),
+        RateLimitExceeded::TooLarge => {
+            return ChatResponder::ValidationError(
+                APIError::invalid_request(format!(
+                    "The request needs more than the rate limit of {} tokens per minute, reduce \
+                     the prompt or `max_tokens`.",
+                    limits.tokens_per_minute.unwrap_or_default()
+                ))
+                .with_code("rate_limit_exceeded"),
+            )
+        }
+    };
+    ChatResponder::TooManyRequests(
+        APIError::too_many_requests(format!(
+            "Rate limit of {limit} reached, retry in {:.1}s.",
+            retry_after.as_secs_f64()
+        ))
+        .with_code("rate_limit_exceeded"),
+        retry_after,
+    )
+}
+
+/// Take the tokens of a request from the rate limit of its client address.
+fn acquire_tokens(
+    data: &OpenAIServerData,
+    key: Option<&RateLimitKey>,
+    tokens: usize,
+) -> Result<(), ChatResponder> {
+    match (&data.rate_limiter, key) {
+        (Some(limiter), Some(key)) => limiter
+            .acquire_tokens(key, tokens, Instant::now())
+            .map_err(|exceeded| rate_limited(limiter, exceeded)),
+        _ => Ok(()),
+    }
+}
+
+/// Report the number of requests waiting in all the engines on responses that do not report
+/// the queue of their model.
+pub async fn queue_depth_header(
+    State(data): State<Arc<OpenAIServerData>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if !response.headers().contains_key(ENGINE_QUEUE_DEPTH_HEADER) {
+        let queue_depth = data
+            .models()
+            .iter()
+            .map(|served| served.queue_depth.load(Ordering::Relaxed))
+            .sum::<usize>();
+        response
+            .headers_mut()
+            .insert(ENGINE_QUEUE_DEPTH_HEADER, queue_depth.into());
+    }
+    response
+}
+
+/// Prometheus metrics of the prefix and KV caches, the GPU energy and the canary probes (labeled
+/// by model), and of the end users.
+pub async fn metrics(State(data): State<Arc<OpenAIServerData>>) -> impl IntoResponse {
+    let models = data.models();
+    let prefix_cache_metrics = models
+        .iter()
+        .map(|served| (served.model_name.as_str(), &*served.prefix_cache_metrics))
+        .collect::<Vec<_>>();
+    let kv_cache_metrics = models
+        .iter()
+        .map(|served| (served.model_name.as_str(), &*served.kv_cache_metrics))
+        .collect::<Vec<_>>();
+    let energy_metrics = models
+        .iter()
+        .filter_map(|served| {
+            Some((
+                served.model_name.as_str(),
+                &**served.energy_metrics.as_ref()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let canary_metrics = models
+        .iter()
+        .filter_map(|served| {
+            Some((
+                served.model_name.as_str(),
+                &**served.canary_metrics.as_ref()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        PrefixCacheMetrics::render(&prefix_cache_metrics)
+            + &data.user_metrics.render()
+            + &KvCacheMetrics::render(&kv_cache_metrics)
+            + &EnergyMetrics::render(&energy_metrics)
+            + &CanaryMetrics::render(&canary_metrics),
+    )
+}
+
+/// A JSON snapshot of the scheduler queues, KV cache blocks, swaps, preemptions, batch size and
+/// token throughput of each served model. It does not wait for the engines.
+pub async fn stats(State(data): State<Arc<OpenAIServerData>>) -> Json<Stats> {
+    let now = Instant::now();
+    Json(Stats {
+        object: "stats".to_string(),
+        models: data
+            .models()
+            .iter()
+            .map(|served| served.engine_stats.snapshot(&served.model_name, now))
+            .collect(),
+    })
+}
+
+/// Embed one or more inputs, pooled from the final hidden states of the model and normalized.
+pub async fn embeddings(
+    State(data): State<Arc<OpenAIServerData>>,
+    rate_limit_key: Option<Extension<RateLimitKey>>,
+    request: Result<Json<EmbeddingRequest>, JsonRejection>,
+) -> ChatResponder {
+    let request = match request_body(request) {
+        Ok(request) => request,
+        Err(responder) => return responder,
+    };
+    let base64 = match request.encoding_format.as_deref() {
+        None | Some("float") => false,
+        Some("base64") => true,
+        Some(format) => {
+            return ChatResponder::ValidationError(APIError::new(format!(
+                "Unsupported `encoding_format` {format}, expected float or base64."
+            )))
+        }
+    };
+    let served = match data.get_model(&request.model) {
+        Ok(served) => served,
+        Err(e) => return ChatResponder::ModelNotFound(e),
+    };
+    let request_id = format!("embd-{}", Uuid::new_v4());
+
+    // Owned, the forward passes run on the blocking pool
+    let mut model = served.model.clone().lock_owned().await;
+    let hidden_size = model.get_pipeline().get_model_config().hidden_size;
+    if request
+        .dimensions
+        .is_some_and(|d| d == 0 || d > hidden_size)
+    {
+        return ChatResponder::ValidationError(APIError::new(format!(
+            "`dimensions` must be between 1 and {hidden_size}."
+        )));
+    }
+    let inputs = match &request.input {
+        EmbeddingInput::Single(text) => encode_embedding_inputs(&model, std::slice::from_ref(text)),
+        EmbeddingInput::Multi(texts) => encode_embedding_inputs(&model, texts),
+        EmbeddingInput::Tokens(tokens) => Ok(vec![tokens.clone()]),
+        EmbeddingInput::MultiTokens(inputs) => Ok(inputs.clone()),
+    };
+    let inputs = match inputs {
+        Ok(inputs) => inputs,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
+    if let Err(e) = validate_encoder_inputs(&model, &inputs, served.pipeline_config.max_model_len) {
+        return ChatResponder::ValidationError(e);
+    }
+    let tokens = inputs.iter().map(Vec::len).sum();
+    let rate_limit_key = rate_limit_key.map(|Extension(key)| key);
+    if let Err(responder) = acquire_tokens(&data, rate_limit_key.as_ref(), tokens) {
+        return responder;
+    }
+
+    let start = SystemTime::now();
+    let pooling = request.pooling;
+    let embeddings = run_blocking(move || {
+        let embeddings = inputs
+            .iter()
+            .map(|input| {
+                let embedding = model.get_mut_pipeline().embed(input, pooling)?;
+                embedding.to_vec1::<f32>().map_err(APIError::from)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((inputs, embeddings))
+    })
+    .await;
+    let (inputs, embeddings) = match embeddings {
+        Ok(embeddings) => embeddings,
+        Err(e) => return ChatResponder::ModelError(e),
+    };
+    let mut data_out = Vec::with_capacity(inputs.len());
+    for (index, mut embedding) in embeddings.into_iter().enumerate() {
+        if let Some(dimensions) = request.dimensions {
+            // Matryoshka truncation, the prefix is normalized again. A prefix of zeros stays zero.
+            embedding.truncate(dimensions);
+            let norm = embedding
+                .iter()
+                .map(|x| x * x)
+                .sum::<f32>()
+                .sqrt()
+                .max(1e-12);
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        let embedding = if base64 {
+            let bytes = embedding
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect::<Vec<_>>();
+            EmbeddingVector::Base64(base64_encode(&bytes))
+        } else {
+            EmbeddingVector::Float(embedding)
+        };
+        data_out.push(EmbeddingData {
+            object: "embedding".to_string(),
+            embedding,
+            index,
+        });
+    }
+
+    let prompt_tokens = inputs.iter().map(Vec::len).sum::<usize>();
+    data.user_metrics
+        .record_request(request.user.as_deref(), prompt_tokens);
+    tracing::info!(
+        %request_id,
+        user = request.user.as_deref(),
+        inputs = inputs.len(),
+        tokens = prompt_tokens,
+        duration_ms = start.elapsed().unwrap_or_default().as_millis() as u64,
+        "Embeddings computed"
+    );
+    ChatResponder::Embedding(EmbeddingResponse {
+        object: "list".to_string(),
+        data: data_out,
+        model: served.model_name.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
+
+/// Tag every token of one or more inputs with the token classification head of an encoder (e.g.
+/// NER), in one batched forward, and group the tagged tokens into entities.
+pub async fn token_classify(
+    State(data): State<Arc<OpenAIServerData>>,
+    request: Result<Json<TokenClassificationRequest>, JsonRejection>,
+) -> ChatResponder {
+    let request = match request_body(request) {
+        Ok(request) => request,
+        Err(responder) => return responder,
+    };
+    let served = match data.get_model(&request.model) {
+        Ok(served) => served,
+        Err(e) => return ChatResponder::ModelNotFound(e),
+    };
+    let request_id = format!("tcls-{}", Uuid::new_v4());
+
+    let mut model = served.model.lock().await;
+    if model.get_pipeline().token_labels().is_empty() {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "This model has no token classification head.",
+        ));
+    }
+    let tokenizer = model.get_pipeline().tokenizer().tokenizer();
+    let inputs = match &request.input {
+        EmbeddingInput::Single(text) => {
+            encode_classification_inputs(tokenizer, std::slice::from_ref(text))
+        }
+        EmbeddingInput::Multi(texts) => encode_classification_inputs(tokenizer, texts),
+        EmbeddingInput::Tokens(tokens) => {
+            Ok(vec![ClassificationInput::from_ids(tokenizer, tokens)])
+        }
+        EmbeddingInput::MultiTokens(inputs) => Ok(inputs
+            .iter()
+            .map(|tokens| ClassificationInput::from_ids(tokenizer, tokens))
+            .collect()),
+    };
+    let inputs = match inputs {
+        Ok(inputs) => inputs,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
+    let ids = inputs
+        .iter()
+        .map(|input| input.ids.clone())
+        .collect::<Vec<_>>();
+    if let Err(e) = validate_encoder_inputs(&model, &ids, served.pipeline_config.max_model_len) {
+        return ChatResponder::ValidationError(e);
+    }
+
+    let start = SystemTime::now();
+    let labels = match model.get_mut_pipeline().classify_tokens(&ids) {
+        Ok(labels) => labels,
+        Err(e) => return ChatResponder::ModelError(e),
+    };
+    drop(model);
+
+    let data_out = inputs
+        .into_iter()
+        .zip(labels)
+        .enumerate()
+        .map(|(index, (input, labels))| {
+            let tokens = labels
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| !input.special[*i])
+                .map(|(i, (label, score))| ClassifiedToken {
+                    token: input.tokens[i].clone(),
+                    label,
+                    score,
+                    start: input.offsets.as_ref().map(|offsets| offsets[i].0),
+                    end: input.offsets.as_ref().map(|offsets| offsets[i].1),
+                })
+                .collect::<Vec<_>>();
+            TokenClassificationData {
+                object: "token_classification".to_string(),
+                entities: TokenEntity::group(&tokens, input.text.as_deref()),
+                tokens,
+                index,
+            }
         })
+        .collect();
+
+    let prompt_tokens = ids.iter().map(Vec::len).sum::<usize>();
+    data.user_metrics
+        .record_request(request.user.as_deref(), prompt_tokens);
+    tracing::info!(
+        %request_id,
+        user = request.user.as_deref(),
+        inputs = ids.len(),
+        tokens = prompt_tokens,
+        duration_ms = start.elapsed().unwrap_or_default().as_millis() as u64,
+        "Tokens classified"
+    );
+    ChatResponder::TokenClassification(TokenClassificationResponse {
+        object: "list".to_string(),
+        data: data_out,
+        model: served.model_name.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
+
+/// Score one or more conversations or texts with the score head of a reward model (e.g.
+/// Skywork-Reward), one prefill-only forward per input.
+pub async fn score(
+    State(data): State<Arc<OpenAIServerData>>,
+    rate_limit_key: Option<Extension<RateLimitKey>>,
+    request: Result<Json<ScoreRequest>, JsonRejection>,
+) -> ChatResponder {
+    let request = match request_body(request) {
+        Ok(request) => request,
+        Err(responder) => return responder,
+    };
+    let served = match data.get_model(&request.model) {
+        Ok(served) => served,
+        Err(e) => return ChatResponder::ModelNotFound(e),
+    };
+    let request_id = format!("score-{}", Uuid::new_v4());
+
+    let mut model = served.model.lock().await;
+    let labels = model.get_pipeline().score_labels().to_vec();
+    if labels.is_empty() {
+        return ChatResponder::ValidationError(APIError::new_str(
+            "This model has no score head, /v1/score requires a reward model.",
+        ));
     }
+    let inputs = match &request.input {
+        ScoreInput::Conversation(messages) => {
+            encode_conversations(&mut model, std::slice::from_ref(messages))
+        }
+        ScoreInput::Conversations(conversations) => encode_conversations(&mut model, conversations),
+        ScoreInput::Input(EmbeddingInput::Single(text)) => {
+            encode_embedding_inputs(&model, std::slice::from_ref(text))
+        }
+        ScoreInput::Input(EmbeddingInput::Multi(texts)) => encode_embedding_inputs(&model, texts),
+        ScoreInput::Input(EmbeddingInput::Tokens(tokens)) => Ok(vec![tokens.clone()]),
+        ScoreInput::Input(EmbeddingInput::MultiTokens(inputs)) => Ok(inputs.clone()),
+    };
+    let inputs = match inputs {
+        Ok(inputs) => inputs,
+        Err(e) => return ChatResponder::ValidationError(e),
+    };
+    if let Err(e) = validate_encoder_inputs(&model, &inputs, served.pipeline_config.max_model_len) {
+        return ChatResponder::ValidationError(e);
+    }
+    let prompt_tokens = inputs.iter().map(Vec::len).sum::<usize>();
+    let rate_limit_key = rate_limit_key.map(|Extension(key)| key);
+    if let Err(responder) = acquire_tokens(&data, rate_limit_key.as_ref(), prompt_tokens) {
+        return responder;
+    }
+
+    let start = SystemTime::now();
+    let mut data_out = Vec::with_capacity(inputs.len());
+    for (index, input) in inputs.iter().enumerate() {
+        let scores = match model.get_mut_pipeline().score(input) {
+            Ok(scores) => scores,
+            Err(e) => return ChatResponder::ModelError(e),
+        };
+        data_out.push(ScoreData {
+            object: "score".to_string(),
+            score: scores[0],
+            scores: labels.iter().cloned().zip(scores).collect(),
+            index,
+        });
+    }
+    drop(model);
+
+    data.user_metrics
+        .record_request(request.user.as_deref(), prompt_tokens);
+    tracing::info!(
+        %request_id,
+        user = request.user.as_deref(),
+        inputs = inputs.len(),
+        tokens = prompt_tokens,
+        duration_ms = start.elapsed().unwrap_or_default().as_millis() as u64,
+        "Inputs scored"
+    );
+    ChatResponder::Score(ScoreResponse {
+        object: "list".to_string(),
+        data: data_out,
+        model: served.model_name.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    })
+}
+
+/// Render conversations with the chat template of the model, ending with their last turn rather
+/// than opening the next assistant turn, and tokenize them.
+fn encode_conversations(
+    model: &mut LLMEngine,
+    conversations: &[Vec<HashMap<String, Value>>],
+) -> Result<Vec<Vec<u32>>, APIError> {
+    conversations
+        .iter()
+        .map(|messages| {
+            let conversation = model.get_mut_pipeline().get_conversation(false);
+            conversation.set_tools_prompt(None);
+            conversation.set_system_message(String::new());
+            for message in messages {
+                let role = message
+                    .get("role")
+                    .and_then(Value::as_str)
+                    .ok_or(APIError::new_str("Message key `role` not found."))?;
+                let content = message
+                    .get("content")
+                    .and_then(Value::as_str)
+                    .ok_or(APIError::new_str("Message key `content` not found."))?;
+                if role == "system" {
+                    conversation.set_system_message(content.to_string());
+                } else {
+                    conversation.append_message(role.to_string(), content.to_string());
+                }
+            }
+            let prompt = conversation.get_scored_prompt();
+            let encoding = model
+                .get_pipeline()
+                .tokenizer()
+                .tokenizer()
+                .encode(prompt, false)
+                .map_err(APIError::from)?;
+            Ok(encoding.get_ids().to_vec())
+        })
+        .collect()
+}
+
+/// Reject empty inputs, token ids out of the vocabulary and inputs longer than the context.
+fn validate_encoder_inputs(
+    model: &LLMEngine,
+    inputs: &[Vec<u32>],
+    max_model_len: usize,
+) -> Result<(), APIError> {
+    if inputs.is_empty() || inputs.iter().any(Vec::is_empty) {
+        return Err(APIError::new_str("`input` must not be empty."));
+    }
+    let vocab_size = model.get_pipeline().get_model_config().vocab_size;
+    if let Some(token) = inputs.iter().flatten().find(|&&t| t as usize >= vocab_size) {
+        return Err(APIError::new(format!(
+            "Token id {token} is out of the vocabulary of {vocab_size} tokens."
+        )));
+    }
+    if let Some(input) = inputs.iter().find(|input| input.len() > max_model_len) {
+        return Err(APIError::new(format!(
+            "This model's maximum context length is {max_model_len} tokens. \
+            However, an input has {} tokens.",
+            input.len()
+        )));
+    }
+    Ok(())
+}
+
+/// An input of `/v1/token_classify` with what is needed to report its tokens.
+struct ClassificationInput {
+    ids: Vec<u32>,
+    tokens: Vec<String>,
+    /// Byte offsets of the tokens into `text`
+    offsets: Option<Vec<(usize, usize)>>,
+    special: Vec<bool>,
+    text: Option<String>,
+}
+
+impl ClassificationInput {
+    fn from_ids(tokenizer: &Tokenizer, ids: &[u32]) -> Self {
+        let tokens = ids
+            .iter()
+            .map(|&id| tokenizer.id_to_token(id).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let special = tokens
+            .iter()
+            .map(|token| tokenizer.get_added_vocabulary().is_special_token(token))
+            .collect();
+        Self {
+            ids: ids.to_vec(),
+            tokens,
+            offsets: None,
+            special,
+            text: None,
+        }
+    }
+}
+
+fn encode_classification_inputs(
+    tokenizer: &Tokenizer,
+    texts: &[String],
+) -> Result<Vec<ClassificationInput>, APIError> {
+    texts
+        .iter()
+        .map(|text| {
+            let encoding = tokenizer
+                .encode(text.as_str(), true)
+                .map_err(APIError::from)?;
+            Ok(ClassificationInput {
+                ids: encoding.get_ids().to_vec(),
+                tokens: encoding.get_tokens().to_vec(),
+                offsets: Some(encoding.get_offsets().to_vec()),
+                special: encoding
+                    .get_special_tokens_mask()
+                    .iter()
+                    .map(|&mask| mask == 1)
+                    .collect(),
+                text: Some(text.clone()),
+            })
+        })
+        .collect()
+}
+
+fn encode_embedding_inputs(model: &LLMEngine, texts: &[String]) -> Result<Vec<Vec<u32>>, APIError> {
+    let tokenizer = model.get_pipeline().tokenizer().tokenizer();
+    texts
+        .iter()
+        .map(|text| {
+            let encoding = tokenizer
+                .encode(text.as_str(), true)
+                .map_err(APIError::from)?;
+            Ok(encoding.get_ids().to_vec())
+        })
+        .collect()
 }
@@ -0,0 +1,176 @@
+//! Request spans for tracing backends such as Jaeger or Tempo, exported over OTLP with
+//! `--otlp-endpoint` (requires the `otlp` feature). Every request gets a root `request` span from
+//! its arrival to its end, with the model, token counts and finish reason as attributes, and
+//! child spans for the time it waited in the `queue`, its `prefill` and every `decode_step`
+//! (under a `decode` span). A preempted request goes back to a new `queue` span. Only these spans
+//! are exported, not the `phase` spans of the logs nor the spans of the models.
+
+use super::observer::{
+    EngineObserver, FinishEvent, PreemptEvent, RequestStart, ScheduleEvent, StepEvent,
+};
+use super::responses::APIError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{field, info_span, Span};
+use tracing_subscriber::{Layer, Registry};
+
+struct RequestSpans {
+    request: Span,
+    queue: Option<Span>,
+    decode: Option<Span>,
+    /// The prefill or decoding step in flight.
+    step: Option<Span>,
+    decode_steps: usize,
+}
+
+/// An engine observer opening the spans of the requests of a model, see [`otel`](self).
+pub struct TracingObserver {
+    model: String,
+    requests: Mutex<HashMap<String, RequestSpans>>,
+}
+
+impl TracingObserver {
+    pub fn new(model: String) -> Self {
+        Self {
+            model,
+            requests: Mutex::default(),
+        }
+    }
+}
+
+impl EngineObserver for TracingObserver {
+    fn on_request_start(&self, event: &RequestStart) {
+        // A root span, not a child of whatever the engine loop runs in
+        let request = info_span!(
+            parent: None,
+            "request",
+            model = %self.model,
+            request_id = %event.request_id,
+            user = event.user.as_deref(),
+            prompt_tokens = event.prompt_tokens,
+            max_tokens = event.max_tokens,
+            completion_tokens = field::Empty,
+            finish_reason = field::Empty,
+        );
+        let queue = info_span!(parent: &request, "queue");
+        self.requests.lock().unwrap().insert(
+            event.request_id.clone(),
+            RequestSpans {
+                request,
+                queue: Some(queue),
+                decode: None,
+                step: None,
+                decode_steps: 0,
+            },
+        );
+    }
+
+    fn on_schedule(&self, event: &ScheduleEvent) {
+        let mut requests = self.requests.lock().unwrap();
+        let batch_size = event.request_ids.len();
+        for request_id in &event.request_ids {
+            let Some(spans) = requests.get_mut(request_id) else {
+                continue;
+            };
+            spans.queue = None;
+            spans.step = Some(if event.is_prompt {
+                info_span!(parent: &spans.request, "prefill", batch_tokens = field::Empty)
+            } else {
+                let decode = spans
+                    .decode
+                    .get_or_insert_with(|| info_span!(parent: &spans.request, "decode"));
+                spans.decode_steps += 1;
+                info_span!(
+                    parent: &*decode,
+                    "decode_step",
+                    step = spans.decode_steps,
+                    batch_size,
+                    batch_tokens = field::Empty,
+                )
+            });
+        }
+    }
+
+    fn on_step(&self, event: &StepEvent) {
+        let mut requests = self.requests.lock().unwrap();
+        for request_id in &event.request_ids {
+            if let Some(step) = requests
+                .get_mut(request_id)
+                .and_then(|spans| spans.step.take())
+            {
+                step.record("batch_tokens", event.num_tokens);
+            }
+        }
+    }
+
+    fn on_preempt(&self, event: &PreemptEvent) {
+        let mut requests = self.requests.lock().unwrap();
+        if let Some(spans) = requests.get_mut(&event.request_id) {
+            tracing::info!(parent: &spans.request, preemption = ?event.preemption, "Request preempted.");
+            spans.step = None;
+            spans.decode = None;
+            spans.queue = Some(info_span!(parent: &spans.request, "queue"));
+        }
+    }
+
+    fn on_finish(&self, event: &FinishEvent) {
+        let Some(spans) = self.requests.lock().unwrap().remove(&event.request_id) else {
+            return;
+        };
+        spans
+            .request
+            .record("completion_tokens", event.completion_tokens)
+            .record("finish_reason", event.finish_reason.as_str());
+    }
+}
+
+/// Shuts the exporter down when dropped, flushing the spans not exported yet.
+pub struct OtlpGuard(());
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+/// The layer exporting the spans to the OTLP (gRPC) collector at `endpoint`, e.g.
+/// `http://localhost:4317`. Must be called from the tokio runtime, which exports the spans in
+/// batches.
+#[cfg(feature = "otlp")]
+pub fn otlp_layer(
+    endpoint: &str,
+) -> Result<(Box<dyn Layer<Registry> + Send + Sync>, OtlpGuard), APIError> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_subscriber::filter::filter_fn;
+
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::Config::default().with_resource(
+            opentelemetry_sdk::Resource::new([KeyValue::new("service.name", "candle-vllm")]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+        .map_err(|e| APIError::new(format!("Cannot export spans to {endpoint}: {e}")))?;
+    let tracer = provider.tracer("candle-vllm");
+    opentelemetry::global::set_tracer_provider(provider);
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter_fn(|metadata| metadata.target() == module_path!()));
+    Ok((Box::new(layer), OtlpGuard(())))
+}
+
+#[cfg(not(feature = "otlp"))]
+pub fn otlp_layer(
+    _endpoint: &str,
+) -> Result<(Box<dyn Layer<Registry> + Send + Sync>, OtlpGuard), APIError> {
+    Err(APIError::new_str(
+        "Exporting spans requires building with the `otlp` feature.",
+    ))
+}
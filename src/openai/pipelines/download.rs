@@ -0,0 +1,193 @@
+//! Downloads of the model files from the Hugging Face Hub. The weight shards are fetched a few at
+//! a time, with their progress reported as tracing events and, on a terminal, as progress bars. A
+//! shard whose download was interrupted is resumed from its `.part` file in the cache instead of
+//! being downloaded again from the start.
+
+use crate::openai::responses::APIError;
+use crate::try_api;
+use hf_hub::api::{
+    sync::{ApiError, ApiRepo},
+    Progress,
+};
+use hf_hub::{Cache, Repo};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget};
+use rayon::prelude::*;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Weight files downloaded at the same time
+pub const MAX_PARALLEL_DOWNLOADS: usize = 4;
+/// A download is retried this many times, each retry resumes where the previous attempt stopped
+pub const DOWNLOAD_RETRIES: usize = 3;
+/// Progress events are logged every this many percent of a file
+const PROGRESS_STEP: usize = 10;
+/// Environment variable of the Hub endpoint, used without `--hf-endpoint`
+pub const HF_ENDPOINT: &str = "HF_ENDPOINT";
+
+/// Progress of the download of a file, drives its progress bar and logs an event every
+/// `PROGRESS_STEP` percent.
+pub struct DownloadProgress {
+    bar: ProgressBar,
+    filename: String,
+    size: usize,
+    downloaded: usize,
+    resumed_from: usize,
+    /// hf-hub reports the bytes already in the `.part` file first
+    awaiting_offset: bool,
+    reported_percent: usize,
+    start: Instant,
+}
+
+impl DownloadProgress {
+    pub fn new(bar: ProgressBar) -> Self {
+        Self {
+            bar,
+            filename: String::new(),
+            size: 0,
+            downloaded: 0,
+            resumed_from: 0,
+            awaiting_offset: false,
+            reported_percent: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// Bytes of the file on disk, including those of a resumed download.
+    pub fn downloaded(&self) -> usize {
+        self.downloaded
+    }
+
+    /// Bytes that were already downloaded when the download started.
+    pub fn resumed_from(&self) -> usize {
+        self.resumed_from
+    }
+
+    pub fn percent(&self) -> usize {
+        if self.size == 0 {
+            100
+        } else {
+            (self.downloaded * 100 / self.size).min(100)
+        }
+    }
+}
+
+impl Progress for DownloadProgress {
+    fn init(&mut self, size: usize, filename: &str) {
+        // Also called before every retry, which restarts from the bytes on disk
+        Progress::init(&mut self.bar, size, filename);
+        self.bar.set_position(0);
+        self.filename = filename.to_string();
+        self.size = size;
+        self.downloaded = 0;
+        self.awaiting_offset = true;
+        self.reported_percent = 0;
+        self.start = Instant::now();
+    }
+
+    fn update(&mut self, size: usize) {
+        Progress::update(&mut self.bar, size);
+        self.downloaded += size;
+        if std::mem::take(&mut self.awaiting_offset) {
+            self.resumed_from = size;
+            if size > 0 {
+                tracing::info!(
+                    "Resuming the download of {} at {} of {}",
+                    self.filename,
+                    HumanBytes(size as u64),
+                    HumanBytes(self.size as u64)
+                );
+            }
+            let percent = self.percent();
+            self.reported_percent = percent - percent % PROGRESS_STEP;
+            return;
+        }
+        let percent = self.percent();
+        if percent < 100 && percent >= self.reported_percent + PROGRESS_STEP {
+            self.reported_percent = percent - percent % PROGRESS_STEP;
+            tracing::info!(
+                "Downloading {}: {percent}% ({} of {})",
+                self.filename,
+                HumanBytes(self.downloaded as u64),
+                HumanBytes(self.size as u64)
+            );
+        }
+    }
+
+    fn finish(&mut self) {
+        Progress::finish(&mut self.bar);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let fetched = self.downloaded - self.resumed_from;
+        tracing::info!(
+            "Downloaded {} ({}) in {elapsed:.1}s, {}/s",
+            self.filename,
+            HumanBytes(self.size as u64),
+            HumanBytes((fetched as f64 / elapsed.max(1e-3)) as u64)
+        );
+    }
+}
+
+/// Path of `filename` in `subfolder` of the repo, or at the root of the repo when the subfolder
+/// has none: the tokenizer is often shared by the models of the subfolders.
+pub fn get_file(
+    api: &ApiRepo,
+    subfolder: Option<&str>,
+    filename: &str,
+) -> Result<PathBuf, ApiError> {
+    match subfolder {
+        Some(dir) => api
+            .get(&format!("{dir}/{filename}"))
+            .or_else(|_| api.get(filename)),
+        None => api.get(filename),
+    }
+}
+
+/// Paths of `filenames` in the repo, in their order. The files in the cache are used as is, the
+/// others are downloaded, at most `MAX_PARALLEL_DOWNLOADS` at a time.
+pub fn fetch_files(
+    api: &ApiRepo,
+    cache: &Cache,
+    repo: &Repo,
+    filenames: &[String],
+) -> Result<Vec<PathBuf>, APIError> {
+    let cache = cache.repo(repo.clone());
+    let cached = filenames
+        .iter()
+        .map(|filename| cache.get(filename))
+        .collect::<Vec<_>>();
+    let missing = cached.iter().filter(|path| path.is_none()).count();
+    if missing == 0 {
+        return Ok(cached.into_iter().flatten().collect());
+    }
+    tracing::info!(
+        "Downloading {missing} of the {} weight files of {}, {} at a time",
+        filenames.len(),
+        repo.url(),
+        MAX_PARALLEL_DOWNLOADS.min(missing)
+    );
+    let start = Instant::now();
+    // The bars are only drawn on a terminal, the events are logged either way
+    let bars = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+    let pool = try_api!(rayon::ThreadPoolBuilder::new()
+        .num_threads(MAX_PARALLEL_DOWNLOADS.min(missing))
+        .build());
+    let paths = pool.install(|| {
+        filenames
+            .par_iter()
+            .zip(cached)
+            .map(|(filename, path)| match path {
+                Some(path) => Ok(path),
+                None => {
+                    let progress = DownloadProgress::new(bars.add(ProgressBar::new(0)));
+                    api.download_with_progress(filename, progress)
+                        .map_err(APIError::from)
+                }
+            })
+            .collect::<Result<Vec<_>, APIError>>()
+    })?;
+    tracing::info!(
+        "Downloaded the weights of {} in {:.1}s",
+        repo.url(),
+        start.elapsed().as_secs_f64()
+    );
+    Ok(paths)
+}
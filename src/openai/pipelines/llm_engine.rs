@@ -1,36 +1,54 @@
 use std::{
     collections::{HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
     iter::zip,
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
 };
 
 use super::{ModulePipeline, _make_tensor_with_pad};
-use crate::openai::streaming::ChatResponse;
+use crate::fault;
+use crate::openai::streaming::{CancelFlag, CancelFlags, CancelOnDrop, ChatResponse};
 use crate::scheduler::Scheduler;
 use crate::{
     openai::{
+        energy::{self, EnergyMeter},
+        metrics::{EnergyMetrics, EngineStats, UserMetrics},
+        observer::{
+            EngineObserver, FinishEvent, PreemptEvent, RequestStart, ScheduleEvent, StepEvent,
+            TokenEvent,
+        },
+        requests::{AttentionSinks, CachePriority, ForkRequest, StreamOptions},
         responses::{
             APIError, ChatChoice, ChatChoiceData, ChatCompletionChunk, ChatCompletionUsageResponse,
-            Choice, ChoiceData, WrapperLogprobs,
+            Choice, ChoiceData, EnergyUsage, PromptTokensDetails, WrapperLogprobs,
         },
         sampling_params::SamplingParams,
         utils::get_created_time_secs,
     },
-    paged_attention::input_metadata::InputMetadata,
+    paged_attention::{attn_bias::BlockDiagonalCausalMask, input_metadata::InputMetadata},
     scheduler::{
-        cache_engine::{CacheConfig, CacheEngine},
+        block_engine::PrefixCacheMetrics,
+        cache_engine::{CacheConfig, CacheEngine, KvCacheMetrics},
         sequence::{Sequence, SequenceGroup, _Sequence},
-        SchedulerConfig, SchedulerOutput,
+        state_cache::StateCache,
+        KeyRebase, SchedulerConfig, SchedulerOutput,
     },
     try_api,
 };
-use candle_core::Tensor;
+use candle_core::{DType, Tensor};
 use either::Either;
 use flume::Sender;
-use std::time::SystemTime;
-use tokenizers::Encoding;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use std::time::{Instant, SystemTime};
+use tokenizers::{Encoding, Tokenizer};
 use tokio::sync::Mutex;
 use tokio::sync::Notify;
+use uuid::Uuid;
 #[allow(dead_code)]
 struct PreparedInputs {
     tokens: Tensor,
@@ -40,6 +58,145 @@ struct PreparedInputs {
 
 const _PAD_SLOT_ID: i64 = -1;
 
+/// Identical deterministic requests share one generation: prompt tokens plus the
+/// debug-formatted sampling params and the logprobs flag.
+type CoalesceKey = (Vec<u32>, String);
+
+/// Why a request is aborted before it finishes.
+#[derive(Clone, Copy)]
+enum AbortReason {
+    /// The client has gone away, nobody waits for the response.
+    Disconnected,
+    /// Through `/v1/abort/{id}`.
+    Api,
+    /// Past the deadline of the request.
+    Timeout,
+}
+
+/// A request served from the generation of another, identical in-flight request.
+struct Follower {
+    request_id: String,
+    created: u64,
+    sender: Option<Sender<ChatResponse>>,
+    stream_options: StreamOptions,
+}
+
+/// A request handed to [`LLMEngine::add_request`], see [`NewRequest::new`] for the defaults.
+pub struct NewRequest {
+    pub prompt: Encoding,
+    /// Leading prompt tokens of the system and tools prefix, shared through the prefix cache
+    pub prefix_len: usize,
+    pub request_id: String,
+    pub created: SystemTime,
+    pub sampling_params: SamplingParams,
+    pub use_logprobs: bool,
+    /// Where the responses go, a request without a sender is reported in `completion_records`
+    /// or `failed_requests`
+    pub sender: Option<Sender<ChatResponse>>,
+    pub stream_options: StreamOptions,
+    pub cancel: CancelFlag,
+    /// Keep the KV blocks of the request to fork it later
+    pub forkable: bool,
+    pub user: Option<String>,
+    pub attention_sinks: Option<AttentionSinks>,
+    pub priority: i32,
+    pub cache_priority: CachePriority,
+    pub session_id: Option<String>,
+}
+
+impl NewRequest {
+    /// A request for `prompt`, created now, without a sender or a prefix.
+    pub fn new(prompt: Encoding, request_id: String, sampling_params: SamplingParams) -> Self {
+        Self {
+            prompt,
+            prefix_len: 0,
+            request_id,
+            created: SystemTime::now(),
+            sampling_params,
+            use_logprobs: false,
+            sender: None,
+            stream_options: StreamOptions::default(),
+            cancel: CancelFlag::default(),
+            forkable: false,
+            user: None,
+            attention_sinks: None,
+            priority: 0,
+            cache_priority: CachePriority::default(),
+            session_id: None,
+        }
+    }
+}
+
+/// Text generated for one sequence of a request, see [`LLMEngine::generate`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct GeneratedToken {
+    /// Index of the sequence when the request generates `n > 1` of them.
+    pub index: usize,
+    pub text: String,
+    /// Set on the last token of the sequence.
+    pub finish_reason: Option<String>,
+}
+
+/// Tokens of a request generated in-process, the request is aborted when dropped early.
+pub struct GenerationStream {
+    prompt_tokens: usize,
+    rx: BoxStream<'static, ChatResponse>,
+    pending: VecDeque<GeneratedToken>,
+    cancel: Option<CancelOnDrop>,
+}
+
+impl GenerationStream {
+    /// Tokens of the prompt the request generates from.
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens
+    }
+}
+
+impl Stream for GenerationStream {
+    type Item = Result<GeneratedToken, APIError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Poll::Ready(Some(Ok(token)));
+            }
+            if self.cancel.is_none() {
+                return Poll::Ready(None);
+            }
+            let response = match ready!(self.rx.poll_next_unpin(cx)) {
+                Some(response) => response,
+                // The request finished without a `Done`, e.g. its prompt did not fit the cache.
+                None => {
+                    self.cancel.take().unwrap().disarm();
+                    return Poll::Ready(Some(Err(APIError::new_str(
+                        "The request was dropped by the engine.",
+                    ))));
+                }
+            };
+            match response {
+                ChatResponse::Chunk(chunk) => {
+                    self.pending
+                        .extend(chunk.choices.into_iter().map(|choice| GeneratedToken {
+                            index: choice.index,
+                            text: choice.delta.content.unwrap_or_default(),
+                            finish_reason: choice.finish_reason,
+                        }));
+                }
+                ChatResponse::Done => {
+                    self.cancel.take().unwrap().disarm();
+                }
+                ChatResponse::InternalError(e)
+                | ChatResponse::ValidationError(e)
+                | ChatResponse::ModelError(e) => {
+                    self.cancel.take().unwrap().disarm();
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
 pub struct LLMEngine {
     pipeline: Box<dyn ModulePipeline>,
     scheduler: Scheduler,
@@ -47,20 +204,97 @@ pub struct LLMEngine {
     cache_config: CacheConfig,
     group_id: usize,
     cache_engine: CacheEngine,
+    /// The states of the sequences of a recurrent model, which has no use for the KV cache.
+    state_cache: Option<StateCache>,
+    /// The decoder start token of an encoder-decoder model, whose keys and values are kept in
+    /// the `CacheEngine` by sequence instead of in KV cache blocks.
+    decoder_start_token_id: Option<u32>,
     sliding_window: Option<usize>,
+    track_attn_scores: bool,
+    in_flight: HashMap<CoalesceKey, (String, Arc<Sequence>)>,
+    followers: HashMap<String, Vec<Follower>>,
+    /// Cancelled requests that keep generating for their followers, aborted once those are gone
+    cancelled_leaders: HashMap<String, AbortReason>,
+    /// Cancel flags of the requests in flight, shared with the server for `/v1/abort/{id}`.
+    pub cancel_flags: CancelFlags,
+    energy_meter: Option<EnergyMeter>,
+    /// Wakes up the generation loop once requests were added.
     pub notify: Arc<Notify>,
+    /// Notified after each generation run, the results of its requests are in `completion_records`.
     pub finish_notify: Arc<Notify>,
+    /// Hit, miss and tier counters of the prefix cache, served by `/metrics`.
+    pub prefix_cache_metrics: Arc<PrefixCacheMetrics>,
+    /// Request and token counters per hashed end user, served by `/metrics`.
+    pub user_metrics: Arc<UserMetrics>,
+    /// GPU KV cache utilization per tensor parallel rank, served by `/metrics`.
+    pub kv_cache_metrics: Arc<KvCacheMetrics>,
+    /// GPU energy of the batches with `--energy-telemetry`, served by `/metrics`.
+    pub energy_metrics: Option<Arc<EnergyMetrics>>,
+    /// Scheduler and KV cache statistics, served by `/v1/stats`.
+    pub engine_stats: Arc<EngineStats>,
+    /// Requests waiting to be scheduled, reported to load balancers in response headers.
+    pub queue_depth: Arc<AtomicUsize>,
+    /// Identifies the backend configuration (OpenAI `system_fingerprint`), seeded requests
+    /// generate the same output as long as it does not change.
+    pub system_fingerprint: String,
+    /// Tokenizer of the pipeline, to tokenize and render prompts without the engine lock.
+    pub tokenizer: Arc<Tokenizer>,
     pub completion_records: HashMap<String, (Vec<ChatChoice>, ChatCompletionUsageResponse)>,
+    /// Errors of the failed requests without a stream, by request id, until their callers take
+    /// them.
+    pub failed_requests: HashMap<String, APIError>,
+    observers: Vec<Arc<dyn EngineObserver>>,
+    /// Set by [`LLMEngine::stop`], the generation loop exits and releases the engine.
+    stopped: bool,
+}
+
+/// The prefill batches of the scheduled prompts, shortest first: the first token of a short
+/// prompt is streamed as soon as its own batch is done, without waiting for the long prompts.
+/// Prompts of similar lengths (up to twice the shortest of their batch) share a forward pass,
+/// which bounds the padding. A prompt extending a cached prefix is prefilled on its own, and so
+/// is the prompt of a `recurrent` model, whose state would run over the padding.
+fn prefill_batches(
+    scheduled: &VecDeque<Arc<SequenceGroup>>,
+    recurrent: bool,
+) -> Vec<VecDeque<Arc<SequenceGroup>>> {
+    let mut prompts = scheduled
+        .iter()
+        .map(|group| {
+            let seq = group.get_seqs().values().nth(0).unwrap();
+            let prefix_cached_len = seq.deref().get_prefix_cached_len();
+            (
+                seq.get_len() - prefix_cached_len,
+                prefix_cached_len > 0,
+                group,
+            )
+        })
+        .collect::<Vec<_>>();
+    // Stable, prompts of the same length keep their scheduling order
+    prompts.sort_by_key(|(len, ..)| *len);
+    // The shortest prompt of each batch, `None` for the prompts prefilled on their own
+    let mut batches: Vec<(Option<usize>, VecDeque<Arc<SequenceGroup>>)> = Vec::new();
+    for (len, prefix_cached, group) in prompts {
+        let alone = recurrent || prefix_cached;
+        if let Some((Some(shortest), batch)) = batches.last_mut() {
+            if !alone && len <= 2 * *shortest {
+                batch.push_back(group.clone());
+                continue;
+            }
+        }
+        batches.push(((!alone).then_some(len), VecDeque::from([group.clone()])));
+    }
+    batches.into_iter().map(|(_, batch)| batch).collect()
 }
 
 impl LLMEngine {
+    /// Create the engine and start its generation loop on the current tokio runtime.
     pub fn new(
         pipeline: Box<dyn ModulePipeline>,
         scheduler_config: SchedulerConfig,
         cache_config: CacheConfig,
-        notify: Arc<Notify>,
-        finish_notify: Arc<Notify>,
     ) -> Result<Arc<Mutex<Self>>, APIError> {
+        let notify = Arc::new(Notify::new());
+        let finish_notify = Arc::new(Notify::new());
         let cache_engine = CacheEngine::new(
             pipeline.get_model_config(),
             cache_config.clone(),
@@ -68,18 +302,94 @@ impl LLMEngine {
             pipeline.device(),
         )?;
         let sliding_window = pipeline.get_model_config().sliding_window;
+        let track_attn_scores = scheduler_config.kv_eviction.is_some();
+        let recurrent_state = pipeline.recurrent_state()?;
+        let decoder_start_token_id = pipeline.decoder_start_token_id();
+        if (recurrent_state.is_some() || decoder_start_token_id.is_some())
+            && (track_attn_scores || scheduler_config.num_lookahead_slots > 0)
+        {
+            return Err(APIError::new_str(
+                "Heavy-hitter eviction and speculative decoding are not supported for recurrent and encoder-decoder models.",
+            ));
+        }
+        if track_attn_scores && cache_config.dtype == DType::U8 {
+            // The decoding attention scores are computed from the cached keys
+            return Err(APIError::new_str(
+                "Heavy-hitter eviction is not supported with the int8 KV cache.",
+            ));
+        }
+        let model_config = pipeline.get_model_config();
+        if model_config.specific_config.self_extend.is_some() {
+            // The cached keys are gathered for the grouped attention, at the positions of their
+            // slots
+            let unsupported = if model_config.mla_config.is_some() {
+                Some("latent attention")
+            } else if cache_config.dtype == DType::U8 {
+                Some("the int8 KV cache")
+            } else if track_attn_scores {
+                Some("heavy-hitter eviction")
+            } else {
+                None
+            };
+            if let Some(unsupported) = unsupported {
+                return Err(APIError::new(format!(
+                    "Self-Extend is not supported with {unsupported}."
+                )));
+            }
+        }
+        // A slot per sequence of the batch, the states do not grow with the sequences
+        let state_cache = recurrent_state.map(|_| StateCache::new(scheduler_config.max_num_seqs));
+        let max_num_seqs = scheduler_config.max_num_seqs;
+        let mut scheduler = Scheduler::new(scheduler_config, &cache_config);
+        if let Some(state_cache) = &state_cache {
+            scheduler.use_state_slots(state_cache.num_slots());
+        } else if decoder_start_token_id.is_some() {
+            // The keys and values of a sequence are bounded by the context length
+            scheduler.use_state_slots(max_num_seqs);
+        }
+        let prefix_cache_metrics = scheduler.block_engine.prefix_cache_metrics();
+        let queue_depth = scheduler.queue_depth();
+        let kv_cache_metrics = Arc::new(KvCacheMetrics::new(
+            &pipeline.get_model_config(),
+            &cache_config,
+        ));
+
+        let engine_stats = Arc::new(EngineStats::new(Instant::now()));
+        engine_stats.record_blocks(scheduler.block_engine.block_stats());
+
+        let system_fingerprint = Self::system_fingerprint(&*pipeline, &cache_config);
+        let tokenizer = Arc::new(pipeline.tokenizer().tokenizer().clone());
 
         let engine = Arc::new(Mutex::new(Self {
             pipeline,
-            scheduler: Scheduler::new(scheduler_config, &cache_config),
+            scheduler,
             seq_id: 0,
             cache_config,
             group_id: 0,
             cache_engine,
+            state_cache,
+            decoder_start_token_id,
             sliding_window,
+            track_attn_scores,
+            in_flight: HashMap::new(),
+            followers: HashMap::new(),
+            cancelled_leaders: HashMap::new(),
+            cancel_flags: CancelFlags::default(),
+            energy_meter: None,
             notify: notify.clone(),
             finish_notify: finish_notify.clone(),
+            prefix_cache_metrics,
+            user_metrics: Arc::new(UserMetrics::default()),
+            kv_cache_metrics,
+            energy_metrics: None,
+            engine_stats,
+            queue_depth,
+            system_fingerprint,
+            tokenizer,
             completion_records: HashMap::new(),
+            failed_requests: HashMap::new(),
+            observers: Vec::new(),
+            stopped: false,
         }));
         let engine_clone = engine.clone();
 
@@ -89,12 +399,29 @@ impl LLMEngine {
                     notify.notified().await; // Blocking call to wait for notification
                     let _ = tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
                     let mut e = engine.lock().await;
-                    let result = e.generate_once().unwrap();
+                    if e.stopped {
+                        break;
+                    }
+                    let num_failed = e.failed_requests.len();
+                    let result = match e.generate_once() {
+                        Ok(result) => result,
+                        Err(error) => {
+                            // Fail the requests rather than the generation loop
+                            e.fail_unfinished(&error);
+                            finish_notify.notify_one();
+                            continue;
+                        }
+                    };
                     if result.len() == 0 {
+                        // Wake up the callers of the requests that failed
+                        if e.failed_requests.len() != num_failed {
+                            finish_notify.notify_one();
+                        }
                         continue;
                     }
                     for request_id in result.keys() {
-                        e.completion_records.insert(request_id.to_string(), result[request_id].clone());
+                        e.completion_records
+                            .insert(request_id.to_string(), result[request_id].clone());
                     }
                     finish_notify.notify_one();
 
@@ -102,7 +429,8 @@ impl LLMEngine {
                     let overall_usage = ChatCompletionUsageResponse {
                         request_id: "".to_string(),
                         created: 0,
-                        completion_tokens: result.values()
+                        completion_tokens: result
+                            .values()
                             .map(|(_, usage)| usage.completion_tokens)
                             .sum(),
                         prompt_tokens: result.values().map(|(_, usage)| usage.prompt_tokens).sum(),
@@ -117,18 +445,26 @@ impl LLMEngine {
                             .map(|(_, usage)| usage.completion_time_costs)
                             .max()
                             .unwrap_or(0),
+                        prompt_tokens_details: None,
+                        energy: None,
                     };
 
-                    println!(
-                        "\r\n [{} requests] Prefilling: {} prompt tokens processed in {} seconds",
-                        result.len(),
+                    tracing::info!(
+                        requests = result.len(),
+                        phase = "prefill",
+                        tokens = overall_usage.prompt_tokens,
+                        duration_ms = overall_usage.prompt_time_costs as u64,
+                        "Prefilling: {} prompt tokens processed in {} seconds",
                         overall_usage.prompt_tokens,
                         overall_usage.prompt_time_costs / 1000
                     );
 
-                    println!(
-                        "\r\n [{} requests] Decoding: {} tokens processed in {} seconds ({} tokens/s)",
-                        result.len(),
+                    tracing::info!(
+                        requests = result.len(),
+                        phase = "decode",
+                        tokens = overall_usage.completion_tokens,
+                        duration_ms = overall_usage.completion_time_costs as u64,
+                        "Decoding: {} tokens processed in {} seconds ({} tokens/s)",
                         overall_usage.completion_tokens,
                         overall_usage.completion_time_costs / 1000,
                         overall_usage.completion_tokens * 1000
@@ -145,10 +481,109 @@ impl LLMEngine {
         Ok(engine_clone)
     }
 
+    /// Generate from `prompt` in-process, without the HTTP server. The prompt is tokenized as
+    /// is, without applying the chat template.
+    pub async fn generate(
+        engine: &Arc<Mutex<Self>>,
+        prompt: &str,
+        sampling_params: SamplingParams,
+    ) -> Result<GenerationStream, APIError> {
+        Self::generate_until(engine, prompt, sampling_params, None).await
+    }
+
+    /// [`generate`](Self::generate), aborting the request with a timeout error at `deadline`.
+    pub async fn generate_until(
+        engine: &Arc<Mutex<Self>>,
+        prompt: &str,
+        sampling_params: SamplingParams,
+        deadline: Option<Instant>,
+    ) -> Result<GenerationStream, APIError> {
+        let (sender, rx) = flume::unbounded();
+        let cancel = CancelFlag::default().with_deadline(deadline);
+        // Tokenized on the blocking pool, a long prompt does not hold up the generation loop
+        let tokenizer = engine.lock().await.tokenizer.clone();
+        let prompt = prompt.to_string();
+        let prompt = tokio::task::spawn_blocking(move || tokenizer.encode(prompt, false))
+            .await
+            .map_err(APIError::from)?
+            .map_err(APIError::from)?;
+        let prompt_tokens = {
+            let mut e = engine.lock().await;
+            if e.stopped {
+                return Err(APIError::new_str("The engine is stopped."));
+            }
+            let prompt_len = prompt.len();
+            e.check_context_length(prompt_len, sampling_params.max_tokens)?;
+            e.add_request(NewRequest {
+                sender: Some(sender),
+                cancel: cancel.clone(),
+                ..NewRequest::new(prompt, format!("cmpl-{}", Uuid::new_v4()), sampling_params)
+            });
+            e.notify.notify_one();
+            prompt_len
+        };
+        Ok(GenerationStream {
+            prompt_tokens,
+            rx: rx.into_stream().boxed(),
+            pending: VecDeque::new(),
+            cancel: Some(CancelOnDrop::new(cancel)),
+        })
+    }
+
+    /// Attribute the GPU energy of every batch to its requests, see [`energy`].
+    pub fn enable_energy_telemetry(&mut self) -> Result<(), APIError> {
+        self.energy_meter = Some(EnergyMeter::new(self.pipeline.device())?);
+        self.energy_metrics = Some(Arc::new(EnergyMetrics::default()));
+        Ok(())
+    }
+
+    /// Register an observer of the engine events, see [`crate::openai::observer`].
+    pub fn add_observer(&mut self, observer: Arc<dyn EngineObserver>) {
+        self.observers.push(observer);
+    }
+
+    fn observe(&self, callback: impl Fn(&dyn EngineObserver)) {
+        for observer in &self.observers {
+            callback(&**observer);
+        }
+    }
+
+    /// The finish event of a request whose group ended with `finish_reason`.
+    fn finish_event(group: &SequenceGroup, finish_reason: &str) -> FinishEvent {
+        let seq = group.get_seqs().values().nth(0).unwrap().deref();
+        FinishEvent {
+            request_id: group.request_id.clone(),
+            user: group.user.clone(),
+            finish_reason: finish_reason.to_string(),
+            prompt_tokens: seq.get_prompt_len() + seq.get_num_inherited_tokens(),
+            completion_tokens: seq.get_len()
+                - seq.get_prompt_len()
+                - seq.get_num_inherited_tokens(),
+            duration: SystemTime::now()
+                .duration_since(group.created_time)
+                .unwrap_or_default(),
+        }
+    }
+
     pub fn get_pipeline(&self) -> &dyn ModulePipeline {
         &*self.pipeline
     }
 
+    /// Hash of the version, model, dtypes and device the outputs depend on.
+    fn system_fingerprint(pipeline: &dyn ModulePipeline, cache_config: &CacheConfig) -> String {
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        pipeline.name().hash(&mut hasher);
+        format!(
+            "{:?}|{:?}|{:?}",
+            pipeline.get_dtype(),
+            cache_config.dtype,
+            pipeline.device().location()
+        )
+        .hash(&mut hasher);
+        format!("fp_{:010x}", hasher.finish() >> 24)
+    }
+
     pub fn get_mut_pipeline(&mut self) -> &mut dyn ModulePipeline {
         &mut *self.pipeline
     }
@@ -165,6 +600,7 @@ impl LLMEngine {
             delta: ChoiceData {
                 role: self.pipeline.get_conversation(true).get_roles().0.clone(),
                 content,
+                tool_calls: None,
             },
             finish_reason,
             index: 0,
@@ -176,8 +612,311 @@ impl LLMEngine {
             choices,
             created,
             model: self.pipeline.name().to_string(),
-            object: "chat.completion.chunk",
-            system_fingerprint: None,
+            object: "chat.completion.chunk".to_string(),
+            system_fingerprint: Some(self.system_fingerprint.clone()),
+            usage: None,
+        }
+    }
+
+    /// The final chunk of a stream with `include_usage`, carrying no choices.
+    fn get_usage_chunk(&self, usage: ChatCompletionUsageResponse) -> ChatCompletionChunk {
+        ChatCompletionChunk {
+            id: usage.request_id.clone(),
+            choices: vec![],
+            created: usage.created,
+            model: self.pipeline.name().to_string(),
+            object: "chat.completion.chunk".to_string(),
+            system_fingerprint: Some(self.system_fingerprint.clone()),
+            usage: Some(usage),
+        }
+    }
+
+    /// Token counts of a streaming request so far, `new_tokens` being sent but not yet added
+    /// to the sequence.
+    /// Prompt tokens of `seq` that were not prefilled: its shared prefix and, for a fork, the
+    /// tokens taken over from the forked sequence.
+    fn prompt_tokens_details(seq: &_Sequence) -> PromptTokensDetails {
+        PromptTokensDetails {
+            cached_tokens: seq.get_prefix_cached_len() + seq.get_num_inherited_tokens(),
+        }
+    }
+
+    fn get_running_usage(
+        group: &SequenceGroup,
+        prompt_finish_time: SystemTime,
+        new_tokens: usize,
+    ) -> ChatCompletionUsageResponse {
+        let seq = group.get_seqs().values().nth(0).unwrap();
+        // Tokens inherited by a forked sequence count as prompt
+        let prompt_tokens = seq.deref().get_prompt_len() + seq.deref().get_num_inherited_tokens();
+        let completion_tokens = seq.deref().get_len() - prompt_tokens + new_tokens;
+        let prompt_time_costs = prompt_finish_time
+            .duration_since(group.created_time)
+            .unwrap_or_default()
+            .as_millis();
+        let completion_time_costs = SystemTime::now()
+            .duration_since(prompt_finish_time)
+            .unwrap_or_default()
+            .as_millis();
+        ChatCompletionUsageResponse {
+            request_id: group.request_id.clone(),
+            created: group.arrival_time,
+            completion_tokens,
+            prompt_tokens,
+            total_tokens: completion_tokens + prompt_tokens,
+            prompt_time_costs: prompt_time_costs as usize,
+            completion_time_costs: completion_time_costs as usize,
+            prompt_tokens_details: Some(Self::prompt_tokens_details(&seq.deref())),
+            energy: None,
+        }
+    }
+
+    fn has_followers(&self, request_id: &str) -> bool {
+        self.followers
+            .get(request_id)
+            .is_some_and(|followers| !followers.is_empty())
+    }
+
+    /// Mirror a stream chunk of a leading request to every request coalesced into it,
+    /// dropping the streaming callers that have gone away.
+    fn send_to_followers(
+        &mut self,
+        request_id: &str,
+        content: Option<String>,
+        finish_reason: Option<String>,
+        usage: &ChatCompletionUsageResponse,
+    ) {
+        let Some(mut followers) = self.followers.remove(request_id) else {
+            return;
+        };
+        followers.retain(|follower| match &follower.sender {
+            Some(sender) => {
+                let mut chunk = self.get_stream_response(
+                    follower.request_id.clone(),
+                    follower.created,
+                    content.clone(),
+                    finish_reason.clone(),
+                );
+                if follower.stream_options.continuous_usage_stats {
+                    chunk.usage = Some(ChatCompletionUsageResponse {
+                        request_id: follower.request_id.clone(),
+                        created: follower.created,
+                        ..usage.clone()
+                    });
+                }
+                sender.send(ChatResponse::Chunk(chunk)).is_ok()
+            }
+            None => true,
+        });
+        self.followers.insert(request_id.to_string(), followers);
+    }
+
+    /// Stream generated `content` to the callers of a group, `new_tokens` being sent but not
+    /// yet added to the sequence. Returns false when the caller has gone away and nobody else
+    /// listens, the sequence is then aborted.
+    fn send_content(
+        &mut self,
+        group: &SequenceGroup,
+        prompt_finish_time: SystemTime,
+        content: String,
+        new_tokens: usize,
+    ) -> bool {
+        let usage = Self::get_running_usage(group, prompt_finish_time, new_tokens);
+        if let Some(sender) = &group.sender {
+            let mut chunk = self.get_stream_response(
+                group.request_id.clone(),
+                group.arrival_time,
+                Some(content.clone()),
+                None,
+            );
+            if group.stream_options.continuous_usage_stats {
+                chunk.usage = Some(usage.clone());
+            }
+            let ret = sender.send(ChatResponse::Chunk(chunk));
+            // Keep generating while coalesced callers are still listening.
+            if ret.is_err() && !self.has_followers(&group.request_id) {
+                tracing::warn!(
+                    request_id = %group.request_id,
+                    "Send stream response error!"
+                );
+                let seq = group.get_seqs().values().nth(0).unwrap();
+                seq.deref_mut().set_finish_reason("Abort".to_string());
+                return false;
+            }
+        };
+        self.send_to_followers(&group.request_id, Some(content), None, &usage);
+        true
+    }
+
+    /// Finish the sequence of a group with `finish_reason`, streaming it to its callers.
+    fn send_finish(
+        &mut self,
+        group: &SequenceGroup,
+        prompt_finish_time: SystemTime,
+        finish_reason: String,
+    ) {
+        let usage = Self::get_running_usage(group, prompt_finish_time, 0);
+        if let Some(sender) = &group.sender {
+            let mut chunk = self.get_stream_response(
+                group.request_id.clone(),
+                group.arrival_time,
+                None,
+                Some(finish_reason.clone()),
+            );
+            if group.stream_options.continuous_usage_stats {
+                chunk.usage = Some(usage.clone());
+            }
+            let _ = sender.send(ChatResponse::Chunk(chunk));
+        };
+        self.send_to_followers(&group.request_id, None, Some(finish_reason.clone()), &usage);
+        let seq = group.get_seqs().values().nth(0).unwrap();
+        seq.deref_mut().set_finish_reason(finish_reason)
+    }
+
+    /// Abort the requests of a batch whose forward pass failed, reporting the error to their
+    /// callers, so that the other requests keep being served.
+    fn fail_batch(&mut self, batch: &VecDeque<Arc<SequenceGroup>>, error: &APIError) {
+        for group in batch {
+            let request_id = &group.request_id;
+            tracing::error!(%request_id, event = "failed", "Request failed: {error}");
+            let followers = self.followers.remove(request_id).unwrap_or_default();
+            let senders = group
+                .sender
+                .iter()
+                .chain(followers.iter().filter_map(|f| f.sender.as_ref()));
+            for sender in senders {
+                let _ = sender.send(ChatResponse::ModelError(error.clone()));
+            }
+            // Callers waiting for the whole response read the error once notified
+            for follower in followers.iter() {
+                self.cancel_flags.remove(&follower.request_id);
+                if follower.sender.is_none() {
+                    self.failed_requests
+                        .insert(follower.request_id.clone(), error.clone());
+                }
+            }
+            self.cancel_flags.remove(request_id);
+            self.in_flight
+                .retain(|_, (leader_id, _)| leader_id != request_id);
+            self.scheduler.abort_request(request_id);
+            if group.sender.is_none() {
+                self.failed_requests
+                    .insert(request_id.clone(), error.clone());
+            }
+            let event = Self::finish_event(group, "error");
+            self.observe(|observer| observer.on_finish(&event));
+        }
+    }
+
+    /// Fail every request of the scheduler after an error outside of the forward pass of a batch.
+    fn fail_unfinished(&mut self, error: &APIError) {
+        let groups = self.scheduler.unfinished_groups();
+        if !groups.is_empty() {
+            self.fail_batch(&groups, error);
+        }
+    }
+
+    /// Abort the requests whose client has gone away, that were aborted through
+    /// `/v1/abort/{id}` or that are past their deadline, freeing their KV blocks. A request that
+    /// other callers are coalesced into keeps running for them.
+    fn abort_cancelled_requests(&mut self) {
+        let cancelled =
+            self.cancel_flags
+                .take_cancelled()
+                .into_iter()
+                .map(|(request_id, aborted)| {
+                    let reason = if aborted {
+                        AbortReason::Api
+                    } else {
+                        AbortReason::Disconnected
+                    };
+                    (request_id, reason)
+                });
+        let expired = self
+            .cancel_flags
+            .take_expired()
+            .into_iter()
+            .map(|request_id| (request_id, AbortReason::Timeout));
+        let leaders = std::mem::take(&mut self.cancelled_leaders);
+        for (request_id, reason) in leaders
+            .into_iter()
+            .chain(cancelled)
+            .chain(expired)
+            .collect::<Vec<_>>()
+        {
+            let mut follower = None;
+            for followers in self.followers.values_mut() {
+                if let Some(index) = followers.iter().position(|f| f.request_id == request_id) {
+                    follower = Some(followers.remove(index));
+                }
+            }
+            if let Some(follower) = follower {
+                self.report_aborted(&request_id, follower.sender.as_ref(), reason);
+                continue;
+            }
+            if self.has_followers(&request_id) {
+                self.cancelled_leaders.insert(request_id, reason);
+                continue;
+            }
+            if let Some(group) = self.scheduler.abort_request(&request_id) {
+                let finish_reason = match reason {
+                    AbortReason::Timeout => "timeout",
+                    _ => "abort",
+                };
+                let event = Self::finish_event(&group, finish_reason);
+                self.observe(|observer| observer.on_finish(&event));
+                self.in_flight
+                    .retain(|_, (leader_id, _)| leader_id != &request_id);
+                self.report_aborted(&request_id, group.sender.as_ref(), reason);
+            }
+        }
+    }
+
+    /// Tell the caller of an aborted request that it will get no response, unless it has gone
+    /// away.
+    fn report_aborted(
+        &mut self,
+        request_id: &str,
+        sender: Option<&Sender<ChatResponse>>,
+        reason: AbortReason,
+    ) {
+        let error = match reason {
+            AbortReason::Disconnected => {
+                tracing::info!(
+                    %request_id,
+                    event = "aborted",
+                    reason = "disconnected",
+                    "Request aborted, the client has disconnected."
+                );
+                return;
+            }
+            AbortReason::Api => {
+                tracing::info!(
+                    %request_id,
+                    event = "aborted",
+                    reason = "api",
+                    "Request aborted through the API."
+                );
+                APIError::new(format!("Request {request_id} was aborted."))
+            }
+            AbortReason::Timeout => {
+                tracing::info!(
+                    %request_id,
+                    event = "aborted",
+                    reason = "timeout",
+                    "Request aborted, it exceeded its timeout."
+                );
+                APIError::timeout(format!("Request {request_id} exceeded its timeout."))
+                    .with_code("request_timeout")
+            }
+        };
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(ChatResponse::ModelError(error));
+            }
+            None => {
+                self.failed_requests.insert(request_id.to_string(), error);
+            }
         }
     }
 
@@ -187,85 +926,286 @@ impl LLMEngine {
         let mut responses =
             HashMap::<String, (Vec<ChatChoice>, ChatCompletionUsageResponse)>::new();
         let mut prompt_finish_times = HashMap::<usize, SystemTime>::new();
+        // The GPU idled since the previous run
+        if let Some(meter) = &mut self.energy_meter {
+            meter.read();
+        }
         // let mut prompt_finish_time = SystemTime::now();
         while self.scheduler.has_unfinished_sequences() {
+            self.abort_cancelled_requests();
+            if !self.scheduler.has_unfinished_sequences() {
+                break;
+            }
             let scheduler_outputs = self.scheduler.schedule();
             if !scheduler_outputs.ignored_seq_groups.is_empty() {
-                todo!();
+                // They can never be scheduled, e.g. prompts longer than the whole KV cache or more
+                // sequences than the state cache holds
+                let error = APIError::invalid_request(
+                    "The request does not fit in the cache of the model, shorten the prompt or \
+                    request fewer sequences.",
+                );
+                self.fail_batch(&scheduler_outputs.ignored_seq_groups, &error);
             }
 
-            self.execute_scheduler_ops(&scheduler_outputs).unwrap();
+            self.execute_scheduler_ops(&scheduler_outputs)?;
+            self.engine_stats.record_schedule(
+                self.scheduler.queue_sizes(),
+                scheduler_outputs.blocks_to_swap_in.len(),
+                scheduler_outputs.blocks_to_swap_out.len(),
+            );
+            for (request_id, preemption) in self.scheduler.take_preemptions() {
+                self.engine_stats.record_preemption(preemption);
+                let event = PreemptEvent {
+                    request_id,
+                    preemption,
+                };
+                self.observe(|observer| observer.on_preempt(&event));
+            }
 
             let scheduled: &VecDeque<Arc<SequenceGroup>> = &scheduler_outputs.scheduled;
+            // Everything running was swapped out this step
+            if scheduled.is_empty() {
+                continue;
+            }
             // for group in scheduled.iter() {
-            let seqs = scheduled[0].get_seqs();
-
-            let PreparedInputs {
-                tokens,
-                positions,
-                metadata,
-            } = if seqs.values().nth(0).unwrap().deref().is_prompt() {
-                self.prepare_prompt(scheduled)
+            let is_prompt = scheduled[0]
+                .get_seqs()
+                .values()
+                .nth(0)
+                .unwrap()
+                .deref()
+                .is_prompt();
+            if !self.observers.is_empty() {
+                let event = ScheduleEvent {
+                    request_ids: scheduled
+                        .iter()
+                        .map(|group| group.request_id.clone())
+                        .collect(),
+                    is_prompt,
+                    queue_depth: self.queue_depth.load(Ordering::Relaxed),
+                    blocks_to_swap_in: scheduler_outputs.blocks_to_swap_in.len(),
+                    blocks_to_swap_out: scheduler_outputs.blocks_to_swap_out.len(),
+                    blocks_to_copy: scheduler_outputs
+                        .blocks_to_copy
+                        .values()
+                        .map(Vec::len)
+                        .sum(),
+                };
+                self.observe(|observer| observer.on_schedule(&event));
+            }
+            // Each batch is sampled and streamed once its forward pass is done, see
+            // `prefill_batches`
+            let batches = if is_prompt {
+                prefill_batches(scheduled, self.state_cache.is_some())
             } else {
-                self.prepare_decode(scheduled)
-            }
-            .unwrap();
-
-            let logits = self
-                .pipeline
-                .forward(
-                    tokens,
-                    &positions,
-                    Some(&*self.cache_engine.get_kv_cache()),
-                    metadata,
-                )
-                .unwrap();
-            let results = self.pipeline.sample(logits, scheduled).unwrap();
-
-            for (result_, group) in zip(results, scheduled) {
-                match result_ {
-                    Either::Left(logprobs) => {
-                        let seq = group.get_seqs().values().nth(0).unwrap();
-                        if seq.deref().is_prompt() {
-                            prompt_finish_times.insert(*group.get_id(), SystemTime::now());
+                vec![scheduled.clone()]
+            };
+            for batch in &batches {
+                let step_start = Instant::now();
+                let (logits, num_tokens) = if self.state_cache.is_some() {
+                    match self.forward_recurrent(batch, is_prompt) {
+                        Ok(forwarded) => forwarded,
+                        Err(e) => {
+                            self.fail_batch(batch, &e);
+                            continue;
+                        }
+                    }
+                } else if self.decoder_start_token_id.is_some() {
+                    match self.forward_encoder_decoder(batch, is_prompt) {
+                        Ok(forwarded) => forwarded,
+                        Err(e) => {
+                            self.fail_batch(batch, &e);
+                            continue;
+                        }
+                    }
+                } else {
+                    let prefix_cached = is_prompt
+                        && batch[0]
+                            .get_seqs()
+                            .values()
+                            .nth(0)
+                            .unwrap()
+                            .deref()
+                            .get_prefix_cached_len()
+                            > 0;
+                    let inputs = if !is_prompt {
+                        self.prepare_decode(batch)
+                    } else if prefix_cached {
+                        self.prepare_cached_prefix_prompt(&batch[0])
+                    } else {
+                        self.prepare_prompt(batch)
+                    };
+                    let PreparedInputs {
+                        tokens,
+                        positions,
+                        mut metadata,
+                    } = match inputs {
+                        Ok(inputs) => inputs,
+                        Err(e) => {
+                            self.fail_batch(batch, &e);
+                            continue;
+                        }
+                    };
+                    let num_tokens = tokens.elem_count();
+
+                    let logits = fault::kernel_failure().and_then(|()| {
+                        self.pipeline.forward(
+                            tokens,
+                            &positions,
+                            Some(&*self.cache_engine.get_kv_cache()),
+                            &mut metadata,
+                        )
+                    });
+                    let logits = match logits {
+                        Ok(logits) => logits,
+                        Err(e) => {
+                            self.fail_batch(batch, &e);
+                            continue;
+                        }
+                    };
+                    let logits = if prefix_cached {
+                        // One row per prompt token after the cached prefix, only the last one is sampled
+                        let num_rows = try_api!(logits.dim(0));
+                        try_api!(logits.narrow(0, num_rows - 1, 1))
+                    } else {
+                        logits
+                    };
+                    if let Some(scores) = metadata.attn_scores.take() {
+                        let scores = try_api!(scores.to_vec2::<f32>());
+                        let seqs = batch.iter().flat_map(|group| group.get_seqs().values());
+                        for (seq, seq_scores) in zip(seqs, scores) {
+                            seq.deref_mut().accumulate_token_scores(&seq_scores);
                         }
-                        if let Some(sender) = &group.sender {
-                            let chunk = self.get_stream_response(
-                                group.request_id.clone(),
-                                group.arrival_time,
-                                Some(logprobs.bytes.clone()),
-                                None,
-                            );
-                            let ret = sender.send(ChatResponse::Chunk(chunk));
-                            if ret.is_err() {
-                                println!("Send stream response error!");
-                                seq.deref_mut().set_finish_reason("Abort".to_string());
-                                break;
-                            }
-                        };
-                        // print!("{}", logprobs.bytes.clone());
-                        seq.deref_mut().add_token(logprobs);
                     }
-                    Either::Right(finish_reason) => {
-                        let seq = group.get_seqs().values().nth(0).unwrap();
-                        if let Some(sender) = &group.sender {
-                            let chunk = self.get_stream_response(
-                                group.request_id.clone(),
-                                group.arrival_time,
-                                None,
-                                Some(finish_reason.clone()),
+                    (logits, num_tokens)
+                };
+                if is_prompt {
+                    // The KV of the prompts is written, later prompts can share their prefixes
+                    for group in batch {
+                        self.scheduler.block_engine.cache_prefilled_prefix(group);
+                    }
+                }
+                let results = match self.pipeline.sample(logits, batch) {
+                    Ok(results) => results,
+                    Err(e) => {
+                        self.fail_batch(batch, &e);
+                        continue;
+                    }
+                };
+                self.attribute_energy(batch, is_prompt);
+                self.engine_stats.record_step(
+                    is_prompt,
+                    batch.iter().map(|group| group.get_seqs().len()).sum(),
+                    num_tokens,
+                    Instant::now(),
+                );
+                if !self.observers.is_empty() {
+                    let event = StepEvent {
+                        request_ids: batch.iter().map(|group| group.request_id.clone()).collect(),
+                        is_prompt,
+                        num_tokens,
+                        duration: step_start.elapsed(),
+                    };
+                    self.observe(|observer| observer.on_step(&event));
+                }
+
+                for (result_, group) in zip(results, batch) {
+                    let result_ = match result_ {
+                        Ok(result_) => result_,
+                        Err(e) => {
+                            self.fail_batch(&VecDeque::from([group.clone()]), &e);
+                            continue;
+                        }
+                    };
+                    // The first result of a group, forked groups do not go through the prefill.
+                    let prompt_finish_time = *prompt_finish_times
+                        .entry(*group.get_id())
+                        .or_insert_with(|| {
+                            let now = SystemTime::now();
+                            let ttft_ms = now
+                                .duration_since(group.created_time)
+                                .unwrap_or_default()
+                                .as_millis() as u64;
+                            tracing::info!(
+                                request_id = %group.request_id,
+                                user = group.user.as_deref(),
+                                event = "first_token",
+                                prompt_tokens = group
+                                    .get_seqs()
+                                    .values()
+                                    .map(|seq| seq.deref().get_prompt_len())
+                                    .max(),
+                                ttft_ms,
+                                "Request first token after {ttft_ms} ms."
                             );
-                            sender.send(ChatResponse::Chunk(chunk)).unwrap();
-                        };
-                        seq.deref_mut().set_finish_reason(finish_reason)
+                            now
+                        });
+                    match result_ {
+                        Either::Left(logprobs) => {
+                            let seq = group.get_seqs().values().nth(0).unwrap();
+                            // Text that may start a stop string is held back, nothing is
+                            // sent until it is known whether it does.
+                            let (content, stopped) =
+                                group.stop_buffer().lock().unwrap().push(&logprobs.bytes);
+                            if (!content.is_empty() || content == logprobs.bytes)
+                                && !self.send_content(group, prompt_finish_time, content, 1)
+                            {
+                                // The other groups of the batch keep their tokens
+                                continue;
+                            }
+                            if !self.observers.is_empty() {
+                                let event = TokenEvent {
+                                    request_id: group.request_id.clone(),
+                                    index: 0,
+                                    token: logprobs.token,
+                                    text: logprobs.bytes.clone(),
+                                };
+                                self.observe(|observer| observer.on_token(&event));
+                            }
+                            seq.deref_mut().add_token(logprobs);
+                            if stopped {
+                                self.send_finish(group, prompt_finish_time, "stop".to_string());
+                            }
+                        }
+                        Either::Right(finish_reason) => {
+                            // The text held back was not the start of a stop string
+                            let content = group.stop_buffer().lock().unwrap().flush();
+                            if !content.is_empty() {
+                                self.send_content(group, prompt_finish_time, content, 0);
+                            }
+                            self.send_finish(group, prompt_finish_time, finish_reason);
+                        }
                     }
                 }
             }
 
+            self.scheduler.release_lookahead_slots();
             self.scheduler.free_finished_sequence_groups();
+            if let Some(state_cache) = &mut self.state_cache {
+                // Finished, aborted and failed sequences
+                let running = self.scheduler.running_seq_ids();
+                state_cache.retain(|seq_id| running.contains(&seq_id));
+            } else if self.decoder_start_token_id.is_some() {
+                let running = self.scheduler.running_seq_ids();
+                self.cache_engine
+                    .retain_encoder_decoder(|seq_id| running.contains(&seq_id));
+            }
+            self.scheduler
+                .evict_heavy_hitters(self.cache_config.block_size);
+            if let Some(sliding_window) = self.sliding_window {
+                self.scheduler
+                    .evict_beyond_window(self.cache_config.block_size, sliding_window);
+            }
+            let rebases = self
+                .scheduler
+                .evict_beyond_sinks(self.cache_config.block_size);
+            self.rebase_keys(&rebases)?;
 
             for group in scheduled.iter() {
-                if group.is_finished() && !responses.contains_key(&group.request_id) {
+                if group.is_finished()
+                    && !responses.contains_key(&group.request_id)
+                    && !group.is_aborted()
+                {
                     let end_time = SystemTime::now();
                     let prompt_finish_time = prompt_finish_times[group.get_id()];
                     let completion_time_costs = end_time
@@ -273,13 +1213,32 @@ impl LLMEngine {
                         .unwrap()
                         .as_millis();
                     let seq = group.get_seqs().values().nth(0).unwrap();
-                    let decoded_tokens = seq.deref().get_len() - seq.deref().get_prompt_len();
-                    println!(
-                        "Request {} decoding {} tokens finished in {} seconds",
-                        group.request_id,
+                    let decoded_tokens = seq.deref().get_len()
+                        - seq.deref().get_prompt_len()
+                        - seq.deref().get_num_inherited_tokens();
+                    self.user_metrics
+                        .record_completion(group.user.as_deref(), decoded_tokens);
+                    tracing::info!(
+                        request_id = %group.request_id,
+                        user = group.user.as_deref(),
+                        event = "finished",
+                        phase = "decode",
+                        prompt_tokens = seq.deref().get_prompt_len(),
+                        tokens = decoded_tokens,
+                        duration_ms = completion_time_costs as u64,
+                        e2e_ms = end_time
+                            .duration_since(group.created_time)
+                            .unwrap_or_default()
+                            .as_millis() as u64,
+                        "Request decoding {} tokens finished in {} seconds",
                         decoded_tokens,
                         completion_time_costs / 1000
                     );
+                    if !self.observers.is_empty() {
+                        let finish_reason = seq.deref().get_finish_reason();
+                        let event = Self::finish_event(group, &finish_reason);
+                        self.observe(|observer| observer.on_finish(&event));
+                    }
                     // Create choices from the group
                     let mut seqs = group.get_seqs().values().collect::<Vec<_>>();
                     seqs.sort_by(|seq_a, seq_b| {
@@ -293,7 +1252,8 @@ impl LLMEngine {
 
                     let mut choices = Vec::new();
                     for (index, seq) in top_n.iter().enumerate() {
-                        let outputs = seq.deref_mut().get_output_tokens();
+                        let num_inherited = seq.deref().get_num_inherited_tokens();
+                        let outputs = seq.deref_mut().get_output_tokens()[num_inherited..].to_vec();
                         let data = outputs
                             .iter()
                             .map(|x| x.token.try_into().unwrap())
@@ -303,11 +1263,19 @@ impl LLMEngine {
                             .tokenizer()
                             .tokenizer()
                             .decode(&data, false)
-                            .unwrap();
+                            .map_err(APIError::from)?;
+                        // A stop string is not part of the output
+                        let data = group
+                            .stop_buffer()
+                            .lock()
+                            .unwrap()
+                            .truncate(&data)
+                            .to_string();
                         let choice = ChatChoice {
                             message: ChatChoiceData {
                                 role: self.pipeline.get_conversation(true).get_roles().0.clone(),
                                 content: Some(data),
+                                tool_calls: None,
                             },
                             finish_reason: Some(seq.deref_mut().get_finish_reason().clone()),
                             index,
@@ -322,9 +1290,14 @@ impl LLMEngine {
 
                     let completion_tokens = top_n
                         .iter()
-                        .map(|seq| seq.deref().get_len() - seq.deref().get_prompt_len())
+                        .map(|seq| {
+                            seq.deref().get_len()
+                                - seq.deref().get_prompt_len()
+                                - seq.deref().get_num_inherited_tokens()
+                        })
                         .sum();
-                    let prompt_tokens = top_n.first().unwrap().deref().get_prompt_len();
+                    let first = top_n.first().unwrap().deref();
+                    let prompt_tokens = first.get_prompt_len() + first.get_num_inherited_tokens();
 
                     let prompt_time_costs = prompt_finish_time
                         .duration_since(group.created_time)
@@ -339,36 +1312,114 @@ impl LLMEngine {
                         total_tokens: completion_tokens + prompt_tokens,
                         prompt_time_costs: prompt_time_costs as usize,
                         completion_time_costs: completion_time_costs as usize,
+                        prompt_tokens_details: Some(Self::prompt_tokens_details(&first)),
+                        energy: self.energy_meter.as_ref().map(|_| {
+                            let joules = group.energy();
+                            let duration = end_time
+                                .duration_since(group.created_time)
+                                .unwrap_or_default();
+                            EnergyUsage {
+                                joules,
+                                avg_power_watts: energy::average_power(joules, duration),
+                            }
+                        }),
                     };
 
-                    responses.insert(group.request_id.clone(), (choices, usage));
+                    self.in_flight
+                        .retain(|_, (request_id, _)| request_id != &group.request_id);
+                    self.cancel_flags.remove(&group.request_id);
+                    for follower in self.followers.remove(&group.request_id).unwrap_or_default() {
+                        self.cancel_flags.remove(&follower.request_id);
+                        let usage = ChatCompletionUsageResponse {
+                            request_id: follower.request_id.clone(),
+                            created: follower.created,
+                            ..usage.clone()
+                        };
+                        if let Some(sender) = &follower.sender {
+                            if follower.stream_options.include_usage {
+                                let chunk = self.get_usage_chunk(usage.clone());
+                                let _ = sender.send(ChatResponse::Chunk(chunk));
+                            }
+                            let _ = sender.send(ChatResponse::Done);
+                        }
+                        responses.insert(follower.request_id, (choices.clone(), usage));
+                    }
 
                     if let Some(sender) = &group.sender {
+                        if group.stream_options.include_usage {
+                            let chunk = self.get_usage_chunk(usage.clone());
+                            let _ = sender.send(ChatResponse::Chunk(chunk));
+                        }
                         let _ = sender.send(ChatResponse::Done);
                     };
+                    responses.insert(group.request_id.clone(), (choices, usage));
                 }
             }
         }
+        self.update_kv_cache_usage();
         self.pipeline.reset_decoder();
         Ok(responses)
     }
 }
 
 impl LLMEngine {
+    /// Split the GPU energy used since the previous reading between the groups of `batch`, by
+    /// the tokens each one computed in it.
+    fn attribute_energy(&mut self, batch: &VecDeque<Arc<SequenceGroup>>, is_prompt: bool) {
+        let (Some(meter), Some(metrics)) = (&mut self.energy_meter, &self.energy_metrics) else {
+            return;
+        };
+        let (joules, duration) = meter.read();
+        let tokens = batch
+            .iter()
+            .map(|group| {
+                if is_prompt {
+                    group
+                        .get_seqs()
+                        .values()
+                        .map(|seq| seq.deref().get_len() - seq.deref().get_prefix_cached_len())
+                        .sum()
+                } else {
+                    group.get_seqs().len()
+                }
+            })
+            .collect::<Vec<_>>();
+        for (group, joules) in zip(batch, energy::attribute(joules, &tokens)) {
+            group.add_energy(joules);
+        }
+        metrics.record(is_prompt, joules, duration);
+    }
+
+    fn update_kv_cache_usage(&self) {
+        self.kv_cache_metrics.used_gpu_blocks.store(
+            self.scheduler.block_engine.num_used_gpu_blocks(),
+            Ordering::Relaxed,
+        );
+        self.engine_stats
+            .record_blocks(self.scheduler.block_engine.block_stats());
+    }
+
     fn execute_scheduler_ops(
         &mut self,
         scheduler_output: &SchedulerOutput,
     ) -> Result<(), APIError> {
-        if !scheduler_output.blocks_to_swap_in.is_empty() {
-            try_api!(self
-                .cache_engine
-                .swap_in(scheduler_output.blocks_to_swap_in.clone()));
+        self.update_kv_cache_usage();
+        // Swapped out first, the GPU blocks of spilled prefixes may receive swapped in blocks
+        if !scheduler_output.blocks_to_swap_out.is_empty()
+            || !scheduler_output.blocks_to_swap_in.is_empty()
+        {
+            fault::delay_swap();
         }
         if !scheduler_output.blocks_to_swap_out.is_empty() {
             try_api!(self
                 .cache_engine
                 .swap_out(scheduler_output.blocks_to_swap_out.clone()));
         }
+        if !scheduler_output.blocks_to_swap_in.is_empty() {
+            try_api!(self
+                .cache_engine
+                .swap_in(scheduler_output.blocks_to_swap_in.clone()));
+        }
         if !scheduler_output.blocks_to_copy.is_empty() {
             try_api!(self
                 .cache_engine
@@ -377,6 +1428,250 @@ impl LLMEngine {
         Ok(())
     }
 
+    /// Leading tokens of a prompt, with a shareable prefix of `prefix_len` tokens, that the
+    /// prefix cache currently holds.
+    pub fn cached_prefix_len(&self, prompt: &[u32], prefix_len: usize) -> usize {
+        let prompt = prompt.iter().map(|&id| id as usize).collect::<Vec<_>>();
+        self.scheduler
+            .block_engine
+            .cached_prefix_len(&prompt, prefix_len)
+    }
+
+    fn rebase_keys(&self, rebases: &[KeyRebase]) -> Result<(), APIError> {
+        let config = self.pipeline.get_model_config();
+        for rebase in rebases {
+            let (cos, sin) = try_api!(config.get_rope_rebase(rebase.delta, self.pipeline.device()));
+            self.cache_engine
+                .rebase_keys(&rebase.blocks, &cos, &sin, config.rope_interleaved)?;
+        }
+        Ok(())
+    }
+
+    pub fn kv_cache_dtype(&self) -> DType {
+        self.cache_config.dtype
+    }
+
+    /// Whether the scheduler reserves slots for speculative tokens.
+    pub fn speculative_decoding(&self) -> bool {
+        self.scheduler.num_lookahead_slots() > 0
+    }
+
+    /// Whether requests are waiting, running or swapped out.
+    pub fn has_unfinished_requests(&self) -> bool {
+        self.scheduler.has_unfinished_sequences()
+    }
+
+    /// Stop the generation loop, which drops its reference to the engine so that the model and
+    /// its KV cache are freed with the last one. Requests must be drained first, the ones left
+    /// are never run.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+        self.notify.notify_one();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Check that the attention sinks of a request can be served: the keys must be re-rotatable
+    /// and the sinks and twice the window (positions are compacted once per window) must fit in
+    /// the context length.
+    pub fn check_attention_sinks(&self, sinks: &AttentionSinks) -> Result<(), APIError> {
+        let config = self.pipeline.get_model_config();
+        let unsupported = if self.pipeline.is_encoder_only() {
+            Some("the model does not generate")
+        } else if self.state_cache.is_some() {
+            Some("recurrent models have no KV cache")
+        } else if self.decoder_start_token_id.is_some() {
+            Some("encoder-decoder models do not use the paged KV cache")
+        } else if config.rope_scaling.is_some() || config.specific_config.self_extend.is_some() {
+            Some("rope scaling and Self-Extend are not supported")
+        } else if config.mla_config.is_some() {
+            Some("latent attention caches are not supported")
+        } else if self.sliding_window.is_some() {
+            Some("sliding window models are not supported")
+        } else if self.track_attn_scores {
+            Some("they cannot be combined with heavy-hitter eviction")
+        } else if self.cache_config.dtype == DType::U8 {
+            Some("the int8 KV cache is not supported")
+        } else {
+            None
+        };
+        if let Some(reason) = unsupported {
+            return Err(APIError::new(format!(
+                "`attention_sinks` cannot be used, {reason}."
+            )));
+        }
+        let block_size = self.cache_config.block_size;
+        let max_len = sinks.num_sink_tokens.div_ceil(block_size) * block_size
+            + 2 * sinks.window
+            + 2 * block_size;
+        let max_model_len = config.get_max_model_len();
+        if sinks.window < block_size || max_len > max_model_len {
+            return Err(APIError::new(format!(
+                "The `attention_sinks` window must be at least {block_size} tokens, and the sink \
+                tokens and twice the window must fit in the context length ({} of {max_model_len} \
+                tokens).",
+                max_len - 2 * block_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that a prompt of `prompt_len` tokens and `max_tokens` generated tokens fit in the
+    /// context of the model, the forward pass cannot go beyond it.
+    pub fn check_context_length(
+        &self,
+        prompt_len: usize,
+        max_tokens: usize,
+    ) -> Result<(), APIError> {
+        let max_model_len = self.pipeline.get_model_config().get_max_model_len();
+        if prompt_len + max_tokens > max_model_len {
+            return Err(APIError::new(format!(
+                "This model's maximum context length is {max_model_len} tokens. However, you \
+                requested {} tokens ({prompt_len} in the messages, {max_tokens} in the \
+                completion). Please clear the chat history, reduce the length of the messages or \
+                set `truncate_prompt_tokens`.",
+                prompt_len + max_tokens
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that the `n` and `best_of` sequences of a request fit in the state cache of a
+    /// recurrent or encoder-decoder model, the scheduler never admits a group with more
+    /// sequences than slots.
+    pub fn check_num_seqs(&self, n: usize, best_of: Option<usize>) -> Result<(), APIError> {
+        let num_seqs = best_of.unwrap_or(n).max(n);
+        match self.scheduler.state_slots() {
+            Some(num_slots) if num_seqs > num_slots => {
+                let param = if best_of.is_some_and(|best_of| best_of > n) {
+                    "best_of"
+                } else {
+                    "n"
+                };
+                Err(APIError::invalid_request(format!(
+                    "This model serves at most {num_slots} sequences at once, however `{param}` \
+                    requests {num_seqs}."
+                ))
+                .with_param(param))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the sequences keep their state (recurrent models) or keys and values
+    /// (encoder-decoder models) outside the paged KV cache.
+    fn without_kv_blocks(&self) -> bool {
+        self.state_cache.is_some() || self.decoder_start_token_id.is_some()
+    }
+
+    /// Forward a batch of an encoder-decoder model. The prompts of a prefill run through the
+    /// encoder once per group, and the cross-attention keys and values of its output are cached
+    /// for the decoding steps. The decoder then runs one token per sequence: the decoder start
+    /// token for a prefill, the last generated token otherwise. Returns the logits and the
+    /// number of tokens forwarded.
+    fn forward_encoder_decoder(
+        &mut self,
+        batch: &VecDeque<Arc<SequenceGroup>>,
+        is_prompt: bool,
+    ) -> Result<(Tensor, usize), APIError> {
+        let decoder_start_token_id = self.decoder_start_token_id.unwrap_or(0);
+        let mut num_tokens = 0;
+        if is_prompt {
+            for group in batch {
+                let seqs = group.get_seqs();
+                let Some(seq) = seqs.values().next() else {
+                    continue;
+                };
+                let prompt = seq.deref().get_token_ids();
+                num_tokens += prompt.len();
+                let prompt = prompt.into_iter().map(|x| x as i64).collect::<Vec<_>>();
+                let len = prompt.len();
+                let prompt = _make_tensor_with_pad(vec![prompt], len, 0, self.pipeline.device())?;
+                let cross = fault::kernel_failure().and_then(|()| self.pipeline.encode(prompt))?;
+                for seq in seqs.values() {
+                    self.cache_engine
+                        .set_cross_attention(seq.deref().get_id(), cross.clone());
+                }
+            }
+        }
+        let seqs = batch
+            .iter()
+            .flat_map(|group| group.get_seqs().values())
+            .collect::<Vec<_>>();
+        let seq_ids = seqs
+            .iter()
+            .map(|seq| seq.deref().get_id())
+            .collect::<Vec<_>>();
+        let input_tokens = seqs
+            .iter()
+            .map(|seq| {
+                if is_prompt {
+                    vec![decoder_start_token_id as i64]
+                } else {
+                    vec![seq.deref().get_last_token_id() as i64]
+                }
+            })
+            .collect::<Vec<_>>();
+        num_tokens += input_tokens.len();
+        let tokens = _make_tensor_with_pad(input_tokens, 1, 0, self.pipeline.device())?;
+        let mut kv = self.cache_engine.take_encoder_decoder(&seq_ids)?;
+        let logits =
+            fault::kernel_failure().and_then(|()| self.pipeline.forward_decoder(tokens, &mut kv));
+        self.cache_engine.put_encoder_decoder(&seq_ids, kv);
+        Ok((logits?, num_tokens))
+    }
+
+    /// Forward a batch of a recurrent model over the states of its sequences: the prompts of a
+    /// prefill, allocating their states, or the last token of each sequence. Returns the
+    /// logits and the number of tokens forwarded.
+    fn forward_recurrent(
+        &mut self,
+        batch: &VecDeque<Arc<SequenceGroup>>,
+        is_prompt: bool,
+    ) -> Result<(Tensor, usize), APIError> {
+        let seqs = batch
+            .iter()
+            .flat_map(|group| group.get_seqs().values())
+            .collect::<Vec<_>>();
+        let seq_ids = seqs
+            .iter()
+            .map(|seq| seq.deref().get_id())
+            .collect::<Vec<_>>();
+        // Prompts are prefilled one group at a time, its sequences share the prompt
+        let input_tokens = seqs
+            .iter()
+            .map(|seq| {
+                let seq = seq.deref();
+                let tokens = if is_prompt {
+                    seq.get_token_ids()
+                } else {
+                    vec![seq.get_last_token_id()]
+                };
+                tokens.into_iter().map(|x| x as i64).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let seq_len = input_tokens.iter().map(Vec::len).max().unwrap_or(0);
+        let num_tokens = seq_len * input_tokens.len();
+        let tokens = _make_tensor_with_pad(input_tokens, seq_len, 0, self.pipeline.device())?;
+        let state_cache = self.state_cache.as_mut().unwrap();
+        if is_prompt {
+            for seq_id in &seq_ids {
+                let state = self
+                    .pipeline
+                    .recurrent_state()?
+                    .ok_or_else(|| APIError::new_str("The model has no recurrent state."))?;
+                state_cache.allocate(*seq_id, state)?;
+            }
+        }
+        let mut state = state_cache.gather(&seq_ids)?;
+        let logits = fault::kernel_failure()
+            .and_then(|()| self.pipeline.forward_recurrent(tokens, &mut state))?;
+        state_cache.scatter(&seq_ids, &state)?;
+        Ok((logits, num_tokens))
+    }
+
     fn prepare_prompt(
         &self,
         groups: &VecDeque<Arc<SequenceGroup>>,
@@ -410,30 +1705,15 @@ impl LLMEngine {
                     .map(|block| block.deref_mut().block_id)
                     .collect::<Vec<_>>();
 
-                let start_idx = if let Some(sliding_window) = self.sliding_window {
-                    if prompt_len > sliding_window {
-                        0.min(prompt_len - sliding_window)
-                    } else {
-                        0
-                    }
-                } else {
-                    0
-                };
-
                 let mut slot_mapping = Vec::new();
                 for i in 0..prompt_len {
-                    if i < start_idx {
-                        // Pad [0,start_idx) with _PAD_TOKEN_ID
-                        slot_mapping.push(_PAD_SLOT_ID);
-                    }
-
                     let block_number = if i / self.cache_config.block_size >= table.len() {
-                        panic!(
+                        return Err(APIError::new(format!(
                             "Block table is too small (prompt)! i={} block_size={} table_len={}",
                             i,
                             self.cache_config.block_size,
                             table.len()
-                        );
+                        )));
                     } else {
                         table.get(i / self.cache_config.block_size).unwrap()
                     };
@@ -466,14 +1746,19 @@ impl LLMEngine {
             tokens: input_tokens,
             positions: input_positions,
             metadata: InputMetadata {
+                attn_bias: Some(BlockDiagonalCausalMask::from_seqlens(
+                    prompt_lens.iter().map(|len| *len as u32).collect(),
+                    None,
+                )?),
                 prompt_lens,
                 slot_mapping,
                 max_context_len: None,
                 context_lens: None,
                 block_tables: None,
-                attn_bias: None,
                 is_prompt: true,
                 kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
+                track_attn_scores: self.track_attn_scores,
+                attn_scores: None,
             },
         })
     }
@@ -492,15 +1777,14 @@ impl LLMEngine {
                 let last_token_id = seq.deref_mut().get_last_token_id();
                 input_tokens.push(vec![last_token_id]);
 
-                let position = seq.deref_mut().get_len() - 1;
+                let position = seq.deref_mut().get_last_position();
                 input_positions.push(vec![position]);
+                // Differs from `position` once heavy-hitter eviction dropped cached blocks.
+                let cache_position = seq.deref_mut().get_cached_len() - 1;
 
-                let context_len = if let Some(sliding_window) = self.sliding_window {
-                    seq.deref_mut().get_len().min(sliding_window)
-                } else {
-                    seq.deref_mut().get_len()
-                };
-                context_lens.push(context_len);
+                // Sliding window models attend to the end of the context only, their blocks
+                // before the window were evicted.
+                context_lens.push(seq.deref_mut().get_cached_len());
 
                 let table = self
                     .scheduler
@@ -513,27 +1797,18 @@ impl LLMEngine {
                     .map(|block| block.deref_mut().block_id)
                     .collect::<Vec<_>>();
 
-                let block_number = if position / self.cache_config.block_size >= table.len() {
-                    panic!("Block table is too small (completion)! start_pos={} block_size={} table_len={}", position, self.cache_config.block_size, table.len());
+                let block_number = if cache_position / self.cache_config.block_size >= table.len() {
+                    return Err(APIError::new(format!("Block table is too small (completion)! start_pos={} block_size={} table_len={}", cache_position, self.cache_config.block_size, table.len())));
                 } else {
-                    table.get(position / self.cache_config.block_size).unwrap()
+                    table
+                        .get(cache_position / self.cache_config.block_size)
+                        .unwrap()
                 };
-                let block_offset = position % self.cache_config.block_size;
+                let block_offset = cache_position % self.cache_config.block_size;
                 let slot = block_number * self.cache_config.block_size + block_offset;
                 let slot = slot.try_into().unwrap();
                 slot_mappings.push(vec![slot]);
-
-                if let Some(sliding_window) = self.sliding_window {
-                    let sliding_window_blocks = sliding_window / self.cache_config.block_size;
-                    let slide_idx = if table.len() > sliding_window_blocks {
-                        table.len() - sliding_window_blocks
-                    } else {
-                        0
-                    };
-                    block_tables.push(table.get(slide_idx..).unwrap().to_vec());
-                } else {
-                    block_tables.push(table);
-                }
+                block_tables.push(table);
             }
         }
 
@@ -579,20 +1854,163 @@ impl LLMEngine {
                 attn_bias: None,
                 is_prompt: false,
                 kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
+                track_attn_scores: self.track_attn_scores,
+                attn_scores: None,
             },
         })
     }
 
-    pub fn add_request(
-        &mut self,
-        prompt: Encoding,
-        request_id: String,
-        created: SystemTime,
-        sampling_params: SamplingParams,
-        use_logprobs: bool,
-        sender: Option<Sender<ChatResponse>>,
-    ) {
+    /// Prefill of a prompt whose leading blocks are shared from the prefix cache. Only the tokens
+    /// after the cached prefix are computed, each as its own decode query: the KV of all of them
+    /// is written to the cache first, then token `p` attends over the first `p + 1` cached slots.
+    fn prepare_cached_prefix_prompt(
+        &self,
+        group: &SequenceGroup,
+    ) -> Result<PreparedInputs, APIError> {
+        let seq = group.get_seqs().values().nth(0).unwrap();
+        let token_ids = seq.deref().get_token_ids();
+        let prefix_cached_len = seq.deref().get_prefix_cached_len();
+        let table = self
+            .scheduler
+            .block_engine
+            .block_tables
+            .get(&seq.deref().get_id())
+            .unwrap()
+            .iter()
+            .map(|block| block.deref_mut().block_id as u32)
+            .collect::<Vec<_>>();
+
+        let mut input_tokens = Vec::new();
+        let mut input_positions = Vec::new();
+        let mut slot_mapping = Vec::new();
+        let mut context_lens = Vec::new();
+        for (position, token_id) in token_ids.iter().enumerate().skip(prefix_cached_len) {
+            input_tokens.push(*token_id as i64);
+            input_positions.push(vec![position]);
+            let block_number = table[position / self.cache_config.block_size] as usize;
+            let slot = block_number * self.cache_config.block_size
+                + position % self.cache_config.block_size;
+            slot_mapping.push(slot as i64);
+            context_lens.push(position as u32 + 1);
+        }
+        let num_tokens = input_tokens.len();
+        tracing::info!(
+            request_id = %group.request_id,
+            cached_tokens = prefix_cached_len,
+            tokens = num_tokens,
+            "Request reused {} cached prompt tokens, prefilling {} tokens.",
+            prefix_cached_len,
+            num_tokens
+        );
+        let device = self.pipeline.device();
+        let input_tokens = try_api!(Tensor::from_vec(input_tokens, (num_tokens, 1), device));
+        let slot_mapping = try_api!(Tensor::from_vec(slot_mapping, (num_tokens, 1), device));
+        let context_lens = try_api!(Tensor::from_vec(context_lens, num_tokens, device));
+        let block_tables = table.repeat(num_tokens);
+        let block_tables = try_api!(Tensor::from_vec(
+            block_tables,
+            (num_tokens, table.len()),
+            device
+        ));
+        Ok(PreparedInputs {
+            tokens: input_tokens,
+            positions: input_positions,
+            metadata: InputMetadata {
+                prompt_lens: vec![],
+                slot_mapping,
+                max_context_len: Some(token_ids.len()),
+                context_lens: Some(context_lens),
+                block_tables: Some(block_tables),
+                attn_bias: None,
+                is_prompt: false,
+                kv_cache_dtype: "auto".to_string(), // TODO(EricLBuehler): specialize for models
+                track_attn_scores: false,
+                attn_scores: None,
+            },
+        })
+    }
+
+    pub fn add_request(&mut self, request: NewRequest) {
+        let NewRequest {
+            prompt,
+            prefix_len,
+            request_id,
+            created,
+            sampling_params,
+            use_logprobs,
+            sender,
+            stream_options,
+            cancel,
+            forkable,
+            user,
+            attention_sinks,
+            priority,
+            cache_priority,
+            session_id,
+        } = request;
+        if self.stopped {
+            // Handed over after the engine was drained for a reload
+            if let Some(sender) = sender {
+                let _ = sender.send(ChatResponse::ModelError(APIError::new_str(
+                    "The model was unloaded, retry the request.",
+                )));
+            }
+            return;
+        }
         let prompt_len = prompt.get_ids().len();
+        self.cancel_flags.insert(request_id.clone(), cancel);
+        self.user_metrics
+            .record_request(user.as_deref(), prompt_len);
+        // The blocks of sliding window models are evicted once out of the window, and cached
+        // prefill produces no attention scores for eviction. The keys of attention sinks
+        // sessions are rotated in place, they are not shared.
+        // Recurrent and encoder-decoder models have no KV blocks to share.
+        let shareable = self.sliding_window.is_none()
+            && !self.track_attn_scores
+            && attention_sinks.is_none()
+            && !self.without_kv_blocks();
+        // A forkable request, or one whose KV is kept for its session, needs a sequence group of
+        // its own.
+        let coalesce_key = if sampling_params.is_deterministic(self.pipeline.is_greedy())
+            && !forkable
+            && attention_sinks.is_none()
+            && session_id.is_none()
+        {
+            Some((
+                prompt.get_ids().to_vec(),
+                format!("{:?}|{}", sampling_params, use_logprobs),
+            ))
+        } else {
+            None
+        };
+        // Only into a request that has not generated yet, a follower gets the chunks from then on
+        if let Some((leader_id, _)) = coalesce_key
+            .as_ref()
+            .and_then(|key| self.in_flight.get(key))
+            .filter(|(_, leader_seq)| {
+                let leader_seq = leader_seq.deref();
+                leader_seq.get_len() == leader_seq.get_prompt_len()
+            })
+            .cloned()
+        {
+            let follower = Follower {
+                request_id: request_id.clone(),
+                created: get_created_time_secs(),
+                sender,
+                stream_options,
+            };
+            self.followers
+                .entry(leader_id.clone())
+                .or_default()
+                .push(follower);
+            tracing::info!(
+                %request_id,
+                user = user.as_deref(),
+                %leader_id,
+                "Request coalesced with an identical in-flight request."
+            );
+            return;
+        }
         let seq = Arc::new(Sequence(std::sync::RwLock::new(_Sequence::new(
             prompt
                 .get_ids()
@@ -604,6 +2022,10 @@ impl LLMEngine {
             self.cache_config.block_size,
         ))));
         self.seq_id += 1;
+        if let Some(key) = coalesce_key {
+            self.in_flight
+                .insert(key, (request_id.clone(), seq.clone()));
+        }
         let seq_group = SequenceGroup::new(
             &[seq],
             get_created_time_secs(),
@@ -613,14 +2035,142 @@ impl LLMEngine {
             sampling_params,
             use_logprobs,
             sender,
-        );
+            stream_options,
+            if shareable { prefix_len } else { 0 },
+            user,
+        )
+        .with_attention_sinks(attention_sinks)
+        .with_priority(priority)
+        .with_cache_priority(cache_priority)
+        .with_session_id(session_id.filter(|_| shareable));
         self.group_id += 1;
 
-        self.scheduler.add_sequence(seq_group);
-        println!(
-            "Request {} with length {} added to sequence group.",
-            request_id.clone(),
-            prompt_len
+        if forkable && !self.without_kv_blocks() {
+            self.scheduler.set_forkable(request_id.clone());
+        }
+        tracing::info!(
+            %request_id,
+            user = seq_group.user.as_deref(),
+            event = "queued",
+            prompt_tokens = prompt_len,
+            "Request added to sequence group."
         );
+        let start = RequestStart {
+            request_id,
+            user: seq_group.user.clone(),
+            prompt_tokens: prompt_len,
+            max_tokens: seq_group.sampling_params.max_tokens,
+        };
+        self.scheduler.add_sequence(seq_group);
+        self.observe(|observer| observer.on_request_start(&start));
+    }
+
+    /// Continue the finished generation of a forkable request in one branch per id of
+    /// `branch_ids`, each starting after the first `num_tokens` generated tokens of the parent
+    /// (all of them by default). The branches share the KV blocks of the parent instead of
+    /// prefilling its prompt again. The sampling parameters of the request override the
+    /// parent's, `max_tokens` counting the tokens generated after the branching point.
+    pub fn fork_request(
+        &mut self,
+        request: &ForkRequest,
+        branch_ids: &[String],
+        created: SystemTime,
+    ) -> Result<(), APIError> {
+        let parent_id = &request.request_id;
+        if self.without_kv_blocks() {
+            return Err(APIError::new_str(
+                "Requests of recurrent and encoder-decoder models cannot be forked.",
+            ));
+        }
+        let Some(parent) = self.scheduler.get_retained(parent_id) else {
+            return Err(APIError::new(format!(
+                "Request {parent_id} cannot be forked, it must be sent with `forkable` set and \
+                may have been released under KV cache pressure."
+            )));
+        };
+        if parent.sampling_params.guide.is_some() {
+            return Err(APIError::new_str(
+                "Requests with guided decoding cannot be forked.",
+            ));
+        }
+        let parent_seq = parent.get_seqs().values().nth(0).unwrap().clone();
+        if parent_seq.deref().get_cached_len() != parent_seq.deref().get_len() {
+            return Err(APIError::new_str(
+                "Requests with evicted KV cache blocks cannot be forked.",
+            ));
+        }
+        let outputs = parent_seq.deref().get_output_tokens();
+        let num_tokens = request.num_tokens.unwrap_or(outputs.len());
+        if num_tokens == 0 || num_tokens > outputs.len() {
+            return Err(APIError::new(format!(
+                "`num_tokens` must be between 1 and {}, the number of tokens generated by request {parent_id}.",
+                outputs.len()
+            )));
+        }
+        let params = &parent.sampling_params;
+        let mut branch_params = SamplingParams::new(
+            1,
+            None,
+            params.presence_penalty,
+            params.frequency_penalty,
+            params.repetition_penalty,
+            request.temperature.unwrap_or(params.temperature),
+            request.top_p.unwrap_or(params.top_p),
+            request.top_k.unwrap_or(params.top_k),
+            false,
+            params.length_penalty,
+            params.early_stopping.clone(),
+            params.stop.clone(),
+            params.stop_token_ids.clone(),
+            params.ignore_eos,
+            num_tokens + request.max_tokens.unwrap_or(params.max_tokens),
+            params.logprobs,
+            params.prompt_logprobs,
+            params.skip_special_tokens,
+        )?;
+        branch_params.logit_bias = params.logit_bias.clone();
+        branch_params.min_p = params.min_p;
+        branch_params.typical_p = params.typical_p;
+        let prompt_ids =
+            parent_seq.deref().get_token_ids()[..parent_seq.deref().get_prompt_len()].to_vec();
+
+        for branch_id in branch_ids {
+            let mut seq = _Sequence::new(
+                prompt_ids.clone(),
+                self.seq_id,
+                self.cache_config.block_size,
+            );
+            for logprobs in &outputs[..num_tokens] {
+                seq.add_token(logprobs.clone());
+            }
+            seq.set_num_inherited_tokens(num_tokens);
+            let seq = Arc::new(Sequence(std::sync::RwLock::new(seq)));
+            self.seq_id += 1;
+            let seq_group = SequenceGroup::new(
+                &[seq],
+                get_created_time_secs(),
+                self.group_id,
+                branch_id.clone(),
+                created,
+                branch_params.clone(),
+                parent.use_logprobs,
+                None,
+                StreamOptions::default(),
+                0,
+                parent.user.clone(),
+            )
+            .with_priority(parent.priority);
+            self.group_id += 1;
+            if !self.scheduler.fork(&parent_seq, seq_group) {
+                return Err(APIError::new(format!(
+                    "Not enough KV cache blocks to fork request {parent_id}."
+                )));
+            }
+            if request.forkable.unwrap_or(false) {
+                self.scheduler.set_forkable(branch_id.clone());
+            }
+            tracing::info!(request_id = %branch_id, %parent_id, "Request forked.");
+        }
+        Ok(())
     }
 }
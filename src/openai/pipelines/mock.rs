@@ -0,0 +1,522 @@
+use super::{LoadOptions, ModelLoader, ModelPaths, ModulePipeline, TokenOrFinishReason};
+use crate::openai::models::TokenID;
+use crate::openai::sampling_params::{Logprobs, SamplingParams, TopLogprob};
+use crate::scheduler::cache_engine::{EncoderDecoderKv, KVCache};
+use crate::scheduler::sequence::{Sequence, SequenceGroup};
+use crate::{
+    openai::{
+        conversation::{
+            default_conversation::{
+                DefaultConversation, DefaultConversationSeparators, SeparatorStyle,
+            },
+            Conversation,
+        },
+        models::Config,
+        requests::Pooling,
+        responses::APIError,
+        PipelineConfig,
+    },
+    paged_attention::input_metadata::InputMetadata,
+    SpecificConfig,
+};
+use candle_core::{DType, Device, Tensor};
+use candle_examples::token_output_stream::TokenOutputStream;
+use either::Either::{Left, Right};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokenizers::Tokenizer;
+
+use super::pipeline::{DefaultModelPaths, MAX_GEN_TOKENS, MIN_GEN_TOKENS};
+
+const END_OF_TEXT: u32 = 256;
+const IM_START: u32 = 257;
+const IM_END: u32 = 258;
+const USER_TURN: &str = "<|im_start|>user\n";
+
+/// Loads the mock model: nothing is downloaded and no weights are read.
+pub struct MockLoader {
+    response: Option<String>,
+    prefill_latency: Duration,
+    latency: Duration,
+    max_model_len: usize,
+    max_gen_tokens: Option<usize>,
+    recurrent: bool,
+}
+
+/// A model without weights for developing clients against the API. It generates `response`,
+/// or echoes the last user message, one byte per step after waiting `latency`. Replies holding
+/// a `<tool_call>` are parsed as tool calls since the conversation uses the Qwen2 template.
+/// A `recurrent` mock keeps a state per sequence, the number of tokens it forwarded, instead
+/// of KV cache blocks.
+pub struct MockPipeline {
+    response: Option<String>,
+    prefill_latency: Duration,
+    latency: Duration,
+    recurrent: bool,
+    config: Config,
+    tokenizer: TokenOutputStream,
+    conversation: DefaultConversation,
+    dtype: DType,
+    device: Device,
+}
+
+impl MockLoader {
+    pub fn new(
+        response: Option<String>,
+        prefill_latency_ms: u64,
+        latency_ms: u64,
+        max_model_len: usize,
+        max_gen_tokens: Option<usize>,
+        recurrent: bool,
+    ) -> Self {
+        Self {
+            response,
+            prefill_latency: Duration::from_millis(prefill_latency_ms),
+            latency: Duration::from_millis(latency_ms),
+            max_model_len,
+            max_gen_tokens,
+            recurrent,
+        }
+    }
+}
+
+/// The printable characters GPT-2 style byte-level tokenizers use for the 256 bytes.
+fn byte_symbols() -> Vec<char> {
+    let mut n = 0;
+    (0..=255u8)
+        .map(|b| {
+            if matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF) {
+                char::from(b)
+            } else {
+                n += 1;
+                char::from_u32(255 + n).unwrap()
+            }
+        })
+        .collect()
+}
+
+/// A byte-level tokenizer without merges: token `b` is byte `b`, followed by the ChatML
+/// special tokens. Any text round-trips through it.
+fn mock_tokenizer() -> Result<Tokenizer, APIError> {
+    let vocab = byte_symbols()
+        .into_iter()
+        .enumerate()
+        .map(|(id, symbol)| (symbol.to_string(), serde_json::json!(id)))
+        .collect::<serde_json::Map<_, _>>();
+    let added_tokens = [
+        (END_OF_TEXT, "<|endoftext|>"),
+        (IM_START, "<|im_start|>"),
+        (IM_END, "<|im_end|>"),
+    ]
+    .map(|(id, content)| {
+        serde_json::json!({
+            "id": id,
+            "content": content,
+            "single_word": false,
+            "lstrip": false,
+            "rstrip": false,
+            "normalized": false,
+            "special": true,
+        })
+    });
+    let byte_level = serde_json::json!({
+        "type": "ByteLevel",
+        "add_prefix_space": false,
+        "trim_offsets": true,
+        "use_regex": true,
+    });
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": byte_level,
+        "post_processor": null,
+        "decoder": byte_level,
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": vocab,
+            "merges": [],
+        },
+    });
+    Tokenizer::from_str(&tokenizer.to_string()).map_err(|e| APIError::new(e.to_string()))
+}
+
+impl ModelLoader for MockLoader {
+    fn download_model(
+        &self,
+        _model_id: String,
+        _revision: Option<String>,
+        _hf_token: Option<String>,
+        _hf_token_path: Option<String>,
+        _hf_endpoint: Option<String>,
+        _subfolder: Option<String>,
+    ) -> Result<Box<dyn ModelPaths>, APIError> {
+        Ok(Box::new(DefaultModelPaths {
+            tokenizer_filename: Default::default(),
+            tokenizer_config_filename: None,
+            generation_config_filename: None,
+            config_filename: Default::default(),
+            filenames: vec![],
+        }))
+    }
+
+    fn load_model(
+        &self,
+        _paths: Box<dyn ModelPaths>,
+        options: LoadOptions,
+    ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError> {
+        let LoadOptions {
+            dtype,
+            device,
+            self_extend,
+            ..
+        } = options;
+        let specific_args = SpecificConfig {
+            self_extend,
+            ..SpecificConfig::new(None, None, None, None, None, self.max_gen_tokens, None)
+        };
+        // The KV cache is allocated but never read, its shape only sizes the blocks like an 8B model
+        let config = Config {
+            hidden_size: 1024,
+            head_dim: Some(128),
+            intermediate_size: 4096,
+            vocab_size: IM_END as usize + 1,
+            num_hidden_layers: 4,
+            num_attention_heads: 8,
+            num_key_value_heads: 8,
+            use_flash_attn: false,
+            rms_norm_eps: 1e-6,
+            rope_theta: 10000.,
+            bos_token_id: TokenID(Right(None)),
+            eos_token_id: TokenID(Left(Some(IM_END))),
+            max_seq_len: self.max_model_len,
+            sliding_window: None,
+            hidden_act: None,
+            tie_word_embeddings: false,
+            rope_scaling: None,
+            original_max_position_embeddings: None,
+            attention_bias: false,
+            partial_rotary_factor: None,
+            qk_layer_rms_norm: None,
+            kv_cache_dtype: dtype,
+            use_qkv_bias: None,
+            custom_stop_tokens: None,
+            specific_config: specific_args,
+            attn_logit_softcapping: None,
+            final_logit_softcapping: None,
+            query_pre_attn_scalar: None,
+            local_sliding_window: None,
+            moe_config: None,
+            mla_config: None,
+            rope_interleaved: false,
+        };
+
+        let tokenizer = TokenOutputStream::new(mock_tokenizer()?);
+
+        let pipeline_config = PipelineConfig {
+            max_model_len: config.get_max_model_len(),
+            default_max_tokens: self
+                .max_gen_tokens
+                .unwrap_or(config.max_seq_len / 5)
+                .clamp(MIN_GEN_TOKENS, MAX_GEN_TOKENS),
+            penalty: 1.,
+            repeat_last_n: 64,
+            temperature: 0.,
+            quantization: None,
+        };
+
+        println!("Serving the mock model, no weights are loaded.");
+
+        Ok((
+            Box::new(MockPipeline {
+                response: self.response.clone(),
+                prefill_latency: self.prefill_latency,
+                latency: self.latency,
+                recurrent: self.recurrent,
+                config,
+                tokenizer,
+                conversation: DefaultConversation::new(
+                    "mock".to_string(),
+                    "<|im_start|>system\n{}<|im_end|>".to_string(),
+                    Vec::default(),
+                    0,
+                    SeparatorStyle::Qwen2,
+                    "".to_string(),
+                    vec![IM_END, END_OF_TEXT],
+                    ("user".to_string(), "assistant".to_string()),
+                    DefaultConversationSeparators {
+                        sep: " ".to_string(),
+                        sep2: Some(" </s></s>".to_string()),
+                    },
+                ),
+                dtype,
+                device,
+            }),
+            pipeline_config,
+        ))
+    }
+}
+
+impl MockPipeline {
+    /// The reply to a prompt: the configured response, or the last user message of a chat
+    /// prompt, or the whole prompt of a plain completion.
+    fn reply(&self, prompt: &[u32]) -> String {
+        if let Some(response) = &self.response {
+            return response.clone();
+        }
+        let prompt = self
+            .tokenizer
+            .tokenizer()
+            .decode(prompt, false)
+            .unwrap_or_default();
+        match prompt.rsplit_once(USER_TURN) {
+            Some((_, message)) => message
+                .split("<|im_end|>")
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+            None => prompt,
+        }
+    }
+
+    fn next_token(&self, seq: &Sequence, params: &SamplingParams) -> TokenOrFinishReason {
+        let seq = seq.deref();
+        let prompt_len = seq.get_prompt_len();
+        let tokens_generated = seq.get_len() - prompt_len;
+        if tokens_generated >= params.max_tokens {
+            return Right("length".to_string());
+        }
+        let prompt = seq.get_token_ids()[..prompt_len]
+            .iter()
+            .map(|x| *x as u32)
+            .collect::<Vec<_>>();
+        let reply = self.reply(&prompt);
+        // The reply is repeated while the end of the reply (its EOS) is ignored
+        let at = if reply.is_empty()
+            || tokens_generated < reply.len()
+            || !(params.ignore_eos || tokens_generated < params.min_tokens)
+        {
+            tokens_generated
+        } else {
+            tokens_generated % reply.len()
+        };
+        let Some(&byte) = reply.as_bytes().get(at) else {
+            return Right("stop".to_string());
+        };
+        // A character is streamed with its last byte
+        let end = at + 1;
+        let text = if reply.is_char_boundary(end) {
+            let start = (0..end)
+                .rev()
+                .find(|i| reply.is_char_boundary(*i))
+                .unwrap_or(0);
+            reply[start..end].to_string()
+        } else {
+            "".to_string()
+        };
+        Left(Logprobs {
+            token: byte as usize,
+            logprob: 0.0,
+            top_logprobs: Vec::<TopLogprob>::new(),
+            bytes: text,
+        })
+    }
+}
+
+impl ModulePipeline for MockPipeline {
+    fn forward(
+        &mut self,
+        input_tokens: Tensor,
+        _input_positions: &[Vec<usize>],
+        _kv_cache: Option<&Vec<(Tensor, Tensor)>>,
+        input_metadata: &mut InputMetadata,
+    ) -> Result<Tensor, APIError> {
+        std::thread::sleep(if input_metadata.is_prompt {
+            self.prefill_latency
+        } else {
+            self.latency
+        });
+        // The logits are ignored by `sample`, one row per sequence (or prompt token)
+        let rows = input_tokens.dims().first().copied().unwrap_or(1).max(1);
+        Tensor::zeros((rows, 1), DType::F32, &self.device).map_err(APIError::from)
+    }
+
+    fn sample(
+        &mut self,
+        _logits: Tensor,
+        groups: &VecDeque<Arc<SequenceGroup>>,
+    ) -> Result<Vec<Result<TokenOrFinishReason, APIError>>, APIError> {
+        Ok(groups
+            .iter()
+            .filter_map(|group| {
+                // Sequences of a group share the prompt, they generate the same reply
+                let seq = group.get_seqs().values().next()?;
+                Some(Ok(self.next_token(seq, &group.sampling_params)))
+            })
+            .collect())
+    }
+
+    fn is_greedy(&self) -> bool {
+        // The replies are scripted
+        true
+    }
+
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn tokenizer(&self) -> &TokenOutputStream {
+        &self.tokenizer
+    }
+
+    fn get_conversation(&mut self, with_history: bool) -> &mut dyn Conversation {
+        if !with_history {
+            self.conversation.clear_message();
+        }
+        &mut self.conversation
+    }
+
+    fn get_model_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    fn get_dtype(&self) -> DType {
+        self.dtype
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn reset_decoder(&mut self) -> Option<String> {
+        let ret = self.tokenizer.decode_rest().unwrap_or(None);
+        self.tokenizer.clear();
+        ret
+    }
+
+    /// The counts of the tokens of the input, normalized.
+    fn embed(&mut self, input_ids: &[u32], _pooling: Option<Pooling>) -> Result<Tensor, APIError> {
+        let mut counts = vec![0f32; self.config.hidden_size];
+        for &token in input_ids {
+            counts[token as usize % counts.len()] += 1.;
+        }
+        let norm = counts.iter().map(|x| x * x).sum::<f32>().sqrt();
+        counts.iter_mut().for_each(|x| *x /= norm);
+        Tensor::from_vec(counts, self.config.hidden_size, &self.device).map_err(APIError::from)
+    }
+
+    /// The mock model is certain of every prompt token.
+    fn prompt_logprobs(
+        &mut self,
+        input_ids: &[u32],
+        top_n: usize,
+    ) -> Result<Vec<Option<Logprobs>>, APIError> {
+        let decode = |token: u32| {
+            self.tokenizer
+                .tokenizer()
+                .decode(&[token], false)
+                .unwrap_or_default()
+        };
+        Ok(input_ids
+            .iter()
+            .enumerate()
+            .map(|(position, &token)| {
+                (position > 0).then(|| Logprobs {
+                    token: token as usize,
+                    logprob: 0.0,
+                    bytes: decode(token),
+                    top_logprobs: (top_n > 0)
+                        .then(|| TopLogprob {
+                            token: token as usize,
+                            logprob: 0.0,
+                            bytes: decode(token),
+                        })
+                        .into_iter()
+                        .collect(),
+                })
+            })
+            .collect())
+    }
+
+    fn recurrent_state(&self) -> Result<Option<Vec<Tensor>>, APIError> {
+        if !self.recurrent {
+            return Ok(None);
+        }
+        let state = Tensor::zeros((1, 1), DType::F32, &self.device).map_err(APIError::from)?;
+        Ok(Some(vec![state]))
+    }
+
+    fn forward_recurrent(
+        &mut self,
+        input_tokens: Tensor,
+        state: &mut [Tensor],
+    ) -> Result<Tensor, APIError> {
+        if !self.recurrent {
+            return Err(APIError::new_str(
+                "The mock model runs over the paged KV cache unless it is recurrent.",
+            ));
+        }
+        let (rows, seq_len) = input_tokens.dims2().map_err(APIError::from)?;
+        std::thread::sleep(if seq_len > 1 {
+            self.prefill_latency
+        } else {
+            self.latency
+        });
+        for tensor in state.iter_mut() {
+            *tensor = (&*tensor + seq_len as f64).map_err(APIError::from)?;
+        }
+        Tensor::zeros((rows, 1), DType::F32, &self.device).map_err(APIError::from)
+    }
+
+    fn decoder_start_token_id(&self) -> Option<u32> {
+        None
+    }
+
+    fn encode(&mut self, _input_tokens: Tensor) -> Result<Vec<KVCache>, APIError> {
+        Err(APIError::new_str("The mock model has no encoder."))
+    }
+
+    fn forward_decoder(
+        &mut self,
+        _input_tokens: Tensor,
+        _kv: &mut [EncoderDecoderKv],
+    ) -> Result<Tensor, APIError> {
+        Err(APIError::new_str("The mock model has no encoder."))
+    }
+
+    fn is_encoder_only(&self) -> bool {
+        false
+    }
+
+    fn token_labels(&self) -> &[String] {
+        &[]
+    }
+
+    fn classify_tokens(
+        &mut self,
+        _inputs: &[Vec<u32>],
+    ) -> Result<Vec<Vec<(String, f32)>>, APIError> {
+        Err(APIError::new_str(
+            "The mock model does not classify tokens.",
+        ))
+    }
+
+    fn score_labels(&self) -> &[String] {
+        &[]
+    }
+
+    fn score(&mut self, _input_ids: &[u32]) -> Result<Vec<f32>, APIError> {
+        Err(APIError::new_str("The mock model has no score head."))
+    }
+}
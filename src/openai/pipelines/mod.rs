@@ -4,14 +4,28 @@ use dirs;
 use either::Either;
 use std::{env, fs, path::PathBuf, sync::Arc};
 
-use crate::{paged_attention::input_metadata::InputMetadata, try_api};
+use crate::{
+    paged_attention::input_metadata::InputMetadata,
+    scheduler::cache_engine::{EncoderDecoderKv, KVCache},
+    try_api,
+};
 
-use super::{conversation::Conversation, models::Config, responses::APIError, PipelineConfig};
+use super::{
+    conversation::Conversation,
+    models::{Config, SelfExtend},
+    requests::Pooling,
+    responses::APIError,
+    PipelineConfig,
+};
 use candle_examples::token_output_stream::TokenOutputStream;
+use weights::DtypeOverride;
 /// The LLMEngine is effectively a wrapper around a ModulePipeline. It contains a Scheduler and a CacheEngine
 /// which are used to scheduler and manage the cache during generation requests, respectively.
+pub mod download;
 pub mod llm_engine;
+pub mod mock;
 pub mod pipeline;
+pub mod weights;
 use crate::scheduler::sequence::SequenceGroup;
 type TokenOrFinishReason = Either<Logprobs, String>;
 use std::collections::VecDeque;
@@ -21,14 +35,19 @@ pub trait ModulePipeline: Send + Sync {
         input_tokens: Tensor,
         input_positions: &[Vec<usize>],
         kv_cache: Option<&Vec<(Tensor, Tensor)>>,
-        input_metadata: InputMetadata,
+        input_metadata: &mut InputMetadata,
     ) -> Result<Tensor, APIError>;
 
+    /// The next token or the finish reason of each group, or the error that fails it.
     fn sample(
         &mut self,
         logits: Tensor,
         groups: &VecDeque<Arc<SequenceGroup>>,
-    ) -> Result<Vec<TokenOrFinishReason>, APIError>;
+    ) -> Result<Vec<Result<TokenOrFinishReason, APIError>>, APIError>;
+
+    /// Whether `sample` always picks the most likely token, whatever the sampling parameters
+    /// of the groups.
+    fn is_greedy(&self) -> bool;
 
     fn name(&self) -> &str;
 
@@ -43,6 +62,67 @@ pub trait ModulePipeline: Send + Sync {
     fn device(&self) -> &Device;
 
     fn reset_decoder(&mut self) -> Option<String>;
+
+    /// Pool the final hidden states of `input_ids` into an L2-normalized F32 embedding of shape
+    /// `(hidden_size,)`. Runs outside the paged KV cache. `None` uses the model default pooling.
+    fn embed(&mut self, input_ids: &[u32], pooling: Option<Pooling>) -> Result<Tensor, APIError>;
+
+    /// Log probability of every token of `input_ids` given the tokens before it, with the
+    /// `top_n` most likely tokens at its position. The first token has none. Runs outside the
+    /// paged KV cache.
+    fn prompt_logprobs(
+        &mut self,
+        input_ids: &[u32],
+        top_n: usize,
+    ) -> Result<Vec<Option<Logprobs>>, APIError>;
+
+    /// The zeroed state of a new sequence of a recurrent model, kept in the `StateCache` in
+    /// place of KV cache blocks. `None` for the models attending over the paged KV cache.
+    fn recurrent_state(&self) -> Result<Option<Vec<Tensor>>, APIError>;
+
+    /// Forward pass of a recurrent model: logits `(b_size, vocab_size)` of the last of
+    /// `input_tokens` `(b_size, seq_len)`, advancing `state`, the states of the sequences
+    /// concatenated along their first dimension.
+    fn forward_recurrent(
+        &mut self,
+        input_tokens: Tensor,
+        state: &mut [Tensor],
+    ) -> Result<Tensor, APIError>;
+
+    /// The token the decoder of an encoder-decoder model starts from. `None` for the
+    /// decoder-only models.
+    fn decoder_start_token_id(&self) -> Option<u32>;
+
+    /// Run the encoder of an encoder-decoder model over the prompt `input_tokens`
+    /// `(1, seq_len)`: the cross-attention keys and values of each decoder layer, which the
+    /// `CacheEngine` keeps for the decoding steps of the request.
+    fn encode(&mut self, input_tokens: Tensor) -> Result<Vec<KVCache>, APIError>;
+
+    /// Decoding step of an encoder-decoder model: logits `(b_size, vocab_size)` after
+    /// `input_tokens` `(b_size, 1)`, the last token of each sequence, attending to the keys
+    /// and values of `kv` (one per sequence) and appending its own.
+    fn forward_decoder(
+        &mut self,
+        input_tokens: Tensor,
+        kv: &mut [EncoderDecoderKv],
+    ) -> Result<Tensor, APIError>;
+
+    /// Encoder-only models serve embeddings and reward models serve scores, neither can
+    /// generate.
+    fn is_encoder_only(&self) -> bool;
+
+    /// Labels of the token classification head, empty when the model cannot classify tokens.
+    fn token_labels(&self) -> &[String];
+
+    /// The label and probability of every token of each input, from one batched forward.
+    fn classify_tokens(&mut self, inputs: &[Vec<u32>])
+        -> Result<Vec<Vec<(String, f32)>>, APIError>;
+
+    /// Labels of the score head of a reward model, empty for the other models.
+    fn score_labels(&self) -> &[String];
+
+    /// The score of each label of the score head for a whole input, from its last token.
+    fn score(&mut self, input_ids: &[u32]) -> Result<Vec<f32>, APIError>;
 }
 
 // TODO(EricLBuehler): Ensure the padding token matches tokenizer
@@ -89,21 +169,59 @@ pub trait ModelPaths {
     fn get_weight_filenames(&self) -> &Vec<PathBuf>;
     fn get_config_filename(&self) -> &PathBuf;
     fn get_tokenizer_filename(&self) -> &PathBuf;
+    /// `tokenizer_config.json`, which holds the chat template. Optional, older repositories
+    /// have none.
+    fn get_tokenizer_config_filename(&self) -> Option<&PathBuf>;
+    /// `generation_config.json`, which holds the EOS tokens of chat models. Optional.
+    fn get_generation_config_filename(&self) -> Option<&PathBuf>;
+}
+
+/// How [`ModelLoader::load_model`] loads a model, see [`LoadOptions::new`] for the defaults.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    pub dtype: DType,
+    /// Modules loaded in another dtype than `dtype`
+    pub dtype_overrides: Vec<DtypeOverride>,
+    pub device: Device,
+    pub self_extend: Option<SelfExtend>,
+    /// Read the weights of a layer when it runs instead of keeping them on the device
+    pub stream_weights: bool,
+    /// The decoder layers past the first `num_gpu_layers` run on the CPU
+    pub num_gpu_layers: Option<usize>,
+}
+
+impl LoadOptions {
+    /// The whole model in `dtype` on `device`.
+    pub fn new(dtype: DType, device: Device) -> Self {
+        Self {
+            dtype,
+            dtype_overrides: Vec::new(),
+            device,
+            self_extend: None,
+            stream_weights: false,
+            num_gpu_layers: None,
+        }
+    }
 }
 
 pub trait ModelLoader {
+    /// Fetch the files of `model_id` at `revision` (a branch, tag or commit, `main` by default)
+    /// from `hf_endpoint` (the Hugging Face Hub by default), the tokenizer, config and weights of
+    /// `subfolder` when the model is not at the root of the repository.
     fn download_model(
         &self,
         model_id: String,
         revision: Option<String>,
         hf_token: Option<String>,
         hf_token_path: Option<String>,
+        hf_endpoint: Option<String>,
+        subfolder: Option<String>,
     ) -> Result<Box<dyn ModelPaths>, APIError>;
 
+    /// Load the model as `options` says.
     fn load_model(
         &self,
         paths: Box<dyn ModelPaths>,
-        dtype: DType,
-        device: Device,
+        options: LoadOptions,
     ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError>;
 }
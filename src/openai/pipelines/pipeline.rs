@@ -1,56 +1,81 @@
-use super::{get_token, ModelLoader, ModelPaths, ModulePipeline, TokenOrFinishReason};
-use crate::openai::logits_processor::{LogitsProcessor, Sampling};
-use crate::openai::models::TokenID;
+use super::download::{fetch_files, get_file, DOWNLOAD_RETRIES, HF_ENDPOINT};
+use super::weights;
+use super::{get_token, LoadOptions, ModelLoader, ModelPaths, ModulePipeline, TokenOrFinishReason};
+use crate::backend::{apply_penalties, penalty_counts, Penalties};
+use crate::openai::logits_processor::{
+    apply_logit_bias, apply_min_p_typical_p, ban_tokens, token_logprobs, LogitsProcessor, Sampling,
+};
+use crate::openai::models::linear::QuantizationConfig;
+use crate::openai::models::{GenerationConfig, TokenID};
 use crate::openai::sampling_params::{Logprobs, TopLogprob};
 use crate::scheduler::sequence::SequenceGroup;
 use crate::{
     openai::{
         conversation::{
+            chat_template::ChatTemplate,
             default_conversation::{
                 DefaultConversation, DefaultConversationSeparators, SeparatorStyle,
             },
             Conversation,
         },
         models::{
+            bert::{Bert, BertConfig},
+            deepseek_v2::{DeepSeekV2, DeepSeekV2Config},
             gemma::{Gemma, GemmaConfig},
+            gemma2::{Gemma2, Gemma2Config},
+            glm4::{Glm4, Glm4Config},
             llama::{Llama, LlamaConfig},
+            mamba2::{Mamba2, Mamba2Config},
             mistral::{Mistral, MistralConfig},
+            mixtral::{Mixtral, MixtralConfig},
             phi2::{Phi2, Phi2Config},
             phi3::{Phi, PhiConfig},
             qwen2::{Qwen2, QwenConfig},
+            qwen2_moe::{Qwen2Moe, Qwen2MoeConfig},
             stable_lm::{StableLM, StableLMConfig},
+            t5::{T5Config, T5},
             yi::{Yi, YiConfig},
-            Config,
+            Config, RopeScalingKind, ScoreHead, SequenceClassificationConfig,
         },
+        requests::Pooling,
         responses::APIError,
         PipelineConfig,
     },
     paged_attention::input_metadata::InputMetadata,
+    scheduler::cache_engine::{EncoderDecoderKv, KVCache},
     try_api, SpecificConfig,
 };
 use candle_core::{DType, Device, IndexOp, Tensor};
 use candle_examples::token_output_stream::TokenOutputStream;
-use candle_nn::VarBuilder;
+use candle_transformers::models::bert::Config as BertModelConfig;
 use either::Either;
 use either::Either::{Left, Right};
-use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
+use hf_hub::{api::sync::ApiBuilder, Cache, Repo, RepoType};
 use rayon::prelude::*;
 use std::collections::VecDeque;
 use std::{path::PathBuf, sync::Arc};
 use tokenizers::Tokenizer;
 const EOS_TOKEN: &str = "</s>";
 const SAMPLING_SEED: u64 = 299792458;
-const MIN_GEN_TOKENS: usize = 128;
-const MAX_GEN_TOKENS: usize = 4096;
+pub(super) const MIN_GEN_TOKENS: usize = 128;
+pub(super) const MAX_GEN_TOKENS: usize = 4096;
 enum LLMModel {
     Llama(Llama),
     Phi2(Phi2),
     Phi3(Phi),
     Qwen2(Qwen2),
+    Qwen2Moe(Qwen2Moe),
     Gemma(Gemma),
+    Gemma2(Gemma2),
     Mistral(Mistral),
+    Mixtral(Mixtral),
     Yi(Yi),
+    DeepSeekV2(DeepSeekV2),
+    Glm4(Glm4),
     StableLM(StableLM),
+    Bert(Bert),
+    Mamba2(Mamba2),
+    T5(T5),
 }
 /// top-p, multinomial, and argmax sampling are implemented. Beam search is not implemented.
 pub struct DefaultPipeline {
@@ -63,6 +88,8 @@ pub struct DefaultPipeline {
     dtype: DType,
     device: Device,
     stop_token_ids: Vec<u32>,
+    /// The head of the reward models, which serve `/v1/score` and do not generate
+    score_head: Option<ScoreHead>,
 }
 
 pub struct DefaultLoader {
@@ -70,8 +97,44 @@ pub struct DefaultLoader {
     name: String,
 }
 
+/// Parse the `config.json` of the model, naming the file in the error (e.g. a missing field).
+/// Python writes infinite floats as `Infinity` (the `time_step_limit` of Mamba2), which is not
+/// JSON: they are read as null.
+fn read_config<T: serde::de::DeserializeOwned>(paths: &dyn ModelPaths) -> Result<T, APIError> {
+    let path = paths.get_config_filename();
+    let config = std::fs::read(path)
+        .map_err(|e| APIError::new(format!("Cannot read {}: {e}", path.display())))?;
+    serde_json::from_slice(&config)
+        .or_else(|e| {
+            let config = String::from_utf8_lossy(&config);
+            if !config.contains("Infinity") {
+                return Err(e);
+            }
+            serde_json::from_str(
+                &config
+                    .replace("-Infinity", "null")
+                    .replace("Infinity", "null"),
+            )
+        })
+        .map_err(|e| APIError::new(format!("Invalid model config {}: {e}", path.display())))
+}
+
+/// Parse the `generation_config.json` of the model, if it has one.
+fn read_generation_config(paths: &dyn ModelPaths) -> Result<Option<GenerationConfig>, APIError> {
+    let Some(path) = paths.get_generation_config_filename() else {
+        return Ok(None);
+    };
+    let config = std::fs::read(path)
+        .map_err(|e| APIError::new(format!("Cannot read {}: {e}", path.display())))?;
+    serde_json::from_slice(&config)
+        .map(Some)
+        .map_err(|e| APIError::new(format!("Invalid generation config {}: {e}", path.display())))
+}
+
 pub struct DefaultModelPaths<P> {
     pub tokenizer_filename: P,
+    pub tokenizer_config_filename: Option<P>,
+    pub generation_config_filename: Option<P>,
     pub config_filename: P,
     pub filenames: Vec<P>,
 }
@@ -83,6 +146,12 @@ impl ModelPaths for DefaultModelPaths<PathBuf> {
     fn get_tokenizer_filename(&self) -> &PathBuf {
         &self.tokenizer_filename
     }
+    fn get_tokenizer_config_filename(&self) -> Option<&PathBuf> {
+        self.tokenizer_config_filename.as_ref()
+    }
+    fn get_generation_config_filename(&self) -> Option<&PathBuf> {
+        self.generation_config_filename.as_ref()
+    }
     fn get_weight_filenames(&self) -> &Vec<PathBuf> {
         &self.filenames
     }
@@ -101,31 +170,51 @@ impl ModelLoader for DefaultLoader {
         revision: Option<String>,
         hf_token: Option<String>,
         hf_token_path: Option<String>,
+        hf_endpoint: Option<String>,
+        subfolder: Option<String>,
     ) -> Result<Box<dyn ModelPaths>, APIError> {
-        let api = try_api!(ApiBuilder::new()
-            .with_progress(true)
-            .with_token(Some(get_token(hf_token, hf_token_path)?))
-            .build());
+        let cache = Cache::default();
+        let mut builder = ApiBuilder::from_cache(cache.clone())
+            .with_progress(false)
+            .with_retries(DOWNLOAD_RETRIES)
+            .with_token(Some(get_token(hf_token, hf_token_path)?));
+        if let Some(endpoint) = hf_endpoint.or_else(|| std::env::var(HF_ENDPOINT).ok()) {
+            builder = builder.with_endpoint(endpoint.trim_end_matches('/').to_string());
+        }
+        let api = try_api!(builder.build());
         let revision = revision.unwrap_or("main".to_string());
-        let api = api.repo(Repo::with_revision(model_id, RepoType::Model, revision));
+        let repo = Repo::with_revision(model_id, RepoType::Model, revision);
+        let api = api.repo(repo.clone());
+        let subfolder = subfolder.as_deref().map(|dir| dir.trim_matches('/'));
+
+        let tokenizer_filename = try_api!(get_file(&api, subfolder, "tokenizer.json"));
 
-        let tokenizer_filename = try_api!(api.get("tokenizer.json"));
+        let tokenizer_config_filename = get_file(&api, subfolder, "tokenizer_config.json").ok();
 
-        let config_filename = try_api!(api.get("config.json"));
+        let generation_config_filename = get_file(&api, subfolder, "generation_config.json").ok();
 
-        let mut filenames = vec![];
-        for rfilename in try_api!(api.info())
+        let config_filename = try_api!(get_file(&api, subfolder, "config.json"));
+
+        let rfilenames = try_api!(api.info())
             .siblings
-            .iter()
-            .map(|x| x.rfilename.clone())
+            .into_iter()
+            .map(|x| x.rfilename)
             .filter(|x| x.ends_with(".safetensors"))
-        {
-            let filename = try_api!(api.get(&rfilename));
-            filenames.push(filename);
+            .filter(|x| subfolder.map_or(true, |dir| x.starts_with(&format!("{dir}/"))))
+            .collect::<Vec<_>>();
+        if rfilenames.is_empty() {
+            return Err(APIError::new(format!(
+                "No safetensors weights in {}{}.",
+                repo.url(),
+                subfolder.map(|dir| format!("/{dir}")).unwrap_or_default()
+            )));
         }
+        let filenames = fetch_files(&api, &cache, &repo, &rfilenames)?;
 
         Ok(Box::new(DefaultModelPaths {
             tokenizer_filename,
+            tokenizer_config_filename,
+            generation_config_filename,
             config_filename,
             filenames,
         }))
@@ -134,74 +223,212 @@ impl ModelLoader for DefaultLoader {
     fn load_model(
         &self,
         paths: Box<dyn ModelPaths>,
-        dtype: DType,
-        device: Device,
+        options: LoadOptions,
     ) -> Result<(Box<dyn ModulePipeline>, PipelineConfig), APIError> {
-        let specific_args = self.config.clone();
+        let LoadOptions {
+            dtype,
+            dtype_overrides,
+            device,
+            self_extend,
+            stream_weights,
+            num_gpu_layers,
+        } = options;
+        let mut specific_args = self.config.clone();
+        specific_args.self_extend = self_extend;
+        specific_args.stream_weights = stream_weights;
+        if stream_weights && !matches!(self.name.as_str(), "llama" | "llama3") {
+            return Err(APIError::new(format!(
+                "Weight streaming is not supported for {} models.",
+                self.name
+            )));
+        }
+        if num_gpu_layers.is_some() {
+            if !matches!(self.name.as_str(), "llama" | "llama3") {
+                return Err(APIError::new(format!(
+                    "Layer offloading is not supported for {} models.",
+                    self.name
+                )));
+            }
+            if stream_weights {
+                return Err(APIError::new_str(
+                    "Layer offloading and weight streaming cannot be combined.",
+                ));
+            }
+        }
+        // Every layer already runs on the CPU
+        specific_args.num_gpu_layers = num_gpu_layers.filter(|_| !device.is_cpu());
+        // The other models have norms (or fused layers) that do not convert their input
+        let overridable = [
+            "llama",
+            "llama3",
+            "mistral",
+            "mixtral",
+            "qwen2",
+            "qwen2moe",
+            "yi",
+            "glm4",
+            "phi3",
+            "deepseekv2",
+        ];
+        if !dtype_overrides.is_empty() && !overridable.contains(&self.name.as_str()) {
+            return Err(APIError::new(format!(
+                "Dtype overrides are not supported for {} models.",
+                self.name
+            )));
+        }
+
+        // Checkpoints published with FP8 weights, loaded as they are by the linear layers
+        #[derive(serde::Deserialize)]
+        struct Quantized {
+            quantization_config: Option<QuantizationConfig>,
+        }
+        let quantized: Quantized = read_config(&*paths)?;
+        if let Some(quantization) = quantized.quantization_config {
+            quantization.validate().map_err(APIError::new)?;
+            if specific_args.quant.is_some() {
+                return Err(APIError::new_str(
+                    "The weights of the model are already quantized (FP8), they cannot be quantized again.",
+                ));
+            }
+        }
 
-        let config = match self.name.as_str() {
+        let mut config = match self.name.as_str() {
             "llama" | "llama3" => {
-                let config: LlamaConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
+                let config: LlamaConfig = read_config(&*paths)?;
                 config.into_config(false, dtype, &specific_args)
             }
             "phi2" => {
-                let config: Phi2Config = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
+                let config: Phi2Config = read_config(&*paths)?;
                 //Phi2 use F32 type for kvcache
                 config.into_config(false, DType::F32, &specific_args)
             }
             "phi3" => {
-                let config: PhiConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
-                    paths.get_config_filename()
-                )),));
+                let config: PhiConfig = read_config(&*paths)?;
                 config.into_config(false, dtype, &specific_args)
             }
             "qwen2" => {
-                let config: QwenConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
+                let config: QwenConfig = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            "qwen2moe" => {
+                let config: Qwen2MoeConfig = read_config(&*paths)?;
                 config.into_config(false, dtype, &specific_args)
             }
             "gemma" => {
-                let config: GemmaConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
+                let config: GemmaConfig = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            "gemma2" => {
+                let config: Gemma2Config = read_config(&*paths)?;
                 config.into_config(false, dtype, &specific_args)
             }
             "mistral" => {
-                let config: MistralConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
+                let config: MistralConfig = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            "mixtral" => {
+                let config: MixtralConfig = read_config(&*paths)?;
                 config.into_config(false, dtype, &specific_args)
             }
             "yi" => {
-                let config: YiConfig = try_api!(serde_json::from_slice(&try_api!(std::fs::read(
-                    paths.get_config_filename()
-                )),));
+                let config: YiConfig = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            "deepseekv2" => {
+                let config: DeepSeekV2Config = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            "glm4" => {
+                let config: Glm4Config = read_config(&*paths)?;
                 config.into_config(false, dtype, &specific_args)
             }
             "stablelm" => {
-                let config: StableLMConfig = try_api!(serde_json::from_slice(&try_api!(
-                    std::fs::read(paths.get_config_filename())
-                ),));
+                let config: StableLMConfig = read_config(&*paths)?;
                 config.into_config(false, dtype, &specific_args)
             }
-            _ => panic!("Model not supported!"),
+            "bert" => {
+                let config: BertConfig = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            "mamba2" => {
+                let config: Mamba2Config = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            "t5" => {
+                let config: T5Config = read_config(&*paths)?;
+                config.into_config(false, dtype, &specific_args)
+            }
+            _ => {
+                return Err(APIError::new(format!(
+                    "Model {} is not supported.",
+                    self.name
+                )))
+            }
         };
 
+        // A broken generation config must not keep the model from loading, like the chat template
+        match read_generation_config(&*paths) {
+            Ok(Some(generation)) => config.merge_generation_config(&generation),
+            Ok(None) => {}
+            Err(e) => tracing::warn!(model = %self.name, "Ignoring the generation config: {e}"),
+        }
+
         println!("Model {:?}", config);
 
+        // Phi-3 applies its LongRoPE factors itself
+        let long_rope = config
+            .rope_scaling
+            .as_ref()
+            .is_some_and(|scaling| scaling.contains_key("long_factor"));
+        match (config.rope_scaling_type(), config.get_rope_scaling()) {
+            (_, Some((kind, factor))) => tracing::info!(
+                model = %self.name,
+                "Rope scaling {kind:?} by {factor}, context of {} tokens.",
+                config.get_max_model_len()
+            ),
+            (Some(scaling_type), None)
+                if !long_rope
+                    && scaling_type != "default"
+                    && RopeScalingKind::from_name(scaling_type).is_none() =>
+            {
+                tracing::warn!(model = %self.name, "Ignoring the unsupported rope scaling `{scaling_type}`.")
+            }
+            _ => {}
+        }
+
         println!("Loading {} model.", self.name);
+        if config.num_gpu_layers() < config.num_hidden_layers {
+            tracing::info!(
+                model = %self.name,
+                "{} of the {} decoder layers on the GPU, the others on the CPU.",
+                config.num_gpu_layers(),
+                config.num_hidden_layers
+            );
+        }
+
+        let vb = try_api!(unsafe {
+            weights::var_builder(
+                paths.get_weight_filenames(),
+                dtype,
+                &dtype_overrides,
+                &device,
+            )
+        });
 
-        let vb = match unsafe {
-            VarBuilder::from_mmaped_safetensors(paths.get_weight_filenames(), dtype, &device)
-        } {
-            Ok(vb_) => vb_,
-            _ => panic!("Load model weights failed!"),
+        // `*ForSequenceClassification` checkpoints of the decoders that serve embeddings
+        let score_head = if matches!(self.name.as_str(), "llama" | "llama3" | "qwen2" | "mistral") {
+            let labels = read_config::<SequenceClassificationConfig>(&*paths)?.labels();
+            try_api!(ScoreHead::load(&vb, config.hidden_size, labels))
+        } else {
+            None
         };
+        if let Some(score_head) = &score_head {
+            tracing::info!(
+                model = %self.name,
+                "Reward model with the labels {:?}, it serves /v1/score and does not generate.",
+                score_head.labels()
+            );
+        }
 
         let (model, sep_style) = match self.name.as_str() {
             "llama" => (
@@ -224,30 +451,115 @@ impl ModelLoader for DefaultLoader {
                 LLMModel::Qwen2(try_api!(Qwen2::new(vb, &config, dtype, &device))),
                 SeparatorStyle::Qwen2,
             ),
+            "qwen2moe" => (
+                LLMModel::Qwen2Moe(try_api!(Qwen2Moe::new(vb, &config, dtype, &device))),
+                SeparatorStyle::Qwen2,
+            ),
             "gemma" => (
                 LLMModel::Gemma(try_api!(Gemma::new(vb, &config, dtype, &device))),
                 SeparatorStyle::Gemma,
             ),
+            "gemma2" => (
+                LLMModel::Gemma2(try_api!(Gemma2::new(vb, &config, dtype, &device))),
+                SeparatorStyle::Gemma,
+            ),
             "mistral" => (
                 LLMModel::Mistral(try_api!(Mistral::new(vb, &config, dtype, &device))),
                 SeparatorStyle::Mistral,
             ),
+            "mixtral" => (
+                LLMModel::Mixtral(try_api!(Mixtral::new(vb, &config, dtype, &device))),
+                SeparatorStyle::Mistral,
+            ),
             "yi" => (
                 LLMModel::Yi(try_api!(Yi::new(vb, &config, dtype, &device))),
                 SeparatorStyle::Yi,
             ),
+            "deepseekv2" => (
+                LLMModel::DeepSeekV2(try_api!(DeepSeekV2::new(vb, &config, dtype, &device))),
+                SeparatorStyle::DeepSeek,
+            ),
+            "glm4" => {
+                let glm_config: Glm4Config = read_config(&*paths)?;
+                (
+                    LLMModel::Glm4(try_api!(Glm4::new(
+                        vb,
+                        &config,
+                        &glm_config,
+                        dtype,
+                        &device
+                    ))),
+                    SeparatorStyle::Glm4,
+                )
+            }
             "stablelm" => (
                 LLMModel::StableLM(try_api!(StableLM::new(vb, &config, dtype, &device))),
                 SeparatorStyle::StableLM,
             ),
-            _ => panic!("Model not supported!"),
+            "bert" => {
+                let config_file = try_api!(std::fs::read(paths.get_config_filename()));
+                let model_config: BertModelConfig = try_api!(serde_json::from_slice(&config_file));
+                let labels = try_api!(serde_json::from_slice::<BertConfig>(&config_file)).labels();
+                (
+                    LLMModel::Bert(try_api!(Bert::new(
+                        vb,
+                        &config,
+                        &model_config,
+                        labels,
+                        &device
+                    ))),
+                    SeparatorStyle::NoColonSingle,
+                )
+            }
+            "mamba2" => {
+                let mamba_config: Mamba2Config = read_config(&*paths)?;
+                (
+                    LLMModel::Mamba2(try_api!(Mamba2::new(
+                        vb,
+                        &config,
+                        &mamba_config,
+                        dtype,
+                        &device
+                    ))),
+                    SeparatorStyle::Mistral,
+                )
+            }
+            "t5" => {
+                let t5_config: T5Config = read_config(&*paths)?;
+                (
+                    LLMModel::T5(try_api!(T5::new(vb, &config, &t5_config, &device))),
+                    SeparatorStyle::T5,
+                )
+            }
+            _ => {
+                return Err(APIError::new(format!(
+                    "Model {} is not supported.",
+                    self.name
+                )))
+            }
         };
 
-        let tokenizer_ = Tokenizer::from_file(paths.get_tokenizer_filename())
-            .map_err(|x| APIError::new(x.to_string()))?;
+        let tokenizer_ = Tokenizer::from_file(paths.get_tokenizer_filename()).map_err(|x| {
+            APIError::new(format!(
+                "Cannot load the tokenizer {}: {x}",
+                paths.get_tokenizer_filename().display()
+            ))
+        })?;
 
         let tokenizer = candle_examples::token_output_stream::TokenOutputStream::new(tokenizer_);
 
+        // A broken template must not keep the model from loading, the built-in one is used instead
+        let chat_template = match paths.get_tokenizer_config_filename() {
+            Some(path) => ChatTemplate::from_tokenizer_config(path).unwrap_or_else(|e| {
+                tracing::warn!(model = %self.name, "Ignoring the chat template: {e}");
+                None
+            }),
+            None => None,
+        };
+        if chat_template.is_some() {
+            tracing::info!(model = %self.name, "Using the chat template of the tokenizer config.");
+        }
+
         println!("Done loading.");
 
         //max and min number of tokens generated per request
@@ -257,11 +569,12 @@ impl ModelLoader for DefaultLoader {
             .clamp(MIN_GEN_TOKENS, MAX_GEN_TOKENS);
 
         let pipeline_config = PipelineConfig {
-            max_model_len: config.max_seq_len,
+            max_model_len: config.get_max_model_len(),
             default_max_tokens,
             penalty: specific_args.penalty.unwrap_or(1.),
             repeat_last_n: specific_args.repeat_last_n.unwrap_or(64),
             temperature: specific_args.temperature.unwrap_or(0.7),
+            quantization: specific_args.quant.clone(),
         };
 
         println!("{:?}", pipeline_config);
@@ -336,11 +649,13 @@ impl ModelLoader for DefaultLoader {
                         sep: " ".to_string(),
                         sep2: Some(" </s></s>".to_string()),
                     },
-                ),
+                )
+                .with_chat_template(chat_template),
                 name: self.name.clone(),
                 dtype,
                 device: device.clone(),
                 stop_token_ids,
+                score_head,
             }),
             pipeline_config,
         ))
@@ -353,7 +668,7 @@ impl ModulePipeline for DefaultPipeline {
         input_tokens: Tensor,
         input_positions: &[Vec<usize>],
         kv_cache: Option<&Vec<(Tensor, Tensor)>>,
-        mut input_metadata: InputMetadata,
+        input_metadata: &mut InputMetadata,
     ) -> Result<Tensor, APIError> {
         let input_tokens = if input_tokens.shape().dims().len() < 2 {
             input_tokens
@@ -365,69 +680,53 @@ impl ModulePipeline for DefaultPipeline {
 
         match &mut self.model {
             LLMModel::Llama(llama) => llama
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
             LLMModel::Phi2(phi) => phi
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
             LLMModel::Phi3(phi) => phi
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
             LLMModel::Qwen2(qwen2) => qwen2
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
+                .map_err(APIError::from),
+            LLMModel::Qwen2Moe(qwen2_moe) => qwen2_moe
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
             LLMModel::Gemma(gemma) => gemma
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
+                .map_err(APIError::from),
+            LLMModel::Gemma2(gemma2) => gemma2
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
             LLMModel::Mistral(mistral) => mistral
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
+                .map_err(APIError::from),
+            LLMModel::Mixtral(mixtral) => mixtral
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
             LLMModel::Yi(yi) => yi
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
+                .map_err(APIError::from),
+            LLMModel::DeepSeekV2(deepseek) => deepseek
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
+                .map_err(APIError::from),
+            LLMModel::Glm4(glm4) => glm4
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
             LLMModel::StableLM(stablelm) => stablelm
-                .forward(
-                    &input_tokens,
-                    input_positions,
-                    kv_cache,
-                    &mut input_metadata,
-                )
+                .forward(&input_tokens, input_positions, kv_cache, input_metadata)
                 .map_err(APIError::from),
+            LLMModel::Bert(_) => Err(APIError::new_str(
+                "BERT models are encoders and only serve /v1/embeddings and /v1/token_classify.",
+            )),
+            LLMModel::Mamba2(_) => Err(APIError::new_str(
+                "Mamba2 models are recurrent, they run with `forward_recurrent`.",
+            )),
+            LLMModel::T5(_) => Err(APIError::new_str(
+                "T5 models are encoder-decoders, they run with `encode` and `forward_decoder`.",
+            )),
         }
     }
 
@@ -435,98 +734,20 @@ impl ModulePipeline for DefaultPipeline {
         &mut self,
         logits: Tensor,
         groups: &VecDeque<Arc<SequenceGroup>>,
-    ) -> Result<Vec<TokenOrFinishReason>, APIError> {
-        use std::collections::HashMap;
-        use std::sync::Mutex;
-        let shared_result = Arc::new(Mutex::new(HashMap::<usize, TokenOrFinishReason>::new()));
-        let shared_group_idx = Arc::new(Mutex::new(0));
-        groups.par_iter().for_each(|group| {
-            let group_idx = {
-                let mut groupidx = shared_group_idx.lock().unwrap();
-                let group_idx = *groupidx;
-                *groupidx += 1;
-                group_idx
-            };
-
-            let sampling_params = &group.sampling_params;
-            for seq in group.get_seqs().values() {
-                let logits = logits.i((group_idx, ..)).unwrap().contiguous();
-                let logits = logits.unwrap().squeeze(0).unwrap();
-                let sq = seq.deref_mut();
-                let tokens = sq
-                    .get_token_ids()
-                    .iter()
-                    .map(|x| *x as u32)
-                    .collect::<Vec<_>>();
-                let tokens_generated = sq.get_len() - sq.get_prompt_len();
-
-                if tokens_generated > sampling_params.max_tokens {
-                    let mut result = shared_result.lock().unwrap();
-                    result.insert(group_idx, Right("length".to_string()));
-                    break;
-                }
-
-                let logits = if sampling_params.repetition_penalty == 1.
-                    || self.args.repeat_last_n.unwrap_or(64) >= tokens_generated
-                {
-                    logits
-                } else {
-                    let start_at = tokens
-                        .len()
-                        .saturating_sub(self.args.repeat_last_n.unwrap_or(64));
-                    candle_transformers::utils::apply_repeat_penalty(
-                        &logits,
-                        sampling_params.repetition_penalty,
-                        &tokens[start_at..],
-                    )
-                    .unwrap_or(logits)
-                };
-
-                let next_token = self.logits_processor.sample(&logits).unwrap();
-                let mut text = self
-                    .tokenizer
-                    .tokenizer()
-                    .decode(&[next_token], false)
-                    .unwrap_or(" ".to_string());
-                let origin_text = self
-                    .tokenizer
-                    .tokenizer()
-                    .id_to_token(next_token)
-                    .unwrap_or("".to_string());
-                //properly handle space token
-                if origin_text.contains("▁") && origin_text.replace("▁", "") == text {
-                    text = origin_text.replace("▁", " ");
-                }
-                if self.stop_token_ids.contains(&next_token) && tokens_generated > 1 {
-                    let mut result = shared_result.lock().unwrap();
-                    result.insert(group_idx, Right("stop".to_string()));
-                    break;
-                }
-                {
-                    let logprob = Logprobs {
-                        token: next_token as usize,
-                        logprob: 0.0,
-                        top_logprobs: Vec::<TopLogprob>::new(),
-                        bytes: text,
-                    };
-                    let mut result = shared_result.lock().unwrap();
-                    result.insert(group_idx, Left(logprob));
-                }
-            }
-        });
-
-        let final_result = Arc::try_unwrap(shared_result)
-            .expect("Arc should have only one reference left")
-            .into_inner()
-            .expect("Mutex should not be poisoned");
-
-        let mut sorted_vec: Vec<_> = final_result.into_iter().collect();
-        sorted_vec.sort_by_key(|&(key, _)| key);
-
-        let result: Vec<TokenOrFinishReason> =
-            sorted_vec.into_iter().map(|(_, value)| value).collect();
+    ) -> Result<Vec<Result<TokenOrFinishReason, APIError>>, APIError> {
+        let logits = penalize_batch(&logits, groups, self.args.repeat_last_n.unwrap_or(64))
+            .unwrap_or(logits);
+        Ok(sample_groups(
+            &logits,
+            groups,
+            &self.logits_processor,
+            &self.stop_token_ids,
+            self.tokenizer.tokenizer(),
+        ))
+    }
 
-        Ok(result)
+    fn is_greedy(&self) -> bool {
+        self.logits_processor.is_greedy()
     }
 
     fn name(&self) -> &str {
@@ -550,10 +771,18 @@ impl ModulePipeline for DefaultPipeline {
             LLMModel::Phi2(phi) => phi.get_config().clone(),
             LLMModel::Phi3(phi) => phi.get_config().clone(),
             LLMModel::Qwen2(qwen2) => qwen2.get_config().clone(),
+            LLMModel::Qwen2Moe(qwen2_moe) => qwen2_moe.get_config().clone(),
             LLMModel::Gemma(gemma) => gemma.get_config().clone(),
+            LLMModel::Gemma2(gemma2) => gemma2.get_config().clone(),
             LLMModel::Mistral(mistral) => mistral.get_config().clone(),
+            LLMModel::Mixtral(mixtral) => mixtral.get_config().clone(),
             LLMModel::Yi(yi) => yi.get_config().clone(),
+            LLMModel::DeepSeekV2(deepseek) => deepseek.get_config().clone(),
+            LLMModel::Glm4(glm4) => glm4.get_config().clone(),
             LLMModel::StableLM(stablelm) => stablelm.get_config().clone(),
+            LLMModel::Bert(bert) => bert.get_config().clone(),
+            LLMModel::Mamba2(mamba2) => mamba2.get_config().clone(),
+            LLMModel::T5(t5) => t5.get_config().clone(),
         }
     }
 
@@ -570,6 +799,505 @@ impl ModulePipeline for DefaultPipeline {
         self.tokenizer.clear();
         ret
     }
+
+    fn embed(&mut self, input_ids: &[u32], pooling: Option<Pooling>) -> Result<Tensor, APIError> {
+        let num_tokens = input_ids.len();
+        if num_tokens == 0 {
+            return Err(APIError::new_str("Cannot embed an empty input."));
+        }
+        let input = try_api!(try_api!(Tensor::new(input_ids, &self.device)).unsqueeze(0));
+        // Nothing is written to the KV cache, the slots are never read
+        let slot_mapping = try_api!(Tensor::zeros(num_tokens, DType::I64, &self.device));
+        let mut metadata = InputMetadata::new(
+            vec![num_tokens],
+            None,
+            None,
+            None,
+            slot_mapping,
+            "auto".to_string(),
+        );
+        // Decoders only see the whole input at the last token
+        let (hidden, default_pooling) = match &mut self.model {
+            LLMModel::Bert(bert) => (
+                bert.embed(&input),
+                self.args.pooling.unwrap_or(Pooling::Cls),
+            ),
+            LLMModel::Llama(llama) => (llama.embed(&input, &mut metadata), Pooling::LastToken),
+            LLMModel::Qwen2(qwen2) => (qwen2.embed(&input, &mut metadata), Pooling::LastToken),
+            LLMModel::Mistral(mistral) => {
+                (mistral.embed(&input, &mut metadata), Pooling::LastToken)
+            }
+            _ => {
+                return Err(APIError::new(format!(
+                    "Embeddings are not supported for {} models.",
+                    self.name
+                )))
+            }
+        };
+        let pool = |hidden: Tensor| -> candle_core::Result<Tensor> {
+            // (seq_len, hidden_size), pooled in F32 to avoid overflowing half precision sums
+            let hidden = hidden.squeeze(0)?.to_dtype(DType::F32)?;
+            let pooled = match pooling.unwrap_or(default_pooling) {
+                Pooling::Mean => hidden.mean(0)?,
+                Pooling::Cls => hidden.i(0)?,
+                Pooling::LastToken => hidden.i(num_tokens - 1)?,
+            };
+            let norm = pooled.sqr()?.sum_all()?.sqrt()?;
+            pooled.broadcast_div(&norm)
+        };
+        pool(try_api!(hidden)).map_err(APIError::from)
+    }
+
+    fn prompt_logprobs(
+        &mut self,
+        input_ids: &[u32],
+        top_n: usize,
+    ) -> Result<Vec<Option<Logprobs>>, APIError> {
+        let num_tokens = input_ids.len();
+        if num_tokens == 0 {
+            return Err(APIError::new_str("Cannot score an empty prompt."));
+        }
+        let input = try_api!(try_api!(Tensor::new(input_ids, &self.device)).unsqueeze(0));
+        // Nothing is written to the KV cache, the slots are never read
+        let slot_mapping = try_api!(Tensor::zeros(num_tokens, DType::I64, &self.device));
+        let mut metadata = InputMetadata::new(
+            vec![num_tokens],
+            None,
+            None,
+            None,
+            slot_mapping,
+            "auto".to_string(),
+        );
+        let logits = match &mut self.model {
+            LLMModel::Llama(llama) => llama.prompt_logits(&input, &mut metadata),
+            LLMModel::Qwen2(qwen2) => qwen2.prompt_logits(&input, &mut metadata),
+            LLMModel::Mistral(mistral) => mistral.prompt_logits(&input, &mut metadata),
+            _ => {
+                return Err(APIError::new(format!(
+                    "Prompt logprobs are not supported for {} models.",
+                    self.name
+                )))
+            }
+        };
+        let logits = try_api!(try_api!(logits).squeeze(0));
+        let decode = |token: u32| {
+            self.tokenizer
+                .tokenizer()
+                .decode(&[token], false)
+                .unwrap_or_default()
+        };
+        let mut logprobs = vec![None];
+        // The logits at a position score the token after it
+        for (position, &token) in input_ids.iter().enumerate().skip(1) {
+            let (logprob, top_logprobs) = try_api!(token_logprobs(
+                &try_api!(logits.i(position - 1)),
+                token,
+                top_n
+            ));
+            logprobs.push(Some(Logprobs {
+                token: token as usize,
+                logprob,
+                bytes: decode(token),
+                top_logprobs: top_logprobs
+                    .into_iter()
+                    .map(|(token, logprob)| TopLogprob {
+                        token: token as usize,
+                        logprob,
+                        bytes: decode(token),
+                    })
+                    .collect(),
+            }));
+        }
+        Ok(logprobs)
+    }
+
+    fn recurrent_state(&self) -> Result<Option<Vec<Tensor>>, APIError> {
+        match &self.model {
+            LLMModel::Mamba2(mamba2) => Ok(Some(try_api!(mamba2.initial_state()))),
+            _ => Ok(None),
+        }
+    }
+
+    fn forward_recurrent(
+        &mut self,
+        input_tokens: Tensor,
+        state: &mut [Tensor],
+    ) -> Result<Tensor, APIError> {
+        match &self.model {
+            LLMModel::Mamba2(mamba2) => {
+                mamba2.forward(&input_tokens, state).map_err(APIError::from)
+            }
+            _ => Err(APIError::new(format!(
+                "{} models are not recurrent, they run over the paged KV cache.",
+                self.name
+            ))),
+        }
+    }
+
+    fn decoder_start_token_id(&self) -> Option<u32> {
+        match &self.model {
+            LLMModel::T5(t5) => Some(t5.decoder_start_token_id()),
+            _ => None,
+        }
+    }
+
+    fn encode(&mut self, input_tokens: Tensor) -> Result<Vec<KVCache>, APIError> {
+        match &self.model {
+            LLMModel::T5(t5) => t5.encode(&input_tokens).map_err(APIError::from),
+            _ => Err(APIError::new(format!(
+                "{} models are decoder-only, they have no encoder.",
+                self.name
+            ))),
+        }
+    }
+
+    fn forward_decoder(
+        &mut self,
+        input_tokens: Tensor,
+        kv: &mut [EncoderDecoderKv],
+    ) -> Result<Tensor, APIError> {
+        match &self.model {
+            LLMModel::T5(t5) => t5.decode(&input_tokens, kv).map_err(APIError::from),
+            _ => Err(APIError::new(format!(
+                "{} models are decoder-only, they have no encoder.",
+                self.name
+            ))),
+        }
+    }
+
+    fn is_encoder_only(&self) -> bool {
+        matches!(self.model, LLMModel::Bert(_)) || self.score_head.is_some()
+    }
+
+    fn token_labels(&self) -> &[String] {
+        match &self.model {
+            LLMModel::Bert(bert) => bert.labels(),
+            _ => &[],
+        }
+    }
+
+    fn classify_tokens(
+        &mut self,
+        inputs: &[Vec<u32>],
+    ) -> Result<Vec<Vec<(String, f32)>>, APIError> {
+        let LLMModel::Bert(bert) = &self.model else {
+            return Err(APIError::new(format!(
+                "Token classification is not supported for {} models.",
+                self.name
+            )));
+        };
+        let max_len = inputs.iter().map(Vec::len).max().unwrap_or(0);
+        if max_len == 0 {
+            return Err(APIError::new_str("Cannot classify an empty input."));
+        }
+        // One prefill-only forward for the whole batch, right-padded and masked
+        let shape = (inputs.len(), max_len);
+        let input_ids = inputs
+            .iter()
+            .flat_map(|input| {
+                let mut input = input.clone();
+                input.resize(max_len, 0);
+                input
+            })
+            .collect::<Vec<u32>>();
+        let attention_mask = inputs
+            .iter()
+            .flat_map(|input| (0..max_len).map(|i| u32::from(i < input.len())))
+            .collect::<Vec<u32>>();
+        let input_ids = try_api!(Tensor::from_vec(input_ids, shape, &self.device));
+        let attention_mask = try_api!(Tensor::from_vec(attention_mask, shape, &self.device));
+        let logits = try_api!(bert.classify(&input_ids, &attention_mask));
+        let probs = try_api!(candle_nn::ops::softmax_last_dim(&try_api!(
+            logits.to_dtype(DType::F32)
+        )));
+        let probs = try_api!(probs.to_vec3::<f32>());
+        let labels = bert.labels();
+        Ok(inputs
+            .iter()
+            .zip(probs)
+            .map(|(input, probs)| {
+                probs[..input.len()]
+                    .iter()
+                    .map(|probs| {
+                        let (class, score) = probs
+                            .iter()
+                            .copied()
+                            .enumerate()
+                            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                            .unwrap_or((0, 0.));
+                        (labels[class].clone(), score)
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn score_labels(&self) -> &[String] {
+        self.score_head
+            .as_ref()
+            .map_or(&[], |score_head| score_head.labels())
+    }
+
+    fn score(&mut self, input_ids: &[u32]) -> Result<Vec<f32>, APIError> {
+        let Some(score_head) = &self.score_head else {
+            return Err(APIError::new(format!(
+                "This {} model has no score head.",
+                self.name
+            )));
+        };
+        let num_tokens = input_ids.len();
+        if num_tokens == 0 {
+            return Err(APIError::new_str("Cannot score an empty input."));
+        }
+        let input = try_api!(try_api!(Tensor::new(input_ids, &self.device)).unsqueeze(0));
+        // Nothing is written to the KV cache, the slots are never read
+        let slot_mapping = try_api!(Tensor::zeros(num_tokens, DType::I64, &self.device));
+        let mut metadata = InputMetadata::new(
+            vec![num_tokens],
+            None,
+            None,
+            None,
+            slot_mapping,
+            "auto".to_string(),
+        );
+        let hidden = match &mut self.model {
+            LLMModel::Llama(llama) => llama.embed(&input, &mut metadata),
+            LLMModel::Qwen2(qwen2) => qwen2.embed(&input, &mut metadata),
+            LLMModel::Mistral(mistral) => mistral.embed(&input, &mut metadata),
+            _ => {
+                return Err(APIError::new(format!(
+                    "Reward models are not supported for {} models.",
+                    self.name
+                )))
+            }
+        };
+        let scores = try_api!(score_head.forward(&try_api!(hidden)));
+        Ok(try_api!(try_api!(scores.squeeze(0)).to_vec1::<f32>()))
+    }
+}
+
+/// Sample the next token of each group from its row of the (penalized) `logits`, the results
+/// are in the order of `groups`.
+pub fn sample_groups(
+    logits: &Tensor,
+    groups: &VecDeque<Arc<SequenceGroup>>,
+    logits_processor: &LogitsProcessor,
+    stop_token_ids: &[u32],
+    tokenizer: &Tokenizer,
+) -> Vec<Result<TokenOrFinishReason, APIError>> {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    let shared_result = Arc::new(Mutex::new(HashMap::<
+        usize,
+        Result<TokenOrFinishReason, APIError>,
+    >::new()));
+    // The rows of `logits` follow the order of `groups`, not the order rayon runs them in
+    groups
+        .par_iter()
+        .enumerate()
+        .for_each(|(group_idx, group)| {
+            let sampling_params = &group.sampling_params;
+            for seq in group.get_seqs().values() {
+                let logits = logits.i((group_idx, ..)).unwrap().contiguous();
+                let logits = logits.unwrap().squeeze(0).unwrap();
+                let mut sq = seq.deref_mut();
+                let tokens_generated = sq.get_len() - sq.get_prompt_len();
+
+                if tokens_generated > sampling_params.max_tokens {
+                    let mut result = shared_result.lock().unwrap();
+                    result.insert(group_idx, Ok(Right("length".to_string())));
+                    break;
+                }
+
+                let guided = sampling_params.guide.as_ref().map(|guide| {
+                    let state = sq.get_guided_state().unwrap_or_else(|| guide.start_state());
+                    (guide, state)
+                });
+                if let Some((guide, state)) = &guided {
+                    // Only the end of sequence can follow the guided output, even before `min_tokens`
+                    if guide.is_accepting(state) && guide.allowed_tokens(state).is_empty() {
+                        let mut result = shared_result.lock().unwrap();
+                        result.insert(group_idx, Ok(Right("stop".to_string())));
+                        break;
+                    }
+                }
+
+                let logits =
+                    apply_logit_bias(&logits, &sampling_params.logit_bias).unwrap_or(logits);
+                // Nothing ends the output before `min_tokens`
+                let logits = if tokens_generated < sampling_params.min_tokens {
+                    let stop_tokens = stop_token_ids
+                        .iter()
+                        .copied()
+                        .chain(sampling_params.stop_token_ids.iter().map(|t| *t as u32))
+                        .collect::<Vec<_>>();
+                    ban_tokens(&logits, &stop_tokens).unwrap_or(logits)
+                } else {
+                    logits
+                };
+
+                let logits = match &guided {
+                    Some((guide, state)) => {
+                        match guide.mask_logits(state, &logits, stop_token_ids) {
+                            Ok(Some(logits)) => logits,
+                            Ok(None) => {
+                                // No token can continue the guided output.
+                                let mut result = shared_result.lock().unwrap();
+                                result.insert(group_idx, Ok(Right("stop".to_string())));
+                                break;
+                            }
+                            Err(e) => {
+                                // Sampling without the guide would break the constraint
+                                let mut result = shared_result.lock().unwrap();
+                                result.insert(
+                                    group_idx,
+                                    Err(APIError::new(format!("Guided decoding failed: {e}"))),
+                                );
+                                break;
+                            }
+                        }
+                    }
+                    None => logits,
+                };
+
+                let filter = |prs: &mut [f32]| {
+                    apply_min_p_typical_p(prs, sampling_params.min_p, sampling_params.typical_p)
+                };
+                let next_token = match group.rng() {
+                    Some(rng) => logits_processor.sample_f_with_rng(&logits, filter, rng),
+                    None => logits_processor.sample_f(&logits, filter),
+                };
+                let next_token = match next_token {
+                    Ok(next_token) => next_token,
+                    Err(e) => {
+                        // E.g. every token is banned or masked out
+                        let mut result = shared_result.lock().unwrap();
+                        result.insert(
+                            group_idx,
+                            Err(APIError::new(format!("Sampling failed: {e}"))),
+                        );
+                        break;
+                    }
+                };
+                // Stop tokens are matched on ids, before detokenization.
+                if (!sampling_params.ignore_eos
+                    && stop_token_ids.contains(&next_token)
+                    && (tokens_generated > 1 || guided.is_some()))
+                    || sampling_params
+                        .stop_token_ids
+                        .contains(&(next_token as usize))
+                {
+                    let mut result = shared_result.lock().unwrap();
+                    result.insert(group_idx, Ok(Right("stop".to_string())));
+                    break;
+                }
+                if let Some((guide, state)) = guided {
+                    if let Some(state) = guide.next_state(&state, next_token) {
+                        sq.set_guided_state(Some(state));
+                    }
+                }
+                let mut text = tokenizer
+                    .decode(&[next_token], false)
+                    .unwrap_or(" ".to_string());
+                let origin_text = tokenizer.id_to_token(next_token).unwrap_or("".to_string());
+                //properly handle space token
+                if origin_text.contains("▁") && origin_text.replace("▁", "") == text {
+                    text = origin_text.replace("▁", " ");
+                }
+                {
+                    // Under the logits the token was sampled from, after penalties and biases
+                    let (logprob, top_logprobs) = if group.use_logprobs {
+                        let top_n = sampling_params.logprobs.unwrap_or(0);
+                        token_logprobs(&logits, next_token, top_n).unwrap_or_default()
+                    } else {
+                        (0.0, Vec::new())
+                    };
+                    let top_logprobs = top_logprobs
+                        .into_iter()
+                        .map(|(token, logprob)| TopLogprob {
+                            token: token as usize,
+                            logprob,
+                            bytes: tokenizer.decode(&[token], false).unwrap_or_default(),
+                        })
+                        .collect();
+                    let logprob = Logprobs {
+                        token: next_token as usize,
+                        logprob,
+                        top_logprobs,
+                        bytes: text,
+                    };
+                    let mut result = shared_result.lock().unwrap();
+                    result.insert(group_idx, Ok(Left(logprob)));
+                }
+            }
+        });
+
+    let final_result = Arc::try_unwrap(shared_result)
+        .expect("Arc should have only one reference left")
+        .into_inner()
+        .expect("Mutex should not be poisoned");
+
+    let mut sorted_vec: Vec<_> = final_result.into_iter().collect();
+    sorted_vec.sort_by_key(|&(key, _)| key);
+
+    sorted_vec.into_iter().map(|(_, value)| value).collect()
+}
+
+/// Apply the repetition, presence and frequency penalties of the groups to their rows of
+/// `logits` in a single pass, the sequences of a group share a row. The repetition penalty counts
+/// the last `repeat_last_n` tokens once more tokens than that were generated, the presence and
+/// frequency penalties count all the generated tokens.
+fn penalize_batch(
+    logits: &Tensor,
+    groups: &VecDeque<Arc<SequenceGroup>>,
+    repeat_last_n: usize,
+) -> candle_core::Result<Tensor> {
+    let mut tokens = Vec::with_capacity(groups.len());
+    let mut penalties = Vec::with_capacity(groups.len());
+    for group in groups {
+        let params = &group.sampling_params;
+        let Some(seq) = group.get_seqs().values().next() else {
+            tokens.push((Vec::new(), 0, 0));
+            penalties.push(Penalties::default());
+            continue;
+        };
+        let seq = seq.deref();
+        let token_ids = seq
+            .get_token_ids()
+            .iter()
+            .map(|x| *x as u32)
+            .collect::<Vec<_>>();
+        let prompt_len = seq.get_prompt_len();
+        let tokens_generated = token_ids.len() - prompt_len;
+        let window_start = if params.repetition_penalty == 1. || repeat_last_n >= tokens_generated {
+            token_ids.len()
+        } else {
+            token_ids.len().saturating_sub(repeat_last_n)
+        };
+        tokens.push((token_ids, window_start, prompt_len));
+        penalties.push(Penalties {
+            repetition: params.repetition_penalty,
+            presence: params.presence_penalty,
+            frequency: params.frequency_penalty,
+        });
+    }
+    if penalties.iter().all(Penalties::is_neutral) {
+        return Ok(logits.clone());
+    }
+    let sequences = tokens
+        .iter()
+        .map(|(token_ids, window_start, prompt_len)| {
+            (&token_ids[*window_start..], &token_ids[*prompt_len..])
+        })
+        .collect::<Vec<_>>();
+    // A copy of its own, the penalties are applied in place
+    let penalized = logits
+        .reshape((groups.len(), ()))?
+        .to_dtype(DType::F32)?
+        .copy()?;
+    let (tokens, counts) = penalty_counts(&sequences, logits.device())?;
+    apply_penalties(&penalized, &tokens, &counts, &penalties)?;
+    penalized.reshape(logits.shape())
 }
 
 unsafe impl Send for DefaultPipeline {}
@@ -0,0 +1,340 @@
+//! Loading of the safetensors weights. The shards are memory mapped and every tensor is read when
+//! the model asks for it, so the checkpoint is never held in host memory as a whole. The shards
+//! are read ahead of the model a few at a time on background threads, which brings their pages
+//! into the page cache in parallel. On CUDA, the bytes of a tensor are copied to the GPU through
+//! a pair of pinned staging buffers: one is filled from the mapped file while the other is being
+//! copied, instead of a pageable copy of the whole tensor.
+//!
+//! Modules can be loaded in another dtype than the model with [`DtypeOverride`]s, e.g. to keep the
+//! norms and the LM head in f32 while the rest of the model runs in bf16.
+
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Result, Shape, Tensor};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::{Init, VarBuilder};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Shards read ahead of the one the model is loaded from
+pub const MAX_PARALLEL_SHARDS: usize = 4;
+/// Bytes read at a time when reading a shard ahead
+const READ_AHEAD_CHUNK: usize = 16 * 1024 * 1024;
+
+/// Parse a dtype name: `f16`, `bf16` or `f32`.
+pub fn parse_dtype(name: &str) -> Option<DType> {
+    match name {
+        "f16" => Some(DType::F16),
+        "bf16" => Some(DType::BF16),
+        "f32" => Some(DType::F32),
+        _ => None,
+    }
+}
+
+/// Load the weights of the modules whose path contains `pattern` in `dtype`, written
+/// `<pattern>=<dtype>`: `lm_head=f32` matches the LM head, `norm=f32` all the norms. The linear
+/// layers and RMS norms of these modules compute in their dtype and return the dtype of their
+/// input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DtypeOverride {
+    pub pattern: String,
+    pub dtype: DType,
+}
+
+impl FromStr for DtypeOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let Some((pattern, dtype)) = s.split_once('=') else {
+            return Err(format!("expected <module>=<dtype>, got `{s}`"));
+        };
+        let dtype = parse_dtype(dtype.trim())
+            .ok_or_else(|| format!("unsupported dtype `{dtype}`, use f16, bf16 or f32"))?;
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(format!("no module in `{s}`"));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            dtype,
+        })
+    }
+}
+
+/// The memory-mapped shards of a checkpoint, a [`SimpleBackend`] of a [`VarBuilder`].
+pub struct StagedSafetensors {
+    paths: Vec<PathBuf>,
+    shards: Vec<MmapedSafetensors>,
+    /// Shard of every tensor
+    routing: HashMap<String, usize>,
+    /// Dtype of the tensors of the modules matching an override, the first match wins
+    overrides: Vec<DtypeOverride>,
+    /// Shards handed to the read-ahead threads, in order
+    read_ahead: Mutex<usize>,
+    #[cfg(feature = "cuda")]
+    staging: Mutex<Option<cuda::PinnedStaging>>,
+}
+
+impl StagedSafetensors {
+    /// Map the shards, no tensor is read yet.
+    ///
+    /// # Safety
+    ///
+    /// The files must not be modified while they are mapped, see [`MmapedSafetensors::new`].
+    pub unsafe fn new(paths: &[PathBuf], overrides: &[DtypeOverride]) -> Result<Self> {
+        let shards = paths
+            .iter()
+            .map(|path| MmapedSafetensors::new(path))
+            .collect::<Result<Vec<_>>>()?;
+        let mut routing = HashMap::new();
+        for (idx, shard) in shards.iter().enumerate() {
+            for (name, _) in shard.tensors() {
+                routing.insert(name, idx);
+            }
+        }
+        for dtype_override in overrides {
+            let matches = routing
+                .keys()
+                .filter(|name| Self::module_of(name).contains(&dtype_override.pattern))
+                .count();
+            if matches == 0 {
+                tracing::warn!(
+                    "The dtype override of `{}` matches no weight.",
+                    dtype_override.pattern
+                );
+            } else {
+                tracing::info!(
+                    "Loading {matches} weights matching `{}` in {:?}.",
+                    dtype_override.pattern,
+                    dtype_override.dtype
+                );
+            }
+        }
+        Ok(Self {
+            paths: paths.to_vec(),
+            shards,
+            routing,
+            overrides: overrides.to_vec(),
+            read_ahead: Mutex::new(0),
+            #[cfg(feature = "cuda")]
+            staging: Mutex::new(None),
+        })
+    }
+
+    /// Path of the module of a tensor, e.g. `model.norm` for `model.norm.weight`.
+    fn module_of(name: &str) -> &str {
+        name.rsplit_once('.').map_or(name, |(module, _)| module)
+    }
+
+    /// Dtype of the tensor `name`, `dtype` unless an override matches its module.
+    pub fn dtype_of(&self, name: &str, dtype: DType) -> DType {
+        let module = Self::module_of(name);
+        self.overrides
+            .iter()
+            .find(|dtype_override| module.contains(&dtype_override.pattern))
+            .map_or(dtype, |dtype_override| dtype_override.dtype)
+    }
+
+    /// Read the shards up to `MAX_PARALLEL_SHARDS` after `shard` into the page cache, each on
+    /// its own thread. Shards are only read once, in order.
+    fn read_ahead(&self, shard: usize) {
+        let mut next = self.read_ahead.lock().unwrap();
+        let end = (shard + MAX_PARALLEL_SHARDS).min(self.paths.len());
+        while *next < end {
+            let path = self.paths[*next].clone();
+            std::thread::spawn(move || {
+                let Ok(mut file) = std::fs::File::open(&path) else {
+                    return;
+                };
+                let mut buffer = vec![0u8; READ_AHEAD_CHUNK];
+                while matches!(file.read(&mut buffer), Ok(n) if n > 0) {}
+            });
+            *next += 1;
+        }
+    }
+
+    /// Whether a safetensors dtype is FP8 E4M3. Candle has no such dtype and does not export
+    /// those of safetensors, so its name is compared.
+    fn is_fp8(dtype: impl std::fmt::Debug) -> bool {
+        format!("{dtype:?}") == "F8_E4M3"
+    }
+
+    /// Load a tensor in the dtype of the checkpoint.
+    fn load(&self, name: &str, device: &Device) -> Result<Tensor> {
+        let Some(&shard) = self.routing.get(name) else {
+            Err(candle_core::Error::CannotFindTensor {
+                path: name.to_string(),
+            }
+            .bt())?
+        };
+        self.read_ahead(shard);
+        let view = self.shards[shard].get(name)?;
+        if Self::is_fp8(view.dtype()) {
+            // Kept as bytes, see `backend::fp8_dequantize`
+            return Tensor::from_raw_buffer(view.data(), DType::U8, view.shape(), device);
+        }
+        match device {
+            #[cfg(feature = "cuda")]
+            Device::Cuda(_) => {
+                let dtype = DType::try_from(view.dtype())?;
+                let tensor = Tensor::zeros(view.shape(), dtype, device)?;
+                let mut staging = self.staging.lock().unwrap();
+                cuda::copy_to_tensor(&mut staging, view.data(), &tensor)?;
+                Ok(tensor)
+            }
+            _ => candle_core::safetensors::Load::load(&view, device),
+        }
+    }
+}
+
+impl SimpleBackend for StagedSafetensors {
+    fn get(&self, s: Shape, name: &str, _: Init, dtype: DType, dev: &Device) -> Result<Tensor> {
+        let tensor = self.load(name, dev)?;
+        // FP8 weights are only read as their bytes, by the layers that dequantize them
+        let tensor = match (tensor.dtype(), dtype) {
+            (DType::U8, DType::U8) => tensor,
+            (DType::U8, _) => Err(candle_core::Error::Msg(format!(
+                "{name} is quantized, it is only supported in linear layers"
+            ))
+            .bt())?,
+            _ => tensor.to_dtype(self.dtype_of(name, dtype))?,
+        };
+        if tensor.shape() != &s {
+            Err(candle_core::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name}"),
+                expected: s,
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.routing.contains_key(name)
+    }
+}
+
+/// A [`VarBuilder`] reading the weights from the memory-mapped `paths`, in `dtype` unless one of
+/// the `overrides` matches their module.
+///
+/// # Safety
+///
+/// The files must not be modified while the model is loaded from them.
+pub unsafe fn var_builder(
+    paths: &[PathBuf],
+    dtype: DType,
+    overrides: &[DtypeOverride],
+    device: &Device,
+) -> Result<VarBuilder<'static>> {
+    let backend = StagedSafetensors::new(paths, overrides)?;
+    Ok(VarBuilder::from_backend(
+        Box::new(backend),
+        dtype,
+        device.clone(),
+    ))
+}
+
+#[cfg(feature = "cuda")]
+mod cuda {
+    use candle_core::cuda_backend::cudarc::driver::{
+        result::{memcpy_htod_async, stream::synchronize, DriverError},
+        sys::{lib, CUdeviceptr},
+        DevicePtr,
+    };
+    use candle_core::cuda_backend::CudaStorageSlice;
+    use candle_core::{Result, Storage, Tensor};
+
+    /// Bytes of each of the two pinned staging buffers
+    const STAGING_BUFFER_SIZE: usize = 32 * 1024 * 1024;
+
+    /// Two page-locked host buffers the weights are copied to the GPU through.
+    pub(super) struct PinnedStaging {
+        buffers: [*mut u8; 2],
+    }
+
+    // The buffers are only used under the lock of the backend
+    unsafe impl Send for PinnedStaging {}
+
+    impl PinnedStaging {
+        fn new() -> std::result::Result<Self, DriverError> {
+            let mut buffers = [std::ptr::null_mut(); 2];
+            for buffer in &mut buffers {
+                let mut ptr = std::ptr::null_mut();
+                unsafe {
+                    lib()
+                        .cuMemHostAlloc(&mut ptr, STAGING_BUFFER_SIZE, 0)
+                        .result()?
+                };
+                *buffer = ptr as *mut u8;
+            }
+            Ok(Self { buffers })
+        }
+    }
+
+    impl Drop for PinnedStaging {
+        fn drop(&mut self) {
+            for buffer in self.buffers {
+                if !buffer.is_null() {
+                    unsafe { lib().cuMemFreeHost(buffer as *mut _) };
+                }
+            }
+        }
+    }
+
+    /// Copy `data` into the freshly allocated contiguous `tensor`. Each chunk is copied to the
+    /// GPU from one buffer while the next chunk is read into the other.
+    pub(super) fn copy_to_tensor(
+        staging: &mut Option<PinnedStaging>,
+        data: &[u8],
+        tensor: &Tensor,
+    ) -> Result<()> {
+        let (storage, _) = tensor.storage_and_layout();
+        let Storage::Cuda(storage) = &*storage else {
+            candle_core::bail!("expected a CUDA tensor")
+        };
+        let dst: CUdeviceptr = match &storage.slice {
+            CudaStorageSlice::U8(slice) => *slice.device_ptr(),
+            CudaStorageSlice::U32(slice) => *slice.device_ptr(),
+            CudaStorageSlice::I64(slice) => *slice.device_ptr(),
+            CudaStorageSlice::BF16(slice) => *slice.device_ptr(),
+            CudaStorageSlice::F16(slice) => *slice.device_ptr(),
+            CudaStorageSlice::F32(slice) => *slice.device_ptr(),
+            CudaStorageSlice::F64(slice) => *slice.device_ptr(),
+        };
+        let device = &storage.device;
+        // Loads also run on the threads of weight streaming
+        device.bind_to_thread().map_err(candle_core::Error::wrap)?;
+        if staging.is_none() {
+            *staging = Some(PinnedStaging::new().map_err(candle_core::Error::wrap)?);
+        }
+        let buffers = staging.as_ref().unwrap().buffers;
+        let stream = *device.cu_stream();
+        let chunks = data.chunks(STAGING_BUFFER_SIZE).collect::<Vec<_>>();
+        let fill = |idx: usize| unsafe {
+            let chunk = chunks[idx];
+            std::ptr::copy_nonoverlapping(chunk.as_ptr(), buffers[idx % 2], chunk.len());
+        };
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        fill(0);
+        let mut offset = 0;
+        for (idx, chunk) in chunks.iter().enumerate() {
+            unsafe {
+                let buffer = std::slice::from_raw_parts(buffers[idx % 2], chunk.len());
+                memcpy_htod_async(dst + offset as u64, buffer, stream)
+                    .map_err(candle_core::Error::wrap)?;
+            }
+            // The other buffer was copied before this copy was queued
+            if idx + 1 < chunks.len() {
+                fill(idx + 1);
+            }
+            unsafe { synchronize(stream).map_err(candle_core::Error::wrap)? };
+            offset += chunk.len();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+use axum::response::Html;
+
+const PLAYGROUND: &str = include_str!("../../res/playground.html");
+
+/// A minimal chat page to try a deployment from the browser: it reads the model card from
+/// `/v1/models` and sends streamed or plain chat completions with the chosen sampling parameters.
+pub async fn playground() -> Html<&'static str> {
+    Html(PLAYGROUND)
+}
@@ -0,0 +1,265 @@
+//! Request and response transformation plugins. The chat completion handler runs every chat
+//! completion request through the plugins before serving it, and its non-streamed response
+//! before returning it. Plugins see and return the JSON of the request or response, so a
+//! deployment can add prompt augmentation or compliance filtering without forking the server.
+//!
+//! With the `wasm-plugins` feature, WebAssembly modules are loaded from `--plugins-dir`. A module
+//! exports its `memory`, `alloc(len: i32) -> i32` and one or both of the hooks
+//! `transform_request(ptr: i32, len: i32) -> i64` and `transform_response(ptr: i32, len: i32)
+//! -> i64`. A hook gets the UTF-8 JSON written by the host to the buffer returned by `alloc`, and
+//! returns `(out_ptr << 32) | out_len` of the JSON to continue with, or 0 to leave it unchanged.
+//! Returning `{"error": "<message>"}` rejects the request. Modules cannot import anything (no
+//! WASI, so no file, network or clock access), and every call runs in a fresh instance with
+//! bounded memory and fuel.
+
+use derive_more::Display;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+use super::responses::APIError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    Request,
+    Response,
+}
+
+impl Hook {
+    /// Name of the hook exported by WebAssembly plugins.
+    pub fn export_name(self) -> &'static str {
+        match self {
+            Hook::Request => "transform_request",
+            Hook::Response => "transform_response",
+        }
+    }
+}
+
+/// A request/response transformation plugin.
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Transform the JSON `input` at `hook`, `None` leaves it unchanged.
+    fn transform(&self, hook: Hook, input: &[u8]) -> Result<Option<Vec<u8>>, String>;
+}
+
+#[derive(Debug, Display)]
+pub enum PluginError {
+    /// The plugin returned an `error`, e.g. a compliance filter.
+    #[display(fmt = "Rejected by plugin `{}`: {}", plugin, message)]
+    Rejected { plugin: String, message: String },
+    /// The plugin trapped, ran out of fuel or memory, or returned invalid JSON.
+    #[display(fmt = "Plugin `{}` failed: {}", plugin, message)]
+    Failed { plugin: String, message: String },
+}
+
+/// The plugins of the server, run in order.
+#[derive(Default)]
+pub struct PluginHost {
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl PluginHost {
+    pub fn new(plugins: Vec<Box<dyn Plugin>>) -> Self {
+        Self { plugins }
+    }
+
+    /// Load the `*.wasm` modules of `dir` in file name order.
+    #[cfg(feature = "wasm-plugins")]
+    pub fn load_dir(dir: &Path, limits: PluginLimits) -> Result<Self, APIError> {
+        let mut paths = std::fs::read_dir(dir)
+            .map_err(|e| APIError::new(format!("Cannot read the plugins directory: {e}")))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        let plugins = paths
+            .iter()
+            .map(|path| {
+                let name = path
+                    .file_stem()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let bytes = std::fs::read(path).map_err(APIError::from)?;
+                let plugin = WasmPlugin::new(&name, &bytes, limits)
+                    .map_err(|e| APIError::new(format!("Cannot load plugin `{name}`: {e}")))?;
+                Ok(Box::new(plugin) as Box<dyn Plugin>)
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        Ok(Self::new(plugins))
+    }
+
+    #[cfg(not(feature = "wasm-plugins"))]
+    pub fn load_dir(_dir: &Path, _limits: PluginLimits) -> Result<Self, APIError> {
+        Err(APIError::new_str(
+            "Plugins require building with the `wasm-plugins` feature.",
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.plugins.iter().map(|plugin| plugin.name()).collect()
+    }
+
+    /// Run `value` through the `hook` of every plugin in order, each plugin sees the output of
+    /// the previous one.
+    pub fn transform<T: Serialize + DeserializeOwned>(
+        &self,
+        hook: Hook,
+        value: T,
+    ) -> Result<T, PluginError> {
+        let mut transformed: Option<(&str, Value)> = None;
+        for plugin in &self.plugins {
+            let failed = |message: String| PluginError::Failed {
+                plugin: plugin.name().to_string(),
+                message,
+            };
+            let input = match &transformed {
+                Some((_, json)) => serde_json::to_vec(json),
+                None => serde_json::to_vec(&value),
+            };
+            let input = input.map_err(|e| failed(e.to_string()))?;
+            let Some(output) = plugin.transform(hook, &input).map_err(failed)? else {
+                continue;
+            };
+            let output: Value = serde_json::from_slice(&output)
+                .map_err(|e| failed(format!("invalid JSON output: {e}")))?;
+            if let Some(message) = output.get("error") {
+                return Err(PluginError::Rejected {
+                    plugin: plugin.name().to_string(),
+                    message: message
+                        .as_str()
+                        .map(str::to_string)
+                        .unwrap_or_else(|| message.to_string()),
+                });
+            }
+            transformed = Some((plugin.name(), output));
+        }
+        match transformed {
+            Some((plugin, json)) => serde_json::from_value(json).map_err(|e| PluginError::Failed {
+                plugin: plugin.to_string(),
+                message: format!("invalid output: {e}"),
+            }),
+            None => Ok(value),
+        }
+    }
+}
+
+/// Resource limits of every call of a WebAssembly plugin.
+#[derive(Debug, Clone, Copy)]
+pub struct PluginLimits {
+    /// Linear memory of an instance
+    pub max_memory_bytes: usize,
+    /// Fuel of a call, roughly the number of WebAssembly instructions it may execute
+    pub fuel: u64,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        Self {
+            max_memory_bytes: 64 << 20,
+            fuel: 1_000_000_000,
+        }
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+pub use self::wasm::WasmPlugin;
+
+#[cfg(feature = "wasm-plugins")]
+mod wasm {
+    use super::{Hook, Plugin, PluginLimits};
+    use wasmtime::{Config, Engine, Error, InstancePre, Linker, Module, Store, StoreLimits};
+
+    /// A WebAssembly plugin, instantiated anew for every call.
+    pub struct WasmPlugin {
+        name: String,
+        engine: Engine,
+        instance: InstancePre<StoreLimits>,
+        hooks: Vec<Hook>,
+        limits: PluginLimits,
+    }
+
+    impl WasmPlugin {
+        /// Compile a module, from its binary or text format.
+        pub fn new(name: &str, bytes: &[u8], limits: PluginLimits) -> wasmtime::Result<Self> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)?;
+            let module = Module::new(&engine, bytes)?;
+            if let Some(import) = module.imports().next() {
+                return Err(Error::msg(format!(
+                    "plugins cannot import anything, it imports `{}::{}`",
+                    import.module(),
+                    import.name()
+                )));
+            }
+            for export in ["memory", "alloc"] {
+                if module.get_export(export).is_none() {
+                    return Err(Error::msg(format!("`{export}` is not exported")));
+                }
+            }
+            let hooks = [Hook::Request, Hook::Response]
+                .into_iter()
+                .filter(|hook| module.get_export(hook.export_name()).is_some())
+                .collect::<Vec<_>>();
+            if hooks.is_empty() {
+                return Err(Error::msg(
+                    "neither `transform_request` nor `transform_response` is exported",
+                ));
+            }
+            let instance = Linker::new(&engine).instantiate_pre(&module)?;
+            Ok(Self {
+                name: name.to_string(),
+                engine,
+                instance,
+                hooks,
+                limits,
+            })
+        }
+
+        fn call(&self, hook: Hook, input: &[u8]) -> wasmtime::Result<Option<Vec<u8>>> {
+            if !self.hooks.contains(&hook) {
+                return Ok(None);
+            }
+            let limits = wasmtime::StoreLimitsBuilder::new()
+                .memory_size(self.limits.max_memory_bytes)
+                .build();
+            let mut store = Store::new(&self.engine, limits);
+            store.limiter(|limits| limits);
+            store.set_fuel(self.limits.fuel)?;
+            let instance = self.instance.instantiate(&mut store)?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| Error::msg("`memory` is not a memory"))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let transform =
+                instance.get_typed_func::<(i32, i32), i64>(&mut store, hook.export_name())?;
+
+            let len = i32::try_from(input.len())?;
+            let ptr = alloc.call(&mut store, len)?;
+            memory.write(&mut store, ptr as u32 as usize, input)?;
+            let output = transform.call(&mut store, (ptr, len))? as u64;
+            if output == 0 {
+                return Ok(None);
+            }
+            let (ptr, len) = ((output >> 32) as usize, (output & 0xffff_ffff) as usize);
+            let mut buf = vec![0; len];
+            memory.read(&store, ptr, &mut buf)?;
+            Ok(Some(buf))
+        }
+    }
+
+    impl Plugin for WasmPlugin {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn transform(&self, hook: Hook, input: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            self.call(hook, input).map_err(|e| format!("{e:#}"))
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Profiling endpoints of the `pprof` feature, to capture performance issues of a running
+//! server without attaching an external profiler.
+//!
+//! - `GET /debug/pprof/profile?seconds=10&frequency=99&format=flamegraph` samples the stacks of
+//!   every thread of the process (the engine threads included) over the window, and returns an
+//!   SVG flamegraph, or with `format=pprof` a protobuf profile for `go tool pprof`.
+//! - `GET /debug/pprof/allocs?seconds=10` counts the allocations made over the window, with
+//!   [`CountingAllocator`] installed as the global allocator.
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Longest sampling window, the request holds its connection open meanwhile.
+const MAX_SECONDS: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileParams {
+    /// Sampling window, 10 seconds by default
+    #[serde(default)]
+    pub seconds: Option<u64>,
+    /// Samples per second, 99 by default
+    #[serde(default)]
+    pub frequency: Option<i32>,
+    /// `flamegraph` (default) or `pprof`
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+fn window(seconds: Option<u64>) -> Result<Duration, Response> {
+    match seconds.unwrap_or(10) {
+        seconds @ 1..=MAX_SECONDS => Ok(Duration::from_secs(seconds)),
+        _ => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("`seconds` must be between 1 and {MAX_SECONDS}."),
+        )
+            .into_response()),
+    }
+}
+
+/// Sample a CPU profile of the process over the window.
+pub async fn profile(Query(params): Query<ProfileParams>) -> Response {
+    let window = match window(params.seconds) {
+        Ok(window) => window,
+        Err(response) => return response,
+    };
+    let pprof_format = match params.format.as_deref() {
+        None | Some("flamegraph") => false,
+        Some("pprof") => true,
+        Some(format) => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Unsupported `format` {format}, expected flamegraph or pprof."),
+            )
+                .into_response()
+        }
+    };
+    let frequency = params.frequency.unwrap_or(99).clamp(1, 1000);
+    // The sampler is signal based and blocks its thread for the whole window
+    let profile = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, (StatusCode, String)> {
+        let failed = |e: pprof::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        // Only one profile can be sampled at a time
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(frequency)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+        std::thread::sleep(window);
+        let report = guard.report().build().map_err(failed)?;
+        let mut body = Vec::new();
+        if pprof_format {
+            use pprof::protos::Message;
+            let profile = report.pprof().map_err(failed)?;
+            profile
+                .encode(&mut body)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        } else {
+            report.flamegraph(&mut body).map_err(failed)?;
+        }
+        Ok(body)
+    })
+    .await;
+    match profile {
+        Ok(Ok(body)) if pprof_format => {
+            ([(header::CONTENT_TYPE, "application/octet-stream")], body).into_response()
+        }
+        Ok(Ok(body)) => ([(header::CONTENT_TYPE, "image/svg+xml")], body).into_response(),
+        Ok(Err(e)) => e.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static FREED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting the allocations and their bytes. The server binary installs
+/// it as the global allocator with the `pprof` feature.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        FREED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Counted as freeing the old block and allocating the new one
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        FREED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Allocation counters since the start of the process, or over a window.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct AllocStats {
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub allocated_bytes: u64,
+    pub freed_bytes: u64,
+}
+
+impl AllocStats {
+    pub fn now() -> Self {
+        Self {
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+            allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+            freed_bytes: FREED_BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The counts from `earlier` to `self`.
+    pub fn since(&self, earlier: &AllocStats) -> AllocStats {
+        AllocStats {
+            allocations: self.allocations - earlier.allocations,
+            deallocations: self.deallocations - earlier.deallocations,
+            allocated_bytes: self.allocated_bytes - earlier.allocated_bytes,
+            freed_bytes: self.freed_bytes - earlier.freed_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AllocReport {
+    pub seconds: u64,
+    /// Over the window
+    pub window: AllocStats,
+    /// Bytes allocated and not freed yet, by the whole process
+    pub live_bytes: u64,
+}
+
+/// Count the allocations of the process over the window.
+pub async fn allocs(Query(params): Query<ProfileParams>) -> Response {
+    let window = match window(params.seconds) {
+        Ok(window) => window,
+        Err(response) => return response,
+    };
+    let start = AllocStats::now();
+    tokio::time::sleep(window).await;
+    let end = AllocStats::now();
+    Json(AllocReport {
+        seconds: window.as_secs(),
+        window: end.since(&start),
+        live_bytes: end.allocated_bytes.saturating_sub(end.freed_bytes),
+    })
+    .into_response()
+}
@@ -0,0 +1,68 @@
+//! Routing between the two precision variants of a model served with `--fast-quant`: its weights
+//! in the model dtype (`best`) and the same weights quantized at load time (`fast`), each with its
+//! own engine and KV cache. A request picks one with its `quality` field, the requests without
+//! one are spread over both by weighted round-robin.
+
+use super::requests::Quality;
+use super::responses::APIError;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug)]
+pub struct QualityRouter {
+    /// Name of the model in its dtype.
+    pub best: String,
+    /// Name of its quantized variant.
+    pub fast: String,
+    fast_weight: u64,
+    best_weight: u64,
+    next: AtomicU64,
+}
+
+impl QualityRouter {
+    /// Out of every `fast_weight + best_weight` requests without a `quality`, `fast_weight` go to
+    /// the fast variant.
+    pub fn new(
+        best: String,
+        fast: String,
+        fast_weight: u32,
+        best_weight: u32,
+    ) -> Result<Self, APIError> {
+        if fast_weight == 0 && best_weight == 0 {
+            return Err(APIError::new_str(
+                "--fast-weight and --best-weight cannot both be 0.",
+            ));
+        }
+        Ok(Self {
+            best,
+            fast,
+            fast_weight: fast_weight.into(),
+            best_weight: best_weight.into(),
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// The variant of the next request without a `quality`. The fast requests are interleaved
+    /// evenly with the others, rather than sent in a row.
+    pub fn next(&self) -> Quality {
+        let total = self.fast_weight + self.best_weight;
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % total;
+        // The slot goes to the fast variant when it raises the share of fast slots so far
+        if (slot + 1) * self.fast_weight / total > slot * self.fast_weight / total {
+            Quality::Fast
+        } else {
+            Quality::Best
+        }
+    }
+
+    /// The variant serving a request for `model` with `quality`, `None` when `model` is neither
+    /// variant. A request for the fast variant by name without a `quality` stays on it.
+    pub fn route(&self, model: &str, quality: Option<Quality>) -> Option<Quality> {
+        if model == self.fast {
+            Some(quality.unwrap_or(Quality::Fast))
+        } else if model == self.best {
+            Some(quality.unwrap_or_else(|| self.next()))
+        } else {
+            None
+        }
+    }
+}
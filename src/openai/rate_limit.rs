@@ -0,0 +1,256 @@
+//! Rate limiting of the requests and tokens per minute of every client address. The server does
+//! not check API keys, so the bearer token of a request is not a key: a client could send a new
+//! one with every request to get around its limits. Each key has a bucket of requests and one of tokens that
+//! refill continuously up to the limit per minute, like the limits of the OpenAI API. A request
+//! takes one request when it arrives, and the chat completions and embeddings take their tokens
+//! (the prompt and `max_tokens`) once tokenized. The remaining requests and tokens of the key are
+//! reported in the `x-ratelimit-*` headers of every response.
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{HeaderMap, HeaderValue};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Buckets are pruned once there are this many of them
+const PRUNE_THRESHOLD: usize = 1024;
+const MINUTE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u64>,
+    pub tokens_per_minute: Option<u64>,
+}
+
+/// Who a request is counted against: the address of the client, whatever its `Authorization`
+/// header.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RateLimitKey(String);
+
+impl RateLimitKey {
+    pub fn of(request: &Request) -> Self {
+        let addr = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        Self::of_addr(addr)
+    }
+
+    /// The key of a client at `addr`, the port of its connection does not matter.
+    pub fn of_addr(addr: Option<SocketAddr>) -> Self {
+        match addr {
+            Some(addr) => Self(format!("ip:{}", addr.ip())),
+            None => Self("anonymous".to_string()),
+        }
+    }
+}
+
+/// Why a request or its tokens were not admitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitExceeded {
+    /// Retry after the duration.
+    Requests(Duration),
+    Tokens(Duration),
+    /// The request needs more tokens than the limit per minute, it is never admitted.
+    TooLarge,
+}
+
+/// The limits of a key and what remains of them, as the `x-ratelimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStatus {
+    pub limit_requests: Option<u64>,
+    pub remaining_requests: u64,
+    /// Until the requests are back to their limit
+    pub reset_requests: Duration,
+    pub limit_tokens: Option<u64>,
+    pub remaining_tokens: u64,
+    pub reset_tokens: Duration,
+}
+
+impl RateLimitStatus {
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        if let Some(limit) = self.limit_requests {
+            headers.insert("x-ratelimit-limit-requests", limit.into());
+            headers.insert(
+                "x-ratelimit-remaining-requests",
+                self.remaining_requests.into(),
+            );
+            headers.insert(
+                "x-ratelimit-reset-requests",
+                format_reset(self.reset_requests),
+            );
+        }
+        if let Some(limit) = self.limit_tokens {
+            headers.insert("x-ratelimit-limit-tokens", limit.into());
+            headers.insert("x-ratelimit-remaining-tokens", self.remaining_tokens.into());
+            headers.insert("x-ratelimit-reset-tokens", format_reset(self.reset_tokens));
+        }
+    }
+}
+
+/// A duration as OpenAI writes the resets, e.g. `120ms`, `12s` or `6m0s`.
+fn format_reset(duration: Duration) -> HeaderValue {
+    let reset = if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        let secs = duration.as_secs() + (duration.subsec_nanos() > 0) as u64;
+        if secs < 60 {
+            format!("{secs}s")
+        } else {
+            format!("{}m{}s", secs / 60, secs % 60)
+        }
+    };
+    HeaderValue::from_str(&reset).unwrap()
+}
+
+/// A bucket refilled at `limit` per minute up to `limit`.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    level: f64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: u64, elapsed: Duration) {
+        self.level = (self.level + limit as f64 * elapsed.as_secs_f64() / MINUTE.as_secs_f64())
+            .min(limit as f64);
+    }
+
+    /// Until `amount` is available.
+    fn wait(&self, limit: u64, amount: f64) -> Duration {
+        let missing = (amount - self.level).max(0.);
+        Duration::from_secs_f64(missing * MINUTE.as_secs_f64() / limit as f64)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Bucket,
+    tokens: Bucket,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<RateLimitKey, Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits
+    }
+
+    /// Run `f` on the buckets of `key` refilled up to `now`, a new key starts with full buckets.
+    fn with_buckets<T>(
+        &self,
+        key: &RateLimitKey,
+        now: Instant,
+        f: impl FnOnce(&mut Buckets) -> T,
+    ) -> T {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // Untouched for a minute, the buckets are full again, like those of a new key
+            buckets.retain(|_, buckets| now.saturating_duration_since(buckets.updated) < MINUTE);
+        }
+        let limits = self.limits;
+        let entry = buckets.entry(key.clone()).or_insert_with(|| Buckets {
+            requests: Bucket {
+                level: limits.requests_per_minute.unwrap_or(0) as f64,
+            },
+            tokens: Bucket {
+                level: limits.tokens_per_minute.unwrap_or(0) as f64,
+            },
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(entry.updated);
+        if let Some(limit) = limits.requests_per_minute {
+            entry.requests.refill(limit, elapsed);
+        }
+        if let Some(limit) = limits.tokens_per_minute {
+            entry.tokens.refill(limit, elapsed);
+        }
+        entry.updated = entry.updated.max(now);
+        f(entry)
+    }
+
+    /// Take a request of `key`. Requests are also refused while the tokens of the key are
+    /// exhausted.
+    pub fn acquire_request(
+        &self,
+        key: &RateLimitKey,
+        now: Instant,
+    ) -> Result<(), RateLimitExceeded> {
+        let limits = self.limits;
+        self.with_buckets(key, now, |buckets| {
+            if let Some(limit) = limits.requests_per_minute {
+                if buckets.requests.level < 1. {
+                    let wait = buckets.requests.wait(limit, 1.);
+                    return Err(RateLimitExceeded::Requests(wait));
+                }
+            }
+            if let Some(limit) = limits.tokens_per_minute {
+                if buckets.tokens.level < 1. {
+                    let wait = buckets.tokens.wait(limit, 1.);
+                    return Err(RateLimitExceeded::Tokens(wait));
+                }
+            }
+            if limits.requests_per_minute.is_some() {
+                buckets.requests.level -= 1.;
+            }
+            Ok(())
+        })
+    }
+
+    /// Take `tokens` of `key`, or none of them if there are not enough.
+    pub fn acquire_tokens(
+        &self,
+        key: &RateLimitKey,
+        tokens: usize,
+        now: Instant,
+    ) -> Result<(), RateLimitExceeded> {
+        let Some(limit) = self.limits.tokens_per_minute else {
+            return Ok(());
+        };
+        if tokens as u64 > limit {
+            return Err(RateLimitExceeded::TooLarge);
+        }
+        self.with_buckets(key, now, |buckets| {
+            if buckets.tokens.level < tokens as f64 {
+                return Err(RateLimitExceeded::Tokens(
+                    buckets.tokens.wait(limit, tokens as f64),
+                ));
+            }
+            buckets.tokens.level -= tokens as f64;
+            Ok(())
+        })
+    }
+
+    pub fn status(&self, key: &RateLimitKey, now: Instant) -> RateLimitStatus {
+        let limits = self.limits;
+        self.with_buckets(key, now, |buckets| {
+            let status = |bucket: &Bucket, limit: Option<u64>| match limit {
+                Some(limit) => (bucket.level as u64, bucket.wait(limit, limit as f64)),
+                None => (0, Duration::ZERO),
+            };
+            let (remaining_requests, reset_requests) =
+                status(&buckets.requests, limits.requests_per_minute);
+            let (remaining_tokens, reset_tokens) =
+                status(&buckets.tokens, limits.tokens_per_minute);
+            RateLimitStatus {
+                limit_requests: limits.requests_per_minute,
+                remaining_requests,
+                reset_requests,
+                limit_tokens: limits.tokens_per_minute,
+                remaining_tokens,
+                reset_tokens,
+            }
+        })
+    }
+}
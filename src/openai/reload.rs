@@ -0,0 +1,118 @@
+//! Hot reload of a served model. `POST /admin/reload` stops admitting requests, drains the ones
+//! in flight, unloads the model and loads the model of the request in its place, without
+//! restarting the process: the listener and the connections of the clients are kept. Requests
+//! sent during the reload are turned away with a 503 and a `Retry-After`. The endpoint is only
+//! served with `--admin-port`, on its own listener, to the callers holding the admin token.
+
+use axum::http::{header, HeaderMap};
+use futures::future::BoxFuture;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::requests::ReloadRequest;
+use super::responses::APIError;
+use super::ServedModel;
+
+/// Interval at which a reload checks whether the requests in flight are done.
+pub const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// `Retry-After` of the requests turned away during a reload.
+pub const RELOAD_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Loads the model of a reload request, to be served under the given name.
+pub type ReloadFn = Box<
+    dyn Fn(String, ReloadRequest) -> BoxFuture<'static, Result<ServedModel, APIError>>
+        + Send
+        + Sync,
+>;
+
+/// The admission of the requests and the loading of the models of the reloads.
+pub struct Reloader {
+    loader: ReloadFn,
+    /// Bearer token of the callers of `/admin/reload`
+    token: String,
+    reloading: AtomicBool,
+    /// Admitted requests whose handler has not returned yet. Streamed requests are handed to the
+    /// engine by then.
+    in_flight: AtomicUsize,
+}
+
+impl Reloader {
+    pub fn new(loader: ReloadFn, token: String) -> Self {
+        Self {
+            loader,
+            token,
+            reloading: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether `headers` hold the admin token, as `Authorization: Bearer <token>`.
+    pub fn authorize(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        // In constant time for tokens of the same length
+        token.len() == self.token.len()
+            && token
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Count a request in flight until the guard is dropped, `None` during a reload.
+    pub fn admit(&self) -> Option<InFlight<'_>> {
+        // Counted before the check, so that a reload that starts in between waits for it
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight(self);
+        (!self.reloading.load(Ordering::SeqCst)).then_some(guard)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn is_reloading(&self) -> bool {
+        self.reloading.load(Ordering::SeqCst)
+    }
+
+    /// Stop admitting requests until the guard is dropped, `None` if another reload runs.
+    pub fn begin(&self) -> Option<Reloading<'_>> {
+        self.reloading
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| Reloading(self))
+    }
+
+    /// Load the model of `request` to serve as `model_name`.
+    pub async fn load(
+        &self,
+        model_name: String,
+        request: ReloadRequest,
+    ) -> Result<ServedModel, APIError> {
+        (self.loader)(model_name, request).await
+    }
+}
+
+/// An admitted request, see [`Reloader::admit`].
+pub struct InFlight<'a>(&'a Reloader);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A reload in progress, see [`Reloader::begin`]. Requests are admitted again once it is
+/// dropped, whether the reload succeeded or not.
+pub struct Reloading<'a>(&'a Reloader);
+
+impl Drop for Reloading<'_> {
+    fn drop(&mut self) {
+        self.0.reloading.store(false, Ordering::SeqCst);
+    }
+}
@@ -5,10 +5,16 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Messages {
-    Map(Vec<HashMap<String, String>>),
+    Map(Vec<HashMap<String, serde_json::Value>>),
     Literal(String),
 }
 
+impl Default for Messages {
+    fn default() -> Self {
+        Messages::Map(vec![])
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum StopTokens {
     Multi(Vec<String>),
@@ -16,6 +22,106 @@ pub enum StopTokens {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolChoiceFunction {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NamedToolChoice {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: ToolChoiceFunction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String), //none, auto or required
+    Function(NamedToolChoice),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub schema: serde_json::Value,
+    #[serde(default)]
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseFormat {
+    #[serde(rename = "type")]
+    pub format_type: String, //text, json_object or json_schema
+    #[serde(default)]
+    pub json_schema: Option<JsonSchemaFormat>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: bool, //false, send a final usage-only chunk
+    #[serde(default)]
+    pub continuous_usage_stats: bool, //false, report running usage in every chunk
+}
+
+/// Experimental StreamingLLM-style attention sinks (candle-vllm extension): the KV cache keeps
+/// the first `num_sink_tokens` tokens and the last `window` tokens, the blocks in between are
+/// evicted as the generation goes on, so a session can generate past the context length with a
+/// bounded cache. The cached keys are periodically re-rotated to compact their positions.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AttentionSinks {
+    #[serde(default = "default_num_sink_tokens")]
+    pub num_sink_tokens: usize, //4
+    pub window: usize,
+}
+
+fn default_num_sink_tokens() -> usize {
+    4
+}
+
+/// Eviction hint for the cached prefix of a request (candle-vllm extension). When the KV cache
+/// runs short, `low` prefixes are released first and `pinned` ones only once no other prefix
+/// is left, least recently used first within a priority. A prefix keeps the highest priority
+/// of the requests that used it.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePriority {
+    Low,
+    #[default]
+    Normal,
+    Pinned,
+}
+
+/// Precision variant of the model serving a request (candle-vllm extension), with
+/// `--fast-quant`: `fast` runs it on the quantized weights, `best` on the weights in the model
+/// dtype.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    Fast,
+    Best,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ChatCompletionRequest {
     pub model: String,
     pub messages: Messages,
@@ -32,6 +138,8 @@ pub struct ChatCompletionRequest {
     #[serde(default)]
     pub stream: Option<bool>, //false
     #[serde(default)]
+    pub stream_options: Option<StreamOptions>, //None
+    #[serde(default)]
     pub presence_penalty: Option<f32>, //0.0
     #[serde(default)]
     pub frequency_penalty: Option<f32>, //0.0
@@ -40,20 +148,168 @@ pub struct ChatCompletionRequest {
     #[serde(default)]
     pub user: Option<String>, //None
     #[serde(default)]
+    pub seed: Option<u64>, //None
+    #[serde(default)]
     //Additional candle-vllm params
     pub top_k: Option<isize>, //-1
     #[serde(default)]
+    pub min_p: Option<f32>, //0.0, drop tokens less likely than min_p times the most likely one
+    #[serde(default)]
+    pub typical_p: Option<f32>, //1.0, locally typical sampling
+    #[serde(default)]
     pub best_of: Option<usize>, //None
     #[serde(default)]
     pub use_beam_search: Option<bool>, //false
     #[serde(default)]
     pub ignore_eos: Option<bool>, //false
     #[serde(default)]
+    pub min_tokens: Option<usize>, //0, EOS and stop tokens are not sampled before
+    #[serde(default)]
     pub skip_special_tokens: Option<bool>, //false
     #[serde(default)]
     pub stop_token_ids: Option<Vec<usize>>, //[]
     #[serde(default)]
     pub logprobs: Option<bool>, //false
     #[serde(default)]
+    pub echo: Option<bool>, //false, the completion starts with the prompt
+    #[serde(default)]
+    pub prompt_logprobs: Option<usize>, //None, logprob of every prompt token with this many top logprobs
+    #[serde(default)]
     pub repetition_penalty: Option<f32>, //1.1
+    #[serde(default)]
+    pub tools: Option<Vec<Tool>>, //None
+    #[serde(default)]
+    pub tool_choice: Option<ToolChoice>, //auto
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>, //None
+    #[serde(default)]
+    pub guided_regex: Option<String>, //None, candle-vllm extension
+    #[serde(default)]
+    pub forkable: Option<bool>, //false, candle-vllm extension, keep the KV cache for /v1/chat/completions/fork
+    #[serde(default)]
+    pub attention_sinks: Option<AttentionSinks>, //None, candle-vllm extension, max_tokens may exceed the context length
+    #[serde(default)]
+    pub priority: Option<i32>, //0, candle-vllm extension, lower values are served first with --scheduling-policy priority
+    #[serde(default)]
+    pub cache_priority: Option<CachePriority>, //normal, candle-vllm extension, eviction hint for the cached system prompt
+    #[serde(default)]
+    pub truncate_prompt_tokens: Option<isize>, //None, candle-vllm extension, keep the last k prompt tokens, -1 for as many as fit with max_tokens
+    #[serde(default)]
+    pub session_id: Option<String>, //None, candle-vllm extension, keep the KV cache of the conversation for its next request
+    #[serde(default)]
+    pub timeout: Option<f64>, //None, candle-vllm extension, seconds after which the request is aborted, overrides --request-timeout
+    #[serde(default)]
+    pub quality: Option<Quality>, //None, candle-vllm extension, fast or best variant of the model with --fast-quant, weighted round-robin between them otherwise
+}
+
+/// Branch the finished generation of a `forkable` request into `n` continuations sharing its KV
+/// cache (candle-vllm extension). Branch `i` gets the request id `<id>-<i>`, `id` being the id of
+/// the fork response, and can be forked again if `forkable` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForkRequest {
+    pub request_id: String,
+    /// Model that generated the forked request, required when several models are served
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub n: Option<usize>, //1
+    #[serde(default)]
+    pub num_tokens: Option<usize>, //None, branch after all the generated tokens
+    #[serde(default)]
+    pub max_tokens: Option<usize>, //None, same as the forked request
+    #[serde(default)]
+    pub temperature: Option<f32>, //None, same as the forked request
+    #[serde(default)]
+    pub top_p: Option<f32>, //None, same as the forked request
+    #[serde(default)]
+    pub top_k: Option<isize>, //None, same as the forked request
+    #[serde(default)]
+    pub forkable: Option<bool>, //false
+}
+
+/// How the per-token hidden states of an input are reduced to one embedding vector.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Pooling {
+    /// Average over all tokens (GTE, sentence-transformers)
+    Mean,
+    /// Hidden state of the first token (BGE)
+    Cls,
+    /// Hidden state of the last token, the only one that attends to the whole input in a decoder
+    /// (Qwen2 and Mistral based embedding models)
+    LastToken,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Multi(Vec<String>),
+    Tokens(Vec<u32>),
+    MultiTokens(Vec<Vec<u32>>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub encoding_format: Option<String>, //float or base64
+    #[serde(default)]
+    pub dimensions: Option<usize>, //None, truncate (and renormalize) the embeddings
+    #[serde(default)]
+    pub user: Option<String>, //None
+    #[serde(default)]
+    pub pooling: Option<Pooling>, //None, the model default, candle-vllm extension
+}
+
+/// Token classification (e.g. NER tagging) of one or more inputs, candle-vllm extension.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClassificationRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+    #[serde(default)]
+    pub user: Option<String>, //None
+}
+
+/// What `/v1/score` rates: one or more conversations, rendered with the chat template of the
+/// model, or texts and token ids as for `/v1/embeddings`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ScoreInput {
+    Conversation(Vec<HashMap<String, serde_json::Value>>),
+    Conversations(Vec<Vec<HashMap<String, serde_json::Value>>>),
+    Input(EmbeddingInput),
+}
+
+/// Reward model scoring of one or more inputs, candle-vllm extension.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreRequest {
+    pub model: String,
+    pub input: ScoreInput,
+    #[serde(default)]
+    pub user: Option<String>, //None
+}
+
+/// Swap a served model for another without restarting the server, see
+/// [`reload`](super::reload). The new model has the architecture of the served one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadRequest {
+    /// The served model to replace, optional with a single model
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Model id on the hub of the new model. Without it and `weight_path`, the weights of the
+    /// served model are loaded again.
+    #[serde(default)]
+    pub model_id: Option<String>,
+    #[serde(default)]
+    pub revision: Option<String>,
+    /// Local directory of the new weights
+    #[serde(default)]
+    pub weight_path: Option<String>,
+    /// Seconds to wait for the requests in flight before they are aborted (default: until they
+    /// finish)
+    #[serde(default)]
+    pub drain_timeout: Option<f64>,
 }
@@ -1,16 +1,29 @@
 use super::streaming::Streamer;
 use crate::openai::sampling_params::Logprobs;
 use axum::extract::Json;
-use axum::http::{self, StatusCode};
-use axum::response::{IntoResponse, Sse};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, IntoResponseParts, ResponseParts, Sse};
 use derive_more::{Display, Error};
 use serde::{Deserialize, Serialize};
-#[derive(Debug, Display, Error, Serialize)]
+use std::time::Duration;
+/// An error of the API. It is answered in the error format of OpenAI,
+/// `{"error": {"message", "type", "param", "code"}}`, with the HTTP status of its type: errors are
+/// internal server errors unless they are built as another type.
+#[derive(Clone, Debug, Display, Error)]
 #[display(fmt = "Error: {}", data)]
 pub struct APIError {
     data: String,
+    status: StatusCode,
+    error_type: &'static str,
+    param: Option<String>,
+    code: Option<&'static str>,
 }
 
+const SERVER_ERROR: &str = "server_error";
+const INVALID_REQUEST_ERROR: &str = "invalid_request_error";
+const RATE_LIMIT_ERROR: &str = "rate_limit_error";
+const TIMEOUT_ERROR: &str = "timeout_error";
+
 // impl error::ResponseError for APIError {
 //     fn error_response(&self) -> HttpResponse {
 //         //pack error to json so that client can handle it
@@ -22,19 +35,122 @@ pub struct APIError {
 
 impl APIError {
     pub fn new(data: String) -> Self {
-        Self { data }
+        Self {
+            data,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error_type: SERVER_ERROR,
+            param: None,
+            code: None,
+        }
     }
 
     pub fn new_str(data: &str) -> Self {
-        Self {
-            data: data.to_string(),
-        }
+        Self::new(data.to_string())
     }
 
     pub fn from<T: ToString>(value: T) -> Self {
         //panic!("{}", value.to_string());
         Self::new(value.to_string())
     }
+
+    /// An error of the request rather than of the server, a 400.
+    pub fn invalid_request<T: ToString>(value: T) -> Self {
+        Self::new(value.to_string()).classified(StatusCode::BAD_REQUEST, INVALID_REQUEST_ERROR)
+    }
+
+    /// The server (or the caller) is over one of its limits, a 429.
+    pub fn too_many_requests<T: ToString>(value: T) -> Self {
+        Self::new(value.to_string()).classified(StatusCode::TOO_MANY_REQUESTS, RATE_LIMIT_ERROR)
+    }
+
+    /// The caller did not give a valid token, a 401.
+    pub fn unauthorized<T: ToString>(value: T) -> Self {
+        Self::new(value.to_string())
+            .classified(StatusCode::UNAUTHORIZED, INVALID_REQUEST_ERROR)
+            .with_code("invalid_api_key")
+    }
+
+    /// The request did not finish before its deadline, a 408.
+    pub fn timeout<T: ToString>(value: T) -> Self {
+        Self::new(value.to_string()).classified(StatusCode::REQUEST_TIMEOUT, TIMEOUT_ERROR)
+    }
+
+    /// The request parameter at fault, e.g. `messages` or `model`.
+    pub fn with_param(mut self, param: &str) -> Self {
+        self.param = Some(param.to_string());
+        self
+    }
+
+    /// A machine-readable code of the error, e.g. `context_length_exceeded`.
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.data
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn error_type(&self) -> &str {
+        self.error_type
+    }
+
+    pub fn param(&self) -> Option<&str> {
+        self.param.as_deref()
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        self.code
+    }
+
+    /// Give an internal server error the status and type of the failure it reports, errors that
+    /// were built as another type keep theirs.
+    fn classified(mut self, status: StatusCode, error_type: &'static str) -> Self {
+        if self.error_type == SERVER_ERROR {
+            self.status = status;
+            self.error_type = error_type;
+        }
+        self
+    }
+}
+
+/// The body of an error response in the OpenAI format, so that OpenAI clients can tell its cause
+/// from `type` and `code`. Also the data of the error events of streamed responses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAIError {
+    pub error: OpenAIErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenAIErrorBody {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+impl From<&APIError> for OpenAIError {
+    fn from(e: &APIError) -> Self {
+        Self {
+            error: OpenAIErrorBody {
+                message: e.data.clone(),
+                error_type: e.error_type.to_string(),
+                param: e.param.clone(),
+                code: e.code.map(str::to_string),
+            },
+        }
+    }
+}
+
+impl IntoResponse for APIError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status, Json(OpenAIError::from(&self))).into_response()
+    }
 }
 
 #[macro_export]
@@ -58,13 +174,56 @@ pub struct ChatCompletionUsageResponse {
     pub total_tokens: usize,
     pub prompt_time_costs: usize,     //milliseconds
     pub completion_time_costs: usize, //milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub energy: Option<EnergyUsage>,
+}
+
+/// Approximate GPU energy of a request with `--energy-telemetry`, its share of the energy of the
+/// batches it ran in.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct EnergyUsage {
+    pub joules: f64,
+    /// Over the time from the arrival of the request to its end
+    pub avg_power_watts: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptTokensDetails {
+    /// Prompt tokens whose KV cache was reused (shared prefix or forked generation) instead of
+    /// being prefilled.
+    pub cached_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String, //json encoded
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(flatten)]
+    pub call: ToolCall,
 }
 
-// tool_calls, function_call not supported!
+// function_call (deprecated) not supported!
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatChoiceData {
     pub content: Option<String>,
     pub role: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -86,15 +245,23 @@ pub struct ChatCompletionResponse {
     pub choices: Vec<ChatChoice>,
     pub created: u64,
     pub model: String,
-    pub object: &'static str,
+    pub object: String,
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
     pub usage: ChatCompletionUsageResponse,
+    /// Logprob of every prompt token given the ones before it, `null` for the first one, with
+    /// `prompt_logprobs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Vec<Option<Logprobs>>>,
 }
 
-// tool_calls, function_call not supported!
+// function_call (deprecated) not supported!
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChoiceData {
     pub content: Option<String>,
     pub role: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -110,36 +277,319 @@ pub struct ChatCompletionChunk {
     pub choices: Vec<Choice>,
     pub created: u64,
     pub model: String,
-    pub object: &'static str,
+    pub object: String,
     pub system_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ChatCompletionUsageResponse>,
 }
 
-trait ErrorToResponse: Serialize {
-    fn to_response(&self, code: StatusCode) -> axum::response::Response {
-        let mut r = Json(self).into_response();
-        *r.status_mut() = code;
-        r
+impl ChatCompletionChunk {
+    /// Append the deltas of `next`, the following chunk of the same stream, so that the tokens
+    /// buffered between two flushes are sent as one event. `next` is returned when it cannot be
+    /// merged: a different set of choices, tool calls or a choice that already finished.
+    pub fn merge(&mut self, next: ChatCompletionChunk) -> Option<ChatCompletionChunk> {
+        let mergeable = next.id == self.id
+            && !self.choices.is_empty()
+            && self.choices.len() == next.choices.len()
+            && self
+                .choices
+                .iter()
+                .zip(&next.choices)
+                .all(|(choice, next)| {
+                    choice.index == next.index
+                        && choice.finish_reason.is_none()
+                        && choice.delta.tool_calls.is_none()
+                        && next.delta.tool_calls.is_none()
+                });
+        if !mergeable {
+            return Some(next);
+        }
+        for (choice, next) in self.choices.iter_mut().zip(next.choices) {
+            if let Some(content) = next.delta.content {
+                choice
+                    .delta
+                    .content
+                    .get_or_insert_with(String::new)
+                    .push_str(&content);
+            }
+            choice.finish_reason = next.finish_reason;
+        }
+        if next.usage.is_some() {
+            self.usage = next.usage;
+        }
+        None
     }
 }
 
-#[derive(Serialize)]
-struct JsonError {
-    message: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelCard {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub owned_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compute_capability: Option<String>, //e.g. "8.6", GPU only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_model_len: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelList {
+    pub object: String,
+    pub data: Vec<ModelCard>,
+}
+
+/// What a served model supports, so that a gateway can route requests to capable replicas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelCapabilities {
+    pub id: String,
+    pub max_model_len: usize,
+    /// False for encoder-only and reward models, which do not generate
+    pub chat_completions: bool,
+    pub embeddings: bool,
+    /// `/v1/token_classify`, encoders with a token classification head
+    pub token_classification: bool,
+    /// `/v1/score`, reward models
+    pub score: bool,
+    /// `response_format` and `guided_regex`
+    pub guided_decoding: bool,
+    pub tools: bool,
+    pub logprobs: bool,
+    /// In-situ quantization of the weights, e.g. `q4k`
+    pub quantization: Option<String>,
+    /// e.g. `bf16`, or `u8` for the int8 cache
+    pub kv_cache_dtype: String,
+    pub speculative_decoding: bool,
+}
+
+/// The response of `/v1/capabilities`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub object: String,
+    /// candle-vllm version of the replica
+    pub version: String,
+    /// Values of the `quant` model option this version supports
+    pub quantizations: Vec<String>,
+    pub models: Vec<ModelCapabilities>,
+}
+
+/// Used, free and total KV cache blocks of a tier.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub used: usize,
+    pub free: usize,
+    pub total: usize,
 }
 
-impl JsonError {
-    fn new(message: String) -> Self {
-        Self { message }
+/// A snapshot of the scheduler and KV cache of a served model, see `EngineStats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelStats {
+    pub id: String,
+    /// Sequence groups being generated
+    pub running: usize,
+    /// Sequence groups waiting to be admitted
+    pub waiting: usize,
+    /// Preempted sequence groups whose KV blocks are on the CPU
+    pub swapped: usize,
+    pub gpu_blocks: BlockStats,
+    pub cpu_blocks: BlockStats,
+    /// KV blocks moved between the GPU and the CPU since the start
+    pub blocks_swapped_in: usize,
+    pub blocks_swapped_out: usize,
+    /// Preemptions since the start, by method
+    pub preemptions_swap: usize,
+    pub preemptions_recompute: usize,
+    /// Sequences per decoding step over the last minute
+    pub average_batch_size: f64,
+    /// Over the last minute
+    pub prompt_tokens_per_second: f64,
+    pub generation_tokens_per_second: f64,
+}
+
+/// The response of `/v1/stats`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub object: String,
+    pub models: Vec<ModelStats>,
+}
+
+/// A float vector, or the base64 encoding of its little-endian f32 bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingData {
+    pub object: String,
+    pub embedding: EmbeddingVector,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+/// A token and its most likely label. `start` and `end` are byte offsets into the input text,
+/// absent for token id inputs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClassifiedToken {
+    pub token: String,
+    pub label: String,
+    pub score: f32,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+/// Consecutive tokens tagged with the same entity type, `score` is their mean score.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenEntity {
+    pub label: String,
+    pub score: f32,
+    pub text: String,
+    pub start: Option<usize>,
+    pub end: Option<usize>,
+}
+
+impl TokenEntity {
+    /// Group IOB tagged tokens (`B-PER`, `I-PER`, `O`) into entities. A `B-` tag starts a new
+    /// entity unless it is on a WordPiece continuation (`##`) of the previous token; untagged
+    /// labels such as `PER` continue a run of the same type. `text` is the input text the
+    /// offsets point into, without it the entity text is rebuilt from the WordPiece tokens.
+    pub fn group(tokens: &[ClassifiedToken], text: Option<&str>) -> Vec<TokenEntity> {
+        let mut entities = Vec::new();
+        let mut current: Option<(&str, Vec<&ClassifiedToken>)> = None;
+        for token in tokens {
+            let (tag, entity_type) = match token.label.split_once('-') {
+                Some((tag @ ("B" | "I"), entity_type)) => (tag, entity_type),
+                _ => ("I", token.label.as_str()),
+            };
+            let continues = current.as_ref().is_some_and(|(current_type, _)| {
+                *current_type == entity_type && (tag == "I" || token.token.starts_with("##"))
+            });
+            if continues {
+                current.as_mut().unwrap().1.push(token);
+                continue;
+            }
+            if let Some((entity_type, run)) = current.take() {
+                entities.push(Self::from_run(entity_type, &run, text));
+            }
+            if entity_type != "O" {
+                current = Some((entity_type, vec![token]));
+            }
+        }
+        if let Some((entity_type, run)) = current {
+            entities.push(Self::from_run(entity_type, &run, text));
+        }
+        entities
+    }
+
+    fn from_run(label: &str, run: &[&ClassifiedToken], text: Option<&str>) -> Self {
+        let start = run[0].start;
+        let end = run[run.len() - 1].end;
+        let text = match (text, start, end) {
+            (Some(text), Some(start), Some(end)) => text.get(start..end).unwrap_or("").to_string(),
+            _ => run
+                .iter()
+                .enumerate()
+                .fold(String::new(), |mut text, (i, token)| {
+                    match token.token.strip_prefix("##") {
+                        Some(piece) => text.push_str(piece),
+                        None => {
+                            if i > 0 {
+                                text.push(' ');
+                            }
+                            text.push_str(&token.token);
+                        }
+                    }
+                    text
+                }),
+        };
+        Self {
+            label: label.to_string(),
+            score: run.iter().map(|token| token.score).sum::<f32>() / run.len() as f32,
+            text,
+            start,
+            end,
+        }
     }
 }
-impl ErrorToResponse for JsonError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClassificationData {
+    pub object: String,
+    /// Every token of the input except the special tokens
+    pub tokens: Vec<ClassifiedToken>,
+    pub entities: Vec<TokenEntity>,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClassificationResponse {
+    pub object: String,
+    pub data: Vec<TokenClassificationData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreData {
+    pub object: String,
+    /// The score of the first label, the reward of single-label reward models
+    pub score: f32,
+    /// The score of every label of the head, by label
+    pub scores: Vec<(String, f32)>,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoreResponse {
+    pub object: String,
+    pub data: Vec<ScoreData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
 
 pub enum ChatResponder {
     Streamer(Sse<Streamer>),
     Completion(ChatCompletionResponse),
+    Embedding(EmbeddingResponse),
+    TokenClassification(TokenClassificationResponse),
+    Score(ScoreResponse),
+    Reload(ReloadResponse),
     ModelError(APIError),
     InternalError(APIError),
     ValidationError(APIError),
+    ModelNotFound(APIError),
+    /// The prompt and `max_tokens` do not fit in the context of the model.
+    ContextLengthExceeded(APIError),
+    /// No request with this id is in flight, or no session with this id has a history.
+    RequestNotFound(APIError),
+    /// The request was not admitted, the caller should retry after the duration (the
+    /// `Retry-After` header).
+    TooManyRequests(APIError, Duration),
+    /// The server does not admit requests for now, e.g. during a model reload, the caller should
+    /// retry after the duration.
+    Unavailable(APIError, Duration),
+}
+
+/// The `Retry-After` header of a duration, in whole seconds rounded up so that a retry does not
+/// come too early.
+fn retry_after_header(retry_after: Duration) -> [(header::HeaderName, String); 1] {
+    let retry_after = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+    [(header::RETRY_AFTER, retry_after.to_string())]
 }
 
 impl IntoResponse for ChatResponder {
@@ -147,15 +597,101 @@ impl IntoResponse for ChatResponder {
         match self {
             ChatResponder::Streamer(s) => s.into_response(),
             ChatResponder::Completion(s) => Json(s).into_response(),
-            ChatResponder::InternalError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            ChatResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
-            }
-            ChatResponder::ModelError(msg) => {
-                JsonError::new(msg.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
-            }
+            ChatResponder::Embedding(s) => Json(s).into_response(),
+            ChatResponder::TokenClassification(s) => Json(s).into_response(),
+            ChatResponder::Score(s) => Json(s).into_response(),
+            ChatResponder::Reload(s) => Json(s).into_response(),
+            ChatResponder::InternalError(e) | ChatResponder::ModelError(e) => e.into_response(),
+            ChatResponder::ValidationError(e) => e
+                .classified(StatusCode::BAD_REQUEST, INVALID_REQUEST_ERROR)
+                .into_response(),
+            ChatResponder::ModelNotFound(e) => e
+                .classified(StatusCode::NOT_FOUND, INVALID_REQUEST_ERROR)
+                .with_param("model")
+                .with_code("model_not_found")
+                .into_response(),
+            ChatResponder::RequestNotFound(e) => e
+                .classified(StatusCode::NOT_FOUND, INVALID_REQUEST_ERROR)
+                .into_response(),
+            ChatResponder::TooManyRequests(e, retry_after) => (
+                retry_after_header(retry_after),
+                e.classified(StatusCode::TOO_MANY_REQUESTS, RATE_LIMIT_ERROR),
+            )
+                .into_response(),
+            ChatResponder::Unavailable(e, retry_after) => (
+                retry_after_header(retry_after),
+                e.classified(StatusCode::SERVICE_UNAVAILABLE, SERVER_ERROR),
+            )
+                .into_response(),
+            ChatResponder::ContextLengthExceeded(e) => e
+                .classified(StatusCode::BAD_REQUEST, INVALID_REQUEST_ERROR)
+                .with_param("messages")
+                .with_code("context_length_exceeded")
+                .into_response(),
         }
     }
 }
+
+pub const PREFIX_CACHE_HIT_TOKENS_HEADER: &str = "x-prefix-cache-hit-tokens";
+pub const ENGINE_QUEUE_DEPTH_HEADER: &str = "x-engine-queue-depth";
+/// Id of a chat completion, set by the caller or generated, to abort it with `/v1/abort/{id}`.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Response of `/v1/abort/{id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbortResponse {
+    pub id: String,
+    pub object: String,
+    pub aborted: bool,
+}
+
+/// Response of `/admin/reload`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadResponse {
+    pub object: String,
+    pub model: String,
+    /// Of the new model
+    pub system_fingerprint: String,
+    /// Requests aborted once past the `drain_timeout`
+    pub aborted_requests: usize,
+    pub duration_ms: u64,
+}
+
+/// Response of `GET /v1/sessions/{id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub object: String,
+    pub messages: Vec<std::collections::HashMap<String, serde_json::Value>>,
+}
+
+/// Response of `DELETE /v1/sessions/{id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionDeleted {
+    pub id: String,
+    pub object: String,
+    pub deleted: bool,
+}
+
+/// Response headers for load balancers doing session affinity: the prompt tokens found in the
+/// prefix cache of this replica when the request arrived, and the number of requests waiting
+/// to be scheduled by the engine that serves it.
+#[derive(Debug, Clone, Copy)]
+pub struct RouterHints {
+    pub prefix_cache_hit_tokens: usize,
+    pub queue_depth: usize,
+}
+
+impl IntoResponseParts for RouterHints {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        let headers = res.headers_mut();
+        headers.insert(
+            PREFIX_CACHE_HIT_TOKENS_HEADER,
+            self.prefix_cache_hit_tokens.into(),
+        );
+        headers.insert(ENGINE_QUEUE_DEPTH_HEADER, self.queue_depth.into());
+        Ok(res)
+    }
+}
@@ -1,6 +1,8 @@
-use super::{requests::StopTokens, responses::APIError};
+use super::{guided_decoding::TokenGuide, requests::StopTokens, responses::APIError};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ops::Range;
+use std::sync::Arc;
 
 const SAMPLING_EPS: f32 = 1e-5;
 
@@ -77,6 +79,9 @@ pub struct SamplingParams {
     pub stop_token_ids: Vec<usize>,
     /// Whether to ignore EOS token.
     pub ignore_eos: bool,
+    /// EOS and stop tokens are not sampled before `min_tokens` tokens were generated.
+    /// rec. default = 0
+    pub min_tokens: usize,
     /// Max number of toks to gen per output seq.
     /// rec. default = 16
     pub max_tokens: usize,
@@ -89,6 +94,20 @@ pub struct SamplingParams {
     /// Skip special toks in output.
     /// rec. default = true
     pub skip_special_tokens: bool,
+    /// Constrains the output to a regular expression (guided decoding).
+    pub guide: Option<Arc<TokenGuide>>,
+    /// Seed of the sampling of the request, which then samples from its own generator and
+    /// produces the same output whatever else is in the batch.
+    pub seed: Option<u64>,
+    /// Bias added to the logits of tokens before sampling, -100 bans a token.
+    pub logit_bias: HashMap<u32, f32>,
+    /// Tokens less likely than `min_p` times the most likely token are not sampled.
+    /// rec. default = 0
+    pub min_p: f32,
+    /// Sample from the smallest set of tokens closest to the expected information content
+    /// holding `typical_p` of the probability mass (locally typical sampling).
+    /// rec. default = 1
+    pub typical_p: f32,
 }
 
 impl SamplingParams {
@@ -128,10 +147,16 @@ impl SamplingParams {
             stop,
             stop_token_ids,
             ignore_eos,
+            min_tokens: 0,
             max_tokens,
             logprobs,
             prompt_logprobs,
             skip_special_tokens,
+            guide: None,
+            seed: None,
+            logit_bias: HashMap::new(),
+            min_p: 0.0,
+            typical_p: 1.0,
         };
 
         this.verify_args()?;
@@ -147,6 +172,85 @@ impl SamplingParams {
         Ok(this)
     }
 
+    /// Build sampling params from the parameters that differ from the recommended defaults.
+    pub fn builder() -> SamplingParamsBuilder {
+        SamplingParamsBuilder::default()
+    }
+
+    /// Set the OpenAI `logit_bias` of a request, which maps token ids (as strings) to a bias in
+    /// [-100, 100].
+    pub fn set_logit_bias(
+        &mut self,
+        logit_bias: &HashMap<String, f32>,
+        vocab_size: usize,
+    ) -> Result<(), APIError> {
+        self.logit_bias = logit_bias
+            .iter()
+            .map(|(token, bias)| {
+                let token_id = token
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|id| (*id as usize) < vocab_size)
+                    .ok_or_else(|| {
+                        APIError::new(format!(
+                            "logit_bias keys must be token ids below {vocab_size}, got {token}"
+                        ))
+                    })?;
+                if !(-100.0..=100.0).contains(bias) {
+                    return Err(APIError::new(format!(
+                        "logit_bias values must be in [-100, 100], got {bias}"
+                    )));
+                }
+                Ok((token_id, *bias))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Set the min-p and typical sampling filters of a request, `None` disables them.
+    pub fn set_probability_filters(
+        &mut self,
+        min_p: Option<f32>,
+        typical_p: Option<f32>,
+    ) -> Result<(), APIError> {
+        let min_p = min_p.unwrap_or(0.0);
+        if !(0.0..=1.0).contains(&min_p) {
+            return Err(APIError::new(format!(
+                "min_p must be in [0, 1], got {min_p}."
+            )));
+        }
+        let typical_p = typical_p.unwrap_or(1.0);
+        if !(typical_p > 0.0 && typical_p <= 1.0) {
+            return Err(APIError::new(format!(
+                "typical_p must be in (0, 1], got {typical_p}."
+            )));
+        }
+        self.min_p = min_p;
+        self.typical_p = typical_p;
+        Ok(())
+    }
+
+    /// Set the minimum number of tokens a request generates before it can end with an EOS or
+    /// stop token, at most `max_tokens`.
+    pub fn set_min_tokens(&mut self, min_tokens: Option<usize>) -> Result<(), APIError> {
+        let min_tokens = min_tokens.unwrap_or(0);
+        if min_tokens > self.max_tokens {
+            return Err(APIError::new(format!(
+                "min_tokens must be at most max_tokens ({}), got {min_tokens}.",
+                self.max_tokens
+            )));
+        }
+        self.min_tokens = min_tokens;
+        Ok(())
+    }
+
+    /// Single-sequence requests always produce the same output for the same prompt when the
+    /// pipeline samples greedily. The pipeline samples with its own `--temperature`, not the
+    /// `temperature` of the request.
+    pub fn is_deterministic(&self, greedy_sampling: bool) -> bool {
+        greedy_sampling && !self.use_beam_search && self.n == 1 && self.best_of == 1
+    }
+
     // pub fn get_logits_processor<'a>(
     //     &self,
     //     seed: u64,
@@ -288,3 +392,168 @@ impl SamplingParams {
         Ok(())
     }
 }
+
+/// Builds [`SamplingParams`], unset parameters take the recommended defaults of the fields: a
+/// single sequence of up to 16 tokens sampled at temperature 1, without penalties.
+#[derive(Clone, Debug)]
+pub struct SamplingParamsBuilder {
+    n: usize,
+    best_of: Option<usize>,
+    presence_penalty: f32,
+    frequency_penalty: f32,
+    repetition_penalty: f32,
+    temperature: f32,
+    top_p: f32,
+    top_k: isize,
+    use_beam_search: bool,
+    length_penalty: f32,
+    early_stopping: EarlyStoppingCondition,
+    stop: Option<StopTokens>,
+    stop_token_ids: Vec<usize>,
+    ignore_eos: bool,
+    max_tokens: usize,
+    logprobs: Option<usize>,
+    prompt_logprobs: Option<usize>,
+    skip_special_tokens: bool,
+}
+
+impl Default for SamplingParamsBuilder {
+    fn default() -> Self {
+        Self {
+            n: 1,
+            best_of: None,
+            presence_penalty: 0.,
+            frequency_penalty: 0.,
+            repetition_penalty: 1.,
+            temperature: 1.,
+            top_p: 1.,
+            top_k: -1,
+            use_beam_search: false,
+            length_penalty: 1.,
+            early_stopping: EarlyStoppingCondition::UnlikelyBetterCandidates,
+            stop: None,
+            stop_token_ids: vec![],
+            ignore_eos: false,
+            max_tokens: 16,
+            logprobs: None,
+            prompt_logprobs: None,
+            skip_special_tokens: true,
+        }
+    }
+}
+
+impl SamplingParamsBuilder {
+    pub fn n(mut self, n: usize) -> Self {
+        self.n = n;
+        self
+    }
+
+    pub fn best_of(mut self, best_of: usize) -> Self {
+        self.best_of = Some(best_of);
+        self
+    }
+
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.presence_penalty = presence_penalty;
+        self
+    }
+
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.frequency_penalty = frequency_penalty;
+        self
+    }
+
+    pub fn repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.repetition_penalty = repetition_penalty;
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.top_p = top_p;
+        self
+    }
+
+    pub fn top_k(mut self, top_k: isize) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn use_beam_search(mut self, use_beam_search: bool) -> Self {
+        self.use_beam_search = use_beam_search;
+        self
+    }
+
+    pub fn length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    pub fn early_stopping(mut self, early_stopping: EarlyStoppingCondition) -> Self {
+        self.early_stopping = early_stopping;
+        self
+    }
+
+    pub fn stop(mut self, stop: StopTokens) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    pub fn stop_token_ids(mut self, stop_token_ids: Vec<usize>) -> Self {
+        self.stop_token_ids = stop_token_ids;
+        self
+    }
+
+    pub fn ignore_eos(mut self, ignore_eos: bool) -> Self {
+        self.ignore_eos = ignore_eos;
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn logprobs(mut self, logprobs: usize) -> Self {
+        self.logprobs = Some(logprobs);
+        self
+    }
+
+    pub fn prompt_logprobs(mut self, prompt_logprobs: usize) -> Self {
+        self.prompt_logprobs = Some(prompt_logprobs);
+        self
+    }
+
+    pub fn skip_special_tokens(mut self, skip_special_tokens: bool) -> Self {
+        self.skip_special_tokens = skip_special_tokens;
+        self
+    }
+
+    /// Check the parameters like [`SamplingParams::new`].
+    pub fn build(self) -> Result<SamplingParams, APIError> {
+        SamplingParams::new(
+            self.n,
+            self.best_of,
+            self.presence_penalty,
+            self.frequency_penalty,
+            self.repetition_penalty,
+            self.temperature,
+            self.top_p,
+            self.top_k,
+            self.use_beam_search,
+            self.length_penalty,
+            self.early_stopping,
+            self.stop,
+            self.stop_token_ids,
+            self.ignore_eos,
+            self.max_tokens,
+            self.logprobs,
+            self.prompt_logprobs,
+            self.skip_special_tokens,
+        )
+    }
+}
@@ -0,0 +1,187 @@
+//! Conversation history kept by the server for the requests with a `session_id`, with
+//! `--session-history`. A request only sends its new messages: the prompt is built from the
+//! history of the session followed by them, and once it finishes they are appended to the history
+//! with the reply. The number of sessions, their length and their idle time are bounded; the least
+//! recently used session is dropped to make room for a new one.
+
+use super::responses::ChatChoiceData;
+use super::streaming::ChatResponse;
+use super::tools::ToolFormat;
+use flume::{Receiver, Sender};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A chat message, as in the `messages` of a request.
+pub type Message = HashMap<String, Value>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SessionLimits {
+    pub max_sessions: usize,
+    /// Older turns are dropped beyond this many messages, the system message is kept.
+    pub max_messages: usize,
+    /// Sessions idle for longer are dropped.
+    pub ttl: Duration,
+}
+
+#[derive(Debug)]
+struct Session {
+    messages: Vec<Message>,
+    last_used: Instant,
+}
+
+fn is_system(message: &Message) -> bool {
+    message.get("role").and_then(Value::as_str) == Some("system")
+}
+
+fn is_user(message: &Message) -> bool {
+    message.get("role").and_then(Value::as_str) == Some("user")
+}
+
+#[derive(Debug)]
+pub struct SessionStore {
+    limits: SessionLimits,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    pub fn new(limits: SessionLimits) -> Self {
+        Self {
+            limits,
+            sessions: Mutex::default(),
+        }
+    }
+
+    fn drop_expired(&self, sessions: &mut HashMap<String, Session>, now: Instant) {
+        sessions.retain(|_, session| now.duration_since(session.last_used) <= self.limits.ttl);
+    }
+
+    /// The history of the session, `None` for an unknown (or expired) session.
+    pub fn history(&self, session_id: &str, now: Instant) -> Option<Vec<Message>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.drop_expired(&mut sessions, now);
+        sessions
+            .get(session_id)
+            .map(|session| session.messages.clone())
+    }
+
+    /// The messages of the prompt of a request of the session: its history followed by the
+    /// `messages` of the request.
+    pub fn prompt_messages(
+        &self,
+        session_id: &str,
+        messages: &[Message],
+        now: Instant,
+    ) -> Vec<Message> {
+        let mut prompt = self.history(session_id, now).unwrap_or_default();
+        prompt.extend_from_slice(messages);
+        prompt
+    }
+
+    /// Append a finished turn to the session, creating it if needed: the `messages` of the request
+    /// and the reply. A system message replaces the previous one.
+    pub fn append(&self, session_id: &str, messages: Vec<Message>, now: Instant) {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.drop_expired(&mut sessions, now);
+        if !sessions.contains_key(session_id) && sessions.len() >= self.limits.max_sessions {
+            let oldest = sessions
+                .iter()
+                .min_by_key(|(_, session)| session.last_used)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        let session = sessions
+            .entry(session_id.to_string())
+            .or_insert_with(|| Session {
+                messages: Vec::new(),
+                last_used: now,
+            });
+        session.last_used = now;
+        if messages.iter().any(is_system) {
+            session.messages.retain(|message| !is_system(message));
+        }
+        session.messages.extend(messages);
+        // Drop the oldest turns, a turn starts with a user message
+        let max_messages = self.limits.max_messages;
+        while session.messages.len() > max_messages {
+            let Some(oldest) = session.messages.iter().position(|m| !is_system(m)) else {
+                break;
+            };
+            session.messages.remove(oldest);
+            while let Some(next) = session.messages.iter().position(|m| !is_system(m)) {
+                if is_user(&session.messages[next]) {
+                    break;
+                }
+                session.messages.remove(next);
+            }
+        }
+    }
+
+    /// Forget the session, returns whether it existed.
+    pub fn remove(&self, session_id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(session_id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The reply of a finished request as a message of its session.
+pub fn reply_message(reply: &ChatChoiceData) -> Message {
+    match serde_json::to_value(reply) {
+        Ok(Value::Object(message)) => message.into_iter().collect(),
+        _ => Message::new(),
+    }
+}
+
+/// Relay the stream of a request of `session_id` to `tx` and append the turn, its `messages` and
+/// the streamed reply (with its tool calls), to the session once the stream is done. Nothing is
+/// appended when the request fails.
+pub async fn relay_session_stream(
+    rx: Receiver<ChatResponse>,
+    tx: Sender<ChatResponse>,
+    sessions: Arc<SessionStore>,
+    session_id: String,
+    mut messages: Vec<Message>,
+    tool_format: Option<ToolFormat>,
+) {
+    let mut text = String::new();
+    let mut failed = false;
+    while let Ok(response) = rx.recv_async().await {
+        match &response {
+            ChatResponse::Chunk(chunk) => {
+                let content = chunk
+                    .choices
+                    .iter()
+                    .filter(|choice| choice.index == 0)
+                    .filter_map(|choice| choice.delta.content.as_deref());
+                text.extend(content);
+            }
+            ChatResponse::Done if !failed => {
+                let tool_calls = tool_format.and_then(|format| format.parse_tool_calls(&text));
+                let reply = ChatChoiceData {
+                    content: tool_calls.is_none().then_some(text.clone()),
+                    role: "assistant".to_string(),
+                    tool_calls,
+                };
+                messages.push(reply_message(&reply));
+                sessions.append(&session_id, std::mem::take(&mut messages), Instant::now());
+            }
+            ChatResponse::Done => {}
+            ChatResponse::InternalError(_)
+            | ChatResponse::ValidationError(_)
+            | ChatResponse::ModelError(_) => failed = true,
+        }
+        if tx.send(response).is_err() {
+            break;
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use super::responses::ChatCompletionChunk;
+use super::requests::StopTokens;
+use super::responses::{APIError, ChatCompletionChunk, OpenAIError};
+use crate::fault;
 use axum::response::sse::Event;
 use flume::Receiver;
 use futures::Stream;
 use std::{
+    collections::HashMap,
     pin::Pin,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
+    time::Instant,
 };
 
 #[derive(PartialEq)]
@@ -15,16 +23,264 @@ pub enum StreamingStatus {
     Stopped,
 }
 pub enum ChatResponse {
-    InternalError(String),
-    ValidationError(String),
-    ModelError(String),
+    InternalError(APIError),
+    ValidationError(APIError),
+    ModelError(APIError),
     Chunk(ChatCompletionChunk),
     Done, //finish flag
 }
 
+const CANCELLED: u8 = 1;
+const ABORTED: u8 = 2;
+
+/// Set when the client of a request has gone away or the request was aborted through
+/// `/v1/abort/{id}`, the engine aborts the request between steps. The engine also aborts the
+/// request once past its deadline, if it has one.
+#[derive(Clone, Default)]
+pub struct CancelFlag {
+    state: Arc<AtomicU8>,
+    deadline: Option<Instant>,
+}
+
+impl CancelFlag {
+    /// A flag of a request that times out at `deadline`.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn cancel(&self) {
+        let _ = self
+            .state
+            .compare_exchange(0, CANCELLED, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Cancel the request on behalf of its caller, who gets an error instead of the response.
+    pub fn abort(&self) {
+        self.state.store(ABORTED, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == ABORTED
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/// The cancel flags of the requests of an engine by request id, shared with the server so that
+/// requests can be aborted without waiting for the engine lock, which generation runs hold.
+#[derive(Clone, Default)]
+pub struct CancelFlags(Arc<Mutex<HashMap<String, CancelFlag>>>);
+
+impl CancelFlags {
+    /// Register a request, `false` if a request with the same id is in flight.
+    pub fn try_insert(&self, request_id: &str, flag: CancelFlag) -> bool {
+        let mut flags = self.0.lock().unwrap();
+        if flags.contains_key(request_id) {
+            return false;
+        }
+        flags.insert(request_id.to_string(), flag);
+        true
+    }
+
+    pub fn insert(&self, request_id: String, flag: CancelFlag) {
+        self.0.lock().unwrap().insert(request_id, flag);
+    }
+
+    pub fn remove(&self, request_id: &str) {
+        self.0.lock().unwrap().remove(request_id);
+    }
+
+    /// Abort the request, `false` if it is not in flight.
+    pub fn abort(&self, request_id: &str) -> bool {
+        match self.0.lock().unwrap().get(request_id) {
+            Some(flag) => {
+                flag.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Abort all the requests in flight, the number of requests aborted.
+    pub fn abort_all(&self) -> usize {
+        let flags = self.0.lock().unwrap();
+        for flag in flags.values() {
+            flag.abort();
+        }
+        flags.len()
+    }
+
+    /// Unregister the cancelled requests, with whether each one was aborted.
+    pub fn take_cancelled(&self) -> Vec<(String, bool)> {
+        let mut flags = self.0.lock().unwrap();
+        let cancelled = flags
+            .iter()
+            .filter(|(_, flag)| flag.is_cancelled())
+            .map(|(request_id, flag)| (request_id.clone(), flag.is_aborted()))
+            .collect::<Vec<_>>();
+        for (request_id, _) in &cancelled {
+            flags.remove(request_id);
+        }
+        cancelled
+    }
+
+    /// Unregister the requests past their deadline.
+    pub fn take_expired(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut flags = self.0.lock().unwrap();
+        let expired = flags
+            .iter()
+            .filter(|(_, flag)| flag.is_expired(now))
+            .map(|(request_id, _)| request_id.clone())
+            .collect::<Vec<_>>();
+        for request_id in &expired {
+            flags.remove(request_id);
+        }
+        expired
+    }
+}
+
+/// Cancels the request when dropped before `disarm`, e.g. when axum drops the handler
+/// future of a closed connection.
+pub struct CancelOnDrop {
+    flag: CancelFlag,
+    armed: bool,
+}
+
+impl CancelOnDrop {
+    pub fn new(flag: CancelFlag) -> Self {
+        Self { flag, armed: true }
+    }
+
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            self.flag.cancel();
+        }
+    }
+}
+
+/// Matches the stop strings of a request on its generated text, which may split a stop string
+/// across tokens. Text that could be the start of a stop string is held back until the next
+/// tokens tell whether it is one, so a stream never sends part of a stop string.
+#[derive(Debug, Default)]
+pub struct StopBuffer {
+    stop: Vec<String>,
+    pending: String,
+}
+
+impl StopBuffer {
+    pub fn new(stop: Option<&StopTokens>) -> Self {
+        let stop = match stop {
+            Some(StopTokens::Multi(stop)) => stop.clone(),
+            Some(StopTokens::Single(stop)) => vec![stop.clone()],
+            None => Vec::new(),
+        };
+        Self {
+            stop: stop.into_iter().filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stop.is_empty()
+    }
+
+    /// Add the `text` of a token, returns the text that can be sent and whether a stop string
+    /// was generated, in which case the text ends before it.
+    pub fn push(&mut self, text: &str) -> (String, bool) {
+        if self.stop.is_empty() {
+            return (text.to_string(), false);
+        }
+        self.pending.push_str(text);
+        if let Some(at) = self.find_stop(&self.pending) {
+            let mut out = std::mem::take(&mut self.pending);
+            out.truncate(at);
+            return (out, true);
+        }
+        // The longest suffix which is the start of a stop string is held back
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let suffix = &self.pending[i..];
+                self.stop.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len());
+        let held = self.pending.split_off(held);
+        (std::mem::replace(&mut self.pending, held), false)
+    }
+
+    /// The text held back, once the generation finished without a stop string.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// `text` up to its first stop string.
+    pub fn truncate<'a>(&self, text: &'a str) -> &'a str {
+        match self.find_stop(text) {
+            Some(at) => &text[..at],
+            None => text,
+        }
+    }
+
+    fn find_stop(&self, text: &str) -> Option<usize> {
+        self.stop
+            .iter()
+            .filter_map(|stop| text.find(stop.as_str()))
+            .min()
+    }
+}
+
 pub struct Streamer {
     pub rx: Receiver<ChatResponse>,
     pub status: StreamingStatus,
+    pub cancel: CancelFlag,
+    /// Chunks sent so far.
+    pub chunks: usize,
+    /// Response received after the chunks merged into the last event, sent next.
+    pending: Option<ChatResponse>,
+}
+
+impl Streamer {
+    pub fn new(rx: Receiver<ChatResponse>, cancel: CancelFlag) -> Self {
+        Self {
+            rx,
+            status: StreamingStatus::Uninitilized,
+            cancel,
+            chunks: 0,
+            pending: None,
+        }
+    }
+
+    fn try_next(&mut self) -> Result<ChatResponse, flume::TryRecvError> {
+        match self.pending.take() {
+            Some(resp) => Ok(resp),
+            None => self.rx.try_recv(),
+        }
+    }
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The response body is dropped before the end of the stream: the client disconnected.
+        if self.status != StreamingStatus::Stopped {
+            self.cancel.cancel();
+        }
+    }
 }
 
 impl Stream for Streamer {
@@ -34,15 +290,40 @@ impl Stream for Streamer {
         if self.status == StreamingStatus::Stopped {
             return Poll::Ready(None);
         }
-        match self.rx.try_recv() {
+        match self.try_next() {
             Ok(resp) => match resp {
-                ChatResponse::InternalError(e) => Poll::Ready(Some(Ok(Event::default().data(e)))),
-                ChatResponse::ValidationError(e) => Poll::Ready(Some(Ok(Event::default().data(e)))),
-                ChatResponse::ModelError(e) => Poll::Ready(Some(Ok(Event::default().data(e)))),
-                ChatResponse::Chunk(response) => {
+                ChatResponse::InternalError(e) | ChatResponse::ValidationError(e) => {
+                    Poll::Ready(Some(Event::default().json_data(OpenAIError::from(&e))))
+                }
+                ChatResponse::ModelError(e) => {
+                    // The request failed, the stream ends once the engine drops its sender
+                    self.status = StreamingStatus::Started;
+                    Poll::Ready(Some(Event::default().json_data(OpenAIError::from(&e))))
+                }
+                ChatResponse::Chunk(mut response) => {
                     if self.status != StreamingStatus::Started {
                         self.status = StreamingStatus::Started;
                     }
+                    if fault::drop_stream(self.chunks) {
+                        // Dropped like a closed connection, the request is aborted
+                        self.cancel.cancel();
+                        self.status = StreamingStatus::Interrupted;
+                        return Poll::Ready(None);
+                    }
+                    // The tokens generated since the last event are sent as one delta
+                    while let Ok(next) = self.rx.try_recv() {
+                        let unmerged = match next {
+                            ChatResponse::Chunk(next) => {
+                                response.merge(next).map(ChatResponse::Chunk)
+                            }
+                            other => Some(other),
+                        };
+                        if unmerged.is_some() {
+                            self.pending = unmerged;
+                            break;
+                        }
+                    }
+                    self.chunks += 1;
                     Poll::Ready(Some(Event::default().json_data(response)))
                 }
                 ChatResponse::Done => {
@@ -0,0 +1,238 @@
+use super::requests::{Tool, ToolChoice};
+use super::responses::{APIError, ChatCompletionChunk, FunctionCall, ToolCall, ToolCallDelta};
+use super::streaming::ChatResponse;
+use flume::{Receiver, Sender};
+use serde_json::Value;
+use uuid::Uuid;
+
+const QWEN2_TOOL_CALL_START: &str = "<tool_call>";
+const QWEN2_TOOL_CALL_END: &str = "</tool_call>";
+const LLAMA3_PYTHON_TAG: &str = "<|python_tag|>";
+
+/// Tool calling convention of a chat template.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToolFormat {
+    /// Llama 3.1 json tool calls: `{"name": ..., "parameters": {...}}`.
+    Llama3,
+    /// Qwen2.5 tool calls wrapped in `<tool_call></tool_call>` tags.
+    Qwen2,
+}
+
+/// Tools enabled by `tool_choice` and whether the model must call one of them,
+/// `None` if tool calling is disabled for the request.
+pub fn select_tools(
+    tools: &Option<Vec<Tool>>,
+    tool_choice: &Option<ToolChoice>,
+) -> Result<Option<(Vec<Tool>, bool)>, APIError> {
+    let tools = match tools {
+        Some(tools) if !tools.is_empty() => tools,
+        _ => return Ok(None),
+    };
+    if let Some(tool) = tools.iter().find(|tool| tool.tool_type != "function") {
+        return Err(APIError::new(format!(
+            "Tool type `{}` is not supported.",
+            tool.tool_type
+        )));
+    }
+    match tool_choice {
+        None => Ok(Some((tools.clone(), false))),
+        Some(ToolChoice::Mode(mode)) => match mode.as_str() {
+            "none" => Ok(None),
+            "auto" => Ok(Some((tools.clone(), false))),
+            "required" => Ok(Some((tools.clone(), true))),
+            _ => Err(APIError::new(format!("Invalid `tool_choice` `{mode}`."))),
+        },
+        Some(ToolChoice::Function(choice)) => {
+            let selected = tools
+                .iter()
+                .filter(|tool| tool.function.name == choice.function.name)
+                .cloned()
+                .collect::<Vec<_>>();
+            if selected.is_empty() {
+                Err(APIError::new(format!(
+                    "Tool `{}` in `tool_choice` is not defined in `tools`.",
+                    choice.function.name
+                )))
+            } else {
+                Ok(Some((selected, true)))
+            }
+        }
+    }
+}
+
+impl ToolFormat {
+    /// System prompt describing the available tools in the format the model was trained on.
+    pub fn system_prompt(&self, tools: &[Tool], required: bool) -> String {
+        let tools = tools
+            .iter()
+            .map(|tool| serde_json::to_string(tool).unwrap_or_default())
+            .collect::<Vec<_>>();
+        let mut prompt = match self {
+            ToolFormat::Llama3 => format!(
+                "Environment: ipython\n\nGiven the following functions, please respond with a JSON \
+                for a function call with its proper arguments that best answers the given prompt.\n\n\
+                Respond in the format {{\"name\": function name, \"parameters\": dictionary of \
+                argument name and its value}}. Do not use variables.\n\n{}",
+                tools.join("\n\n")
+            ),
+            ToolFormat::Qwen2 => format!(
+                "# Tools\n\nYou may call one or more functions to assist with the user query.\n\n\
+                You are provided with function signatures within <tools></tools> XML tags:\n\
+                <tools>\n{}\n</tools>\n\nFor each function call, return a json object with function \
+                name and arguments within <tool_call></tool_call> XML tags:\n<tool_call>\n\
+                {{\"name\": <function-name>, \"arguments\": <args-json-object>}}\n</tool_call>",
+                tools.join("\n")
+            ),
+        };
+        if required {
+            prompt += "\n\nYou must call at least one of the functions above.";
+        }
+        prompt
+    }
+
+    /// Render the tool calls of an assistant message in the conversation history.
+    pub fn format_tool_calls(&self, calls: &[ToolCall]) -> String {
+        let key = match self {
+            ToolFormat::Llama3 => "parameters",
+            ToolFormat::Qwen2 => "arguments",
+        };
+        let calls = calls.iter().map(|call| {
+            let arguments = serde_json::from_str::<Value>(&call.function.arguments)
+                .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+            serde_json::json!({ "name": call.function.name, key: arguments }).to_string()
+        });
+        match self {
+            ToolFormat::Llama3 => calls.collect::<Vec<_>>().join("\n"),
+            ToolFormat::Qwen2 => calls
+                .map(|call| format!("{QWEN2_TOOL_CALL_START}\n{call}\n{QWEN2_TOOL_CALL_END}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Whether the generated text so far is, or may still turn into, a tool call.
+    pub fn may_be_tool_call(&self, text: &str) -> bool {
+        let text = text.trim_start();
+        let markers: &[&str] = match self {
+            ToolFormat::Llama3 => &[LLAMA3_PYTHON_TAG, "{"],
+            ToolFormat::Qwen2 => &[QWEN2_TOOL_CALL_START],
+        };
+        markers
+            .iter()
+            .any(|marker| text.starts_with(marker) || marker.starts_with(text))
+    }
+
+    /// Parse the tool calls of a finished generation, `None` if it is a plain message.
+    pub fn parse_tool_calls(&self, text: &str) -> Option<Vec<ToolCall>> {
+        let mut calls = Vec::new();
+        match self {
+            ToolFormat::Llama3 => {
+                let text = text.trim();
+                let text = text.strip_prefix(LLAMA3_PYTHON_TAG).unwrap_or(text);
+                match serde_json::from_str::<Value>(text).ok()? {
+                    Value::Array(values) => {
+                        for value in values {
+                            calls.push(to_tool_call(&value)?);
+                        }
+                    }
+                    value => calls.push(to_tool_call(&value)?),
+                }
+            }
+            ToolFormat::Qwen2 => {
+                let mut rest = text;
+                while let Some(start) = rest.find(QWEN2_TOOL_CALL_START) {
+                    let body = &rest[start + QWEN2_TOOL_CALL_START.len()..];
+                    let end = body.find(QWEN2_TOOL_CALL_END).unwrap_or(body.len());
+                    let value = serde_json::from_str::<Value>(body[..end].trim()).ok()?;
+                    calls.push(to_tool_call(&value)?);
+                    rest = body.get(end + QWEN2_TOOL_CALL_END.len()..).unwrap_or("");
+                }
+            }
+        }
+        if calls.is_empty() {
+            None
+        } else {
+            Some(calls)
+        }
+    }
+}
+
+fn to_tool_call(value: &Value) -> Option<ToolCall> {
+    let name = value.get("name")?.as_str()?.to_string();
+    let arguments = match value.get("parameters").or(value.get("arguments")) {
+        Some(Value::String(arguments)) => arguments.clone(),
+        Some(arguments) => arguments.to_string(),
+        None => "{}".to_string(),
+    };
+    Some(ToolCall {
+        id: format!("call_{}", Uuid::new_v4().simple()),
+        tool_type: "function".to_string(),
+        function: FunctionCall { name, arguments },
+    })
+}
+
+/// Forward the stream of a tool-enabled request, holding back the content chunks while
+/// the output may be a tool call and emitting them as `tool_calls` deltas once finished.
+pub async fn relay_tool_call_stream(
+    rx: Receiver<ChatResponse>,
+    tx: Sender<ChatResponse>,
+    format: ToolFormat,
+) {
+    let mut held = Vec::<ChatCompletionChunk>::new();
+    let mut text = String::new();
+    let mut passthrough = false;
+    while let Ok(response) = rx.recv_async().await {
+        let mut outgoing = Vec::new();
+        match response {
+            // Usage-only chunks of `stream_options.include_usage` carry no choices.
+            ChatResponse::Chunk(chunk) if !passthrough && !chunk.choices.is_empty() => {
+                let finished = chunk.choices[0].finish_reason.is_some();
+                if let Some(content) = &chunk.choices[0].delta.content {
+                    text += content;
+                }
+                held.push(chunk);
+                if !finished && format.may_be_tool_call(&text) {
+                    continue;
+                }
+                let calls = if finished {
+                    format.parse_tool_calls(&text)
+                } else {
+                    None
+                };
+                match calls {
+                    Some(calls) => {
+                        let mut chunk = held.pop().unwrap();
+                        held.clear();
+                        chunk.choices[0].delta.content = None;
+                        chunk.choices[0].finish_reason = None;
+                        chunk.choices[0].delta.tool_calls = Some(
+                            calls
+                                .into_iter()
+                                .enumerate()
+                                .map(|(index, call)| ToolCallDelta { index, call })
+                                .collect(),
+                        );
+                        let mut finish = chunk.clone();
+                        finish.choices[0].delta.tool_calls = None;
+                        finish.choices[0].finish_reason = Some("tool_calls".to_string());
+                        outgoing.push(ChatResponse::Chunk(chunk));
+                        outgoing.push(ChatResponse::Chunk(finish));
+                    }
+                    None => {
+                        passthrough = true;
+                        outgoing.extend(held.drain(..).map(ChatResponse::Chunk));
+                    }
+                }
+            }
+            response => {
+                outgoing.extend(held.drain(..).map(ChatResponse::Chunk));
+                outgoing.push(response);
+            }
+        }
+        for response in outgoing {
+            if tx.send(response).is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Startup warmup. Before the server accepts requests, a few dummy requests are run through the
+//! engine: a single sequence, then a full batch, each with a prefill and some decoding steps. The
+//! first launches of the kernels, the cuBLAS handles and the allocations of the activations of
+//! these shapes then happen at startup instead of in the first real requests.
+
+use futures::StreamExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use super::bench::{synthetic_requests, BenchConfig, LengthRange};
+use super::pipelines::llm_engine::LLMEngine;
+use super::responses::APIError;
+use super::sampling_params::SamplingParams;
+
+#[derive(Debug, Clone)]
+pub struct WarmupConfig {
+    /// Sequences of each warmup batch, run one batch after the other
+    pub batch_sizes: Vec<usize>,
+    pub prompt_len: usize,
+    /// Decoding steps after the prefill
+    pub decode_steps: usize,
+}
+
+impl WarmupConfig {
+    /// A single sequence and a batch of up to `max_num_seqs` (capped at 16) sequences, with
+    /// prompts of 128 tokens, shorter for models with a short context.
+    pub fn new(max_num_seqs: usize, max_model_len: usize) -> Self {
+        let decode_steps = 8;
+        let prompt_len = 128
+            .min(max_model_len.saturating_sub(decode_steps) / 2)
+            .max(1);
+        let mut batch_sizes = vec![1];
+        let batch = max_num_seqs.min(16);
+        if batch > 1 {
+            batch_sizes.push(batch);
+        }
+        Self {
+            batch_sizes,
+            prompt_len,
+            decode_steps,
+        }
+    }
+}
+
+/// Run the warmup batches, returns the time they took.
+pub async fn run(
+    engine: &Arc<Mutex<LLMEngine>>,
+    config: &WarmupConfig,
+) -> Result<Duration, APIError> {
+    let start = Instant::now();
+    for &batch_size in &config.batch_sizes {
+        let requests = synthetic_requests(&BenchConfig {
+            num_requests: batch_size,
+            prompt_len: LengthRange {
+                min: config.prompt_len,
+                max: config.prompt_len,
+            },
+            output_len: LengthRange {
+                min: config.decode_steps,
+                max: config.decode_steps,
+            },
+            request_rate: None,
+            seed: 0,
+        });
+        let mut streams = Vec::with_capacity(requests.len());
+        for request in requests {
+            let sampling_params = SamplingParams::builder()
+                .temperature(0.)
+                .ignore_eos(true)
+                .max_tokens(request.output_len)
+                .build()?;
+            streams.push(LLMEngine::generate(engine, &request.prompt, sampling_params).await?);
+        }
+        // Drained once all of them are queued, so that the engine batches them together
+        for mut tokens in streams {
+            while let Some(token) = tokens.next().await {
+                token?;
+            }
+        }
+    }
+    Ok(start.elapsed())
+}
@@ -86,7 +86,19 @@ impl SeqLenInfo {
         Ok(Self::new(seqstart_py))
     }
 
-    fn intervals(&self) -> Box<dyn Iterator<Item = (u32, u32)>> {
+    /// Cumulative sequence lengths, `[0, len_0, len_0 + len_1, ...]`.
+    pub(crate) fn seqstart(&self) -> &[u32] {
+        &self.seqstart_py
+    }
+
+    pub(crate) fn max_seqlen(&self) -> usize {
+        self.intervals()
+            .map(|(start, end)| (end - start) as usize)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn intervals(&self) -> Box<dyn Iterator<Item = (u32, u32)>> {
         Box::new(zip(
             self.seqstart_py.clone(),
             self.seqstart_py[1..].to_vec(),
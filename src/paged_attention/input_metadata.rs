@@ -1,4 +1,4 @@
-use candle_core::Tensor;
+use candle_core::{Device, Result, Tensor};
 
 use super::attn_bias::AttentionBiasBlockDiagonal;
 
@@ -11,6 +11,10 @@ pub struct InputMetadata {
     pub attn_bias: Option<Box<dyn AttentionBiasBlockDiagonal>>,
     pub is_prompt: bool,
     pub kv_cache_dtype: String,
+    /// Accumulate the attention each cached token receives (summed over layers, heads and queries)
+    /// into `attn_scores`, used by heavy-hitter cache eviction.
+    pub track_attn_scores: bool,
+    pub attn_scores: Option<Tensor>,
 }
 
 impl InputMetadata {
@@ -38,6 +42,49 @@ impl InputMetadata {
             attn_bias: None,
             is_prompt,
             kv_cache_dtype,
+            track_attn_scores: false,
+            attn_scores: None,
         }
     }
+
+    /// A copy on `device` for the layers offloaded to it. It has no attention bias, which is only
+    /// used by FlashAttention on CUDA.
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        let to_device = |tensor: &Option<Tensor>| {
+            tensor
+                .as_ref()
+                .map(|tensor| tensor.to_device(device))
+                .transpose()
+        };
+        Ok(Self {
+            prompt_lens: self.prompt_lens.clone(),
+            max_context_len: self.max_context_len,
+            block_tables: to_device(&self.block_tables)?,
+            context_lens: to_device(&self.context_lens)?,
+            slot_mapping: self.slot_mapping.to_device(device)?,
+            attn_bias: None,
+            is_prompt: self.is_prompt,
+            kv_cache_dtype: self.kv_cache_dtype.clone(),
+            track_attn_scores: self.track_attn_scores,
+            attn_scores: None,
+        })
+    }
+
+    /// Add the attention scores tracked by a copy from [`InputMetadata::to_device`].
+    pub fn merge_attn_scores(&mut self, copy: Self) -> Result<()> {
+        if let Some(scores) = copy.attn_scores {
+            self.add_attn_scores(scores.to_device(self.slot_mapping.device())?)?;
+        }
+        Ok(())
+    }
+
+    /// Add the attention received by each key token `(batch_size, num_tokens)` of a layer to
+    /// `attn_scores`.
+    pub fn add_attn_scores(&mut self, scores: Tensor) -> Result<()> {
+        self.attn_scores = Some(match self.attn_scores.take() {
+            Some(acc) => (acc + scores)?,
+            None => scores,
+        });
+        Ok(())
+    }
 }
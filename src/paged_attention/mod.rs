@@ -1,10 +1,18 @@
 use candle_core::{DType, Device, Result, Tensor};
 
-use crate::backend::{paged_attention, reshape_and_cache};
+use crate::backend::{
+    paged_attention, paged_attention_scores, paged_latent_attention, reshape_and_cache,
+    reshape_and_cache_latent,
+};
+use crate::openai::models::Config;
 
+#[cfg(feature = "flash-attn")]
+use self::attn_bias::AttentionBiasBlockDiagonal;
 use self::input_metadata::InputMetadata;
-mod attn_bias;
+use self::self_extend::SelfExtendAttention;
+pub(crate) mod attn_bias;
 pub(crate) mod input_metadata;
+pub mod self_extend;
 pub(crate) mod utils;
 
 const _PARTITION_SIZE: usize = 512;
@@ -18,6 +26,7 @@ pub struct PagedAttention {
     sliding_window: Option<usize>,
     num_queries_per_kv: usize,
     alibi_slopes: Option<Tensor>,
+    self_extend: Option<SelfExtendAttention>,
 }
 
 impl PagedAttention {
@@ -45,9 +54,16 @@ impl PagedAttention {
             sliding_window,
             num_queries_per_kv,
             alibi_slopes,
+            self_extend: None,
         })
     }
 
+    /// Attend with Self-Extend if the model enables it, see [`SelfExtendAttention`].
+    pub fn with_self_extend(mut self, cfg: &Config) -> Self {
+        self.self_extend = SelfExtendAttention::from_config(cfg);
+        self
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[allow(unused_variables)]
     /// query: shape = [batch_size, seq_len, num_heads * head_size]
@@ -81,10 +97,19 @@ impl PagedAttention {
 
         let att = match attention_mask {
             None => None,
+            #[cfg(feature = "flash-attn")]
+            Some(_) if self.use_flash_prefill(query, input_metadata, softcapping) => {
+                let attn_bias = input_metadata.attn_bias.as_deref().unwrap();
+                Some(self.flash_prefill(query, key, value, attn_bias)?)
+            }
             Some(mask) => {
                 //Only perform key/value repeat in prefiling stage, this will reduce kvcache
                 //and remove redundant repeat_kv in decoding stage
-                let att = if key_value_heads != attention_heads {
+                let att = if let Some(self_extend) = &self.self_extend {
+                    // The prompts start at position 0
+                    let positions = (0..seq_len).collect::<Vec<_>>();
+                    self_extend.logits(query, key, &positions, &positions, self.scale)?
+                } else if key_value_heads != attention_heads {
                     let key_repeat = if key_value_heads == 1 {
                         key.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
                     } else {
@@ -101,8 +126,13 @@ impl PagedAttention {
                 };
 
                 let att = att.broadcast_add(mask)?;
-                let att = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?
-                    .to_dtype(att.dtype())?;
+                let probs = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?;
+                if input_metadata.track_attn_scores {
+                    // [batch_size, seq_len]: attention received by each key token
+                    let scores = probs.sum(2)?.sum(1)?;
+                    input_metadata.add_attn_scores(scores)?;
+                }
+                let att = probs.to_dtype(att.dtype())?;
                 if key_value_heads != attention_heads {
                     let value_repeat = if key_value_heads == 1 {
                         value.broadcast_as((batch_size, attention_heads, seq_len, head_size))?
@@ -170,6 +200,30 @@ impl PagedAttention {
         //  input_metadata: metadata for paged attention.
         //
         //  alibi_slopes: shape = [num_heads]
+        if let Some(self_extend) = &self.self_extend {
+            return self_extend.paged_attention(
+                &query,
+                key_cache.as_ref().unwrap(),
+                value_cache.as_ref().unwrap(),
+                input_metadata.block_tables.as_ref().unwrap(),
+                input_metadata.context_lens.as_ref().unwrap(),
+                self.scale,
+                softcapping.unwrap_or(1.0f64) as f32,
+                self.sliding_window.unwrap_or(0),
+            );
+        }
+        if input_metadata.track_attn_scores {
+            // The decoding queries also count towards the heavy hitters
+            let scores = paged_attention_scores(
+                &query,
+                key_cache.as_ref().unwrap(),
+                input_metadata.block_tables.as_ref().unwrap(),
+                input_metadata.context_lens.as_ref().unwrap(),
+                self.scale,
+                softcapping.unwrap_or(1.0f64) as f32,
+            )?;
+            input_metadata.add_attn_scores(scores)?;
+        }
         paged_attention(
             &query,
             key_cache.as_ref().unwrap(),
@@ -179,6 +233,160 @@ impl PagedAttention {
             input_metadata.max_context_len.unwrap(),
             self.scale,
             softcapping.unwrap_or(1.0f64) as f32,
+            self.sliding_window.unwrap_or(0),
+        )
+    }
+
+    /// Whether the prompt phase can run FlashAttention-2 instead of the masked attention: on
+    /// CUDA in half precision, with the sequence lengths of the batch in `attn_bias`, and
+    /// without the softcapping, ALiBi, Self-Extend or attention score tracking the kernel does
+    /// not do.
+    #[cfg(feature = "flash-attn")]
+    fn use_flash_prefill(
+        &self,
+        query: &Tensor,
+        input_metadata: &InputMetadata,
+        softcapping: Option<f64>,
+    ) -> bool {
+        input_metadata.attn_bias.is_some()
+            && query.device().is_cuda()
+            && matches!(query.dtype(), DType::F16 | DType::BF16)
+            && self.head_dim % 8 == 0
+            && self.head_dim <= 256
+            && softcapping.is_none()
+            && self.alibi_slopes.is_none()
+            && self.self_extend.is_none()
+            && !input_metadata.track_attn_scores
+    }
+
+    /// Variable-length FlashAttention-2 over the prompts of a batch. The right-padded
+    /// query/key/value of shape [batch_size, num_heads, seq_len, head_size] are packed to
+    /// [num_tokens, num_heads, head_size] following the sequence lengths of `attn_bias`, so
+    /// no work is spent on padding, and the output is padded back to the input shape.
+    /// Grouped-query attention is handled by the kernel, keys and values are not repeated.
+    #[cfg(feature = "flash-attn")]
+    fn flash_prefill(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        attn_bias: &dyn AttentionBiasBlockDiagonal,
+    ) -> Result<Tensor> {
+        use candle_core::IndexOp;
+
+        let (_, _, seq_len, _) = query.dims4()?;
+        let q_seqinfo = attn_bias.get_q_seqinfo();
+        let k_seqinfo = attn_bias.get_k_seqinfo();
+        let pack = |x: &Tensor, seqinfo: &attn_bias::SeqLenInfo| -> Result<Tensor> {
+            let seqs = seqinfo
+                .intervals()
+                .enumerate()
+                .map(|(i, (start, end))| {
+                    x.i(i)?
+                        .narrow(1, 0, (end - start) as usize)?
+                        .transpose(0, 1)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Tensor::cat(&seqs, 0)?.contiguous()
+        };
+        let q = pack(query, q_seqinfo)?;
+        let k = pack(key, k_seqinfo)?;
+        let v = pack(value, k_seqinfo)?;
+        let seqlens_q = Tensor::new(q_seqinfo.seqstart(), query.device())?;
+        let seqlens_k = Tensor::new(k_seqinfo.seqstart(), query.device())?;
+        // Causal, limited to the sliding window if the layer has one: a window of `w` tokens
+        // includes the query token and the `w - 1` before it
+        let att = candle_flash_attn::flash_attn_varlen_windowed(
+            &q,
+            &k,
+            &v,
+            &seqlens_q,
+            &seqlens_k,
+            q_seqinfo.max_seqlen(),
+            k_seqinfo.max_seqlen(),
+            self.scale,
+            self.sliding_window.map(|w| w.saturating_sub(1)),
+            Some(0),
+        )?;
+        let seqs = q_seqinfo
+            .intervals()
+            .map(|(start, end)| {
+                let len = (end - start) as usize;
+                att.narrow(0, start as usize, len)?
+                    .pad_with_zeros(0, 0, seq_len - len)?
+                    .transpose(0, 1)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::stack(&seqs, 0)?.contiguous()
+    }
+
+    /// Multi-head latent attention over a latent cache (see `KvCacheLayout::Latent`): every
+    /// head attends to the same latents, used as keys and, truncated to their first `value_dim`
+    /// elements, as values.
+    /// query: shape = [batch_size, num_heads, seq_len, latent_dim], projected to the latent space
+    /// latent: shape = [batch_size, seq_len, latent_dim]
+    /// latent_cache: shape = [num_blocks, 1, latent_dim/x, block_size, x]
+    /// Like `forward`, prompts return [batch_size, num_heads, seq_len, value_dim] and decoding
+    /// returns [num_tokens, num_heads, value_dim].
+    pub fn forward_latent(
+        &mut self,
+        query: &Tensor,
+        latent: &Tensor,
+        attention_mask: Option<&Tensor>,
+        latent_cache: Option<&Tensor>,
+        input_metadata: &mut InputMetadata,
+        value_dim: usize,
+    ) -> Result<Tensor> {
+        let slot_mapping = input_metadata.slot_mapping.flatten_all()?;
+        let (batch_size, num_heads, seq_len, latent_dim) = query.dims4()?;
+
+        // The values are a narrowing of the latent, narrower than the keys, so the prompt goes
+        // through the masked attention even with `flash-attn`
+        let att = match attention_mask {
+            None => None,
+            Some(mask) => {
+                let latent = latent.unsqueeze(1)?;
+                let att = (query.broadcast_matmul(&latent.t()?)? * f64::from(self.scale))?;
+                let att = att.broadcast_add(mask)?;
+                let probs = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?;
+                if input_metadata.track_attn_scores {
+                    let scores = probs.sum(2)?.sum(1)?;
+                    input_metadata.add_attn_scores(scores)?;
+                }
+                let values = latent.narrow(3, 0, value_dim)?.contiguous()?;
+                Some(probs.to_dtype(query.dtype())?.broadcast_matmul(&values)?)
+            }
+        };
+
+        if let Some(latent_cache) = latent_cache {
+            let latent = latent.reshape(((), latent_dim))?;
+            reshape_and_cache_latent(&latent, latent_cache, &slot_mapping)?;
+        }
+
+        if let Some(att) = att {
+            //prefill result
+            return Ok(att);
+        }
+        let query = query.reshape((batch_size * seq_len, num_heads, latent_dim))?;
+        if input_metadata.track_attn_scores {
+            // The latents are the keys of a single head
+            let scores = paged_attention_scores(
+                &query,
+                latent_cache.unwrap(),
+                input_metadata.block_tables.as_ref().unwrap(),
+                input_metadata.context_lens.as_ref().unwrap(),
+                self.scale,
+                1.,
+            )?;
+            input_metadata.add_attn_scores(scores)?;
+        }
+        paged_latent_attention(
+            &query,
+            latent_cache.unwrap(),
+            input_metadata.block_tables.as_ref().unwrap(),
+            input_metadata.context_lens.as_ref().unwrap(),
+            self.scale,
+            value_dim,
         )
     }
 }
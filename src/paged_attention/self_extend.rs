@@ -0,0 +1,179 @@
+//! Self-Extend attention, see [`SelfExtend`]. The keys are cached rotated at their exact
+//! positions, which is the neighbor branch. The grouped branch moves the rotary embedded queries
+//! and keys from their exact positions to their grouped ones, a rotation by the difference, and
+//! both branches are merged before the softmax. Decoding gathers the cached keys and values of
+//! each sequence instead of running the paged attention kernels, which attend to a single
+//! position per key.
+
+use candle_core::{DType, IndexOp, Result, Tensor};
+
+use crate::backend::{gather_keys, gather_values};
+use crate::openai::models::{Config, SelfExtend};
+
+pub struct SelfExtendAttention {
+    self_extend: SelfExtend,
+    /// Rotary frequencies of the leading `2 * inv_freq.len()` dims of each head
+    inv_freq: Vec<f64>,
+    interleaved: bool,
+}
+
+impl SelfExtendAttention {
+    pub fn new(self_extend: SelfExtend, inv_freq: Vec<f64>, interleaved: bool) -> Self {
+        Self {
+            self_extend,
+            inv_freq,
+            interleaved,
+        }
+    }
+
+    /// The Self-Extend attention of a model with the rotary embedding of `cfg`, `None` unless
+    /// Self-Extend is enabled.
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        let self_extend = cfg.specific_config.self_extend?;
+        let (inv_freq, _) = cfg.get_rope_inv_freq(cfg.get_rotary_dim());
+        Some(Self::new(self_extend, inv_freq, cfg.rope_interleaved))
+    }
+
+    /// Rotate the rotary embedded `x` of shape `(batch_size, num_heads, num_tokens, head_size)`,
+    /// moving token `t` by `deltas[t]` positions.
+    fn shift(&self, x: &Tensor, deltas: &[i64]) -> Result<Tensor> {
+        let (_, _, num_tokens, head_size) = x.dims4()?;
+        let rotary_dim = 2 * self.inv_freq.len();
+        let angles = deltas
+            .iter()
+            .flat_map(|&delta| self.inv_freq.iter().map(move |f| (delta as f64 * f) as f32))
+            .collect::<Vec<_>>();
+        let angles = Tensor::from_vec(angles, (num_tokens, rotary_dim / 2), x.device())?;
+        let rope = if self.interleaved {
+            candle_nn::rotary_emb::rope_i
+        } else {
+            candle_nn::rotary_emb::rope
+        };
+        let x = x.to_dtype(DType::F32)?;
+        let rotated = rope(
+            &x.narrow(3, 0, rotary_dim)?.contiguous()?,
+            &angles.cos()?,
+            &angles.sin()?,
+        )?;
+        let pass = x.narrow(3, rotary_dim, head_size - rotary_dim)?;
+        Tensor::cat(&[&rotated, &pass], 3)
+    }
+
+    /// Scaled attention logits `(batch_size, num_heads, num_queries, num_keys)` of the rotary
+    /// embedded `query` `(batch_size, num_heads, num_queries, head_size)` at `query_positions`
+    /// and `key` `(batch_size, num_kv_heads, num_keys, head_size)` at `key_positions`: exact
+    /// in the neighbor window of each query, grouped beyond it.
+    pub fn logits(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        query_positions: &[usize],
+        key_positions: &[usize],
+        scale: f32,
+    ) -> Result<Tensor> {
+        let (batch_size, num_heads, num_queries, _) = query.dims4()?;
+        let num_keys = key_positions.len();
+        let self_extend = &self.self_extend;
+        let neighbor = query_positions
+            .iter()
+            .flat_map(|&q| key_positions.iter().map(move |&k| (q, k)))
+            .map(|(q, k)| u8::from(self_extend.is_neighbor(q, k)))
+            .collect::<Vec<_>>();
+        let exact = Self::scaled_logits(query, key, scale)?;
+        if neighbor.iter().all(|&n| n == 1) {
+            return Ok(exact);
+        }
+        let query_deltas = query_positions
+            .iter()
+            .map(|&q| self_extend.grouped_query_position(q) as i64 - q as i64)
+            .collect::<Vec<_>>();
+        let key_deltas = key_positions
+            .iter()
+            .map(|&k| self_extend.grouped_key_position(k) as i64 - k as i64)
+            .collect::<Vec<_>>();
+        let grouped = Self::scaled_logits(
+            &self.shift(query, &query_deltas)?,
+            &self.shift(key, &key_deltas)?,
+            scale,
+        )?
+        .to_dtype(exact.dtype())?;
+        let neighbor = Tensor::from_vec(neighbor, (num_queries, num_keys), query.device())?
+            .broadcast_as((batch_size, num_heads, num_queries, num_keys))?;
+        neighbor.where_cond(&exact, &grouped)
+    }
+
+    /// `query @ key^T * scale`, query head h attends to key head h / (num_heads / num_kv_heads).
+    fn scaled_logits(query: &Tensor, key: &Tensor, scale: f32) -> Result<Tensor> {
+        let (batch_size, num_heads, _, head_size) = query.dims4()?;
+        let (_, num_kv_heads, num_keys, _) = key.dims4()?;
+        let key = key
+            .unsqueeze(2)?
+            .broadcast_as((
+                batch_size,
+                num_kv_heads,
+                num_heads / num_kv_heads,
+                num_keys,
+                head_size,
+            ))?
+            .reshape((batch_size, num_heads, num_keys, head_size))?;
+        query.contiguous()?.matmul(&key.t()?)? * f64::from(scale)
+    }
+
+    /// Decoding attention over the paged caches, the query of a sequence being at the last
+    /// position of its context. Arguments as in [`crate::backend::paged_attention`], returns a
+    /// tensor of shape `(num_sequences, num_heads, head_size)`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn paged_attention(
+        &self,
+        query: &Tensor,
+        key_cache: &Tensor,
+        value_cache: &Tensor,
+        block_tables: &Tensor,
+        context_lens: &Tensor,
+        scale: f32,
+        softcapping: f32,
+        sliding_window: usize,
+    ) -> Result<Tensor> {
+        let (_, num_heads, _) = query.dims3()?;
+        let block_tables = block_tables.to_dtype(DType::U32)?.to_vec2::<u32>()?;
+        let context_lens = context_lens.to_dtype(DType::I64)?.to_vec1::<i64>()?;
+
+        let mut outputs = Vec::with_capacity(context_lens.len());
+        for (i, (table, context_len)) in block_tables.iter().zip(context_lens).enumerate() {
+            let context_len = context_len as usize;
+            let window_start = match sliding_window {
+                0 => 0,
+                window => context_len.saturating_sub(window),
+            };
+            let len = context_len - window_start;
+            // (1, num_kv_heads, len, head_size)
+            let cached = |x: Tensor| -> Result<Tensor> {
+                x.narrow(0, window_start, len)?
+                    .transpose(0, 1)?
+                    .unsqueeze(0)?
+                    .contiguous()
+            };
+            let keys = cached(gather_keys(key_cache, table, context_len)?)?;
+            let values = cached(gather_values(value_cache, table, context_len)?)?;
+            let key_positions = (window_start..context_len).collect::<Vec<_>>();
+            // (1, num_heads, 1, head_size)
+            let q = query.i(i)?.unsqueeze(0)?.unsqueeze(2)?;
+            let att = self.logits(&q, &keys, &[context_len - 1], &key_positions, scale)?;
+            let att = if softcapping != 1f32 {
+                let softcapping = f64::from(softcapping);
+                ((att / softcapping)?.tanh()? * softcapping)?
+            } else {
+                att
+            };
+            let probs = candle_nn::ops::softmax_last_dim(&att.to_dtype(DType::F32)?)?;
+            let (_, num_kv_heads, _, head_size) = values.dims4()?;
+            let values = values
+                .unsqueeze(2)?
+                .broadcast_as((1, num_kv_heads, num_heads / num_kv_heads, len, head_size))?
+                .reshape((1, num_heads, len, head_size))?;
+            let out = probs.to_dtype(query.dtype())?.matmul(&values)?;
+            outputs.push(out.reshape((num_heads, head_size))?);
+        }
+        Tensor::stack(&outputs, 0)
+    }
+}
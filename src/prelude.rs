@@ -0,0 +1,39 @@
+//! The stable library API of candle-vllm.
+//!
+//! Applications embedding the engine or talking to a server should import from here:
+//!
+//! ```no_run
+//! use candle_vllm::prelude::*;
+//! ```
+//!
+//! Stability contract:
+//! - The items re-exported here follow semver: they are not removed or renamed, and their
+//!   signatures do not change incompatibly, within a major version (a minor version while the
+//!   crate is 0.x).
+//! - Types marked `#[non_exhaustive]` may gain variants or fields in any release, match them
+//!   with a wildcard arm.
+//! - Everything else, reached through the module paths (`backend`, `paged_attention`, the
+//!   models, the scheduler internals, ...), is internal and may change in any release even
+//!   though it is public for the server binary and the tests.
+
+#[cfg(feature = "client")]
+pub use crate::client::{ChatCompletionRequestBuilder, ChatCompletionStream, Client, ClientError};
+pub use crate::openai::models::SelfExtend;
+pub use crate::openai::pipelines::llm_engine::{GeneratedToken, GenerationStream, LLMEngine};
+pub use crate::openai::pipelines::pipeline::{DefaultLoader, DefaultModelPaths};
+pub use crate::openai::pipelines::{ModelLoader, ModelPaths, ModulePipeline};
+pub use crate::openai::requests::{
+    CachePriority, ChatCompletionRequest, EmbeddingInput, EmbeddingRequest, Messages, Pooling,
+    ReloadRequest, ResponseFormat, ScoreInput, ScoreRequest, StopTokens, StreamOptions,
+    TokenClassificationRequest, Tool, ToolChoice,
+};
+pub use crate::openai::responses::{
+    APIError, AbortResponse, ChatCompletionChunk, ChatCompletionResponse,
+    ChatCompletionUsageResponse, EmbeddingResponse, ModelList, ReloadResponse, ScoreResponse,
+    TokenClassificationResponse,
+};
+pub use crate::openai::sampling_params::{EarlyStoppingCondition, SamplingParams};
+pub use crate::openai::{OpenAIServerData, PipelineConfig, PromptLogging, ServedModel};
+pub use crate::scheduler::cache_engine::CacheConfig;
+pub use crate::scheduler::{SchedulerConfig, SchedulingPolicy};
+pub use crate::{get_model_loader, local_weight_files, ModelSelected, WeightFilesError};
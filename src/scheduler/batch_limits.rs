@@ -0,0 +1,70 @@
+use candle_core::DType;
+
+use crate::openai::models::Config;
+
+/// Scheduler batch limits picked at startup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchLimits {
+    pub max_num_seqs: usize,
+    pub max_num_batched_tokens: usize,
+}
+
+/// Limits per model size class: (largest size in billions of parameters, max_num_seqs,
+/// max_num_batched_tokens). Small models are cheap to step and want wide batches, large
+/// models are bound by their KV cache and by the activations of each step.
+const SIZE_CLASSES: [(f64, usize, usize); 6] = [
+    (2., 512, 16384),
+    (4., 384, 16384),
+    (10., 256, 8192),
+    (20., 128, 8192),
+    (40., 64, 4096),
+    (f64::INFINITY, 32, 4096),
+];
+
+/// Context length a sequence is expected to reach on average when sizing the batch against the
+/// KV cache.
+const TYPICAL_SEQ_LEN: usize = 1024;
+
+/// Derive the default batch limits from the model size and dtype, the KV cache capacity (the
+/// `state_slots` sequences of a recurrent model) and, when known, the device memory left free
+/// once the model and the KV cache are loaded.
+pub fn auto_batch_limits(
+    config: &Config,
+    dtype: DType,
+    num_gpu_blocks: usize,
+    block_size: usize,
+    state_slots: Option<usize>,
+    free_memory: Option<usize>,
+) -> BatchLimits {
+    let params_b = config.approx_num_params() as f64 / 1e9;
+    let (_, max_num_seqs, max_num_batched_tokens) = SIZE_CLASSES
+        .iter()
+        .find(|(max_params_b, _, _)| params_b <= *max_params_b)
+        .copied()
+        .unwrap();
+
+    // Sequences beyond what the KV cache holds at a typical length would only be preempted
+    let typical_len = config.max_seq_len.clamp(1, TYPICAL_SEQ_LEN);
+    let kv_seqs = state_slots
+        .unwrap_or(num_gpu_blocks * block_size / typical_len)
+        .max(1);
+    let mut limits = BatchLimits {
+        max_num_seqs: max_num_seqs.min(kv_seqs),
+        max_num_batched_tokens,
+    };
+
+    if let Some(free_memory) = free_memory {
+        // Keep half of the free memory as slack for the allocator and the attention scores
+        let headroom = free_memory / 2;
+        let dsize = dtype.size_in_bytes();
+        // Hidden states and MLP activations of one token within a layer
+        let token_bytes = (4 * config.hidden_size + 2 * config.intermediate_size) * dsize;
+        // A decoding sequence also produces a row of F32 logits
+        let seq_bytes = token_bytes + config.vocab_size * 4;
+        limits.max_num_seqs = limits.max_num_seqs.min((headroom / seq_bytes).max(1));
+        limits.max_num_batched_tokens = limits
+            .max_num_batched_tokens
+            .min((headroom / token_bytes).max(config.max_seq_len.min(TYPICAL_SEQ_LEN)));
+    }
+    limits
+}
@@ -1,12 +1,18 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    hash::Hash,
+    collections::{hash_map::DefaultHasher, hash_map::Entry, HashMap, HashSet},
+    fmt::Write,
+    hash::{Hash, Hasher},
     marker::PhantomData,
     ops::Deref,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use super::sequence::{Sequence, SequenceGroup};
+use crate::openai::requests::CachePriority;
+use crate::openai::responses::BlockStats;
 
 pub struct LogicalTokenBlock {
     tokens: Vec<usize>,
@@ -160,7 +166,7 @@ impl Allocator<CPUAllocator> {
                     block_id: id,
                     block_size,
                     refcount: 0,
-                    is_gpu: true,
+                    is_gpu: false,
                 },
             ))))
         }
@@ -179,30 +185,166 @@ pub enum AllocStatus {
 
 type SeqID = usize;
 
+/// Full blocks holding the KV of a prompt prefix (the template-rendered system and tools part of
+/// a chat prompt), kept after the request finishes so that later prompts starting with the same
+/// tokens skip its prefill. The cache holds one reference on each block.
+///
+/// The blocks live either on the GPU or, once spilled to make room on the GPU, on the CPU. A hit
+/// on the CPU tier swaps the blocks back in, which is still much cheaper than the prefill.
+///
+/// Prefixes are released by `priority`, then least recently used first.
+struct CachedPrefix {
+    tokens: Vec<usize>,
+    blocks: BlockTable,
+    last_used: usize,
+    priority: CachePriority,
+}
+
+impl CachedPrefix {
+    fn is_gpu(&self) -> bool {
+        self.blocks[0].deref_mut().is_gpu
+    }
+
+    /// Prefixes ranking lower are released first.
+    fn rank(&self) -> (CachePriority, usize) {
+        (self.priority, self.last_used)
+    }
+}
+
+/// Prefix cache counters, shared with the server so they can be read without locking the engine.
+#[derive(Debug, Default)]
+pub struct PrefixCacheMetrics {
+    pub gpu_hits: AtomicUsize,
+    pub cpu_hits: AtomicUsize,
+    pub misses: AtomicUsize,
+    /// Prefixes moved from the GPU to the CPU tier
+    pub spills: AtomicUsize,
+    /// Prefixes dropped from the cache
+    pub evictions: AtomicUsize,
+    pub gpu_blocks: AtomicUsize,
+    pub cpu_blocks: AtomicUsize,
+}
+
+impl PrefixCacheMetrics {
+    /// The counters of the served models in the Prometheus text format, labeled by model.
+    pub fn render(models: &[(&str, &Self)]) -> String {
+        type Series = fn(&PrefixCacheMetrics) -> Vec<(&'static str, &AtomicUsize)>;
+        let families: [(&str, &str, &str, Series); 5] = [
+            (
+                "hits_total",
+                "counter",
+                "Prompts whose prefix was cached, by tier.",
+                |m| vec![("tier=\"gpu\"", &m.gpu_hits), ("tier=\"cpu\"", &m.cpu_hits)],
+            ),
+            (
+                "misses_total",
+                "counter",
+                "Prompts whose shareable prefix was not cached.",
+                |m| vec![("", &m.misses)],
+            ),
+            (
+                "spills_total",
+                "counter",
+                "Prefixes moved from the GPU to the CPU tier.",
+                |m| vec![("", &m.spills)],
+            ),
+            (
+                "evictions_total",
+                "counter",
+                "Prefixes dropped from the cache.",
+                |m| vec![("", &m.evictions)],
+            ),
+            (
+                "blocks",
+                "gauge",
+                "KV cache blocks held by cached prefixes, by tier.",
+                |m| {
+                    vec![
+                        ("tier=\"gpu\"", &m.gpu_blocks),
+                        ("tier=\"cpu\"", &m.cpu_blocks),
+                    ]
+                },
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, series) in families {
+            let _ = writeln!(
+                out,
+                "# HELP candle_vllm_prefix_cache_{name} {help}\n\
+                 # TYPE candle_vllm_prefix_cache_{name} {kind}"
+            );
+            for (model, metrics) in models {
+                for (labels, counter) in series(metrics) {
+                    let sep = if labels.is_empty() { "" } else { "," };
+                    let _ = writeln!(
+                        out,
+                        "candle_vllm_prefix_cache_{name}{{model=\"{model}\"{sep}{labels}}} {}",
+                        counter.load(Ordering::Relaxed)
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
 /// A BlockEngine maps each Sequence (identified by its SeqID), to physical token blocks.
 /// The physical token blocks may not match the logical token blocks because during
 /// scheduling, physical blocks are allocated to accommodate the new tokens generated.
 /// These new tokens will be added to the logical token block for each sequence.
 pub struct BlockEngine {
+    block_size: usize,
     num_gpu_blocks: usize,
+    num_cpu_blocks: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
     pub block_tables: HashMap<SeqID, BlockTable>,
+    prefix_cache: HashMap<u64, CachedPrefix>,
+    /// Shareable prefixes of the prompts allocated but not prefilled yet, by the id of their
+    /// first sequence. Their blocks hold no KV until then.
+    pending_prefixes: HashMap<SeqID, (u64, Vec<usize>, CachePriority)>,
+    prefix_cache_clock: usize,
+    prefix_metrics: Arc<PrefixCacheMetrics>,
+    /// Swaps of cached prefixes between the tiers, issued with the next scheduler output.
+    prefix_swap_in: HashMap<usize, usize>,
+    prefix_swap_out: HashMap<usize, usize>,
+    /// CPU blocks of prefixes swapped back in, released once their swap-in is issued.
+    swapped_in_cpu_blocks: BlockTable,
 }
 
 impl BlockEngine {
     #[must_use]
     pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
         Self {
+            block_size,
             num_gpu_blocks,
+            num_cpu_blocks,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
             block_tables: HashMap::new(),
+            prefix_cache: HashMap::new(),
+            pending_prefixes: HashMap::new(),
+            prefix_cache_clock: 0,
+            prefix_metrics: Arc::new(PrefixCacheMetrics::default()),
+            prefix_swap_in: HashMap::new(),
+            prefix_swap_out: HashMap::new(),
+            swapped_in_cpu_blocks: Vec::new(),
         }
     }
 
+    /// Blocks of the prompt of a waiting group. Its sequences all start from the prompt and share
+    /// its blocks until they diverge.
+    fn num_prompt_blocks(seq_group: &SequenceGroup) -> usize {
+        seq_group
+            .get_seqs()
+            .values()
+            .map(|seq| seq.deref().get_logical_token_blocks())
+            .max()
+            .unwrap_or(0)
+    }
+
     pub fn can_allocate(&self, seq_group: &SequenceGroup) -> AllocStatus {
-        let num_required_blocks = seq_group.get_total_logical_token_blocks();
+        let num_required_blocks = Self::num_prompt_blocks(seq_group);
         let num_free_gpu_blocks = *self.gpu_allocator.get_num_free_blocks();
 
         if self.num_gpu_blocks < num_required_blocks {
@@ -215,19 +357,501 @@ impl BlockEngine {
     }
 
     pub fn allocate(&mut self, seq_group: &SequenceGroup) {
+        let num_blocks = Self::num_prompt_blocks(seq_group);
         let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq_group.get_total_logical_token_blocks() {
+        let mut prefix_cached_len = self.restore_session(seq_group, &mut block_table);
+        // A restored session covers the shareable prefix of its prompt
+        let prefix = if prefix_cached_len == 0 {
+            self.shareable_prefix(seq_group)
+        } else {
+            None
+        };
+        if let Some((key, tokens)) = &prefix {
+            self.prefix_cache_clock += 1;
+            let cached = self.prefix_cache.get_mut(key);
+            if let Some(cached) = cached.filter(|cached| cached.tokens == *tokens) {
+                cached.last_used = self.prefix_cache_clock;
+                cached.priority = cached.priority.max(seq_group.cache_priority);
+                self.swap_in_prefix(*key);
+                for block in &self.prefix_cache[key].blocks {
+                    block.deref_mut().refcount += 1;
+                    block_table.push(block.clone());
+                }
+                prefix_cached_len = tokens.len();
+            } else {
+                self.prefix_metrics.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        while block_table.len() < num_blocks {
             block_table.push(self.gpu_allocator.allocate());
         }
-        for seq_id in seq_group.get_seqs().keys() {
+        if let Some((key, tokens)) = prefix {
+            if prefix_cached_len == 0 {
+                // Cached once the prefill of this group wrote the KV of its blocks, see
+                // `cache_prefilled_prefix`
+                if let Some(seq_id) = seq_group.get_seqs().keys().next() {
+                    self.pending_prefixes
+                        .insert(*seq_id, (key, tokens, seq_group.cache_priority));
+                }
+            }
+            self.update_prefix_gauges();
+        }
+        // The sequences of the group (`best_of` samples) share the prompt blocks, each one copies
+        // a block on its first write to it
+        for (idx, (seq_id, seq)) in seq_group.get_seqs().iter().enumerate() {
+            if idx > 0 {
+                for block in &block_table {
+                    block.deref_mut().refcount += 1;
+                }
+            }
+            seq.deref_mut().set_prefix_cached_len(prefix_cached_len);
             self.block_tables.insert(*seq_id, block_table.clone());
         }
     }
 
+    /// Cache the shareable prefix of the prompt of an allocated group once its prefill is done,
+    /// later prompts starting with it share its blocks. Nothing is cached for a prompt whose
+    /// prefill failed, its blocks hold no KV.
+    pub fn cache_prefilled_prefix(&mut self, seq_group: &SequenceGroup) {
+        let Some(seq_id) = seq_group.get_seqs().keys().next() else {
+            return;
+        };
+        let Some((key, tokens, priority)) = self.pending_prefixes.remove(seq_id) else {
+            return;
+        };
+        if self
+            .prefix_cache
+            .get(&key)
+            .is_some_and(|cached| cached.tokens == tokens)
+        {
+            // Another prompt of the same step cached it first
+            return;
+        }
+        let Some(block_table) = self.block_tables.get(seq_id) else {
+            return;
+        };
+        let blocks = block_table[..tokens.len() / self.block_size].to_vec();
+        for block in &blocks {
+            block.deref_mut().refcount += 1;
+        }
+        self.prefix_cache_clock += 1;
+        self.insert_cached_prefix(key, tokens, blocks, priority);
+        self.update_prefix_gauges();
+    }
+
+    /// Forget the pending prefix of a sequence released before its prefill.
+    pub fn discard_pending_prefix(&mut self, sequence: &Sequence) {
+        self.pending_prefixes.remove(&sequence.deref_mut().get_id());
+    }
+
+    /// Count a hit on a cached prefix and bring its blocks back to the GPU if they were spilled to
+    /// the CPU tier. `can_allocate` counted the blocks of the swap-in.
+    fn swap_in_prefix(&mut self, key: u64) {
+        let cached = self.prefix_cache.get_mut(&key).unwrap();
+        if cached.is_gpu() {
+            self.prefix_metrics.gpu_hits.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.prefix_metrics.cpu_hits.fetch_add(1, Ordering::Relaxed);
+        let gpu_blocks = cached
+            .blocks
+            .iter()
+            .map(|cpu_block| {
+                let gpu_block = self.gpu_allocator.allocate();
+                self.prefix_swap_in.insert(
+                    cpu_block.deref_mut().block_id,
+                    gpu_block.deref_mut().block_id,
+                );
+                gpu_block
+            })
+            .collect();
+        let cpu_blocks = std::mem::replace(&mut cached.blocks, gpu_blocks);
+        self.swapped_in_cpu_blocks.extend(cpu_blocks);
+    }
+
+    /// Key of the snapshot of a session in the prefix cache.
+    fn session_key(session_id: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        ("session", session_id).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Share the blocks of the snapshot of the group's session that hold the start of its
+    /// prompt, e.g. the history of a chat, and return the number of tokens they hold. The
+    /// snapshot stays cached for the next turns.
+    fn restore_session(
+        &mut self,
+        seq_group: &SequenceGroup,
+        block_table: &mut BlockTable,
+    ) -> usize {
+        let Some(session_id) = &seq_group.session_id else {
+            return 0;
+        };
+        let Some(seq) = seq_group.get_seqs().values().next() else {
+            return 0;
+        };
+        if !seq.deref().is_prompt() {
+            return 0;
+        }
+        let prompt = seq.deref().get_token_ids();
+        let key = Self::session_key(session_id);
+        let Some(cached) = self.prefix_cache.get_mut(&key) else {
+            return 0;
+        };
+        // The new prompt re-renders the history, it may differ from the snapshot after a point.
+        // The last prompt token is never shared, its logits are needed.
+        let matched = cached
+            .tokens
+            .iter()
+            .zip(&prompt)
+            .take_while(|(cached, token)| cached == token)
+            .count()
+            .min(prompt.len().saturating_sub(1));
+        let num_blocks = matched / self.block_size;
+        if num_blocks == 0 {
+            return 0;
+        }
+        self.prefix_cache_clock += 1;
+        cached.last_used = self.prefix_cache_clock;
+        cached.priority = cached.priority.max(seq_group.cache_priority);
+        self.swap_in_prefix(key);
+        for block in &self.prefix_cache[&key].blocks[..num_blocks] {
+            block.deref_mut().refcount += 1;
+            block_table.push(block.clone());
+        }
+        self.update_prefix_gauges();
+        num_blocks * self.block_size
+    }
+
+    /// Keep the KV blocks of the finished `sequence` of a session in the prefix cache, replacing
+    /// the previous snapshot of the session, so that the next request of the session starts
+    /// from them instead of prefilling the whole conversation again. The snapshot is spilled to
+    /// the CPU and released like the other cached prefixes.
+    pub fn save_session(&mut self, session_id: &str, sequence: &Sequence, priority: CachePriority) {
+        let tokens = sequence.deref().get_token_ids();
+        let Some(table) = self.block_tables.get(&sequence.deref().get_id()) else {
+            return;
+        };
+        // The KV of the last token was never computed
+        let num_blocks = (tokens.len().saturating_sub(1) / self.block_size).min(table.len());
+        if num_blocks == 0 || !table[..num_blocks].iter().all(|b| b.deref_mut().is_gpu) {
+            return;
+        }
+        let blocks = table[..num_blocks].to_vec();
+        for block in &blocks {
+            block.deref_mut().refcount += 1;
+        }
+        let key = Self::session_key(session_id);
+        // Replaced, not evicted: the blocks it shares with the new snapshot stay allocated
+        if let Some(previous) = self.prefix_cache.remove(&key) {
+            self.release_blocks(previous.blocks);
+        }
+        self.prefix_cache_clock += 1;
+        self.insert_cached_prefix(
+            key,
+            tokens[..num_blocks * self.block_size].to_vec(),
+            blocks,
+            priority,
+        );
+        self.update_prefix_gauges();
+    }
+
+    /// Whether a snapshot of the session is cached, on either tier.
+    pub fn has_session(&self, session_id: &str) -> bool {
+        self.prefix_cache
+            .contains_key(&Self::session_key(session_id))
+    }
+
+    /// Key and tokens of the full blocks covered by the shared prefix of the group's prompt. The
+    /// last prompt token is never shared, its logits are needed to sample the first token.
+    fn shareable_prefix(&self, seq_group: &SequenceGroup) -> Option<(u64, Vec<usize>)> {
+        let seq = seq_group.get_seqs().values().next()?;
+        let seq = seq.deref();
+        if !seq.is_prompt() {
+            return None;
+        }
+        self.prefix_key(&seq.get_token_ids(), seq_group.prefix_len)
+    }
+
+    /// The cache key and tokens of the full blocks of the first `prefix_len` tokens of a prompt.
+    /// The last prompt token is never shared, its logits are needed.
+    fn prefix_key(&self, prompt: &[usize], prefix_len: usize) -> Option<(u64, Vec<usize>)> {
+        let num_blocks = prefix_len.min(prompt.len().saturating_sub(1)) / self.block_size;
+        if num_blocks == 0 {
+            return None;
+        }
+        let tokens = prompt[..num_blocks * self.block_size].to_vec();
+        let mut hasher = DefaultHasher::new();
+        tokens.hash(&mut hasher);
+        Some((hasher.finish(), tokens))
+    }
+
+    /// Prompt tokens that would be read from the prefix cache (on either tier) if the prompt,
+    /// with a shareable prefix of `prefix_len` tokens, was scheduled now.
+    pub fn cached_prefix_len(&self, prompt: &[usize], prefix_len: usize) -> usize {
+        self.prefix_key(prompt, prefix_len)
+            .and_then(|(key, tokens)| {
+                self.prefix_cache
+                    .get(&key)
+                    .filter(|cached| cached.tokens == tokens)
+            })
+            .map_or(0, |cached| cached.tokens.len())
+    }
+
+    fn insert_cached_prefix(
+        &mut self,
+        key: u64,
+        tokens: Vec<usize>,
+        blocks: BlockTable,
+        priority: CachePriority,
+    ) {
+        let cached = CachedPrefix {
+            tokens,
+            blocks,
+            last_used: self.prefix_cache_clock,
+            priority,
+        };
+        if let Some(old) = self.prefix_cache.insert(key, cached) {
+            // Hash collision with a different prefix, the newer one wins.
+            self.free_prefix(old);
+        }
+    }
+
+    /// Release the cache's reference on the blocks of a dropped prefix.
+    fn free_prefix(&mut self, cached: CachedPrefix) {
+        self.prefix_metrics
+            .evictions
+            .fetch_add(1, Ordering::Relaxed);
+        self.release_blocks(cached.blocks);
+    }
+
+    fn release_blocks(&mut self, blocks: BlockTable) {
+        for block in blocks {
+            if block.deref_mut().is_gpu {
+                self.gpu_allocator.free_block(block);
+            } else {
+                self.cpu_allocator.free_block(block);
+            }
+        }
+    }
+
+    /// Key of the cached prefix to release first on the given tier: the least recently used of
+    /// the lowest priority.
+    fn lru_prefix(&self, gpu: bool) -> Option<u64> {
+        self.prefix_cache
+            .iter()
+            .filter(|(_, cached)| cached.is_gpu() == gpu)
+            .min_by_key(|(_, cached)| cached.rank())
+            .map(|(key, _)| *key)
+    }
+
+    /// Release the least recently used prefix of the lowest priority on the GPU. Its blocks are spilled to the CPU tier
+    /// when no sequence shares them and the CPU has room, made by dropping colder CPU prefixes;
+    /// otherwise the prefix is dropped. Returns false if no prefix is cached on the GPU.
+    pub fn evict_lru_prefix(&mut self) -> bool {
+        let Some(key) = self.lru_prefix(true) else {
+            return false;
+        };
+        let cached = self.prefix_cache.remove(&key).unwrap();
+        let unshared = cached
+            .blocks
+            .iter()
+            .all(|block| block.deref_mut().refcount == 1);
+        if unshared && self.make_cpu_room(cached.blocks.len(), cached.rank()) {
+            self.prefix_metrics.spills.fetch_add(1, Ordering::Relaxed);
+            let cpu_blocks = cached
+                .blocks
+                .into_iter()
+                .map(|gpu_block| {
+                    let cpu_block = self.cpu_allocator.allocate();
+                    self.prefix_swap_out.insert(
+                        gpu_block.deref_mut().block_id,
+                        cpu_block.deref_mut().block_id,
+                    );
+                    self.gpu_allocator.free_block(gpu_block);
+                    cpu_block
+                })
+                .collect();
+            self.prefix_cache.insert(
+                key,
+                CachedPrefix {
+                    blocks: cpu_blocks,
+                    ..cached
+                },
+            );
+        } else {
+            self.free_prefix(cached);
+        }
+        self.update_prefix_gauges();
+        true
+    }
+
+    /// Drop the least recently used prefix of the lowest priority on the CPU, e.g. to swap out a
+    /// sequence. Returns false if no prefix is cached on the CPU.
+    pub fn evict_lru_cpu_prefix(&mut self) -> bool {
+        let Some(key) = self.lru_prefix(false) else {
+            return false;
+        };
+        let cached = self.prefix_cache.remove(&key).unwrap();
+        self.free_prefix(cached);
+        self.update_prefix_gauges();
+        true
+    }
+
+    /// Free `num_blocks` CPU blocks by dropping CPU prefixes ranking below `rank`, lowest first.
+    /// Nothing is dropped if that is not enough.
+    fn make_cpu_room(&mut self, num_blocks: usize, rank: (CachePriority, usize)) -> bool {
+        let mut colder = self
+            .prefix_cache
+            .iter()
+            .filter(|(_, cached)| !cached.is_gpu() && cached.rank() < rank)
+            .map(|(key, cached)| (cached.rank(), *key, cached.blocks.len()))
+            .collect::<Vec<_>>();
+        let num_free = self.cpu_allocator.free_blocks.len();
+        if num_free + colder.iter().map(|(_, _, len)| len).sum::<usize>() < num_blocks {
+            return false;
+        }
+        colder.sort_unstable();
+        for (_, key, _) in colder {
+            if self.cpu_allocator.free_blocks.len() >= num_blocks {
+                break;
+            }
+            let cached = self.prefix_cache.remove(&key).unwrap();
+            self.free_prefix(cached);
+        }
+        true
+    }
+
+    fn update_prefix_gauges(&self) {
+        let (mut gpu_blocks, mut cpu_blocks) = (0, 0);
+        for cached in self.prefix_cache.values() {
+            if cached.is_gpu() {
+                gpu_blocks += cached.blocks.len();
+            } else {
+                cpu_blocks += cached.blocks.len();
+            }
+        }
+        let metrics = &self.prefix_metrics;
+        metrics.gpu_blocks.store(gpu_blocks, Ordering::Relaxed);
+        metrics.cpu_blocks.store(cpu_blocks, Ordering::Relaxed);
+    }
+
+    /// GPU blocks held by sequences or cached prefixes.
+    pub fn num_used_gpu_blocks(&self) -> usize {
+        self.num_gpu_blocks - *self.gpu_allocator.get_num_free_blocks()
+    }
+
+    /// Used, free and total blocks of the GPU and CPU caches.
+    pub fn block_stats(&self) -> (BlockStats, BlockStats) {
+        let stats = |free: usize, total: usize| BlockStats {
+            used: total - free,
+            free,
+            total,
+        };
+        (
+            stats(
+                *self.gpu_allocator.get_num_free_blocks(),
+                self.num_gpu_blocks,
+            ),
+            stats(self.cpu_allocator.free_blocks.len(), self.num_cpu_blocks),
+        )
+    }
+
+    pub fn prefix_cache_metrics(&self) -> Arc<PrefixCacheMetrics> {
+        self.prefix_metrics.clone()
+    }
+
+    /// Swaps of cached prefixes between the tiers issued since the last call, as
+    /// `(swap_in, swap_out)` block mappings. The CPU blocks read by the swap-ins are only released
+    /// now, so that no spill of the same scheduling step overwrites them before they are read.
+    pub fn take_prefix_swaps(&mut self) -> (HashMap<usize, usize>, HashMap<usize, usize>) {
+        for block in std::mem::take(&mut self.swapped_in_cpu_blocks) {
+            self.cpu_allocator.free_block(block);
+        }
+        (
+            std::mem::take(&mut self.prefix_swap_in),
+            std::mem::take(&mut self.prefix_swap_out),
+        )
+    }
+
+    /// Whether the GPU has the blocks for `child`, a sequence forked from another one.
+    pub fn can_fork(&self, child: &Sequence) -> bool {
+        let child_len = child.deref().get_len();
+        let num_shared = (child_len - 1) / self.block_size;
+        child_len.div_ceil(self.block_size) - num_shared
+            <= *self.gpu_allocator.get_num_free_blocks()
+    }
+
+    /// Give `child`, whose tokens start with the tokens of `parent`, a block table sharing the
+    /// parent's full blocks. All tokens but the last (pending) one are cached already. Returns the
+    /// copy-on-write mapping (src, dst) of the partially filled block holding the pending token.
+    pub fn fork(&mut self, parent: &Sequence, child: &Sequence) -> Option<(usize, usize)> {
+        let child_len = child.deref().get_len();
+        let parent_table = self
+            .block_tables
+            .get(&parent.deref().get_id())
+            .unwrap()
+            .clone();
+        let num_cached = child_len - 1;
+        let mut table = parent_table[..num_cached / self.block_size].to_vec();
+        for block in &table {
+            block.deref_mut().refcount += 1;
+        }
+        let mut copy = None;
+        if num_cached % self.block_size != 0 {
+            let src = &parent_table[num_cached / self.block_size];
+            let dst = self.gpu_allocator.allocate();
+            copy = Some((src.deref_mut().block_id, dst.deref_mut().block_id));
+            table.push(dst);
+        }
+        while table.len() < child_len.div_ceil(self.block_size) {
+            table.push(self.gpu_allocator.allocate());
+        }
+        self.block_tables.insert(child.deref().get_id(), table);
+        copy
+    }
+
+    /// Give `child`, a sequence holding the same tokens as `parent` (another sample of the same
+    /// prompt, a beam, a draft being verified), the blocks of the parent. The blocks are shared,
+    /// not copied: the first write of either sequence to a shared block copies it (see
+    /// `append_token_slot_to_seq`), so the sequences only hold their own blocks once they
+    /// diverge.
+    pub fn fork_seq(&mut self, parent: &Sequence, child: &Sequence) {
+        let table = self.block_tables.get(&parent.deref().get_id()).unwrap();
+        // Lookahead blocks of the parent are not shared, they hold no token yet
+        let num_cached = child.deref().get_cached_len().div_ceil(self.block_size);
+        let table = table[..num_cached.min(table.len())].to_vec();
+        for block in &table {
+            block.deref_mut().refcount += 1;
+        }
+        self.block_tables.insert(child.deref().get_id(), table);
+    }
+
+    /// Whether the block at `idx` of the sequence's block table is shared with another sequence
+    /// or a cached prefix, and has to be copied before the sequence writes to it.
+    fn is_shared(&self, sequence: &Sequence, idx: usize) -> bool {
+        self.block_tables
+            .get(&sequence.deref().get_id())
+            .and_then(|table| table.get(idx))
+            .is_some_and(|block| block.deref_mut().refcount > 1)
+    }
+
+    /// Blocks to allocate for the next token of the sequence: a new block when the pending token
+    /// fills its last block, and a copy of the last block when it is shared.
+    fn num_blocks_to_append(&self, sequence: &Sequence) -> usize {
+        let table_len = self.block_tables[&sequence.deref().get_id()].len();
+        sequence.deref().blocks_to_add_new_tok()
+            + usize::from(self.is_shared(sequence, table_len - 1))
+    }
+
     pub fn can_append_token_to_seq(&self, seq_group: &SequenceGroup) -> bool {
         let free_blocks = self.gpu_allocator.get_num_free_blocks();
-        // Physical blocks = logical blocks
-        seq_group.total_blocks_to_add_new_tok() <= *free_blocks
+        let required: usize = seq_group
+            .get_seqs()
+            .values()
+            .map(|seq| self.num_blocks_to_append(seq))
+            .sum();
+        required <= *free_blocks
     }
 
     pub fn free_sequence(&mut self, sequence: &Sequence) {
@@ -246,16 +870,36 @@ impl BlockEngine {
         }
 
         self.block_tables.remove(&sequence.deref_mut().get_id());
+        self.discard_pending_prefix(sequence);
     }
 
-    pub fn can_swap_out_seq_group(&self, seq_group: &SequenceGroup) -> bool {
-        let blocks_required: usize = self
+    /// Release the physical blocks at the given positions of the sequence's block table.
+    pub fn evict_blocks(&mut self, sequence: &Sequence, block_indices: &[usize]) {
+        let table = self
             .block_tables
+            .get_mut(&sequence.deref_mut().get_id())
+            .unwrap();
+        let mut block_indices = block_indices.to_vec();
+        block_indices.sort_unstable_by(|a, b| b.cmp(a));
+        for idx in block_indices {
+            let block = table.remove(idx);
+            assert!(block.deref_mut().is_gpu);
+            self.gpu_allocator.free_block(block);
+        }
+    }
+
+    /// Physical blocks of the group, the blocks its sequences share are swapped once.
+    fn num_group_blocks(&self, seq_group: &SequenceGroup) -> usize {
+        self.block_tables
             .iter()
             .filter(|(id, _)| seq_group.get_seqs().contains_key(id))
-            .map(|(_, table)| table.len())
-            .sum();
-        blocks_required <= self.cpu_allocator.free_blocks.len()
+            .flat_map(|(_, table)| table.iter().map(|block| block.deref_mut().block_id))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn can_swap_out_seq_group(&self, seq_group: &SequenceGroup) -> bool {
+        self.num_group_blocks(seq_group) <= self.cpu_allocator.free_blocks.len()
     }
 
     /// Update the block table so that the sequence does no longer reserve any GPU
@@ -295,48 +939,109 @@ impl BlockEngine {
             .collect::<HashMap<_, _>>()
     }
 
+    /// Give the sequence a block of its own at `idx` of its block table before it writes to it.
+    /// Returns the COW mapping (src, dst) if the block was shared.
+    fn copy_on_write(&mut self, seq_id: usize, idx: usize) -> Option<(usize, usize)> {
+        let block = &mut self.block_tables.get_mut(&seq_id).unwrap()[idx];
+        assert!(block.deref_mut().is_gpu);
+        if block.deref_mut().refcount == 1 {
+            None
+        } else {
+            // We would be writing into shared, so COW.
+            let new_block = self.gpu_allocator.allocate();
+            self.gpu_allocator.free_block(block.clone());
+            let old_number = block.deref_mut().block_id;
+            let new_number = new_block.deref_mut().block_id;
+            *block = new_block;
+            Some((old_number, new_number))
+        }
+    }
+
     // Returns the COW mapping (src, dst).
-    // COW is performed if there are multiple references to the last physical block.
+    // COW is performed if there are multiple references to the block receiving the pending
+    // token, the last physical block before a new one is added.
     pub fn append_token_slot_to_seq(&mut self, sequence: &Sequence) -> Option<(usize, usize)> {
-        let table = self
-            .block_tables
-            .get_mut(&sequence.deref_mut().get_id())
-            .unwrap();
-
-        match sequence.deref_mut().blocks_to_add_new_tok() {
+        let seq_id = sequence.deref().get_id();
+        let pending_block = self.block_tables[&seq_id].len() - 1;
+        let copy = self.copy_on_write(seq_id, pending_block);
+        match sequence.deref().blocks_to_add_new_tok() {
             1 => {
-                table.push(self.gpu_allocator.allocate());
-                None
-            }
-            0 => {
-                let last_block = table.last_mut().unwrap();
-                assert!(last_block.deref_mut().is_gpu);
-                if last_block.deref_mut().refcount == 1 {
-                    None
-                } else {
-                    // We would be writing into shared, so COW.
-                    let new_block = self.gpu_allocator.allocate();
-                    self.gpu_allocator.free_block(last_block.clone());
-                    let old_number = last_block.deref_mut().block_id;
-                    let new_number = new_block.deref_mut().block_id;
-                    *last_block = new_block;
-                    Some((old_number, new_number))
-                }
+                let block = self.gpu_allocator.allocate();
+                self.block_tables.get_mut(&seq_id).unwrap().push(block);
             }
+            0 => {}
             _ => {
                 unreachable!()
             }
         }
+        copy
     }
 
-    pub fn can_swap_in_seq_group(&self, seq_group: &SequenceGroup) -> bool {
-        let blocks_required: usize = self
-            .block_tables
-            .iter()
-            .filter(|(id, _)| seq_group.get_seqs().contains_key(id))
-            .map(|(_, table)| table.len())
+    /// Number of physical blocks to add so that the sequence holds its pending token plus
+    /// `num_lookahead_slots` speculative tokens.
+    pub fn num_blocks_to_reserve(&self, sequence: &Sequence, num_lookahead_slots: usize) -> usize {
+        let seq = sequence.deref();
+        let table_len = self.block_tables.get(&seq.get_id()).map_or(0, Vec::len);
+        (seq.get_cached_len() + num_lookahead_slots)
+            .div_ceil(self.block_size)
+            .saturating_sub(table_len)
+    }
+
+    pub fn can_reserve_slots(&self, seq_group: &SequenceGroup, num_lookahead_slots: usize) -> bool {
+        let required: usize = seq_group
+            .get_seqs()
+            .values()
+            .map(|seq| {
+                let pending_block = (seq.deref().get_cached_len() - 1) / self.block_size;
+                self.num_blocks_to_reserve(seq, num_lookahead_slots)
+                    + usize::from(self.is_shared(seq, pending_block))
+            })
             .sum();
-        blocks_required <= self.gpu_allocator.free_blocks.len()
+        required <= *self.gpu_allocator.get_num_free_blocks()
+    }
+
+    /// Grow the block table to hold the pending token plus `num_lookahead_slots` speculative
+    /// tokens. Returns the COW mapping (src, dst) of the block receiving the pending token.
+    pub fn reserve_slots_for_seq(
+        &mut self,
+        sequence: &Sequence,
+        num_lookahead_slots: usize,
+    ) -> Option<(usize, usize)> {
+        let cached_len = sequence.deref().get_cached_len();
+        let table = self
+            .block_tables
+            .get_mut(&sequence.deref().get_id())
+            .unwrap();
+        let required = (cached_len + num_lookahead_slots).div_ceil(self.block_size);
+        while table.len() < required {
+            table.push(self.gpu_allocator.allocate());
+        }
+        self.copy_on_write(
+            sequence.deref().get_id(),
+            (cached_len - 1) / self.block_size,
+        )
+    }
+
+    /// Free the trailing blocks that no longer hold any token of the sequence, the lookahead
+    /// blocks that were not filled.
+    pub fn rollback_slots(&mut self, sequence: &Sequence) {
+        let seq = sequence.deref();
+        let Some(table) = self.block_tables.get_mut(&seq.get_id()) else {
+            return;
+        };
+        let keep = seq.get_cached_len().div_ceil(self.block_size).max(1);
+        while table.len() > keep {
+            let block = table.pop().unwrap();
+            if block.deref_mut().is_gpu {
+                self.gpu_allocator.free_block(block);
+            } else {
+                self.cpu_allocator.free_block(block);
+            }
+        }
+    }
+
+    pub fn can_swap_in_seq_group(&self, seq_group: &SequenceGroup) -> bool {
+        self.num_group_blocks(seq_group) <= self.gpu_allocator.free_blocks.len()
     }
 
     /// Update the block table so that the sequence does no longer reserve any CPU
@@ -352,7 +1057,7 @@ impl BlockEngine {
                 let gpu_block =
                     if let Entry::Vacant(e) = new_mapping.entry(cpu_block.deref_mut().block_id) {
                         // Create a new block
-                        let gpu_block = self.cpu_allocator.allocate();
+                        let gpu_block = self.gpu_allocator.allocate();
                         e.insert(gpu_block.clone());
                         gpu_block
                     } else {
@@ -365,7 +1070,7 @@ impl BlockEngine {
                         gpu_block
                     };
                 new_block_table.push(gpu_block);
-                self.gpu_allocator.free_block(cpu_block.clone());
+                self.cpu_allocator.free_block(cpu_block.clone());
             }
             self.block_tables.insert(*seq_id, new_block_table);
         }
@@ -1,12 +1,16 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex, MutexGuard},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
 };
 
 use candle_core::{DType, Device, Tensor};
 
 use crate::{
-    backend::{copy_blocks, swap_blocks},
+    backend::{copy_blocks, copy_latent_blocks, kv_cache_head_rows, swap_blocks},
     openai::{models::Config, responses::APIError},
     try_api,
 };
@@ -17,7 +21,11 @@ pub struct CacheConfig {
     pub num_gpu_blocks: Option<usize>, // Set after profiling init
     pub num_cpu_blocks: Option<usize>, // Set after profiling init
     pub fully_init: bool,
+    /// U8 for an INT8 cache with a scale per slot and head
     pub dtype: DType,
+    /// Tensor parallel ranks the KV heads are sharded over, 1 on a single device. Block counts
+    /// are per rank: every rank holds the same blocks for its share of the heads.
+    pub tensor_parallel_size: usize,
 }
 
 impl CacheConfig {
@@ -37,10 +45,143 @@ impl CacheConfig {
 
 pub type KVCache = (Tensor, Tensor);
 
+/// The keys and values of a sequence of an encoder-decoder model (T5), kept apart from the
+/// paged KV cache. Per decoder layer: the cross-attention keys and values of the encoder output
+/// of its prompt, and the self-attention keys and values of the tokens decoded so far, all
+/// `(1, num_heads, len, head_dim)`.
+#[derive(Clone, Debug, Default)]
+pub struct EncoderDecoderKv {
+    pub cross: Vec<KVCache>,
+    /// Empty before the first decoding step
+    pub decoder: Vec<KVCache>,
+}
+
+impl EncoderDecoderKv {
+    /// Tokens of the encoder output.
+    pub fn encoder_len(&self) -> usize {
+        self.cross
+            .first()
+            .map_or(0, |(key, _)| key.dim(2).unwrap_or(0))
+    }
+
+    /// Tokens decoded so far.
+    pub fn decoder_len(&self) -> usize {
+        self.decoder
+            .first()
+            .map_or(0, |(key, _)| key.dim(2).unwrap_or(0))
+    }
+}
+
+/// Utilization of the GPU KV cache on each tensor parallel rank, served by `/metrics`.
+#[derive(Debug)]
+pub struct KvCacheMetrics {
+    tensor_parallel_size: usize,
+    num_gpu_blocks: usize,
+    /// Bytes of one block on each rank
+    block_bytes: usize,
+    pub used_gpu_blocks: AtomicUsize,
+}
+
+impl KvCacheMetrics {
+    pub fn new(model_config: &Config, cache_config: &CacheConfig) -> Self {
+        Self {
+            tensor_parallel_size: cache_config.tensor_parallel_size.max(1),
+            num_gpu_blocks: cache_config.num_gpu_blocks.unwrap_or(0),
+            block_bytes: CacheEngine::block_bytes(
+                model_config,
+                cache_config.dtype,
+                cache_config.block_size,
+                cache_config.tensor_parallel_size,
+            ),
+            used_gpu_blocks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fraction of the GPU KV cache blocks in use.
+    pub fn usage_ratio(&self) -> f64 {
+        self.used_gpu_blocks.load(Ordering::Relaxed) as f64 / self.num_gpu_blocks.max(1) as f64
+    }
+
+    /// The gauges of the served models in the Prometheus text format, one series per model and
+    /// rank.
+    pub fn render(models: &[(&str, &Self)]) -> String {
+        let mut out = String::from(
+            "# HELP candle_vllm_kv_cache_blocks GPU KV cache blocks, by rank and state.\n\
+             # TYPE candle_vllm_kv_cache_blocks gauge\n",
+        );
+        for (model, metrics) in models {
+            let used = metrics.used_gpu_blocks.load(Ordering::Relaxed);
+            for rank in 0..metrics.tensor_parallel_size {
+                let labels = format!("model=\"{model}\",rank=\"{rank}\"");
+                let _ = writeln!(
+                    out,
+                    "candle_vllm_kv_cache_blocks{{{labels},state=\"used\"}} {used}\n\
+                     candle_vllm_kv_cache_blocks{{{labels},state=\"total\"}} {}",
+                    metrics.num_gpu_blocks
+                );
+            }
+        }
+        out.push_str(
+            "# HELP candle_vllm_kv_cache_bytes GPU KV cache memory, by rank and state.\n\
+             # TYPE candle_vllm_kv_cache_bytes gauge\n",
+        );
+        for (model, metrics) in models {
+            let used = metrics.used_gpu_blocks.load(Ordering::Relaxed);
+            for rank in 0..metrics.tensor_parallel_size {
+                let labels = format!("model=\"{model}\",rank=\"{rank}\"");
+                let _ = writeln!(
+                    out,
+                    "candle_vllm_kv_cache_bytes{{{labels},state=\"used\"}} {}\n\
+                     candle_vllm_kv_cache_bytes{{{labels},state=\"total\"}} {}",
+                    used * metrics.block_bytes,
+                    metrics.num_gpu_blocks * metrics.block_bytes
+                );
+            }
+        }
+        out.push_str(
+            "# HELP candle_vllm_kv_cache_usage_ratio Fraction of the GPU KV cache blocks in use, by rank.\n\
+             # TYPE candle_vllm_kv_cache_usage_ratio gauge\n",
+        );
+        for (model, metrics) in models {
+            let ratio = metrics.usage_ratio();
+            for rank in 0..metrics.tensor_parallel_size {
+                let _ = writeln!(
+                    out,
+                    "candle_vllm_kv_cache_usage_ratio{{model=\"{model}\",rank=\"{rank}\"}} {ratio}"
+                );
+            }
+        }
+        out
+    }
+}
+
+/// How the paged KV cache of a model is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvCacheLayout {
+    /// Key and value caches with `num_key_value_heads` heads per token.
+    Heads,
+    /// One latent vector per token (multi-head latent attention) held in the key cache, read as
+    /// both the keys and the values. The value cache is a one element placeholder.
+    Latent,
+}
+
+impl KvCacheLayout {
+    pub fn of(model_config: &Config) -> Self {
+        if model_config.mla_config.is_some() {
+            Self::Latent
+        } else {
+            Self::Heads
+        }
+    }
+}
+
 pub struct CacheEngine {
     gpu_cache: Arc<Mutex<Vec<KVCache>>>,
     cpu_cache: Vec<KVCache>,
     num_layers: usize,
+    layout: KvCacheLayout,
+    /// The keys and values of the sequences of an encoder-decoder model, by sequence id.
+    encoder_decoder: HashMap<usize, EncoderDecoderKv>,
 }
 
 impl CacheEngine {
@@ -57,11 +198,52 @@ impl CacheEngine {
                 dtype,
                 device,
             )?)),
-            cpu_cache: Self::allocate_cpu_cache(&model_config, &cache_config, dtype, device)?,
+            // Host memory, the target of swapped out blocks
+            cpu_cache: Self::allocate_cpu_cache(&model_config, &cache_config, dtype, &Device::Cpu)?,
             num_layers: model_config.num_hidden_layers,
+            layout: KvCacheLayout::of(&model_config),
+            encoder_decoder: HashMap::new(),
         })
     }
 
+    /// KV heads cached by each of `tensor_parallel_size` ranks. Heads are split evenly across
+    /// the ranks, and replicated when there are fewer heads than ranks. The latent of multi-head
+    /// latent attention is shared by all heads, every rank caches all of it.
+    pub fn kv_heads_per_rank(model_config: &Config, tensor_parallel_size: usize) -> usize {
+        match KvCacheLayout::of(model_config) {
+            KvCacheLayout::Heads => model_config
+                .num_key_value_heads
+                .div_ceil(tensor_parallel_size.max(1)),
+            KvCacheLayout::Latent => model_config.num_key_value_heads,
+        }
+    }
+
+    /// Bytes of KV cache taken by one block on each rank, over all layers.
+    pub fn block_bytes(
+        model_config: &Config,
+        dtype: DType,
+        block_size: usize,
+        tensor_parallel_size: usize,
+    ) -> usize {
+        let (heads, rows, block_size, x) =
+            Self::calculate_key_block_shape(model_config, dtype, block_size, tensor_parallel_size);
+        let value_elems = match KvCacheLayout::of(model_config) {
+            KvCacheLayout::Heads => {
+                let (heads, rows, block_size) = Self::calculate_value_block_shape(
+                    model_config,
+                    dtype,
+                    block_size,
+                    tensor_parallel_size,
+                );
+                heads * rows * block_size
+            }
+            KvCacheLayout::Latent => 0,
+        };
+        (heads * rows * block_size * x + value_elems)
+            * dtype.size_in_bytes()
+            * model_config.num_hidden_layers
+    }
+
     pub fn get_kv_cache(&self) -> MutexGuard<'_, Vec<KVCache>> {
         loop {
             if let Ok(v) = self.gpu_cache.try_lock() {
@@ -78,12 +260,22 @@ impl CacheEngine {
     ) -> Result<Vec<KVCache>, APIError> {
         assert!(cache_config.fully_init);
 
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let key_block_shape = Self::calculate_key_block_shape(
+            model_config,
+            dtype,
+            cache_config.block_size,
+            cache_config.tensor_parallel_size,
+        );
+        let value_block_shape = Self::calculate_value_block_shape(
+            model_config,
+            dtype,
+            cache_config.block_size,
+            cache_config.tensor_parallel_size,
+        );
         let mut gpu_cache = Vec::new();
-        for _ in 0..model_config.num_hidden_layers {
+        for layer in 0..model_config.num_hidden_layers {
+            // The caches of the layers offloaded to the CPU are in host memory
+            let device = &model_config.layer_device(layer, device);
             let key_blocks = try_api!(Tensor::zeros(
                 (
                     cache_config.num_gpu_blocks.unwrap(),
@@ -95,16 +287,13 @@ impl CacheEngine {
                 dtype,
                 device,
             ));
-            let value_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_gpu_blocks.unwrap(),
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
+            let value_blocks = Self::allocate_value_blocks(
+                model_config,
+                cache_config.num_gpu_blocks.unwrap(),
+                value_block_shape,
                 dtype,
                 device,
-            ));
+            )?;
             gpu_cache.push((key_blocks, value_blocks));
         }
         Ok(gpu_cache)
@@ -118,10 +307,18 @@ impl CacheEngine {
     ) -> Result<Vec<KVCache>, APIError> {
         assert!(cache_config.fully_init);
 
-        let key_block_shape =
-            Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
-        let value_block_shape =
-            Self::calculate_value_block_shape(model_config, cache_config.block_size);
+        let key_block_shape = Self::calculate_key_block_shape(
+            model_config,
+            dtype,
+            cache_config.block_size,
+            cache_config.tensor_parallel_size,
+        );
+        let value_block_shape = Self::calculate_value_block_shape(
+            model_config,
+            dtype,
+            cache_config.block_size,
+            cache_config.tensor_parallel_size,
+        );
         let mut cpu_cache = Vec::new();
         for _ in 0..model_config.num_hidden_layers {
             let key_blocks = try_api!(Tensor::zeros(
@@ -135,16 +332,13 @@ impl CacheEngine {
                 dtype,
                 device,
             ));
-            let value_blocks = try_api!(Tensor::zeros(
-                (
-                    cache_config.num_cpu_blocks.unwrap(),
-                    value_block_shape.0,
-                    value_block_shape.1,
-                    value_block_shape.2,
-                ),
+            let value_blocks = Self::allocate_value_blocks(
+                model_config,
+                cache_config.num_cpu_blocks.unwrap(),
+                value_block_shape,
                 dtype,
                 device,
-            ));
+            )?;
             cpu_cache.push((key_blocks, value_blocks));
         }
         Ok(cpu_cache)
@@ -152,34 +346,102 @@ impl CacheEngine {
 }
 
 impl CacheEngine {
+    fn allocate_value_blocks(
+        model_config: &Config,
+        num_blocks: usize,
+        value_block_shape: (usize, usize, usize),
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Tensor, APIError> {
+        let value_blocks = match KvCacheLayout::of(model_config) {
+            KvCacheLayout::Heads => Tensor::zeros(
+                (
+                    num_blocks,
+                    value_block_shape.0,
+                    value_block_shape.1,
+                    value_block_shape.2,
+                ),
+                dtype,
+                device,
+            ),
+            KvCacheLayout::Latent => Tensor::zeros(1, dtype, device),
+        };
+        Ok(try_api!(value_blocks))
+    }
+
     fn calculate_key_block_shape(
         model_config: &Config,
         dtype: DType,
         block_size: usize,
+        tensor_parallel_size: usize,
     ) -> (usize, usize, usize, usize) {
         let element_size = dtype.size_in_bytes();
         let x = 16 / element_size;
-        (
-            model_config.num_key_value_heads,
-            model_config.get_head_size() / x,
-            block_size,
-            x,
-        )
+        let (key_rows, _) = kv_cache_head_rows(model_config.get_head_size(), x, dtype);
+        let heads = Self::kv_heads_per_rank(model_config, tensor_parallel_size);
+        (heads, key_rows, block_size, x)
     }
 
     fn calculate_value_block_shape(
         model_config: &Config,
+        dtype: DType,
         block_size: usize,
+        tensor_parallel_size: usize,
     ) -> (usize, usize, usize) {
-        (
-            model_config.num_key_value_heads,
-            model_config.get_head_size(),
-            block_size,
-        )
+        let x = 16 / dtype.size_in_bytes();
+        let (_, value_rows) = kv_cache_head_rows(model_config.get_head_size(), x, dtype);
+        let heads = Self::kv_heads_per_rank(model_config, tensor_parallel_size);
+        (heads, value_rows, block_size)
     }
 }
 
 impl CacheEngine {
+    /// Cache the cross-attention keys and values of the encoder output of the prompt of
+    /// `seq_id`, computed once for all its decoding steps.
+    pub fn set_cross_attention(&mut self, seq_id: usize, cross: Vec<KVCache>) {
+        self.encoder_decoder.insert(
+            seq_id,
+            EncoderDecoderKv {
+                cross,
+                decoder: Vec::new(),
+            },
+        );
+    }
+
+    /// Take the keys and values of `seq_ids` for a decoding step, in that order. They are put
+    /// back with `put_encoder_decoder`.
+    pub fn take_encoder_decoder(
+        &mut self,
+        seq_ids: &[usize],
+    ) -> Result<Vec<EncoderDecoderKv>, APIError> {
+        if let Some(seq_id) = seq_ids
+            .iter()
+            .find(|seq_id| !self.encoder_decoder.contains_key(seq_id))
+        {
+            return Err(APIError::new(format!(
+                "Sequence {seq_id} has no encoder output."
+            )));
+        }
+        Ok(seq_ids
+            .iter()
+            .filter_map(|seq_id| self.encoder_decoder.remove(seq_id))
+            .collect())
+    }
+
+    pub fn put_encoder_decoder(&mut self, seq_ids: &[usize], kv: Vec<EncoderDecoderKv>) {
+        self.encoder_decoder.extend(seq_ids.iter().copied().zip(kv));
+    }
+
+    /// Drop the keys and values of the sequences for which `keep` is false.
+    pub fn retain_encoder_decoder(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        self.encoder_decoder.retain(|seq_id, _| keep(*seq_id));
+    }
+
+    /// Sequences of an encoder-decoder model with cached keys and values.
+    pub fn num_encoder_decoder_seqs(&self) -> usize {
+        self.encoder_decoder.len()
+    }
+
     pub fn swap_in(&self, src_to_dst: HashMap<usize, usize>) -> Result<(), APIError> {
         for i in 0..self.num_layers {
             let (src_key_cache, src_value_cache) = self.cpu_cache.get(i).unwrap();
@@ -191,12 +453,14 @@ impl CacheEngine {
                 dst_key_cache,
                 src_to_dst.clone()
             ));
-            // Swap (copy) key blocks
-            try_api!(swap_blocks(
-                src_value_cache.clone(),
-                dst_value_cache,
-                src_to_dst.clone()
-            ));
+            // Swap (copy) value blocks, latent caches have none
+            if self.layout == KvCacheLayout::Heads {
+                try_api!(swap_blocks(
+                    src_value_cache.clone(),
+                    dst_value_cache,
+                    src_to_dst.clone()
+                ));
+            }
         }
         Ok(())
     }
@@ -214,26 +478,112 @@ impl CacheEngine {
                 dst_key_cache,
                 src_to_dst.clone()
             ));
-            // Swap (copy) key blocks
-            try_api!(swap_blocks(
-                src_value_cache.clone(),
-                dst_value_cache,
-                src_to_dst.clone()
-            ));
+            // Swap (copy) value blocks, latent caches have none
+            if self.layout == KvCacheLayout::Heads {
+                try_api!(swap_blocks(
+                    src_value_cache.clone(),
+                    dst_value_cache,
+                    src_to_dst.clone()
+                ));
+            }
         }
         Ok(())
     }
 
     pub fn copy(&mut self, src_to_dst: HashMap<usize, Vec<usize>>) -> Result<(), APIError> {
         let mut gpu_cache = self.get_kv_cache();
-        #[allow(clippy::map_identity)]
-        let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
-            gpu_cache.iter_mut().map(|(a, b)| (a, b)).unzip();
-        let (key_caches, value_caches) = caches;
+        // The layers offloaded to the CPU come last, the kernels copy the layers of one device
+        let num_gpu_layers = gpu_cache
+            .iter()
+            .take_while(|(key_cache, _)| key_cache.device().same_device(gpu_cache[0].0.device()))
+            .count();
+        let (gpu_layers, cpu_layers) = gpu_cache.split_at_mut(num_gpu_layers);
+        for layers in [gpu_layers, cpu_layers] {
+            if layers.is_empty() {
+                continue;
+            }
+            #[allow(clippy::map_identity)]
+            let caches: (Vec<&mut Tensor>, Vec<&mut Tensor>) =
+                layers.iter_mut().map(|(a, b)| (a, b)).unzip();
+            let (key_caches, value_caches) = caches;
+
+            match self.layout {
+                // NOTE(EricLBuehler): This may synchronize the CPU and GPU
+                KvCacheLayout::Heads => {
+                    try_api!(unsafe { copy_blocks(key_caches, value_caches, src_to_dst.clone()) })
+                }
+                KvCacheLayout::Latent => {
+                    try_api!(copy_latent_blocks(key_caches, src_to_dst.clone()))
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-        // NOTE(EricLBuehler): This may synchronize the CPU and GPU
-        try_api!(unsafe { copy_blocks(key_caches, value_caches, src_to_dst) });
+    /// Rotate the rotary embedded keys cached in `blocks` of every layer by `cos` and `sin` (see
+    /// `Config::get_rope_rebase`), in place. Values do not depend on positions.
+    pub fn rebase_keys(
+        &self,
+        blocks: &[usize],
+        cos: &Tensor,
+        sin: &Tensor,
+        interleaved: bool,
+    ) -> Result<(), APIError> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let gpu_cache = self.get_kv_cache();
+        for (key_cache, _) in gpu_cache.iter() {
+            try_api!(Self::rotate_keys(key_cache, blocks, cos, sin, interleaved));
+        }
+        Ok(())
+    }
 
+    fn rotate_keys(
+        key_cache: &Tensor,
+        blocks: &[usize],
+        cos: &Tensor,
+        sin: &Tensor,
+        interleaved: bool,
+    ) -> candle_core::Result<()> {
+        let (_, num_heads, rows, block_size, x) = key_cache.dims5()?;
+        let (head_size, rotary_dim) = (rows * x, cos.dim(1)? * 2);
+        // The caches of offloaded layers are on the CPU
+        let (cos, sin) = (
+            cos.to_device(key_cache.device())?,
+            sin.to_device(key_cache.device())?,
+        );
+        let ids = blocks.iter().map(|&block| block as u32).collect::<Vec<_>>();
+        let ids = Tensor::from_vec(ids, blocks.len(), key_cache.device())?;
+        // (blocks, heads, head_size / x, block_size, x) -> (blocks, heads, block_size, head_size)
+        let keys = key_cache
+            .index_select(&ids, 0)?
+            .permute((0, 1, 3, 2, 4))?
+            .contiguous()?
+            .reshape((blocks.len(), num_heads, block_size, head_size))?
+            .to_dtype(DType::F32)?;
+        let rope = if interleaved {
+            candle_nn::rotary_emb::rope_i
+        } else {
+            candle_nn::rotary_emb::rope
+        };
+        let rotated = rope(
+            &keys.narrow(3, 0, rotary_dim)?.contiguous()?,
+            &cos.broadcast_as((block_size, rotary_dim / 2))?
+                .contiguous()?,
+            &sin.broadcast_as((block_size, rotary_dim / 2))?
+                .contiguous()?,
+        )?;
+        let pass = keys.narrow(3, rotary_dim, head_size - rotary_dim)?;
+        let keys = Tensor::cat(&[&rotated, &pass], 3)?
+            .to_dtype(key_cache.dtype())?
+            .reshape((blocks.len(), num_heads, block_size, rows, x))?
+            .permute((0, 1, 3, 2, 4))?
+            .contiguous()?;
+        for (i, &block) in blocks.iter().enumerate() {
+            key_cache.slice_set(&keys.narrow(0, i, 1)?, 0, block)?;
+        }
         Ok(())
     }
 }
@@ -2,6 +2,8 @@
 //! primary method `schedule` returns the batched sequences as inputs, as well as the
 //! operations to be executed on the cache by the CacheEngine.
 
+/// Startup defaults for the batch limits of the scheduler.
+pub mod batch_limits;
 /// The higher-level manager of the blocks allocated. Operations performed by the block engine do
 /// not directly change memory.
 pub mod block_engine;
@@ -10,6 +12,9 @@ pub mod block_engine;
 /// operations issued by the scheduler.
 pub mod cache_engine;
 pub mod sequence;
+/// The per-sequence states of recurrent models, which take the place of the KV cache. The
+/// scheduler admits their sequences by state slots rather than blocks.
+pub mod state_cache;
 
 type CPUBlockFrom = usize;
 type GPUBlockFrom = usize;
@@ -19,13 +24,20 @@ type SrcBlockFrom = usize;
 type DstBlocksTo = Vec<usize>;
 
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::Arc,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use crate::scheduler::{block_engine::AllocStatus, sequence::SequenceStatus};
 
-use self::{block_engine::BlockEngine, cache_engine::CacheConfig, sequence::SequenceGroup};
+use self::{
+    block_engine::BlockEngine,
+    cache_engine::CacheConfig,
+    sequence::{Sequence, SequenceGroup},
+};
 
 pub struct SchedulerOutput {
     pub scheduled: Arc<VecDeque<Arc<SequenceGroup>>>,
@@ -37,6 +49,49 @@ pub struct SchedulerOutput {
 
 pub struct SchedulerConfig {
     pub max_num_seqs: usize,
+    /// Maximum number of prompt tokens admitted from the waiting queue in one step. The first
+    /// waiting group is always admitted.
+    pub max_num_batched_tokens: usize,
+    pub kv_eviction: Option<HeavyHitterConfig>,
+    /// KV slots reserved for each running sequence beyond its pending token, for the tokens
+    /// proposed by speculative decoding (0 without speculation).
+    pub num_lookahead_slots: usize,
+    pub policy: SchedulingPolicy,
+}
+
+/// Order in which the scheduler admits, preempts and swaps in sequence groups.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchedulingPolicy {
+    /// First come, first served
+    #[default]
+    Fcfs,
+    /// Lowest `priority` first, first come first served within a priority. A waiting group
+    /// preempts running groups of a lower priority when the KV cache or the batch is full.
+    Priority,
+}
+
+/// How a running group was preempted to free GPU blocks for others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preemption {
+    /// Its KV blocks were moved to the CPU cache, to be swapped back in.
+    Swap,
+    /// Its KV blocks were freed, its prompt is prefilled again.
+    Recompute,
+}
+
+/// Keys of cached blocks to be rotated `delta` positions back (rope rebase of attention sinks).
+pub struct KeyRebase {
+    pub blocks: Vec<usize>,
+    pub delta: usize,
+}
+
+/// Experimental H2O-style cache compression: once a sequence caches more than `budget` tokens,
+/// the full blocks that received the least accumulated attention are evicted. The most recent
+/// `recent_window` tokens are never evicted.
+#[derive(Clone, Debug)]
+pub struct HeavyHitterConfig {
+    pub budget: usize,
+    pub recent_window: usize,
 }
 
 pub struct Scheduler {
@@ -45,6 +100,20 @@ pub struct Scheduler {
     swapped_out: VecDeque<Arc<SequenceGroup>>,
     config: SchedulerConfig,
     pub block_engine: BlockEngine,
+    /// Requests whose sequence group is kept with its KV blocks once finished, to be forked.
+    forkable: HashSet<String>,
+    /// Finished forkable groups, least recently used first.
+    retained: VecDeque<Arc<SequenceGroup>>,
+    /// Copy-on-write of the blocks of forked sequences, done with the next decoding step.
+    pending_copies: HashMap<SrcBlockFrom, DstBlocksTo>,
+    /// Groups waiting to be admitted or swapped back in, shared with the server so it can be
+    /// read without locking the engine.
+    queue_depth: Arc<AtomicUsize>,
+    /// Groups preempted since the last `take_preemptions`, by request id.
+    preemptions: Vec<(String, Preemption)>,
+    /// Sequences the engine keeps the state (recurrent models) or keys and values
+    /// (encoder-decoder models) of outside the paged KV cache, which then runs without KV blocks.
+    state_slots: Option<usize>,
 }
 
 impl Scheduler {
@@ -60,19 +129,68 @@ impl Scheduler {
                 cache_config.num_gpu_blocks.unwrap(),
                 cache_config.num_cpu_blocks.unwrap(),
             ),
+            forkable: HashSet::new(),
+            retained: VecDeque::new(),
+            pending_copies: HashMap::new(),
+            queue_depth: Arc::new(AtomicUsize::new(0)),
+            preemptions: Vec::new(),
+            state_slots: None,
         }
     }
 
+    /// Schedule the sequences of a recurrent model, whose fixed-size states are kept in a
+    /// `StateCache` of `num_slots` sequences: groups are admitted while their sequences have a
+    /// slot, no KV block is allocated, and running groups are never preempted since their
+    /// states do not grow. Encoder-decoder models are scheduled the same way: the prompt goes
+    /// through the encoder and not into KV blocks, and the keys and values the `CacheEngine`
+    /// keeps for a sequence are bounded by the context length.
+    pub fn use_state_slots(&mut self, num_slots: usize) {
+        self.state_slots = Some(num_slots);
+    }
+
+    /// The number of sequences the state cache holds, see [`Self::use_state_slots`].
+    pub fn state_slots(&self) -> Option<usize> {
+        self.state_slots
+    }
+
     pub fn add_sequence(&mut self, seq_group: SequenceGroup) {
         self.waiting.push_back(Arc::new(seq_group));
+        self.update_queue_depth();
+    }
+
+    pub fn queue_depth(&self) -> Arc<AtomicUsize> {
+        self.queue_depth.clone()
+    }
+
+    fn update_queue_depth(&self) {
+        self.queue_depth.store(
+            self.waiting.len() + self.swapped_out.len(),
+            Ordering::Relaxed,
+        );
     }
 
     pub fn schedule(&mut self) -> SchedulerOutput {
+        let mut output = self.schedule_groups();
+        self.update_queue_depth();
+        // Blocks freed by spilling a prefix may be reused right away: spilled blocks win over the
+        // swap-out of a group holding one of them, it has no data there yet.
+        let (prefix_swap_in, prefix_swap_out) = self.block_engine.take_prefix_swaps();
+        output.blocks_to_swap_in.extend(prefix_swap_in);
+        output.blocks_to_swap_out.extend(prefix_swap_out);
+        output
+    }
+
+    fn schedule_groups(&mut self) -> SchedulerOutput {
+        let mut blocks_to_swap_out = HashMap::new();
+        if self.config.policy == SchedulingPolicy::Priority {
+            self.sort_waiting_by_priority();
+        }
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
-        if self.swapped_out.is_empty() {
+        if self.swapped_out.is_empty() || self.waiting_outranks_swapped_out() {
             let mut scheduled = VecDeque::new();
             let mut ignored_seq_groups = VecDeque::new();
+            let mut batched_tokens = 0;
             while !self.waiting.is_empty() {
                 let seq_group = self.waiting.front().unwrap().clone();
 
@@ -84,27 +202,81 @@ impl Scheduler {
                         .map(|group| group.get_seqs().len())
                         .sum::<usize>()
                         + 1
+                {
+                    if self.preempt_lower_priority(&seq_group, &mut blocks_to_swap_out) {
+                        continue;
+                    }
+                    break;
+                }
+
+                let prompt_tokens = seq_group
+                    .get_seqs()
+                    .values()
+                    .map(|seq| seq.deref().get_prompt_len())
+                    .sum::<usize>();
+                if !scheduled.is_empty()
+                    && batched_tokens + prompt_tokens > self.config.max_num_batched_tokens
                 {
                     break;
                 }
 
                 // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
-                let can_allocate = self.block_engine.can_allocate(&seq_group);
+                if let Some(num_slots) = self.state_slots {
+                    let num_seqs = seq_group.get_seqs().len();
+                    let used_slots = self
+                        .running
+                        .iter()
+                        .map(|group| group.get_seqs().len())
+                        .sum::<usize>();
+                    if num_seqs > num_slots {
+                        tracing::warn!(
+                            request_id = %seq_group.request_id,
+                            num_seqs,
+                            "Request has more sequences than the state cache holds."
+                        );
+                        seq_group.set_status(SequenceStatus::FinishedIgnored);
+                        ignored_seq_groups.push_back(self.waiting.pop_front().unwrap());
+                        continue;
+                    }
+                    if used_slots + num_seqs > num_slots {
+                        break;
+                    }
+                    seq_group.set_status(SequenceStatus::Running);
+                    seq_group.set_phase(Some("generation"));
+                    batched_tokens += prompt_tokens;
+                    let seq_group = self.waiting.pop_front().unwrap();
+                    self.running.push_back(seq_group.clone());
+                    scheduled.push_back(seq_group);
+                    continue;
+                }
+                let mut can_allocate = self.block_engine.can_allocate(&seq_group);
+                // Cached prefixes and retained groups only hold on to otherwise free blocks, drop
+                // the least recently used ones before making the group wait.
+                while matches!(can_allocate, AllocStatus::Later)
+                    && (self.release_cached_blocks()
+                        || self.preempt_lower_priority(&seq_group, &mut blocks_to_swap_out))
+                {
+                    can_allocate = self.block_engine.can_allocate(&seq_group);
+                }
                 match can_allocate {
                     AllocStatus::Later => break, //If we can only allocate later, do not bother iterating over the rest.
                     AllocStatus::Impossible => {
-                        println!(
-                            "Input prompt with length of {} tokens is too long and exceeds capacity of block engine.",
-                            seq_group.get_prompt_len()
+                        tracing::warn!(
+                            request_id = %seq_group.request_id,
+                            prompt_tokens = seq_group.get_prompt_len(),
+                            "Input prompt is too long and exceeds capacity of block engine."
                         );
                         seq_group.set_status(SequenceStatus::FinishedIgnored);
                         ignored_seq_groups.push_back(self.waiting.pop_front().unwrap());
+                        continue;
                     }
                     _ => {}
                 }
 
                 seq_group.set_status(SequenceStatus::Running);
+                seq_group.set_phase(Some("generation"));
                 self._allocate(&seq_group);
+                batched_tokens += prompt_tokens;
 
                 let seq_group = self.waiting.pop_front().unwrap();
                 self.running.push_back(seq_group.clone());
@@ -117,15 +289,14 @@ impl Scheduler {
                     scheduled: Arc::new(scheduled),
                     blocks_to_swap_in: HashMap::new(),
                     blocks_to_copy: HashMap::new(),
-                    blocks_to_swap_out: HashMap::new(),
+                    blocks_to_swap_out,
                     ignored_seq_groups: Arc::new(ignored_seq_groups),
                 };
             }
         }
 
-        let mut blocks_to_swap_out = HashMap::new();
         let mut blocks_to_swap_in = HashMap::new();
-        let mut blocks_to_copy = HashMap::new();
+        let mut blocks_to_copy = std::mem::take(&mut self.pending_copies);
 
         // Reserve token slots for the running sequence groups, preempting the lowest (earliest) first.
         // Preempt lowest priority sequences that are in the running queue, forming a
@@ -141,7 +312,11 @@ impl Scheduler {
         while !self.running.is_empty() {
             let seq_group = self.running.pop_front().unwrap();
             let mut finished_with_break = false;
-            while !self.block_engine.can_append_token_to_seq(&seq_group) {
+            while !self.can_append_slots(&seq_group) {
+                // Release cached prefixes and retained groups before preempting anything
+                if self.release_cached_blocks() {
+                    continue;
+                }
                 // If we cannot, now we need to preempt some seqs
                 if !self.running.is_empty() {
                     // There is something to preempt.
@@ -176,7 +351,9 @@ impl Scheduler {
                 let seq_group = self.swapped_out.front().unwrap();
 
                 // If the GPU cannot handle the group being swapped in, stop
-                if !self.block_engine.can_swap_in_seq_group(seq_group) {
+                if !self.block_engine.can_swap_in_seq_group(seq_group)
+                    || (self.config.num_lookahead_slots > 0 && !self.can_append_slots(seq_group))
+                {
                     break;
                 }
 
@@ -184,6 +361,7 @@ impl Scheduler {
                 // Swap in the blocks
                 let to_swap_in = self.block_engine.swap_in(&seq_group);
                 blocks_to_swap_in.extend(to_swap_in);
+                seq_group.set_status(SequenceStatus::Running);
                 // Reserve a new slot
                 self._append_token_slot_to_seq_group(&seq_group, &mut blocks_to_copy);
                 self.running.push_back(seq_group);
@@ -199,8 +377,232 @@ impl Scheduler {
         }
     }
 
+    /// The numbers of `(running, waiting, swapped out)` groups.
+    pub fn queue_sizes(&self) -> (usize, usize, usize) {
+        (
+            self.running.len(),
+            self.waiting.len(),
+            self.swapped_out.len(),
+        )
+    }
+
+    /// Ids of the sequences of the running groups.
+    pub fn running_seq_ids(&self) -> HashSet<usize> {
+        self.running
+            .iter()
+            .flat_map(|group| group.get_seqs().keys().copied())
+            .collect()
+    }
+
+    /// The waiting, running and swapped out groups.
+    pub fn unfinished_groups(&self) -> VecDeque<Arc<SequenceGroup>> {
+        self.waiting
+            .iter()
+            .chain(&self.running)
+            .chain(&self.swapped_out)
+            .cloned()
+            .collect()
+    }
+
     pub fn has_unfinished_sequences(&self) -> bool {
-        !self.running.is_empty() || !self.waiting.is_empty()
+        !self.running.is_empty() || !self.waiting.is_empty() || !self.swapped_out.is_empty()
+    }
+
+    /// Shrink the block tables of running sequences that exceed the heavy-hitter budget.
+    pub fn evict_heavy_hitters(&mut self, block_size: usize) {
+        let Some(config) = self.config.kv_eviction.clone() else {
+            return;
+        };
+        for group in self.running.iter() {
+            if group.is_finished() {
+                continue;
+            }
+            for seq in group.get_seqs().values() {
+                let cached_len = seq.deref().get_cached_len();
+                if cached_len <= config.budget {
+                    continue;
+                }
+                let num_to_evict = (cached_len - config.budget).div_ceil(block_size);
+                // Blocks overlapping the recent window (and the slot of the pending token) are protected.
+                let first_protected =
+                    (cached_len - 1).saturating_sub(config.recent_window) / block_size;
+                let mut candidates = seq
+                    .deref()
+                    .get_full_block_scores()
+                    .into_iter()
+                    .filter(|(idx, _)| *idx < first_protected)
+                    .collect::<Vec<_>>();
+                candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+                let to_evict = candidates
+                    .iter()
+                    .take(num_to_evict)
+                    .map(|(idx, _)| *idx)
+                    .collect::<Vec<_>>();
+                if to_evict.is_empty() {
+                    continue;
+                }
+                self.block_engine.evict_blocks(seq, &to_evict);
+                seq.deref_mut().evict_blocks(&to_evict);
+            }
+        }
+    }
+
+    /// Free the blocks of running sequences that fell out of the attention window of a sliding
+    /// window model, which bounds their cache by the window. Positions are left as they are.
+    pub fn evict_beyond_window(&mut self, block_size: usize, sliding_window: usize) {
+        for group in self.running.iter() {
+            if group.is_finished() {
+                continue;
+            }
+            for seq in group.get_seqs().values() {
+                let cached_len = seq.deref().get_cached_len();
+                // Blocks overlapping the window of the pending token are kept.
+                let first_protected = (cached_len - 1).saturating_sub(sliding_window) / block_size;
+                if first_protected == 0 {
+                    continue;
+                }
+                let to_evict = (0..first_protected).collect::<Vec<_>>();
+                self.block_engine.evict_blocks(seq, &to_evict);
+                seq.deref_mut().evict_blocks(&to_evict);
+            }
+        }
+    }
+
+    /// Evict the oldest blocks after the sink tokens of running sequences with attention sinks
+    /// that exceed their window. Once the evicted positions add up to a window, the positions of
+    /// the blocks after the sinks are compacted: returns the keys to re-rotate.
+    pub fn evict_beyond_sinks(&mut self, block_size: usize) -> Vec<KeyRebase> {
+        let mut rebases = Vec::new();
+        for group in self.running.iter() {
+            let Some(sinks) = group.attention_sinks else {
+                continue;
+            };
+            if group.is_finished() {
+                continue;
+            }
+            let sink_blocks = sinks.num_sink_tokens.div_ceil(block_size);
+            for seq in group.get_seqs().values() {
+                let cached_len = seq.deref().get_cached_len();
+                let budget = sink_blocks * block_size + sinks.window;
+                if cached_len > budget {
+                    // Blocks overlapping the window (and the slot of the pending token) are kept.
+                    let first_protected =
+                        (cached_len - 1).saturating_sub(sinks.window) / block_size;
+                    let num_to_evict = (cached_len - budget).div_ceil(block_size);
+                    let to_evict = (sink_blocks..first_protected)
+                        .take(num_to_evict)
+                        .collect::<Vec<_>>();
+                    if !to_evict.is_empty() {
+                        self.block_engine.evict_blocks(seq, &to_evict);
+                        seq.deref_mut().evict_blocks(&to_evict);
+                    }
+                }
+                let delta = seq.deref().get_rebase_gap();
+                if delta == 0 || delta < sinks.window {
+                    continue;
+                }
+                let table = &self.block_engine.block_tables[&seq.deref().get_id()];
+                rebases.push(KeyRebase {
+                    blocks: table[sink_blocks.min(table.len())..]
+                        .iter()
+                        .map(|block| block.deref_mut().block_id)
+                        .collect(),
+                    delta,
+                });
+                seq.deref_mut().rebase_positions(delta);
+            }
+        }
+        rebases
+    }
+
+    /// Abort the sequence group of a request wherever it is queued, releasing its blocks.
+    /// Returns the aborted group, `None` if the request is not (or no longer) scheduled.
+    pub fn abort_request(&mut self, request_id: &str) -> Option<Arc<SequenceGroup>> {
+        if let Some(idx) = self
+            .waiting
+            .iter()
+            .position(|group| group.request_id == request_id)
+        {
+            // Waiting groups hold no blocks.
+            let seq_group = self.waiting.remove(idx).unwrap();
+            seq_group.set_status(SequenceStatus::FinishedAborted);
+            self.update_queue_depth();
+            return Some(seq_group);
+        }
+        let seq_group = self
+            .running
+            .iter()
+            .chain(self.swapped_out.iter())
+            .find(|group| group.request_id == request_id)
+            .cloned();
+        if let Some(seq_group) = &seq_group {
+            self._abort_seq_group(seq_group);
+        }
+        seq_group
+    }
+
+    pub fn num_lookahead_slots(&self) -> usize {
+        self.config.num_lookahead_slots
+    }
+
+    /// Release the lookahead blocks that were not filled during the last step, so that they
+    /// do not count against other sequences under memory pressure.
+    pub fn release_lookahead_slots(&mut self) {
+        if self.config.num_lookahead_slots == 0 {
+            return;
+        }
+        for group in self.running.iter() {
+            for seq in group.get_seqs().values() {
+                self.block_engine.rollback_slots(seq);
+            }
+        }
+    }
+
+    /// Keep the sequence group of the request, with its KV blocks, once it finishes so that it can
+    /// be forked.
+    /// The groups preempted since the previous call, by request id.
+    pub fn take_preemptions(&mut self) -> Vec<(String, Preemption)> {
+        std::mem::take(&mut self.preemptions)
+    }
+
+    pub fn set_forkable(&mut self, request_id: String) {
+        self.forkable.insert(request_id);
+    }
+
+    /// The finished group of a forkable request, if its blocks were not released meanwhile.
+    pub fn get_retained(&mut self, request_id: &str) -> Option<Arc<SequenceGroup>> {
+        let idx = self
+            .retained
+            .iter()
+            .position(|group| group.request_id == request_id)?;
+        // Most recently used last
+        let group = self.retained.remove(idx).unwrap();
+        self.retained.push_back(group.clone());
+        Some(group)
+    }
+
+    /// Start a branch of a retained sequence. The single sequence of `seq_group` repeats the
+    /// first tokens of `parent` and shares their KV blocks. Returns false if the GPU lacks the
+    /// blocks for the branch.
+    pub fn fork(&mut self, parent: &Sequence, seq_group: SequenceGroup) -> bool {
+        let child = seq_group.get_seqs().values().next().unwrap().clone();
+        let mut can_fork = self.block_engine.can_fork(&child);
+        while !can_fork && self.block_engine.evict_lru_prefix() {
+            can_fork = self.block_engine.can_fork(&child);
+        }
+        if !can_fork {
+            return false;
+        }
+        if let Some((src_block, dst_block)) = self.block_engine.fork(parent, &child) {
+            self.pending_copies
+                .entry(src_block)
+                .or_default()
+                .push(dst_block);
+        }
+        seq_group.set_status(SequenceStatus::Running);
+        seq_group.set_phase(Some("generation"));
+        self.running.push_back(Arc::new(seq_group));
+        true
     }
 
     pub fn free_finished_sequence_groups(&mut self) {
@@ -219,7 +621,20 @@ impl Scheduler {
             .cloned()
             .collect::<VecDeque<_>>();
         for group in to_free {
-            self._free(&group);
+            group.set_phase(None);
+            if let Some(session_id) = &group.session_id {
+                let seqs = group.get_seqs();
+                if seqs.len() == 1 {
+                    let seq = seqs.values().next().unwrap();
+                    self.block_engine
+                        .save_session(session_id, seq, group.cache_priority);
+                }
+            }
+            if self.forkable.remove(&group.request_id) {
+                self.retained.push_back(group);
+            } else {
+                self._free(&group);
+            }
         }
     }
 }
@@ -251,13 +666,33 @@ impl Scheduler {
             self.swapped_out.remove(idx);
         };
     }
+    fn can_append_slots(&self, seq_group: &SequenceGroup) -> bool {
+        if self.state_slots.is_some() {
+            return true;
+        }
+        match self.config.num_lookahead_slots {
+            0 => self.block_engine.can_append_token_to_seq(seq_group),
+            num_lookahead_slots => self
+                .block_engine
+                .can_reserve_slots(seq_group, num_lookahead_slots),
+        }
+    }
+
     fn _append_token_slot_to_seq_group(
         &mut self,
         seq_group: &SequenceGroup,
         blocks_to_copy: &mut HashMap<usize, Vec<usize>>,
     ) {
+        if self.state_slots.is_some() {
+            return;
+        }
         for seq in seq_group.get_seqs().values() {
-            let op = self.block_engine.append_token_slot_to_seq(seq);
+            let op = match self.config.num_lookahead_slots {
+                0 => self.block_engine.append_token_slot_to_seq(seq),
+                num_lookahead_slots => self
+                    .block_engine
+                    .reserve_slots_for_seq(seq, num_lookahead_slots),
+            };
             if let Some((src_block, dst_block)) = op {
                 if let std::collections::hash_map::Entry::Vacant(e) =
                     blocks_to_copy.entry(src_block)
@@ -271,27 +706,56 @@ impl Scheduler {
     }
 
     fn _abort_seq_group(&mut self, seq_group: &SequenceGroup) {
+        self.forkable.remove(&seq_group.request_id);
         self.remove_seq_group(seq_group);
         seq_group.set_status(SequenceStatus::FinishedAborted);
         self._free(seq_group);
     }
 
-    /// Preempt either by recomputation (for single sequence), or by swapping (for multiple).
+    /// Preempt by swapping the blocks out to the CPU cache, which keeps the generation state
+    /// until the group is swapped back in. If the CPU cache is full, groups that did not generate
+    /// yet are recomputed from their prompt and the others are aborted.
     fn _preempt(
         &mut self,
         seq_group: Arc<SequenceGroup>,
         blocks_to_swap_out: &mut HashMap<usize, usize>,
     ) {
-        match seq_group.get_seqs().len() {
-            1 => self._preempt_by_recompute(seq_group),
-            _ => self._preempt_by_swap(seq_group, blocks_to_swap_out),
+        // Cached prefixes on the CPU are dropped to make room for the group
+        while !self.block_engine.can_swap_out_seq_group(&seq_group)
+            && self.block_engine.evict_lru_cpu_prefix()
+        {}
+        if self.block_engine.can_swap_out_seq_group(&seq_group) {
+            self._preempt_by_swap(seq_group, blocks_to_swap_out)
+        } else if seq_group
+            .get_seqs()
+            .values()
+            .all(|seq| seq.deref().is_prompt())
+        {
+            self._preempt_by_recompute(seq_group)
+        } else {
+            tracing::warn!(
+                request_id = %seq_group.request_id,
+                event = "aborted",
+                reason = "no_cpu_cache",
+                "Request aborted, no CPU cache left to swap it out."
+            );
+            self._abort_seq_group(&seq_group);
         }
     }
 
     fn _preempt_by_recompute(&mut self, seq_group: Arc<SequenceGroup>) {
+        self.preemptions
+            .push((seq_group.request_id.clone(), Preemption::Recompute));
         seq_group.set_status(SequenceStatus::Waiting);
+        seq_group.set_phase(Some("queue"));
         self._free(&seq_group);
+        for seq in seq_group.get_seqs().values() {
+            seq.deref_mut().reset_evictions();
+        }
         self.waiting.push_front(seq_group);
+        if self.config.policy == SchedulingPolicy::Priority {
+            self.sort_waiting_by_priority();
+        }
     }
 
     fn _preempt_by_swap(
@@ -299,11 +763,8 @@ impl Scheduler {
         seq_group: Arc<SequenceGroup>,
         blocks_to_swap_out: &mut HashMap<usize, usize>,
     ) {
-        if !self.block_engine.can_swap_out_seq_group(&seq_group) {
-            // If we cannot swap it out, abort the sequence group.
-            self._abort_seq_group(&seq_group);
-            return;
-        }
+        self.preemptions
+            .push((seq_group.request_id.clone(), Preemption::Swap));
         let new_to_swap = self.block_engine.swap_out(&seq_group);
         blocks_to_swap_out.extend(new_to_swap);
         seq_group.set_status(SequenceStatus::Swapped);
@@ -311,17 +772,98 @@ impl Scheduler {
         self.swapped_out.push_back(seq_group);
     }
 
+    /// Under the priority policy, whether the first waiting group ranks above every swapped out
+    /// group, which are otherwise swapped in before anything is admitted.
+    fn waiting_outranks_swapped_out(&self) -> bool {
+        if self.config.policy != SchedulingPolicy::Priority {
+            return false;
+        }
+        match (
+            self.waiting.front(),
+            self.swapped_out.iter().map(|group| group.priority).min(),
+        ) {
+            (Some(waiting), Some(swapped_out)) => waiting.priority < swapped_out,
+            _ => false,
+        }
+    }
+
+    /// Under the priority policy, preempt the running group of the lowest priority (the latest
+    /// to arrive among equals) if it ranks below `seq_group`. Returns false if there is none.
+    fn preempt_lower_priority(
+        &mut self,
+        seq_group: &SequenceGroup,
+        blocks_to_swap_out: &mut HashMap<usize, usize>,
+    ) -> bool {
+        // The states of recurrent and encoder-decoder sequences cannot be swapped out
+        if self.config.policy != SchedulingPolicy::Priority || self.state_slots.is_some() {
+            return false;
+        }
+        let Some(idx) = self
+            .running
+            .iter()
+            .enumerate()
+            .filter(|(_, running)| running.priority > seq_group.priority)
+            .max_by_key(|(_, running)| (running.priority, running.arrival_time()))
+            .map(|(idx, _)| idx)
+        else {
+            return false;
+        };
+        let preempted = self.running.remove(idx).unwrap();
+        tracing::info!(
+            request_id = %preempted.request_id,
+            by = %seq_group.request_id,
+            "Request preempted by a higher priority request."
+        );
+        self._preempt(preempted, blocks_to_swap_out);
+        true
+    }
+
+    /// Release blocks that are only kept for reuse: cached prefixes first, then the least recently
+    /// used retained group. Returns false if there is nothing to release.
+    fn release_cached_blocks(&mut self) -> bool {
+        if self.block_engine.evict_lru_prefix() {
+            return true;
+        }
+        match self.retained.pop_front() {
+            Some(seq_group) => {
+                self._free(&seq_group);
+                true
+            }
+            None => false,
+        }
+    }
+
     fn _allocate(&mut self, seq_group: &SequenceGroup) {
         self.block_engine.allocate(seq_group)
     }
 
     fn _free(&mut self, seq_group: &SequenceGroup) {
+        // The engine releases the states of recurrent and encoder-decoder sequences
+        if self.state_slots.is_some() {
+            for seq in seq_group.get_seqs().values() {
+                self.block_engine.discard_pending_prefix(seq);
+            }
+            return;
+        }
         for seq in seq_group.get_seqs().values() {
             self.block_engine.free_sequence(seq);
         }
     }
 
+    fn sort_waiting_by_priority(&mut self) {
+        self.waiting
+            .make_contiguous()
+            .sort_by_key(|seq_group| (seq_group.priority, seq_group.arrival_time()));
+    }
+
     fn sort_running_by_priority_fcfs(&mut self) {
+        // Highest priority first, so the lowest is preempted first
+        if self.config.policy == SchedulingPolicy::Priority {
+            self.running
+                .make_contiguous()
+                .sort_by_key(|seq_group| (seq_group.priority, seq_group.arrival_time()));
+            return;
+        }
         self.running
             .make_contiguous()
             .sort_by_key(|seq_group| seq_group.arrival_time());
@@ -329,6 +871,13 @@ impl Scheduler {
     }
 
     fn sort_swapped_out_by_priority_fcfs(&mut self) {
+        // Highest priority swapped in first
+        if self.config.policy == SchedulingPolicy::Priority {
+            self.swapped_out
+                .make_contiguous()
+                .sort_by_key(|seq_group| (seq_group.priority, seq_group.arrival_time()));
+            return;
+        }
         self.swapped_out
             .make_contiguous()
             .sort_by_key(|seq_group| seq_group.arrival_time());
@@ -1,13 +1,17 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    iter::zip,
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
 use super::block_engine::LogicalTokenBlock;
+use crate::openai::guided_decoding::GuideState;
+use crate::openai::requests::{AttentionSinks, CachePriority, StreamOptions};
 use crate::openai::sampling_params::{Logprobs, SamplingParams};
-use crate::openai::streaming::ChatResponse;
+use crate::openai::streaming::{ChatResponse, StopBuffer};
 use flume::Sender;
-use std::time::SystemTime;
+use rand::{rngs::StdRng, SeedableRng};
+use std::time::{Instant, SystemTime};
 #[derive(Clone)]
 pub enum SequenceStatus {
     FinishedIgnored,
@@ -56,6 +60,19 @@ pub struct _Sequence {
     seq_id: usize,
     logical_token_blocks: Vec<LogicalTokenBlock>,
     block_size: usize,
+    /// Accumulated attention received by each cached token (heavy-hitter eviction).
+    token_scores: Vec<f32>,
+    /// Number of tokens whose KV entries were evicted from the cache.
+    num_evicted_tokens: usize,
+    /// Positions removed from the cached keys by rope rebases (attention sinks), new tokens are
+    /// placed that many positions earlier.
+    position_offset: usize,
+    /// State of the guided decoding automaton after the generated tokens.
+    guided_state: Option<GuideState>,
+    /// Number of leading prompt tokens whose KV entries are shared from the prefix cache.
+    prefix_cached_len: usize,
+    /// Number of leading output tokens taken over from the sequence this one was forked from.
+    num_inherited_tokens: usize,
 }
 
 impl _Sequence {
@@ -65,6 +82,12 @@ impl _Sequence {
             seq_id,
             logical_token_blocks: Vec::new(),
             block_size,
+            token_scores: vec![0f32; prompt_token_ids.len()],
+            num_evicted_tokens: 0,
+            position_offset: 0,
+            guided_state: None,
+            prefix_cached_len: 0,
+            num_inherited_tokens: 0,
         };
         this.append_tokens_to_blocks(prompt_token_ids);
         this
@@ -72,9 +95,102 @@ impl _Sequence {
 
     pub fn add_token(&mut self, logprobs: Logprobs) {
         self.append_token_to_blocks(logprobs.token);
+        self.token_scores.push(0f32);
         self.deref_mut().append_token_id(logprobs);
     }
 
+    /// Number of tokens that still have KV entries in the cache.
+    pub fn get_cached_len(&self) -> usize {
+        self.get_len() - self.num_evicted_tokens
+    }
+
+    /// Rotary position of the last token.
+    pub fn get_last_position(&self) -> usize {
+        self.get_len() - 1 - self.position_offset
+    }
+
+    /// Evicted tokens whose positions were not removed by a rope rebase yet.
+    pub fn get_rebase_gap(&self) -> usize {
+        self.num_evicted_tokens - self.position_offset
+    }
+
+    /// Record that the cached keys after the sink tokens were moved `delta` positions earlier.
+    pub fn rebase_positions(&mut self, delta: usize) {
+        assert!(delta <= self.get_rebase_gap());
+        self.position_offset += delta;
+    }
+
+    pub fn accumulate_token_scores(&mut self, scores: &[f32]) {
+        for (acc, score) in zip(self.token_scores.iter_mut(), scores) {
+            *acc += *score;
+        }
+    }
+
+    /// (block index, accumulated score) of each logical block that is completely filled.
+    pub fn get_full_block_scores(&self) -> Vec<(usize, f32)> {
+        self.logical_token_blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.is_full())
+            .map(|(idx, _)| {
+                let score = self.token_scores[idx * self.block_size..(idx + 1) * self.block_size]
+                    .iter()
+                    .sum();
+                (idx, score)
+            })
+            .collect()
+    }
+
+    /// Drop the given (full) logical blocks, their tokens no longer take part in attention.
+    pub fn evict_blocks(&mut self, block_indices: &[usize]) {
+        let mut block_indices = block_indices.to_vec();
+        block_indices.sort_unstable_by(|a, b| b.cmp(a));
+        for idx in block_indices {
+            assert!(self.logical_token_blocks[idx].is_full());
+            self.logical_token_blocks.remove(idx);
+            self.token_scores
+                .drain(idx * self.block_size..(idx + 1) * self.block_size);
+            self.num_evicted_tokens += self.block_size;
+        }
+    }
+
+    pub fn get_guided_state(&self) -> Option<GuideState> {
+        self.guided_state.clone()
+    }
+
+    pub fn set_guided_state(&mut self, state: Option<GuideState>) {
+        self.guided_state = state;
+    }
+
+    pub fn get_prefix_cached_len(&self) -> usize {
+        self.prefix_cached_len
+    }
+
+    pub fn set_prefix_cached_len(&mut self, len: usize) {
+        self.prefix_cached_len = len;
+    }
+
+    pub fn get_num_inherited_tokens(&self) -> usize {
+        self.num_inherited_tokens
+    }
+
+    pub fn set_num_inherited_tokens(&mut self, num_tokens: usize) {
+        self.num_inherited_tokens = num_tokens;
+    }
+
+    /// Restore the full logical layout, used when the sequence is recomputed from scratch.
+    pub fn reset_evictions(&mut self) {
+        if self.num_evicted_tokens == 0 {
+            return;
+        }
+        let token_ids = self.get_token_ids();
+        self.logical_token_blocks.clear();
+        self.token_scores = vec![0f32; token_ids.len()];
+        self.num_evicted_tokens = 0;
+        self.position_offset = 0;
+        self.append_tokens_to_blocks(token_ids);
+    }
+
     pub fn blocks_to_add_new_tok(&self) -> usize {
         let last = self.logical_token_blocks.last();
         if !last.is_some_and(|last| last.is_full() || last.is_empty()) {
@@ -135,6 +251,10 @@ impl _Sequence {
         )
     }
 
+    pub fn is_aborted(&self) -> bool {
+        matches!(self.deref().status, SequenceStatus::FinishedAborted)
+    }
+
     pub fn get_cumulative_logprob(&self) -> f32 {
         self.deref().get_cumulative_logprob()
     }
@@ -239,6 +359,37 @@ pub struct SequenceGroup {
     pub sampling_params: SamplingParams,
     pub use_logprobs: bool,
     pub sender: Option<Sender<ChatResponse>>,
+    pub stream_options: StreamOptions,
+    /// Number of prompt tokens of the template-rendered system and tools prefix, shared with
+    /// other requests through the prefix cache.
+    pub prefix_len: usize,
+    /// Attention sinks of the request, its KV cache is bounded to the sinks and a recent window.
+    pub attention_sinks: Option<AttentionSinks>,
+    /// End user of the request (the OpenAI `user` field), for logs and metrics.
+    pub user: Option<String>,
+    /// Scheduling priority, lower values first (see `SchedulingPolicy::Priority`).
+    pub priority: i32,
+    /// Eviction hint for the prefix this group shares through the prefix cache.
+    pub cache_priority: CachePriority,
+    /// Session whose KV snapshot the prompt starts from, replaced by the KV of this group once
+    /// it finishes.
+    pub session_id: Option<String>,
+    /// Generator of the sequences of a request with a `seed`, which do not share the generator
+    /// of the pipeline.
+    rng: Option<Mutex<StdRng>>,
+    /// Span of the current lifecycle phase (`queue` or `generation`), closed when the next one
+    /// starts so its duration is logged.
+    phase_span: Mutex<PhaseSpan>,
+    /// GPU energy attributed to the request in joules, with `--energy-telemetry`.
+    energy: Mutex<f64>,
+    /// Stop strings of the request and the streamed text held back while matching them.
+    stop_buffer: Mutex<StopBuffer>,
+}
+
+struct PhaseSpan {
+    phase: Option<&'static str>,
+    span: tracing::Span,
+    started: Instant,
 }
 
 impl SequenceGroup {
@@ -252,11 +403,18 @@ impl SequenceGroup {
         sampling_params: SamplingParams,
         use_logprobs: bool,
         sender: Option<Sender<ChatResponse>>,
+        stream_options: StreamOptions,
+        prefix_len: usize,
+        user: Option<String>,
     ) -> Self {
         let mut seq_map = HashMap::new();
         for seq in seqs {
             seq_map.insert(seq.deref_mut().get_id(), seq.clone());
         }
+        let rng = sampling_params
+            .seed
+            .map(|seed| Mutex::new(StdRng::seed_from_u64(seed)));
+        let stop_buffer = Mutex::new(StopBuffer::new(sampling_params.stop.as_ref()));
         Self {
             seqs: seq_map,
             arrival_time,
@@ -266,7 +424,101 @@ impl SequenceGroup {
             sampling_params,
             use_logprobs,
             sender,
+            stream_options,
+            prefix_len,
+            attention_sinks: None,
+            user,
+            priority: 0,
+            cache_priority: CachePriority::Normal,
+            session_id: None,
+            rng,
+            phase_span: Mutex::new(PhaseSpan {
+                phase: None,
+                span: tracing::Span::none(),
+                started: Instant::now(),
+            }),
+            energy: Mutex::new(0.0),
+            stop_buffer,
         }
+        .with_phase(Some("queue"))
+    }
+
+    pub fn with_attention_sinks(mut self, attention_sinks: Option<AttentionSinks>) -> Self {
+        self.attention_sinks = attention_sinks;
+        self
+    }
+
+    pub fn add_energy(&self, joules: f64) {
+        *self.energy.lock().unwrap() += joules;
+    }
+
+    pub fn energy(&self) -> f64 {
+        *self.energy.lock().unwrap()
+    }
+
+    pub fn rng(&self) -> Option<&Mutex<StdRng>> {
+        self.rng.as_ref()
+    }
+
+    pub fn stop_buffer(&self) -> &Mutex<StopBuffer> {
+        &self.stop_buffer
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_cache_priority(mut self, cache_priority: CachePriority) -> Self {
+        self.cache_priority = cache_priority;
+        self
+    }
+
+    pub fn with_session_id(mut self, session_id: Option<String>) -> Self {
+        self.session_id = session_id;
+        self
+    }
+
+    fn with_phase(self, phase: Option<&'static str>) -> Self {
+        self.set_phase(phase);
+        self
+    }
+
+    /// Close the span of the current lifecycle phase and open one for `phase`, `None` once the
+    /// request finished. Moving between the queue and generation is logged as a lifecycle event.
+    pub fn set_phase(&self, phase: Option<&'static str>) {
+        let mut current = self.phase_span.lock().unwrap();
+        let duration_ms = current.started.elapsed().as_millis() as u64;
+        match (current.phase, phase) {
+            (Some("queue"), Some("generation")) => tracing::info!(
+                request_id = %self.request_id,
+                user = self.user.as_deref(),
+                event = "scheduled",
+                queue_ms = duration_ms,
+                "Request scheduled after {duration_ms} ms in the queue."
+            ),
+            (Some("generation"), Some("queue")) => tracing::info!(
+                request_id = %self.request_id,
+                user = self.user.as_deref(),
+                event = "requeued",
+                "Request preempted, it is queued to be recomputed."
+            ),
+            _ => {}
+        }
+        let span = match phase {
+            Some(phase) => tracing::info_span!(
+                "phase",
+                request_id = %self.request_id,
+                user = self.user.as_deref(),
+                phase
+            ),
+            None => tracing::Span::none(),
+        };
+        *current = PhaseSpan {
+            phase,
+            span,
+            started: Instant::now(),
+        };
     }
 
     pub fn set_status(&self, status: SequenceStatus) {
@@ -316,6 +568,10 @@ impl SequenceGroup {
         self.seqs.iter().all(|(_, x)| x.deref().is_finished())
     }
 
+    pub fn is_aborted(&self) -> bool {
+        self.seqs.values().any(|seq| seq.is_aborted())
+    }
+
     pub fn get_request_id(&self) -> &String {
         &self.request_id
     }
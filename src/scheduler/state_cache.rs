@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use candle_core::Tensor;
+
+use crate::{openai::responses::APIError, try_api};
+
+/// The states of the sequences of a recurrent model (Mamba2), which replace the paged KV cache:
+/// each sequence holds a fixed-size set of tensors, allocated with its prefill and advanced in
+/// place by every forward pass. Batches gather the states of their sequences along the first
+/// dimension and scatter them back once the forward pass is done.
+pub struct StateCache {
+    states: HashMap<usize, Vec<Tensor>>,
+    num_slots: usize,
+}
+
+impl StateCache {
+    /// A cache holding the states of up to `num_slots` sequences.
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            states: HashMap::new(),
+            num_slots,
+        }
+    }
+
+    pub fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    pub fn num_free_slots(&self) -> usize {
+        self.num_slots - self.states.len()
+    }
+
+    pub fn contains(&self, seq_id: usize) -> bool {
+        self.states.contains_key(&seq_id)
+    }
+
+    /// Give the sequence `seq_id` its initial `state`, replacing the one it had (a sequence
+    /// prefilled again after a failure).
+    pub fn allocate(&mut self, seq_id: usize, state: Vec<Tensor>) -> Result<(), APIError> {
+        if !self.states.contains_key(&seq_id) && self.states.len() == self.num_slots {
+            return Err(APIError::new(format!(
+                "No state slot left for sequence {seq_id}, all {} are in use.",
+                self.num_slots
+            )));
+        }
+        self.states.insert(seq_id, state);
+        Ok(())
+    }
+
+    /// The states of `seq_ids`, concatenated in that order.
+    pub fn gather(&self, seq_ids: &[usize]) -> Result<Vec<Tensor>, APIError> {
+        let states = seq_ids
+            .iter()
+            .map(|seq_id| {
+                self.states
+                    .get(seq_id)
+                    .ok_or_else(|| APIError::new(format!("Sequence {seq_id} has no state.")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(first) = states.first() else {
+            return Ok(Vec::new());
+        };
+        if states.len() == 1 {
+            return Ok(first.to_vec());
+        }
+        (0..first.len())
+            .map(|i| {
+                let tensors = states.iter().map(|state| &state[i]).collect::<Vec<_>>();
+                Tensor::cat(&tensors, 0).map_err(APIError::from)
+            })
+            .collect()
+    }
+
+    /// Store the states of `seq_ids`, concatenated in that order as `gather` returns them.
+    pub fn scatter(&mut self, seq_ids: &[usize], state: &[Tensor]) -> Result<(), APIError> {
+        for (i, seq_id) in seq_ids.iter().enumerate() {
+            let seq_state = state
+                .iter()
+                .map(|tensor| tensor.narrow(0, i, 1))
+                .collect::<Result<Vec<_>, _>>();
+            let seq_state = try_api!(seq_state);
+            match self.states.get_mut(seq_id) {
+                Some(entry) => *entry = seq_state,
+                None => return Err(APIError::new(format!("Sequence {seq_id} has no state."))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Release the state of the sequence `seq_id`, if it has one.
+    pub fn free(&mut self, seq_id: usize) {
+        self.states.remove(&seq_id);
+    }
+
+    /// Release the states of the sequences for which `keep` is false.
+    pub fn retain(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        self.states.retain(|seq_id, _| keep(*seq_id));
+    }
+}
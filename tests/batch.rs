@@ -0,0 +1,64 @@
+mod common;
+
+use candle_vllm::openai::{
+    batch::{self, BatchOutput},
+    responses::APIError,
+};
+use common::MockEngine;
+
+#[test]
+fn test_parse_requests() {
+    let jsonl = r#"{"prompt": "Hello", "max_tokens": 4}
+
+{"id": "b", "prompt": "World", "temperature": 0.5, "stop": ["."]}"#;
+    let requests = batch::parse_requests(jsonl).unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].id.as_deref(), Some("line-1"));
+    assert_eq!(requests[0].max_tokens, Some(4));
+    assert_eq!(requests[1].id.as_deref(), Some("b"));
+    assert_eq!(requests[1].stop, vec!["."]);
+
+    let err = batch::parse_requests("{\"prompt\": \"a\"}\n{\"max_tokens\": 1}").unwrap_err();
+    assert!(err.to_string().contains("Line 2"));
+    assert!(batch::parse_requests("\n\n").is_err());
+}
+
+#[tokio::test]
+async fn test_batch_run_keeps_input_order() -> Result<(), APIError> {
+    let served = MockEngine::default().serve().await?;
+    // More requests than `max_num_seqs`, the mock model echoes the prompts
+    let jsonl = (0..10)
+        .map(|i| format!(r#"{{"prompt": "prompt {i}", "max_tokens": {}}}"#, 4 + i))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let requests = batch::parse_requests(&jsonl)?;
+    let mut out = Vec::new();
+    let summary = batch::run(&served, requests, &mut out).await?;
+    assert_eq!(summary.requests, 10);
+    assert_eq!(summary.failed, 0);
+
+    let outputs = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<BatchOutput>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(outputs.len(), 10);
+    for (i, output) in outputs.iter().enumerate() {
+        assert_eq!(output.id, format!("line-{}", i + 1));
+        let prompt = format!("prompt {i}");
+        assert!(prompt.starts_with(&output.text));
+        assert!(output.usage.prompt_tokens > 0);
+        assert_eq!(
+            output.usage.total_tokens,
+            output.usage.prompt_tokens + output.usage.completion_tokens
+        );
+    }
+    assert_eq!(
+        summary.completion_tokens,
+        outputs
+            .iter()
+            .map(|output| output.usage.completion_tokens)
+            .sum::<usize>()
+    );
+    Ok(())
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use candle_vllm::openai::{
+    bench::{self, BenchConfig, LengthRange, Percentiles},
+    responses::APIError,
+};
+use common::MockEngine;
+use std::time::Duration;
+
+fn config(num_requests: usize, request_rate: Option<f64>) -> BenchConfig {
+    BenchConfig {
+        num_requests,
+        prompt_len: "8-16".parse().unwrap(),
+        output_len: "4".parse().unwrap(),
+        request_rate,
+        seed: 7,
+    }
+}
+
+#[test]
+fn test_length_range() {
+    assert_eq!(
+        "128".parse::<LengthRange>(),
+        Ok(LengthRange { min: 128, max: 128 })
+    );
+    assert_eq!(
+        "32-512".parse::<LengthRange>(),
+        Ok(LengthRange { min: 32, max: 512 })
+    );
+    assert!("0".parse::<LengthRange>().is_err());
+    assert!("64-32".parse::<LengthRange>().is_err());
+    assert!("many".parse::<LengthRange>().is_err());
+}
+
+#[test]
+fn test_percentiles() {
+    let samples = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+    let p = Percentiles::of(&samples);
+    assert!((p.mean - 50.5).abs() < 1e-9);
+    assert_eq!((p.p50, p.p90, p.p99), (50.0, 90.0, 99.0));
+    assert_eq!(Percentiles::of(&[]), Percentiles::default());
+}
+
+#[test]
+fn test_synthetic_requests() {
+    let requests = bench::synthetic_requests(&config(20, Some(10.0)));
+    assert_eq!(requests.len(), 20);
+    // Reproducible for a seed
+    let again = bench::synthetic_requests(&config(20, Some(10.0)));
+    assert!(requests
+        .iter()
+        .zip(&again)
+        .all(|(a, b)| a.prompt == b.prompt && a.arrival == b.arrival));
+    for (idx, request) in requests.iter().enumerate() {
+        let words = request.prompt.split(' ').count();
+        assert!((8..=16).contains(&words));
+        assert!(request.prompt.starts_with(&format!("{idx} ")));
+        assert_eq!(request.output_len, 4);
+    }
+    assert_eq!(requests[0].arrival, Duration::ZERO);
+    assert!(requests.windows(2).all(|w| w[0].arrival <= w[1].arrival));
+    assert!(requests[19].arrival > Duration::ZERO);
+    // All at once without a rate
+    assert!(bench::synthetic_requests(&config(5, None))
+        .iter()
+        .all(|request| request.arrival == Duration::ZERO));
+}
+
+#[tokio::test]
+async fn test_bench_mock_model() -> Result<(), APIError> {
+    let served = MockEngine::default().serve().await?;
+
+    let report = bench::run(&served, &config(8, None)).await?;
+    assert_eq!(report.requests, 8);
+    assert_eq!(report.failed, 0);
+    // The mock model echoes the prompts, which are longer than the outputs
+    assert_eq!(report.output_tokens, 8 * 4);
+    assert!(report.prompt_tokens > 0);
+    assert!(report.output_throughput > 0.0);
+    assert!(report.ttft.p50 <= report.ttft.p99);
+    assert!(report.latency.mean >= report.ttft.mean);
+    assert!(report.kv_cache_usage_peak <= 1.0);
+    assert!(report.to_string().contains("Time to 1st token"));
+    Ok(())
+}
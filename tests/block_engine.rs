@@ -0,0 +1,670 @@
+use candle_core::DType;
+use candle_vllm::{
+    openai::{
+        requests::{AttentionSinks, CachePriority, StreamOptions},
+        responses::APIError,
+        sampling_params::{Logprobs, SamplingParams},
+    },
+    scheduler::{
+        block_engine::BlockEngine,
+        cache_engine::CacheConfig,
+        sequence::{_Sequence, Sequence, SequenceGroup, SequenceStatus},
+        Scheduler, SchedulerConfig, SchedulerOutput, SchedulingPolicy,
+    },
+};
+use std::{
+    collections::HashSet,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::SystemTime,
+};
+
+const BLOCK_SIZE: usize = 4;
+
+fn sampling_params() -> Result<SamplingParams, APIError> {
+    SamplingParams::builder()
+        .temperature(0.)
+        .skip_special_tokens(false)
+        .build()
+}
+
+/// A group with one sequence whose first `prefix_len` prompt tokens are shareable.
+fn group(seq_id: usize, prompt: Vec<usize>, prefix_len: usize) -> Result<SequenceGroup, APIError> {
+    let seq = Arc::new(Sequence(RwLock::new(_Sequence::new(
+        prompt, seq_id, BLOCK_SIZE,
+    ))));
+    Ok(SequenceGroup::new(
+        &[seq],
+        0,
+        seq_id,
+        format!("cmpl-{seq_id}"),
+        SystemTime::now(),
+        sampling_params()?,
+        false,
+        None,
+        StreamOptions::default(),
+        prefix_len,
+        None,
+    ))
+}
+
+fn free(engine: &mut BlockEngine, group: &SequenceGroup) {
+    for seq in group.get_seqs().values() {
+        engine.free_sequence(seq);
+    }
+}
+
+/// Allocate the blocks of a group and cache its prefix, as the engine does after its prefill.
+fn prefill(engine: &mut BlockEngine, group: &SequenceGroup) {
+    engine.allocate(group);
+    engine.cache_prefilled_prefix(group);
+}
+
+#[test]
+fn test_prefix_is_cached_once_prefilled() -> Result<(), APIError> {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 8, 0);
+    let prompt = (0..10).collect::<Vec<_>>();
+    let prefix_cached_len = |group: &SequenceGroup| {
+        group
+            .get_seqs()
+            .values()
+            .next()
+            .unwrap()
+            .deref()
+            .get_prefix_cached_len()
+    };
+
+    // Released before its prefill, e.g. its batch failed: its blocks hold no KV
+    let failed = group(0, prompt.clone(), 8)?;
+    engine.allocate(&failed);
+    assert_eq!(engine.cached_prefix_len(&prompt, 8), 0);
+    free(&mut engine, &failed);
+    engine.cache_prefilled_prefix(&failed);
+    assert_eq!(engine.cached_prefix_len(&prompt, 8), 0);
+
+    // Prompts scheduled in the same step do not share the prefix before it is written
+    let first = group(1, prompt.clone(), 8)?;
+    let second = group(2, prompt.clone(), 8)?;
+    engine.allocate(&first);
+    engine.allocate(&second);
+    assert_eq!(prefix_cached_len(&second), 0);
+    engine.cache_prefilled_prefix(&first);
+    engine.cache_prefilled_prefix(&second);
+    assert_eq!(engine.cached_prefix_len(&prompt, 8), 8);
+    assert_eq!(
+        engine
+            .prefix_cache_metrics()
+            .evictions
+            .load(Ordering::Relaxed),
+        0
+    );
+
+    let third = group(3, prompt, 8)?;
+    engine.allocate(&third);
+    assert_eq!(prefix_cached_len(&third), 8);
+    Ok(())
+}
+
+#[test]
+fn test_prefix_spills_to_cpu_and_swaps_back() -> Result<(), APIError> {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 4, 4);
+    let metrics = engine.prefix_cache_metrics();
+    let prompt = (0..10).collect::<Vec<_>>();
+
+    // The first request caches its two full prefix blocks
+    let first = group(0, prompt.clone(), 8)?;
+    prefill(&mut engine, &first);
+    let (gpu, cpu) = engine.block_stats();
+    assert_eq!((gpu.used, gpu.free, gpu.total), (3, 1, 4));
+    assert_eq!((cpu.used, cpu.free, cpu.total), (0, 4, 4));
+    free(&mut engine, &first);
+    assert_eq!(metrics.misses.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.gpu_blocks.load(Ordering::Relaxed), 2);
+
+    // GPU pressure moves the prefix to the CPU tier
+    assert!(engine.evict_lru_prefix());
+    let (swap_in, swap_out) = engine.take_prefix_swaps();
+    assert!(swap_in.is_empty());
+    assert_eq!(swap_out.len(), 2);
+    assert_eq!(metrics.spills.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.gpu_blocks.load(Ordering::Relaxed), 0);
+    assert_eq!(metrics.cpu_blocks.load(Ordering::Relaxed), 2);
+    // Nothing left to release on the GPU
+    assert!(!engine.evict_lru_prefix());
+
+    // A hit on the CPU tier swaps the spilled blocks back in
+    let second = group(1, prompt, 8)?;
+    engine.allocate(&second);
+    let (swap_in, swap_out) = engine.take_prefix_swaps();
+    assert!(swap_out.is_empty());
+    let mut table_prefix = prefix_blocks(&engine, &second);
+    table_prefix.sort_unstable();
+    assert_eq!(swap_in.len(), 2);
+    assert_eq!(metrics.cpu_hits.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.gpu_blocks.load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.cpu_blocks.load(Ordering::Relaxed), 0);
+    let seq = second.get_seqs().values().next().unwrap();
+    assert_eq!(seq.deref().get_prefix_cached_len(), 8);
+    // The sequence reads its prefix from the swapped in blocks
+    let mut swapped_in = swap_in.values().copied().collect::<Vec<_>>();
+    swapped_in.sort_unstable();
+    assert_eq!(table_prefix, swapped_in);
+    Ok(())
+}
+
+#[test]
+fn test_prefix_eviction_follows_cache_priority() -> Result<(), APIError> {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 0);
+    let prompts = [0, 100, 200].map(|start| (start..start + 10).collect::<Vec<_>>());
+    // Cached oldest first: a pinned prefix, then bulk traffic
+    let priorities = [
+        CachePriority::Pinned,
+        CachePriority::Normal,
+        CachePriority::Low,
+    ];
+    for (seq_id, (prompt, priority)) in prompts.iter().zip(priorities).enumerate() {
+        let group = group(seq_id, prompt.clone(), 8)?.with_cache_priority(priority);
+        prefill(&mut engine, &group);
+        free(&mut engine, &group);
+    }
+    let cached = |engine: &BlockEngine| {
+        prompts
+            .iter()
+            .map(|prompt| engine.cached_prefix_len(prompt, 8) > 0)
+            .collect::<Vec<_>>()
+    };
+
+    // Low first, then normal, although the pinned prefix is the least recently used
+    assert!(engine.evict_lru_prefix());
+    assert_eq!(cached(&engine), [true, true, false]);
+    assert!(engine.evict_lru_prefix());
+    assert_eq!(cached(&engine), [true, false, false]);
+
+    // A low priority hit does not demote a pinned prefix
+    let hit = group(3, prompts[0].clone(), 8)?.with_cache_priority(CachePriority::Low);
+    prefill(&mut engine, &hit);
+    free(&mut engine, &hit);
+    let normal = group(4, prompts[1].clone(), 8)?;
+    prefill(&mut engine, &normal);
+    free(&mut engine, &normal);
+    assert!(engine.evict_lru_prefix());
+    assert_eq!(cached(&engine), [true, false, false]);
+
+    // Pinned prefixes go last
+    assert!(engine.evict_lru_prefix());
+    assert_eq!(cached(&engine), [false, false, false]);
+    assert!(!engine.evict_lru_prefix());
+    Ok(())
+}
+
+/// Generate `tokens` for the single sequence of an allocated group.
+fn generate(engine: &mut BlockEngine, group: &SequenceGroup, tokens: std::ops::Range<usize>) {
+    let seq = group.get_seqs().values().next().unwrap();
+    for token in tokens {
+        seq.deref_mut().add_token(Logprobs {
+            token,
+            logprob: 0.,
+            bytes: String::new(),
+            top_logprobs: vec![],
+        });
+        engine.append_token_slot_to_seq(seq);
+    }
+}
+
+#[test]
+fn test_session_snapshot_is_restored() -> Result<(), APIError> {
+    let mut engine = BlockEngine::new(BLOCK_SIZE, 16, 8);
+    let metrics = engine.prefix_cache_metrics();
+    let session = || Some("chat".to_string());
+
+    // First turn: 6 prompt tokens and 5 generated ones, the KV of the first 10 is computed
+    let first = group(0, (0..6).collect(), 0)?.with_session_id(session());
+    engine.allocate(&first);
+    generate(&mut engine, &first, 6..11);
+    let seq = first.get_seqs().values().next().unwrap();
+    engine.save_session("chat", seq, CachePriority::Normal);
+    let snapshot = prefix_blocks(&engine, &first);
+    free(&mut engine, &first);
+    assert!(engine.has_session("chat"));
+    assert_eq!(metrics.gpu_blocks.load(Ordering::Relaxed), 2);
+
+    // The next turn repeats the conversation and adds a message
+    let second = group(1, (0..14).collect(), 0)?.with_session_id(session());
+    engine.allocate(&second);
+    let seq = second.get_seqs().values().next().unwrap();
+    assert_eq!(seq.deref().get_prefix_cached_len(), 8);
+    assert_eq!(prefix_blocks(&engine, &second), snapshot);
+    assert_eq!(metrics.gpu_hits.load(Ordering::Relaxed), 1);
+
+    // Its own snapshot replaces the first one
+    generate(&mut engine, &second, 14..20);
+    engine.save_session("chat", seq, CachePriority::Normal);
+    free(&mut engine, &second);
+    assert_eq!(metrics.gpu_blocks.load(Ordering::Relaxed), 4);
+    assert_eq!(metrics.evictions.load(Ordering::Relaxed), 0);
+
+    // A conversation that was edited only reuses the blocks before the edit
+    let mut edited = (0..20).collect::<Vec<_>>();
+    edited[6] = 100;
+    let third = group(2, edited, 0)?.with_session_id(session());
+    engine.allocate(&third);
+    let seq = third.get_seqs().values().next().unwrap();
+    assert_eq!(seq.deref().get_prefix_cached_len(), 4);
+    free(&mut engine, &third);
+
+    // Other sessions and requests without a session start from scratch
+    for (id, group) in [
+        (
+            3,
+            group(3, (0..14).collect(), 0)?.with_session_id(Some("other".to_string())),
+        ),
+        (4, group(4, (0..14).collect(), 0)?),
+    ] {
+        engine.allocate(&group);
+        let seq = group.get_seqs().values().next().unwrap();
+        assert_eq!(seq.deref().get_prefix_cached_len(), 0, "request {id}");
+        free(&mut engine, &group);
+    }
+    Ok(())
+}
+
+/// GPU blocks of the cached prefix in the block table of the group's sequence.
+fn prefix_blocks(engine: &BlockEngine, group: &SequenceGroup) -> Vec<usize> {
+    let seq = group.get_seqs().values().next().unwrap();
+    engine.block_tables[&seq.deref().get_id()][..2]
+        .iter()
+        .map(|block| block.deref_mut().block_id)
+        .collect()
+}
+
+#[test]
+fn test_attention_sinks_bound_the_cache() -> Result<(), APIError> {
+    let cache_config = CacheConfig {
+        block_size: BLOCK_SIZE,
+        num_gpu_blocks: Some(16),
+        num_cpu_blocks: Some(16),
+        fully_init: true,
+        dtype: DType::F16,
+        tensor_parallel_size: 1,
+    };
+    let config = SchedulerConfig {
+        max_num_seqs: 4,
+        max_num_batched_tokens: 64,
+        kv_eviction: None,
+        num_lookahead_slots: 0,
+        policy: SchedulingPolicy::Fcfs,
+    };
+    let mut scheduler = Scheduler::new(config, &cache_config);
+    let sinks = AttentionSinks {
+        num_sink_tokens: 4,
+        window: 8,
+    };
+    let session = group(0, (0..8).collect(), 0)?.with_attention_sinks(Some(sinks));
+    let seq = session.get_seqs().values().next().unwrap().clone();
+    scheduler.add_sequence(session);
+
+    let mut rebases = Vec::new();
+    for token in 0..64 {
+        scheduler.schedule();
+        seq.deref_mut().add_token(Logprobs {
+            token,
+            logprob: 0.,
+            bytes: String::new(),
+            top_logprobs: vec![],
+        });
+        rebases.extend(scheduler.evict_beyond_sinks(BLOCK_SIZE));
+        // The sink block, the window and the partially filled block
+        assert!(seq.deref().get_cached_len() <= 4 + 8 + BLOCK_SIZE);
+        // Positions are compacted once a window of them was evicted
+        assert!(seq.deref().get_last_position() < 4 + 2 * 8 + BLOCK_SIZE);
+    }
+    assert_eq!(seq.deref().get_len(), 72);
+    assert!(!rebases.is_empty());
+    let sink_block = scheduler.block_engine.block_tables[&seq.deref().get_id()][0]
+        .deref_mut()
+        .block_id;
+    for rebase in &rebases {
+        assert!(rebase.delta >= 8);
+        assert!(!rebase.blocks.contains(&sink_block));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sliding_window_bounds_the_cache() -> Result<(), APIError> {
+    let cache_config = CacheConfig {
+        block_size: BLOCK_SIZE,
+        num_gpu_blocks: Some(16),
+        num_cpu_blocks: Some(16),
+        fully_init: true,
+        dtype: DType::F16,
+        tensor_parallel_size: 1,
+    };
+    let config = SchedulerConfig {
+        max_num_seqs: 4,
+        max_num_batched_tokens: 64,
+        kv_eviction: None,
+        num_lookahead_slots: 0,
+        policy: SchedulingPolicy::Fcfs,
+    };
+    let mut scheduler = Scheduler::new(config, &cache_config);
+    let window = 8;
+    let request = group(0, (0..6).collect(), 0)?;
+    let seq = request.get_seqs().values().next().unwrap().clone();
+    scheduler.add_sequence(request);
+
+    for token in 0..64 {
+        scheduler.schedule();
+        seq.deref_mut().add_token(Logprobs {
+            token,
+            logprob: 0.,
+            bytes: String::new(),
+            top_logprobs: vec![],
+        });
+        scheduler.evict_beyond_window(BLOCK_SIZE, window);
+        let cached_len = seq.deref().get_cached_len();
+        // The window of the pending token is kept, with the block it starts in
+        assert!(cached_len > window.min(seq.deref().get_len() - 1));
+        assert!(cached_len <= window + BLOCK_SIZE);
+        let table_len = scheduler.block_engine.block_tables[&seq.deref().get_id()].len();
+        assert!(table_len * BLOCK_SIZE <= window + 2 * BLOCK_SIZE);
+        // Positions are not compacted
+        assert_eq!(seq.deref().get_last_position(), seq.deref().get_len() - 1);
+    }
+    assert_eq!(seq.deref().get_len(), 70);
+    Ok(())
+}
+
+fn scheduler(policy: SchedulingPolicy) -> Scheduler {
+    scheduler_with_blocks(policy, 8, 8)
+}
+
+fn scheduler_with_blocks(
+    policy: SchedulingPolicy,
+    num_gpu_blocks: usize,
+    num_cpu_blocks: usize,
+) -> Scheduler {
+    let cache_config = CacheConfig {
+        block_size: BLOCK_SIZE,
+        num_gpu_blocks: Some(num_gpu_blocks),
+        num_cpu_blocks: Some(num_cpu_blocks),
+        fully_init: true,
+        dtype: DType::F16,
+        tensor_parallel_size: 1,
+    };
+    let config = SchedulerConfig {
+        max_num_seqs: 4,
+        max_num_batched_tokens: 64,
+        kv_eviction: None,
+        num_lookahead_slots: 0,
+        policy,
+    };
+    Scheduler::new(config, &cache_config)
+}
+
+#[test]
+fn test_priority_request_preempts_lower_priority() -> Result<(), APIError> {
+    for policy in [SchedulingPolicy::Fcfs, SchedulingPolicy::Priority] {
+        let mut scheduler = scheduler(policy);
+        // A long batch generation holds 5 of the 8 GPU blocks
+        scheduler.add_sequence(group(0, (0..20).collect(), 0)?.with_priority(1));
+        let output = scheduler.schedule();
+        assert_eq!(output.scheduled.len(), 1);
+
+        // An interactive request does not fit next to it
+        scheduler.add_sequence(group(1, (0..12).collect(), 0)?.with_priority(0));
+        let output = scheduler.schedule();
+        let scheduled = output
+            .scheduled
+            .iter()
+            .map(|group| group.request_id.as_str())
+            .collect::<Vec<_>>();
+        match policy {
+            SchedulingPolicy::Fcfs => {
+                assert_eq!(scheduled, ["cmpl-0"]);
+                assert!(output.blocks_to_swap_out.is_empty());
+                assert_eq!(scheduler.queue_depth().load(Ordering::Relaxed), 1);
+            }
+            SchedulingPolicy::Priority => {
+                // The batch generation is swapped out to make room
+                assert_eq!(scheduled, ["cmpl-1"]);
+                assert_eq!(output.blocks_to_swap_out.len(), 5);
+                assert_eq!(scheduler.queue_depth().load(Ordering::Relaxed), 1);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Schedule two groups with 12 token prompts on the 8 GPU blocks and decode until one of them
+/// is preempted, after 5 tokens each. Returns the output of the step that preempted it.
+fn decode_until_preempted(scheduler: &mut Scheduler) -> Result<SchedulerOutput, APIError> {
+    for seq_id in 0..2 {
+        scheduler.add_sequence(group(seq_id, (0..12).collect(), 0)?);
+    }
+    for token in 0.. {
+        let output = scheduler.schedule();
+        if output.scheduled.len() < 2 {
+            assert_eq!(token, 5);
+            return Ok(output);
+        }
+        for group in output.scheduled.iter() {
+            for seq in group.get_seqs().values() {
+                add_token(seq, token);
+            }
+        }
+    }
+    unreachable!()
+}
+
+#[test]
+fn test_preempted_group_swaps_out_and_back_in() -> Result<(), APIError> {
+    let mut scheduler = scheduler_with_blocks(SchedulingPolicy::Fcfs, 8, 8);
+    let output = decode_until_preempted(&mut scheduler)?;
+    // The 4 blocks of the preempted group moved to the CPU cache
+    let swapped_out = output
+        .blocks_to_swap_out
+        .values()
+        .copied()
+        .collect::<HashSet<_>>();
+    assert_eq!(swapped_out.len(), 4);
+    assert_eq!(scheduler.queue_sizes(), (1, 0, 1));
+    let (gpu, cpu) = scheduler.block_engine.block_stats();
+    assert_eq!((gpu.used, cpu.used), (5, 4));
+    let running = output.scheduled[0].clone();
+
+    // Once the running group finishes, the preempted one comes back with its tokens
+    running.set_status(SequenceStatus::Finished("stop".to_string()));
+    scheduler.free_finished_sequence_groups();
+    let output = scheduler.schedule();
+    assert_eq!(output.scheduled.len(), 1);
+    let swapped = output.scheduled[0].clone();
+    assert_ne!(swapped.request_id, running.request_id);
+    // Read back from the CPU blocks it was swapped out to
+    let swapped_in = output
+        .blocks_to_swap_in
+        .keys()
+        .copied()
+        .collect::<HashSet<_>>();
+    assert_eq!(swapped_in, swapped_out);
+    assert_eq!(scheduler.queue_sizes(), (1, 0, 0));
+    let (gpu, cpu) = scheduler.block_engine.block_stats();
+    assert_eq!((gpu.used, cpu.used), (5, 0));
+    let seq = swapped.get_seqs().values().next().unwrap();
+    assert_eq!(seq.deref().get_len(), 17);
+    let tokens = seq
+        .deref()
+        .get_output_tokens()
+        .iter()
+        .map(|logprobs| logprobs.token)
+        .collect::<Vec<_>>();
+    assert_eq!(tokens, [0, 1, 2, 3, 4]);
+    Ok(())
+}
+
+#[test]
+fn test_preempted_group_aborts_when_the_cpu_cache_is_full() -> Result<(), APIError> {
+    // The preempted group generated already and its 4 blocks do not fit in 2 CPU blocks
+    let mut scheduler = scheduler_with_blocks(SchedulingPolicy::Fcfs, 8, 2);
+    let output = decode_until_preempted(&mut scheduler)?;
+    assert!(output.blocks_to_swap_out.is_empty());
+    assert_eq!(scheduler.queue_sizes(), (1, 0, 0));
+    let (gpu, cpu) = scheduler.block_engine.block_stats();
+    assert_eq!((gpu.used, cpu.used), (5, 0));
+    // Neither running nor swapped out
+    let aborted = if output.scheduled[0].request_id == "cmpl-0" {
+        "cmpl-1"
+    } else {
+        "cmpl-0"
+    };
+    assert!(scheduler.abort_request(aborted).is_none());
+    Ok(())
+}
+
+/// Ids of the physical blocks of a sequence.
+fn block_ids(engine: &BlockEngine, seq: &Sequence) -> Vec<usize> {
+    engine.block_tables[&seq.deref().get_id()]
+        .iter()
+        .map(|block| block.deref_mut().block_id)
+        .collect()
+}
+
+fn add_token(seq: &Sequence, token: usize) {
+    seq.deref_mut().add_token(Logprobs {
+        token,
+        logprob: 0.,
+        bytes: String::new(),
+        top_logprobs: vec![],
+    });
+}
+
+#[test]
+fn test_samples_share_blocks_until_they_diverge() -> Result<(), APIError> {
+    let mut scheduler = scheduler(SchedulingPolicy::Fcfs);
+    // Two samples of a prompt of 6 tokens, which takes 2 blocks
+    let seqs = (0..2)
+        .map(|id| {
+            Arc::new(Sequence(RwLock::new(_Sequence::new(
+                (0..6).collect(),
+                id,
+                BLOCK_SIZE,
+            ))))
+        })
+        .collect::<Vec<_>>();
+    let samples = SequenceGroup::new(
+        &seqs,
+        0,
+        0,
+        "cmpl-0".to_string(),
+        SystemTime::now(),
+        sampling_params()?,
+        false,
+        None,
+        StreamOptions::default(),
+        0,
+        None,
+    );
+    scheduler.add_sequence(samples);
+    scheduler.schedule();
+    let engine = &scheduler.block_engine;
+    assert_eq!(block_ids(engine, &seqs[0]), block_ids(engine, &seqs[1]));
+    assert_eq!(engine.num_used_gpu_blocks(), 2);
+
+    // Their first tokens differ: the partially filled block is copied for one of them, the other
+    // one keeps writing to it
+    add_token(&seqs[0], 6);
+    add_token(&seqs[1], 7);
+    let output = scheduler.schedule();
+    let engine = &scheduler.block_engine;
+    let (first, second) = (block_ids(engine, &seqs[0]), block_ids(engine, &seqs[1]));
+    assert_eq!(first[0], second[0]);
+    assert_ne!(first[1], second[1]);
+    assert_eq!(engine.num_used_gpu_blocks(), 3);
+    let copies = output
+        .blocks_to_copy
+        .iter()
+        .flat_map(|(src, dsts)| dsts.iter().map(|dst| (*src, *dst)))
+        .collect::<Vec<_>>();
+    assert_eq!(copies.len(), 1);
+    assert!([(first[1], second[1]), (second[1], first[1])].contains(&copies[0]));
+
+    // A sequence forked from the first sample shares all of its blocks, the token filling the
+    // last block copies it before a new block is added
+    let child = Arc::new(Sequence(RwLock::new(_Sequence::new(
+        (0..7).collect(),
+        2,
+        BLOCK_SIZE,
+    ))));
+    let engine = &mut scheduler.block_engine;
+    engine.fork_seq(&seqs[0], &child);
+    assert_eq!(block_ids(engine, &child), first);
+    assert_eq!(engine.num_used_gpu_blocks(), 3);
+    add_token(&child, 100);
+    let (src, dst) = engine.append_token_slot_to_seq(&child).unwrap();
+    assert_eq!(src, first[1]);
+    let forked = block_ids(engine, &child);
+    assert_eq!(forked.len(), 3);
+    assert_eq!((forked[0], forked[1]), (first[0], dst));
+    assert_eq!(block_ids(engine, &seqs[0]), first);
+    assert_eq!(engine.num_used_gpu_blocks(), 5);
+
+    // The shared blocks are released with the last sequence holding them
+    for seq in seqs.iter().chain([&child]) {
+        engine.free_sequence(seq);
+    }
+    assert_eq!(engine.num_used_gpu_blocks(), 0);
+    Ok(())
+}
+
+/// Recurrent sequences are admitted by state slots, without KV blocks: prompts longer than the
+/// whole KV cache run, and a group waits for a slot rather than for blocks.
+#[test]
+fn test_state_slots_admit_without_blocks() -> Result<(), APIError> {
+    let mut scheduler = scheduler(SchedulingPolicy::Priority);
+    scheduler.use_state_slots(2);
+    // 40 tokens each, the 8 blocks hold 32
+    for seq_id in 0..3 {
+        scheduler.add_sequence(group(seq_id, (0..40).collect(), 0)?);
+    }
+    let output = scheduler.schedule();
+    let scheduled = output
+        .scheduled
+        .iter()
+        .map(|group| group.request_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(scheduled, ["cmpl-0", "cmpl-1"]);
+    assert!(scheduler.block_engine.block_tables.is_empty());
+    assert_eq!(scheduler.queue_depth().load(Ordering::Relaxed), 1);
+    assert_eq!(scheduler.running_seq_ids(), HashSet::from([0, 1]));
+
+    // Decoding steps append no blocks and preempt nothing
+    for token in 0..16 {
+        let output = scheduler.schedule();
+        assert_eq!(output.scheduled.len(), 2);
+        assert!(output.blocks_to_swap_out.is_empty());
+        for group in output.scheduled.iter() {
+            for seq in group.get_seqs().values() {
+                seq.deref_mut().add_token(Logprobs {
+                    token,
+                    logprob: 0.,
+                    bytes: String::new(),
+                    top_logprobs: vec![],
+                });
+            }
+        }
+    }
+    assert!(scheduler.block_engine.block_tables.is_empty());
+
+    // The slot of a finished group goes to the waiting one
+    let finished = output.scheduled[0].clone();
+    finished.set_status(SequenceStatus::Finished("stop".to_string()));
+    scheduler.free_finished_sequence_groups();
+    let output = scheduler.schedule();
+    let scheduled = output
+        .scheduled
+        .iter()
+        .map(|group| group.request_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(scheduled, ["cmpl-2"]);
+    assert_eq!(scheduler.running_seq_ids(), HashSet::from([1, 2]));
+    Ok(())
+}
@@ -0,0 +1,568 @@
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_vllm::{
+    backend::{
+        copy_blocks, paged_attention, paged_attention_scores, paged_latent_attention,
+        reshape_and_cache, reshape_and_cache_latent, swap_blocks,
+    },
+    openai::{models::llama::LlamaConfig, responses::APIError},
+    scheduler::cache_engine::{CacheConfig, CacheEngine, EncoderDecoderKv},
+    try_api, SpecificConfig,
+};
+use std::collections::HashMap;
+use std::iter::zip;
+use std::time::Instant;
+
+// (num_kv_heads, head_size / x, block_size, x), the key cache block shape of a 128-dim head in f16
+const KEY_BLOCK_SHAPE: (usize, usize, usize, usize) = (4, 16, 32, 8);
+const NUM_BLOCKS: usize = 8;
+
+/// A key cache whose elements are small integers (exact in f16/bf16), different for every
+/// block and every `seed`.
+fn patterned_cache(
+    num_blocks: usize,
+    seed: usize,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor, APIError> {
+    let (h, d, b, x) = KEY_BLOCK_SHAPE;
+    let data = (0..num_blocks * h * d * b * x)
+        .map(|i| ((i * 7 + seed * 131) % 251) as f32)
+        .collect::<Vec<_>>();
+    let cache = try_api!(Tensor::from_vec(
+        data,
+        (num_blocks, h, d, b, x),
+        &Device::Cpu
+    ));
+    Ok(try_api!(try_api!(cache.to_dtype(dtype)).to_device(device)))
+}
+
+fn block(cache: &Tensor, idx: usize) -> Result<Vec<f32>, APIError> {
+    let block = try_api!(try_api!(cache.i(idx)).to_dtype(DType::F32));
+    Ok(try_api!(try_api!(block.flatten_all()).to_vec1::<f32>()))
+}
+
+/// Every mapped destination block holds its source block, every other block is untouched.
+fn assert_blocks(
+    src: &Tensor,
+    dst_before: &Tensor,
+    dst: &Tensor,
+    mapping: &HashMap<usize, usize>,
+) -> Result<(), APIError> {
+    for dst_idx in 0..dst.dims()[0] {
+        let expected = match mapping.iter().find(|(_, dst_block)| **dst_block == dst_idx) {
+            Some((src_idx, _)) => block(src, *src_idx)?,
+            None => block(dst_before, dst_idx)?,
+        };
+        assert_eq!(block(dst, dst_idx)?, expected, "block {dst_idx} differs");
+    }
+    Ok(())
+}
+
+fn check_swap(src_device: &Device, dst_device: &Device, dtype: DType) -> Result<(), APIError> {
+    let src = patterned_cache(NUM_BLOCKS, 1, dtype, src_device)?;
+    let mut dst = patterned_cache(NUM_BLOCKS, 2, dtype, dst_device)?;
+    let dst_before = try_api!(dst.copy());
+    let mapping = HashMap::from([(0, 3), (2, 1), (7, 0)]);
+    swap_blocks(src.clone(), &mut dst, mapping.clone())?;
+    try_api!(dst_device.synchronize());
+    assert_blocks(&src, &dst_before, &dst, &mapping)
+}
+
+#[test]
+fn test_swap_blocks_cpu_to_cpu() -> Result<(), APIError> {
+    check_swap(&Device::Cpu, &Device::Cpu, DType::F32)
+}
+
+#[cfg(feature = "cuda")]
+#[test]
+fn test_swap_in() -> Result<(), APIError> {
+    let gpu = try_api!(Device::new_cuda(0));
+    for dtype in [DType::F32, DType::F16, DType::BF16, DType::U8] {
+        check_swap(&Device::Cpu, &gpu, dtype)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cuda")]
+#[test]
+fn test_swap_out() -> Result<(), APIError> {
+    let gpu = try_api!(Device::new_cuda(0));
+    for dtype in [DType::F32, DType::F16, DType::BF16, DType::U8] {
+        check_swap(&gpu, &Device::Cpu, dtype)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cuda")]
+#[test]
+fn test_swap_blocks_gpu_to_gpu() -> Result<(), APIError> {
+    let gpu = try_api!(Device::new_cuda(0));
+    for dtype in [DType::F32, DType::F16, DType::BF16, DType::U8] {
+        check_swap(&gpu, &gpu, dtype)?;
+    }
+    Ok(())
+}
+
+fn check_copy_blocks(gpu: &Device, dtypes: &[DType]) -> Result<(), APIError> {
+    let num_layers = 2;
+    for &dtype in dtypes {
+        let mut key_caches = Vec::new();
+        let mut value_caches = Vec::new();
+        for layer in 0..num_layers {
+            key_caches.push(patterned_cache(NUM_BLOCKS, 2 * layer, dtype, gpu)?);
+            value_caches.push(patterned_cache(NUM_BLOCKS, 2 * layer + 1, dtype, gpu)?);
+        }
+        let keys_before = key_caches
+            .iter()
+            .map(|cache| cache.copy())
+            .collect::<candle_core::Result<Vec<_>>>();
+        let keys_before = try_api!(keys_before);
+        let values_before = value_caches
+            .iter()
+            .map(|cache| cache.copy())
+            .collect::<candle_core::Result<Vec<_>>>();
+        let values_before = try_api!(values_before);
+
+        // Copy-on-write of block 1 into blocks 4 and 5, block 6 into block 2.
+        let mapping = HashMap::from([(1, vec![4, 5]), (6, vec![2])]);
+        unsafe {
+            copy_blocks(
+                key_caches.iter_mut().collect(),
+                value_caches.iter_mut().collect(),
+                mapping,
+            )?;
+        }
+        try_api!(gpu.synchronize());
+
+        let pairs = HashMap::from([(1, 4), (6, 2)]);
+        for layer in 0..num_layers {
+            for (before, after) in [
+                (&keys_before[layer], &key_caches[layer]),
+                (&values_before[layer], &value_caches[layer]),
+            ] {
+                assert_blocks(before, before, after, &pairs)?;
+                assert_eq!(block(after, 5)?, block(before, 1)?, "block 5 differs");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cuda")]
+#[test]
+fn test_copy_blocks() -> Result<(), APIError> {
+    let gpu = try_api!(Device::new_cuda(0));
+    check_copy_blocks(&gpu, &[DType::F32, DType::F16, DType::BF16, DType::U8])
+}
+
+#[cfg(feature = "metal")]
+#[test]
+fn test_metal_kernels() -> Result<(), APIError> {
+    let gpu = try_api!(Device::new_metal(0));
+    // Fails unless reshape_and_cache and paged_attention match the naive implementation
+    for dtype in [DType::F32, DType::F16] {
+        candle_vllm::backend::probe_native_kernels(&gpu, dtype, true)?;
+    }
+    check_copy_blocks(&gpu, &[DType::F32, DType::F16])?;
+    check_swap(&Device::Cpu, &gpu, DType::F16)?;
+    check_swap(&gpu, &Device::Cpu, DType::F16)
+}
+
+#[test]
+fn test_cpu_kernels() -> Result<(), APIError> {
+    // Fails unless reshape_and_cache and paged_attention match the naive implementation
+    for dtype in [DType::F32, DType::F16] {
+        candle_vllm::backend::probe_native_kernels(&Device::Cpu, dtype, true)?;
+    }
+    check_copy_blocks(
+        &Device::Cpu,
+        &[DType::F32, DType::F16, DType::BF16, DType::U8],
+    )
+}
+
+/// Attention over an INT8 cache stays close to attention over an f16 cache.
+#[cfg(feature = "cuda")]
+#[test]
+fn test_int8_paged_attention() -> Result<(), APIError> {
+    let gpu = try_api!(Device::new_cuda(0));
+    let (num_blocks, block_size, num_kv_heads, num_heads, head_size) = (4, 16, 2, 4, 64);
+    let num_tokens = 20;
+    // A single sequence spanning blocks 2 and 0
+    let block_table = [2u32, 0];
+    let slots = (0..num_tokens)
+        .map(|t| (block_table[t / block_size] as usize * block_size + t % block_size) as i64)
+        .collect::<Vec<_>>();
+    let randn = |shape: (usize, usize, usize)| {
+        Tensor::randn(0f32, 1f32, shape, &gpu).and_then(|t| t.to_dtype(DType::F16))
+    };
+    let key = try_api!(randn((num_tokens, num_kv_heads, head_size)));
+    let value = try_api!(randn((num_tokens, num_kv_heads, head_size)));
+    let query = try_api!(randn((1, num_heads, head_size)));
+    let slot_mapping = try_api!(Tensor::from_vec(slots, num_tokens, &gpu));
+    let block_tables = try_api!(Tensor::from_vec(block_table.to_vec(), (1, 2), &gpu));
+    let context_lens = try_api!(Tensor::from_vec(vec![num_tokens as u32], 1, &gpu));
+
+    let run = |cache_dtype: DType| -> Result<Tensor, APIError> {
+        let x = 16 / cache_dtype.size_in_bytes();
+        let (key_rows, value_rows) =
+            candle_vllm::backend::kv_cache_head_rows(head_size, x, cache_dtype);
+        let key_cache = try_api!(Tensor::zeros(
+            (num_blocks, num_kv_heads, key_rows, block_size, x),
+            cache_dtype,
+            &gpu
+        ));
+        let value_cache = try_api!(Tensor::zeros(
+            (num_blocks, num_kv_heads, value_rows, block_size),
+            cache_dtype,
+            &gpu
+        ));
+        try_api!(reshape_and_cache(
+            &key,
+            &value,
+            &key_cache,
+            &value_cache,
+            &slot_mapping
+        ));
+        let out = try_api!(paged_attention(
+            &query,
+            &key_cache,
+            &value_cache,
+            &block_tables,
+            &context_lens,
+            num_tokens,
+            1f32 / (head_size as f32).sqrt(),
+            1f32,
+            0,
+        ));
+        Ok(try_api!(out.to_dtype(DType::F32)))
+    };
+    let reference = run(DType::F16)?;
+    let int8 = run(DType::U8)?;
+    let diff = try_api!(try_api!(try_api!((reference - int8)).abs()).max_all());
+    let diff = try_api!(diff.to_scalar::<f32>());
+    assert!(diff < 5e-2, "int8 attention differs from f16 by {diff}");
+    Ok(())
+}
+
+/// Attention over a latent cache matches dense attention where the latents are both the keys
+/// and, truncated, the values.
+#[test]
+fn test_latent_paged_attention() -> Result<(), APIError> {
+    let cpu = Device::Cpu;
+    let (num_blocks, block_size, num_heads, latent_dim, value_dim) = (4, 16, 4, 48, 32);
+    let num_tokens = 20;
+    let x = 16 / DType::F32.size_in_bytes();
+    // A single sequence spanning blocks 2 and 0
+    let block_table = [2u32, 0];
+    let slots = (0..num_tokens)
+        .map(|t| (block_table[t / block_size] as usize * block_size + t % block_size) as i64)
+        .collect::<Vec<_>>();
+    let latent = try_api!(Tensor::randn(0f32, 1f32, (num_tokens, latent_dim), &cpu));
+    let query = try_api!(Tensor::randn(0f32, 1f32, (1, num_heads, latent_dim), &cpu));
+    let latent_cache = try_api!(Tensor::zeros(
+        (num_blocks, 1, latent_dim / x, block_size, x),
+        DType::F32,
+        &cpu
+    ));
+    let slot_mapping = try_api!(Tensor::from_vec(slots, num_tokens, &cpu));
+    try_api!(reshape_and_cache_latent(
+        &latent,
+        &latent_cache,
+        &slot_mapping
+    ));
+    let block_tables = try_api!(Tensor::from_vec(block_table.to_vec(), (1, 2), &cpu));
+    let context_lens = try_api!(Tensor::from_vec(vec![num_tokens as u32], 1, &cpu));
+    let scale = 1f32 / (latent_dim as f32).sqrt();
+    let out = try_api!(paged_latent_attention(
+        &query,
+        &latent_cache,
+        &block_tables,
+        &context_lens,
+        scale,
+        value_dim,
+    ));
+    assert_eq!(out.dims(), &[1, num_heads, value_dim]);
+
+    let att = try_api!(try_api!(try_api!(query.i(0)).matmul(&try_api!(latent.t()))) * scale as f64);
+    let probs = try_api!(candle_nn::ops::softmax_last_dim(&att));
+    let reference = try_api!(probs.matmul(&try_api!(latent.narrow(1, 0, value_dim))));
+    let diff = try_api!(try_api!(try_api!(try_api!(out.i(0)) - reference).abs()).max_all());
+    let diff = try_api!(diff.to_scalar::<f32>());
+    assert!(
+        diff < 1e-4,
+        "latent attention differs from dense attention by {diff}"
+    );
+    Ok(())
+}
+
+/// Decoding with a sliding window attends to the last `window` tokens only: the same as
+/// attending to a context made of the blocks of the window.
+#[test]
+fn test_sliding_window_paged_attention() -> Result<(), APIError> {
+    let cpu = Device::Cpu;
+    let (num_blocks, block_size, num_kv_heads, num_heads, head_size) = (4, 4, 2, 4, 64);
+    let (num_tokens, window) = (10, 6);
+    let x = 16 / DType::F32.size_in_bytes();
+    // A single sequence spanning blocks 2, 0 and 1, the window starts with block 0
+    let block_table = [2u32, 0, 1];
+    let slots = (0..num_tokens)
+        .map(|t| (block_table[t / block_size] as usize * block_size + t % block_size) as i64)
+        .collect::<Vec<_>>();
+    let key = try_api!(Tensor::randn(
+        0f32,
+        1f32,
+        (num_tokens, num_kv_heads, head_size),
+        &cpu
+    ));
+    let value = try_api!(Tensor::randn(
+        0f32,
+        1f32,
+        (num_tokens, num_kv_heads, head_size),
+        &cpu
+    ));
+    let query = try_api!(Tensor::randn(0f32, 1f32, (1, num_heads, head_size), &cpu));
+    let key_cache = try_api!(Tensor::zeros(
+        (num_blocks, num_kv_heads, head_size / x, block_size, x),
+        DType::F32,
+        &cpu
+    ));
+    let value_cache = try_api!(Tensor::zeros(
+        (num_blocks, num_kv_heads, head_size, block_size),
+        DType::F32,
+        &cpu
+    ));
+    let slot_mapping = try_api!(Tensor::from_vec(slots, num_tokens, &cpu));
+    try_api!(reshape_and_cache(
+        &key,
+        &value,
+        &key_cache,
+        &value_cache,
+        &slot_mapping
+    ));
+    let scale = 1f32 / (head_size as f32).sqrt();
+    let attend = |table: &[u32], context_len: usize, sliding_window: usize| {
+        let block_tables = try_api!(Tensor::from_vec(table.to_vec(), (1, table.len()), &cpu));
+        let context_lens = try_api!(Tensor::from_vec(vec![context_len as u32], 1, &cpu));
+        Ok::<_, APIError>(try_api!(paged_attention(
+            &query,
+            &key_cache,
+            &value_cache,
+            &block_tables,
+            &context_lens,
+            context_len,
+            scale,
+            1f32,
+            sliding_window,
+        )))
+    };
+    let windowed = attend(&block_table, num_tokens, window)?;
+    let reference = attend(&block_table[1..], window, 0)?;
+    let full = attend(&block_table, num_tokens, 0)?;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32, APIError> {
+        let diff = try_api!(try_api!(try_api!(a - b).abs()).max_all());
+        Ok(try_api!(diff.to_scalar::<f32>()))
+    };
+    assert!(max_diff(&windowed, &reference)? < 1e-5);
+    assert!(max_diff(&windowed, &full)? > 1e-3);
+    // A window longer than the context attends to all of it
+    assert!(max_diff(&attend(&block_table, num_tokens, 64)?, &full)? < 1e-5);
+    Ok(())
+}
+
+/// The attention scores of the decoding queries are the attention probabilities over the cached
+/// keys summed over the heads, zero past the context of a sequence.
+#[test]
+fn test_paged_attention_scores() -> Result<(), APIError> {
+    let cpu = Device::Cpu;
+    let (num_blocks, block_size, num_kv_heads, num_heads, head_size) = (4, 16, 2, 4, 64);
+    let x = 16 / DType::F32.size_in_bytes();
+    // A sequence of 20 tokens in blocks 2 and 0, and one of 5 tokens in block 1
+    let tables = [[2u32, 0], [1, 0]];
+    let context_lens = [20, 5];
+    let slots = zip(tables, context_lens)
+        .flat_map(|(table, len)| {
+            (0..len)
+                .map(move |t| (table[t / block_size] as usize * block_size + t % block_size) as i64)
+        })
+        .collect::<Vec<_>>();
+    let num_tokens = slots.len();
+    let key = try_api!(Tensor::randn(
+        0f32,
+        1f32,
+        (num_tokens, num_kv_heads, head_size),
+        &cpu
+    ));
+    let value = try_api!(key.zeros_like());
+    let query = try_api!(Tensor::randn(0f32, 1f32, (2, num_heads, head_size), &cpu));
+    let key_cache = try_api!(Tensor::zeros(
+        (num_blocks, num_kv_heads, head_size / x, block_size, x),
+        DType::F32,
+        &cpu
+    ));
+    let value_cache = try_api!(Tensor::zeros(
+        (num_blocks, num_kv_heads, head_size, block_size),
+        DType::F32,
+        &cpu
+    ));
+    let slot_mapping = try_api!(Tensor::from_vec(slots, num_tokens, &cpu));
+    try_api!(reshape_and_cache(
+        &key,
+        &value,
+        &key_cache,
+        &value_cache,
+        &slot_mapping
+    ));
+    let scale = 1f32 / (head_size as f32).sqrt();
+    let scores = try_api!(paged_attention_scores(
+        &query,
+        &key_cache,
+        &try_api!(Tensor::from_vec(tables.concat(), (2, 2), &cpu)),
+        &try_api!(Tensor::from_vec(
+            context_lens.map(|len| len as u32).to_vec(),
+            2,
+            &cpu
+        )),
+        scale,
+        1f32,
+    ));
+    assert_eq!(scores.dims(), &[2, 20]);
+    let scores = try_api!(scores.to_vec2::<f32>());
+
+    let mut start = 0;
+    for (i, len) in context_lens.into_iter().enumerate() {
+        // (num_heads, len, head_size), query head h attends to kv head h / 2
+        let keys = try_api!(try_api!(key.narrow(0, start, len)).transpose(0, 1));
+        let keys = try_api!(try_api!(try_api!(keys.unsqueeze(1)).broadcast_as((
+            num_kv_heads,
+            num_heads / num_kv_heads,
+            len,
+            head_size
+        )))
+        .reshape((num_heads, len, head_size)));
+        let q = try_api!(try_api!(query.i(i)).unsqueeze(1));
+        let att = try_api!(try_api!(q.matmul(&try_api!(keys.t()))) * scale as f64);
+        let probs = try_api!(candle_nn::ops::softmax_last_dim(&att));
+        let reference = try_api!(try_api!(probs.sum((0, 1))).to_vec1::<f32>());
+        for (t, score) in scores[i].iter().enumerate() {
+            let expected = reference.get(t).copied().unwrap_or(0.);
+            assert!(
+                (score - expected).abs() < 1e-4,
+                "token {t}: {score} != {expected}"
+            );
+        }
+        start += len;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_block_bytes_per_rank() {
+    // 8 KV heads of 128 dims, 32 layers
+    let config: LlamaConfig = serde_json::from_str(
+        r#"{"hidden_size": 4096, "intermediate_size": 14336, "vocab_size": 128256,
+            "num_hidden_layers": 32, "num_attention_heads": 32, "num_key_value_heads": 8,
+            "rms_norm_eps": 1e-5, "bos_token_id": 128000, "eos_token_id": 128009}"#,
+    )
+    .unwrap();
+    let scfg = SpecificConfig::new(None, None, None, None, None, None, None);
+    let config = config.into_config(false, DType::F16, &scfg);
+    let bytes = |tp| CacheEngine::block_bytes(&config, DType::F16, 16, tp);
+    // Keys and values of 8 heads, 16 tokens and 32 layers in f16
+    assert_eq!(bytes(1), 2 * 8 * 128 * 16 * 32 * 2);
+    // The heads are split across the ranks
+    assert_eq!(bytes(2), bytes(1) / 2);
+    assert_eq!(bytes(8), bytes(1) / 8);
+    assert_eq!(CacheEngine::kv_heads_per_rank(&config, 4), 2);
+    // With more ranks than heads, each rank holds one replicated head
+    assert_eq!(CacheEngine::kv_heads_per_rank(&config, 16), 1);
+    assert_eq!(bytes(16), bytes(8));
+}
+
+#[test]
+fn test_encoder_decoder_store() -> Result<(), APIError> {
+    let config: LlamaConfig = serde_json::from_str(
+        r#"{"hidden_size": 64, "intermediate_size": 128, "vocab_size": 64,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 4,
+            "rms_norm_eps": 1e-5, "bos_token_id": 1, "eos_token_id": 2}"#,
+    )
+    .unwrap();
+    let scfg = SpecificConfig::new(None, None, None, None, None, None, None);
+    let config = config.into_config(false, DType::F32, &scfg);
+    let cache_config = CacheConfig {
+        block_size: 16,
+        num_gpu_blocks: Some(1),
+        num_cpu_blocks: Some(1),
+        fully_init: true,
+        dtype: DType::F32,
+        tensor_parallel_size: 1,
+    };
+    let mut cache = CacheEngine::new(config, cache_config, DType::F32, &Device::Cpu)?;
+    // Keys and values of 4 heads of 16 dims for `len` tokens in each of 2 layers
+    let kv = |len: usize| -> Result<Vec<_>, APIError> {
+        (0..2)
+            .map(|_| {
+                let key = try_api!(Tensor::zeros((1, 4, len, 16), DType::F32, &Device::Cpu));
+                Ok((key.clone(), key))
+            })
+            .collect()
+    };
+    cache.set_cross_attention(3, kv(5)?);
+    cache.set_cross_attention(7, kv(9)?);
+    assert_eq!(cache.num_encoder_decoder_seqs(), 2);
+    assert!(cache.take_encoder_decoder(&[3, 4]).is_err());
+
+    // Taken in the order of the sequences, then put back with their decoded tokens
+    let mut taken = cache.take_encoder_decoder(&[7, 3])?;
+    assert_eq!(
+        taken
+            .iter()
+            .map(EncoderDecoderKv::encoder_len)
+            .collect::<Vec<_>>(),
+        [9, 5]
+    );
+    assert_eq!(taken[0].decoder_len(), 0);
+    for seq in taken.iter_mut() {
+        seq.decoder = kv(1)?;
+    }
+    cache.put_encoder_decoder(&[7, 3], taken);
+    let taken = cache.take_encoder_decoder(&[3])?;
+    assert_eq!((taken[0].encoder_len(), taken[0].decoder_len()), (5, 1));
+    cache.put_encoder_decoder(&[3], taken);
+
+    // Finished sequences are dropped
+    cache.retain_encoder_decoder(|seq_id| seq_id != 7);
+    assert_eq!(cache.num_encoder_decoder_seqs(), 1);
+    assert!(cache.take_encoder_decoder(&[7]).is_err());
+    Ok(())
+}
+
+/// Swap bandwidth between host and device, run with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn bench_swap_bandwidth() -> Result<(), APIError> {
+    let gpu = try_api!(Device::new_cuda(0));
+    let num_blocks = 512;
+    let mapping = (0..num_blocks)
+        .map(|idx| (idx, num_blocks - 1 - idx))
+        .collect::<HashMap<_, _>>();
+    for (name, src_device, dst_device) in [
+        ("swap in (cpu -> gpu)", Device::Cpu, gpu.clone()),
+        ("swap out (gpu -> cpu)", gpu.clone(), Device::Cpu),
+        ("gpu -> gpu", gpu.clone(), gpu.clone()),
+    ] {
+        let src = patterned_cache(num_blocks, 1, DType::F16, &src_device)?;
+        let mut dst = patterned_cache(num_blocks, 2, DType::F16, &dst_device)?;
+        let bytes = src.elem_count() * src.dtype().size_in_bytes();
+        let start = Instant::now();
+        swap_blocks(src, &mut dst, mapping.clone())?;
+        try_api!(dst_device.synchronize());
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{name}: {} MB in {:.2} ms ({:.2} GB/s)",
+            bytes / 1024 / 1024,
+            secs * 1e3,
+            bytes as f64 / secs / 1e9
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use candle_vllm::openai::canary::{compare, load_prompts, similarity, ProbeOutput};
+use candle_vllm::openai::metrics::CanaryMetrics;
+
+fn probe(prompt: &str, output: &str) -> ProbeOutput {
+    ProbeOutput::new(prompt.to_string(), output.to_string())
+}
+
+#[test]
+fn test_probe_hash_is_stable() {
+    // FNV-1a test vectors, baselines recorded by other builds must keep matching
+    assert_eq!(probe("p", "").hash, "cbf29ce484222325");
+    assert_eq!(probe("p", "a").hash, "af63dc4c8601ec8c");
+    assert_eq!(probe("p", "foobar").hash, "85944171f73967e8");
+}
+
+#[test]
+fn test_similarity() {
+    assert_eq!(
+        similarity("The capital is Paris.", "The capital is Paris."),
+        1.0
+    );
+    assert_eq!(similarity("abcd", "abxy"), 0.5);
+    assert_eq!(similarity("ab", "abcd"), 0.5);
+    assert_eq!(similarity("abc", "xyz"), 0.0);
+}
+
+#[test]
+fn test_compare_with_baseline() {
+    let baseline = vec![probe("1+1=", "2"), probe("Hello", "Hello world")];
+    let outputs = vec![
+        probe("1+1=", "2"),
+        probe("Hello", "Hello there"),
+        probe("new prompt", "no baseline"),
+    ];
+    let drifts = compare(&baseline, &outputs);
+    assert_eq!(drifts.len(), 1);
+    assert_eq!(drifts[0].prompt, "Hello");
+    assert_eq!(drifts[0].baseline_hash, baseline[1].hash);
+    assert!((drifts[0].similarity - 6.0 / 11.0).abs() < 1e-9);
+    assert!(compare(&baseline, &baseline).is_empty());
+}
+
+#[test]
+fn test_load_prompts() {
+    let dir = std::env::temp_dir().join(format!("canary-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("prompts.json");
+    std::fs::write(&path, r#"["1+1=", "The capital of France is"]"#).unwrap();
+    assert_eq!(load_prompts(&path).unwrap().len(), 2);
+    std::fs::write(&path, "[]").unwrap();
+    assert!(load_prompts(&path).is_err());
+    std::fs::write(&path, "1+1=").unwrap();
+    assert!(load_prompts(&path).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_render_canary_metrics() {
+    assert!(CanaryMetrics::render(&[]).is_empty());
+    let metrics = CanaryMetrics::default();
+    metrics.record_run(0);
+    metrics.record_run(2);
+    metrics.record_failure();
+    let out = CanaryMetrics::render(&[("llama", &metrics)]);
+    assert!(out.contains("candle_vllm_canary_runs_total{model=\"llama\"} 2"));
+    assert!(out.contains("candle_vllm_canary_failures_total{model=\"llama\"} 1"));
+    assert!(out.contains("candle_vllm_canary_drifts_total{model=\"llama\"} 2"));
+    assert!(out.contains("candle_vllm_canary_drifted_probes{model=\"llama\"} 2"));
+    assert!(out.contains("# TYPE candle_vllm_canary_drifted_probes gauge"));
+}
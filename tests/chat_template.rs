@@ -0,0 +1,230 @@
+use candle_vllm::openai::conversation::chat_template::ChatTemplate;
+use candle_vllm::openai::conversation::default_conversation::{
+    DefaultConversation, DefaultConversationSeparators, SeparatorStyle,
+};
+use candle_vllm::openai::conversation::Conversation;
+
+const CHATML: &str = "{% for message in messages %}<|im_start|>{{ message.role }}\n{{ message.content | trim }}<|im_end|>\n{% endfor %}{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}";
+
+fn conversation(sep_style: SeparatorStyle, template: Option<ChatTemplate>) -> DefaultConversation {
+    DefaultConversation::new(
+        "test".to_string(),
+        "[INST] <<SYS>>\n{}\n<</SYS>>\n\n [/INST]".to_string(),
+        Vec::default(),
+        0,
+        sep_style,
+        "".to_string(),
+        vec![],
+        ("user".to_string(), "assistant".to_string()),
+        DefaultConversationSeparators {
+            sep: " ".to_string(),
+            sep2: Some(" </s></s>".to_string()),
+        },
+    )
+    .with_chat_template(template)
+}
+
+fn write_config(name: &str, json: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("chat-template-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, json).unwrap();
+    path
+}
+
+#[test]
+fn test_render_messages() {
+    let template =
+        ChatTemplate::new(CHATML.to_string(), "<s>".to_string(), "</s>".to_string()).unwrap();
+    let prompt = template
+        .render(&[("system", "Be brief."), ("user", " Hi ")], true)
+        .unwrap();
+    assert_eq!(
+        prompt,
+        "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+    );
+}
+
+#[test]
+fn test_special_tokens_and_python_methods() {
+    let template = ChatTemplate::new(
+        "{{ bos_token }}{% for m in messages %}{{ m.content.strip() }}{{ eos_token }}{% endfor %}"
+            .to_string(),
+        "<s>".to_string(),
+        "</s>".to_string(),
+    )
+    .unwrap();
+    assert_eq!(
+        template.render(&[("user", " Hi ")], false).unwrap(),
+        "<s>Hi</s>"
+    );
+}
+
+#[test]
+fn test_raise_exception() {
+    let template = ChatTemplate::new(
+        "{% if messages[0].role != 'user' %}{{ raise_exception('Conversations must start with a user message') }}{% endif %}".to_string(),
+        String::new(),
+        String::new(),
+    )
+    .unwrap();
+    let err = template.render(&[("system", "x")], true).unwrap_err();
+    assert!(err.to_string().contains("must start with a user message"));
+    assert!(ChatTemplate::new("{% if %}".to_string(), String::new(), String::new()).is_err());
+}
+
+#[test]
+fn test_from_tokenizer_config() {
+    let path = write_config(
+        "single.json",
+        &serde_json::json!({
+            "chat_template": CHATML,
+            "bos_token": {"content": "<s>", "lstrip": false},
+            "eos_token": "</s>",
+        })
+        .to_string(),
+    );
+    assert!(ChatTemplate::from_tokenizer_config(&path)
+        .unwrap()
+        .is_some());
+
+    let path = write_config(
+        "named.json",
+        &serde_json::json!({
+            "chat_template": [
+                {"name": "tool_use", "template": "tools"},
+                {"name": "default", "template": "{{ bos_token }}default"},
+            ],
+            "bos_token": "<s>",
+        })
+        .to_string(),
+    );
+    let template = ChatTemplate::from_tokenizer_config(&path).unwrap().unwrap();
+    assert_eq!(template.render(&[], false).unwrap(), "<s>default");
+
+    let path = write_config("none.json", r#"{"bos_token": "<s>"}"#);
+    assert!(ChatTemplate::from_tokenizer_config(&path)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_conversation_uses_template() {
+    let template = ChatTemplate::new(CHATML.to_string(), String::new(), String::new()).unwrap();
+    let mut conversation = conversation(SeparatorStyle::Llama, Some(template));
+    conversation.set_system_message("Be brief.".to_string());
+    // The system prefix alone, then with the turns
+    assert_eq!(
+        conversation.get_prompt(),
+        "<|im_start|>system\nBe brief.<|im_end|>\n"
+    );
+    conversation.append_message("user".to_string(), "Hi".to_string());
+    conversation.append_none_message("assistant".to_string());
+    let prompt = conversation.get_prompt();
+    assert!(prompt.starts_with("<|im_start|>system\nBe brief.<|im_end|>\n"));
+    assert!(prompt.ends_with("<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"));
+}
+
+#[test]
+fn test_conversation_falls_back_on_render_error() {
+    let template = ChatTemplate::new(
+        "{{ raise_exception('unsupported') }}".to_string(),
+        String::new(),
+        String::new(),
+    )
+    .unwrap();
+    let mut with_template = conversation(SeparatorStyle::Llama, Some(template));
+    let mut without_template = conversation(SeparatorStyle::Llama, None);
+    for conversation in [&mut with_template, &mut without_template] {
+        conversation.set_system_message("Be brief.".to_string());
+        conversation.append_message("user".to_string(), "Hi".to_string());
+        conversation.append_none_message("assistant".to_string());
+    }
+    assert_eq!(with_template.get_prompt(), without_template.get_prompt());
+}
+
+#[test]
+fn test_gemma_merges_system_message() {
+    // Gemma templates raise for a system message
+    let template = ChatTemplate::new(
+        "{% for m in messages %}{% if m.role == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}<start_of_turn>{{ m.role }}\n{{ m.content }}<end_of_turn>\n{% endfor %}".to_string(),
+        String::new(),
+        String::new(),
+    )
+    .unwrap();
+    let mut with_template = conversation(SeparatorStyle::Gemma, Some(template));
+    let mut without_template = conversation(SeparatorStyle::Gemma, None);
+    for conversation in [&mut with_template, &mut without_template] {
+        conversation.set_system_message("Be brief.".to_string());
+        conversation.append_message("user".to_string(), "Hi".to_string());
+        conversation.append_message("assistant".to_string(), "Hello".to_string());
+        conversation.append_message("user".to_string(), "Bye".to_string());
+    }
+    assert_eq!(
+        with_template.get_prompt(),
+        "<start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n<start_of_turn>assistant\nHello<end_of_turn>\n<start_of_turn>user\nBye<end_of_turn>\n"
+    );
+    let prompt = without_template.get_prompt();
+    assert!(prompt.starts_with("<bos><start_of_turn>user\n Be brief.\n\nHi <end_of_turn>\n"));
+    assert_eq!(prompt.matches("Be brief.").count(), 1);
+}
+
+#[test]
+fn test_t5_plain_prompt() {
+    let mut conversation = conversation(SeparatorStyle::T5, None);
+    conversation.append_message("user".to_string(), "Translate to German: Hello".to_string());
+    conversation.append_none_message("assistant".to_string());
+    assert_eq!(conversation.get_prompt(), "Translate to German: Hello");
+    conversation.set_system_message("Answer briefly.".to_string());
+    assert_eq!(
+        conversation.get_prompt(),
+        "Answer briefly.\nTranslate to German: Hello"
+    );
+}
+
+/// A conversation scored by a reward model ends with its assistant turn.
+#[test]
+fn test_scored_prompt_opens_no_turn() {
+    let template =
+        ChatTemplate::new(CHATML.to_string(), "<s>".to_string(), "</s>".to_string()).unwrap();
+    let mut with_template = conversation(SeparatorStyle::ChatML, Some(template));
+    let mut without_template = conversation(SeparatorStyle::ChatML, None);
+    for conversation in [&mut with_template, &mut without_template] {
+        conversation.append_message("user".to_string(), "Hi".to_string());
+        conversation.append_message("assistant".to_string(), "Hello".to_string());
+    }
+    assert_eq!(
+        with_template.get_scored_prompt(),
+        "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello<|im_end|>\n"
+    );
+    // The generation prompt is still opened for chat
+    assert!(with_template
+        .get_prompt()
+        .ends_with("<|im_start|>assistant\n"));
+    // The built-in templates only open a turn for a message without content
+    assert_eq!(
+        without_template.get_scored_prompt(),
+        without_template.get_prompt()
+    );
+}
+
+#[test]
+fn test_snapshot_renders_without_the_conversation() {
+    let template = ChatTemplate::new(CHATML.to_string(), String::new(), String::new()).unwrap();
+    let mut conversation = conversation(SeparatorStyle::Llama, Some(template));
+    conversation.set_system_message("Be brief.".to_string());
+    let mut prefix = conversation.snapshot();
+    conversation.append_message("user".to_string(), "Hi".to_string());
+    let mut snapshot = conversation.snapshot();
+    // Later changes of the conversation do not reach the snapshots
+    conversation.clear_message();
+    conversation.set_system_message(String::new());
+    let (prompt, prefix) = std::thread::spawn(move || (snapshot.get_prompt(), prefix.get_prompt()))
+        .join()
+        .unwrap();
+    assert_eq!(prefix, "<|im_start|>system\nBe brief.<|im_end|>\n");
+    assert_eq!(
+        prompt,
+        "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+    );
+}
@@ -0,0 +1,301 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use axum::{
+    http::StatusCode,
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    routing::post,
+    Json, Router,
+};
+use candle_vllm::{
+    client::{ChatCompletionRequestBuilder, Client, ClientError},
+    openai::{
+        compression::{compression_layer, DEFAULT_MIN_COMPRESSED_BYTES},
+        openai_server,
+        requests::{
+            ChatCompletionRequest, EmbeddingInput, EmbeddingRequest, ForkRequest, Messages,
+        },
+        responses::{
+            ChatChoice, ChatChoiceData, ChatCompletionChunk, ChatCompletionResponse,
+            ChatCompletionUsageResponse, Choice, ChoiceData, EmbeddingVector,
+        },
+    },
+};
+use common::{server_data, MockEngine};
+use futures::{stream, StreamExt};
+use std::convert::Infallible;
+use std::sync::Arc;
+
+fn usage() -> ChatCompletionUsageResponse {
+    ChatCompletionUsageResponse {
+        request_id: "cmpl-test".to_string(),
+        created: 0,
+        completion_tokens: 2,
+        prompt_tokens: 3,
+        total_tokens: 5,
+        prompt_time_costs: 0,
+        completion_time_costs: 0,
+        prompt_tokens_details: None,
+        energy: None,
+    }
+}
+
+fn chunk(content: &str) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: "cmpl-test".to_string(),
+        choices: vec![Choice {
+            delta: ChoiceData {
+                content: Some(content.to_string()),
+                role: "assistant".to_string(),
+                tool_calls: None,
+            },
+            finish_reason: None,
+            index: 0,
+        }],
+        created: 0,
+        model: "test".to_string(),
+        object: "chat.completion.chunk".to_string(),
+        system_fingerprint: None,
+        usage: None,
+    }
+}
+
+/// Echoes the last message, streamed as one chunk per word.
+async fn chat_completions(Json(request): Json<ChatCompletionRequest>) -> Response {
+    let Messages::Map(messages) = &request.messages else {
+        unreachable!()
+    };
+    let content = messages.last().unwrap()["content"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    if request.stream == Some(true) {
+        let mut events = vec![Event::default().comment("keep-alive-text")];
+        for (i, word) in content.split(' ').enumerate() {
+            let word = if i == 0 {
+                word.to_string()
+            } else {
+                format!(" {word}")
+            };
+            events.push(Event::default().json_data(chunk(&word)).unwrap());
+        }
+        events.push(Event::default().data("[DONE]"));
+        let events = stream::iter(events.into_iter().map(Ok::<_, Infallible>));
+        return Sse::new(events).into_response();
+    }
+    Json(ChatCompletionResponse {
+        id: "cmpl-test".to_string(),
+        choices: vec![ChatChoice {
+            message: ChatChoiceData {
+                content: Some(content),
+                role: "assistant".to_string(),
+                tool_calls: None,
+            },
+            finish_reason: Some("stop".to_string()),
+            index: 0,
+            logprobs: None,
+        }],
+        created: 0,
+        model: request.model,
+        object: "chat.completion".to_string(),
+        system_fingerprint: None,
+        usage: usage(),
+        prompt_logprobs: None,
+    })
+    .into_response()
+}
+
+async fn fork() -> Response {
+    let error = serde_json::json!({"message": "Request cmpl-missing cannot be forked."});
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(error)).into_response()
+}
+
+/// Base url of a mock server with response compression.
+async fn serve_url() -> String {
+    let app = Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/chat/completions/fork", post(fork))
+        .layer(compression_layer(DEFAULT_MIN_COMPRESSED_BYTES));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{addr}/")
+}
+
+async fn serve() -> Client {
+    Client::new(serve_url().await)
+}
+
+/// A client of the server serving the mock model.
+async fn serve_mock_model() -> Client {
+    let served = MockEngine::default().serve().await.unwrap();
+    let app = Router::new()
+        .route(
+            "/v1/chat/completions",
+            post(openai_server::chat_completions),
+        )
+        .route("/v1/embeddings", post(openai_server::embeddings))
+        .with_state(Arc::new(server_data(served)));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    Client::new(format!("http://{addr}/"))
+}
+
+#[test]
+fn test_request_builder() {
+    let request = ChatCompletionRequestBuilder::new("llama")
+        .system("You are a helpful assistant.")
+        .user("Hello")
+        .max_tokens(16)
+        .stop(vec!["\n".to_string()])
+        .build();
+    let json = serde_json::to_value(&request).unwrap();
+    assert_eq!(json["model"], "llama");
+    assert_eq!(json["messages"][0]["role"], "system");
+    assert_eq!(json["messages"][1]["content"], "Hello");
+    assert_eq!(json["max_tokens"], 16);
+    assert!(json["temperature"].is_null());
+
+    // The server parses what the builder produces
+    let parsed: ChatCompletionRequest = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed.max_tokens, Some(16));
+}
+
+#[tokio::test]
+async fn test_chat_completion() -> Result<(), ClientError> {
+    let client = serve().await;
+    let request = ChatCompletionRequestBuilder::new("llama")
+        .user("Hello world")
+        .build();
+    let response = client.chat_completion(&request).await?;
+    assert_eq!(response.model, "llama");
+    assert_eq!(
+        response.choices[0].message.content.as_deref(),
+        Some("Hello world")
+    );
+    assert_eq!(response.usage.total_tokens, 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chat_completion_stream() -> Result<(), ClientError> {
+    let client = serve().await;
+    let request = ChatCompletionRequestBuilder::new("llama")
+        .user("Hello streaming world")
+        .build();
+    let mut stream = client.chat_completion_stream(&request).await?;
+    let mut contents = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        contents.push(chunk?.choices[0].delta.content.clone().unwrap());
+    }
+    assert_eq!(contents, ["Hello", " streaming", " world"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_error() {
+    let client = serve().await;
+    let request = ForkRequest {
+        request_id: "cmpl-missing".to_string(),
+        model: None,
+        n: Some(2),
+        num_tokens: None,
+        max_tokens: None,
+        temperature: None,
+        top_p: None,
+        top_k: None,
+        forkable: None,
+    };
+    match client.fork(&request).await {
+        Err(ClientError::Api { status, message }) => {
+            assert_eq!(status, 422);
+            assert_eq!(message, "Request cmpl-missing cannot be forked.");
+        }
+        other => panic!("expected an API error, got {:?}", other.map(|r| r.id)),
+    }
+}
+
+#[tokio::test]
+async fn test_openai_api_error() {
+    let client = serve_mock_model().await;
+    let request = ChatCompletionRequestBuilder::new("mock")
+        .user("Hello world")
+        .max_tokens(8192)
+        .build();
+    match client.chat_completion(&request).await {
+        Err(ClientError::Api { status, message }) => {
+            assert_eq!(status, 400);
+            assert!(
+                message.starts_with("This model's maximum context length is 4096 tokens."),
+                "{message}"
+            );
+        }
+        other => panic!("expected an API error, got {:?}", other.map(|r| r.id)),
+    }
+}
+
+#[tokio::test]
+async fn test_embeddings() -> Result<(), ClientError> {
+    let client = serve_mock_model().await;
+    let float = |vector: &EmbeddingVector| match vector {
+        EmbeddingVector::Float(embedding) => embedding.clone(),
+        EmbeddingVector::Base64(_) => panic!("expected a float embedding"),
+    };
+    // The mock model embeds the normalized counts of the tokens, a token per byte
+    let request = EmbeddingRequest {
+        model: "mock".to_string(),
+        input: EmbeddingInput::Multi(vec!["a".to_string(), "abc".to_string()]),
+        encoding_format: None,
+        dimensions: None,
+        user: None,
+        pooling: None,
+    };
+    let response = client.embeddings(&request).await?;
+    assert_eq!(response.model, "mock");
+    assert_eq!(response.data.len(), 2);
+    assert_eq!(response.usage.prompt_tokens, 4);
+    let embedding = float(&response.data[1].embedding);
+    assert_eq!(embedding.len(), 1024);
+    for (i, x) in embedding.iter().enumerate() {
+        let expected = if (97..100).contains(&i) {
+            1. / 3f32.sqrt()
+        } else {
+            0.
+        };
+        assert!((x - expected).abs() < 1e-6, "{i}: {x}");
+    }
+
+    // The truncated prefix is normalized again, a prefix of zeros stays zero
+    let request = EmbeddingRequest {
+        input: EmbeddingInput::Multi(vec!["ab".to_string(), "b".to_string()]),
+        dimensions: Some(98),
+        ..request
+    };
+    let response = client.embeddings(&request).await?;
+    let embedding = float(&response.data[0].embedding);
+    assert_eq!(embedding.len(), 98);
+    assert!((embedding[97] - 1.).abs() < 1e-6);
+    assert!(float(&response.data[1].embedding).iter().all(|&x| x == 0.));
+    Ok(())
+}
+
+/// The client negotiates the compression and decodes the response.
+#[tokio::test]
+async fn test_compressed_response() -> Result<(), ClientError> {
+    let url = serve_url().await;
+    let long = ["token"; 1000].join(" ");
+    let request = ChatCompletionRequestBuilder::new("llama")
+        .user(&long)
+        .build();
+    let response = Client::new(url).chat_completion(&request).await?;
+    assert_eq!(
+        response.choices[0].message.content.as_deref(),
+        Some(long.as_str())
+    );
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! The mock model engine and server shared by the integration tests.
+#![allow(dead_code)]
+
+use candle_core::{DType, Device};
+use candle_vllm::{
+    get_model_loader,
+    openai::{
+        metrics::UserMetrics,
+        pipelines::{llm_engine::LLMEngine, LoadOptions},
+        plugins::PluginHost,
+        responses::APIError,
+        OpenAIServerData, PipelineConfig, PromptLogging, ServedModel,
+    },
+    scheduler::{cache_engine::CacheConfig, SchedulerConfig, SchedulingPolicy},
+    ModelSelected,
+};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+
+/// An engine serving the mock model on the CPU, the defaults fit most tests.
+pub struct MockEngine {
+    /// The reply of the model, it echoes the prompt by default
+    pub response: Option<String>,
+    /// Time to generate each token
+    pub latency_ms: u64,
+    /// Context of the model, in tokens
+    pub max_model_len: usize,
+    pub recurrent: bool,
+    pub max_num_seqs: usize,
+    pub num_gpu_blocks: usize,
+    pub num_cpu_blocks: usize,
+}
+
+impl Default for MockEngine {
+    fn default() -> Self {
+        Self {
+            response: None,
+            latency_ms: 0,
+            max_model_len: 4096,
+            recurrent: false,
+            max_num_seqs: 4,
+            num_gpu_blocks: 64,
+            num_cpu_blocks: 16,
+        }
+    }
+}
+
+impl MockEngine {
+    /// A mock model that answers `response`.
+    pub fn replying(response: &str) -> Self {
+        Self {
+            response: Some(response.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The engine, the config of its pipeline and the name of its model.
+    pub fn load(self) -> Result<(Arc<Mutex<LLMEngine>>, PipelineConfig, String), APIError> {
+        let (loader, model_id) = get_model_loader(
+            ModelSelected::Mock {
+                response: self.response,
+                prefill_latency_ms: 0,
+                latency_ms: self.latency_ms,
+                max_model_len: self.max_model_len,
+                max_gen_tokens: None,
+                recurrent: self.recurrent,
+            },
+            None,
+        );
+        let paths = loader.download_model(model_id, None, None, None, None, None)?;
+        let model = loader.load_model(paths, LoadOptions::new(DType::F16, Device::Cpu))?;
+        let model_name = model.0.name().to_string();
+        let llm_engine = LLMEngine::new(
+            model.0,
+            SchedulerConfig {
+                max_num_seqs: self.max_num_seqs,
+                max_num_batched_tokens: 4096,
+                kv_eviction: None,
+                num_lookahead_slots: 0,
+                policy: SchedulingPolicy::Fcfs,
+            },
+            CacheConfig {
+                block_size: 16,
+                num_gpu_blocks: Some(self.num_gpu_blocks),
+                num_cpu_blocks: Some(self.num_cpu_blocks),
+                fully_init: true,
+                dtype: DType::F16,
+                tensor_parallel_size: 1,
+            },
+        )?;
+        Ok((llm_engine, model.1, model_name))
+    }
+
+    pub fn engine(self) -> Result<Arc<Mutex<LLMEngine>>, APIError> {
+        Ok(self.load()?.0)
+    }
+
+    /// The engine served under the name of its model.
+    pub async fn serve(self) -> Result<ServedModel, APIError> {
+        let (llm_engine, pipeline_config, model_name) = self.load()?;
+        Ok(ServedModel::new(llm_engine, pipeline_config, model_name, None).await)
+    }
+}
+
+/// The server data of `served` alone, on the CPU and without any of the optional features.
+pub fn server_data(served: ServedModel) -> OpenAIServerData {
+    OpenAIServerData {
+        models: RwLock::new(vec![Arc::new(served)]),
+        record_conversation: false,
+        default_system_prompt: None,
+        device: Device::Cpu,
+        log_prompts: PromptLogging::Off,
+        user_metrics: Arc::new(UserMetrics::default()),
+        plugins: PluginHost::default(),
+        max_waiting_requests: None,
+        request_timeout: None,
+        rate_limiter: None,
+        sessions: None,
+        quality_router: None,
+        reloader: None,
+    }
+}
@@ -0,0 +1,40 @@
+use candle_vllm::openai::pipelines::download::DownloadProgress;
+use hf_hub::api::Progress;
+use indicatif::ProgressBar;
+
+#[test]
+fn test_download_progress() {
+    let mut progress = DownloadProgress::new(ProgressBar::hidden());
+    progress.init(1000, "model-00001-of-00002.safetensors");
+    // Nothing on disk yet
+    progress.update(0);
+    assert_eq!(progress.resumed_from(), 0);
+    progress.update(250);
+    progress.update(250);
+    assert_eq!(progress.downloaded(), 500);
+    assert_eq!(progress.percent(), 50);
+    progress.update(500);
+    progress.finish();
+    assert_eq!(progress.percent(), 100);
+}
+
+#[test]
+fn test_download_progress_resume() {
+    let mut progress = DownloadProgress::new(ProgressBar::hidden());
+    progress.init(1000, "model.safetensors");
+    // The bytes of the `.part` file are reported first
+    progress.update(600);
+    assert_eq!(progress.resumed_from(), 600);
+    assert_eq!(progress.percent(), 60);
+    progress.update(100);
+    assert_eq!(progress.downloaded(), 700);
+
+    // A retry restarts from the bytes on disk
+    progress.init(1000, "model.safetensors");
+    progress.update(700);
+    assert_eq!(progress.resumed_from(), 700);
+    assert_eq!(progress.downloaded(), 700);
+    progress.update(300);
+    progress.finish();
+    assert_eq!(progress.percent(), 100);
+}
@@ -0,0 +1,33 @@
+use candle_vllm::openai::energy::{attribute, average_power};
+use candle_vllm::openai::metrics::EnergyMetrics;
+use std::time::Duration;
+
+#[test]
+fn test_attribute_by_token_share() {
+    // A prefill of 30 tokens batched with a decoding step of one token
+    let shares = attribute(62.0, &[30, 1]);
+    assert_eq!(shares, vec![60.0, 2.0]);
+    assert_eq!(attribute(5.0, &[0, 0]), vec![0.0, 0.0]);
+    assert!(attribute(5.0, &[]).is_empty());
+}
+
+#[test]
+fn test_average_power() {
+    assert_eq!(average_power(300.0, Duration::from_secs(2)), 150.0);
+    assert_eq!(average_power(1.0, Duration::ZERO), 0.0);
+}
+
+#[test]
+fn test_render_energy_metrics() {
+    assert!(EnergyMetrics::render(&[]).is_empty());
+    let metrics = EnergyMetrics::default();
+    metrics.record(true, 12.5, Duration::from_millis(50));
+    metrics.record(false, 3.0, Duration::from_millis(20));
+    metrics.record(false, 3.0, Duration::from_millis(20));
+    let out = EnergyMetrics::render(&[("llama", &metrics)]);
+    assert!(
+        out.contains("candle_vllm_gpu_energy_joules_total{model=\"llama\",phase=\"prefill\"} 12.5")
+    );
+    assert!(out.contains("candle_vllm_gpu_energy_joules_total{model=\"llama\",phase=\"decode\"} 6"));
+    assert!(out.contains("candle_vllm_gpu_power_watts{model=\"llama\"} 150"));
+}
@@ -0,0 +1,53 @@
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use candle_vllm::openai::responses::{APIError, ChatResponder, OpenAIError};
+
+async fn body(response: impl IntoResponse) -> (StatusCode, OpenAIError) {
+    let response = response.into_response();
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[tokio::test]
+async fn test_errors_use_the_openai_envelope() {
+    let (status, error) = body(APIError::new_str("The forward pass failed.")).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(error.error.message, "The forward pass failed.");
+    assert_eq!(error.error.error_type, "server_error");
+    assert_eq!(error.error.param, None);
+    assert_eq!(error.error.code, None);
+
+    let invalid = APIError::invalid_request("`n` must be at least 1.").with_param("n");
+    let (status, error) = body(invalid).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error.error.error_type, "invalid_request_error");
+    assert_eq!(error.error.param.as_deref(), Some("n"));
+}
+
+#[tokio::test]
+async fn test_responders_classify_their_errors() {
+    let validation = ChatResponder::ValidationError(APIError::new_str("Bad stop token."));
+    let (status, error) = body(validation).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error.error.error_type, "invalid_request_error");
+
+    let not_found = ChatResponder::ModelNotFound(APIError::new_str("No model gpt-4."));
+    let (status, error) = body(not_found).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error.error.param.as_deref(), Some("model"));
+    assert_eq!(error.error.code.as_deref(), Some("model_not_found"));
+
+    let too_long = ChatResponder::ContextLengthExceeded(APIError::new_str("Too long."));
+    let (status, error) = body(too_long).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error.error.param.as_deref(), Some("messages"));
+    assert_eq!(error.error.code.as_deref(), Some("context_length_exceeded"));
+
+    // Engine failures keep their status
+    let (status, error) = body(ChatResponder::ModelError(APIError::new_str("OOM."))).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(error.error.error_type, "server_error");
+}
@@ -0,0 +1,36 @@
+#![cfg(feature = "fault-injection")]
+
+use candle_vllm::openai::{
+    responses::ChatCompletionChunk,
+    streaming::{CancelFlag, ChatResponse, Streamer},
+};
+use futures::StreamExt;
+
+fn chunk() -> ChatResponse {
+    ChatResponse::Chunk(ChatCompletionChunk {
+        id: "cmpl-fault".to_string(),
+        choices: vec![],
+        created: 0,
+        model: "llama".to_string(),
+        object: "chat.completion.chunk".to_string(),
+        system_fingerprint: None,
+        usage: None,
+    })
+}
+
+#[tokio::test]
+async fn test_dropped_stream_cancels_request() {
+    std::env::set_var("CANDLE_VLLM_FAULT_DROP_STREAM_AFTER", "2");
+    let (tx, rx) = flume::unbounded();
+    for _ in 0..3 {
+        tx.send(chunk()).unwrap();
+    }
+    let cancel = CancelFlag::default();
+    let mut streamer = Streamer::new(rx, cancel.clone());
+    assert!(streamer.next().await.is_some());
+    assert!(streamer.next().await.is_some());
+    assert!(!cancel.is_cancelled());
+    // The third chunk is dropped with the connection
+    assert!(streamer.next().await.is_none());
+    assert!(cancel.is_cancelled());
+}
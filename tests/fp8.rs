@@ -0,0 +1,119 @@
+use candle_core::{DType, Device, Module, Tensor};
+use candle_vllm::backend::{fp8_dequantize, fp8_e4m3_to_f32};
+use candle_vllm::openai::models::linear::{linear_no_bias_x, QuantizationConfig};
+use candle_vllm::openai::pipelines::weights;
+use std::path::PathBuf;
+
+#[test]
+fn test_fp8_e4m3_values() {
+    assert_eq!(fp8_e4m3_to_f32(0x00), 0.);
+    assert_eq!(fp8_e4m3_to_f32(0x38), 1.);
+    assert_eq!(fp8_e4m3_to_f32(0x40), 2.);
+    assert_eq!(fp8_e4m3_to_f32(0x3C), 1.5);
+    assert_eq!(fp8_e4m3_to_f32(0xB8), -1.);
+    // The largest value, and the smallest subnormal
+    assert_eq!(fp8_e4m3_to_f32(0x7E), 448.);
+    assert_eq!(fp8_e4m3_to_f32(0x01), 2f32.powi(-9));
+    assert!(fp8_e4m3_to_f32(0x7F).is_nan());
+    assert!(fp8_e4m3_to_f32(0xFF).is_nan());
+}
+
+#[test]
+fn test_fp8_dequantize_scales() -> candle_core::Result<()> {
+    let device = Device::Cpu;
+    // 1, 2, -1, 1.5 in each row
+    let weight = Tensor::from_vec(
+        vec![0x38u8, 0x40, 0xB8, 0x3C, 0x38, 0x40, 0xB8, 0x3C],
+        (2, 4),
+        &device,
+    )?;
+
+    let per_tensor = Tensor::new(&[0.5f32], &device)?;
+    let out = fp8_dequantize(&weight, &per_tensor, DType::F32)?;
+    assert_eq!(
+        out.to_vec2::<f32>()?,
+        vec![vec![0.5, 1., -0.5, 0.75], vec![0.5, 1., -0.5, 0.75]]
+    );
+
+    let per_channel = Tensor::new(&[[1f32], [4.]], &device)?;
+    let out = fp8_dequantize(&weight, &per_channel, DType::BF16)?;
+    assert_eq!(out.dtype(), DType::BF16);
+    assert_eq!(
+        out.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        vec![vec![1., 2., -1., 1.5], vec![4., 8., -4., 6.]]
+    );
+
+    assert!(fp8_dequantize(&weight, &Tensor::ones(3, DType::F32, &device)?, DType::F32).is_err());
+    assert!(fp8_dequantize(&weight.to_dtype(DType::F32)?, &per_tensor, DType::F32).is_err());
+    Ok(())
+}
+
+/// A safetensors file with a `(2, 4)` FP8 weight and its per-channel scale, candle cannot write
+/// FP8 tensors.
+fn fp8_checkpoint(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("candle-vllm-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let weight = [0x38u8, 0x40, 0xB8, 0x3C, 0x38, 0x40, 0xB8, 0x3C];
+    let scale = [1f32.to_le_bytes(), 4f32.to_le_bytes()].concat();
+    let header = serde_json::json!({
+        "proj.weight": {"dtype": "F8_E4M3", "shape": [2, 4], "data_offsets": [0, 8]},
+        "proj.weight_scale": {"dtype": "F32", "shape": [2, 1], "data_offsets": [8, 16]},
+    })
+    .to_string();
+    let mut file = (header.len() as u64).to_le_bytes().to_vec();
+    file.extend(header.as_bytes());
+    file.extend(weight);
+    file.extend(scale);
+    let path = dir.join("model.safetensors");
+    std::fs::write(&path, file).unwrap();
+    path
+}
+
+#[test]
+fn test_fp8_linear_from_checkpoint() -> candle_core::Result<()> {
+    let paths = vec![fp8_checkpoint("fp8-linear")];
+    let vb = unsafe { weights::var_builder(&paths, DType::F32, &[], &Device::Cpu)? };
+    // The weight is quantized, it cannot be read as a plain tensor
+    assert!(vb.get((2, 4), "proj.weight").is_err());
+
+    let proj = linear_no_bias_x(4, 2, vb.pp("proj"), &None)?;
+    let x = Tensor::new(&[[1f32, 1., 1., 1.], [1., 0., 0., 0.]], &Device::Cpu)?;
+    assert_eq!(
+        proj.forward(&x)?.to_vec2::<f32>()?,
+        vec![vec![3.5, 14.], vec![1., 4.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_quantization_config() {
+    let config = |json: serde_json::Value| {
+        serde_json::from_value::<QuantizationConfig>(json)
+            .unwrap()
+            .validate()
+    };
+    assert!(
+        config(serde_json::json!({"quant_method": "fp8", "activation_scheme": "dynamic"})).is_ok()
+    );
+    assert!(
+        config(serde_json::json!({"quant_method": "fp8", "weight_block_size": [128, 128]}))
+            .is_err()
+    );
+
+    let compressed = |num_bits: usize, kind: &str, strategy: &str| {
+        config(serde_json::json!({
+            "quant_method": "compressed-tensors",
+            "config_groups": {"group_0": {
+                "targets": ["Linear"],
+                "weights": {"num_bits": num_bits, "type": kind, "strategy": strategy},
+                "input_activations": {"num_bits": 8, "type": "float", "strategy": "token", "dynamic": true},
+            }},
+        }))
+    };
+    assert!(compressed(8, "float", "channel").is_ok());
+    assert!(compressed(8, "float", "tensor").is_ok());
+    assert!(compressed(8, "float", "block").is_err());
+    assert!(compressed(4, "int", "group").is_err());
+    assert!(config(serde_json::json!({"quant_method": "gptq"})).is_err());
+}
@@ -0,0 +1,63 @@
+use candle_core::DType;
+use candle_vllm::openai::models::llama::LlamaConfig;
+use candle_vllm::openai::models::{Config, GenerationConfig};
+use candle_vllm::SpecificConfig;
+
+/// A small Llama config with the `bos_token_id` and `eos_token_id` (JSON) of its `config.json`.
+fn llama_config(bos_token_id: &str, eos_token_id: &str) -> Config {
+    let config: LlamaConfig = serde_json::from_str(&format!(
+        r#"{{"hidden_size": 64, "intermediate_size": 128, "vocab_size": 128256,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-5, "rope_theta": 500000.0, "bos_token_id": {bos_token_id},
+            "eos_token_id": {eos_token_id}, "max_position_embeddings": 4096}}"#
+    ))
+    .unwrap();
+    let scfg = SpecificConfig::new(None, None, None, None, None, None, None);
+    config.into_config(false, DType::F16, &scfg)
+}
+
+#[test]
+fn test_llama31_eos_tokens() {
+    // The generation config of Llama 3.1 Instruct
+    let generation: GenerationConfig = serde_json::from_str(
+        r#"{"bos_token_id": 128000, "do_sample": true,
+            "eos_token_id": [128001, 128008, 128009], "temperature": 0.6, "top_p": 0.9,
+            "transformers_version": "4.42.3"}"#,
+    )
+    .unwrap();
+    assert_eq!(generation.pad_token_id.map(|id| id.ids()), None);
+
+    let mut config = llama_config("128000", "128001");
+    config.merge_generation_config(&generation);
+    assert_eq!(config.eos_token_id.ids(), vec![128001, 128008, 128009]);
+    assert_eq!(config.bos_token_id.ids(), vec![128000]);
+
+    // The EOS tokens of both configs are kept, once
+    let mut config = llama_config("128000", "[128009, 128256]");
+    config.merge_generation_config(&generation);
+    assert_eq!(
+        config.eos_token_id.ids(),
+        vec![128009, 128256, 128001, 128008]
+    );
+}
+
+#[test]
+fn test_partial_generation_config() {
+    let generation: GenerationConfig =
+        serde_json::from_str(r#"{"bos_token_id": 1, "pad_token_id": 0}"#).unwrap();
+    assert_eq!(generation.pad_token_id.map(|id| id.ids()), Some(vec![0]));
+
+    // The BOS token of the model config wins, its EOS token is left as is
+    let mut config = llama_config("2", "3");
+    config.merge_generation_config(&generation);
+    assert_eq!(config.bos_token_id.ids(), vec![2]);
+    assert_eq!(config.eos_token_id.ids(), vec![3]);
+
+    let mut config = llama_config("null", "3");
+    config.merge_generation_config(&generation);
+    assert_eq!(config.bos_token_id.ids(), vec![1]);
+
+    let mut config = llama_config("1", "3");
+    config.merge_generation_config(&GenerationConfig::default());
+    assert_eq!(config.eos_token_id.ids(), vec![3]);
+}
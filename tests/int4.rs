@@ -0,0 +1,134 @@
+use candle_core::{DType, Device, Module, Tensor};
+use candle_vllm::backend::{
+    dequantize_4bit, dequantize_absmax, quantize_4bit, quantize_absmax, Codebook4Bit,
+    ABSMAX_GROUP_SIZE, BLOCK_SIZE_4BIT,
+};
+use candle_vllm::openai::models::linear::{Linear, Linear4Bit};
+
+#[test]
+fn test_codebook_values() {
+    for codebook in [Codebook4Bit::Nf4, Codebook4Bit::Int4] {
+        let values = codebook.values();
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(values.contains(&0.) && values.contains(&1.) && values.contains(&-1.));
+    }
+    assert_eq!(Codebook4Bit::Int4.values()[9], 1. / 7.);
+}
+
+#[test]
+fn test_quantize_4bit_codebook_values() -> candle_core::Result<()> {
+    let device = Device::Cpu;
+    for codebook in [Codebook4Bit::Nf4, Codebook4Bit::Int4] {
+        // Each block scaled by 3 holds the values of the codebook, which round-trip exactly
+        let values = codebook.values();
+        let block = (0..BLOCK_SIZE_4BIT)
+            .map(|i| values[1 + i % 15] * 3.)
+            .collect::<Vec<_>>();
+        let weight = Tensor::new(block.as_slice(), &device)?
+            .repeat(2)?
+            .reshape((2, BLOCK_SIZE_4BIT))?;
+        let (codes, absmax) = quantize_4bit(&weight, codebook)?;
+        assert_eq!(codes.dtype(), DType::U8);
+        assert_eq!(codes.elem_count(), BLOCK_SIZE_4BIT);
+        assert_eq!(absmax.to_vec1::<f32>()?, vec![3., 3.]);
+        // The first weight of a byte is in the high bits
+        let first = codes.to_vec1::<u8>()?[0];
+        assert_eq!((first >> 4, first & 0xF), (1, 2));
+
+        let out = dequantize_4bit(&codes, &absmax, codebook, (2, BLOCK_SIZE_4BIT), DType::F32)?;
+        let diff = (out - &weight)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-6, "{codebook:?}: {diff}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_quantize_4bit_error() -> candle_core::Result<()> {
+    let device = Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (16, 4 * BLOCK_SIZE_4BIT), &device)?;
+    for codebook in [Codebook4Bit::Nf4, Codebook4Bit::Int4] {
+        let (codes, absmax) = quantize_4bit(&weight, codebook)?;
+        let out = dequantize_4bit(&codes, &absmax, codebook, weight.shape(), DType::BF16)?;
+        assert_eq!(out.dtype(), DType::BF16);
+        // At most half the largest gap between values (0.30 for NF4) times the scale of the block
+        let error = (out.to_dtype(DType::F32)? - &weight)?.abs()?;
+        let bound = absmax
+            .reshape((16, 4, 1))?
+            .broadcast_as((16, 4, BLOCK_SIZE_4BIT))?
+            .reshape(weight.shape())?
+            .affine(0.17, 0.)?;
+        assert_eq!(error.le(&bound)?.min_all()?.to_scalar::<u8>()?, 1);
+    }
+    // Blocks of zeros stay zeros
+    let zeros = Tensor::zeros(BLOCK_SIZE_4BIT, DType::F16, &device)?;
+    let (codes, absmax) = quantize_4bit(&zeros, Codebook4Bit::Nf4)?;
+    let out = dequantize_4bit(
+        &codes,
+        &absmax,
+        Codebook4Bit::Nf4,
+        BLOCK_SIZE_4BIT,
+        DType::F16,
+    )?;
+    assert_eq!(
+        out.to_dtype(DType::F32)?.to_vec1::<f32>()?,
+        vec![0.; BLOCK_SIZE_4BIT]
+    );
+
+    assert!(quantize_4bit(&Tensor::zeros(10, DType::F32, &device)?, Codebook4Bit::Nf4).is_err());
+    assert!(dequantize_4bit(
+        &codes,
+        &absmax,
+        Codebook4Bit::Nf4,
+        2 * BLOCK_SIZE_4BIT,
+        DType::F32
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_double_quantization() -> candle_core::Result<()> {
+    let device = Device::Cpu;
+    // More than one group, the last one partial
+    let len = ABSMAX_GROUP_SIZE + 10;
+    let absmax = Tensor::rand(0f32, 2., len, &device)?;
+    let (quantized, scale) = quantize_absmax(&absmax)?;
+    assert_eq!(quantized.dtype(), DType::U8);
+    assert_eq!(quantized.elem_count(), len);
+    assert_eq!(scale.elem_count(), 2);
+    let out = dequantize_absmax(&quantized, &scale)?;
+    let error = (out - &absmax)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(error <= 2. / 255. / 2. + 1e-6, "{error}");
+    Ok(())
+}
+
+#[test]
+fn test_linear_4bit() -> candle_core::Result<()> {
+    let device = Device::Cpu;
+    assert_eq!(Linear4Bit::parse("nf4"), Some((Codebook4Bit::Nf4, false)));
+    assert_eq!(
+        Linear4Bit::parse("int4_dq"),
+        Some((Codebook4Bit::Int4, true))
+    );
+    assert_eq!(Linear4Bit::parse("q4k"), None);
+
+    let weight = Tensor::randn(0f32, 1., (8, 2 * BLOCK_SIZE_4BIT), &device)?;
+    let bias = Tensor::randn(0f32, 1., 8, &device)?;
+    let x = Tensor::randn(0f32, 1., (3, 2 * BLOCK_SIZE_4BIT), &device)?;
+    let expected = Linear::new(weight.clone(), Some(bias.clone())).forward(&x)?;
+    for double_quant in [false, true] {
+        let ln = Linear4Bit::quantize(
+            Linear::new(weight.clone(), Some(bias.clone())),
+            Codebook4Bit::Nf4,
+            double_quant,
+        )?;
+        assert_eq!(ln.dequantize()?.dims(), weight.dims());
+        let out = ln.forward(&x)?;
+        // Relative error of the 4-bit weights
+        let error = ((out - &expected)?.sqr()?.sum_all()? / expected.sqr()?.sum_all()?)?
+            .sqrt()?
+            .to_scalar::<f32>()?;
+        assert!(error < 0.2, "{error}");
+    }
+    Ok(())
+}
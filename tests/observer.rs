@@ -0,0 +1,89 @@
+mod common;
+
+use candle_vllm::openai::{
+    observer::{EngineObserver, FinishEvent, RequestStart, StepEvent, TokenEvent},
+    pipelines::llm_engine::LLMEngine,
+    responses::APIError,
+    sampling_params::SamplingParams,
+};
+use common::MockEngine;
+use futures::StreamExt;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Recorder {
+    starts: Mutex<Vec<RequestStart>>,
+    steps: Mutex<Vec<StepEvent>>,
+    tokens: Mutex<Vec<TokenEvent>>,
+    finishes: Mutex<Vec<FinishEvent>>,
+}
+
+impl EngineObserver for Recorder {
+    fn on_request_start(&self, event: &RequestStart) {
+        self.starts.lock().unwrap().push(event.clone());
+    }
+
+    fn on_step(&self, event: &StepEvent) {
+        self.steps.lock().unwrap().push(event.clone());
+    }
+
+    fn on_token(&self, event: &TokenEvent) {
+        self.tokens.lock().unwrap().push(event.clone());
+    }
+
+    fn on_finish(&self, event: &FinishEvent) {
+        self.finishes.lock().unwrap().push(event.clone());
+    }
+}
+
+#[tokio::test]
+async fn test_observer_sees_the_request_lifecycle() -> Result<(), APIError> {
+    let llm_engine = MockEngine::default().engine()?;
+    let recorder = Arc::new(Recorder::default());
+    llm_engine.lock().await.add_observer(recorder.clone());
+
+    let sampling_params = SamplingParams::builder()
+        .temperature(0.)
+        .ignore_eos(true)
+        .max_tokens(4)
+        .build()?;
+    let mut stream = LLMEngine::generate(&llm_engine, "one two three", sampling_params).await?;
+    let prompt_tokens = stream.prompt_tokens();
+    let mut text = String::new();
+    while let Some(token) = stream.next().await {
+        text.push_str(&token?.text);
+    }
+
+    let starts = recorder.starts.lock().unwrap();
+    assert_eq!(starts.len(), 1);
+    let request_id = &starts[0].request_id;
+    assert_eq!(starts[0].prompt_tokens, prompt_tokens);
+    assert_eq!(starts[0].max_tokens, 4);
+
+    let tokens = recorder.tokens.lock().unwrap();
+    assert!(!tokens.is_empty());
+    assert!(tokens.iter().all(|token| &token.request_id == request_id));
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect::<String>(),
+        text
+    );
+
+    // The prompt is prefilled first, then one token is decoded per step
+    let steps = recorder.steps.lock().unwrap();
+    assert!(steps[0].is_prompt);
+    assert_eq!(steps[0].num_tokens, prompt_tokens);
+    assert!(steps[1..].iter().all(|step| !step.is_prompt));
+    assert!(steps
+        .iter()
+        .all(|step| step.request_ids == [request_id.clone()]));
+
+    let finishes = recorder.finishes.lock().unwrap();
+    assert_eq!(finishes.len(), 1);
+    assert_eq!(&finishes[0].request_id, request_id);
+    assert_eq!(finishes[0].prompt_tokens, prompt_tokens);
+    assert_eq!(finishes[0].completion_tokens, tokens.len());
+    Ok(())
+}
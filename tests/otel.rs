@@ -0,0 +1,147 @@
+mod common;
+
+use candle_vllm::openai::{
+    otel::TracingObserver, pipelines::llm_engine::LLMEngine, responses::APIError,
+    sampling_params::SamplingParams,
+};
+use common::MockEngine;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+#[derive(Debug)]
+struct SpanRecord {
+    id: u64,
+    name: &'static str,
+    parent: Option<&'static str>,
+    fields: HashMap<String, String>,
+    closed: bool,
+}
+
+/// Records the spans of the observer.
+#[derive(Clone, Default)]
+struct Spans(Arc<Mutex<Vec<SpanRecord>>>);
+
+struct Fields<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl Spans {
+    fn with_open<T>(&self, id: &Id, f: impl FnOnce(&mut SpanRecord) -> T) -> Option<T> {
+        let mut spans = self.0.lock().unwrap();
+        spans
+            .iter_mut()
+            .rev()
+            .find(|span| span.id == id.into_u64() && !span.closed)
+            .map(f)
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Spans {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !attrs.metadata().target().ends_with("otel") {
+            return;
+        }
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .map(|parent| parent.name());
+        let mut fields = HashMap::new();
+        attrs.record(&mut Fields(&mut fields));
+        self.0.lock().unwrap().push(SpanRecord {
+            id: id.into_u64(),
+            name: attrs.metadata().name(),
+            parent,
+            fields,
+            closed: false,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+        self.with_open(id, |span| values.record(&mut Fields(&mut span.fields)));
+    }
+
+    fn on_close(&self, id: Id, _ctx: Context<'_, S>) {
+        self.with_open(&id, |span| span.closed = true);
+    }
+}
+
+#[tokio::test]
+async fn test_request_spans() -> Result<(), APIError> {
+    let spans = Spans::default();
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(spans.clone()))
+        .unwrap();
+
+    let llm_engine = MockEngine::default().engine()?;
+    llm_engine
+        .lock()
+        .await
+        .add_observer(Arc::new(TracingObserver::new("mock".to_string())));
+
+    let sampling_params = SamplingParams::builder()
+        .temperature(0.)
+        .ignore_eos(true)
+        .max_tokens(4)
+        .build()?;
+    let mut stream = LLMEngine::generate(&llm_engine, "one two three", sampling_params).await?;
+    let prompt_tokens = stream.prompt_tokens();
+    let mut completion_tokens = 0;
+    while let Some(token) = stream.next().await {
+        token?;
+        completion_tokens += 1;
+    }
+    // The step that finished the request is over
+    drop(llm_engine.lock().await);
+
+    let spans = spans.0.lock().unwrap();
+    assert!(spans.iter().all(|span| span.closed), "{spans:?}");
+    let of = |name: &str| spans.iter().filter(move |span| span.name == name);
+
+    let request = of("request").collect::<Vec<_>>();
+    assert_eq!(request.len(), 1);
+    let request = &request[0];
+    assert_eq!(request.parent, None);
+    assert_eq!(request.fields["model"], "mock");
+    assert_eq!(request.fields["prompt_tokens"], prompt_tokens.to_string());
+    assert_eq!(request.fields["max_tokens"], "4");
+    assert_eq!(
+        request.fields["completion_tokens"],
+        completion_tokens.to_string()
+    );
+    assert_eq!(request.fields["finish_reason"], "length");
+
+    for name in ["queue", "prefill", "decode"] {
+        assert_eq!(of(name).count(), 1, "{name}");
+        assert_eq!(of(name).next().unwrap().parent, Some("request"));
+    }
+    assert_eq!(
+        of("prefill").next().unwrap().fields["batch_tokens"],
+        prompt_tokens.to_string()
+    );
+    // One span per decoding step, numbered from 1
+    let steps = of("decode_step").collect::<Vec<_>>();
+    assert!(!steps.is_empty());
+    for (i, step) in steps.iter().enumerate() {
+        assert_eq!(step.parent, Some("decode"));
+        assert_eq!(step.fields["step"], (i + 1).to_string());
+        assert_eq!(step.fields["batch_size"], "1");
+        assert_eq!(step.fields["batch_tokens"], "1");
+    }
+    Ok(())
+}
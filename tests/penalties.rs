@@ -0,0 +1,93 @@
+use candle_core::{Device, Tensor};
+use candle_vllm::backend::{apply_penalties, penalty_counts, Penalties};
+use candle_vllm::openai::logits_processor::apply_presence_frequency_penalty;
+
+#[test]
+fn test_penalty_counts() -> candle_core::Result<()> {
+    let (tokens, counts) = penalty_counts(&[(&[3, 3, 1], &[3]), (&[], &[2])], &Device::Cpu)?;
+    assert_eq!(tokens.dims(), [2, 2]);
+    assert_eq!(counts.dims(), [2, 2, 2]);
+    let tokens = tokens.to_vec2::<u32>()?;
+    let counts = counts.to_vec3::<u32>()?;
+    let mut first = tokens[0]
+        .iter()
+        .zip(&counts[0])
+        .map(|(token, counts)| (*token, counts.clone()))
+        .collect::<Vec<_>>();
+    first.sort();
+    assert_eq!(first, vec![(1, vec![1, 0]), (3, vec![2, 1])]);
+    // Padded with tokens out of the vocabulary
+    assert_eq!(tokens[1][0], 2);
+    assert_eq!(counts[1][0], vec![0, 1]);
+    assert_eq!(tokens[1][1], u32::MAX);
+    assert_eq!(counts[1][1], vec![0, 0]);
+    Ok(())
+}
+
+#[test]
+fn test_apply_penalties_matches_per_sequence() -> candle_core::Result<()> {
+    let device = Device::Cpu;
+    let rows = [
+        vec![1f32, -2., 0.5, 4., -1., 3.],
+        vec![-0.5f32, 2., 1., -3., 0., 1.5],
+        vec![0.25f32, 0.5, -0.75, 1., 2., -2.],
+    ];
+    let logits = Tensor::from_vec(rows.concat(), (3, 6), &device)?;
+    let penalties = [
+        Penalties {
+            repetition: 1.5,
+            presence: 0.5,
+            frequency: 0.25,
+        },
+        Penalties {
+            repetition: 1.,
+            presence: 1.,
+            frequency: 0.,
+        },
+        Penalties::default(),
+    ];
+    let windows: [&[u32]; 3] = [&[1, 3, 3, 5], &[0, 2], &[4]];
+    let outputs: [&[u32]; 3] = [&[3, 3, 2], &[1, 1, 9], &[4]];
+    let sequences = windows
+        .iter()
+        .zip(&outputs)
+        .map(|(window, output)| (*window, *output))
+        .collect::<Vec<_>>();
+    let (tokens, counts) = penalty_counts(&sequences, &device)?;
+    apply_penalties(&logits, &tokens, &counts, &penalties)?;
+
+    for (i, row) in rows.iter().enumerate() {
+        let row = Tensor::new(row.as_slice(), &device)?;
+        let expected = candle_transformers::utils::apply_repeat_penalty(
+            &row,
+            penalties[i].repetition,
+            windows[i],
+        )?;
+        let expected = apply_presence_frequency_penalty(
+            &expected,
+            penalties[i].presence,
+            penalties[i].frequency,
+            outputs[i],
+        )?;
+        assert_eq!(logits.get(i)?.to_vec1::<f32>()?, expected.to_vec1::<f32>()?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_apply_penalties_checks_shapes() -> candle_core::Result<()> {
+    let device = Device::Cpu;
+    let logits = Tensor::zeros((2, 4), candle_core::DType::F32, &device)?;
+    let (tokens, counts) = penalty_counts(&[(&[1], &[])], &device)?;
+    let penalties = [Penalties::default(); 2];
+    assert!(apply_penalties(&logits, &tokens, &counts, &penalties).is_err());
+    let (tokens, counts) = penalty_counts(&[(&[1], &[]), (&[], &[2])], &device)?;
+    assert!(apply_penalties(
+        &logits.to_dtype(candle_core::DType::F16)?,
+        &tokens,
+        &counts,
+        &penalties
+    )
+    .is_err());
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use candle_vllm::openai::plugins::{Hook, Plugin, PluginError, PluginHost};
+use candle_vllm::openai::requests::ChatCompletionRequest;
+use serde_json::{json, Value};
+
+/// Caps `max_tokens` of the requests.
+struct CapMaxTokens(u64);
+
+impl Plugin for CapMaxTokens {
+    fn name(&self) -> &str {
+        "cap"
+    }
+
+    fn transform(&self, hook: Hook, input: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if hook != Hook::Request {
+            return Ok(None);
+        }
+        let mut request: Value = serde_json::from_slice(input).map_err(|e| e.to_string())?;
+        request["max_tokens"] = json!(request["max_tokens"].as_u64().unwrap_or(self.0).min(self.0));
+        Ok(Some(serde_json::to_vec(&request).unwrap()))
+    }
+}
+
+/// Rejects the requests mentioning a word.
+struct Deny(&'static str);
+
+impl Plugin for Deny {
+    fn name(&self) -> &str {
+        "deny"
+    }
+
+    fn transform(&self, _hook: Hook, input: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let input = String::from_utf8_lossy(input);
+        Ok(input
+            .contains(self.0)
+            .then(|| br#"{"error": "forbidden topic"}"#.to_vec()))
+    }
+}
+
+fn request(content: &str, max_tokens: usize) -> ChatCompletionRequest {
+    serde_json::from_value(json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": content}],
+        "max_tokens": max_tokens,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_plugins_transform_in_order() {
+    let host = PluginHost::new(vec![Box::new(CapMaxTokens(64)), Box::new(Deny("secret"))]);
+    assert_eq!(host.names(), vec!["cap", "deny"]);
+
+    let transformed = host.transform(Hook::Request, request("Hi", 512)).unwrap();
+    assert_eq!(transformed.max_tokens, Some(64));
+    let untouched = host.transform(Hook::Request, request("Hi", 16)).unwrap();
+    assert_eq!(untouched.max_tokens, Some(16));
+
+    match host.transform(Hook::Request, request("Tell me the secret", 16)) {
+        Err(PluginError::Rejected { plugin, message }) => {
+            assert_eq!(plugin, "deny");
+            assert_eq!(message, "forbidden topic");
+        }
+        other => panic!("expected a rejection, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_invalid_plugin_output_fails() {
+    struct Garbage;
+    impl Plugin for Garbage {
+        fn name(&self) -> &str {
+            "garbage"
+        }
+        fn transform(&self, _hook: Hook, _input: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            Ok(Some(br#"{"messages": 42}"#.to_vec()))
+        }
+    }
+    let host = PluginHost::new(vec![Box::new(Garbage)]);
+    assert!(matches!(
+        host.transform(Hook::Request, request("Hi", 16)),
+        Err(PluginError::Failed { .. })
+    ));
+}
+
+#[cfg(feature = "wasm-plugins")]
+#[test]
+fn test_wasm_plugins() {
+    use candle_vllm::openai::plugins::{PluginLimits, WasmPlugin};
+
+    // Rejects every request with the JSON at offset 0
+    let deny_all = r#"(module
+        (memory (export "memory") 1)
+        (data (i32.const 0) "{\"error\": \"blocked\"}")
+        (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+        (func (export "transform_request") (param i32 i32) (result i64) (i64.const 20)))"#;
+    let plugin = WasmPlugin::new("deny_all", deny_all.as_bytes(), PluginLimits::default()).unwrap();
+    let host = PluginHost::new(vec![Box::new(plugin)]);
+    assert!(matches!(
+        host.transform(Hook::Request, request("Hi", 16)),
+        Err(PluginError::Rejected { .. })
+    ));
+
+    // Runs out of fuel
+    let spin = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) (i32.const 0))
+        (func (export "transform_request") (param i32 i32) (result i64)
+            (loop $spin (br $spin))
+            (i64.const 0)))"#;
+    let limits = PluginLimits {
+        fuel: 1_000_000,
+        ..Default::default()
+    };
+    let plugin = WasmPlugin::new("spin", spin.as_bytes(), limits).unwrap();
+    let host = PluginHost::new(vec![Box::new(plugin)]);
+    assert!(matches!(
+        host.transform(Hook::Request, request("Hi", 16)),
+        Err(PluginError::Failed { .. })
+    ));
+
+    // Imports are refused
+    let imports = r#"(module (import "wasi_snapshot_preview1" "fd_write" (func)))"#;
+    assert!(WasmPlugin::new("imports", imports.as_bytes(), PluginLimits::default()).is_err());
+}
@@ -0,0 +1,65 @@
+use candle_vllm::openai::quality::QualityRouter;
+use candle_vllm::openai::requests::{ChatCompletionRequest, Quality};
+
+fn router(fast_weight: u32, best_weight: u32) -> QualityRouter {
+    QualityRouter::new(
+        "llama".to_string(),
+        "llama-fast".to_string(),
+        fast_weight,
+        best_weight,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_weighted_round_robin() {
+    use Quality::{Best, Fast};
+    let next = |router: &QualityRouter, n: usize| (0..n).map(|_| router.next()).collect::<Vec<_>>();
+    assert_eq!(next(&router(1, 1), 4), vec![Best, Fast, Best, Fast]);
+    // The fast requests are spread over the cycle
+    assert_eq!(
+        next(&router(2, 4), 12),
+        vec![Best, Best, Fast, Best, Best, Fast, Best, Best, Fast, Best, Best, Fast]
+    );
+    assert_eq!(next(&router(3, 1), 4), vec![Best, Fast, Fast, Fast]);
+    assert!(next(&router(0, 1), 5).iter().all(|q| *q == Best));
+    assert!(next(&router(1, 0), 5).iter().all(|q| *q == Fast));
+    assert!(QualityRouter::new("a".to_string(), "b".to_string(), 0, 0).is_err());
+}
+
+#[test]
+fn test_route() {
+    let router = router(1, 1);
+    assert_eq!(
+        router.route("llama", Some(Quality::Fast)),
+        Some(Quality::Fast)
+    );
+    assert_eq!(
+        router.route("llama-fast", Some(Quality::Best)),
+        Some(Quality::Best)
+    );
+    // Named explicitly, the fast variant is kept
+    assert_eq!(router.route("llama-fast", None), Some(Quality::Fast));
+    assert_eq!(router.route("llama", None), Some(Quality::Best));
+    assert_eq!(router.route("llama", None), Some(Quality::Fast));
+    assert_eq!(router.route("qwen2", None), None);
+}
+
+#[test]
+fn test_quality_field() {
+    let request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+        "model": "llama",
+        "messages": [{"role": "user", "content": "Hi"}],
+        "quality": "fast",
+    }))
+    .unwrap();
+    assert_eq!(request.quality, Some(Quality::Fast));
+    assert!(
+        serde_json::from_value::<ChatCompletionRequest>(serde_json::json!({
+            "model": "llama",
+            "messages": [],
+            "quality": "medium",
+        }))
+        .is_err()
+    );
+}
@@ -0,0 +1,124 @@
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap};
+use candle_vllm::openai::rate_limit::{RateLimitExceeded, RateLimitKey, RateLimiter, RateLimits};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The key of a request from `addr` with the `Authorization` header.
+fn key(addr: Option<&str>, authorization: Option<&str>) -> RateLimitKey {
+    let mut request = Request::builder().uri("/v1/chat/completions");
+    if let Some(authorization) = authorization {
+        request = request.header(header::AUTHORIZATION, authorization);
+    }
+    let mut request = request.body(axum::body::Body::empty()).unwrap();
+    if let Some(addr) = addr {
+        let addr: SocketAddr = addr.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(addr));
+    }
+    RateLimitKey::of(&request)
+}
+
+/// The key of a client at 10.0.0.7
+fn alice() -> RateLimitKey {
+    key(Some("10.0.0.7:51234"), None)
+}
+
+#[test]
+fn test_requests_per_minute_refill() {
+    let limiter = RateLimiter::new(RateLimits {
+        requests_per_minute: Some(2),
+        tokens_per_minute: None,
+    });
+    let alice = alice();
+    let now = Instant::now();
+    assert_eq!(limiter.acquire_request(&alice, now), Ok(()));
+    assert_eq!(limiter.acquire_request(&alice, now), Ok(()));
+    // A request comes back every 30 seconds
+    assert_eq!(
+        limiter.acquire_request(&alice, now),
+        Err(RateLimitExceeded::Requests(Duration::from_secs(30)))
+    );
+    // Other keys have their own limits
+    assert_eq!(
+        limiter.acquire_request(&key(Some("10.0.0.8:51234"), None), now),
+        Ok(())
+    );
+    assert_eq!(
+        limiter.acquire_request(&alice, now + Duration::from_secs(30)),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_tokens_per_minute() {
+    let limiter = RateLimiter::new(RateLimits {
+        requests_per_minute: None,
+        tokens_per_minute: Some(600),
+    });
+    let alice = alice();
+    let now = Instant::now();
+    assert_eq!(limiter.acquire_tokens(&alice, 500, now), Ok(()));
+    // 10 tokens come back every second, nothing is taken on failure
+    assert_eq!(
+        limiter.acquire_tokens(&alice, 200, now),
+        Err(RateLimitExceeded::Tokens(Duration::from_secs(10)))
+    );
+    assert_eq!(limiter.acquire_tokens(&alice, 100, now), Ok(()));
+    // Requests wait for tokens to come back
+    assert_eq!(
+        limiter.acquire_request(&alice, now),
+        Err(RateLimitExceeded::Tokens(Duration::from_millis(100)))
+    );
+    assert_eq!(
+        limiter.acquire_tokens(&alice, 601, now + Duration::from_secs(600)),
+        Err(RateLimitExceeded::TooLarge)
+    );
+}
+
+#[test]
+fn test_status_headers() {
+    let limiter = RateLimiter::new(RateLimits {
+        requests_per_minute: Some(60),
+        tokens_per_minute: Some(1000),
+    });
+    let alice = alice();
+    let now = Instant::now();
+    limiter.acquire_request(&alice, now).unwrap();
+    limiter.acquire_tokens(&alice, 100, now).unwrap();
+
+    let status = limiter.status(&alice, now);
+    assert_eq!(status.remaining_requests, 59);
+    assert_eq!(status.reset_requests, Duration::from_secs(1));
+    assert_eq!(status.remaining_tokens, 900);
+    assert_eq!(status.reset_tokens, Duration::from_secs(6));
+
+    let mut headers = HeaderMap::new();
+    status.insert_headers(&mut headers);
+    assert_eq!(headers["x-ratelimit-limit-requests"], "60");
+    assert_eq!(headers["x-ratelimit-remaining-requests"], "59");
+    assert_eq!(headers["x-ratelimit-reset-requests"], "1s");
+    assert_eq!(headers["x-ratelimit-limit-tokens"], "1000");
+    assert_eq!(headers["x-ratelimit-remaining-tokens"], "900");
+    assert_eq!(headers["x-ratelimit-reset-tokens"], "6s");
+}
+
+#[test]
+fn test_requests_are_keyed_by_address() {
+    assert_ne!(alice(), key(Some("10.0.0.8:51234"), None));
+    assert_ne!(alice(), key(None, None));
+    // The port of the connection does not matter
+    assert_eq!(alice(), key(Some("10.0.0.7:40000"), None));
+    // API keys are not checked, a new one on every request does not escape the limits
+    assert_eq!(
+        alice(),
+        key(Some("10.0.0.7:51234"), Some("Bearer sk-alice"))
+    );
+    assert_eq!(
+        alice(),
+        key(Some("10.0.0.7:51234"), Some("Bearer sk-random"))
+    );
+    assert_eq!(
+        key(None, Some("Bearer sk-alice")),
+        key(None, Some("Bearer sk-bob"))
+    );
+}
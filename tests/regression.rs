@@ -0,0 +1,704 @@
+//! Regression tests against tiny random-weight models of each architecture. The weights are
+//! generated from a fixed seed, so a model always produces the same tokens: their ids and log
+//! probabilities (with the top 5 alternatives) are compared to the references recorded in
+//! `tests/references`, for the batched prefill, the decoding steps over the paged KV cache, and
+//! the quantized linear layers.
+//!
+//! A missing reference fails the test. Run with `CANDLE_VLLM_RECORD_REFERENCES=1` to record the
+//! references, and again after an intended change of the outputs.
+
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::{
+    get_model_loader,
+    openai::{
+        pipelines::{
+            llm_engine::{LLMEngine, NewRequest},
+            pipeline::DefaultModelPaths,
+            LoadOptions,
+        },
+        responses::APIError,
+        sampling_params::SamplingParams,
+    },
+    scheduler::{cache_engine::CacheConfig, SchedulerConfig, SchedulingPolicy},
+    ModelSelected,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const HIDDEN: usize = 64;
+const INTERMEDIATE: usize = 128;
+const VOCAB: usize = 64;
+const LAYERS: usize = 2;
+const HEADS: usize = 4;
+const KV_HEADS: usize = 2;
+const HEAD_DIM: usize = HIDDEN / HEADS;
+const MAX_TOKENS: usize = 8;
+const TOP_LOGPROBS: usize = 5;
+/// Log probabilities are compared up to this absolute difference.
+const TOLERANCE: f32 = 1e-3;
+
+/// Prompts of different lengths, batched together, spanning several KV cache blocks of 8 tokens.
+const PROMPTS: [&str; 3] = [
+    "w3 w17 w42",
+    "w9 w9 w9 w20 w33 w41 w50 w61 w12 w5 w7",
+    "w60 w31 w8 w44 w27 w3 w14",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arch {
+    Llama,
+    Mistral,
+    Qwen2,
+    Gemma,
+    Phi3,
+    T5,
+}
+
+impl Arch {
+    fn name(self) -> &'static str {
+        match self {
+            Arch::Llama => "llama",
+            Arch::Mistral => "mistral",
+            Arch::Qwen2 => "qwen2",
+            Arch::Gemma => "gemma",
+            Arch::Phi3 => "phi3",
+            Arch::T5 => "t5",
+        }
+    }
+
+    fn selected(self, quant: Option<&str>) -> ModelSelected {
+        let quant = quant.map(str::to_string);
+        // Greedy sampling, the temperature of the pipeline selects argmax
+        let (repeat_last_n, temperature, penalty, max_gen_tokens) = (None, Some(0.), None, None);
+        match self {
+            Arch::Llama => ModelSelected::Llama {
+                repeat_last_n,
+                temperature,
+                penalty,
+                max_gen_tokens,
+                quant,
+            },
+            Arch::Mistral => ModelSelected::Mistral {
+                repeat_last_n,
+                temperature,
+                penalty,
+                max_gen_tokens,
+                quant,
+            },
+            Arch::Qwen2 => ModelSelected::Qwen2 {
+                repeat_last_n,
+                temperature,
+                top_p: None,
+                top_k: None,
+                penalty,
+                max_gen_tokens,
+                quant,
+            },
+            Arch::Gemma => ModelSelected::Gemma {
+                repeat_last_n,
+                temperature,
+                penalty,
+                max_gen_tokens,
+                quant,
+            },
+            Arch::Phi3 => ModelSelected::Phi3 {
+                repeat_last_n,
+                temperature,
+                top_p: None,
+                top_k: None,
+                penalty,
+                max_gen_tokens,
+                quant,
+            },
+            Arch::T5 => ModelSelected::T5 {
+                repeat_last_n,
+                temperature,
+                penalty,
+                max_gen_tokens,
+                quant,
+            },
+        }
+    }
+
+    fn config(self) -> serde_json::Value {
+        if self == Arch::T5 {
+            return serde_json::json!({
+                "vocab_size": VOCAB,
+                "d_model": HIDDEN,
+                "d_kv": HEAD_DIM,
+                "d_ff": INTERMEDIATE,
+                "num_layers": LAYERS,
+                "num_heads": HEADS,
+                "feed_forward_proj": "gated-gelu",
+                "tie_word_embeddings": false,
+                "decoder_start_token_id": 0,
+                "pad_token_id": 0,
+                "eos_token_id": 2,
+                "n_positions": 512,
+            });
+        }
+        let mut config = serde_json::json!({
+            "hidden_size": HIDDEN,
+            "intermediate_size": INTERMEDIATE,
+            "vocab_size": VOCAB,
+            "num_hidden_layers": LAYERS,
+            "num_attention_heads": HEADS,
+            "num_key_value_heads": KV_HEADS,
+            "rms_norm_eps": 1e-6,
+            "rope_theta": 10000.0,
+            "max_position_embeddings": 512,
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "hidden_act": "silu",
+        });
+        let extra = match self {
+            Arch::Llama | Arch::Mistral => serde_json::json!({}),
+            Arch::Qwen2 => serde_json::json!({
+                "sliding_window": 512,
+                "max_window_layers": LAYERS,
+                "tie_word_embeddings": false,
+                "use_sliding_window": false,
+            }),
+            Arch::Gemma => serde_json::json!({
+                "attention_bias": false,
+                "head_dim": HEAD_DIM,
+                "hidden_act": "gelu",
+            }),
+            Arch::Phi3 | Arch::T5 => serde_json::json!({}),
+        };
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        config
+    }
+
+    /// Names and shapes of the weights of the model.
+    fn weights(self) -> Vec<(String, Vec<usize>)> {
+        if self == Arch::T5 {
+            return t5_weights();
+        }
+        let (q, kv) = (HEADS * HEAD_DIM, KV_HEADS * HEAD_DIM);
+        let mut weights = vec![
+            ("model.embed_tokens.weight".to_string(), vec![VOCAB, HIDDEN]),
+            ("model.norm.weight".to_string(), vec![HIDDEN]),
+        ];
+        // Gemma ties its output layer to the embeddings
+        if self != Arch::Gemma {
+            weights.push(("lm_head.weight".to_string(), vec![VOCAB, HIDDEN]));
+        }
+        for layer in 0..LAYERS {
+            let mut layer_weights = vec![
+                ("input_layernorm.weight", vec![HIDDEN]),
+                ("post_attention_layernorm.weight", vec![HIDDEN]),
+                ("self_attn.o_proj.weight", vec![HIDDEN, q]),
+                ("mlp.down_proj.weight", vec![HIDDEN, INTERMEDIATE]),
+            ];
+            if self == Arch::Phi3 {
+                layer_weights.extend([
+                    ("self_attn.qkv_proj.weight", vec![q + 2 * kv, HIDDEN]),
+                    ("mlp.gate_up_proj.weight", vec![2 * INTERMEDIATE, HIDDEN]),
+                ]);
+            } else {
+                layer_weights.extend([
+                    ("self_attn.q_proj.weight", vec![q, HIDDEN]),
+                    ("self_attn.k_proj.weight", vec![kv, HIDDEN]),
+                    ("self_attn.v_proj.weight", vec![kv, HIDDEN]),
+                    ("mlp.gate_proj.weight", vec![INTERMEDIATE, HIDDEN]),
+                    ("mlp.up_proj.weight", vec![INTERMEDIATE, HIDDEN]),
+                ]);
+            }
+            if self == Arch::Qwen2 {
+                layer_weights.extend([
+                    ("self_attn.q_proj.bias", vec![q]),
+                    ("self_attn.k_proj.bias", vec![kv]),
+                    ("self_attn.v_proj.bias", vec![kv]),
+                ]);
+            }
+            weights.extend(
+                layer_weights
+                    .into_iter()
+                    .map(|(name, shape)| (format!("model.layers.{layer}.{name}"), shape)),
+            );
+        }
+        weights
+    }
+}
+
+/// Names and shapes of the weights of a T5 model, with gated feed forward layers and the relative
+/// position bias in the first layer of the encoder and of the decoder.
+fn t5_weights() -> Vec<(String, Vec<usize>)> {
+    let inner = HEADS * HEAD_DIM;
+    let mut weights = vec![
+        ("shared.weight".to_string(), vec![VOCAB, HIDDEN]),
+        ("lm_head.weight".to_string(), vec![VOCAB, HIDDEN]),
+    ];
+    for stack in ["encoder", "decoder"] {
+        weights.push((format!("{stack}.final_layer_norm.weight"), vec![HIDDEN]));
+        // The decoder attends to the encoder output between its self-attention and feed forward
+        let ff = if stack == "encoder" { 1 } else { 2 };
+        for layer in 0..LAYERS {
+            let mut layer_weights = vec![
+                ("layer.0.layer_norm.weight".to_string(), vec![HIDDEN]),
+                (format!("layer.{ff}.layer_norm.weight"), vec![HIDDEN]),
+                (
+                    format!("layer.{ff}.DenseReluDense.wi_0.weight"),
+                    vec![INTERMEDIATE, HIDDEN],
+                ),
+                (
+                    format!("layer.{ff}.DenseReluDense.wi_1.weight"),
+                    vec![INTERMEDIATE, HIDDEN],
+                ),
+                (
+                    format!("layer.{ff}.DenseReluDense.wo.weight"),
+                    vec![HIDDEN, INTERMEDIATE],
+                ),
+            ];
+            let mut attention = vec!["layer.0.SelfAttention"];
+            if stack == "decoder" {
+                attention.push("layer.1.EncDecAttention");
+                layer_weights.push(("layer.1.layer_norm.weight".to_string(), vec![HIDDEN]));
+            }
+            for prefix in attention {
+                for proj in ["q", "k", "v"] {
+                    layer_weights.push((format!("{prefix}.{proj}.weight"), vec![inner, HIDDEN]));
+                }
+                layer_weights.push((format!("{prefix}.o.weight"), vec![HIDDEN, inner]));
+            }
+            if layer == 0 {
+                layer_weights.push((
+                    "layer.0.SelfAttention.relative_attention_bias.weight".to_string(),
+                    vec![32, HEADS],
+                ));
+            }
+            weights.extend(
+                layer_weights
+                    .into_iter()
+                    .map(|(name, shape)| (format!("{stack}.block.{layer}.{name}"), shape)),
+            );
+        }
+    }
+    weights
+}
+
+/// Uniform values in [-1, 1) from the name of the tensor, independent of the platform and of the
+/// random number generators of the dependencies.
+fn seeded_values(name: &str, len: usize) -> Vec<f32> {
+    // FNV-1a of the name, then splitmix64
+    let mut state = name.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^= z >> 31;
+            (z >> 40) as f32 / (1u64 << 23) as f32 - 1.
+        })
+        .collect()
+}
+
+fn seeded_tensor(name: &str, shape: &[usize]) -> candle_core::Result<Tensor> {
+    let values = seeded_values(name, shape.iter().product());
+    let values = if name.ends_with("norm.weight") {
+        // Norms close to 1
+        values.into_iter().map(|v| 1. + 0.1 * v).collect()
+    } else {
+        // Keep the activations of the layers in range
+        let scale = 1. / (*shape.last().unwrap() as f32).sqrt();
+        values.into_iter().map(|v| v * scale).collect()
+    };
+    Tensor::from_vec(values, shape, &Device::Cpu)
+}
+
+/// A word-level tokenizer over `<unk>`, `<s>`, `</s>` and `w3` to `w63`.
+fn tokenizer() -> serde_json::Value {
+    let mut vocab = serde_json::Map::new();
+    for (id, token) in ["<unk>", "<s>", "</s>"].into_iter().enumerate() {
+        vocab.insert(token.to_string(), id.into());
+    }
+    for id in 3..VOCAB {
+        vocab.insert(format!("w{id}"), id.into());
+    }
+    serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "WhitespaceSplit"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "<unk>"},
+    })
+}
+
+/// Write the config, tokenizer and weights of the tiny model of `arch`, in a directory of their
+/// own since the tests run concurrently.
+fn write_model(arch: Arch) -> Result<DefaultModelPaths<PathBuf>, APIError> {
+    static MODELS: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "candle-vllm-regression-{}-{}-{}",
+        arch.name(),
+        std::process::id(),
+        MODELS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).map_err(APIError::from)?;
+    let config_filename = dir.join("config.json");
+    std::fs::write(&config_filename, arch.config().to_string()).map_err(APIError::from)?;
+    let tokenizer_filename = dir.join("tokenizer.json");
+    std::fs::write(&tokenizer_filename, tokenizer().to_string()).map_err(APIError::from)?;
+    let weights = arch
+        .weights()
+        .into_iter()
+        .map(|(name, shape)| Ok((name.clone(), seeded_tensor(&name, &shape)?)))
+        .collect::<candle_core::Result<HashMap<_, _>>>()?;
+    let weights_filename = dir.join("model.safetensors");
+    candle_core::safetensors::save(&weights, &weights_filename)?;
+    Ok(DefaultModelPaths {
+        tokenizer_filename,
+        tokenizer_config_filename: None,
+        generation_config_filename: None,
+        config_filename,
+        filenames: vec![weights_filename],
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TokenReference {
+    token: usize,
+    logprob: f32,
+    /// The most likely tokens and their log probabilities.
+    top_logprobs: Vec<(usize, f32)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Reference {
+    prompts: Vec<String>,
+    outputs: Vec<Vec<TokenReference>>,
+}
+
+/// Generate `MAX_TOKENS` greedily from each of `prompts`, all of them in the same batch.
+async fn generate(
+    arch: Arch,
+    quant: Option<&str>,
+    prompts: &[&str],
+) -> Result<Vec<Vec<TokenReference>>, APIError> {
+    let (loader, _) = get_model_loader(arch.selected(quant), None);
+    let paths = Box::new(write_model(arch)?);
+    let model = loader.load_model(paths, LoadOptions::new(DType::F32, Device::Cpu))?;
+    let engine = LLMEngine::new(
+        model.0,
+        SchedulerConfig {
+            max_num_seqs: 8,
+            max_num_batched_tokens: 512,
+            kv_eviction: None,
+            num_lookahead_slots: 0,
+            policy: SchedulingPolicy::Fcfs,
+        },
+        CacheConfig {
+            block_size: 8,
+            num_gpu_blocks: Some(32),
+            num_cpu_blocks: Some(8),
+            fully_init: true,
+            dtype: DType::F32,
+            tensor_parallel_size: 1,
+        },
+    )?;
+
+    let (request_ids, finish_notify) = {
+        let mut e = engine.lock().await;
+        let mut request_ids = Vec::new();
+        for (i, prompt) in prompts.iter().enumerate() {
+            let prompt = e
+                .get_pipeline()
+                .tokenizer()
+                .tokenizer()
+                .encode(*prompt, false)
+                .map_err(APIError::from)?;
+            let sampling_params = SamplingParams::builder()
+                .temperature(0.)
+                .ignore_eos(true)
+                .max_tokens(MAX_TOKENS)
+                .logprobs(TOP_LOGPROBS)
+                .build()?;
+            let request_id = format!("regression-{i}");
+            e.add_request(NewRequest {
+                use_logprobs: true,
+                ..NewRequest::new(prompt, request_id.clone(), sampling_params)
+            });
+            request_ids.push(request_id);
+        }
+        e.notify.notify_one();
+        (request_ids, e.finish_notify.clone())
+    };
+
+    loop {
+        finish_notify.notified().await;
+        let e = engine.lock().await;
+        if let Some((request_id, error)) = e.failed_requests.iter().next() {
+            return Err(APIError::new(format!("{request_id} failed: {error}")));
+        }
+        if !request_ids
+            .iter()
+            .all(|id| e.completion_records.contains_key(id))
+        {
+            continue;
+        }
+        return request_ids
+            .iter()
+            .map(|id| {
+                let choice = &e.completion_records[id].0[0];
+                let logprobs = choice
+                    .logprobs
+                    .as_ref()
+                    .ok_or_else(|| APIError::new(format!("{id} has no logprobs")))?;
+                Ok(logprobs
+                    .content
+                    .iter()
+                    .map(|logprob| TokenReference {
+                        token: logprob.token,
+                        logprob: logprob.logprob,
+                        top_logprobs: logprob
+                            .top_logprobs
+                            .iter()
+                            .map(|top| (top.token, top.logprob))
+                            .collect(),
+                    })
+                    .collect())
+            })
+            .collect();
+    }
+}
+
+fn assert_close(expected: &[Vec<TokenReference>], actual: &[Vec<TokenReference>], what: &str) {
+    assert_eq!(expected.len(), actual.len(), "{what}: number of outputs");
+    for (i, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+        let tokens = |output: &[TokenReference]| output.iter().map(|t| t.token).collect::<Vec<_>>();
+        assert_eq!(
+            tokens(expected),
+            tokens(actual),
+            "{what}: tokens of prompt {i}"
+        );
+        for (step, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+            let close = |a: f32, b: f32| (a - b).abs() <= TOLERANCE;
+            assert!(
+                close(expected.logprob, actual.logprob),
+                "{what}: logprob of prompt {i} at step {step}, expected {} got {}",
+                expected.logprob,
+                actual.logprob
+            );
+            assert_eq!(
+                expected.top_logprobs.len(),
+                actual.top_logprobs.len(),
+                "{what}: top logprobs of prompt {i} at step {step}"
+            );
+            let expected = &expected.top_logprobs;
+            for (rank, (&(expected_token, expected_logprob), &(actual_token, actual_logprob))) in
+                expected.iter().zip(&actual.top_logprobs).enumerate()
+            {
+                // Tokens within the tolerance of each other may swap places
+                let tie = [rank.wrapping_sub(1), rank + 1]
+                    .iter()
+                    .filter_map(|&other| expected.get(other))
+                    .any(|&(_, other)| close(other, expected_logprob));
+                assert!(
+                    close(expected_logprob, actual_logprob) && (tie || expected_token == actual_token),
+                    "{what}: top logprobs of prompt {i} at step {step}, expected {expected:?} got {:?}",
+                    actual.top_logprobs
+                );
+            }
+        }
+    }
+}
+
+fn reference_path(arch: Arch, quant: Option<&str>) -> PathBuf {
+    let name = match quant {
+        Some(quant) => format!("{}-{quant}.json", arch.name()),
+        None => format!("{}.json", arch.name()),
+    };
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("references")
+        .join(name)
+}
+
+/// Compare the outputs of the model to its reference, or record it with
+/// `CANDLE_VLLM_RECORD_REFERENCES=1`.
+async fn check_reference(arch: Arch, quant: Option<&str>) -> Result<(), APIError> {
+    let outputs = generate(arch, quant, &PROMPTS).await?;
+    for output in &outputs {
+        assert_eq!(output.len(), MAX_TOKENS);
+        // Greedy decoding picks the most likely token
+        for token in output {
+            assert_eq!(token.top_logprobs.len(), TOP_LOGPROBS);
+            assert_eq!(token.top_logprobs[0].1, token.logprob);
+            assert!(token.logprob <= 0.);
+        }
+    }
+
+    let path = reference_path(arch, quant);
+    let record = std::env::var("CANDLE_VLLM_RECORD_REFERENCES").is_ok_and(|v| v == "1");
+    if record {
+        let reference = Reference {
+            prompts: PROMPTS.iter().map(|p| p.to_string()).collect(),
+            outputs,
+        };
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(APIError::from)?;
+        let json = serde_json::to_string_pretty(&reference).map_err(APIError::from)?;
+        std::fs::write(&path, json + "\n").map_err(APIError::from)?;
+        return Ok(());
+    }
+    if !path.exists() {
+        return Err(APIError::new(format!(
+            "No reference at {}, record it with CANDLE_VLLM_RECORD_REFERENCES=1.",
+            path.display()
+        )));
+    }
+    let reference: Reference =
+        serde_json::from_slice(&std::fs::read(&path).map_err(APIError::from)?)
+            .map_err(APIError::from)?;
+    assert_eq!(reference.prompts, PROMPTS, "{}: prompts", path.display());
+    assert_close(&reference.outputs, &outputs, &path.display().to_string());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_regression_llama() -> Result<(), APIError> {
+    check_reference(Arch::Llama, None).await
+}
+
+#[tokio::test]
+async fn test_regression_mistral() -> Result<(), APIError> {
+    check_reference(Arch::Mistral, None).await
+}
+
+#[tokio::test]
+async fn test_regression_qwen2() -> Result<(), APIError> {
+    check_reference(Arch::Qwen2, None).await
+}
+
+#[tokio::test]
+async fn test_regression_gemma() -> Result<(), APIError> {
+    check_reference(Arch::Gemma, None).await
+}
+
+#[tokio::test]
+async fn test_regression_phi3() -> Result<(), APIError> {
+    check_reference(Arch::Phi3, None).await
+}
+
+#[tokio::test]
+async fn test_regression_t5() -> Result<(), APIError> {
+    check_reference(Arch::T5, None).await
+}
+
+#[tokio::test]
+async fn test_regression_llama_q8_0() -> Result<(), APIError> {
+    check_reference(Arch::Llama, Some("q8_0")).await
+}
+
+#[tokio::test]
+async fn test_regression_llama_nf4() -> Result<(), APIError> {
+    check_reference(Arch::Llama, Some("nf4")).await
+}
+
+/// The outputs of a prompt do not depend on the other prompts of its batch, nor on where its KV
+/// cache blocks are, or for T5 on the padding of the keys and values of the batch.
+#[tokio::test]
+async fn test_regression_batch_invariance() -> Result<(), APIError> {
+    for arch in [Arch::Llama, Arch::T5] {
+        let batched = generate(arch, None, &PROMPTS).await?;
+        for (i, prompt) in PROMPTS.iter().enumerate() {
+            let alone = generate(arch, None, &[prompt]).await?;
+            let what = format!("{}: prompt {i} alone", arch.name());
+            assert_close(&batched[i..i + 1], &alone, &what);
+        }
+    }
+    Ok(())
+}
+
+/// Scoring a prompt followed by a greedy output gives the logprobs of generating that output.
+#[tokio::test]
+async fn test_prompt_logprobs_match_generation() -> Result<(), APIError> {
+    for arch in [Arch::Llama, Arch::Mistral, Arch::Qwen2] {
+        let outputs = generate(arch, None, &PROMPTS[1..2]).await?;
+        let (loader, _) = get_model_loader(arch.selected(None), None);
+        let paths = Box::new(write_model(arch)?);
+        let (mut pipeline, _) =
+            loader.load_model(paths, LoadOptions::new(DType::F32, Device::Cpu))?;
+        let prompt = pipeline
+            .tokenizer()
+            .tokenizer()
+            .encode(PROMPTS[1], false)
+            .map_err(APIError::from)?;
+        let mut input_ids = prompt.get_ids().to_vec();
+        input_ids.extend(outputs[0].iter().map(|output| output.token as u32));
+
+        let logprobs = pipeline.prompt_logprobs(&input_ids, TOP_LOGPROBS)?;
+        assert_eq!(logprobs.len(), input_ids.len());
+        assert!(logprobs[0].is_none());
+        let scored = logprobs[prompt.len()..]
+            .iter()
+            .map(|logprob| {
+                let logprob = logprob.as_ref().unwrap();
+                TokenReference {
+                    token: logprob.token,
+                    logprob: logprob.logprob,
+                    top_logprobs: logprob
+                        .top_logprobs
+                        .iter()
+                        .map(|top| (top.token, top.logprob))
+                        .collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_close(&outputs, &[scored], arch.name());
+    }
+    Ok(())
+}
+
+/// A `LlamaForSequenceClassification` checkpoint: the tiny Llama with a `score` head of two
+/// labels in place of its language model head, the second label scoring twice the first.
+fn write_reward_model() -> Result<DefaultModelPaths<PathBuf>, APIError> {
+    let paths = write_model(Arch::Llama)?;
+    let mut config = Arch::Llama.config();
+    config["architectures"] = serde_json::json!(["LlamaForSequenceClassification"]);
+    config["id2label"] = serde_json::json!({"0": "LABEL_0", "1": "LABEL_1"});
+    std::fs::write(&paths.config_filename, config.to_string()).map_err(APIError::from)?;
+    let mut weights = candle_core::safetensors::load(&paths.filenames[0], &Device::Cpu)?;
+    weights.remove("lm_head.weight");
+    let score = seeded_tensor("score.weight", &[1, HIDDEN])?;
+    weights.insert(
+        "score.weight".to_string(),
+        Tensor::cat(&[&score, &(&score * 2.)?], 0)?,
+    );
+    candle_core::safetensors::save(&weights, &paths.filenames[0])?;
+    Ok(paths)
+}
+
+/// A reward model scores whole inputs with its score head, and does not generate.
+#[test]
+fn test_reward_model_scores() -> Result<(), APIError> {
+    let (loader, _) = get_model_loader(Arch::Llama.selected(None), None);
+    let paths = Box::new(write_reward_model()?);
+    let (mut pipeline, _) = loader.load_model(paths, LoadOptions::new(DType::F32, Device::Cpu))?;
+    assert!(pipeline.is_encoder_only());
+    assert_eq!(pipeline.score_labels(), ["LABEL_0", "LABEL_1"]);
+
+    let scores = pipeline.score(&[3, 17, 42])?;
+    assert_eq!(scores.len(), 2);
+    assert!((scores[1] - 2. * scores[0]).abs() <= TOLERANCE);
+    // Scored from the last token, which sees the whole input
+    let other = pipeline.score(&[4, 17, 42])?;
+    assert!((scores[0] - other[0]).abs() > TOLERANCE);
+    assert_eq!(pipeline.score(&[3, 17, 42])?, scores);
+    assert!(pipeline.score(&[]).is_err());
+    assert!(pipeline.prompt_logprobs(&[3, 17, 42], 1).is_err());
+    Ok(())
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use axum::extract::{Json, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use candle_vllm::openai::{
+    openai_server::reload_model,
+    pipelines::llm_engine::LLMEngine,
+    reload::{ReloadFn, Reloader},
+    requests::ReloadRequest,
+    responses::{APIError, ChatResponder},
+    sampling_params::SamplingParams,
+    OpenAIServerData, ServedModel,
+};
+use common::{server_data, MockEngine};
+use futures::{FutureExt, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+async fn generate(engine: &Arc<AsyncMutex<LLMEngine>>) -> Result<String, APIError> {
+    let sampling_params = SamplingParams::builder()
+        .temperature(0.)
+        .max_tokens(64)
+        .build()?;
+    let tokens = LLMEngine::generate(engine, "Hello", sampling_params)
+        .await?
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(tokens.iter().map(|t| t.text.as_str()).collect())
+}
+
+const ADMIN_TOKEN: &str = "admin-secret";
+
+/// The headers of a reload request with the bearer `token`.
+fn bearer(token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        format!("Bearer {token}").parse().unwrap(),
+    );
+    headers
+}
+
+fn failing_loader() -> ReloadFn {
+    Box::new(|_: String, _: ReloadRequest| async { Err(APIError::new_str("Not loaded.")) }.boxed())
+}
+
+#[test]
+fn test_reload_stops_admission() {
+    let reloader = Reloader::new(failing_loader(), ADMIN_TOKEN.to_string());
+    let admitted = reloader.admit();
+    assert!(admitted.is_some());
+    assert_eq!(reloader.in_flight(), 1);
+
+    let reloading = reloader.begin();
+    assert!(reloading.is_some());
+    assert!(reloader.is_reloading());
+    // One reload at a time, and no request is admitted meanwhile
+    assert!(reloader.begin().is_none());
+    assert!(reloader.admit().is_none());
+    assert_eq!(reloader.in_flight(), 1);
+
+    drop(admitted);
+    assert_eq!(reloader.in_flight(), 0);
+    drop(reloading);
+    assert!(!reloader.is_reloading());
+    assert!(reloader.admit().is_some());
+}
+
+#[test]
+fn test_reload_requires_the_admin_token() {
+    let reloader = Reloader::new(failing_loader(), ADMIN_TOKEN.to_string());
+    assert!(reloader.authorize(&bearer(ADMIN_TOKEN)));
+    assert!(!reloader.authorize(&bearer("admin-secreT")));
+    assert!(!reloader.authorize(&bearer("admin")));
+    assert!(!reloader.authorize(&HeaderMap::new()));
+}
+
+#[tokio::test]
+async fn test_stopped_engine_is_released() -> Result<(), APIError> {
+    let (engine, ..) = MockEngine::replying("Hi").load()?;
+    assert_eq!(generate(&engine).await?, "Hi");
+    engine.lock().await.stop();
+    assert!(generate(&engine).await.is_err());
+    // The generation loop lets go of the engine
+    let stopped = Instant::now();
+    while Arc::strong_count(&engine) > 1 {
+        assert!(stopped.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reload_model() -> Result<(), APIError> {
+    let (engine, pipeline_config, model_name) = MockEngine::replying("Before").load()?;
+    let served = ServedModel::new(engine, pipeline_config, model_name.clone(), None).await;
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let loader: ReloadFn = {
+        let requests = requests.clone();
+        Box::new(move |model_name: String, request: ReloadRequest| {
+            requests.lock().unwrap().push(request);
+            match MockEngine::replying("After").load() {
+                Ok((engine, pipeline_config, _)) => async move {
+                    Ok(ServedModel::new(engine, pipeline_config, model_name, None).await)
+                }
+                .boxed(),
+                Err(e) => async move { Err(e) }.boxed(),
+            }
+        })
+    };
+    let data = Arc::new(OpenAIServerData {
+        reloader: Some(Reloader::new(loader, ADMIN_TOKEN.to_string())),
+        ..server_data(served)
+    });
+    let old = data.get_model(&model_name)?;
+    assert_eq!(generate(&old.model).await?, "Before");
+    let old_engine = Arc::downgrade(&old.model);
+    drop(old);
+
+    let request = ReloadRequest {
+        model_id: Some("new-model".to_string()),
+        drain_timeout: Some(1.),
+        ..Default::default()
+    };
+    // Without the admin token, nothing is loaded
+    let ChatResponder::ValidationError(error) = reload_model(
+        State(data.clone()),
+        bearer("guess"),
+        Ok(Json(request.clone())),
+    )
+    .await
+    else {
+        panic!("The model was reloaded without the admin token.");
+    };
+    assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+    assert!(requests.lock().unwrap().is_empty());
+
+    let ChatResponder::Reload(response) =
+        reload_model(State(data.clone()), bearer(ADMIN_TOKEN), Ok(Json(request))).await
+    else {
+        panic!("The model was not reloaded.");
+    };
+    assert_eq!(response.model, model_name);
+    assert_eq!(response.aborted_requests, 0);
+    assert_eq!(
+        requests.lock().unwrap()[0].model_id.as_deref(),
+        Some("new-model")
+    );
+
+    // Served under the same name, the old engine is freed
+    assert!(old_engine.upgrade().is_none());
+    assert_eq!(data.models().len(), 1);
+    assert_eq!(
+        generate(&data.get_model(&model_name)?.model).await?,
+        "After"
+    );
+    assert!(!data.reloader.as_ref().unwrap().is_reloading());
+    Ok(())
+}
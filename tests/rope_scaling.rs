@@ -0,0 +1,136 @@
+use candle_core::{DType, Device, IndexOp};
+use candle_vllm::openai::models::llama::LlamaConfig;
+use candle_vllm::openai::models::{Config, RopeScalingKind};
+use candle_vllm::SpecificConfig;
+
+/// A Llama config with 128-dim heads, `max_position_embeddings` and `rope_scaling` (JSON).
+fn llama_config(max_position_embeddings: usize, rope_scaling: &str) -> Config {
+    let config: LlamaConfig = serde_json::from_str(&format!(
+        r#"{{"hidden_size": 4096, "intermediate_size": 14336, "vocab_size": 128256,
+            "num_hidden_layers": 32, "num_attention_heads": 32, "num_key_value_heads": 8,
+            "rms_norm_eps": 1e-5, "rope_theta": 500000.0, "bos_token_id": 128000,
+            "eos_token_id": 128009, "max_position_embeddings": {max_position_embeddings},
+            "rope_scaling": {rope_scaling}}}"#
+    ))
+    .unwrap();
+    let scfg = SpecificConfig::new(None, None, None, None, None, None, None);
+    config.into_config(false, DType::F16, &scfg)
+}
+
+fn assert_close(a: f64, b: f64) {
+    assert!((a - b).abs() <= 1e-9 * b.abs().max(1.), "{a} != {b}");
+}
+
+#[test]
+fn test_no_scaling() {
+    let unscaled = llama_config(8192, "null").get_rope_inv_freq(128);
+    for rope_scaling in [
+        r#"{"type": "default"}"#,
+        r#"{"type": "linear", "factor": 1.0}"#,
+        r#"{"rope_type": "unknown", "factor": 4.0}"#,
+    ] {
+        let config = llama_config(8192, rope_scaling);
+        assert_eq!(config.get_rope_scaling(), None);
+        assert_eq!(config.get_max_model_len(), 8192);
+        assert_eq!(config.get_rope_inv_freq(128), unscaled);
+    }
+    assert_eq!(unscaled.1, 1.);
+    assert_eq!(unscaled.0.len(), 64);
+}
+
+#[test]
+fn test_linear() {
+    let config = llama_config(4096, r#"{"type": "linear", "factor": 2.0}"#);
+    assert_eq!(
+        config.get_rope_scaling(),
+        Some((RopeScalingKind::Linear, 2.0))
+    );
+    assert_eq!(config.get_max_model_len(), 8192);
+    let (unscaled, _) = llama_config(4096, "null").get_rope_inv_freq(128);
+    let (inv_freq, mscale) = config.get_rope_inv_freq(128);
+    for (freq, base) in inv_freq.iter().zip(&unscaled) {
+        assert_close(*freq, base / 2.);
+    }
+    assert_eq!(mscale, 1.);
+}
+
+#[test]
+fn test_dynamic_ntk() {
+    let config = llama_config(4096, r#"{"type": "dynamic", "factor": 2.0}"#);
+    assert_eq!(config.get_max_model_len(), 8192);
+    let (unscaled, _) = llama_config(4096, "null").get_rope_inv_freq(128);
+    let (inv_freq, _) = config.get_rope_inv_freq(128);
+    // The base is scaled by 3^(128 / 126), the highest frequency is kept
+    assert_eq!(inv_freq[0], 1.);
+    let base = 500000. * 3f64.powf(128. / 126.);
+    assert_close(inv_freq[63], 1. / base.powf(126. / 128.));
+    assert!(inv_freq[63] < unscaled[63]);
+}
+
+#[test]
+fn test_yarn() {
+    // Qwen2.5 style: the config gives the original context length
+    let config = llama_config(
+        32768,
+        r#"{"type": "yarn", "factor": 4.0, "original_max_position_embeddings": 32768}"#,
+    );
+    assert_eq!(
+        config.get_rope_scaling(),
+        Some((RopeScalingKind::Yarn, 4.0))
+    );
+    assert_eq!(config.get_max_model_len(), 131072);
+    let (unscaled, _) = llama_config(32768, "null").get_rope_inv_freq(128);
+    let (inv_freq, mscale) = config.get_rope_inv_freq(128);
+    // High frequencies are extrapolated, low frequencies interpolated
+    assert_eq!(inv_freq[0], unscaled[0]);
+    assert_close(inv_freq[63], unscaled[63] / 4.);
+    assert_close(mscale, 0.1 * 4f64.ln() + 1.);
+
+    let config = llama_config(
+        32768,
+        r#"{"type": "yarn", "factor": 4.0, "original_max_position_embeddings": 32768,
+            "attention_factor": 1.5}"#,
+    );
+    assert_eq!(config.get_rope_inv_freq(128).1, 1.5);
+}
+
+#[test]
+fn test_llama3() {
+    // Llama 3.1: the config gives the extended context length
+    let config = llama_config(
+        131072,
+        r#"{"factor": 8.0, "low_freq_factor": 1.0, "high_freq_factor": 4.0,
+            "original_max_position_embeddings": 8192, "rope_type": "llama3"}"#,
+    );
+    assert_eq!(
+        config.get_rope_scaling(),
+        Some((RopeScalingKind::Llama3, 8.0))
+    );
+    assert_eq!(config.get_max_model_len(), 131072);
+    let (unscaled, _) = llama_config(131072, "null").get_rope_inv_freq(128);
+    let (inv_freq, mscale) = config.get_rope_inv_freq(128);
+    assert_eq!(inv_freq[0], unscaled[0]);
+    assert_close(inv_freq[63], unscaled[63] / 8.);
+    assert!(inv_freq
+        .iter()
+        .zip(&unscaled)
+        .all(|(freq, base)| freq <= base && *freq >= base / 8. - 1e-15));
+    assert_eq!(mscale, 1.);
+}
+
+#[test]
+fn test_sin_cos_tables() -> candle_core::Result<()> {
+    let config = llama_config(
+        1024,
+        r#"{"type": "yarn", "factor": 4.0, "original_max_position_embeddings": 1024}"#,
+    );
+    let (sin, cos) = config.get_rope_sin_cos(128, &Device::Cpu)?;
+    assert_eq!(sin.dims(), &[4096, 64]);
+    assert_eq!(cos.dims(), &[4096, 64]);
+    // Position 0 is not rotated, the tables only carry the YaRN attention scaling
+    let mscale = (0.1 * 4f64.ln() + 1.) as f32;
+    let cos0 = cos.i(0)?.to_vec1::<f32>()?;
+    assert!(cos0.iter().all(|c| (c - mscale).abs() < 1e-6));
+    assert!(sin.i(0)?.to_vec1::<f32>()?.iter().all(|s| *s == 0.));
+    Ok(())
+}
@@ -0,0 +1,330 @@
+mod common;
+
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::openai::{
+    guided_decoding::{get_token_bytes, TokenGuide},
+    logits_processor::{
+        apply_logit_bias, apply_min_p_typical_p, apply_presence_frequency_penalty, ban_tokens,
+        LogitsProcessor, Sampling,
+    },
+    pipelines::pipeline::sample_groups,
+    requests::StreamOptions,
+    responses::APIError,
+    sampling_params::SamplingParams,
+};
+use candle_vllm::scheduler::sequence::{_Sequence, Sequence, SequenceGroup};
+use common::MockEngine;
+use either::Either::{Left, Right};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokenizers::Tokenizer;
+
+/// The `<|endoftext|>` token of the mock tokenizer, after the 256 bytes
+const END_OF_TEXT: u32 = 256;
+
+/// Up to 16 tokens
+fn sampling_params() -> Result<SamplingParams, APIError> {
+    SamplingParams::builder().max_tokens(16).build()
+}
+
+/// A group with a single sequence of one prompt token.
+fn group(seq_id: usize, sampling_params: SamplingParams) -> SequenceGroup {
+    let seq = Arc::new(Sequence(RwLock::new(_Sequence::new(vec![0], seq_id, 16))));
+    SequenceGroup::new(
+        &[seq],
+        0,
+        seq_id,
+        format!("cmpl-{seq_id}"),
+        SystemTime::now(),
+        sampling_params,
+        false,
+        None,
+        StreamOptions::default(),
+        0,
+        None,
+    )
+}
+
+/// The byte-level tokenizer of the mock model.
+async fn tokenizer() -> Tokenizer {
+    let engine = MockEngine::default().engine().unwrap();
+    let engine = engine.lock().await;
+    engine.get_pipeline().tokenizer().tokenizer().clone()
+}
+
+/// The tokens sampled for each group of the batch over 16 steps, from uniform logits.
+fn sample_batch(
+    groups: Vec<Arc<SequenceGroup>>,
+    tokenizer: &Tokenizer,
+) -> Result<Vec<Vec<usize>>, APIError> {
+    let processor = LogitsProcessor::from_sampling(0, Sampling::All { temperature: 1. });
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let logits = Tensor::zeros((groups.len(), vocab_size), DType::F32, &Device::Cpu)
+        .map_err(APIError::from)?;
+    let groups = VecDeque::from(groups);
+    let mut tokens = vec![Vec::new(); groups.len()];
+    for _ in 0..16 {
+        let results = sample_groups(&logits, &groups, &processor, &[END_OF_TEXT], tokenizer);
+        assert_eq!(results.len(), groups.len());
+        for (tokens, result) in tokens.iter_mut().zip(results) {
+            match result? {
+                Left(logprobs) => tokens.push(logprobs.token),
+                Right(reason) => panic!("finished with {reason}"),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// The text of the token sampled for each group of the batch from uniform logits, or the reason
+/// it finished.
+fn sample_step(
+    groups: &VecDeque<Arc<SequenceGroup>>,
+    tokenizer: &Tokenizer,
+) -> Result<Vec<Result<String, APIError>>, APIError> {
+    let processor = LogitsProcessor::from_sampling(0, Sampling::All { temperature: 1. });
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let logits = Tensor::zeros((groups.len(), vocab_size), DType::F32, &Device::Cpu)
+        .map_err(APIError::from)?;
+    Ok(
+        sample_groups(&logits, groups, &processor, &[END_OF_TEXT], tokenizer)
+            .into_iter()
+            .map(|result| {
+                result.map(|token| match token {
+                    Left(logprobs) => logprobs.bytes,
+                    Right(reason) => reason,
+                })
+            })
+            .collect(),
+    )
+}
+
+#[test]
+fn test_builder_checks_the_params() -> Result<(), APIError> {
+    let params = SamplingParams::builder().build()?;
+    assert_eq!((params.n, params.best_of, params.max_tokens), (1, 1, 16));
+    assert_eq!(
+        (params.temperature, params.top_p, params.top_k),
+        (1., 1., -1)
+    );
+    assert!(params.skip_special_tokens && params.stop.is_none());
+
+    let params = SamplingParams::builder().n(2).temperature(0.7).build()?;
+    assert_eq!((params.n, params.best_of), (2, 2));
+    assert!(SamplingParams::builder().n(2).best_of(1).build().is_err());
+    assert!(SamplingParams::builder()
+        .presence_penalty(3.)
+        .build()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn test_seeded_groups_sample_alike() -> Result<(), APIError> {
+    let processor = LogitsProcessor::from_sampling(0, Sampling::All { temperature: 1. });
+    let logits =
+        Tensor::new(&[0.5f32, 1., 1.5, 2., 0.25, 1.25], &Device::Cpu).map_err(APIError::from)?;
+    let seeded = |seq_id, seed| -> Result<SequenceGroup, APIError> {
+        let mut params = sampling_params()?;
+        params.seed = Some(seed);
+        Ok(group(seq_id, params))
+    };
+    let sample = |group: &SequenceGroup| -> Result<Vec<u32>, APIError> {
+        let rng = group.rng().unwrap();
+        (0..32)
+            .map(|_| {
+                // Draws from the shared generator in between do not change the seeded output
+                processor.sample(&logits)?;
+                processor.sample_with_rng(&logits, rng)
+            })
+            .collect::<candle_core::Result<_>>()
+            .map_err(APIError::from)
+    };
+    let first = sample(&seeded(0, 42)?)?;
+    assert_eq!(first, sample(&seeded(1, 42)?)?);
+    assert_ne!(first, sample(&seeded(2, 7)?)?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batched_groups_sample_with_their_own_params() -> Result<(), APIError> {
+    let tokenizer = tokenizer().await;
+    let vocab_size = tokenizer.get_vocab_size(true);
+    // A group with a `seed`, and the bias that makes `token` certain
+    let seeded = |seq_id, seed, token: Option<u8>| -> Result<Arc<SequenceGroup>, APIError> {
+        let mut params = sampling_params()?;
+        params.seed = Some(seed);
+        if let Some(token) = token {
+            let bias = HashMap::from([(token.to_string(), 100.)]);
+            params.set_logit_bias(&bias, vocab_size)?;
+        }
+        Ok(Arc::new(group(seq_id, params)))
+    };
+
+    // A seed gives the same tokens whatever else is in the batch, and wherever the group is
+    let first = sample_batch(vec![seeded(0, 42, None)?], &tokenizer)?.remove(0);
+    let second = sample_batch(vec![seeded(1, 7, None)?], &tokenizer)?.remove(0);
+    assert_ne!(first, second);
+    let batch = vec![seeded(0, 42, None)?, seeded(1, 7, None)?];
+    assert_eq!(
+        sample_batch(batch, &tokenizer)?,
+        vec![first.clone(), second.clone()]
+    );
+    let batch = vec![seeded(1, 7, None)?, seeded(0, 42, None)?];
+    assert_eq!(sample_batch(batch, &tokenizer)?, vec![second, first]);
+
+    // Each group is biased on its own row
+    let batch = vec![seeded(0, 42, Some(b'a'))?, seeded(1, 7, Some(b'b'))?];
+    assert_eq!(
+        sample_batch(batch, &tokenizer)?,
+        vec![vec![b'a' as usize; 16], vec![b'b' as usize; 16]]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batched_groups_follow_their_own_guide() -> Result<(), APIError> {
+    let tokenizer = tokenizer().await;
+    let token_bytes = Arc::new(get_token_bytes(&tokenizer));
+    let guided = |seq_id, pattern| -> Result<Arc<SequenceGroup>, APIError> {
+        let mut params = sampling_params()?;
+        params.guide = Some(Arc::new(TokenGuide::new(pattern, token_bytes.clone())?));
+        Ok(Arc::new(group(seq_id, params)))
+    };
+    let groups = VecDeque::from(vec![guided(0, "ab")?, guided(1, "xyz")?]);
+    let processor = LogitsProcessor::from_sampling(0, Sampling::All { temperature: 1. });
+    let logits = Tensor::zeros(
+        (2, tokenizer.get_vocab_size(true)),
+        DType::F32,
+        &Device::Cpu,
+    )
+    .map_err(APIError::from)?;
+    let mut steps = Vec::new();
+    for _ in 0..3 {
+        let step = sample_groups(&logits, &groups, &processor, &[END_OF_TEXT], &tokenizer)
+            .into_iter()
+            .map(|result| {
+                Ok(match result? {
+                    Left(logprobs) => logprobs.bytes,
+                    Right(reason) => reason,
+                })
+            })
+            .collect::<Result<Vec<_>, APIError>>()?;
+        steps.push(step);
+    }
+    // Each guide advanced with the token sampled for its own group
+    assert_eq!(steps, [["a", "x"], ["b", "y"], ["stop", "z"]]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sampling_dead_ends_finish_the_group() -> Result<(), APIError> {
+    let tokenizer = tokenizer().await;
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let token_bytes = Arc::new(get_token_bytes(&tokenizer));
+
+    // The end of sequence is the only token after a complete guided output
+    let mut params = sampling_params()?;
+    params.set_min_tokens(Some(8))?;
+    params.guide = Some(Arc::new(TokenGuide::new("yes", token_bytes)?));
+    let guided = Arc::new(group(0, params));
+    // Every token is banned
+    let mut params = sampling_params()?;
+    let bias = (0..vocab_size)
+        .map(|token| (token.to_string(), -100.))
+        .collect::<HashMap<_, _>>();
+    params.set_logit_bias(&bias, vocab_size)?;
+    let banned = Arc::new(group(1, params));
+    let groups = VecDeque::from(vec![guided, banned, Arc::new(group(2, sampling_params()?))]);
+
+    let mut guided_output = Vec::new();
+    for _ in 0..4 {
+        let mut step = sample_step(&groups, &tokenizer)?;
+        assert_eq!(step.len(), 3);
+        assert!(step[2].is_ok());
+        let error = step[1].as_ref().unwrap_err();
+        assert!(error.to_string().contains("Sampling failed"), "{error}");
+        guided_output.push(step.remove(0)?);
+    }
+    assert_eq!(guided_output, ["y", "e", "s", "stop"]);
+    Ok(())
+}
+
+#[test]
+fn test_presence_and_frequency_penalties() -> Result<(), APIError> {
+    let logits = Tensor::new(&[1f32, 1., 1., 1.], &Device::Cpu).map_err(APIError::from)?;
+    let penalized = apply_presence_frequency_penalty(&logits, 0.5, 0.25, &[1, 2, 1])
+        .and_then(|logits| logits.to_vec1::<f32>())
+        .map_err(APIError::from)?;
+    assert_eq!(penalized, vec![1., 0., 0.25, 1.]);
+    Ok(())
+}
+
+#[test]
+fn test_logit_bias_bans_and_boosts_tokens() -> Result<(), APIError> {
+    let mut params = sampling_params()?;
+    let bias = |entries: &[(&str, f32)]| -> HashMap<String, f32> {
+        entries
+            .iter()
+            .map(|(token, bias)| (token.to_string(), *bias))
+            .collect()
+    };
+    assert!(params.set_logit_bias(&bias(&[("7", 1.)]), 4).is_err());
+    assert!(params.set_logit_bias(&bias(&[("x", 1.)]), 4).is_err());
+    assert!(params.set_logit_bias(&bias(&[("1", 101.)]), 4).is_err());
+    params.set_logit_bias(&bias(&[("1", -100.), ("2", 5.)]), 4)?;
+
+    let logits = Tensor::new(&[0f32, 10., 1., 2.], &Device::Cpu).map_err(APIError::from)?;
+    let biased = apply_logit_bias(&logits, &params.logit_bias).map_err(APIError::from)?;
+    assert_eq!(
+        biased.to_vec1::<f32>().map_err(APIError::from)?,
+        vec![0., f32::NEG_INFINITY, 6., 2.]
+    );
+    // The banned token is never sampled, even greedily
+    let processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    assert_eq!(processor.sample(&biased).map_err(APIError::from)?, 2);
+    Ok(())
+}
+
+#[test]
+fn test_min_p_and_typical_p_filters() -> Result<(), APIError> {
+    let prs = [0.5f32, 0.3, 0.15, 0.05];
+    let filtered = |min_p, typical_p| {
+        let mut prs = prs;
+        apply_min_p_typical_p(&mut prs, min_p, typical_p);
+        prs
+    };
+    assert_eq!(filtered(0., 1.), prs);
+    // Below 0.2 * 0.5
+    assert_eq!(filtered(0.2, 1.), [0.5, 0.3, 0.15, 0.]);
+    // The entropy is 1.14 nats, the tokens closest to it are 0.3 (1.20) then 0.5 (0.69)
+    assert_eq!(filtered(0., 0.5), [0.5, 0.3, 0., 0.]);
+    assert_eq!(filtered(0., 0.2), [0., 0.3, 0., 0.]);
+
+    let mut params = sampling_params()?;
+    assert!(params.set_probability_filters(Some(1.5), None).is_err());
+    assert!(params.set_probability_filters(None, Some(0.)).is_err());
+    params.set_probability_filters(Some(0.1), None)?;
+    assert_eq!((params.min_p, params.typical_p), (0.1, 1.));
+    Ok(())
+}
+
+#[test]
+fn test_min_tokens_bans_stop_tokens() -> Result<(), APIError> {
+    let mut params = sampling_params()?;
+    assert_eq!(params.min_tokens, 0);
+    assert!(params.set_min_tokens(Some(17)).is_err());
+    params.set_min_tokens(Some(16))?;
+    assert_eq!(params.min_tokens, 16);
+
+    let logits = Tensor::new(&[0f32, 10., 1., 2.], &Device::Cpu).map_err(APIError::from)?;
+    let banned = ban_tokens(&logits, &[1, 7]).map_err(APIError::from)?;
+    assert_eq!(
+        banned.to_vec1::<f32>().map_err(APIError::from)?,
+        vec![0., f32::NEG_INFINITY, 1., 2.]
+    );
+    let processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    assert_eq!(processor.sample(&banned).map_err(APIError::from)?, 3);
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::{
+    backend::reshape_and_cache,
+    openai::{models::SelfExtend, responses::APIError},
+    paged_attention::self_extend::SelfExtendAttention,
+    try_api,
+};
+use std::iter::zip;
+
+const HEAD_SIZE: usize = 8;
+
+fn inv_freq() -> Vec<f64> {
+    (0..HEAD_SIZE)
+        .step_by(2)
+        .map(|i| 1. / 10000f64.powf(i as f64 / HEAD_SIZE as f64))
+        .collect()
+}
+
+/// `x` rotary embedded at `pos`, the halves of the head being rotated together.
+fn rotate(x: &[f32], pos: usize) -> Vec<f32> {
+    let half = x.len() / 2;
+    let mut out = vec![0.; x.len()];
+    for (i, freq) in inv_freq().iter().enumerate() {
+        let (sin, cos) = (pos as f64 * freq).sin_cos();
+        let (a, b) = (x[i] as f64, x[i + half] as f64);
+        out[i] = (a * cos - b * sin) as f32;
+        out[i + half] = (b * cos + a * sin) as f32;
+    }
+    out
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// The logit of a query and a key, not rotary embedded yet, at the positions Self-Extend gives
+/// them.
+fn reference_logit(
+    self_extend: &SelfExtend,
+    query: &[f32],
+    key: &[f32],
+    q: usize,
+    k: usize,
+    scale: f32,
+) -> f32 {
+    let (q_pos, k_pos) = if self_extend.is_neighbor(q, k) {
+        (q, k)
+    } else {
+        (
+            self_extend.grouped_query_position(q),
+            self_extend.grouped_key_position(k),
+        )
+    };
+    dot(&rotate(query, q_pos), &rotate(key, k_pos)) * scale
+}
+
+fn randn(shape: (usize, usize, usize)) -> Result<Vec<Vec<Vec<f32>>>, APIError> {
+    let x = try_api!(Tensor::randn(0f32, 1f32, shape, &Device::Cpu));
+    Ok(try_api!(x.to_vec3::<f32>()))
+}
+
+#[test]
+fn test_relative_positions() {
+    let self_extend = SelfExtend::new(4, 8);
+    // Exact in the window
+    assert_eq!(self_extend.relative_position(20, 13), 7);
+    // The grouped positions continue the window
+    assert_eq!(self_extend.relative_position(20, 12), 8);
+    assert_eq!(self_extend.relative_position(100, 0), 31);
+    assert_eq!(self_extend.extended_len(16), 40);
+    assert_eq!(self_extend.extended_len(8), 8);
+}
+
+/// The prompt attends to its neighbors at their exact positions and to the tokens beyond the
+/// window at grouped positions, the query head h using the key head h / 2.
+#[test]
+fn test_prefill_logits() -> Result<(), APIError> {
+    let self_extend = SelfExtend::new(3, 4);
+    let attention = SelfExtendAttention::new(self_extend, inv_freq(), false);
+    let (num_heads, seq_len, scale) = (2, 24, 0.5);
+    let queries = randn((num_heads, seq_len, HEAD_SIZE))?;
+    let keys = randn((1, seq_len, HEAD_SIZE))?;
+    let embed = |x: &[Vec<Vec<f32>>]| {
+        let rotated = x
+            .iter()
+            .flat_map(|head| head.iter().enumerate().flat_map(|(pos, x)| rotate(x, pos)))
+            .collect::<Vec<_>>();
+        Tensor::from_vec(rotated, (1, x.len(), seq_len, HEAD_SIZE), &Device::Cpu)
+    };
+    let positions = (0..seq_len).collect::<Vec<_>>();
+    let logits = try_api!(attention.logits(
+        &try_api!(embed(&queries)),
+        &try_api!(embed(&keys)),
+        &positions,
+        &positions,
+        scale,
+    ));
+    let logits = try_api!(try_api!(logits.squeeze(0)).to_vec3::<f32>());
+    for (h, head) in logits.iter().enumerate() {
+        for (q, row) in head.iter().enumerate() {
+            for (k, logit) in row.iter().enumerate() {
+                let expected =
+                    reference_logit(&self_extend, &queries[h][q], &keys[0][k], q, k, scale);
+                assert!(
+                    (logit - expected).abs() < 1e-4,
+                    "head {h}, query {q}, key {k}: {logit} != {expected}"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decoding attends to the cached keys and values like the reference attention of the last
+/// position of the context.
+#[test]
+fn test_decode_attention() -> Result<(), APIError> {
+    let cpu = Device::Cpu;
+    let self_extend = SelfExtend::new(2, 4);
+    let attention = SelfExtendAttention::new(self_extend, inv_freq(), false);
+    let (num_blocks, block_size, num_heads, context_len, scale) = (4, 8, 2, 19, 0.5);
+    let x = 16 / DType::F32.size_in_bytes();
+    // A single sequence spanning blocks 3, 1 and 0
+    let block_table = [3u32, 1, 0];
+    let keys = randn((context_len, 1, HEAD_SIZE))?;
+    let values = randn((context_len, 1, HEAD_SIZE))?;
+    let query = randn((1, num_heads, HEAD_SIZE))?;
+
+    let rotated_keys = keys
+        .iter()
+        .enumerate()
+        .flat_map(|(pos, key)| rotate(&key[0], pos))
+        .collect::<Vec<_>>();
+    let rotated_query = query[0]
+        .iter()
+        .flat_map(|head| rotate(head, context_len - 1))
+        .collect::<Vec<_>>();
+    let key_cache = try_api!(Tensor::zeros(
+        (num_blocks, 1, HEAD_SIZE / x, block_size, x),
+        DType::F32,
+        &cpu
+    ));
+    let value_cache = try_api!(Tensor::zeros(
+        (num_blocks, 1, HEAD_SIZE, block_size),
+        DType::F32,
+        &cpu
+    ));
+    let slots = (0..context_len)
+        .map(|t| (block_table[t / block_size] as usize * block_size + t % block_size) as i64)
+        .collect::<Vec<_>>();
+    try_api!(reshape_and_cache(
+        &try_api!(Tensor::from_vec(
+            rotated_keys,
+            (context_len, 1, HEAD_SIZE),
+            &cpu
+        )),
+        &try_api!(Tensor::from_vec(
+            values
+                .iter()
+                .flatten()
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>(),
+            (context_len, 1, HEAD_SIZE),
+            &cpu
+        )),
+        &key_cache,
+        &value_cache,
+        &try_api!(Tensor::from_vec(slots, context_len, &cpu)),
+    ));
+    let out = try_api!(attention.paged_attention(
+        &try_api!(Tensor::from_vec(
+            rotated_query,
+            (1, num_heads, HEAD_SIZE),
+            &cpu
+        )),
+        &key_cache,
+        &value_cache,
+        &try_api!(Tensor::from_vec(block_table.to_vec(), (1, 3), &cpu)),
+        &try_api!(Tensor::from_vec(vec![context_len as u32], 1, &cpu)),
+        scale,
+        1.,
+        0,
+    ));
+    let out = try_api!(out.to_vec3::<f32>());
+
+    for (h, head) in query[0].iter().enumerate() {
+        let logits = keys
+            .iter()
+            .enumerate()
+            .map(|(k, key)| reference_logit(&self_extend, head, &key[0], context_len - 1, k, scale))
+            .collect::<Vec<_>>();
+        let max = logits.iter().copied().fold(f32::MIN, f32::max);
+        let weights = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+        let total = weights.iter().sum::<f32>();
+        for d in 0..HEAD_SIZE {
+            let expected = zip(&weights, &values)
+                .map(|(w, value)| w * value[0][d])
+                .sum::<f32>()
+                / total;
+            let actual = out[0][h][d];
+            assert!(
+                (actual - expected).abs() < 1e-4,
+                "head {h}, dim {d}: {actual} != {expected}"
+            );
+        }
+    }
+    Ok(())
+}
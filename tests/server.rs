@@ -0,0 +1,171 @@
+mod common;
+
+use axum::{
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use candle_vllm::openai::{
+    compression::{compression_layer, DEFAULT_MIN_COMPRESSED_BYTES},
+    openai_server::chat_completions,
+    OpenAIServerData,
+};
+use common::{server_data, MockEngine};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Context of the served model, in tokens
+const MAX_MODEL_LEN: usize = 64;
+
+async fn serve() -> Arc<OpenAIServerData> {
+    let served = MockEngine {
+        max_model_len: MAX_MODEL_LEN,
+        ..MockEngine::replying("Hi")
+    }
+    .serve()
+    .await
+    .unwrap();
+    Arc::new(server_data(served))
+}
+
+/// The status and body of a chat completion through the handler of the server.
+async fn chat(data: &Arc<OpenAIServerData>, request: Value) -> (StatusCode, Value) {
+    let request = serde_json::from_value(request).unwrap();
+    let response = chat_completions(
+        State(data.clone()),
+        HeaderMap::new(),
+        None,
+        Ok(Json(request)),
+    )
+    .await;
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+/// A request whose prompt does not fit in the context, the mock tokenizer has a token per byte.
+fn long_request(truncate_prompt_tokens: Option<isize>) -> Value {
+    json!({
+        "model": "mock",
+        "messages": [{"role": "user", "content": "a".repeat(100)}],
+        "max_tokens": 8,
+        "truncate_prompt_tokens": truncate_prompt_tokens,
+    })
+}
+
+#[tokio::test]
+async fn test_engine_checks_the_context_length() {
+    let data = serve().await;
+    let served = data.get_model("mock").unwrap();
+    let engine = served.model.lock().await;
+    assert!(engine.check_context_length(MAX_MODEL_LEN - 8, 8).is_ok());
+    let error = engine
+        .check_context_length(MAX_MODEL_LEN - 8, 9)
+        .unwrap_err();
+    assert!(error.to_string().starts_with(
+        "This model's maximum context length is 64 tokens. However, you requested 65 tokens"
+    ));
+}
+
+#[tokio::test]
+async fn test_prompt_beyond_the_context_is_rejected() {
+    let data = serve().await;
+    let (status, body) = chat(&data, long_request(None)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(body["error"]["param"], "messages");
+    assert_eq!(body["error"]["code"], "context_length_exceeded");
+}
+
+#[tokio::test]
+async fn test_truncate_prompt_tokens() {
+    let data = serve().await;
+    // As many as fit with `max_tokens`
+    let (status, body) = chat(&data, long_request(Some(-1))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["usage"]["prompt_tokens"], MAX_MODEL_LEN - 8);
+    assert_eq!(body["choices"][0]["message"]["content"], "Hi");
+
+    let (status, body) = chat(&data, long_request(Some(10))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["usage"]["prompt_tokens"], 10);
+
+    let (status, body) = chat(&data, long_request(Some(0))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body["error"]["message"],
+        "`truncate_prompt_tokens` must be positive or -1, got 0."
+    );
+}
+
+/// Base url of a server with response compression, its model replies `response`.
+async fn serve_compressed(response: &str) -> String {
+    // Room for 2048 tokens
+    let served = MockEngine {
+        num_gpu_blocks: 128,
+        ..MockEngine::replying(response)
+    }
+    .serve()
+    .await
+    .unwrap();
+    let app = Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .layer(compression_layer(DEFAULT_MIN_COMPRESSED_BYTES))
+        .with_state(Arc::new(server_data(served)));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{addr}/")
+}
+
+/// `Content-Encoding` of a chat completion response of `max_tokens` when the client accepts
+/// `encoding`.
+async fn content_encoding(
+    url: &str,
+    max_tokens: usize,
+    stream: bool,
+    encoding: &str,
+) -> Option<String> {
+    let request = json!({
+        "model": "mock",
+        "messages": [{"role": "user", "content": "Hello"}],
+        "max_tokens": max_tokens,
+        "stream": stream,
+    });
+    let request = hyper::Request::post(format!("{url}v1/chat/completions"))
+        .header("content-type", "application/json")
+        .header("accept-encoding", encoding)
+        .body(hyper::Body::from(request.to_string()))
+        .unwrap();
+    let response = hyper::Client::new().request(request).await.unwrap();
+    assert!(response.status().is_success());
+    response
+        .headers()
+        .get("content-encoding")
+        .map(|value| value.to_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn test_response_compression() {
+    let long = "token ".repeat(200);
+    let url = serve_compressed(&long).await;
+    let max_tokens = long.len();
+    assert_eq!(
+        content_encoding(&url, max_tokens, false, "gzip")
+            .await
+            .as_deref(),
+        Some("gzip")
+    );
+    assert_eq!(
+        content_encoding(&url, max_tokens, false, "zstd")
+            .await
+            .as_deref(),
+        Some("zstd")
+    );
+    // Small responses and event streams are sent as is
+    assert_eq!(content_encoding(&url, 2, false, "gzip").await, None);
+    assert_eq!(content_encoding(&url, max_tokens, true, "gzip").await, None);
+}
@@ -0,0 +1,123 @@
+use candle_vllm::openai::sessions::{Message, SessionLimits, SessionStore};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+fn message(role: &str, content: &str) -> Message {
+    serde_json::from_value(json!({"role": role, "content": content})).unwrap()
+}
+
+fn store(max_sessions: usize, max_messages: usize) -> SessionStore {
+    SessionStore::new(SessionLimits {
+        max_sessions,
+        max_messages,
+        ttl: Duration::from_secs(60),
+    })
+}
+
+#[test]
+fn test_prompt_starts_with_the_history() {
+    let sessions = store(8, 16);
+    let now = Instant::now();
+    let hello = message("user", "Hello");
+    assert_eq!(
+        sessions.prompt_messages("s1", &[hello.clone()], now),
+        vec![hello.clone()]
+    );
+    sessions.append(
+        "s1",
+        vec![
+            message("system", "Be brief."),
+            hello.clone(),
+            message("assistant", "Hi!"),
+        ],
+        now,
+    );
+    let prompt = sessions.prompt_messages("s1", &[message("user", "How are you?")], now);
+    assert_eq!(
+        prompt,
+        vec![
+            message("system", "Be brief."),
+            hello,
+            message("assistant", "Hi!"),
+            message("user", "How are you?"),
+        ]
+    );
+    // Other sessions have their own history
+    assert_eq!(sessions.history("s2", now), None);
+
+    // A new system message replaces the previous one
+    sessions.append(
+        "s1",
+        vec![
+            message("system", "Be verbose."),
+            message("user", "Why?"),
+            message("assistant", "Because."),
+        ],
+        now,
+    );
+    let history = sessions.history("s1", now).unwrap();
+    assert_eq!(history.len(), 5);
+    assert_eq!(history[0], message("user", "Hello"));
+    assert_eq!(history[2], message("system", "Be verbose."));
+
+    assert!(sessions.remove("s1"));
+    assert!(!sessions.remove("s1"));
+    assert!(sessions.is_empty());
+}
+
+#[test]
+fn test_oldest_turns_are_dropped() {
+    let sessions = store(8, 4);
+    let now = Instant::now();
+    sessions.append(
+        "s1",
+        vec![
+            message("system", "Be brief."),
+            message("user", "1"),
+            message("assistant", "one"),
+        ],
+        now,
+    );
+    sessions.append(
+        "s1",
+        vec![message("user", "2"), message("assistant", "two")],
+        now,
+    );
+    // The whole first turn goes, the history starts with a user message
+    assert_eq!(
+        sessions.history("s1", now).unwrap(),
+        vec![
+            message("system", "Be brief."),
+            message("user", "2"),
+            message("assistant", "two"),
+        ]
+    );
+}
+
+#[test]
+fn test_sessions_are_evicted() {
+    let sessions = store(2, 16);
+    let now = Instant::now();
+    let turn = || vec![message("user", "Hello"), message("assistant", "Hi!")];
+    sessions.append("s1", turn(), now);
+    sessions.append("s2", turn(), now + Duration::from_secs(1));
+    sessions.append("s1", turn(), now + Duration::from_secs(2));
+    // The least recently used session makes room
+    sessions.append("s3", turn(), now + Duration::from_secs(3));
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions
+        .history("s2", now + Duration::from_secs(3))
+        .is_none());
+    assert_eq!(
+        sessions
+            .history("s1", now + Duration::from_secs(3))
+            .unwrap()
+            .len(),
+        4
+    );
+    // Idle sessions expire
+    assert!(sessions
+        .history("s3", now + Duration::from_secs(64))
+        .is_none());
+    assert!(sessions.is_empty());
+}
@@ -0,0 +1,110 @@
+mod common;
+
+use candle_core::{Device, Tensor};
+use candle_vllm::{
+    openai::{
+        pipelines::llm_engine::LLMEngine, requests::AttentionSinks, responses::APIError,
+        sampling_params::SamplingParams,
+    },
+    scheduler::state_cache::StateCache,
+};
+use common::MockEngine;
+use futures::StreamExt;
+
+/// A state of two tensors, filled with `value`.
+fn state(value: f32) -> Result<Vec<Tensor>, APIError> {
+    Ok(vec![
+        Tensor::full(value, (1, 2), &Device::Cpu)?,
+        Tensor::full(value, (1, 3, 2), &Device::Cpu)?,
+    ])
+}
+
+#[test]
+fn test_state_cache_gather_scatter() -> Result<(), APIError> {
+    let mut cache = StateCache::new(3);
+    for (seq_id, value) in [(0, 0.), (1, 1.), (2, 2.)] {
+        cache.allocate(seq_id, state(value)?)?;
+    }
+    assert_eq!(cache.num_free_slots(), 0);
+    assert!(cache.allocate(3, state(3.)?).is_err());
+
+    // Batched in the order of the sequences
+    let batch = cache.gather(&[2, 0])?;
+    assert_eq!(batch[0].to_vec2::<f32>()?, [[2., 2.], [0., 0.]]);
+    assert_eq!(batch[1].dims(), [2, 3, 2]);
+    let batch = batch
+        .iter()
+        .map(|tensor| (tensor + 10.).map_err(APIError::from))
+        .collect::<Result<Vec<_>, _>>()?;
+    cache.scatter(&[2, 0], &batch)?;
+    assert_eq!(cache.gather(&[0])?[0].to_vec2::<f32>()?, [[10., 10.]]);
+    assert_eq!(cache.gather(&[1])?[0].to_vec2::<f32>()?, [[1., 1.]]);
+    assert_eq!(
+        cache.gather(&[2])?[1].flatten_all()?.to_vec1::<f32>()?,
+        [12.; 6]
+    );
+
+    // Finished sequences give their slot back
+    cache.retain(|seq_id| seq_id != 1);
+    assert!(!cache.contains(1));
+    assert!(cache.gather(&[0, 1]).is_err());
+    cache.allocate(3, state(3.)?)?;
+    cache.free(0);
+    assert_eq!(cache.num_free_slots(), 1);
+    Ok(())
+}
+
+/// A recurrent model serves prompts longer than its whole KV cache and more concurrent requests
+/// than it has state slots, which wait for one.
+#[tokio::test]
+async fn test_recurrent_model_without_kv_blocks() -> Result<(), APIError> {
+    let llm_engine = MockEngine {
+        recurrent: true,
+        max_num_seqs: 3,
+        num_gpu_blocks: 1,
+        num_cpu_blocks: 1,
+        ..Default::default()
+    }
+    .engine()?;
+    let sinks = AttentionSinks {
+        num_sink_tokens: 4,
+        window: 16,
+    };
+    assert!(llm_engine
+        .lock()
+        .await
+        .check_attention_sinks(&sinks)
+        .is_err());
+    // No more sequences than state slots, the scheduler could never admit them
+    {
+        let engine = llm_engine.lock().await;
+        assert!(engine.check_num_seqs(3, None).is_ok());
+        assert!(engine.check_num_seqs(4, None).is_err());
+        assert!(engine.check_num_seqs(1, Some(4)).is_err());
+    }
+
+    let prompts = (0..5)
+        .map(|i| format!("A prompt longer than one block of the KV cache, number {i}"))
+        .collect::<Vec<_>>();
+    let generations = prompts.iter().map(|prompt| {
+        let llm_engine = llm_engine.clone();
+        async move {
+            let sampling_params = SamplingParams::builder()
+                .temperature(0.)
+                .max_tokens(256)
+                .build()?;
+            let tokens = LLMEngine::generate(&llm_engine, prompt, sampling_params)
+                .await?
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, APIError>(tokens.iter().map(|t| t.text.as_str()).collect::<String>())
+        }
+    });
+    let texts = futures::future::join_all(generations).await;
+    for (prompt, text) in prompts.iter().zip(texts) {
+        assert_eq!(&text?, prompt);
+    }
+    Ok(())
+}
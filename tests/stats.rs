@@ -0,0 +1,64 @@
+use candle_vllm::openai::metrics::{EngineStats, STATS_WINDOW};
+use candle_vllm::openai::responses::BlockStats;
+use candle_vllm::scheduler::Preemption;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_engine_stats_snapshot() {
+    let start = Instant::now();
+    let stats = EngineStats::new(start);
+    let blocks = |used, total| BlockStats {
+        used,
+        free: total - used,
+        total,
+    };
+    stats.record_blocks((blocks(6, 10), blocks(2, 8)));
+    stats.record_schedule((2, 3, 1), 0, 2);
+    stats.record_schedule((3, 1, 0), 2, 0);
+    stats.record_preemption(Preemption::Swap);
+    stats.record_preemption(Preemption::Recompute);
+    stats.record_preemption(Preemption::Swap);
+
+    let snapshot = stats.snapshot("llama", start);
+    assert_eq!(snapshot.id, "llama");
+    assert_eq!(
+        (snapshot.running, snapshot.waiting, snapshot.swapped),
+        (3, 1, 0)
+    );
+    assert_eq!(snapshot.gpu_blocks, blocks(6, 10));
+    assert_eq!(snapshot.cpu_blocks, blocks(2, 8));
+    assert_eq!(
+        (snapshot.blocks_swapped_in, snapshot.blocks_swapped_out),
+        (2, 2)
+    );
+    assert_eq!(
+        (snapshot.preemptions_swap, snapshot.preemptions_recompute),
+        (2, 1)
+    );
+    assert_eq!(snapshot.average_batch_size, 0.);
+    assert_eq!(snapshot.generation_tokens_per_second, 0.);
+}
+
+#[test]
+fn test_engine_stats_throughput_window() {
+    let start = Instant::now();
+    let stats = EngineStats::new(start);
+    // A prefill of 100 tokens, then decoding steps of 4 and 2 sequences
+    stats.record_step(true, 1, 100, start + Duration::from_secs(1));
+    stats.record_step(false, 4, 4, start + Duration::from_secs(2));
+    stats.record_step(false, 2, 2, start + Duration::from_secs(3));
+
+    // Over the 4 seconds since the start
+    let snapshot = stats.snapshot("llama", start + Duration::from_secs(4));
+    assert_eq!(snapshot.prompt_tokens_per_second, 25.);
+    // The prefill samples the first token
+    assert_eq!(snapshot.generation_tokens_per_second, 7. / 4.);
+    assert_eq!(snapshot.average_batch_size, 3.);
+
+    // Only the steps of the last minute count
+    let later = start + STATS_WINDOW + Duration::from_millis(2500);
+    let snapshot = stats.snapshot("llama", later);
+    assert_eq!(snapshot.prompt_tokens_per_second, 0.);
+    assert_eq!(snapshot.generation_tokens_per_second, 2. / 60.);
+    assert_eq!(snapshot.average_batch_size, 2.);
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use candle_vllm::openai::{
+    pipelines::llm_engine::{LLMEngine, NewRequest},
+    requests::StopTokens,
+    responses::{APIError, ChatCompletionChunk, Choice, ChoiceData},
+    sampling_params::SamplingParams,
+    streaming::{CancelFlag, CancelFlags, ChatResponse, StopBuffer, Streamer},
+};
+use common::MockEngine;
+use futures::StreamExt;
+
+fn chunk(content: &str, finish_reason: Option<&str>) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: "cmpl-stream".to_string(),
+        choices: vec![Choice {
+            delta: ChoiceData {
+                content: Some(content.to_string()),
+                role: "assistant".to_string(),
+                tool_calls: None,
+            },
+            finish_reason: finish_reason.map(str::to_string),
+            index: 0,
+        }],
+        created: 0,
+        model: "llama".to_string(),
+        object: "chat.completion.chunk".to_string(),
+        system_fingerprint: None,
+        usage: None,
+    }
+}
+
+#[test]
+fn test_buffered_chunks_merge_in_order() {
+    let mut merged = chunk("Hel", None);
+    assert!(merged.merge(chunk("lo", None)).is_none());
+    assert!(merged.merge(chunk(" wörld", Some("stop"))).is_none());
+    assert_eq!(
+        merged.choices[0].delta.content.as_deref(),
+        Some("Hello wörld")
+    );
+    assert_eq!(merged.choices[0].finish_reason.as_deref(), Some("stop"));
+    // Nothing follows the end of a choice
+    assert!(merged.merge(chunk("!", None)).is_some());
+
+    let mut other_request = chunk("Hi", None);
+    other_request.id = "cmpl-other".to_string();
+    assert!(chunk("Hel", None).merge(other_request).is_some());
+}
+
+#[tokio::test]
+async fn test_streamer_sends_buffered_tokens_as_one_event() {
+    let (tx, rx) = flume::unbounded();
+    for content in ["Hel", "lo", " wörld"] {
+        tx.send(ChatResponse::Chunk(chunk(content, None))).unwrap();
+    }
+    tx.send(ChatResponse::Chunk(chunk("", Some("stop"))))
+        .unwrap();
+    tx.send(ChatResponse::Done).unwrap();
+    let mut streamer = Streamer::new(rx, CancelFlag::default());
+    // The merged chunk, then [DONE]
+    assert!(streamer.next().await.is_some());
+    assert_eq!(streamer.chunks, 1);
+    assert!(streamer.next().await.is_some());
+    assert!(streamer.next().await.is_none());
+}
+
+#[test]
+fn test_abort_in_flight_request() {
+    let flags = CancelFlags::default();
+    let flag = CancelFlag::default();
+    assert!(flags.try_insert("cmpl-a", flag.clone()));
+    assert!(!flags.try_insert("cmpl-a", CancelFlag::default()));
+    assert!(flags.try_insert("cmpl-b", CancelFlag::default()));
+    assert!(!flags.abort("cmpl-unknown"));
+    assert!(flags.abort("cmpl-a"));
+    // The handler dropping its guard afterwards does not turn the abort into a disconnection
+    flag.cancel();
+    assert!(flag.is_aborted());
+    assert_eq!(flags.take_cancelled(), vec![("cmpl-a".to_string(), true)]);
+    assert!(flags.take_cancelled().is_empty());
+    assert!(flags.try_insert("cmpl-a", CancelFlag::default()));
+}
+
+#[test]
+fn test_stop_string_across_tokens() {
+    let stop = StopTokens::Multi(vec!["\nUser:".to_string(), "###".to_string()]);
+    let mut buffer = StopBuffer::new(Some(&stop));
+    let mut sent = String::new();
+    for token in [
+        "Hi", " there", "\n", "Us", "ual", "ly\n", "U", "ser", ": more",
+    ] {
+        let (text, stopped) = buffer.push(token);
+        // What could still be a stop string is not sent
+        assert!(!text.ends_with('\n'), "{text:?}");
+        sent.push_str(&text);
+        if stopped {
+            break;
+        }
+    }
+    assert_eq!(sent, "Hi there\nUsually");
+    assert_eq!(buffer.flush(), "");
+    assert_eq!(buffer.truncate("Hi\nUser: more ###"), "Hi");
+
+    // Held back text is sent once it cannot be a stop string, or when generation ends
+    let mut buffer = StopBuffer::new(Some(&stop));
+    assert_eq!(buffer.push("a#"), ("a".to_string(), false));
+    assert_eq!(buffer.push("#b"), ("##b".to_string(), false));
+    assert_eq!(buffer.push("##"), (String::new(), false));
+    assert_eq!(buffer.flush(), "##");
+
+    // Without stop strings nothing is held back
+    let mut buffer = StopBuffer::new(None);
+    assert_eq!(buffer.push("\n"), ("\n".to_string(), false));
+}
+
+#[tokio::test]
+async fn test_stream_stops_on_split_stop_string() -> Result<(), APIError> {
+    let llm_engine = MockEngine::replying("Sure.\nUsers\nUser: again").engine()?;
+    let sampling_params = SamplingParams::builder()
+        .temperature(0.)
+        .stop(StopTokens::Single("\nUser:".to_string()))
+        .max_tokens(64)
+        .build()?;
+    let mut stream = LLMEngine::generate(&llm_engine, "hello", sampling_params).await?;
+    let mut text = String::new();
+    let mut finish_reason = None;
+    while let Some(token) = stream.next().await {
+        let token = token?;
+        // The mock generates a byte per token, the stop string spans six of them
+        assert!(!token.text.ends_with('\n'), "{:?}", token.text);
+        text.push_str(&token.text);
+        finish_reason = finish_reason.or(token.finish_reason);
+    }
+    assert_eq!(text, "Sure.\nUsers");
+    assert_eq!(finish_reason.as_deref(), Some("stop"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disconnected_caller_does_not_hold_up_its_batch() -> Result<(), APIError> {
+    let llm_engine = MockEngine::replying("Sure, here it is.").engine()?;
+    let sampling_params = || {
+        SamplingParams::builder()
+            .temperature(0.)
+            .max_tokens(64)
+            .build()
+    };
+    {
+        // Batched with the request below, its caller is gone before the first token
+        let mut engine = llm_engine.lock().await;
+        let prompt = engine
+            .tokenizer
+            .encode("hello", false)
+            .map_err(APIError::from)?;
+        let (sender, receiver) = flume::unbounded();
+        drop(receiver);
+        engine.add_request(NewRequest {
+            sender: Some(sender),
+            ..NewRequest::new(prompt, "cmpl-gone".to_string(), sampling_params()?)
+        });
+    }
+    let stream = LLMEngine::generate(&llm_engine, "hello", sampling_params()?).await?;
+    let text = stream
+        .map(|token| token.map(|token| token.text))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<String, _>>()?;
+    assert_eq!(text, "Sure, here it is.");
+    Ok(())
+}
@@ -1,3 +1,5 @@
+mod common;
+
 use axum::{
     http::{self, Method},
     routing::post,
@@ -7,14 +9,19 @@ use candle_core::{DType, Device};
 use candle_vllm::{
     get_model_loader,
     openai::{
-        openai_server::chat_completions, pipelines::llm_engine::LLMEngine, responses::APIError,
-        OpenAIServerData,
+        openai_server::chat_completions,
+        pipelines::{llm_engine::LLMEngine, LoadOptions},
+        plugins::PluginHost,
+        responses::APIError,
+        sampling_params::SamplingParams,
+        OpenAIServerData, PromptLogging, ServedModel,
     },
-    scheduler::{cache_engine::CacheConfig, SchedulerConfig},
+    scheduler::{cache_engine::CacheConfig, SchedulerConfig, SchedulingPolicy},
     ModelSelected,
 };
-use std::sync::Arc;
-use tokio::sync::Notify;
+use common::MockEngine;
+use futures::StreamExt;
+use std::sync::{Arc, RwLock};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
 #[tokio::test]
@@ -34,29 +41,46 @@ async fn test_llama() -> Result<(), APIError> {
         None,
         Some(std::env::var("TESTS_HF_TOKEN").unwrap()),
         None,
+        None,
+        None,
     )?;
-    let model = loader.load_model(paths, DType::F16, Device::Cpu)?;
-    let finish_notify = Arc::new(Notify::new());
+    let model = loader.load_model(paths, LoadOptions::new(DType::F16, Device::Cpu))?;
+    let model_name = model.0.name().to_string();
     let llm_engine = LLMEngine::new(
         model.0,
-        SchedulerConfig { max_num_seqs: 256 },
+        SchedulerConfig {
+            max_num_seqs: 256,
+            max_num_batched_tokens: 8192,
+            kv_eviction: None,
+            num_lookahead_slots: 0,
+            policy: SchedulingPolicy::Fcfs,
+        },
         CacheConfig {
             block_size: 16,
             num_gpu_blocks: None,
             num_cpu_blocks: None,
             fully_init: false,
             dtype: DType::F16,
+            tensor_parallel_size: 1,
         },
-        Arc::new(Notify::new()),
-        finish_notify.clone(),
     )?;
+    let user_metrics = llm_engine.lock().await.user_metrics.clone();
+    let served = ServedModel::new(llm_engine, model.1, model_name, None).await;
 
     let server_data = OpenAIServerData {
-        pipeline_config: model.1,
-        model: llm_engine,
+        models: RwLock::new(vec![Arc::new(served)]),
         device: Device::Cpu,
         record_conversation: false,
-        finish_notify: finish_notify.clone(),
+        default_system_prompt: None,
+        log_prompts: PromptLogging::Off,
+        user_metrics,
+        plugins: PluginHost::default(),
+        max_waiting_requests: None,
+        request_timeout: None,
+        rate_limiter: None,
+        sessions: None,
+        quality_router: None,
+        reloader: None,
     };
 
     let allow_origin = AllowOrigin::any();
@@ -79,3 +103,108 @@ async fn test_llama() -> Result<(), APIError> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_mock() -> Result<(), APIError> {
+    let llm_engine = MockEngine {
+        max_num_seqs: 16,
+        ..Default::default()
+    }
+    .engine()?;
+
+    let prompt = "<|im_start|>user\n Hello, wörld <|im_end|><|im_start|>assistant\n";
+    for (max_tokens, expected, finish_reason) in
+        [(64, "Hello, wörld", "stop"), (3, "Hel", "length")]
+    {
+        let sampling_params = SamplingParams::builder()
+            .temperature(0.)
+            .max_tokens(max_tokens)
+            .build()?;
+        let tokens = LLMEngine::generate(&llm_engine, prompt, sampling_params)
+            .await?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        let text = tokens.iter().map(|t| t.text.as_str()).collect::<String>();
+        assert_eq!(text, expected);
+        assert_eq!(
+            tokens.last().and_then(|t| t.finish_reason.as_deref()),
+            Some(finish_reason)
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mock_ignore_eos_and_min_tokens() -> Result<(), APIError> {
+    let llm_engine = MockEngine {
+        max_num_seqs: 16,
+        ..MockEngine::replying("Hi")
+    }
+    .engine()?;
+
+    // The mock model ends its reply like an EOS token would
+    for (ignore_eos, min_tokens, expected, finish_reason) in [
+        (false, 0, "Hi", "stop"),
+        (true, 0, "HiHiH", "length"),
+        (false, 3, "HiH", "stop"),
+    ] {
+        let mut sampling_params = SamplingParams::builder()
+            .temperature(0.)
+            .ignore_eos(ignore_eos)
+            .max_tokens(5)
+            .build()?;
+        sampling_params.set_min_tokens(Some(min_tokens))?;
+        let tokens = LLMEngine::generate(&llm_engine, "hello", sampling_params)
+            .await?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        let text = tokens.iter().map(|t| t.text.as_str()).collect::<String>();
+        assert_eq!(text, expected);
+        assert_eq!(
+            tokens.last().and_then(|t| t.finish_reason.as_deref()),
+            Some(finish_reason)
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mock_prompt_larger_than_kv_cache_fails() -> Result<(), APIError> {
+    // Room for 64 tokens, the prompt fits in the context of the model but never in the cache
+    let llm_engine = MockEngine {
+        max_num_seqs: 16,
+        num_gpu_blocks: 4,
+        num_cpu_blocks: 4,
+        ..Default::default()
+    }
+    .engine()?;
+
+    let sampling_params = SamplingParams::builder()
+        .temperature(0.)
+        .max_tokens(8)
+        .build()?;
+    let prompt = "hello ".repeat(200);
+    let results = LLMEngine::generate(&llm_engine, &prompt, sampling_params)
+        .await?
+        .collect::<Vec<_>>()
+        .await;
+    assert!(matches!(results.as_slice(), [Err(_)]));
+
+    // The engine goes on serving the requests that fit
+    let sampling_params = SamplingParams::builder()
+        .temperature(0.)
+        .max_tokens(3)
+        .build()?;
+    let tokens = LLMEngine::generate(&llm_engine, "hello", sampling_params)
+        .await?
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(!tokens.is_empty());
+    Ok(())
+}
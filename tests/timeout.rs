@@ -0,0 +1,66 @@
+mod common;
+
+use axum::http::StatusCode;
+use candle_vllm::openai::{
+    pipelines::llm_engine::NewRequest,
+    responses::APIError,
+    sampling_params::SamplingParams,
+    streaming::{CancelFlag, ChatResponse},
+};
+use common::MockEngine;
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn test_request_is_aborted_past_its_deadline() -> Result<(), APIError> {
+    let llm_engine = MockEngine {
+        response: Some("token ".repeat(200)),
+        latency_ms: 20,
+        num_gpu_blocks: 256,
+        ..Default::default()
+    }
+    .engine()?;
+
+    // Generating the 1000 tokens of the reply takes 20 seconds
+    let sampling_params = SamplingParams::builder()
+        .temperature(0.)
+        .ignore_eos(true)
+        .max_tokens(1000)
+        .build()?;
+    let (tx, rx) = flume::unbounded();
+    let cancel =
+        CancelFlag::default().with_deadline(Some(Instant::now() + Duration::from_millis(500)));
+    {
+        let mut engine = llm_engine.lock().await;
+        let prompt = engine
+            .get_pipeline()
+            .tokenizer()
+            .tokenizer()
+            .encode("one two three", false)
+            .map_err(APIError::from)?;
+        engine.add_request(NewRequest {
+            sender: Some(tx),
+            cancel,
+            ..NewRequest::new(prompt, "cmpl-timeout".to_string(), sampling_params)
+        });
+        engine.notify.notify_one();
+    }
+
+    let start = Instant::now();
+    let error = loop {
+        let response = tokio::time::timeout(Duration::from_secs(10), rx.recv_async())
+            .await
+            .expect("the request was not aborted")
+            .expect("the engine dropped the request");
+        if let ChatResponse::ModelError(e) = response {
+            break e;
+        }
+    };
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(error.status(), StatusCode::REQUEST_TIMEOUT);
+    assert_eq!(error.code(), Some("request_timeout"));
+
+    // Reported through the stream only, no caller waits for the whole response
+    let engine = llm_engine.lock().await;
+    assert!(!engine.failed_requests.contains_key("cmpl-timeout"));
+    Ok(())
+}
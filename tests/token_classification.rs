@@ -0,0 +1,56 @@
+use candle_vllm::openai::responses::{ClassifiedToken, TokenEntity};
+
+fn token(token: &str, label: &str, score: f32, offsets: Option<(usize, usize)>) -> ClassifiedToken {
+    ClassifiedToken {
+        token: token.to_string(),
+        label: label.to_string(),
+        score,
+        start: offsets.map(|(start, _)| start),
+        end: offsets.map(|(_, end)| end),
+    }
+}
+
+#[test]
+fn test_iob_tokens_group_into_entities() {
+    let text = "Angela Merkel visited Bonn";
+    let tokens = [
+        token("Angela", "B-PER", 0.9, Some((0, 6))),
+        token("Me", "I-PER", 0.8, Some((7, 9))),
+        token("##rkel", "B-PER", 0.7, Some((9, 13))),
+        token("visited", "O", 0.99, Some((14, 21))),
+        token("Bonn", "B-LOC", 0.6, Some((22, 26))),
+    ];
+    let entities = TokenEntity::group(&tokens, Some(text));
+    assert_eq!(entities.len(), 2);
+    assert_eq!(entities[0].label, "PER");
+    assert_eq!(entities[0].text, "Angela Merkel");
+    assert_eq!((entities[0].start, entities[0].end), (Some(0), Some(13)));
+    assert!((entities[0].score - 0.8).abs() < 1e-6);
+    assert_eq!(entities[1].label, "LOC");
+    assert_eq!(entities[1].text, "Bonn");
+}
+
+#[test]
+fn test_entities_of_token_inputs() {
+    // No offsets, the text is rebuilt from the WordPiece tokens; a B- tag splits adjacent entities
+    let tokens = [
+        token("New", "B-LOC", 1.0, None),
+        token("York", "I-LOC", 1.0, None),
+        token("Paris", "B-LOC", 1.0, None),
+        token("Ber", "ORG", 1.0, None),
+        token("##lin", "ORG", 1.0, None),
+    ];
+    let entities = TokenEntity::group(&tokens, None);
+    let spans = entities
+        .iter()
+        .map(|entity| (entity.label.as_str(), entity.text.as_str(), entity.start))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        spans,
+        vec![
+            ("LOC", "New York", None),
+            ("LOC", "Paris", None),
+            ("ORG", "Berlin", None),
+        ]
+    );
+}
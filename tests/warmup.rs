@@ -0,0 +1,29 @@
+mod common;
+
+use candle_vllm::openai::{
+    responses::APIError,
+    warmup::{self, WarmupConfig},
+};
+use common::MockEngine;
+use std::time::Duration;
+
+#[test]
+fn test_warmup_shapes() {
+    let config = WarmupConfig::new(256, 4096);
+    assert_eq!(config.batch_sizes, vec![1, 16]);
+    assert_eq!(config.prompt_len, 128);
+    assert_eq!(WarmupConfig::new(4, 4096).batch_sizes, vec![1, 4]);
+    assert_eq!(WarmupConfig::new(1, 4096).batch_sizes, vec![1]);
+    // The prompt and the decoding steps fit in a short context
+    let config = WarmupConfig::new(8, 64);
+    assert!(config.prompt_len + config.decode_steps <= 64);
+}
+
+#[tokio::test]
+async fn test_warmup_mock_model() -> Result<(), APIError> {
+    let llm_engine = MockEngine::default().engine()?;
+    // Both batches run to completion
+    let elapsed = warmup::run(&llm_engine, &WarmupConfig::new(4, 4096)).await?;
+    assert!(elapsed > Duration::ZERO);
+    Ok(())
+}
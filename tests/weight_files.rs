@@ -0,0 +1,71 @@
+use candle_vllm::{hub_load_local_safetensors, local_weight_files, WeightFilesError};
+use std::path::{Path, PathBuf};
+
+/// An empty model directory unique to the test.
+fn model_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("candle-vllm-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn touch(dir: &Path, file: &str) {
+    std::fs::write(dir.join(file), b"").unwrap();
+}
+
+#[test]
+fn test_weight_map_is_deduplicated_in_index_order() {
+    let dir = model_dir("weight-map");
+    // Tensor names are not sorted by shard, the last shard comes first
+    std::fs::write(
+        dir.join("model.safetensors.index.json"),
+        r#"{"metadata": {}, "weight_map": {
+            "model.norm.weight": "model-00002-of-00002.safetensors",
+            "model.embed_tokens.weight": "model-00001-of-00002.safetensors",
+            "lm_head.weight": "model-00002-of-00002.safetensors",
+            "model.layers.0.mlp.up_proj.weight": "model-00001-of-00002.safetensors"
+        }}"#,
+    )
+    .unwrap();
+    touch(&dir, "model-00001-of-00002.safetensors");
+
+    let path = dir.display().to_string();
+    match hub_load_local_safetensors(&path, "model.safetensors.index.json") {
+        Err(WeightFilesError::MissingShard { shard, .. }) => {
+            assert!(shard.ends_with("model-00002-of-00002.safetensors"))
+        }
+        other => panic!("expected a missing shard, got {other:?}"),
+    }
+
+    touch(&dir, "model-00002-of-00002.safetensors");
+    assert_eq!(
+        local_weight_files(&path).unwrap(),
+        vec![
+            dir.join("model-00002-of-00002.safetensors"),
+            dir.join("model-00001-of-00002.safetensors"),
+        ]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_single_weight_files() {
+    let dir = model_dir("single-weights");
+    let path = dir.display().to_string();
+    assert!(matches!(
+        local_weight_files(&path),
+        Err(WeightFilesError::NoWeights(_))
+    ));
+
+    touch(&dir, "consolidated.safetensors");
+    assert_eq!(
+        local_weight_files(&path).unwrap(),
+        vec![dir.join("consolidated.safetensors")]
+    );
+    touch(&dir, "model.safetensors");
+    assert_eq!(
+        local_weight_files(&path).unwrap(),
+        vec![dir.join("model.safetensors")]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
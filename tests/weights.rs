@@ -0,0 +1,74 @@
+use candle_core::{DType, Device, Tensor};
+use candle_vllm::openai::pipelines::weights::{self, DtypeOverride};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// A checkpoint of two shards unique to the test.
+fn checkpoint(name: &str) -> Vec<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("candle-vllm-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let device = Device::Cpu;
+    let first = HashMap::from([(
+        "model.embed_tokens.weight".to_string(),
+        Tensor::arange(0f32, 6., &device)
+            .unwrap()
+            .reshape((2, 3))
+            .unwrap(),
+    )]);
+    let second = HashMap::from([(
+        "model.norm.weight".to_string(),
+        Tensor::ones(4, DType::F16, &device).unwrap(),
+    )]);
+    let paths = vec![
+        dir.join("model-00001-of-00002.safetensors"),
+        dir.join("model-00002-of-00002.safetensors"),
+    ];
+    candle_core::safetensors::save(&first, &paths[0]).unwrap();
+    candle_core::safetensors::save(&second, &paths[1]).unwrap();
+    paths
+}
+
+#[test]
+fn test_tensors_are_read_from_their_shard() -> candle_core::Result<()> {
+    let paths = checkpoint("weights-shards");
+    let vb = unsafe { weights::var_builder(&paths, DType::F32, &[], &Device::Cpu)? };
+    assert!(vb.contains_tensor("model.embed_tokens.weight"));
+    assert!(vb.contains_tensor("model.norm.weight"));
+    assert!(!vb.contains_tensor("lm_head.weight"));
+
+    let embed = vb.get((2, 3), "model.embed_tokens.weight")?;
+    assert_eq!(
+        embed.to_vec2::<f32>()?,
+        vec![vec![0., 1., 2.], vec![3., 4., 5.]]
+    );
+    // Converted from the dtype of the checkpoint
+    let norm = vb.pp("model.norm").get(4, "weight")?;
+    assert_eq!(norm.dtype(), DType::F32);
+    assert_eq!(norm.to_vec1::<f32>()?, vec![1.; 4]);
+
+    assert!(vb.get((3, 2), "model.embed_tokens.weight").is_err());
+    assert!(vb.get(4, "lm_head.weight").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_dtype_overrides() -> candle_core::Result<()> {
+    let norm: DtypeOverride = "norm=f16".parse().unwrap();
+    assert_eq!(norm.pattern, "norm");
+    assert_eq!(norm.dtype, DType::F16);
+    assert!("norm".parse::<DtypeOverride>().is_err());
+    assert!("norm=f8".parse::<DtypeOverride>().is_err());
+    assert!("=f32".parse::<DtypeOverride>().is_err());
+
+    let paths = checkpoint("weights-overrides");
+    let overrides = [norm, "model=bf16".parse().unwrap()];
+    let vb = unsafe { weights::var_builder(&paths, DType::F32, &overrides, &Device::Cpu)? };
+    // The first matching override wins
+    assert_eq!(vb.get(4, "model.norm.weight")?.dtype(), DType::F16);
+    assert_eq!(
+        vb.get((2, 3), "model.embed_tokens.weight")?.dtype(),
+        DType::BF16
+    );
+    Ok(())
+}